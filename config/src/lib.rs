@@ -15,10 +15,30 @@ pub struct GlobalConfig {
     pub create_if_missing: bool,
     pub db_path: String,
     pub db_name: String,
+    pub flush_queue_capacity: usize,
     // WalConfig
     pub enabled: bool,
     pub wal_config_dir: String,
     pub sync: bool,
+    // FileSystem
+    pub max_io_retries: usize,
+    // BlockCacheConfig
+    pub block_cache_capacity: usize,
+    // RequestWindowConfig
+    pub request_window_size: usize,
+    pub request_window_ttl_secs: u64,
+    // ScrubConfig
+    pub scrub_bytes_per_sec: u64,
+    pub scrub_interval_secs: u64,
+    pub quarantine_dir: String,
+    // RestoreConfig
+    pub restore_confirmed: bool,
+    // CleanerConfig
+    pub clean_interval_secs: u64,
+    pub clean_safety_margin_secs: u64,
+    // CompactConfig
+    pub compaction_window: String,
+    pub urgent_compaction_score: f64,
     // TseriesFamOpt
     pub max_level: u32,
     // pub base_file_size: u64,
@@ -26,8 +46,30 @@ pub struct GlobalConfig {
     pub base_file_size: u64,
     pub compact_trigger: u32,
     pub max_compact_size: u64,
+    #[serde(default = "default_base_dir")]
+    pub base_dir: String,
+    // the tsm and delta directories of the layout before base_dir, their files are moved
+    // under base_dir when the store is opened
+    #[serde(default = "default_legacy_tsm_dir")]
     pub tsm_dir: String,
+    #[serde(default = "default_legacy_delta_dir")]
     pub delta_dir: String,
+    pub trash_dir: String,
+    pub trash_ttl_secs: u64,
+    pub max_entry_cells: usize,
+    pub max_flush_level: u32,
+    pub max_files_per_level: usize,
+    pub max_compact_files: usize,
+    pub small_file_threshold: u64,
+    pub max_small_files: usize,
+    pub read_parallelism: usize,
+    pub ooo_tolerance_ns: i64,
+    pub max_delta_cache_size: u64,
+    pub delta_flush_age_secs: u64,
+    pub result_cache_entries: usize,
+    pub result_cache_ttl_ms: u64,
+    pub last_value_entries: usize,
+    pub max_concurrent_files: usize,
     // MemCacheOpt
     pub tf_id: u32,
     pub seq_no: u64,
@@ -37,6 +79,18 @@ pub struct GlobalConfig {
     pub tsfamily_num: u32,
 }
 
+fn default_base_dir() -> String {
+    "db/data/".to_string()
+}
+
+fn default_legacy_tsm_dir() -> String {
+    "db/tsm/".to_string()
+}
+
+fn default_legacy_delta_dir() -> String {
+    "db/delta/".to_string()
+}
+
 impl GlobalConfig {
     pub const fn max_memcache_size(&self) -> &u64 {
         &self.max_memcache_size
@@ -56,6 +56,15 @@ enum SubCommand {
     /// run query
     #[clap(arg_required_else_help = true)]
     Query {},
+    /// print the tseries families stored on disk as json
+    Dump {},
+    /// rewrite the tsm files of the database at <DIR> written in an older format
+    Migrate {
+        dir: String,
+        /// only report the files to rewrite
+        #[clap(long)]
+        dry_run: bool,
+    },
 }
 
 /// To run cnosdb-cli:
@@ -99,6 +108,31 @@ fn main() -> Result<(), std::io::Error> {
                        }
                    },
                    SubCommand::Query {} => todo!(),
+                   SubCommand::Dump {} => {
+                       let opt = tskv::kv_option::Options::default();
+                       match tskv::TsKv::debug_dump_dir(&opt).await {
+                           Ok(dumps) => {
+                               println!("{}", serde_json::to_string_pretty(&dumps).unwrap())
+                           },
+                           Err(e) => {
+                               eprintln!("{}", e);
+                               std::process::exit(1)
+                           },
+                       }
+                   },
+                   SubCommand::Migrate { dir, dry_run } => {
+                       let mut opt = tskv::kv_option::Options::default();
+                       opt.db.db_path = dir.clone();
+                       match tskv::TsKv::migrate_dir(&opt, *dry_run).await {
+                           Ok(report) => {
+                               println!("{}", serde_json::to_string_pretty(&report).unwrap())
+                           },
+                           Err(e) => {
+                               eprintln!("{}", e);
+                               std::process::exit(1)
+                           },
+                       }
+                   },
                }
            });
     Ok(())
@@ -52,7 +52,8 @@ mod test {
                 let points = fbb.finished_data().to_vec();
                 tx.send(WritePointsRpcRequest { version: 1,
                                                 database: "database".to_string(),
-                                                points })
+                                                points,
+                                                request_id: 0 })
                   .await
                   .unwrap();
             }
@@ -0,0 +1,116 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use tskv::bench::{boolean, float, integer, string, timestamp, unsigned};
+
+const SEED: u64 = 42;
+const BLOCK_SIZES: [usize; 3] = [100, 1000, 10000];
+
+// a random walk, like most metrics
+fn integers(rng: &mut StdRng, n: usize) -> Vec<i64> {
+    let mut v = 0_i64;
+    (0..n).map(|_| {
+              v += rng.gen_range(-100..=100);
+              v
+          })
+          .collect()
+}
+
+// one point every second with a few milliseconds of jitter
+fn timestamps(rng: &mut StdRng, n: usize) -> Vec<i64> {
+    (0..n).map(|i| i as i64 * 1_000_000_000 + rng.gen_range(0..5_000_000)).collect()
+}
+
+fn floats(rng: &mut StdRng, n: usize) -> Vec<f64> {
+    let mut v = 100.0;
+    (0..n).map(|_| {
+              v += rng.gen_range(-1.0..1.0);
+              v
+          })
+          .collect()
+}
+
+fn strings(rng: &mut StdRng, n: usize) -> Vec<Vec<u8>> {
+    (0..n).map(|_| format!("host_{}", rng.gen_range(0..100)).into_bytes()).collect()
+}
+
+// the values decoded by `decode_head`, like a query range covering the start of an edge block
+const HEAD_VALUES: usize = 10;
+
+fn bench_coder<T, E, D, H>(c: &mut Criterion,
+                           name: &str,
+                           data: impl Fn(&mut StdRng, usize) -> T,
+                           encode: E,
+                           decode: D,
+                           decode_head: H)
+    where E: Fn(&T, &mut Vec<u8>),
+          D: Fn(&[u8]),
+          H: Fn(&[u8])
+{
+    let mut group = c.benchmark_group(name);
+    for size in BLOCK_SIZES {
+        let data = data(&mut StdRng::seed_from_u64(SEED), size);
+        let mut buf = vec![];
+        encode(&data, &mut buf);
+        group.bench_with_input(BenchmarkId::new("encode", size), &data, |b, data| {
+                 b.iter(|| {
+                      let mut buf = Vec::with_capacity(buf.len());
+                      encode(data, &mut buf);
+                      buf
+                  })
+             });
+        group.bench_with_input(BenchmarkId::new("decode", size), &buf, |b, buf| {
+                 b.iter(|| decode(buf))
+             });
+        group.bench_with_input(BenchmarkId::new("decode_head", size), &buf, |b, buf| {
+                 b.iter(|| decode_head(buf))
+             });
+    }
+    group.finish();
+}
+
+fn coders(c: &mut Criterion) {
+    bench_coder(c,
+                "integer",
+                integers,
+                |src, dst| integer::encode(src, dst).unwrap(),
+                |src| integer::decode(src, &mut vec![]).unwrap(),
+                |src| integer::decode_range(src, &mut vec![], 0, HEAD_VALUES).unwrap());
+    bench_coder(c,
+                "timestamp",
+                timestamps,
+                |src, dst| timestamp::encode(src, dst).unwrap(),
+                |src| timestamp::decode(src, &mut vec![]).unwrap(),
+                |src| timestamp::decode(src, &mut vec![]).unwrap());
+    bench_coder(c,
+                "unsigned",
+                |rng, n| {
+                    integers(rng, n).into_iter().map(|v| v.unsigned_abs()).collect::<Vec<_>>()
+                },
+                |src, dst| unsigned::encode(src, dst).unwrap(),
+                |src| unsigned::decode(src, &mut vec![]).unwrap(),
+                |src| unsigned::decode_range(src, &mut vec![], 0, HEAD_VALUES).unwrap());
+    bench_coder(c,
+                "float",
+                floats,
+                |src, dst| float::encode(src, dst).unwrap(),
+                |src| float::decode(src, &mut vec![]).unwrap(),
+                |src| float::decode_range(src, &mut vec![], 0, HEAD_VALUES).unwrap());
+    bench_coder(c,
+                "boolean",
+                |rng, n| (0..n).map(|_| rng.gen_bool(0.5)).collect::<Vec<_>>(),
+                |src, dst| boolean::encode(src, dst).unwrap(),
+                |src| boolean::decode(src, &mut vec![]).unwrap(),
+                |src| boolean::decode_range(src, &mut vec![], 0, HEAD_VALUES).unwrap());
+    bench_coder(c,
+                "string",
+                strings,
+                |src, dst| {
+                    let src: Vec<&[u8]> = src.iter().map(|s| s.as_slice()).collect();
+                    string::encode(&src, dst).unwrap()
+                },
+                |src| string::decode(src, &mut vec![]).unwrap(),
+                |src| string::decode_range(src, &mut vec![], 0, HEAD_VALUES).unwrap());
+}
+
+criterion_group!(benches, coders);
+criterion_main!(benches);
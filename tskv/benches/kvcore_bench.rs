@@ -33,7 +33,7 @@ fn big_write(c: &mut Criterion) {
     let points = models_helper::create_big_random_points(&mut fbb, 1);
     fbb.finish(points, None);
     let points = fbb.finished_data().to_vec();
-    let request = WritePointsRpcRequest { version: 1, database, points };
+    let request = WritePointsRpcRequest { version: 1, database, points, request_id: 0 };
 
     // maybe 250 ms
     c.bench_function("big_write", |b| {
@@ -53,7 +53,7 @@ fn run(c: &mut Criterion) {
     fbb.finish(points, None);
     let points_str = fbb.finished_data();
     let points = points_str.to_vec();
-    let request = WritePointsRpcRequest { version: 1, database, points };
+    let request = WritePointsRpcRequest { version: 1, database, points, request_id: 0 };
 
     // maybe 500 us
     c.bench_function("write", |b| b.iter(|| test_write(tskv.clone(), request.clone())));
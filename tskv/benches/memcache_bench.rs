@@ -0,0 +1,116 @@
+use std::{sync::Arc, thread};
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use models::ValueType;
+use parking_lot::RwLock;
+#[cfg(feature = "skiplist")]
+use tskv::SkipListCache;
+use tskv::{FieldHints, MemCache, MemCacheTrait, TimeRange};
+
+const FIELDS: u64 = 10;
+const POINTS: i64 = 10_000;
+const THREADS: u64 = 4;
+// the fields already in the cache when one of them is written in a hot loop
+const WIDE_FIELDS: u64 = 10_000;
+
+// points of every field are written with the timestamps out of order
+fn insert(cache: &mut dyn MemCacheTrait) {
+    for i in 0..POINTS {
+        let ts = (i * 7919) % POINTS;
+        for field_id in 0..FIELDS {
+            cache.insert_raw(i as u64, field_id, ts, ValueType::Integer, &i.to_be_bytes()).unwrap();
+        }
+    }
+}
+
+// every thread writes its own fields into the shared cache, like the writers of a tseries family
+fn insert_concurrently<C: MemCacheTrait + 'static>(cache: C) {
+    let cache = Arc::new(RwLock::new(cache));
+    let handles: Vec<_> =
+        (0..THREADS).map(|t| {
+                        let cache = cache.clone();
+                        thread::spawn(move || {
+                            for i in 0..POINTS {
+                                let ts = (i * 7919) % POINTS;
+                                for field_id in (t..FIELDS).step_by(THREADS as usize) {
+                                    cache.write()
+                                         .insert_raw(i as u64,
+                                                     field_id,
+                                                     ts,
+                                                     ValueType::Integer,
+                                                     &i.to_be_bytes())
+                                         .unwrap();
+                                }
+                            }
+                        })
+                    })
+                    .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+}
+
+// a cache of many fields, only the first one is written in the hot loop
+fn new_wide_cache() -> MemCache {
+    let mut cache = MemCache::new(0, u64::MAX, 0, false);
+    for field_id in 0..WIDE_FIELDS {
+        cache.insert_raw(0, field_id, 0, ValueType::Integer, &0_i64.to_be_bytes()).unwrap();
+    }
+    cache
+}
+
+fn insert_one_field(cache: &mut MemCache, hints: Option<&mut FieldHints>) {
+    match hints {
+        Some(hints) => {
+            for i in 1..=POINTS {
+                cache.insert_raw_hinted(hints, 0, 0, i, ValueType::Integer, &i.to_be_bytes())
+                     .unwrap();
+            }
+        },
+        None => {
+            for i in 1..=POINTS {
+                cache.insert_raw(0, 0, i, ValueType::Integer, &i.to_be_bytes()).unwrap();
+            }
+        },
+    }
+}
+
+fn scan(cache: &dyn MemCacheTrait) -> usize {
+    let time_range = TimeRange { min_ts: i64::MIN, max_ts: i64::MAX };
+    (0..FIELDS).map(|field_id| cache.read(field_id, &time_range).len()).sum()
+}
+
+fn bench_memcache<C, F>(c: &mut Criterion, name: &str, new_cache: F)
+    where C: MemCacheTrait + 'static,
+          F: Fn() -> C
+{
+    c.bench_function(&format!("{}_insert", name), |b| {
+         b.iter_batched(&new_cache, |mut cache| insert(&mut cache), BatchSize::LargeInput)
+     });
+    c.bench_function(&format!("{}_insert_{}_threads", name, THREADS), |b| {
+         b.iter_batched(&new_cache, insert_concurrently, BatchSize::LargeInput)
+     });
+
+    let mut cache = new_cache();
+    insert(&mut cache);
+    c.bench_function(&format!("{}_sorted_scan", name), |b| b.iter(|| scan(&cache)));
+}
+
+fn memcache(c: &mut Criterion) {
+    bench_memcache(c, "hashmap", || MemCache::new(0, u64::MAX, 0, false));
+    c.bench_function("hashmap_insert_one_field", |b| {
+         b.iter_batched(new_wide_cache,
+                        |mut cache| insert_one_field(&mut cache, None),
+                        BatchSize::LargeInput)
+     });
+    c.bench_function("hashmap_insert_one_field_hinted", |b| {
+         b.iter_batched(new_wide_cache,
+                        |mut cache| insert_one_field(&mut cache, Some(&mut FieldHints::default())),
+                        BatchSize::LargeInput)
+     });
+    #[cfg(feature = "skiplist")]
+    bench_memcache(c, "skiplist", || SkipListCache::new(0, u64::MAX, 0, false));
+}
+
+criterion_group!(benches, memcache);
+criterion_main!(benches);
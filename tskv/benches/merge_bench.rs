@@ -0,0 +1,54 @@
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use models::ValueType;
+use tskv::{DataBlock, DataCell, DataType, MergeStream};
+
+// the points merged by the benchmarks varying the number of sources
+const TOTAL_POINTS: i64 = 1_000_000;
+
+fn sources(num: i64, points: i64) -> Vec<Vec<DataType>> {
+    (0..num).map(|s| {
+                (0..points).map(|i| DataType::I64(DataCell { ts: i * num + s, val: i })).collect()
+            })
+            .collect()
+}
+
+fn block(points: &[DataType]) -> DataBlock {
+    let mut block = DataBlock::new(points.len(), ValueType::Integer);
+    for point in points {
+        block.insert(point.clone());
+    }
+    block
+}
+
+fn merge_stream(c: &mut Criterion) {
+    // 200 sources of 10k points each
+    let data = sources(200, 10_000);
+    c.bench_function("merge_stream", |b| {
+         b.iter_batched(|| data.iter().map(|s| s.clone().into_iter()).collect::<Vec<_>>(),
+                        |sources| MergeStream::new(sources).count(),
+                        BatchSize::LargeInput)
+     });
+}
+
+fn merge_sources(c: &mut Criterion) {
+    let mut group = c.benchmark_group("merge_sources");
+    for num in [2, 20, 200] {
+        let data = sources(num, TOTAL_POINTS / num);
+        group.bench_with_input(BenchmarkId::new("merge_stream", num), &data, |b, data| {
+                 b.iter_batched(|| data.iter().map(|s| s.clone().into_iter()).collect::<Vec<_>>(),
+                                |sources| MergeStream::new(sources).count(),
+                                BatchSize::LargeInput)
+             });
+
+        let blocks: Vec<DataBlock> = data.iter().map(|s| block(s)).collect();
+        group.bench_with_input(BenchmarkId::new("merge_blocks", num), &blocks, |b, blocks| {
+                 b.iter_batched(|| blocks.clone(),
+                                |blocks| DataBlock::merge_blocks(blocks, ValueType::Integer).len(),
+                                BatchSize::LargeInput)
+             });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, merge_stream, merge_sources);
+criterion_main!(benches);
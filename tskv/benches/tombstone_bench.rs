@@ -0,0 +1,47 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use tskv::{DataBlock, TombstoneIndex};
+
+const RANGES: i64 = 1_000;
+const POINTS: i64 = 1_000_000;
+
+fn block() -> DataBlock {
+    DataBlock::I64 { index: 0,
+                     ts: (0..POINTS).collect(),
+                     val: (0..POINTS).collect(),
+                     validity: None }
+}
+
+// 1k ranges of 100 points spread over the block
+fn ranges() -> Vec<(i64, i64)> {
+    let step = POINTS / RANGES;
+    (0..RANGES).map(|i| (i * step, i * step + 99)).collect()
+}
+
+fn tombstone_filter(c: &mut Criterion) {
+    let ranges = ranges();
+    c.bench_function("tombstone_naive_filter", |b| {
+         b.iter_batched(block,
+                        |block| {
+                            block.ts()
+                                 .iter()
+                                 .filter(|t| {
+                                     !ranges.iter().any(|(min, max)| min <= *t && *t <= max)
+                                 })
+                                 .count()
+                        },
+                        BatchSize::LargeInput)
+     });
+
+    let index = TombstoneIndex::new(ranges.clone());
+    c.bench_function("tombstone_index_filter", |b| {
+         b.iter_batched(block,
+                        |mut block| {
+                            index.filter(&mut block);
+                            block.len()
+                        },
+                        BatchSize::LargeInput)
+     });
+}
+
+criterion_group!(benches, tombstone_filter);
+criterion_main!(benches);
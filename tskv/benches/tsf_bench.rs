@@ -0,0 +1,146 @@
+use std::{collections::HashMap, sync::Arc};
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use models::ValueType;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use tokio::{runtime::Runtime, sync::RwLock};
+use tskv::{
+    bench::{
+        flush_cache, make_tsm_file_name, new_memcache, write_blocks, CompactMeta, LevelInfo,
+        TseriesFamily, Version,
+    },
+    kv_option::{MemCacheImpl, ReadOptions, TseriesFamOpt},
+    DataBlock, MemCache, MemCacheTrait, TimeRange,
+};
+
+const SEED: u64 = 42;
+const FLUSH_CACHE_BYTES: u64 = 64 * 1024 * 1024;
+const FLUSH_FIELDS: u64 = 100;
+// 1 GB of 16 bytes points, 64 fields spread over 16 files of consecutive time ranges
+const SCAN_TF_ID: u32 = 900;
+const SCAN_FILES: u64 = 16;
+const SCAN_FIELDS: u64 = 64;
+const SCAN_POINTS: i64 = 1024 * 1024;
+// a fragmented tseries family, 2000 files of 64 points of one field
+const FRAGMENTED_TF_ID: u32 = 901;
+const FRAGMENTED_FILES: u64 = 2000;
+const FRAGMENTED_POINTS: i64 = 64;
+
+fn random_cache() -> MemCache {
+    let mut rng = StdRng::seed_from_u64(SEED);
+    let mut cache = MemCache::new(0, u64::MAX, 0, false);
+    let mut ts = 0;
+    while cache.summary().bytes < FLUSH_CACHE_BYTES {
+        for field_id in 0..FLUSH_FIELDS {
+            let val: i64 = rng.gen_range(0..1000);
+            cache.insert_raw(0, field_id, ts, ValueType::Integer, &val.to_be_bytes()).unwrap();
+        }
+        ts += 1;
+    }
+    cache
+}
+
+fn flush(c: &mut Criterion) {
+    let cache = random_cache();
+    let dir = tempfile::tempdir().unwrap();
+    let mut group = c.benchmark_group("flush");
+    group.sample_size(10);
+    group.bench_function("flush_64mb_cache", |b| {
+             b.iter_batched(|| make_tsm_file_name(dir.path().to_str().unwrap(), 1),
+                            |path| flush_cache(&cache, path).unwrap(),
+                            BatchSize::PerIteration)
+         });
+    group.finish();
+}
+
+fn build_tseries_family(opt: &TseriesFamOpt, dir: &str) -> LevelInfo {
+    let mut rng = StdRng::seed_from_u64(SEED);
+    let points = SCAN_POINTS / SCAN_FILES as i64;
+    let mut lvl = LevelInfo::init_in(&opt.base_dir, 1);
+    for file_id in 1..=SCAN_FILES {
+        let ts_min = (file_id - 1) as i64 * points;
+        let mut block_set = HashMap::new();
+        for field_id in 0..SCAN_FIELDS {
+            let ts = (ts_min..ts_min + points).collect();
+            let val = (0..points).map(|_| rng.gen_range(0..1000)).collect();
+            block_set.insert(field_id, DataBlock::I64 { index: 0, ts, val, validity: None });
+        }
+        let file_size = write_blocks(make_tsm_file_name(dir, file_id), block_set).unwrap();
+        lvl.apply(&CompactMeta { file_id,
+                                 file_size,
+                                 range: TimeRange::new(ts_min + points - 1, ts_min),
+                                 level: 1,
+                                 ..Default::default() });
+    }
+    lvl
+}
+
+fn scan(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let tmp = tempfile::tempdir().unwrap();
+    let opt = TseriesFamOpt::for_testing(tmp.path());
+    let dir = opt.tsm_dir(SCAN_TF_ID);
+    std::fs::create_dir_all(&dir).unwrap();
+    let lvl = build_tseries_family(&opt, &dir);
+    let version = Version::new(SCAN_TF_ID, 0, "db".to_string(), vec![lvl], 0);
+    let cache = new_memcache(MemCacheImpl::HashMap, SCAN_TF_ID, u64::MAX, 0, false);
+    let tsf = rt.block_on(TseriesFamily::new(SCAN_TF_ID,
+                                             "db".to_string(),
+                                             cache,
+                                             Arc::new(RwLock::new(version)),
+                                             opt));
+
+    let time_range = TimeRange { min_ts: i64::MIN, max_ts: i64::MAX };
+    let mut group = c.benchmark_group("scan");
+    group.sample_size(10);
+    group.bench_function("scan_field_of_1gb_tseries_family", |b| {
+             b.iter(|| rt.block_on(tsf.scan(SCAN_FIELDS / 2, &time_range)).unwrap().len())
+         });
+    group.finish();
+}
+
+// compares the pool of 8 threads reading the files of a scan with a thread spawned for every
+// file, and with the files read one by one
+fn scan_fragmented(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let tmp = tempfile::tempdir().unwrap();
+    let opt = TseriesFamOpt::for_testing(tmp.path());
+    let dir = opt.tsm_dir(FRAGMENTED_TF_ID);
+    std::fs::create_dir_all(&dir).unwrap();
+    let mut lvl = LevelInfo::init_in(&opt.base_dir, 1);
+    for file_id in 1..=FRAGMENTED_FILES {
+        let ts_min = (file_id - 1) as i64 * FRAGMENTED_POINTS;
+        let ts = (ts_min..ts_min + FRAGMENTED_POINTS).collect();
+        let val = vec![file_id as i64; FRAGMENTED_POINTS as usize];
+        let block_set = HashMap::from([(0, DataBlock::I64 { index: 0, ts, val, validity: None })]);
+        let file_size = write_blocks(make_tsm_file_name(&dir, file_id), block_set).unwrap();
+        lvl.apply(&CompactMeta { file_id,
+                                 file_size,
+                                 range: TimeRange::new(ts_min + FRAGMENTED_POINTS - 1, ts_min),
+                                 level: 1,
+                                 ..Default::default() });
+    }
+    let version = Version::new(FRAGMENTED_TF_ID, 0, "db".to_string(), vec![lvl], 0);
+    let cache = new_memcache(MemCacheImpl::HashMap, FRAGMENTED_TF_ID, u64::MAX, 0, false);
+    let tsf = rt.block_on(TseriesFamily::new(FRAGMENTED_TF_ID,
+                                             "db".to_string(),
+                                             cache,
+                                             Arc::new(RwLock::new(version)),
+                                             opt));
+
+    let time_range = TimeRange { min_ts: i64::MIN, max_ts: i64::MAX };
+    let mut group = c.benchmark_group("scan_fragmented");
+    group.sample_size(10);
+    for (name, read_parallelism) in
+        [("serial", 1), ("pool_of_8", 8), ("thread_per_file", FRAGMENTED_FILES as usize)]
+    {
+        let read_opts = ReadOptions { read_parallelism, ..Default::default() };
+        group.bench_function(format!("scan_2000_files_{}", name), |b| {
+                 b.iter(|| rt.block_on(tsf.scan_with(0, &time_range, &read_opts)).0.len())
+             });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, flush, scan, scan_fragmented);
+criterion_main!(benches);
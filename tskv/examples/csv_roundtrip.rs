@@ -0,0 +1,146 @@
+//! Ingests a csv of `ts,field,value` rows into a database in a temporary directory, flushes it,
+//! queries it back and deletes a time range, only through the public api of the crate.
+//!
+//! The global config is read from `../config/config.toml`, so run it in the `tskv` directory:
+//! `cargo run --example csv_roundtrip`. It also runs with `cargo test --examples`. The column
+//! files are written under the `base_dir` of the global config.
+
+use models::{generate_field_id, generate_series_id, FieldId, SeriesId, Tag};
+use protos::{
+    kv_service::WritePointsRpcRequest,
+    models::{FieldType, Points, PointsArgs},
+    models_helper,
+};
+use tskv::{DataType, Options, TimeRange, TsKv};
+
+const CSV: &str = "ts,field,value
+1000,cpu,10.0
+1000,mem,512.0
+2000,cpu,20.0
+2000,mem,640.0
+3000,cpu,30.0
+3000,mem,768.0
+4000,cpu,40.0
+4000,mem,896.0
+";
+
+const HOST: (&str, &str) = ("host", "example");
+
+/// Parses the rows of the csv, skipping the header.
+fn parse_csv(csv: &str) -> Vec<(i64, String, f64)> {
+    csv.lines()
+       .skip(1)
+       .filter(|line| !line.trim().is_empty())
+       .map(|line| {
+           let cols: Vec<&str> = line.split(',').map(str::trim).collect();
+           assert_eq!(cols.len(), 3, "invalid row: {}", line);
+           (cols[0].parse().unwrap(), cols[1].to_string(), cols[2].parse().unwrap())
+       })
+       .collect()
+}
+
+/// Builds a write request with a point of the series for each row.
+fn build_request(rows: &[(i64, String, f64)]) -> WritePointsRpcRequest {
+    let mut fbb = flatbuffers::FlatBufferBuilder::new();
+    let mut points = vec![];
+    for (ts, field, value) in rows {
+        let tags = models_helper::create_tags(&mut fbb, vec![HOST]);
+        let value = value.to_be_bytes();
+        let fields = models_helper::create_fields(&mut fbb,
+                                                  vec![(field.as_str(),
+                                                        FieldType::Float,
+                                                        value.as_slice())]);
+        points.push(models_helper::create_point(&mut fbb, *ts, tags, fields));
+    }
+    let points = fbb.create_vector(&points);
+    let points = Points::create(&mut fbb, &PointsArgs { points: Some(points) });
+    fbb.finish(points, None);
+    WritePointsRpcRequest { version: 1,
+                            database: "db".to_string(),
+                            points: fbb.finished_data().to_vec(),
+                            request_id: 0 }
+}
+
+fn series_id() -> SeriesId {
+    generate_series_id(&[Tag::new(HOST.0.as_bytes().to_vec(), HOST.1.as_bytes().to_vec())])
+}
+
+fn field_id(name: &str) -> FieldId {
+    generate_field_id(&name.as_bytes().to_vec(), series_id())
+}
+
+fn points(data: &[DataType]) -> Vec<(i64, f64)> {
+    data.iter()
+        .map(|d| match d {
+            DataType::F64(cell) => (cell.ts, cell.val),
+            other => panic!("unexpected value {:?}", other),
+        })
+        .collect()
+}
+
+/// Returns (count, sum, min, max) of the values.
+fn aggregate(data: &[DataType]) -> (usize, f64, f64, f64) {
+    points(data).iter().fold((0, 0.0, f64::MAX, f64::MIN), |(count, sum, min, max), (_, v)| {
+                           (count + 1, sum + v, min.min(*v), max.max(*v))
+                       })
+}
+
+async fn run() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = |name: &str| dir.path().join(name).to_string_lossy().to_string();
+    let mut opt = Options::default();
+    opt.db.db_path = path("db");
+    opt.wal.dir = path("wal");
+    opt.forward_index_conf.path = dir.path().join("tskv.fidx");
+    opt.schema_store.dir = path("schema");
+    let tskv = TsKv::open(opt).await.unwrap();
+
+    let rows = parse_csv(CSV);
+    tskv.write(build_request(&rows)).await.unwrap();
+
+    let all = TimeRange::new(i64::MAX, i64::MIN);
+    let (sid, cpu, mem) = (series_id(), field_id("cpu"), field_id("mem"));
+    let cached = tskv.scan(sid, cpu, &all).await.unwrap();
+    assert_eq!(points(&cached), vec![(1000, 10.0), (2000, 20.0), (3000, 30.0), (4000, 40.0)]);
+
+    // the queries after the flush are answered by the column files
+    tskv.flush().await.unwrap();
+    for dump in tskv.debug_dump_all().available().unwrap() {
+        assert_eq!(dump.mut_cache.available().unwrap().cells, 0);
+        assert!(dump.immut_caches.is_empty());
+    }
+
+    let range = tskv.scan(sid, cpu, &TimeRange::new(3000, 2000)).await.unwrap();
+    assert_eq!(points(&range), vec![(2000, 20.0), (3000, 30.0)]);
+    println!("cpu in [2000, 3000]: {:?}", points(&range));
+
+    let (count, sum, min, max) = aggregate(&tskv.scan(sid, mem, &all).await.unwrap());
+    assert_eq!((count, sum, min, max), (4, 2816.0, 512.0, 896.0));
+    println!("mem: count={} sum={} min={} max={} mean={}",
+             count,
+             sum,
+             min,
+             max,
+             sum / count as f64);
+
+    // deletes every field of the series in the range
+    tskv.delete_series(vec![sid], 1500, 3500).await.unwrap();
+    let cpu_left = tskv.scan(sid, cpu, &all).await.unwrap();
+    assert_eq!(points(&cpu_left), vec![(1000, 10.0), (4000, 40.0)]);
+    let mem_left = tskv.scan(sid, mem, &all).await.unwrap();
+    assert_eq!(points(&mem_left), vec![(1000, 512.0), (4000, 896.0)]);
+    println!("after the delete: cpu={:?} mem={:?}", points(&cpu_left), points(&mem_left));
+}
+
+#[tokio::main]
+async fn main() {
+    run().await;
+}
+
+#[cfg(test)]
+mod test {
+    #[tokio::test]
+    async fn test_csv_roundtrip() {
+        super::run().await;
+    }
+}
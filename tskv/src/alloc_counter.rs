@@ -0,0 +1,57 @@
+//! The allocator of the tests, it counts the bytes allocated by a thread while it measures
+//! them, so that the tests can bound the memory held by a decoder.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+};
+
+struct CountingAlloc;
+
+thread_local! {
+    // the bytes held since the measure started and their peak, None while not measuring
+    static MEASURE: Cell<Option<(isize, isize)>> = const { Cell::new(None) };
+}
+
+fn track(delta: isize) {
+    let _ = MEASURE.try_with(|measure| {
+                       if let Some((held, peak)) = measure.get() {
+                           let held = held + delta;
+                           measure.set(Some((held, peak.max(held))));
+                       }
+                   });
+}
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        track(layout.size() as isize);
+        System.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        track(layout.size() as isize);
+        System.alloc_zeroed(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        track(-(layout.size() as isize));
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        track(new_size as isize - layout.size() as isize);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOC: CountingAlloc = CountingAlloc;
+
+/// Runs `f` and returns its result, with the most bytes the current thread held allocated at
+/// once meanwhile, the result included.
+pub fn peak_bytes<T>(f: impl FnOnce() -> T) -> (T, usize) {
+    MEASURE.with(|measure| measure.set(Some((0, 0))));
+    let res = f();
+    let (_, peak) = MEASURE.with(|measure| measure.replace(None)).unwrap_or_default();
+    (res, peak as usize)
+}
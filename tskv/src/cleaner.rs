@@ -0,0 +1,224 @@
+use std::{
+    collections::HashSet,
+    fs, io,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use logger::{info, warn};
+use tokio::sync::RwLock;
+
+use crate::{
+    compaction::LogEvent, file_utils, kv_option::CleanerConfig, tseries_family::Version,
+    version_set::VersionSet,
+};
+
+/// The files deleted by a cleaning.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct CleanStats {
+    // files marked removed and held by no reader any more
+    pub removed: usize,
+    // files of the directories of a family held by no file of its version
+    pub orphans: usize,
+    pub bytes: u64,
+}
+
+/// Deletes the files the versions of every tseries family no longer need, see
+/// `clean_version`. The files are taken out of the versions under their locks, and deleted on
+/// a blocking thread after the locks are released.
+pub async fn clean_once(version_set: &RwLock<VersionSet>,
+                        config: &CleanerConfig,
+                        now: u64)
+                        -> CleanStats {
+    let mut plans = vec![];
+    for tsf in version_set.read().await.tsfamilies() {
+        let mut version = tsf.version().write().await;
+        plans.push(CleanPlan::new(tsf.tf_id(), &mut version, config.safety_margin_secs, now));
+    }
+    let clean = move || {
+        let mut stats = CleanStats::default();
+        for plan in plans {
+            plan.run(&mut stats);
+        }
+        stats
+    };
+    let stats = tokio::task::spawn_blocking(clean).await.expect("cleaning panicked");
+    if stats.removed + stats.orphans > 0 {
+        info!("{}",
+              LogEvent::new("clean_done").field("removed", stats.removed)
+                                         .field("orphans", stats.orphans)
+                                         .field("bytes", stats.bytes));
+    }
+    stats
+}
+
+/// Deletes the files marked removed at least `margin_secs` before `now` that nothing but the
+/// version holds, with their tombstones, and takes them out of the version. Then deletes the
+/// files of the tsm and delta directories of the family older than the margin that are
+/// neither a file of the version nor its tombstone: the outputs of a flush or a compaction
+/// interrupted by a crash, the copies left by an interrupted migration.
+///
+/// A file failing to be deleted is logged and left for the next cleaning.
+pub fn clean_version(tf_id: u32,
+                     version: &mut Version,
+                     margin_secs: u64,
+                     now: u64,
+                     stats: &mut CleanStats) {
+    CleanPlan::new(tf_id, version, margin_secs, now).run(stats)
+}
+
+/// The files of a tseries family a cleaning deletes, see `clean_version`, taken from its
+/// version under the lock and deleted by `run` without it.
+pub struct CleanPlan {
+    // the paths of the files taken out of the version, with their tombstones
+    removed: Vec<[String; 2]>,
+    // the files of the version and their tombstones, kept in the directories
+    kept: HashSet<PathBuf>,
+    dirs: [PathBuf; 2],
+    cutoff: u64,
+}
+
+impl CleanPlan {
+    pub fn new(tf_id: u32, version: &mut Version, margin_secs: u64, now: u64) -> Self {
+        let cutoff = now.saturating_sub(margin_secs);
+        let removed = version.take_unreferenced(cutoff)
+                             .iter()
+                             .map(|file| [file.path(tf_id), file.tombstone_path(tf_id)])
+                             .collect();
+        let files = version.levels_info.iter().flat_map(|info| info.files.iter());
+        let kept = files.flat_map(|file| [file.path(tf_id), file.tombstone_path(tf_id)])
+                        .map(PathBuf::from)
+                        .collect();
+        let dirs = [file_utils::make_tsm_dir(&version.base_dir, tf_id),
+                    file_utils::make_delta_dir(&version.base_dir, tf_id)];
+        Self { removed, kept, dirs, cutoff }
+    }
+
+    /// Deletes the files, a file written after the plan is younger than the margin and kept.
+    pub fn run(self, stats: &mut CleanStats) {
+        for paths in self.removed.iter() {
+            for path in paths.iter() {
+                match remove(Path::new(path)) {
+                    Ok(bytes) => stats.bytes += bytes,
+                    Err(e) => warn!("failed to delete removed file {}: {:?}", path, e),
+                }
+            }
+            stats.removed += 1;
+        }
+
+        for dir in self.dirs.iter() {
+            let entries = match fs::read_dir(dir) {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => {
+                    warn!("failed to list {}: {:?}", dir.display(), e);
+                    continue;
+                },
+            };
+            for path in entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()) {
+                if self.kept.contains(&path) || !is_stale_file(&path, self.cutoff) {
+                    continue;
+                }
+                match remove(&path) {
+                    Ok(bytes) => {
+                        stats.orphans += 1;
+                        stats.bytes += bytes;
+                    },
+                    Err(e) => warn!("failed to delete orphan file {}: {:?}", path.display(), e),
+                }
+            }
+        }
+    }
+}
+
+// whether the path is a file last modified at or before `cutoff`, in seconds since the epoch
+fn is_stale_file(path: &Path, cutoff: u64) -> bool {
+    let modified = fs::metadata(path).and_then(|meta| {
+                                         if !meta.is_file() {
+                                             return Ok(u64::MAX);
+                                         }
+                                         let modified = meta.modified()?;
+                                         Ok(modified.duration_since(UNIX_EPOCH)
+                                                    .map(|d| d.as_secs())
+                                                    .unwrap_or(0))
+                                     });
+    matches!(modified, Ok(modified) if modified <= cutoff)
+}
+
+// deletes the file and returns its size, 0 if there is no such file
+fn remove(path: &Path) -> io::Result<u64> {
+    let len = match fs::metadata(path) {
+        Ok(meta) => meta.len(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+    fs::remove_file(path)?;
+    Ok(len)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, path::Path};
+
+    use super::{clean_version, CleanStats};
+    use crate::{file_utils, summary::CompactMeta, trash, tseries_family::Version};
+
+    #[test]
+    fn test_clean_version() {
+        let dir = tempfile::tempdir().unwrap();
+        let base_dir = dir.path().to_string_lossy().to_string();
+        let tf_id = 1;
+        let mut version = Version::new(tf_id, 0, "test".to_string(), vec![], i64::MIN);
+        version.base_dir = base_dir.clone();
+        let tsm_dir = file_utils::make_tsm_dir(&base_dir, tf_id);
+        fs::create_dir_all(&tsm_dir).unwrap();
+        let mut files = vec![];
+        for file_id in 1..=3 {
+            let meta = CompactMeta { file_id,
+                                     file_size: 4,
+                                     tsf_id: tf_id,
+                                     level: 1,
+                                     ..CompactMeta::new() };
+            let file = version.apply_file(&meta).unwrap();
+            fs::write(file.path(tf_id), b"data").unwrap();
+            files.push(file);
+        }
+        let paths: Vec<_> = files.iter().map(|file| file.path(tf_id)).collect();
+        let tombstone = files[0].tombstone_path(tf_id);
+        fs::write(&tombstone, b"tomb").unwrap();
+        // the files 1 and 2 are removed by a compaction, 2 is still read
+        files[0].mark_removed();
+        files[1].mark_removed();
+        let reader = files[1].clone();
+        drop(files);
+        // a temp file of no version
+        let tmp = tsm_dir.join("_000009.tmp");
+        fs::write(&tmp, b"tmp").unwrap();
+
+        // nothing is deleted within the safety margin
+        let now = trash::now_secs();
+        let mut stats = CleanStats::default();
+        clean_version(tf_id, &mut version, 60, now, &mut stats);
+        assert_eq!(stats, CleanStats::default());
+        assert!(tmp.exists());
+
+        // the removed file held by the version only is deleted, the one still read is kept
+        let mut stats = CleanStats::default();
+        clean_version(tf_id, &mut version, 60, now + 120, &mut stats);
+        assert_eq!(stats, CleanStats { removed: 1, orphans: 1, bytes: 4 + 4 + 3 });
+        assert!(!Path::new(&paths[0]).exists());
+        assert!(!Path::new(&tombstone).exists());
+        assert!(!tmp.exists());
+        assert!(Path::new(&paths[1]).exists());
+        assert!(Path::new(&paths[2]).exists());
+        assert_eq!(version.live_files().len(), 1);
+
+        // deleted once its reader is done
+        drop(reader);
+        let mut stats = CleanStats::default();
+        clean_version(tf_id, &mut version, 60, now + 120, &mut stats);
+        assert_eq!(stats, CleanStats { removed: 1, orphans: 0, bytes: 4 });
+        assert!(!Path::new(&paths[1]).exists());
+        assert!(Path::new(&paths[2]).exists());
+    }
+}
@@ -0,0 +1,43 @@
+use chrono::{Duration, Local, NaiveDateTime};
+use parking_lot::Mutex;
+
+/// The source of the local time of the background tasks, a `MockClock` in the tests.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> NaiveDateTime;
+}
+
+/// The local time of the system.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> NaiveDateTime {
+        Local::now().naive_local()
+    }
+}
+
+/// A clock standing at the time it is set to.
+#[derive(Debug)]
+pub struct MockClock {
+    now: Mutex<NaiveDateTime>,
+}
+
+impl MockClock {
+    pub fn new(now: NaiveDateTime) -> Self {
+        Self { now: Mutex::new(now) }
+    }
+
+    pub fn set(&self, now: NaiveDateTime) {
+        *self.now.lock() = now;
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock() += duration;
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> NaiveDateTime {
+        *self.now.lock()
+    }
+}
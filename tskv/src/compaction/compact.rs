@@ -1,10 +1,760 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use logger::{info, warn};
+use parking_lot::Mutex;
+use tokio::sync::{mpsc::UnboundedSender, RwLock};
 
+use super::{filter::apply_filter, flush::write_tsm_chunks, DiskSpace, LogEvent};
 use crate::{
-    compaction::CompactReq, context::GlobalContext, error::Result, summary::VersionEdit,
-    tseries_family::ColumnFile,
+    compaction::{CompactReq, CompactionScheduler},
+    context::GlobalContext,
+    direct_io::IoClass,
+    error::Result,
+    file_utils::make_tsm_file_name,
+    kv_option::TseriesFamOpt,
+    summary::{self, CompactMeta, SummaryTask, VersionEdit},
+    tseries_family::{ColumnFile, TimeRange},
+    tsm::{DataBlock, TsmIndexReader, MAX_BLOCK_VALUES},
+    version_set::VersionSet,
 };
 
-pub async fn run_compaction_job(request: CompactReq, kernel: Arc<GlobalContext>) -> Result<()> {
-    Ok(())
+/// What a compaction read and wrote, the cells are counted after the tombstones are applied.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct CompactionReport {
+    pub level: u32,
+    pub files_in: usize,
+    pub files_out: usize,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    // the bytes read less the bytes written, negative if the output is larger
+    pub bytes_saved: i64,
+    pub cells_in: usize,
+    pub cells_out: usize,
+    pub duration: Duration,
+}
+
+/// The sum of the reports of the compactions run by the engine.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct CompactionTotals {
+    pub compactions: u64,
+    pub files_in: u64,
+    pub files_out: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub cells_in: u64,
+    pub cells_out: u64,
+    pub duration: Duration,
+}
+
+#[derive(Debug, Default)]
+pub struct CompactionMetrics {
+    totals: Mutex<CompactionTotals>,
+}
+
+impl CompactionMetrics {
+    pub fn record(&self, report: &CompactionReport) {
+        let mut totals = self.totals.lock();
+        totals.compactions += 1;
+        totals.files_in += report.files_in as u64;
+        totals.files_out += report.files_out as u64;
+        totals.bytes_read += report.bytes_read;
+        totals.bytes_written += report.bytes_written;
+        totals.cells_in += report.cells_in as u64;
+        totals.cells_out += report.cells_out as u64;
+        totals.duration += report.duration;
+    }
+
+    pub fn totals(&self) -> CompactionTotals {
+        self.totals.lock().clone()
+    }
+}
+
+/// Merges the files of the request into one file of the output level, returns the edit that
+/// adds the new file and deletes the merged ones, and the report of the compaction, which is
+/// also recorded in the metrics of the context.
+///
+/// If the disk cannot hold the output, only the oldest files that fit are merged, and nothing
+/// is done if fewer than two of them fit.
+pub async fn run_compaction_job(request: CompactReq,
+                                kernel: Arc<GlobalContext>,
+                                space: &DiskSpace)
+                                -> Result<Option<(VersionEdit, CompactionReport)>> {
+    let CompactReq { files: (level, mut files), version, cf: tf_id, out_lvl, opts } = request;
+    if files.is_empty() {
+        return Ok(None);
+    }
+    // the files written earlier hold the older data
+    files.sort_by_key(|f| f.file_id());
+
+    let path = opts.tsm_dir(tf_id);
+    if let Some(available) = space.available(Path::new(&path)) {
+        let need = |files: &[Arc<ColumnFile>]| {
+            space.compact_ratio().estimate(files.iter().map(|f| f.size()).sum())
+        };
+        // the newer files left behind still shadow the merged ones
+        let mut count = files.len();
+        while count >= 2 && need(&files[..count]) > available {
+            count -= 1;
+        }
+        if count < files.len() {
+            let event = if count < 2 { "compaction_skipped" } else { "compaction_subset" };
+            warn!("{}",
+                  LogEvent::new(event).field("tf_id", tf_id)
+                                      .field("level", level)
+                                      .field("files_in", files.len())
+                                      .field("files_fit", count)
+                                      .field("need", need(&files[..]))
+                                      .field("available", available));
+            let left = if count < 2 { 0 } else { count };
+            for file in files.drain(left..) {
+                file.unmark_compaction();
+            }
+            if files.is_empty() {
+                return Ok(None);
+            }
+        }
+    }
+    info!("{}",
+          LogEvent::new("compaction_start").field("tf_id", tf_id)
+                                           .field("level", level)
+                                           .field("out_level", out_lvl)
+                                           .field("files_in", files.len())
+                                           .field("levels", version.summary()));
+    let start = Instant::now();
+    let report = CompactionReport { level, files_in: files.len(), ..Default::default() };
+    // the files are read and written with low priority, which waits for the high priority
+    // writes, so on a blocking thread rather than on the runtime
+    let job = {
+        let (opts, kernel) = (opts.clone(), kernel.clone());
+        move || {
+            let mut report = report;
+            let res = merge_files(tf_id, &files, out_lvl, &opts, &path, &kernel, &mut report);
+            (files, report, res)
+        }
+    };
+    let (files, mut report, output) =
+        tokio::task::spawn_blocking(job).await.expect("compaction panicked");
+    let output = output?;
+
+    let mut edit = VersionEdit::new();
+    for file in files.iter() {
+        edit.del_file(tf_id,
+                      CompactMeta { file_id: file.file_id(),
+                                    file_size: file.size(),
+                                    range: *file.range(),
+                                    level,
+                                    is_delta: file.is_delta(),
+                                    ..CompactMeta::new() });
+    }
+    // the output holds the deletes applied while reading, a restore to a point before them
+    // must skip it
+    let mut seq = version.last_seq;
+    for file in files.iter() {
+        seq = seq.max(file.tombstone_seq(tf_id)?);
+    }
+    let mut bytes = 0;
+    if let Some((file_id, len, range)) = output {
+        bytes = len;
+        // the output holds the writes of every file, unknown if a file does not record them
+        let (low_seq, high_seq) =
+            files.iter()
+                 .map(|f| f.seq_range())
+                 .fold((u64::MAX, 0), |(low, high), (l, h)| (low.min(l), high.max(h)));
+        let meta = CompactMeta { file_id,
+                                 file_size: bytes,
+                                 range,
+                                 level: out_lvl,
+                                 high_seq,
+                                 low_seq,
+                                 ..CompactMeta::new() };
+        edit.add_file(out_lvl, tf_id, file_id, seq, version.max_level_ts, meta);
+        report.files_out = 1;
+    }
+    report.bytes_read = files.iter().map(|f| f.size()).sum();
+    report.bytes_written = bytes;
+    report.bytes_saved = report.bytes_read as i64 - bytes as i64;
+    report.duration = start.elapsed();
+    space.compact_ratio().observe(report.bytes_read, bytes);
+    kernel.compaction_metrics().record(&report);
+    info!("{}",
+          LogEvent::new("compaction_done").field("tf_id", tf_id)
+                                          .field("level", level)
+                                          .field("out_level", out_lvl)
+                                          .field("files_in", report.files_in)
+                                          .field("files_out", report.files_out)
+                                          .field("bytes_read", report.bytes_read)
+                                          .field("bytes_written", report.bytes_written)
+                                          .field("bytes_saved", report.bytes_saved)
+                                          .field("cells_in", report.cells_in)
+                                          .field("cells_out", report.cells_out)
+                                          .field("duration_ms", report.duration.as_millis()));
+    Ok(Some((edit, report)))
+}
+
+// merges the files into one file of the output level written under the directory, returns the
+// id, the size and the time range of the file, None if no point is left
+fn merge_files(tf_id: u32,
+               files: &[Arc<ColumnFile>],
+               out_lvl: u32,
+               opts: &TseriesFamOpt,
+               path: &str,
+               kernel: &GlobalContext,
+               report: &mut CompactionReport)
+               -> Result<Option<(u64, u64, TimeRange)>> {
+    let mut field_types = BTreeMap::new();
+    for file in files.iter() {
+        let (mut fs_cursor, len) = file.file_reader(tf_id)?;
+        for entry in TsmIndexReader::try_new(&mut fs_cursor, len as usize)? {
+            let entry = entry?;
+            field_types.insert(entry.field_id(), entry.block.field_type);
+        }
+    }
+
+    let mut range = TimeRange::none();
+    let mut block_set = HashMap::new();
+    for (field_id, field_type) in field_types {
+        let mut blocks = Vec::with_capacity(files.len());
+        for file in files.iter() {
+            // tombstones are applied while reading, the blocks are read whole and keep their
+            // null points, the later block holds the newer writes; the blocks of another type
+            // than the latest one of the field are dropped
+            for block in file.read_field_blocks(tf_id, field_id)? {
+                if block.field_type() == field_type {
+                    report.cells_in += block.len();
+                    blocks.push(block);
+                }
+            }
+        }
+        let mut chunks = vec![];
+        let merged =
+            DataBlock::merge_blocks_chunked_with(blocks, MAX_BLOCK_VALUES, opts.duplicate_policy);
+        for block in merged {
+            let block = match opts.compaction_filter.as_ref() {
+                Some(filter) => match apply_filter(filter.0.as_ref(), field_id, block)? {
+                    Some(block) => block,
+                    None => continue,
+                },
+                None => block,
+            };
+            if block.len() > 0 {
+                chunks.push(block);
+            }
+        }
+        if chunks.is_empty() {
+            continue;
+        }
+        report.cells_out += chunks.iter().map(|c| c.len()).sum::<usize>();
+        let last = &chunks[chunks.len() - 1];
+        if let (Some(min), Some(max)) = (chunks[0].ts_at(0), last.ts_at(last.len() - 1)) {
+            range = range.merge(&TimeRange::new(max, min));
+        }
+        block_set.insert(field_id, chunks);
+    }
+
+    if block_set.is_empty() {
+        return Ok(None);
+    }
+    let file_id = kernel.next_file_id();
+    let (len, stats) = write_tsm_chunks(make_tsm_file_name(path, file_id),
+                                        block_set,
+                                        IoClass::Low,
+                                        opts.duplicate_policy,
+                                        opts.compression(out_lvl))?;
+    kernel.compression_metrics().record(tf_id, &stats);
+    Ok(Some((file_id, len, range)))
+}
+
+/// Runs one compaction of the tseries family chosen by the scheduler, returns its report, or
+/// None if no family needs a compaction. The edit is written to the summary before the version
+/// of the family takes it; the inputs are left to the next compaction if either fails.
+pub async fn compact_once(version_set: &RwLock<VersionSet>,
+                          scheduler: &mut CompactionScheduler,
+                          summary_task_sender: &UnboundedSender<SummaryTask>,
+                          kernel: Arc<GlobalContext>,
+                          space: &DiskSpace)
+                          -> Result<Option<CompactionReport>> {
+    let mut cf_opts = HashMap::new();
+    let mut versions = vec![];
+    for tsf in version_set.read().await.tsfamilies() {
+        let version = tsf.version().read().await.compaction_view(tsf.options());
+        cf_opts.insert(tsf.tf_id(), tsf.options().clone());
+        versions.push((tsf.tf_id(), Arc::new(version)));
+    }
+    // the families come and go, their options may change on a restore
+    scheduler.set_family_opts(cf_opts);
+    match scheduler.next(&versions) {
+        Some(request) => {
+            commit_compaction_job(request, version_set, summary_task_sender, kernel, space).await
+        },
+        None => Ok(None),
+    }
+}
+
+// runs the compaction of the request and commits its edit, see `compact_once`
+async fn commit_compaction_job(request: CompactReq,
+                               version_set: &RwLock<VersionSet>,
+                               summary_task_sender: &UnboundedSender<SummaryTask>,
+                               kernel: Arc<GlobalContext>,
+                               space: &DiskSpace)
+                               -> Result<Option<CompactionReport>> {
+    let tf_id = request.cf;
+    let inputs = request.files.1.clone();
+    let unmark = || inputs.iter().for_each(|f| f.unmark_compaction());
+    let (edit, report) = match run_compaction_job(request, kernel, space).await {
+        Ok(Some(compacted)) => compacted,
+        Ok(None) => return Ok(None),
+        Err(e) => {
+            unmark();
+            return Err(e);
+        },
+    };
+    if let Err(e) = summary::apply_edits(summary_task_sender, vec![edit.clone()], vec![]).await {
+        unmark();
+        return Err(e);
+    }
+    // a family dropped meanwhile keeps its files as they were
+    if let Some(tsf) = version_set.read().await.tsfamilies().find(|tsf| tsf.tf_id() == tf_id) {
+        tsf.commit_compaction(&edit).await;
+    }
+    Ok(Some(report))
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::HashMap, sync::Arc};
+
+    use models::{FieldId, ValueType};
+
+    use super::run_compaction_job;
+    use crate::{
+        compaction::{
+            compact_kernel, compact_req, flush::build_tsm_file, CompactionFilter,
+            CompactionFilterRef, DiskSpace, FakeFileSystem, FilterDecision,
+        },
+        file_utils::make_tsm_file_name,
+        kv_option::{BlockCompression, DuplicatePolicy, TseriesFamOpt},
+        memcache::DataType,
+        summary::CompactMeta,
+        tseries_family::{LevelInfo, TimeRange},
+        tsm::{DataBlock, TsmIndexReader, Validity},
+    };
+
+    // halves the float values older than the cutoff
+    #[derive(Debug)]
+    struct HalveFilter {
+        cutoff: i64,
+    }
+
+    impl CompactionFilter for HalveFilter {
+        fn filter(&self, _field_id: FieldId, block: DataBlock) -> FilterDecision {
+            match block {
+                DataBlock::F64 { index, ts, val, validity } => {
+                    let val = ts.iter()
+                                .zip(val)
+                                .map(|(t, v)| if *t < self.cutoff { v / 2.0 } else { v })
+                                .collect();
+                    FilterDecision::Replace(DataBlock::F64 { index, ts, val, validity })
+                },
+                _ => FilterDecision::Keep(block),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_compaction_filter() {
+        let tmp = tempfile::tempdir().unwrap();
+        let tf_id = 101;
+        let opts =
+            TseriesFamOpt { compaction_filter:
+                                Some(CompactionFilterRef(Arc::new(HalveFilter { cutoff: 5 }))),
+                            ..TseriesFamOpt::for_testing(tmp.path()) };
+        let dir = opts.tsm_dir(tf_id);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let mut lvl = LevelInfo::init_in(&opts.base_dir, 1);
+        let inputs = vec![(1, vec![1, 2, 3, 4], vec![10.0, 20.0, 30.0, 40.0]),
+                          (2, vec![3, 4, 5, 6], vec![31.0, 41.0, 50.0, 60.0])];
+        for (file_id, ts, val) in inputs {
+            let meta = CompactMeta { file_id,
+                                     range: TimeRange::new(ts[ts.len() - 1], ts[0]),
+                                     level: 1,
+                                     ..Default::default() };
+            let mut block_set = HashMap::new();
+            block_set.insert(1, DataBlock::F64 { index: 0, ts, val, validity: None });
+            build_tsm_file(make_tsm_file_name(&dir, file_id), block_set).unwrap();
+            lvl.apply(&meta);
+        }
+
+        let kernel = compact_kernel(&lvl.files);
+        let req = compact_req(tf_id, lvl.files.clone(), 2, Arc::new(opts.clone()));
+        let (edit, _) =
+            run_compaction_job(req, kernel, &DiskSpace::default()).await.unwrap().unwrap();
+        assert_eq!(edit.del_files.len(), 2);
+        assert_eq!(edit.add_files.len(), 1);
+
+        let mut out_lvl = LevelInfo::init_in(&opts.base_dir, 2);
+        out_lvl.apply(&edit.add_files[0]);
+        let data =
+            out_lvl.files[0].read_field(tf_id, 1, &TimeRange::new(i64::MAX, i64::MIN)).unwrap();
+        let data: Vec<(i64, f64)> = data.into_iter()
+                                        .map(|d| match d {
+                                            DataType::F64(c) => (c.ts, c.val),
+                                            _ => panic!("unexpected data type"),
+                                        })
+                                        .collect();
+        assert_eq!(data, vec![(1, 5.0), (2, 10.0), (3, 15.5), (4, 20.5), (5, 50.0), (6, 60.0)]);
+    }
+
+    #[tokio::test]
+    async fn test_compaction_null_points() {
+        let tmp = tempfile::tempdir().unwrap();
+        let tf_id = 139;
+        let opts = Arc::new(TseriesFamOpt::for_testing(tmp.path()));
+        let dir = opts.tsm_dir(tf_id);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // the points at 2 and 4 are null, the newer file writes 3 again
+        let mut lvl = LevelInfo::init_in(&opts.base_dir, 1);
+        for (file_id, ts, val) in
+            [(1, vec![1, 2, 3], vec![10, 0, 30]), (2, vec![3, 4, 5], vec![31, 0, 50])]
+        {
+            let validity = Some([true, false, true].into_iter().collect::<Validity>());
+            let mut block_set = HashMap::new();
+            block_set.insert(1, DataBlock::I64 { index: 0, ts: ts.clone(), val, validity });
+            let file_size = build_tsm_file(make_tsm_file_name(&dir, file_id), block_set).unwrap();
+            lvl.apply(&CompactMeta { file_id,
+                                     file_size,
+                                     range: TimeRange::new(ts[ts.len() - 1], ts[0]),
+                                     level: 1,
+                                     ..Default::default() });
+        }
+
+        let kernel = compact_kernel(&lvl.files);
+        let req = compact_req(tf_id, lvl.files.clone(), 2, opts.clone());
+        let (edit, report) =
+            run_compaction_job(req, kernel, &DiskSpace::default()).await.unwrap().unwrap();
+        assert_eq!((report.cells_in, report.cells_out), (6, 5));
+
+        // the null points survive the compaction, only the values are read by a scan
+        let mut out_lvl = LevelInfo::init_in(&opts.base_dir, 2);
+        out_lvl.apply(&edit.add_files[0]);
+        let blocks = out_lvl.files[0].read_field_blocks(tf_id, 1).unwrap();
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].ts(), &[1, 2, 3, 4, 5]);
+        assert_eq!((0..5).map(|i| blocks[0].val_at::<i64>(i)).collect::<Vec<_>>(),
+                   vec![Some(10), None, Some(31), None, Some(50)]);
+        let data =
+            out_lvl.files[0].read_field(tf_id, 1, &TimeRange::new(i64::MAX, i64::MIN)).unwrap();
+        assert_eq!(data.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_compaction_report() {
+        let tmp = tempfile::tempdir().unwrap();
+        let tf_id = 115;
+        let opts = Arc::new(TseriesFamOpt::for_testing(tmp.path()));
+        let dir = opts.tsm_dir(tf_id);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let mut lvl = LevelInfo::init_in(&opts.base_dir, 1);
+        let mut bytes_read = 0;
+        for (file_id, ts) in [(1, vec![1, 2, 3, 4]), (2, vec![3, 4, 5, 6])] {
+            let mut block_set = HashMap::new();
+            let val = ts.clone();
+            block_set.insert(1, DataBlock::I64 { index: 0, ts: ts.clone(), val, validity: None });
+            let file_size = build_tsm_file(make_tsm_file_name(&dir, file_id), block_set).unwrap();
+            bytes_read += file_size;
+            lvl.apply(&CompactMeta { file_id,
+                                     file_size,
+                                     range: TimeRange::new(ts[ts.len() - 1], ts[0]),
+                                     level: 1,
+                                     ..Default::default() });
+        }
+
+        let kernel = compact_kernel(&lvl.files);
+        let req = compact_req(tf_id, lvl.files.clone(), 2, opts.clone());
+        let (edit, report) =
+            run_compaction_job(req, kernel.clone(), &DiskSpace::default()).await.unwrap().unwrap();
+        // the points of timestamps 3 and 4 are merged
+        assert_eq!((report.level, report.files_in, report.files_out), (1, 2, 1));
+        assert_eq!((report.cells_in, report.cells_out), (8, 6));
+        assert_eq!(report.bytes_read, bytes_read);
+        assert_eq!(report.bytes_written, edit.add_files[0].file_size);
+        assert_eq!(report.bytes_saved, bytes_read as i64 - report.bytes_written as i64);
+
+        let totals = kernel.compaction_metrics().totals();
+        assert_eq!(totals.compactions, 1);
+        assert_eq!((totals.files_in, totals.files_out, totals.cells_in, totals.cells_out),
+                   (2, 1, 8, 6));
+        assert_eq!(totals.bytes_written, report.bytes_written);
+    }
+
+    #[tokio::test]
+    async fn test_compaction_recompress() {
+        let tmp = tempfile::tempdir().unwrap();
+        let tf_id = 116;
+        let mut opts = TseriesFamOpt::for_testing(tmp.path());
+        opts.level_compression = vec![BlockCompression::Fast,
+                                      BlockCompression::Fast,
+                                      BlockCompression::Fast,
+                                      BlockCompression::Zstd(19)];
+        let opts = Arc::new(opts);
+        let dir = opts.tsm_dir(tf_id);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // repetitive strings and floats, which the coders alone leave large
+        let values = |ts: &[i64]| -> (Vec<Vec<u8>>, Vec<f64>) {
+            let str_val = ts.iter().map(|t| format!("host-{}-region-{}", t % 5, t % 3)).collect();
+            let f64_val = ts.iter().map(|t| (t % 7) as f64 * 0.1).collect();
+            (str_val, f64_val)
+        };
+        let mut lvl = LevelInfo::init_in(&opts.base_dir, 1);
+        let mut bytes_read = 0;
+        for (file_id, ts) in [(1, (1..=500).collect::<Vec<i64>>()), (2, (501..=1000).collect())] {
+            let (str_val, f64_val) = values(&ts);
+            let block_set = HashMap::from([(1,
+                                            DataBlock::Str { index: 0,
+                                                             ts: ts.clone(),
+                                                             val: str_val,
+                                                             validity: None }),
+                                           (2,
+                                            DataBlock::F64 { index: 0,
+                                                             ts: ts.clone(),
+                                                             val: f64_val,
+                                                             validity: None })]);
+            let file_size = build_tsm_file(make_tsm_file_name(&dir, file_id), block_set).unwrap();
+            bytes_read += file_size;
+            lvl.apply(&CompactMeta { file_id,
+                                     file_size,
+                                     range: TimeRange::new(ts[ts.len() - 1], ts[0]),
+                                     level: 1,
+                                     ..Default::default() });
+        }
+
+        let kernel = compact_kernel(&lvl.files);
+        let request = |out_lvl| compact_req(tf_id, lvl.files.clone(), out_lvl, opts.clone());
+        // the same files merged into level 2 keep the fast encodings
+        let space = DiskSpace::default();
+        let (_, fast) =
+            run_compaction_job(request(2), kernel.clone(), &space).await.unwrap().unwrap();
+        let (edit, report) = run_compaction_job(request(3), kernel, &space).await.unwrap().unwrap();
+        assert_eq!(report.bytes_read, bytes_read);
+        assert!(report.bytes_written < fast.bytes_written);
+        assert!(report.bytes_saved > fast.bytes_saved);
+        assert_eq!(report.bytes_saved, bytes_read as i64 - report.bytes_written as i64);
+
+        let mut out_lvl = LevelInfo::init_in(&opts.base_dir, 3);
+        out_lvl.apply(&edit.add_files[0]);
+        let file = &out_lvl.files[0];
+        let ts: Vec<i64> = (1..=1000).collect();
+        let (str_val, f64_val) = values(&ts);
+        let all = TimeRange::new(i64::MAX, i64::MIN);
+        let data: Vec<(i64, Vec<u8>)> = file.read_field(tf_id, 1, &all)
+                                            .unwrap()
+                                            .into_iter()
+                                            .map(|d| match d {
+                                                DataType::Str(c) => (c.ts, c.val),
+                                                _ => panic!("unexpected data type"),
+                                            })
+                                            .collect();
+        assert_eq!(data, ts.iter().copied().zip(str_val).collect::<Vec<_>>());
+        let data: Vec<(i64, f64)> = file.read_field(tf_id, 2, &all)
+                                        .unwrap()
+                                        .into_iter()
+                                        .map(|d| match d {
+                                            DataType::F64(c) => (c.ts, c.val),
+                                            _ => panic!("unexpected data type"),
+                                        })
+                                        .collect();
+        assert_eq!(data, ts.iter().copied().zip(f64_val).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn test_compaction_disk_space() {
+        let tmp = tempfile::tempdir().unwrap();
+        let tf_id = 106;
+        let opts = Arc::new(TseriesFamOpt::for_testing(tmp.path()));
+        let dir = opts.tsm_dir(tf_id);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let mut lvl = LevelInfo::init_in(&opts.base_dir, 1);
+        for file_id in 1..=3_u64 {
+            let ts = file_id as i64;
+            let mut block_set = HashMap::new();
+            block_set.insert(1,
+                             DataBlock::I64 { index: 0,
+                                              ts: vec![ts],
+                                              val: vec![ts],
+                                              validity: None });
+            build_tsm_file(make_tsm_file_name(&dir, file_id), block_set).unwrap();
+            lvl.apply(&CompactMeta { file_id,
+                                     file_size: 100,
+                                     range: TimeRange::new(ts, ts),
+                                     level: 1,
+                                     ..Default::default() });
+        }
+        let request = || {
+            for file in lvl.files.iter() {
+                file.mark_compaction();
+            }
+            compact_req(tf_id, lvl.files.iter().rev().cloned().collect(), 2, opts.clone())
+        };
+        let kernel = compact_kernel(&lvl.files);
+
+        // two of the files fit, the oldest ones are merged
+        let space = DiskSpace::new(Arc::new(FakeFileSystem(250)));
+        let (edit, _) =
+            run_compaction_job(request(), kernel.clone(), &space).await.unwrap().unwrap();
+        let merged: Vec<u64> = edit.del_files.iter().map(|f| f.file_id).collect();
+        assert_eq!(merged, vec![1, 2]);
+        assert!(!lvl.files.iter().find(|f| f.file_id() == 3).unwrap().is_pending_compaction());
+
+        // a single file is not worth merging
+        let space = DiskSpace::new(Arc::new(FakeFileSystem(150)));
+        assert!(run_compaction_job(request(), kernel, &space).await.unwrap().is_none());
+        assert!(lvl.files.iter().all(|f| !f.is_pending_compaction()));
+    }
+
+    #[tokio::test]
+    async fn test_compaction_chunks() {
+        let tmp = tempfile::tempdir().unwrap();
+        let tf_id = 113;
+        let opts = Arc::new(TseriesFamOpt::for_testing(tmp.path()));
+        let dir = opts.tsm_dir(tf_id);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let mut lvl = LevelInfo::init_in(&opts.base_dir, 1);
+        for (file_id, ts) in [(1, 0..1500), (2, 1000..2500)] {
+            let ts: Vec<i64> = ts.collect();
+            let meta = CompactMeta { file_id,
+                                     range: TimeRange::new(ts[ts.len() - 1], ts[0]),
+                                     level: 1,
+                                     ..Default::default() };
+            let mut block_set = HashMap::new();
+            block_set.insert(1,
+                             DataBlock::I64 { index: 0,
+                                              val: vec![file_id as i64; 1500],
+                                              ts,
+                                              validity: None });
+            build_tsm_file(make_tsm_file_name(&dir, file_id), block_set).unwrap();
+            lvl.apply(&meta);
+        }
+
+        let kernel = compact_kernel(&lvl.files);
+        let req = compact_req(tf_id, lvl.files.clone(), 2, opts.clone());
+        let (edit, _) =
+            run_compaction_job(req, kernel, &DiskSpace::default()).await.unwrap().unwrap();
+        let mut out_lvl = LevelInfo::init_in(&opts.base_dir, 2);
+        out_lvl.apply(&edit.add_files[0]);
+
+        let (mut cursor, len) = out_lvl.files[0].file_reader(tf_id).unwrap();
+        let mut blocks = vec![];
+        for entry in TsmIndexReader::try_new(&mut cursor, len as usize).unwrap() {
+            let block = entry.unwrap().block;
+            blocks.push((block.min_ts, block.max_ts));
+        }
+        assert_eq!(blocks, vec![(0, 999), (1000, 1999), (2000, 2499)]);
+        let data =
+            out_lvl.files[0].read_field(tf_id, 1, &TimeRange::new(i64::MAX, i64::MIN)).unwrap();
+        assert_eq!(data.len(), 2500);
+        assert!(matches!(data[1000], DataType::I64(c) if c.ts == 1000 && c.val == 2));
+    }
+
+    // compacts two files holding duplicates of the timestamps 2 and 3, returns the output
+    async fn compact_duplicates(duplicate_policy: DuplicatePolicy) -> Vec<(i64, i64)> {
+        let tmp = tempfile::tempdir().unwrap();
+        let tf_id = 127;
+        let opts =
+            Arc::new(TseriesFamOpt { duplicate_policy, ..TseriesFamOpt::for_testing(tmp.path()) });
+        let dir = opts.tsm_dir(tf_id);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let mut lvl = LevelInfo::init_in(&opts.base_dir, 1);
+        let inputs =
+            vec![(1, vec![1, 2, 2, 3], vec![10, 20, 21, 30]), (2, vec![2, 3, 4], vec![22, 31, 40])];
+        for (file_id, ts, val) in inputs {
+            let meta = CompactMeta { file_id,
+                                     range: TimeRange::new(ts[ts.len() - 1], ts[0]),
+                                     level: 1,
+                                     ..Default::default() };
+            let mut block_set = HashMap::new();
+            block_set.insert(1, DataBlock::I64 { index: 0, ts, val, validity: None });
+            build_tsm_file(make_tsm_file_name(&dir, file_id), block_set).unwrap();
+            lvl.apply(&meta);
+        }
+
+        let kernel = compact_kernel(&lvl.files);
+        let req = compact_req(tf_id, lvl.files.clone(), 2, opts.clone());
+        let (edit, _) =
+            run_compaction_job(req, kernel, &DiskSpace::default()).await.unwrap().unwrap();
+        let mut out_lvl = LevelInfo::init_in(&opts.base_dir, 2);
+        out_lvl.apply(&edit.add_files[0]);
+        let data =
+            out_lvl.files[0].read_field(tf_id, 1, &TimeRange::new(i64::MAX, i64::MIN)).unwrap();
+        data.into_iter()
+            .map(|d| match d {
+                DataType::I64(c) => (c.ts, c.val),
+                _ => panic!("unexpected data type"),
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_compaction_duplicate_policy() {
+        assert_eq!(compact_duplicates(DuplicatePolicy::LastWins).await,
+                   vec![(1, 10), (2, 22), (3, 31), (4, 40)]);
+        // every version survives, in the order written: the older file first
+        assert_eq!(compact_duplicates(DuplicatePolicy::KeepAll).await,
+                   vec![(1, 10), (2, 20), (2, 21), (2, 22), (3, 30), (3, 31), (4, 40)]);
+    }
+
+    #[tokio::test]
+    async fn test_compaction_keeps_float_bits() {
+        let tmp = tempfile::tempdir().unwrap();
+        let tf_id = 109;
+        let opts = Arc::new(TseriesFamOpt::for_testing(tmp.path()));
+        let dir = opts.tsm_dir(tf_id);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let nan = f64::from_bits(0xfff8_dead_beef_0001);
+        // the nan terminating a gorilla encoded block
+        let sentinel = f64::from_bits(0x7ff8_0000_0000_00ff);
+        let subnormal = f64::from_bits(1);
+        let mut lvl = LevelInfo::init_in(&opts.base_dir, 1);
+        let inputs = vec![(1, vec![1, 2, 3], vec![-0.0, nan, subnormal]),
+                          (2, vec![3, 4], vec![sentinel, -0.0])];
+        for (file_id, ts, val) in inputs {
+            let meta = CompactMeta { file_id,
+                                     range: TimeRange::new(ts[ts.len() - 1], ts[0]),
+                                     level: 1,
+                                     ..Default::default() };
+            let mut block_set = HashMap::new();
+            block_set.insert(1, DataBlock::F64 { index: 0, ts, val, validity: None });
+            build_tsm_file(make_tsm_file_name(&dir, file_id), block_set).unwrap();
+            lvl.apply(&meta);
+        }
+
+        let kernel = compact_kernel(&lvl.files);
+        let req = compact_req(tf_id, lvl.files.clone(), 2, opts.clone());
+        let (edit, _) =
+            run_compaction_job(req, kernel, &DiskSpace::default()).await.unwrap().unwrap();
+        let mut out_lvl = LevelInfo::init_in(&opts.base_dir, 2);
+        out_lvl.apply(&edit.add_files[0]);
+        let mut block = DataBlock::new(0, ValueType::Float);
+        for data in
+            out_lvl.files[0].read_field(tf_id, 1, &TimeRange::new(i64::MAX, i64::MIN)).unwrap()
+        {
+            block.insert(data);
+        }
+        let expected = DataBlock::F64 { index: 0,
+                                        ts: vec![1, 2, 3, 4],
+                                        val: vec![-0.0, nan, sentinel, -0.0],
+                                        validity: None };
+        assert!(block.eq_bits(&expected), "{:?}", block);
+    }
 }
@@ -0,0 +1,134 @@
+use std::{fmt::Debug, sync::Arc};
+
+use models::{FieldId, Timestamp};
+
+use crate::{
+    error::{Error, Result},
+    tsm::DataBlock,
+};
+
+/// What to do with a block rewritten by compaction.
+#[derive(Debug, PartialEq)]
+pub enum FilterDecision {
+    /// Write the block as it is.
+    Keep(DataBlock),
+    /// Drop the whole block.
+    Drop,
+    /// Write another block instead, it may only remove timestamps, never add any.
+    Replace(DataBlock),
+}
+
+/// A hook that sees the blocks of a field while compaction rewrites it, after the tombstones
+/// are applied and before every block is encoded again.
+pub trait CompactionFilter: Send + Sync + Debug {
+    fn filter(&self, field_id: FieldId, block: DataBlock) -> FilterDecision;
+}
+
+/// A compaction filter registered in `TseriesFamOpt`, two refs are equal if they point to the
+/// same filter.
+#[derive(Debug, Clone)]
+pub struct CompactionFilterRef(pub Arc<dyn CompactionFilter>);
+
+impl PartialEq for CompactionFilterRef {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::as_ptr(&self.0) as *const u8 == Arc::as_ptr(&other.0) as *const u8
+    }
+}
+
+/// Drops the points older than the cutoff, retention implemented as a filter.
+#[derive(Debug)]
+pub struct RetentionFilter {
+    cutoff: Timestamp,
+}
+
+impl RetentionFilter {
+    pub fn new(cutoff: Timestamp) -> Self {
+        Self { cutoff }
+    }
+}
+
+impl CompactionFilter for RetentionFilter {
+    fn filter(&self, _field_id: FieldId, mut block: DataBlock) -> FilterDecision {
+        let ts = block.ts();
+        if ts.iter().all(|t| *t < self.cutoff) {
+            return FilterDecision::Drop;
+        }
+        if ts.iter().all(|t| *t >= self.cutoff) {
+            return FilterDecision::Keep(block);
+        }
+        let keep: Vec<bool> = ts.iter().map(|t| *t >= self.cutoff).collect();
+        block.retain(&keep);
+        FilterDecision::Replace(block)
+    }
+}
+
+/// Runs a filter on a block, returns None if the block is dropped.
+pub(crate) fn apply_filter(filter: &dyn CompactionFilter,
+                           field_id: FieldId,
+                           block: DataBlock)
+                           -> Result<Option<DataBlock>> {
+    let origin = block.ts().to_vec();
+    let block = match filter.filter(field_id, block) {
+        FilterDecision::Keep(block) => block,
+        FilterDecision::Drop => return Ok(None),
+        FilterDecision::Replace(block) => {
+            // the timestamps of the new block must be a subsequence of the origin
+            let mut origin_ts = origin.iter();
+            if !block.ts().iter().all(|t| origin_ts.any(|o| o == t)) {
+                return Err(Error::CompactionFilterErr {
+                    reason: format!("filter added timestamps to the block of field {}", field_id),
+                });
+            }
+            block
+        },
+    };
+    if block.len() == 0 {
+        return Ok(None);
+    }
+    Ok(Some(block))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{apply_filter, CompactionFilter, FilterDecision, RetentionFilter};
+    use crate::tsm::DataBlock;
+
+    #[derive(Debug)]
+    struct ShiftFilter;
+
+    impl CompactionFilter for ShiftFilter {
+        fn filter(&self, _field_id: u64, block: DataBlock) -> FilterDecision {
+            match block {
+                DataBlock::I64 { index, ts, val, validity } => {
+                    let ts = ts.iter().map(|t| t + 1).collect();
+                    FilterDecision::Replace(DataBlock::I64 { index, ts, val, validity })
+                },
+                _ => FilterDecision::Keep(block),
+            }
+        }
+    }
+
+    #[test]
+    fn test_retention_filter() {
+        let block = || DataBlock::I64 { index: 0,
+                                        ts: vec![1, 2, 3, 4],
+                                        val: vec![10, 20, 30, 40],
+                                        validity: None };
+        let res = apply_filter(&RetentionFilter::new(3), 1, block()).unwrap();
+        assert_eq!(res,
+                   Some(DataBlock::I64 { index: 0,
+                                         ts: vec![3, 4],
+                                         val: vec![30, 40],
+                                         validity: None }));
+        let res = apply_filter(&RetentionFilter::new(1), 1, block()).unwrap();
+        assert_eq!(res, Some(block()));
+        let res = apply_filter(&RetentionFilter::new(5), 1, block()).unwrap();
+        assert_eq!(res, None);
+    }
+
+    #[test]
+    fn test_filter_adds_timestamps() {
+        let block = DataBlock::I64 { index: 0, ts: vec![1, 2], val: vec![10, 20], validity: None };
+        assert!(apply_filter(&ShiftFilter, 1, block).is_err());
+    }
+}
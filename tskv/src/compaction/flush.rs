@@ -1,49 +1,72 @@
-use std::{cmp::max, collections::HashMap, path::PathBuf, sync::Arc};
+use std::{
+    borrow::Cow,
+    cmp::max,
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Instant,
+};
 
 use logger::{debug, error, info, warn};
 use models::FieldId;
-use parking_lot::Mutex;
 use regex::internal::Input;
 use tokio::sync::{mpsc::UnboundedSender, oneshot, oneshot::Sender, RwLock};
 
 use crate::{
-    compaction::FlushReq,
+    compaction::{DiskSpace, FlushQueue, FlushReq, LogEvent},
     context::GlobalContext,
-    direct_io::FileSync,
+    direct_io::IoClass,
     error::{Error, Result},
-    file_manager,
     file_utils::{make_delta_file_name, make_tsm_file_name},
-    kv_option::TseriesFamOpt,
-    memcache::{MemCache, MemEntry},
+    kv_option::{BlockCompression, DuplicatePolicy, TseriesFamOpt},
+    memcache::{MemCacheRef, MemEntry},
+    merge::MergeStream,
     summary::{CompactMeta, SummaryTask, VersionEdit},
-    tseries_family::LevelInfo,
-    tsm::{DataBlock, TsmBlockWriter, TsmFooterWriter, TsmHeaderWriter, TsmIndexWriter},
+    tseries_family::{spawn_warm, LevelInfo, TimeRange, Version},
+    tsm::{CompressionStats, DataBlock, TsmWriter},
     version_set::VersionSet,
 };
 
 pub struct FlushTask {
-    mems: Vec<Arc<RwLock<MemCache>>>,
+    mems: Vec<MemCacheRef>,
     meta: CompactMeta,
     tsf_id: u32,
     path_tsm: String,
     path_delta: String,
+    duplicate_policy: DuplicatePolicy,
+    max_flush_level: u32,
+    level_compression: Vec<BlockCompression>,
+    time_window_ns: i64,
 }
 
 impl FlushTask {
-    pub fn new(mems: Vec<Arc<RwLock<MemCache>>>,
+    pub fn new(mems: Vec<MemCacheRef>,
                tsf_id: u32,
                path_tsm: String,
-               path_delta: String)
+               path_delta: String,
+               duplicate_policy: DuplicatePolicy,
+               max_flush_level: u32,
+               level_compression: Vec<BlockCompression>,
+               time_window_ns: i64)
                -> Self {
         let meta = CompactMeta::new();
-        Self { mems, meta, tsf_id, path_tsm, path_delta }
+        Self { mems,
+               meta,
+               tsf_id,
+               path_tsm,
+               path_delta,
+               duplicate_policy,
+               max_flush_level,
+               level_compression,
+               time_window_ns }
     }
+
     pub async fn run(&mut self,
                      version_set: Arc<RwLock<VersionSet>>,
                      kernel: Arc<GlobalContext>,
                      edits: &mut Vec<VersionEdit>)
                      -> Result<()> {
-        let (mut ts_min, mut ts_max) = (i64::MAX, i64::MIN);
+        let mut range = TimeRange::none();
         let (mut high_seq, mut low_seq) = (0, u64::MAX);
         let mut field_map = HashMap::new();
         let mut field_map_delta = HashMap::new();
@@ -53,17 +76,18 @@ impl FlushTask {
         for i in self.mems.iter() {
             mem_guard.push(i.read().await);
         }
+        let (delta_mems, mems): (Vec<_>, Vec<_>) =
+            self.mems.iter().zip(mem_guard.iter()).partition(|(_, mem)| mem.is_delta());
+        let delta_mems: Vec<MemCacheRef> = delta_mems.into_iter().map(|(m, _)| m.clone()).collect();
+        let mems: Vec<MemCacheRef> = mems.into_iter().map(|(m, _)| m.clone()).collect();
         for mem in mem_guard.iter() {
-            let data = &mem.data_cache;
-            // get req seq_no range
-            if mem.seq_no > high_seq {
-                high_seq = mem.seq_no;
-            }
-            if mem.seq_no < low_seq {
-                low_seq = mem.seq_no;
-            }
-            for (field_id, entry) in data {
-                if mem.is_delta {
+            info!("{} {}", LogEvent::new("flush_cache"), mem.summary());
+            // get req seq_no range, from the first write of the caches to the last one
+            let (min_seq, max_seq) = mem.seq_range();
+            high_seq = high_seq.max(max_seq);
+            low_seq = low_seq.min(min_seq);
+            for (field_id, entry) in mem.iter_entries() {
+                if mem.is_delta() {
                     let sum = field_size_delta.entry(field_id).or_insert(0_usize);
                     *sum += entry.cells.len();
                     let item = field_map_delta.entry(field_id).or_insert(vec![]);
@@ -77,166 +101,514 @@ impl FlushTask {
             }
         }
         let block_set_delta =
-            build_block_set(field_size_delta, field_map_delta, &mut ts_max, &mut ts_min);
+            build_block_set(field_size_delta, field_map_delta, &mut range, self.duplicate_policy);
         // build tsm file
         if !block_set_delta.is_empty() {
-            self.meta.file_id = kernel.file_id();
-            kernel.file_id_next();
+            self.meta.file_id = kernel.next_file_id();
             build_tsm_file_workflow(&mut self.meta,
                                     block_set_delta,
                                     self.tsf_id,
                                     &self.path_delta,
                                     low_seq,
                                     high_seq,
-                                    ts_max,
-                                    ts_min,
+                                    range,
                                     0,
                                     true,
+                                    self.duplicate_policy,
+                                    BlockCompression::of_level(&self.level_compression, 0),
+                                    self.time_window_ns,
+                                    &delta_mems,
                                     edits,
-                                    version_set.clone()).await
-                                                        .expect("failed to build delta file");
+                                    version_set.clone(),
+                                    &kernel).await
+                                            .expect("failed to build delta file");
         }
-        (ts_min, ts_max) = (i64::MAX, i64::MIN);
-        let block_set = build_block_set(field_size, field_map, &mut ts_max, &mut ts_min);
+        range = TimeRange::none();
+        let block_set = build_block_set(field_size, field_map, &mut range, self.duplicate_policy);
         if !block_set.is_empty() {
-            self.meta.file_id = kernel.file_id();
-            kernel.file_id_next();
+            let level = {
+                let version_s = version_set.read().await;
+                let version = version_s.get_tsfamily_immut(self.tsf_id as u64)
+                                       .unwrap()
+                                       .version()
+                                       .read()
+                                       .await;
+                pick_flush_level(&version, self.max_flush_level, &range)
+            };
+            self.meta.file_id = kernel.next_file_id();
             build_tsm_file_workflow(&mut self.meta,
                                     block_set,
                                     self.tsf_id,
                                     &self.path_tsm,
                                     low_seq,
                                     high_seq,
-                                    ts_max,
-                                    ts_min,
-                                    1,
+                                    range,
+                                    level,
                                     false,
+                                    self.duplicate_policy,
+                                    BlockCompression::of_level(&self.level_compression, level),
+                                    self.time_window_ns,
+                                    &mems,
                                     edits,
-                                    version_set.clone()).await
-                                                        .expect("Failed to build tsm file");
+                                    version_set.clone(),
+                                    &kernel).await
+                                            .expect("Failed to build tsm file");
         }
         Ok(())
     }
 }
 
+/// Returns the level of the tsm file flushed with the data in the time range. Data older than
+/// every file of level 1 goes to the deepest level up to `max_flush_level` whose files and the
+/// files of every level above it do not overlap the data, so that it is never compacted again
+/// only to move down.
+fn pick_flush_level(version: &Version, max_flush_level: u32, time_range: &TimeRange) -> usize {
+    let overlaps = |level: usize| match version.levels_info.get(level) {
+        Some(info) => info.files.iter().any(|f| !f.is_deleted() && f.overlap(time_range)),
+        None => false,
+    };
+    let newest = match version.levels_info.get(1) {
+        Some(info) => info.files.iter().filter(|f| !f.is_deleted()).map(|f| f.range().min_ts).min(),
+        None => None,
+    };
+    match newest {
+        Some(min_ts) if time_range.max_ts < min_ts => {},
+        _ => return 1,
+    }
+    let mut level = 1;
+    while level < max_flush_level as usize && !overlaps(level + 1) {
+        level += 1;
+    }
+    level
+}
+
 async fn build_tsm_file_workflow(meta: &mut CompactMeta,
                                  block_set: HashMap<FieldId, DataBlock>,
                                  tsf_id: u32,
                                  path: &str,
                                  low_seq: u64,
                                  high_seq: u64,
-                                 ts_max: i64,
-                                 ts_min: i64,
+                                 range: TimeRange,
                                  level: usize,
                                  is_delta: bool,
+                                 duplicate_policy: DuplicatePolicy,
+                                 compression: BlockCompression,
+                                 time_window_ns: i64,
+                                 mems: &[MemCacheRef],
                                  edits: &mut Vec<VersionEdit>,
-                                 version_set: Arc<RwLock<VersionSet>>)
+                                 version_set: Arc<RwLock<VersionSet>>,
+                                 kernel: &GlobalContext)
                                  -> Result<()> {
+    std::fs::create_dir_all(path).map_err(|source| Error::IO { source })?;
     let fname = if is_delta {
         make_delta_file_name(path, meta.file_id)
     } else {
         make_tsm_file_name(path, meta.file_id)
     };
-    let file_size = build_tsm_file(fname, block_set)?;
+    // the writer splits the blocks of every window again by points and bytes
+    let chunk_set = block_set.into_iter()
+                             .map(|(fid, block)| (fid, block.partition_by_time(time_window_ns)))
+                             .collect();
+    let (file_size, stats) =
+        write_tsm_chunks(fname, chunk_set, IoClass::High, duplicate_policy, compression)?;
+    kernel.compression_metrics().record(tsf_id, &stats);
+    info!("{}",
+          LogEvent::new("flush_file").field("tf_id", tsf_id)
+                                     .field("file_id", meta.file_id)
+                                     .field("level", level)
+                                     .field("bytes", file_size));
     // update meta
     meta.low_seq = low_seq;
     meta.high_seq = high_seq;
-    meta.ts_max = ts_max;
-    meta.ts_min = ts_min;
+    meta.range = range;
     meta.level = level as u32;
     meta.file_size = file_size;
     meta.is_delta = is_delta;
+    meta.tsf_id = tsf_id;
     let mut version_s = version_set.write().await;
-    let mut version = version_s.get_tsfamily(tsf_id as u64).unwrap().version().write().await;
+    let tsf = version_s.get_tsfamily_by_id(tsf_id).unwrap();
+    // the data moves from the caches to the file in one step for the readers
+    tsf.finish_flush(mems);
+    let mut version = tsf.version().write().await;
     while version.levels_info.len() <= level {
         let i: u32 = version.levels_info.len() as u32;
-        version.levels_info.push(LevelInfo::init(i));
+        let info = LevelInfo::init_in(&version.base_dir, i);
+        version.levels_info.push(info);
     }
-    version.levels_info[level].apply(meta);
+    // published right away, the fields and the tombstones are loaded in the background
+    let new_file = version.apply_file(meta);
+    spawn_warm(tsf_id, new_file.into_iter().collect());
     let mut edit = VersionEdit::new();
     edit.add_file(meta.level, tsf_id, meta.file_id, high_seq, version.max_level_ts, meta.clone());
     edits.push(edit);
     Ok(())
 }
 
-fn build_block_set(field_size: HashMap<&FieldId, usize>,
-                   field_map: HashMap<&FieldId, Vec<&MemEntry>>,
-                   ts_max: &mut i64,
-                   ts_min: &mut i64)
-                   -> HashMap<FieldId, DataBlock> {
+// entrys of a field are ordered from the oldest to the newest
+pub(crate) fn build_block_set(field_size: HashMap<FieldId, usize>,
+                              field_map: HashMap<FieldId, Vec<Cow<MemEntry>>>,
+                              range: &mut TimeRange,
+                              duplicate_policy: DuplicatePolicy)
+                              -> HashMap<FieldId, DataBlock> {
     let mut block_set = HashMap::new();
     for (fid, entrys) in field_map {
         let size = field_size.get(&fid).unwrap();
         let entry = entrys.first().unwrap();
         let mut block = DataBlock::new(*size, entry.field_type);
+        let mut sources = Vec::with_capacity(entrys.len());
         for entry in entrys.iter() {
             // get tsm ts range
-            if entry.ts_max > *ts_max {
-                *ts_max = entry.ts_max;
-            }
-            if entry.ts_min < *ts_min {
-                *ts_min = entry.ts_min;
-            }
-            block.batch_insert(&entry.cells);
+            *range = range.merge(&TimeRange::new(entry.ts_max, entry.ts_min));
+            let mut cells = entry.cells.clone();
+            cells.sort_by_key(|c| c.timestamp());
+            sources.push(cells.into_iter());
         }
-        block_set.insert(*fid, block);
+        for data in MergeStream::with_policy(sources, duplicate_policy) {
+            block.insert(data);
+        }
+        block_set.insert(fid, block);
     }
     block_set
 }
 
-fn build_tsm_file(fname: PathBuf, block_set: HashMap<FieldId, DataBlock>) -> Result<u64> {
-    let file = file_manager::get_file_manager().create_file(fname).unwrap();
-    let mut fs_cursor = file.into_cursor();
-
-    TsmHeaderWriter::write_to(&mut fs_cursor)?;
-    let index = TsmBlockWriter::write_to(&mut fs_cursor, block_set)?;
-    let index_pos = fs_cursor.pos();
-    let bloom_filter = TsmIndexWriter::write_to(&mut fs_cursor, index)?;
-    TsmFooterWriter::write_to(&mut fs_cursor, &bloom_filter, index_pos)?;
-    fs_cursor.sync_all(FileSync::Hard)
-             .map_err(|e| Error::WriteTsmErr { reason: e.to_string() })?;
-    let len = fs_cursor.len();
-    Ok(len)
+pub(crate) fn build_tsm_file(fname: PathBuf,
+                             block_set: HashMap<FieldId, DataBlock>)
+                             -> Result<u64> {
+    write_tsm_file(fname,
+                   block_set,
+                   IoClass::High,
+                   DuplicatePolicy::default(),
+                   BlockCompression::Fast)
+}
+
+/// Writes the blocks into a new tsm file with the io priority and the compression, returns the
+/// file size. A block not sorted by timestamp is sorted with the duplicate policy first, so that
+/// the readers can rely on the order of the persisted blocks.
+pub(crate) fn write_tsm_file(fname: PathBuf,
+                             block_set: HashMap<FieldId, DataBlock>,
+                             io_class: IoClass,
+                             duplicate_policy: DuplicatePolicy,
+                             compression: BlockCompression)
+                             -> Result<u64> {
+    let chunk_set = block_set.into_iter().map(|(fid, block)| (fid, vec![block])).collect();
+    write_tsm_chunks(fname, chunk_set, io_class, duplicate_policy, compression).map(|(len, _)| len)
 }
 
-pub async fn run_flush_memtable_job(reqs: Arc<Mutex<Vec<FlushReq>>>,
+/// Writes the blocks of every field into a new tsm file like `write_tsm_file`, the blocks of a
+/// field are ordered by timestamp and written one after another. Returns the file size and the
+/// compression of the blocks.
+pub(crate) fn write_tsm_chunks(fname: PathBuf,
+                               mut chunk_set: HashMap<FieldId, Vec<DataBlock>>,
+                               io_class: IoClass,
+                               duplicate_policy: DuplicatePolicy,
+                               compression: BlockCompression)
+                               -> Result<(u64, CompressionStats)> {
+    for (field_id, chunks) in chunk_set.iter_mut() {
+        for block in chunks.iter_mut().filter(|b| !b.is_sorted()) {
+            warn!("unsorted block of field {} sorted before written to {}",
+                  field_id,
+                  fname.display());
+            block.sort(duplicate_policy);
+        }
+    }
+    let mut writer = TsmWriter::create(fname, io_class)?;
+    writer.write_chunks(chunk_set, compression)?;
+    let len = writer.finish()?;
+    Ok((len, writer.compression_stats().clone()))
+}
+
+/// Flushes the caches of the requests queued. The caches of a tseries family whose disk cannot
+/// hold the flushed files are queued again, and flushed with the next request or by the retry of
+/// the flush job, whichever comes first.
+///
+/// Returns the receiver of the result of persisting the version edits of the flushed files.
+pub async fn run_flush_memtable_job(reqs: &FlushQueue,
                                     kernel: Arc<GlobalContext>,
                                     tsf_config: HashMap<u32, Arc<TseriesFamOpt>>,
                                     version_set: Arc<RwLock<VersionSet>>,
-                                    summary_task_sender: UnboundedSender<SummaryTask>)
-                                    -> Result<()> {
+                                    summary_task_sender: UnboundedSender<SummaryTask>,
+                                    space: &DiskSpace)
+                                    -> Result<oneshot::Receiver<Result<()>>> {
     let mut mems = vec![];
-    {
-        let mut reqs = reqs.lock();
-        info!("get flush request len {}", reqs.len());
-        for req in reqs.iter() {
-            for (tf, mem) in &req.mems {
-                while *tf >= mems.len() as u32 {
-                    mems.push(vec![]);
-                }
-                mems[(*tf) as usize].push(mem.clone());
+    let taken = reqs.take();
+    info!("{}", LogEvent::new("flush_start").field("req_count", taken.len()));
+    for req in taken.iter() {
+        for (tf, mem) in &req.mems {
+            while *tf >= mems.len() as u32 {
+                mems.push(vec![]);
             }
+            mems[(*tf) as usize].push(mem.clone());
         }
-        reqs.clear();
     }
     let mut edits: Vec<VersionEdit> = vec![];
+    let mut skipped = vec![];
     for (i, memtables) in mems.iter().enumerate() {
         if !memtables.is_empty() {
             // todo: build path by vnode data
             let idx = i as u32;
-            let cf_opt =
-                tsf_config.get(&idx).cloned().unwrap_or_else(|| Arc::new(TseriesFamOpt::default()));
+            let cf_opt = tsf_config.get(&idx)
+                                   .cloned()
+                                   .unwrap_or_else(|| Arc::new(TseriesFamOpt::from_config()));
 
-            let path_tsm = cf_opt.tsm_dir.clone() + &i.to_string();
-            let path_delta = cf_opt.delta_dir.clone() + &i.to_string();
-            let mut job = FlushTask::new(memtables.clone(), i as u32, path_tsm, path_delta);
+            let path_tsm = cf_opt.tsm_dir(idx);
+            let path_delta = cf_opt.delta_dir(idx);
+            let mut input = 0;
+            for mem in memtables.iter() {
+                input += mem.read().await.size();
+            }
+            if let Some(available) = space.available(Path::new(&path_tsm)) {
+                let need = space.flush_ratio().estimate(input);
+                if need > available {
+                    warn!("{}",
+                          LogEvent::new("flush_skipped").field("tf_id", i)
+                                                        .field("mem_count", memtables.len())
+                                                        .field("need", need)
+                                                        .field("available", available));
+                    skipped.extend(memtables.iter().map(|mem| (idx, mem.clone())));
+                    continue;
+                }
+            }
+            let start = Instant::now();
+            let mut job = FlushTask::new(memtables.clone(),
+                                         i as u32,
+                                         path_tsm,
+                                         path_delta,
+                                         cf_opt.duplicate_policy,
+                                         cf_opt.max_flush_level,
+                                         cf_opt.level_compression.clone(),
+                                         cf_opt.flush_time_window_ns);
+            let edits_before = edits.len();
             job.run(version_set.clone(), kernel.clone(), &mut edits).await?;
+            // the caches holding no data to write are done as well
+            if let Some(tsf) = version_set.write().await.get_tsfamily_by_id(idx) {
+                tsf.finish_flush(memtables);
+            }
+            let output = edits[edits_before..].iter()
+                                              .flat_map(|e| e.add_files.iter())
+                                              .map(|f| f.file_size)
+                                              .sum();
+            space.flush_ratio().observe(input, output);
+            info!("{}",
+                  LogEvent::new("flush_done").field("tf_id", i)
+                                             .field("mem_count", memtables.len())
+                                             .field("duration_ms", start.elapsed().as_millis()));
         }
     }
+    if !skipped.is_empty() {
+        reqs.push(FlushReq::new(skipped, 0));
+    }
     let (task_state_sender, task_state_receiver) = oneshot::channel();
-    let task = SummaryTask { edits, cb: task_state_sender };
+    let task =
+        SummaryTask { edits, trash_edits: vec![], scrub_edits: vec![], cb: task_state_sender };
     if let Err(_) = summary_task_sender.send(task) {
-        error!("failed to send Summary task,the edits not be loaded!")
+        error!("{}", LogEvent::new("flush_failed").field("reason", "failed to send summary task"))
+    }
+    Ok(task_state_receiver)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::{BTreeMap, HashMap},
+        sync::Arc,
+    };
+
+    use models::ValueType;
+    use tokio::sync::{mpsc, RwLock};
+
+    use super::{build_tsm_file, pick_flush_level, run_flush_memtable_job, FlushTask};
+    use crate::{
+        compaction::{DiskSpace, FakeFileSystem, FlushQueue, FlushReq},
+        context::GlobalContext,
+        file_utils::make_tsm_file_name,
+        kv_option::{DuplicatePolicy, MemCacheImpl, TseriesFamDesc, TseriesFamOpt},
+        memcache::new_memcache,
+        summary::CompactMeta,
+        tseries_family::{LevelInfo, TimeRange, Version},
+        tsm::{CompressionStats, DataBlock},
+        version_set::VersionSet,
+    };
+
+    // (level, ts_min, ts_max) of every file
+    fn version(files: &[(u32, i64, i64)]) -> Version {
+        let mut levels: Vec<LevelInfo> = (0..4).map(LevelInfo::init).collect();
+        for (i, (level, ts_min, ts_max)) in files.iter().enumerate() {
+            levels[*level as usize].apply(&CompactMeta { file_id: i as u64 + 1,
+                                                         range: TimeRange::new(*ts_max,
+                                                                               *ts_min),
+                                                         level: *level,
+                                                         ..Default::default() });
+        }
+        Version::new(0, 0, "db".to_string(), levels, 0)
+    }
+
+    #[test]
+    fn test_pick_flush_level() {
+        let old = TimeRange::new(10, 1);
+        assert_eq!(pick_flush_level(&version(&[]), 3, &old), 1);
+        assert_eq!(pick_flush_level(&version(&[(1, 100, 200)]), 3, &old), 3);
+        assert_eq!(pick_flush_level(&version(&[(1, 100, 200)]), 1, &old), 1);
+        assert_eq!(pick_flush_level(&version(&[(1, 5, 200)]), 3, &old), 1);
+        assert_eq!(pick_flush_level(&version(&[(1, 100, 200), (3, 5, 20)]), 3, &old), 2);
+        assert_eq!(pick_flush_level(&version(&[(1, 100, 200), (2, 5, 20)]), 3, &old), 1);
+        assert_eq!(pick_flush_level(&version(&[(1, 100, 200), (3, 50, 60)]), 3, &old), 3);
+    }
+
+    #[tokio::test]
+    async fn test_flush_old_cache() {
+        let tmp = tempfile::tempdir().unwrap();
+        let opt = TseriesFamOpt::for_testing(tmp.path());
+        let dir = opt.tsm_dir(104);
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut version = version(&[(1, 100, 200)]);
+        version.base_dir = opt.base_dir.clone();
+        let version = Arc::new(RwLock::new(version));
+        let desc = [TseriesFamDesc { name: "db".to_string(), opt: opt.clone() }];
+        let version_set =
+            VersionSet::new(&desc, HashMap::from([(0, version.clone())]), vec![]).await;
+
+        // a cache replayed from an old wal
+        let mem = new_memcache(MemCacheImpl::HashMap, 0, 4096, 1, false);
+        for ts in 1..=10_i64 {
+            mem.write().await.insert_raw(1, 1, ts, ValueType::Integer, &ts.to_be_bytes()).unwrap();
+        }
+        mem.write().await.switch_to_immutable();
+
+        let kernel = Arc::new(GlobalContext::new());
+        kernel.set_file_id(104_000);
+        let mut task = FlushTask::new(vec![mem],
+                                      0,
+                                      dir,
+                                      opt.delta_dir(104),
+                                      DuplicatePolicy::default(),
+                                      3,
+                                      vec![],
+                                      0);
+        let mut edits = vec![];
+        task.run(Arc::new(RwLock::new(version_set)), kernel, &mut edits).await.unwrap();
+        assert_eq!(edits.len(), 1);
+        assert_eq!(edits[0].add_files[0].level, 3);
+
+        let version = version.read().await;
+        assert_eq!(version.levels_info[3].files.len(), 1);
+        // nothing is added to level 0 or 1, so no compaction moves the file down again
+        assert!(version.levels_info[0].files.is_empty());
+        assert_eq!(version.levels_info[1].files.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_flush_compression_stats() {
+        let tmp = tempfile::tempdir().unwrap();
+        let opt = TseriesFamOpt::for_testing(tmp.path());
+        let dir = opt.tsm_dir(126);
+        let mut version = version(&[]);
+        version.base_dir = opt.base_dir.clone();
+        let desc = [TseriesFamDesc { name: "db".to_string(), opt: opt.clone() }];
+        let version_set = VersionSet::new(&desc,
+                                          HashMap::from([(0, Arc::new(RwLock::new(version)))]),
+                                          vec![]).await;
+
+        // constant integers, random floats and repetitive strings
+        let mem = new_memcache(MemCacheImpl::HashMap, 0, 1 << 20, 1, false);
+        let mut seed = 0x2545_f491_4f6c_dd1d_u64;
+        for ts in 1..=1000_i64 {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            let float = (seed >> 11) as f64 / (1_u64 << 53) as f64;
+            let string = format!("host-{}", ts % 7);
+            let mut mem = mem.write().await;
+            mem.insert_raw(1, 1, ts, ValueType::Integer, &42_i64.to_be_bytes()).unwrap();
+            mem.insert_raw(1, 2, ts, ValueType::Float, &float.to_be_bytes()).unwrap();
+            mem.insert_raw(1, 3, ts, ValueType::String, string.as_bytes()).unwrap();
+        }
+        mem.write().await.switch_to_immutable();
+
+        let kernel = Arc::new(GlobalContext::new());
+        let mut task = FlushTask::new(vec![mem],
+                                      0,
+                                      dir,
+                                      opt.delta_dir(126),
+                                      DuplicatePolicy::default(),
+                                      1,
+                                      vec![],
+                                      0);
+        let mut edits = vec![];
+        task.run(Arc::new(RwLock::new(version_set)), kernel.clone(), &mut edits).await.unwrap();
+
+        let stats = kernel.compression_metrics().of(0);
+        let integers = stats.of(ValueType::Integer).unwrap();
+        assert_eq!((integers.blocks, integers.points), (1, 1000));
+        assert_eq!(integers.encodings, BTreeMap::from([("rle", 1)]));
+        assert!(integers.ratio() < 0.01);
+        let floats = stats.of(ValueType::Float).unwrap();
+        assert_eq!(floats.encodings, BTreeMap::from([("gorilla", 1)]));
+        assert!(floats.ratio() > 0.4 && floats.ratio() < 1.0);
+        let strings = stats.of(ValueType::String).unwrap();
+        assert_eq!(strings.encodings, BTreeMap::from([("snappy", 1)]));
+        assert!(strings.ratio() < 0.3);
+        assert_eq!(stats.of(ValueType::Boolean), None);
+        assert_eq!(kernel.compression_metrics().totals(), stats);
+        assert_eq!(kernel.compression_metrics().of(1), CompressionStats::default());
+    }
+
+    #[tokio::test]
+    async fn test_flush_skipped_on_full_disk() {
+        let mem = new_memcache(MemCacheImpl::HashMap, 0, 4096, 1, false);
+        for ts in 1..=10_i64 {
+            mem.write().await.insert_raw(1, 1, ts, ValueType::Integer, &ts.to_be_bytes()).unwrap();
+        }
+        mem.write().await.switch_to_immutable();
+        let reqs = FlushQueue::new(1);
+        reqs.push(FlushReq::new(vec![(0, mem.clone())], 0));
+        let (summary_task_sender, mut summary_task_receiver) = mpsc::unbounded_channel();
+
+        let space = DiskSpace::new(Arc::new(FakeFileSystem(0)));
+        run_flush_memtable_job(&reqs,
+                               Arc::new(GlobalContext::new()),
+                               HashMap::new(),
+                               Arc::new(RwLock::new(VersionSet::new_default())),
+                               summary_task_sender,
+                               &space).await
+                                      .unwrap();
+        // nothing is written, the cache is requested again
+        assert!(summary_task_receiver.recv().await.unwrap().edits.is_empty());
+        let reqs = reqs.take();
+        assert_eq!(reqs.len(), 1);
+        assert_eq!(reqs[0].mems.len(), 1);
+        assert_eq!(Arc::as_ptr(&reqs[0].mems[0].1) as *const u8, Arc::as_ptr(&mem) as *const u8);
+    }
+
+    #[test]
+    fn test_unsorted_block_sorted_before_written() {
+        let tmp = tempfile::tempdir().unwrap();
+        let tf_id = 111;
+        let opt = TseriesFamOpt::for_testing(tmp.path());
+        let dir = opt.tsm_dir(tf_id);
+        std::fs::create_dir_all(&dir).unwrap();
+        let block = DataBlock::I64 { index: 0,
+                                     ts: vec![5, 1, 3, 1],
+                                     val: vec![50, 10, 30, 11],
+                                     validity: None };
+        let mut block_set = HashMap::new();
+        block_set.insert(1, block);
+        build_tsm_file(make_tsm_file_name(&dir, 1), block_set).unwrap();
+
+        let mut lvl = LevelInfo::init_in(&opt.base_dir, 1);
+        lvl.apply(&CompactMeta { file_id: 1,
+                                 range: TimeRange::new(5, 1),
+                                 level: 1,
+                                 ..Default::default() });
+        let mut block = DataBlock::new(0, ValueType::Integer);
+        for data in lvl.files[0].read_field(tf_id, 1, &TimeRange::new(i64::MAX, i64::MIN)).unwrap()
+        {
+            block.insert(data);
+        }
+        assert!(block.is_sorted());
+        assert_eq!(block,
+                   DataBlock::I64 { index: 0,
+                                    ts: vec![1, 3, 5],
+                                    val: vec![11, 30, 50],
+                                    validity: None });
     }
-    Ok(())
 }
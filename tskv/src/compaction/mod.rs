@@ -1,14 +1,27 @@
 mod compact;
+mod filter;
 mod flush;
 mod picker;
+mod scheduler;
+mod space;
+
+use std::fmt::{self, Display};
 
 pub use compact::*;
+pub use filter::*;
 pub use flush::*;
+use parking_lot::Mutex;
 pub use picker::*;
-use tokio::sync::RwLock;
+pub use scheduler::*;
+pub use space::*;
+use tokio::sync::{
+    mpsc::{error::TrySendError, Sender},
+    Notify, RwLock,
+};
 
 use crate::{
-    memcache::MemCache,
+    kv_option::TseriesFamOpt,
+    memcache::MemCacheRef,
     summary::VersionEdit,
     tseries_family::{ColumnFile, Version},
 };
@@ -22,17 +35,228 @@ pub struct CompactReq {
     version: std::sync::Arc<Version>,
     cf: u32,
     out_lvl: u32,
+    opts: std::sync::Arc<TseriesFamOpt>,
+}
+
+/// The request to merge the level 1 files into `out_lvl`, for the tests.
+#[cfg(test)]
+pub fn compact_req(tf_id: u32,
+                   files: Vec<std::sync::Arc<ColumnFile>>,
+                   out_lvl: u32,
+                   opts: std::sync::Arc<TseriesFamOpt>)
+                   -> CompactReq {
+    let version = Version::new(tf_id, 0, "db".to_string(), vec![], 0);
+    CompactReq { files: (1, files),
+                 version: std::sync::Arc::new(version),
+                 cf: tf_id,
+                 out_lvl,
+                 opts }
+}
+
+/// The context of a compaction of the files, allocating the file ids after theirs, for the
+/// tests.
+#[cfg(test)]
+pub fn compact_kernel(files: &[std::sync::Arc<ColumnFile>])
+                      -> std::sync::Arc<crate::context::GlobalContext> {
+    let kernel = crate::context::GlobalContext::new();
+    kernel.set_file_id(files.iter().map(|f| f.file_id()).max().unwrap_or(0) + 1);
+    std::sync::Arc::new(kernel)
 }
 
 #[derive(Debug)]
 pub struct FlushReq {
     //(tsf id,memcache)
-    pub mems: Vec<(u32, std::sync::Arc<RwLock<MemCache>>)>,
+    pub mems: Vec<(u32, MemCacheRef)>,
     pub wait_req: u64,
 }
 
 impl FlushReq {
-    pub fn new(mems: Vec<(u32, std::sync::Arc<RwLock<MemCache>>)>, wait_req: u64) -> Self {
+    pub fn new(mems: Vec<(u32, MemCacheRef)>, wait_req: u64) -> Self {
         Self { mems, wait_req }
     }
 }
+
+/// Sends the request to the flush job of the store. The sender may hold the version set, which
+/// the flush job takes as well, so it does not wait for room: while the flush channel is full
+/// the request is sent by a task of its own. The writes wait for room before they take the
+/// version set, see `FlushQueue::wait_for_room`.
+pub fn send_flush_req(sender: &Sender<FlushReq>, req: FlushReq) {
+    match sender.try_send(req) {
+        Ok(()) => {},
+        Err(TrySendError::Full(req)) => {
+            let sender = sender.clone();
+            tokio::spawn(async move {
+                sender.send(req).await.expect("error send flush req to kvcore");
+            });
+        },
+        Err(TrySendError::Closed(_)) => panic!("error send flush req to kvcore"),
+    }
+}
+
+/// The flush requests of a store waiting for its flush job, the families send their requests
+/// to the job which queues them here. Every store has its own queue, so the stores opened by
+/// one process flush independently.
+///
+/// At most `capacity` requests are queued: the job stops receiving while the queue is full,
+/// so the bounded flush channel fills up, and the writes of the store wait in `wait_for_room`
+/// until the next flush takes the queue.
+#[derive(Debug)]
+pub struct FlushQueue {
+    reqs: Mutex<Vec<FlushReq>>,
+    capacity: usize,
+    // notified when the queue is taken
+    room: Notify,
+}
+
+impl FlushQueue {
+    pub fn new(capacity: usize) -> Self {
+        Self { reqs: Mutex::new(vec![]), capacity: capacity.max(1), room: Notify::new() }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Queues the request, returns the length of the queue. The caches put back by a flush
+    /// are queued even if the queue is full, see `wait_for_room`.
+    pub fn push(&self, req: FlushReq) -> usize {
+        let mut reqs = self.reqs.lock();
+        reqs.push(req);
+        reqs.len()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len() >= self.capacity
+    }
+
+    /// Waits until fewer than `capacity` requests are queued.
+    pub async fn wait_for_room(&self) {
+        loop {
+            // registered before the check, so that a take in between is not missed
+            let taken = self.room.notified();
+            if !self.is_full() {
+                return;
+            }
+            taken.await;
+        }
+    }
+
+    /// Takes every request queued, in the order they were queued.
+    pub fn take(&self) -> Vec<FlushReq> {
+        let reqs = std::mem::take(&mut *self.reqs.lock());
+        self.room.notify_waiters();
+        reqs
+    }
+
+    pub fn len(&self) -> usize {
+        self.reqs.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.reqs.lock().is_empty()
+    }
+
+    /// Returns the caches of the tseries family queued, None if the queue is locked right now.
+    pub fn pending_caches(&self, tf_id: u32) -> Option<usize> {
+        let reqs = self.reqs.try_lock()?;
+        let mems = reqs.iter().flat_map(|req| req.mems.iter());
+        Some(mems.filter(|(id, _)| *id == tf_id).count())
+    }
+}
+
+/// A flush or compaction log line in `key=value` form, e.g.
+/// `event=flush_file tf_id=1 file_id=3 level=1 bytes=4096`.
+#[derive(Debug)]
+pub struct LogEvent {
+    event: &'static str,
+    fields: Vec<(&'static str, String)>,
+}
+
+impl LogEvent {
+    pub fn new(event: &'static str) -> Self {
+        Self { event, fields: vec![] }
+    }
+
+    pub fn field(mut self, key: &'static str, value: impl Display) -> Self {
+        self.fields.push((key, value.to_string()));
+        self
+    }
+}
+
+impl Display for LogEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "event={}", self.event)?;
+        for (key, value) in self.fields.iter() {
+            // quote the values that would break the parsing
+            if value.is_empty() || value.contains(|c: char| c.is_whitespace() || c == '=') {
+                write!(f, " {}={:?}", key, value)?;
+            } else {
+                write!(f, " {}={}", key, value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{sync::Arc, time::Duration};
+
+    use tokio::sync::mpsc;
+
+    use super::{send_flush_req, FlushQueue, FlushReq, LogEvent};
+    use crate::{kv_option::MemCacheImpl, memcache::new_memcache};
+
+    #[test]
+    fn test_log_event() {
+        let event =
+            LogEvent::new("flush_done").field("tf_id", 1)
+                                       .field("req_count", 3)
+                                       .field("bytes", 4096)
+                                       .field("level", 1)
+                                       .field("duration_ms", Duration::from_millis(15).as_millis());
+        assert_eq!(event.to_string(),
+                   "event=flush_done tf_id=1 req_count=3 bytes=4096 level=1 duration_ms=15");
+
+        let event = LogEvent::new("flush_failed").field("reason", "no space left");
+        assert_eq!(event.to_string(), r#"event=flush_failed reason="no space left""#);
+    }
+
+    #[tokio::test]
+    async fn test_flush_queue_bounded() {
+        let mem = |tf_id| (tf_id, new_memcache(MemCacheImpl::HashMap, tf_id, 4096, 0, false));
+        let queue = Arc::new(FlushQueue::new(2));
+        assert_eq!(queue.push(FlushReq::new(vec![mem(1)], 0)), 1);
+        queue.wait_for_room().await;
+        assert_eq!(queue.push(FlushReq::new(vec![mem(2), mem(1)], 0)), 2);
+        assert!(queue.is_full());
+        assert_eq!(queue.pending_caches(1), Some(2));
+        assert_eq!(queue.pending_caches(4), Some(0));
+
+        // a write waits until the queue is taken
+        let room = tokio::time::timeout(Duration::from_millis(50), queue.wait_for_room()).await;
+        assert!(room.is_err());
+        let waiting = tokio::spawn({
+            let queue = queue.clone();
+            async move { queue.wait_for_room().await }
+        });
+        tokio::task::yield_now().await;
+        let reqs = queue.take();
+        assert_eq!(reqs.iter().map(|req| req.mems.len()).collect::<Vec<_>>(), vec![1, 2]);
+        tokio::time::timeout(Duration::from_secs(5), waiting).await.unwrap().unwrap();
+        assert!(queue.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_send_flush_req_full() {
+        let mem = |tf_id| (tf_id, new_memcache(MemCacheImpl::HashMap, tf_id, 4096, 0, false));
+        let (sender, mut receiver) = mpsc::channel(1);
+        send_flush_req(&sender, FlushReq::new(vec![mem(1)], 0));
+        // the channel is full, the request is sent once there is room, without waiting here
+        send_flush_req(&sender, FlushReq::new(vec![mem(2)], 0));
+        for tf_id in [1, 2] {
+            let req = tokio::time::timeout(Duration::from_secs(5), receiver.recv()).await;
+            assert_eq!(req.unwrap().unwrap().mems[0].0, tf_id);
+        }
+    }
+}
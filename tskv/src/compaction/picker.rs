@@ -5,7 +5,7 @@ use crate::{
     direct_io::File,
     error::Result,
     kv_option::TseriesFamOpt,
-    tseries_family::{ColumnFile, Version},
+    tseries_family::{ColumnFile, LevelInfo, TimeRange, Version},
 };
 
 pub struct LevelCompactionPicker {
@@ -17,27 +17,52 @@ impl LevelCompactionPicker {
         Self { cf_opts }
     }
 
+    /// Picks a file found with blocks out of the time range of their index entries to be
+    /// rewritten within its level first. Otherwise picks the files of the level most in need
+    /// of a compaction; if no level needs one, a run of small files is merged within its
+    /// level, see `small_file_threshold`. Returns None if there is no file to compact.
     pub fn pick_compaction(&self, cf: u32, version: Arc<Version>) -> Option<CompactReq> {
         let opts = self.cf_opts.get(&cf).cloned().unwrap();
         let mut ctx = LevelCompatContext::default();
         ctx.cal_score(version.as_ref(), opts.as_ref());
-        if let Some((start_level, out_lvl)) = ctx.pick_level() {
-            let input = ctx.pick_files(version.as_ref(), opts.as_ref(), start_level, out_lvl);
-            if let Some((lvl, mut files)) = input {
-                for file in files.iter_mut() {
-                    file.mark_compaction();
-                }
-                let request = CompactReq { files: (lvl, files),
-                                           version,
-                                           cf,
-                                           out_lvl /* target_file_size_base:
-                                                    * opts.target_file_size_base,
-                                                    * cf_options: opts,
-                                                    * options: self.db_opts.clone(), */ };
-                return Some(request);
+        let mismatched = LevelCompatContext::pick_range_mismatch(version.as_ref());
+        let mut input = mismatched.map(|(lvl, files)| (lvl, files, lvl));
+        if input.is_none() {
+            if let Some((start_level, out_lvl)) = ctx.pick_level() {
+                // a lone file rewritten within its level would be picked again and again
+                input =
+                    ctx.pick_files(version.as_ref(), opts.as_ref(), start_level, out_lvl)
+                       .filter(|(lvl, files)| {
+                           files.len() > 1 || (!files.is_empty() && *lvl != out_lvl)
+                       })
+                       .map(|(lvl, files)| (lvl, files, out_lvl));
             }
         }
-        None
+        let input = input.or_else(|| {
+                             LevelCompatContext::pick_small_files(version.as_ref(), opts.as_ref())
+                                 .map(|(lvl, files)| (lvl, files, lvl))
+                         });
+        let (lvl, mut files, out_lvl) = input?;
+        for file in files.iter_mut() {
+            file.mark_compaction();
+        }
+        let request = CompactReq { files: (lvl, files),
+                                   version,
+                                   cf,
+                                   out_lvl,
+                                   opts /* target_file_size_base:
+                                         * opts.target_file_size_base,
+                                         * options: self.db_opts.clone(), */ };
+        Some(request)
+    }
+
+    /// Returns the score of the level most in need of a compaction, a level from 1 is over
+    /// its budget.
+    pub fn score(&self, cf: u32, version: &Version) -> f64 {
+        let opts = self.cf_opts.get(&cf).cloned().unwrap();
+        let mut ctx = LevelCompatContext::default();
+        ctx.cal_score(version, opts.as_ref());
+        ctx.lvl_scores.iter().fold(0.0, |max, (_, score)| f64::max(max, *score))
     }
 }
 #[derive(Default)]
@@ -68,8 +93,13 @@ impl LevelCompatContext {
                                 info[0].cur_size as f64 / info[base_level].max_size as f64)));
         }
         for (l, item) in info.iter().enumerate() {
-            let score = item.cur_size.checked_div(item.max_size).unwrap();
-            self.lvl_scores.push((l as u32, score as f64));
+            let score = item.cur_size.checked_div(item.max_size).unwrap_or(0);
+            let mut score = score as f64;
+            // a level over the file count is compacted even if it is under the size budget
+            if item.files.len() > opts.max_files_per_level {
+                score = f64::max(score, item.files.len() as f64 / opts.max_files_per_level as f64);
+            }
+            self.lvl_scores.push((l as u32, score));
         }
         self.base_level = 0;
         self.max_level = info.len() as u32 - 1;
@@ -108,7 +138,12 @@ impl LevelCompatContext {
         let mut file_size = 0;
         let max_size = opts.level_file_size(output_level);
         let lvl_info = &infos[level as usize];
-        for file in &lvl_info.files {
+        if lvl_info.files.len() > opts.max_files_per_level {
+            let files = Self::pick_smallest_files(lvl_info, opts);
+            return Some((level, Self::cap_inputs(files, opts)));
+        }
+        // the files of a running compaction are left to it
+        for file in lvl_info.files.iter().filter(|f| !f.is_pending_compaction()) {
             file_size += file.size();
             if ts_min > file.range().min_ts {
                 ts_min = file.range().min_ts;
@@ -122,6 +157,268 @@ impl LevelCompatContext {
                 break;
             }
         }
-        Some((level, inputs))
+        Some((level, Self::cap_inputs(inputs, opts)))
+    }
+
+    // keeps the leading files within the file count and the bytes a compaction may merge, the
+    // files left out stay unmarked and are picked by the next compaction; two files are kept
+    // at least, so that every compaction makes progress
+    fn cap_inputs(mut files: Vec<Arc<ColumnFile>>, opts: &TseriesFamOpt) -> Vec<Arc<ColumnFile>> {
+        let mut count = 0;
+        let mut bytes = 0;
+        for file in files.iter() {
+            bytes += file.size();
+            if count >= 2 && (count >= opts.max_compact_files || bytes > opts.max_compact_size) {
+                break;
+            }
+            count += 1;
+        }
+        files.truncate(count);
+        files
+    }
+
+    // picks the run of files next to each other in time with the fewest bytes in a level with
+    // too many files, merging them into one brings the level back to `max_files_per_level`
+    // files, or closer to it within `max_compact_files`. A run overlapped by another file of
+    // the level is skipped, the merged file would shadow it.
+    fn pick_smallest_files(lvl_info: &LevelInfo, opts: &TseriesFamOpt) -> Vec<Arc<ColumnFile>> {
+        let mut files: Vec<&Arc<ColumnFile>> =
+            lvl_info.files.iter().filter(|f| !f.is_deleted()).collect();
+        files.sort_by_key(|f| (f.range().min_ts, f.range().max_ts));
+        let over = files.len().saturating_sub(opts.max_files_per_level);
+        let count = (over + 1).min(opts.max_compact_files).max(2);
+        let mut picked: Option<(u64, &[&Arc<ColumnFile>])> = None;
+        for run in files.windows(count) {
+            if run.iter().any(|f| f.is_pending_compaction()) || Self::is_shadowed(&files, run) {
+                continue;
+            }
+            let bytes = run.iter().map(|f| f.size()).sum();
+            if picked.map_or(true, |(min, _)| bytes < min) {
+                picked = Some((bytes, run));
+            }
+        }
+        picked.map(|(_, run)| run.iter().map(|f| (*f).clone()).collect()).unwrap_or_default()
+    }
+
+    // returns true if a file of the level out of the run overlaps the time range of the run
+    fn is_shadowed(files: &[&Arc<ColumnFile>], run: &[&Arc<ColumnFile>]) -> bool {
+        let min_ts = run.iter().map(|f| f.range().min_ts).min().unwrap_or(i64::MAX);
+        let max_ts = run.iter().map(|f| f.range().max_ts).max().unwrap_or(i64::MIN);
+        let range = TimeRange::new(max_ts, min_ts);
+        files.iter().any(|f| !run.iter().any(|r| Arc::ptr_eq(*f, *r)) && f.overlap(&range))
+    }
+
+    // picks a file marked by `ColumnFile::mark_range_mismatch`, the rewrite computes the time
+    // ranges of its blocks again. The delta files of level 0 are rewritten by their next
+    // compaction into level 1 anyway.
+    fn pick_range_mismatch(version: &Version) -> Option<(u32, Vec<Arc<ColumnFile>>)> {
+        let marked = |f: &&Arc<ColumnFile>| {
+            f.has_range_mismatch() && !f.is_pending_compaction() && !f.is_deleted()
+        };
+        for info in version.levels_info().iter().filter(|info| info.level > 0) {
+            if let Some(file) = info.files.iter().find(marked) {
+                return Some((info.level, vec![file.clone()]));
+            }
+        }
+        None
+    }
+
+    // picks the first run of files under `small_file_threshold` next to each other in time in
+    // a level, up to `max_small_files` of them; the larger files are left alone. A run
+    // overlapped by another file of the level is skipped, the merged file would shadow it.
+    fn pick_small_files(version: &Version,
+                        opts: &TseriesFamOpt)
+                        -> Option<(u32, Vec<Arc<ColumnFile>>)> {
+        if opts.small_file_threshold == 0 {
+            return None;
+        }
+        let is_small = |f: &&Arc<ColumnFile>| {
+            !f.is_pending_compaction() && f.size() < opts.small_file_threshold
+        };
+        // level 0 holds the delta files
+        for info in version.levels_info().iter().filter(|info| info.level > 0) {
+            let mut files: Vec<&Arc<ColumnFile>> =
+                info.files.iter().filter(|f| !f.is_deleted()).collect();
+            files.sort_by_key(|f| (f.range().min_ts, f.range().max_ts));
+            for run in files.split(|f| !is_small(f)) {
+                let run = &run[..run.len().min(opts.max_small_files.max(2))];
+                if run.len() < 2 {
+                    continue;
+                }
+                if !Self::is_shadowed(&files, run) {
+                    let files = run.iter().map(|f| (*f).clone()).collect();
+                    return Some((info.level, Self::cap_inputs(files, opts)));
+                }
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::HashMap, sync::Arc};
+
+    use config::GLOBAL_CONFIG;
+
+    use super::LevelCompactionPicker;
+    use crate::{
+        compaction::{build_tsm_file, run_compaction_job, CompactReq, DiskSpace},
+        context::GlobalContext,
+        file_utils::make_tsm_file_name,
+        kv_option::TseriesFamOpt,
+        summary::CompactMeta,
+        tseries_family::{LevelInfo, TimeRange, Version},
+        tsm::DataBlock,
+    };
+
+    // the files of the sizes at level 1, the levels are under the base directory
+    fn version_in(base_dir: &str, file_sizes: &[u64]) -> Version {
+        let mut levels: Vec<LevelInfo> = (0..4).map(|l| LevelInfo::init_in(base_dir, l)).collect();
+        for level in levels.iter_mut() {
+            level.max_size = 1024 * 1024;
+        }
+        for (i, size) in file_sizes.iter().enumerate() {
+            levels[1].apply(&CompactMeta { file_id: i as u64 + 1,
+                                           file_size: *size,
+                                           range: TimeRange::new(i as i64 * 10 + 9,
+                                                                 i as i64 * 10),
+                                           level: 1,
+                                           ..Default::default() });
+        }
+        Version::new(0, 0, "db".to_string(), levels, 0)
+    }
+
+    fn version(file_sizes: &[u64]) -> Arc<Version> {
+        Arc::new(version_in(&GLOBAL_CONFIG.base_dir, file_sizes))
+    }
+
+    #[tokio::test]
+    async fn test_pick_by_file_count() {
+        let tmp = tempfile::tempdir().unwrap();
+        // the small files are not merged on their own
+        let opts = TseriesFamOpt { max_files_per_level: 4,
+                                   small_file_threshold: 0,
+                                   ..TseriesFamOpt::for_testing(tmp.path()) };
+        let picker = LevelCompactionPicker::new(HashMap::from([(0, Arc::new(opts.clone()))]));
+
+        // under the size budget and the file count
+        assert!(picker.pick_compaction(0, version(&[10, 20, 30, 40])).is_none());
+
+        let sizes = [60, 10, 50, 20, 40, 30];
+        let dir = opts.tsm_dir(0);
+        std::fs::create_dir_all(&dir).unwrap();
+        for file_id in 1..=sizes.len() as i64 {
+            let ts: Vec<i64> = ((file_id - 1) * 10..file_id * 10).collect();
+            let block = DataBlock::I64 { index: 0, ts, val: vec![file_id; 10], validity: None };
+            let path = make_tsm_file_name(&dir, file_id as u64);
+            build_tsm_file(path, HashMap::from([(1, block)])).unwrap();
+        }
+        let mut version = version_in(&opts.base_dir, &sizes);
+        let req = picker.pick_compaction(0, Arc::new(version.compaction_view(&opts))).unwrap();
+        assert_eq!(req.files.0, 1);
+        // the run of three files next to each other in time with the fewest bytes
+        let mut picked: Vec<u64> = req.files.1.iter().map(|f| f.size()).collect();
+        picked.sort_unstable();
+        assert_eq!(picked, vec![10, 20, 50]);
+
+        // the merged file replaces the picked ones
+        let kernel = Arc::new(GlobalContext::new());
+        kernel.set_file_id(sizes.len() as u64 + 1);
+        let (edit, _) =
+            run_compaction_job(req, kernel, &DiskSpace::default()).await.unwrap().unwrap();
+        version.commit_compaction(&edit).join().unwrap();
+        version.take_unreferenced(u64::MAX);
+        assert_eq!(version.levels_info[1].files.len(), 3);
+        assert!(version.levels_info[1].files.len() <= opts.max_files_per_level);
+        assert_eq!(version.levels_info[2].files.len(), 1);
+    }
+
+    #[test]
+    fn test_pick_with_input_cap() {
+        let opts =
+            TseriesFamOpt { max_files_per_level: 4, max_compact_files: 8, ..Default::default() };
+        let picker = LevelCompactionPicker::new(HashMap::from([(0, Arc::new(opts))]));
+        let version = version(&[10; 100]);
+
+        // every pick stays under the cap, the files left out are picked by the next one
+        let first = picker.pick_compaction(0, version.clone()).unwrap();
+        assert_eq!(first.files.1.len(), 8);
+        let second = picker.pick_compaction(0, version.clone()).unwrap();
+        assert_eq!(second.files.1.len(), 8);
+        let ids = |req: &CompactReq| req.files.1.iter().map(|f| f.file_id()).collect::<Vec<_>>();
+        assert!(ids(&second).iter().all(|id| !ids(&first).contains(id)));
+
+        let opts =
+            TseriesFamOpt { max_files_per_level: 4, max_compact_size: 35, ..Default::default() };
+        let picker = LevelCompactionPicker::new(HashMap::from([(0, Arc::new(opts))]));
+        let req = picker.pick_compaction(0, version(&[10; 100])).unwrap();
+        assert_eq!(req.files.1.len(), 3);
+    }
+
+    #[test]
+    fn test_pick_adjacent_files() {
+        let opts = TseriesFamOpt { max_files_per_level: 4, ..Default::default() };
+        let picker = LevelCompactionPicker::new(HashMap::from([(0, Arc::new(opts))]));
+        let ids = |req: CompactReq| {
+            let mut ids: Vec<u64> = req.files.1.iter().map(|f| f.file_id()).collect();
+            ids.sort_unstable();
+            ids
+        };
+
+        // the three smallest files are not next to each other in time
+        let version = version(&[10, 100, 10, 100, 10, 10]);
+        assert_eq!(ids(picker.pick_compaction(0, version).unwrap()), vec![1, 2, 3]);
+
+        // file 6 overlaps files 1 and 2, a run holding only some of them is not picked
+        let mut version = version_in(&GLOBAL_CONFIG.base_dir, &[100, 10, 10, 10, 10]);
+        version.levels_info[1].apply(&CompactMeta { file_id: 6,
+                                                    file_size: 10,
+                                                    range: TimeRange::new(15, 0),
+                                                    level: 1,
+                                                    ..Default::default() });
+        assert_eq!(ids(picker.pick_compaction(0, Arc::new(version)).unwrap()), vec![3, 4, 5]);
+    }
+
+    #[test]
+    fn test_pick_small_files() {
+        let opts = TseriesFamOpt { max_files_per_level: 64,
+                                   small_file_threshold: 100,
+                                   max_small_files: 16,
+                                   ..Default::default() };
+        let picker = LevelCompactionPicker::new(HashMap::from([(0, Arc::new(opts))]));
+        // 50 tiny files, and a big one in the middle of them in time
+        let mut sizes = vec![10; 51];
+        sizes[25] = 100_000;
+        let version = version(&sizes);
+        let big = version.levels_info()[1].files[25].clone();
+
+        let mut picks = vec![];
+        while let Some(req) = picker.pick_compaction(0, version.clone()) {
+            assert_eq!((req.files.0, req.out_lvl), (1, 1));
+            picks.push(req.files.1.iter().map(|f| f.file_id()).collect::<Vec<_>>());
+        }
+        assert_eq!(picks.iter().map(|p| p.len()).collect::<Vec<_>>(), vec![16, 9, 16, 9]);
+        let mut picked: Vec<u64> = picks.into_iter().flatten().collect();
+        picked.sort_unstable();
+        assert_eq!(picked, (1..=51).filter(|id| *id != big.file_id()).collect::<Vec<_>>());
+        assert!(!big.is_pending_compaction());
+    }
+
+    #[test]
+    fn test_pick_range_mismatch() {
+        let opts = TseriesFamOpt { max_files_per_level: 4, ..Default::default() };
+        let picker = LevelCompactionPicker::new(HashMap::from([(0, Arc::new(opts))]));
+        let version = version(&[10, 20, 30, 40]);
+        assert!(picker.pick_compaction(0, version.clone()).is_none());
+
+        // the marked file is rewritten on its own, within its level
+        let file = version.levels_info()[1].files[2].clone();
+        file.mark_range_mismatch();
+        let req = picker.pick_compaction(0, version.clone()).unwrap();
+        assert_eq!((req.files.0, req.out_lvl), (1, 1));
+        assert_eq!(req.files.1.iter().map(|f| f.file_id()).collect::<Vec<_>>(),
+                   vec![file.file_id()]);
+        assert!(picker.pick_compaction(0, version).is_none());
     }
 }
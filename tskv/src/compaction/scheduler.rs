@@ -0,0 +1,243 @@
+use std::{cmp::Ordering, collections::HashMap, sync::Arc};
+
+use chrono::NaiveTime;
+
+use crate::{
+    clock::{Clock, SystemClock},
+    compaction::{CompactReq, LevelCompactionPicker},
+    error::{Error, Result},
+    kv_option::{CompactionPolicy, TseriesFamOpt},
+    tseries_family::Version,
+};
+
+/// A range of the local time of day the background compactions run in, it goes past midnight
+/// if it ends before it starts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompactionWindow {
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl CompactionWindow {
+    pub fn new(start: NaiveTime, end: NaiveTime) -> Self {
+        Self { start, end }
+    }
+
+    /// Parses a window like "02:00-05:00", optionally followed by "local", an empty string
+    /// is no window.
+    pub fn parse(window: &str) -> Result<Option<Self>> {
+        let invalid = || Error::InvalidCompactionWindow { window: window.to_string() };
+        let range = window.trim();
+        let range = range.strip_suffix("local").unwrap_or(range).trim_end();
+        if range.is_empty() {
+            return Ok(None);
+        }
+        let (start, end) = range.split_once('-').ok_or_else(invalid)?;
+        let parse = |time: &str| NaiveTime::parse_from_str(time.trim(), "%H:%M");
+        match (parse(start), parse(end)) {
+            (Ok(start), Ok(end)) if start != end => Ok(Some(Self::new(start, end))),
+            _ => Err(invalid()),
+        }
+    }
+
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start < self.end {
+            self.start <= time && time < self.end
+        } else {
+            self.start <= time || time < self.end
+        }
+    }
+}
+
+/// Chooses the tseries family compacted next among many, by the compaction policy.
+pub struct CompactionScheduler {
+    picker: LevelCompactionPicker,
+    policy: CompactionPolicy,
+    // the tseries family compacted last, the fair policy starts after it
+    last: Option<u32>,
+    // out of the window only the families scored at least `urgent_score` are compacted
+    window: Option<CompactionWindow>,
+    urgent_score: f64,
+    clock: Arc<dyn Clock>,
+}
+
+impl CompactionScheduler {
+    pub fn new(picker: LevelCompactionPicker, policy: CompactionPolicy) -> Self {
+        Self { picker,
+               policy,
+               last: None,
+               window: None,
+               urgent_score: f64::INFINITY,
+               clock: Arc::new(SystemClock) }
+    }
+
+    /// Defers the compactions out of the window, unless the score of the family is at least
+    /// `urgent_score`.
+    pub fn with_window(mut self, window: Option<CompactionWindow>, urgent_score: f64) -> Self {
+        self.window = window;
+        self.urgent_score = urgent_score;
+        self
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Replaces the options of the tseries families the picker picks from, a family missing
+    /// from them must not be passed to `next`.
+    pub fn set_family_opts(&mut self, cf_opts: HashMap<u32, Arc<TseriesFamOpt>>) {
+        self.picker = LevelCompactionPicker::new(cf_opts);
+    }
+
+    /// Returns the next compaction of the tseries families of `versions`, given with their
+    /// ids, or None if none of them has a file to compact.
+    ///
+    /// With `Throughput` the families are tried from the highest score down; with `Fair` they
+    /// are tried in the order of their ids, starting after the one compacted last, so a family
+    /// needing a compaction waits at most for one compaction of each other family. Out of the
+    /// compaction window only the urgent families are tried.
+    pub fn next(&mut self, versions: &[(u32, Arc<Version>)]) -> Option<CompactReq> {
+        let mut order: Vec<&(u32, Arc<Version>)> = versions.iter().collect();
+        if !self.in_window() {
+            order.retain(|v| self.picker.score(v.0, v.1.as_ref()) >= self.urgent_score);
+        }
+        match self.policy {
+            CompactionPolicy::Throughput => {
+                let mut scored: Vec<(f64, &(u32, Arc<Version>))> =
+                    order.into_iter().map(|v| (self.picker.score(v.0, v.1.as_ref()), v)).collect();
+                scored.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(Ordering::Equal));
+                order = scored.into_iter().map(|(_, v)| v).collect();
+            },
+            CompactionPolicy::Fair => {
+                order.sort_by_key(|(tf_id, _)| *tf_id);
+                if let Some(last) = self.last {
+                    let start = order.partition_point(|(tf_id, _)| *tf_id <= last);
+                    order.rotate_left(start);
+                }
+            },
+        }
+        for (tf_id, version) in order {
+            if let Some(req) = self.picker.pick_compaction(*tf_id, version.clone()) {
+                self.last = Some(*tf_id);
+                return Some(req);
+            }
+        }
+        None
+    }
+
+    fn in_window(&self) -> bool {
+        self.window.map_or(true, |window| window.contains(self.clock.now().time()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use chrono::{Duration, NaiveDate, NaiveDateTime, NaiveTime};
+
+    use super::{CompactionScheduler, CompactionWindow};
+    use crate::{
+        clock::MockClock,
+        compaction::LevelCompactionPicker,
+        kv_option::{CompactionPolicy, TseriesFamOpt},
+        summary::CompactMeta,
+        tseries_family::{LevelInfo, TimeRange, Version},
+    };
+
+    fn version(tf_id: u32, files: u64) -> Arc<Version> {
+        let mut levels: Vec<LevelInfo> = (0..4).map(LevelInfo::init).collect();
+        for level in levels.iter_mut() {
+            level.max_size = 1024 * 1024;
+        }
+        for i in 0..files {
+            levels[1].apply(&CompactMeta { file_id: i + 1,
+                                           file_size: 10,
+                                           range: TimeRange::new(i as i64 * 10 + 9,
+                                                                 i as i64 * 10),
+                                           level: 1,
+                                           tsf_id: tf_id,
+                                           ..Default::default() });
+        }
+        Arc::new(Version::new(tf_id, 0, "db".to_string(), levels, 0))
+    }
+
+    fn picker() -> LevelCompactionPicker {
+        let opts = TseriesFamOpt { max_files_per_level: 4,
+                                   max_compact_files: 8,
+                                   small_file_threshold: 0,
+                                   ..Default::default() };
+        let opts = Arc::new(opts);
+        LevelCompactionPicker::new((1..=4).map(|tf_id| (tf_id, opts.clone())).collect())
+    }
+
+    // the families picked by the first `count` compactions
+    fn picks(policy: CompactionPolicy, count: usize) -> Vec<u32> {
+        let mut scheduler = CompactionScheduler::new(picker(), policy);
+        // the first family is far over its file count, the others just over it
+        let versions: Vec<_> =
+            [(3, 6), (1, 200), (4, 6), (2, 6)].into_iter()
+                                              .map(|(tf_id, files)| (tf_id, version(tf_id, files)))
+                                              .collect();
+        (0..count).map_while(|_| scheduler.next(&versions)).map(|req| req.cf).collect()
+    }
+
+    #[test]
+    fn test_fair_policy() {
+        // the busiest family keeps the others waiting
+        let picks_by_score = picks(CompactionPolicy::Throughput, 8);
+        assert_eq!(picks_by_score, vec![1; 8]);
+
+        // every family over its budget is compacted within one turn of the others, the
+        // busiest one goes on alone once the others are done
+        let picks = picks(CompactionPolicy::Fair, 12);
+        assert_eq!(picks, vec![1, 2, 3, 4, 1, 2, 3, 4, 1, 1, 1, 1]);
+    }
+
+    #[test]
+    fn test_parse_compaction_window() {
+        let time = |hour, min| NaiveTime::from_hms_opt(hour, min, 0).unwrap();
+        let window = CompactionWindow::parse("02:00-05:00").unwrap().unwrap();
+        assert_eq!(window, CompactionWindow::new(time(2, 0), time(5, 0)));
+        assert!(window.contains(time(2, 0)));
+        assert!(window.contains(time(4, 59)));
+        assert!(!window.contains(time(5, 0)));
+        assert!(!window.contains(time(1, 59)));
+
+        // past midnight
+        let window = CompactionWindow::parse(" 22:30 - 04:00 local ").unwrap().unwrap();
+        assert!(window.contains(time(23, 0)));
+        assert!(window.contains(time(3, 0)));
+        assert!(!window.contains(time(12, 0)));
+
+        assert_eq!(CompactionWindow::parse("").unwrap(), None);
+        for invalid in ["02:00", "02:00-25:00", "03:00-03:00", "2am-5am"] {
+            assert!(CompactionWindow::parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_compaction_window() {
+        let at = |hour, min| -> NaiveDateTime {
+            NaiveDate::from_ymd_opt(2022, 8, 1).unwrap().and_hms_opt(hour, min, 0).unwrap()
+        };
+        let clock = Arc::new(MockClock::new(at(12, 0)));
+        let window = CompactionWindow::parse("02:00-05:00 local").unwrap();
+        let scheduler = CompactionScheduler::new(picker(), CompactionPolicy::Fair);
+        let mut scheduler = scheduler.with_window(window, 10.0).with_clock(clock.clone());
+
+        // a family just over its file count waits for the window
+        assert!(scheduler.next(&[(2, version(2, 6))]).is_none());
+        clock.set(at(4, 59));
+        let req = scheduler.next(&[(2, version(2, 6))]).unwrap();
+        assert_eq!(req.cf, 2);
+
+        // out of the window again, only the family far over its file count is compacted
+        clock.advance(Duration::minutes(1));
+        let versions = [(1, version(1, 200)), (2, version(2, 6))];
+        let picks: Vec<u32> =
+            (0..3).map_while(|_| scheduler.next(&versions)).map(|req| req.cf).collect();
+        assert_eq!(picks, vec![1, 1, 1]);
+    }
+}
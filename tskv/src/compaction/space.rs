@@ -0,0 +1,145 @@
+use std::{ffi::CString, io, os::unix::ffi::OsStrExt, path::Path, sync::Arc};
+
+use logger::warn;
+use parking_lot::Mutex;
+
+use crate::error::{Error, Result};
+
+// the weight of the last observed job in a size ratio
+const OBSERVED_WEIGHT: f64 = 0.2;
+// memcache cells shrink when they are encoded into blocks
+const INITIAL_FLUSH_RATIO: f64 = 0.5;
+// merged files are about as large as their inputs
+const INITIAL_COMPACT_RATIO: f64 = 1.0;
+
+/// The file system holding the tsm files, a trait so that tests can fake a full disk.
+pub trait FileSystem: Send + Sync {
+    /// Returns the bytes available to write on the file system holding the path.
+    fn available_space(&self, path: &Path) -> Result<u64>;
+}
+
+#[derive(Debug, Default)]
+pub struct LocalFileSystem;
+
+impl FileSystem for LocalFileSystem {
+    fn available_space(&self, path: &Path) -> Result<u64> {
+        // the directory of a tseries family is created by its first file
+        let path = path.ancestors().find(|p| p.exists()).unwrap_or(path);
+        let c_path = CString::new(path.as_os_str().as_bytes())
+            .map_err(|e| Error::IO { source: io::Error::new(io::ErrorKind::InvalidInput, e) })?;
+        let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+        if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+            return Err(Error::IO { source: io::Error::last_os_error() });
+        }
+        Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+    }
+}
+
+/// A file system with a fixed available space, to fake a full disk in tests.
+#[cfg(test)]
+pub struct FakeFileSystem(pub u64);
+
+#[cfg(test)]
+impl FileSystem for FakeFileSystem {
+    fn available_space(&self, _path: &Path) -> Result<u64> {
+        Ok(self.0)
+    }
+}
+
+/// The output/input size ratio of a kind of job, tuned by the jobs observed recently.
+#[derive(Debug)]
+pub struct SizeRatio {
+    ratio: Mutex<f64>,
+}
+
+impl SizeRatio {
+    pub fn new(ratio: f64) -> Self {
+        Self { ratio: Mutex::new(ratio) }
+    }
+
+    pub fn ratio(&self) -> f64 {
+        *self.ratio.lock()
+    }
+
+    /// Returns the estimated output size of a job reading `input` bytes.
+    pub fn estimate(&self, input: u64) -> u64 {
+        (input as f64 * self.ratio()).ceil() as u64
+    }
+
+    pub fn observe(&self, input: u64, output: u64) {
+        if input == 0 {
+            return;
+        }
+        let mut ratio = self.ratio.lock();
+        *ratio = *ratio * (1.0 - OBSERVED_WEIGHT) + output as f64 / input as f64 * OBSERVED_WEIGHT;
+    }
+}
+
+/// Checks the disk space before a flush or compaction, so that a job is not started on a
+/// disk too full to hold its output.
+pub struct DiskSpace {
+    fs: Arc<dyn FileSystem>,
+    flush_ratio: SizeRatio,
+    compact_ratio: SizeRatio,
+}
+
+impl DiskSpace {
+    pub fn new(fs: Arc<dyn FileSystem>) -> Self {
+        Self { fs,
+               flush_ratio: SizeRatio::new(INITIAL_FLUSH_RATIO),
+               compact_ratio: SizeRatio::new(INITIAL_COMPACT_RATIO) }
+    }
+
+    pub fn flush_ratio(&self) -> &SizeRatio {
+        &self.flush_ratio
+    }
+
+    pub fn compact_ratio(&self) -> &SizeRatio {
+        &self.compact_ratio
+    }
+
+    /// Returns the available bytes of the path, or None if it is unknown, in which case the
+    /// job is not held back.
+    pub fn available(&self, path: &Path) -> Option<u64> {
+        match self.fs.available_space(path) {
+            Ok(bytes) => Some(bytes),
+            Err(err) => {
+                warn!("unable to get the available space of {}: {}", path.display(), err);
+                None
+            },
+        }
+    }
+}
+
+impl Default for DiskSpace {
+    fn default() -> Self {
+        Self::new(Arc::new(LocalFileSystem))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use super::{FileSystem, LocalFileSystem, SizeRatio};
+
+    #[test]
+    fn test_size_ratio() {
+        let ratio = SizeRatio::new(0.5);
+        assert_eq!(ratio.estimate(100), 50);
+        ratio.observe(0, 100);
+        assert_eq!(ratio.ratio(), 0.5);
+        for _ in 0..50 {
+            ratio.observe(100, 30);
+        }
+        assert!((ratio.ratio() - 0.3).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_local_available_space() {
+        let fs = LocalFileSystem;
+        assert!(fs.available_space(Path::new("/tmp")).unwrap() > 0);
+        // falls back to the nearest existing directory
+        assert!(fs.available_space(Path::new("/tmp/not/created/yet")).is_ok());
+    }
+}
@@ -3,12 +3,17 @@ use std::sync::{
     Arc,
 };
 
+use crate::{compaction::CompactionMetrics, tsm::CompressionMetrics, write_stats::WriteRejects};
+
 #[derive(Default)]
 pub struct GlobalContext {
     file_id: AtomicU64,
     mem_seq: AtomicU64,
     last_seq: AtomicU64,
     max_tsf_id: AtomicU32,
+    compaction_metrics: CompactionMetrics,
+    compression_metrics: CompressionMetrics,
+    write_rejects: WriteRejects,
 }
 
 impl GlobalContext {
@@ -16,7 +21,10 @@ impl GlobalContext {
         Self { file_id: AtomicU64::new(0),
                mem_seq: AtomicU64::new(0),
                last_seq: AtomicU64::new(0),
-               max_tsf_id: AtomicU32::new(0) }
+               max_tsf_id: AtomicU32::new(0),
+               compaction_metrics: CompactionMetrics::default(),
+               compression_metrics: CompressionMetrics::default(),
+               write_rejects: WriteRejects::default() }
     }
 }
 
@@ -29,6 +37,12 @@ impl GlobalContext {
         self.file_id.fetch_add(1, Ordering::SeqCst);
     }
 
+    /// Allocates a file id. The ids are never reused, also across restarts, as the summary
+    /// recovers the allocator past every file id of its edits.
+    pub fn next_file_id(&self) -> u64 {
+        self.file_id.fetch_add(1, Ordering::SeqCst)
+    }
+
     pub fn mem_seq_next(&self) -> u64 {
         self.mem_seq.fetch_add(1, Ordering::SeqCst)
     }
@@ -60,6 +74,20 @@ impl GlobalContext {
         self.max_tsf_id.fetch_add(1, Ordering::SeqCst);
     }
 
+    pub fn compaction_metrics(&self) -> &CompactionMetrics {
+        &self.compaction_metrics
+    }
+
+    /// The compression of the blocks written by the flushes and the compactions.
+    pub fn compression_metrics(&self) -> &CompressionMetrics {
+        &self.compression_metrics
+    }
+
+    /// The points rejected by the writes of all the tseries families.
+    pub fn write_rejects(&self) -> &WriteRejects {
+        &self.write_rejects
+    }
+
     pub fn mark_log_number_used(&self, v: u64) {
         let mut old = self.file_id.load(Ordering::Acquire);
         while old <= v {
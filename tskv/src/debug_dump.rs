@@ -0,0 +1,195 @@
+use serde::{Serialize, Serializer};
+use tokio::sync::RwLock;
+
+use crate::{
+    kv_option::TseriesFamOpt,
+    memcache::CacheSummary,
+    tseries_family::{ColumnFile, LevelInfo, Version},
+    write_stats::RejectCounts,
+};
+
+/// A part of a dump read behind a lock, `Unavailable` if the lock was held by someone else;
+/// serialized as the value itself or as `"unavailable"`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DumpField<T> {
+    Available(T),
+    Unavailable,
+}
+
+impl<T> DumpField<T> {
+    /// Reads the value behind the lock only if the lock is free right now.
+    pub fn try_read<L: ?Sized>(lock: &RwLock<L>, f: impl FnOnce(&L) -> T) -> Self {
+        match lock.try_read() {
+            Ok(guard) => Self::Available(f(&guard)),
+            Err(_) => Self::Unavailable,
+        }
+    }
+
+    pub fn is_available(&self) -> bool {
+        matches!(self, Self::Available(_))
+    }
+
+    pub fn available(&self) -> Option<&T> {
+        match self {
+            Self::Available(v) => Some(v),
+            Self::Unavailable => None,
+        }
+    }
+}
+
+impl<T: Serialize> Serialize for DumpField<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Self::Available(v) => v.serialize(serializer),
+            Self::Unavailable => serializer.serialize_str("unavailable"),
+        }
+    }
+}
+
+/// The in-memory state of a tseries family, taken without waiting for any lock.
+#[derive(Debug, Clone, Serialize)]
+pub struct TsfDebugDump {
+    pub tf_id: u32,
+    pub seq_no: u64,
+    pub super_version_id: u64,
+    pub immut_ts_min: i64,
+    pub mut_ts_max: i64,
+    pub mut_cache: DumpField<CacheSummary>,
+    pub delta_cache: DumpField<CacheSummary>,
+    // from the oldest to the newest
+    pub immut_caches: Vec<DumpField<CacheSummary>>,
+    // caches queued for a flush
+    pub pending_flushes: DumpField<usize>,
+    // points rejected by the writes since the family is opened
+    pub rejected: RejectCounts,
+    pub version: DumpField<VersionDump>,
+    pub options: OptionsDump,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct VersionDump {
+    pub name: String,
+    pub last_seq: u64,
+    pub max_level_ts: i64,
+    // files picked by a compaction that is not committed yet
+    pub pending_compaction_files: usize,
+    pub levels: Vec<LevelDump>,
+}
+
+impl VersionDump {
+    pub fn new(version: &Version) -> Self {
+        let files = version.levels_info.iter().flat_map(|info| info.files.iter());
+        Self { name: version.name.clone(),
+               last_seq: version.last_seq,
+               max_level_ts: version.max_level_ts,
+               pending_compaction_files: files.filter(|f| f.is_pending_compaction()).count(),
+               levels: version.levels_info.iter().map(LevelDump::new).collect() }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LevelDump {
+    pub level: u32,
+    pub bytes: u64,
+    pub ts_range: (i64, i64),
+    pub files: Vec<FileDump>,
+}
+
+impl LevelDump {
+    fn new(info: &LevelInfo) -> Self {
+        Self { level: info.level,
+               bytes: info.cur_size,
+               ts_range: (info.ts_range.min_ts, info.ts_range.max_ts),
+               files: info.files.iter().map(|f| FileDump::new(f)).collect() }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FileDump {
+    pub file_id: u64,
+    pub bytes: u64,
+    pub ts_range: (i64, i64),
+    pub is_delta: bool,
+    pub deleted: bool,
+    pub being_compacted: bool,
+    // the fields and the tombstones are loaded
+    pub warm: bool,
+    pub read_count: u64,
+}
+
+impl FileDump {
+    fn new(file: &ColumnFile) -> Self {
+        Self { file_id: file.file_id(),
+               bytes: file.size(),
+               ts_range: (file.range().min_ts, file.range().max_ts),
+               is_delta: file.is_delta(),
+               deleted: file.is_deleted(),
+               being_compacted: file.is_pending_compaction(),
+               warm: file.is_warm(),
+               read_count: file.read_count() }
+    }
+}
+
+/// The options of a tseries family, the policies are written as their names.
+#[derive(Debug, Clone, Serialize)]
+pub struct OptionsDump {
+    pub max_level: u32,
+    pub level_ratio: f64,
+    pub base_file_size: u64,
+    pub compact_trigger: u32,
+    pub max_compact_size: u64,
+    pub base_dir: String,
+    pub trash_dir: String,
+    pub trash_ttl_secs: u64,
+    pub duplicate_policy: String,
+    pub utf8_policy: String,
+    pub max_entry_cells: usize,
+    pub max_flush_level: u32,
+    pub max_files_per_level: usize,
+    pub max_compact_files: usize,
+    pub small_file_threshold: u64,
+    pub max_small_files: usize,
+    pub read_parallelism: usize,
+    pub ooo_tolerance_ns: i64,
+    pub max_delta_cache_size: u64,
+    pub delta_flush_age_secs: u64,
+    pub result_cache_entries: usize,
+    pub result_cache_ttl_ms: u64,
+    pub last_value_entries: usize,
+    pub memcache_impl: String,
+    pub compaction_filter: bool,
+    pub level_compression: String,
+    pub flush_time_window_ns: i64,
+}
+
+impl OptionsDump {
+    pub fn new(opt: &TseriesFamOpt) -> Self {
+        Self { max_level: opt.max_level,
+               level_ratio: opt.level_ratio,
+               base_file_size: opt.base_file_size,
+               compact_trigger: opt.compact_trigger,
+               max_compact_size: opt.max_compact_size,
+               base_dir: opt.base_dir.clone(),
+               trash_dir: opt.trash_dir.clone(),
+               trash_ttl_secs: opt.trash_ttl_secs,
+               duplicate_policy: format!("{:?}", opt.duplicate_policy),
+               utf8_policy: format!("{:?}", opt.utf8_policy),
+               max_entry_cells: opt.max_entry_cells,
+               max_flush_level: opt.max_flush_level,
+               max_files_per_level: opt.max_files_per_level,
+               max_compact_files: opt.max_compact_files,
+               small_file_threshold: opt.small_file_threshold,
+               max_small_files: opt.max_small_files,
+               read_parallelism: opt.read_parallelism,
+               ooo_tolerance_ns: opt.ooo_tolerance_ns,
+               max_delta_cache_size: opt.max_delta_cache_size,
+               delta_flush_age_secs: opt.delta_flush_age_secs,
+               result_cache_entries: opt.result_cache_entries,
+               result_cache_ttl_ms: opt.result_cache_ttl_ms,
+               last_value_entries: opt.last_value_entries,
+               memcache_impl: format!("{:?}", opt.memcache_impl),
+               compaction_filter: opt.compaction_filter.is_some(),
+               level_compression: format!("{:?}", opt.level_compression),
+               flush_time_window_ns: opt.flush_time_window_ns }
+    }
+}
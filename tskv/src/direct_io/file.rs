@@ -1,5 +1,6 @@
 pub mod cursor;
 mod os;
+pub mod retry;
 mod scope;
 pub mod system;
 
@@ -16,6 +17,7 @@ use std::{
 use cursor::FileCursor;
 use dashmap::DashMap;
 use os::*;
+use retry::RetryPolicy;
 use scope::FileScope;
 use static_assertions::*;
 
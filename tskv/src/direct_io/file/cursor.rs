@@ -5,11 +5,13 @@ use std::{
 };
 
 use super::File;
+use crate::direct_io::{io_scheduler, IoClass};
 
 #[derive(Clone)]
 pub struct FileCursor {
     file: File,
     pos: u64,
+    io_class: IoClass,
 }
 
 impl FileCursor {
@@ -25,14 +27,19 @@ impl FileCursor {
         self.pos = pos;
     }
 
+    /// Sets the priority of the reads and writes of the cursor, high by default.
+    pub fn set_io_class(&mut self, io_class: IoClass) {
+        self.io_class = io_class;
+    }
+
     pub fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
-        let read = self.file.read_at(self.pos, buf)?;
+        let read = io_scheduler().read_at(self.io_class, &self.file, self.pos, buf)?;
         self.seek(SeekFrom::Current(read.try_into().unwrap())).unwrap();
         Ok(read)
     }
 
     pub fn write(&mut self, buf: &[u8]) -> Result<()> {
-        self.file.write_at(self.pos, buf)?;
+        io_scheduler().write_at(self.io_class, &self.file, self.pos, buf)?;
         self.seek(SeekFrom::Current(buf.len().try_into().unwrap())).unwrap();
         Ok(())
     }
@@ -63,7 +70,7 @@ impl FileCursor {
 
 impl From<File> for FileCursor {
     fn from(file: File) -> Self {
-        FileCursor { file, pos: 0 }
+        FileCursor { file, pos: 0, io_class: IoClass::High }
     }
 }
 
@@ -0,0 +1,89 @@
+use std::{
+    io::{ErrorKind, Result},
+    thread,
+    time::Duration,
+};
+
+// the backoff stops doubling after this many retries
+const MAX_BACKOFF_SHIFT: usize = 10;
+
+/// Retries the io calls failed with EAGAIN, the backoff doubles on every retry. The other
+/// errors, such as EIO or ENOSPC, are returned at once. EINTR is returned too, the callers
+/// retry an interrupted call at once without counting it against `max_retries`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    pub max_retries: usize,
+    pub backoff: Duration,
+}
+
+impl RetryPolicy {
+    pub fn is_transient(kind: ErrorKind) -> bool {
+        kind == ErrorKind::WouldBlock
+    }
+
+    pub fn run<T>(&self, mut f: impl FnMut() -> Result<T>) -> Result<T> {
+        let mut retries = 0;
+        loop {
+            match f() {
+                Err(e) if Self::is_transient(e.kind()) && retries < self.max_retries => {
+                    thread::sleep(self.backoff * (1_u32 << retries.min(MAX_BACKOFF_SHIFT)));
+                    retries += 1;
+                },
+                res => return res,
+            }
+        }
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self { max_retries: 8, backoff: Duration::from_millis(1) }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        cell::Cell,
+        io::{Error, ErrorKind},
+        time::Duration,
+    };
+
+    use super::RetryPolicy;
+
+    #[test]
+    fn test_retry_transient_errors() {
+        let retry = RetryPolicy { max_retries: 3, backoff: Duration::from_millis(1) };
+        let calls = Cell::new(0);
+        // fails with EAGAIN twice, then succeeds
+        let res = retry.run(|| {
+                           calls.set(calls.get() + 1);
+                           match calls.get() {
+                               1 | 2 => Err(Error::from_raw_os_error(libc::EAGAIN)),
+                               _ => Ok(42),
+                           }
+                       });
+        assert_eq!(res.unwrap(), 42);
+        assert_eq!(calls.get(), 3);
+
+        // gives up after the max retries
+        calls.set(0);
+        let res: std::io::Result<()> = retry.run(|| {
+                                                calls.set(calls.get() + 1);
+                                                Err(Error::from(ErrorKind::WouldBlock))
+                                            });
+        assert_eq!(res.unwrap_err().kind(), ErrorKind::WouldBlock);
+        assert_eq!(calls.get(), 4);
+
+        // fails fast on the other errors, EINTR is retried by the callers
+        for errno in [libc::EIO, libc::ENOSPC, libc::EINTR] {
+            calls.set(0);
+            let res: std::io::Result<()> = retry.run(|| {
+                                                    calls.set(calls.get() + 1);
+                                                    Err(Error::from_raw_os_error(errno))
+                                                });
+            assert_eq!(res.unwrap_err().raw_os_error(), Some(errno));
+            assert_eq!(calls.get(), 1);
+        }
+    }
+}
@@ -12,6 +12,7 @@ pub struct FileScope {
     id: FileId,
     file: Option<Arc<StdFile>>,
     len: AtomicU64,
+    retry: RetryPolicy,
 }
 
 impl FileScope {
@@ -19,12 +20,14 @@ impl FileScope {
                scope_map: &Arc<ScopeMap>,
                file: StdFile,
                id: FileId,
-               len: u64)
+               len: u64,
+               retry: RetryPolicy)
                -> Result<ScopeHandle> {
         let scope = Self { scope_map: Arc::downgrade(scope_map),
                            id,
                            file: Some(Arc::new(file)),
-                           len: len.into() };
+                           len: len.into(),
+                           retry };
 
         let scope = cache.new_scope(scope);
 
@@ -98,7 +101,9 @@ impl Scope for FileScope {
     fn read(&self, id: PageId, buf: &mut [u8]) -> Result<()> {
         let (pos, len) = self.page_span(id, buf.len());
         if len > 0 {
-            let read = read_all_at(pos, len, buf, |pos, buf| read_at(self.file(), pos, buf))?;
+            let read = read_all_at(pos, len, buf, |pos, buf| {
+                self.retry.run(|| read_at(self.file(), pos, buf))
+            })?;
             if read != buf.len() {
                 debug_assert!(read < buf.len());
                 let new_len = pos + read as u64;
@@ -110,7 +115,7 @@ impl Scope for FileScope {
 
     fn write(&self, id: PageId, buf: &[u8]) -> Result<()> {
         let pos = Self::page_pos(id, buf.len());
-        write_all_at(pos, buf, |pos, buf| write_at(self.file(), pos, buf))?;
+        write_all_at(pos, buf, |pos, buf| self.retry.run(|| write_at(self.file(), pos, buf)))?;
         Ok(())
     }
 }
@@ -185,3 +190,51 @@ fn write_all_at<F>(mut pos: u64, mut buf: &[u8], write_at: F) -> Result<()>
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use std::{
+        cell::Cell,
+        io::{Error, ErrorKind},
+    };
+
+    use super::{read_all_at, write_all_at, RetryPolicy, BLOCK_ALIGN};
+
+    #[test]
+    fn test_interrupted_io_not_counted() {
+        // an interrupted call is retried even if the policy retries nothing
+        let retry = RetryPolicy { max_retries: 0, ..Default::default() };
+        let calls = Cell::new(0);
+        let mut buf = vec![0_u8; BLOCK_ALIGN];
+        let read = read_all_at(0, BLOCK_ALIGN, &mut buf, |_, buf| {
+            retry.run(|| {
+                     calls.set(calls.get() + 1);
+                     match calls.get() {
+                         1 | 2 => Err(Error::from(ErrorKind::Interrupted)),
+                         _ => Ok(buf.len()),
+                     }
+                 })
+        });
+        assert_eq!(read.unwrap(), BLOCK_ALIGN);
+        assert_eq!(calls.get(), 3);
+
+        calls.set(0);
+        let res = write_all_at(0, &buf, |_, buf| {
+            retry.run(|| {
+                     calls.set(calls.get() + 1);
+                     match calls.get() {
+                         1 => Err(Error::from(ErrorKind::Interrupted)),
+                         _ => Ok(buf.len()),
+                     }
+                 })
+        });
+        assert!(res.is_ok());
+        assert_eq!(calls.get(), 2);
+
+        // EAGAIN is counted against the policy
+        let res = read_all_at(0, BLOCK_ALIGN, &mut buf, |_, _| {
+            retry.run(|| Err(Error::from_raw_os_error(libc::EAGAIN)))
+        });
+        assert_eq!(res.unwrap_err().kind(), ErrorKind::WouldBlock);
+    }
+}
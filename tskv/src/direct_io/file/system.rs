@@ -1,3 +1,5 @@
+use std::time::Duration;
+
 use crate::direct_io::file::*;
 
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -6,6 +8,7 @@ pub struct Options {
     max_non_resident: usize,
     page_len_scale: usize,
     thread_num: usize,
+    retry: RetryPolicy,
 }
 
 impl Options {
@@ -30,11 +33,27 @@ impl Options {
     pub fn get_thread_num(&self) -> usize {
         self.thread_num
     }
+
+    /// Sets the times a read or write failed with EAGAIN is retried, EINTR is always retried.
+    pub fn max_io_retries(&mut self, n: usize) -> &mut Self {
+        self.retry.max_retries = n;
+        self
+    }
+
+    /// Sets the wait before the first retry, it doubles on every retry.
+    pub fn io_retry_backoff(&mut self, v: Duration) -> &mut Self {
+        self.retry.backoff = v;
+        self
+    }
 }
 
 impl Default for Options {
     fn default() -> Self {
-        Self { max_resident: 1024, max_non_resident: 1024, page_len_scale: 1, thread_num: 1 }
+        Self { max_resident: 1024,
+               max_non_resident: 1024,
+               page_len_scale: 1,
+               thread_num: 1,
+               retry: RetryPolicy::default() }
     }
 }
 
@@ -42,6 +61,7 @@ impl Default for Options {
 pub struct FileSystem {
     cache: CacheHandle,
     scope_map: Arc<ScopeMap>,
+    retry: RetryPolicy,
 }
 
 assert_impl_all!(FileSystem: Send, Sync);
@@ -56,7 +76,8 @@ impl FileSystem {
                                                    os_page_len.checked_mul(options.page_len_scale)
                                                               .unwrap(),
                                                page_align: os_page_len }),
-               scope_map: Default::default() }
+               scope_map: Default::default(),
+               retry: options.retry }
     }
 
     pub fn max_resident(&self) -> usize {
@@ -99,7 +120,7 @@ impl FileSystem {
             }
         }
 
-        let scope = FileScope::new(&self.cache, &self.scope_map, file, id, len)?;
+        let scope = FileScope::new(&self.cache, &self.scope_map, file, id, len, self.retry)?;
         Ok(File::new(scope))
     }
 
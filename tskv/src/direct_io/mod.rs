@@ -6,11 +6,14 @@
 mod async_rt;
 mod cache;
 mod file;
+mod priority;
 
 pub use async_rt::*;
 pub use cache::PageId;
 pub use file::{
     cursor::FileCursor,
+    retry::RetryPolicy,
     system::{FileSystem, Options},
     File, FileSync,
 };
+pub use priority::{io_scheduler, HighIoGuard, IoClass, IoScheduler};
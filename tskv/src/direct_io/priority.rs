@@ -0,0 +1,231 @@
+use std::{
+    io::Result,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use once_cell::sync::OnceCell;
+use parking_lot::{Condvar, Mutex};
+
+use super::File;
+
+// the bytes a low priority write writes before it yields to high priority writes
+const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+// the longest a low priority chunk waits, so that it is not starved by high priority writes
+const DEFAULT_MAX_YIELD: Duration = Duration::from_millis(100);
+
+/// The priority of an io, flushes and the wal write with high priority, compactions write and
+/// the scrubber reads with low priority.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoClass {
+    High,
+    Low,
+}
+
+/// Schedules the ios of both classes, a low priority io is done in chunks, and waits between
+/// the chunks while any high priority write is pending. The wait blocks the thread, so a low
+/// priority io is done on a blocking thread rather than on the runtime.
+pub struct IoScheduler {
+    chunk_size: usize,
+    max_yield: Duration,
+    high_pending: Mutex<usize>,
+    high_done: Condvar,
+    high_bytes: AtomicU64,
+    low_bytes: AtomicU64,
+    // the low priority chunks that waited for a high priority write
+    yields: AtomicU64,
+}
+
+pub fn io_scheduler() -> &'static IoScheduler {
+    static INSTANCE: OnceCell<IoScheduler> = OnceCell::new();
+    INSTANCE.get_or_init(|| IoScheduler::new(DEFAULT_CHUNK_SIZE, DEFAULT_MAX_YIELD))
+}
+
+impl IoScheduler {
+    pub fn new(chunk_size: usize, max_yield: Duration) -> Self {
+        Self { chunk_size: chunk_size.max(1),
+               max_yield,
+               high_pending: Mutex::new(0),
+               high_done: Condvar::new(),
+               high_bytes: AtomicU64::new(0),
+               low_bytes: AtomicU64::new(0),
+               yields: AtomicU64::new(0) }
+    }
+
+    /// Marks a high priority write pending until the guard is dropped.
+    pub fn begin_high(&self) -> HighIoGuard<'_> {
+        *self.high_pending.lock() += 1;
+        HighIoGuard { scheduler: self }
+    }
+
+    pub fn write_at(&self, class: IoClass, file: &File, mut pos: u64, buf: &[u8]) -> Result<usize> {
+        match class {
+            IoClass::High => {
+                let _guard = self.begin_high();
+                let len = file.write_at(pos, buf)?;
+                self.high_bytes.fetch_add(len as u64, Ordering::Relaxed);
+                Ok(len)
+            },
+            IoClass::Low => {
+                for chunk in buf.chunks(self.chunk_size) {
+                    self.yield_to_high();
+                    let len = file.write_at(pos, chunk)?;
+                    self.low_bytes.fetch_add(len as u64, Ordering::Relaxed);
+                    pos += len as u64;
+                }
+                Ok(buf.len())
+            },
+        }
+    }
+
+    /// Reads like `File::read_at`, a low priority read yields to the high priority writes.
+    pub fn read_at(&self,
+                   class: IoClass,
+                   file: &File,
+                   mut pos: u64,
+                   buf: &mut [u8])
+                   -> Result<usize> {
+        match class {
+            IoClass::High => file.read_at(pos, buf),
+            IoClass::Low => {
+                let mut read = 0;
+                for chunk in buf.chunks_mut(self.chunk_size) {
+                    self.yield_to_high();
+                    let len = file.read_at(pos, chunk)?;
+                    read += len;
+                    pos += len as u64;
+                    if len < chunk.len() {
+                        break;
+                    }
+                }
+                Ok(read)
+            },
+        }
+    }
+
+    /// Returns true while any high priority write is pending.
+    pub fn is_high_pending(&self) -> bool {
+        *self.high_pending.lock() > 0
+    }
+
+    /// Returns the bytes written with the priority.
+    pub fn bytes(&self, class: IoClass) -> u64 {
+        match class {
+            IoClass::High => self.high_bytes.load(Ordering::Relaxed),
+            IoClass::Low => self.low_bytes.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Returns the low priority chunks that waited for a high priority write.
+    pub fn yields(&self) -> u64 {
+        self.yields.load(Ordering::Relaxed)
+    }
+
+    fn yield_to_high(&self) {
+        let deadline = Instant::now() + self.max_yield;
+        let mut pending = self.high_pending.lock();
+        if *pending > 0 {
+            self.yields.fetch_add(1, Ordering::Relaxed);
+        }
+        while *pending > 0 {
+            if self.high_done.wait_until(&mut pending, deadline).timed_out() {
+                break;
+            }
+        }
+    }
+}
+
+pub struct HighIoGuard<'a> {
+    scheduler: &'a IoScheduler,
+}
+
+impl Drop for HighIoGuard<'_> {
+    fn drop(&mut self) {
+        let mut pending = self.scheduler.high_pending.lock();
+        *pending -= 1;
+        if *pending == 0 {
+            self.scheduler.high_done.notify_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::Arc,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use tempfile::NamedTempFile;
+
+    use super::{IoClass, IoScheduler};
+    use crate::direct_io::{FileSystem, Options};
+
+    #[test]
+    fn test_low_yields_to_high() {
+        let fs = FileSystem::new(&Options::default());
+        let tmp = NamedTempFile::new().unwrap();
+        let file = fs.open(tmp.path()).unwrap();
+        let scheduler = Arc::new(IoScheduler::new(1024, Duration::from_secs(10)));
+
+        let guard = scheduler.begin_high();
+        let low = {
+            let scheduler = scheduler.clone();
+            thread::spawn(move || {
+                let start = Instant::now();
+                scheduler.write_at(IoClass::Low, &file, 0, &[1; 4096]).unwrap();
+                start.elapsed()
+            })
+        };
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(scheduler.bytes(IoClass::Low), 0);
+        drop(guard);
+        assert!(low.join().unwrap() >= Duration::from_millis(100));
+        assert_eq!(scheduler.bytes(IoClass::Low), 4096);
+    }
+
+    #[test]
+    fn test_high_before_pending_low() {
+        let fs = FileSystem::new(&Options::default());
+        let (low_tmp, high_tmp) = (NamedTempFile::new().unwrap(), NamedTempFile::new().unwrap());
+        let (low_file, file) =
+            (fs.open(low_tmp.path()).unwrap(), fs.open(high_tmp.path()).unwrap());
+        let scheduler = Arc::new(IoScheduler::new(1024, Duration::from_secs(10)));
+
+        let guard = scheduler.begin_high();
+        let low = {
+            let scheduler = scheduler.clone();
+            thread::spawn(move || {
+                scheduler.write_at(IoClass::Low, &low_file, 0, &[1; 4096]).unwrap();
+            })
+        };
+        while scheduler.yields() == 0 {
+            thread::yield_now();
+        }
+        // the low chunk waits while the high writes are done
+        for i in 0..100 {
+            scheduler.write_at(IoClass::High, &file, i * 16, &[2; 16]).unwrap();
+        }
+        assert_eq!(scheduler.bytes(IoClass::High), 100 * 16);
+        assert_eq!(scheduler.bytes(IoClass::Low), 0);
+        drop(guard);
+        low.join().unwrap();
+        assert_eq!(scheduler.bytes(IoClass::Low), 4096);
+        assert_eq!(scheduler.yields(), 1);
+    }
+
+    #[test]
+    fn test_low_not_starved() {
+        let fs = FileSystem::new(&Options::default());
+        let tmp = NamedTempFile::new().unwrap();
+        let file = fs.open(tmp.path()).unwrap();
+        let scheduler = IoScheduler::new(1024, Duration::from_millis(1));
+
+        // every chunk waits out the longest yield and is written anyway
+        let _guard = scheduler.begin_high();
+        assert_eq!(scheduler.write_at(IoClass::Low, &file, 0, &[1; 4096]).unwrap(), 4096);
+        assert_eq!(scheduler.bytes(IoClass::Low), 4096);
+        assert_eq!(scheduler.yields(), 4);
+    }
+}
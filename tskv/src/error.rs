@@ -58,6 +58,9 @@ pub enum Error {
     #[snafu(display("write tsm block file error: {}", reason))]
     WriteTsmErr { reason: String },
 
+    #[snafu(display("compaction filter error: {}", reason))]
+    CompactionFilterErr { reason: String },
+
     #[snafu(display("unable to walk dir: {}", source))]
     UnableToWalkDir { source: walkdir::Error },
 
@@ -66,4 +69,70 @@ pub enum Error {
 
     #[snafu(display("invalid model: {}", source))]
     InvalidModel { source: models::Error },
+
+    #[snafu(display("invalid utf-8 string: {}", source))]
+    InvalidUtf8 { source: std::str::Utf8Error },
+
+    #[snafu(display("invalid wal record: {}", reason))]
+    InvalidWalRecord { reason: String },
+
+    #[snafu(display("duplicate file id in version: {}", file_id))]
+    DuplicateFileId { file_id: u64 },
+
+    #[snafu(display("field {} has {} blocks, no block {}", field_id, count, n))]
+    BlockOutOfRange { field_id: u64, n: usize, count: usize },
+
+    #[snafu(display("unsupported format features: {:#x}", bits))]
+    UnsupportedFeature { bits: u32 },
+
+    #[snafu(display("cannot coerce {:?} values to {:?}", from, to))]
+    IncompatibleCoercion { from: models::ValueType, to: models::ValueType },
+
+    #[snafu(display("field {} holds {:?} values, not {:?}", field_id, expected, found))]
+    FieldTypeConflict { field_id: u64, expected: models::ValueType, found: models::ValueType },
+
+    #[snafu(display("field ids {} and {} are both mapped to {}", from, other, to))]
+    DuplicateFieldId { from: u64, other: u64, to: u64 },
+
+    #[snafu(display("field {} of {} differs once migrated", field_id, path))]
+    MigrationMismatch { path: String, field_id: u64 },
+
+    #[snafu(display("flush of {} caches postponed, not enough disk space", count))]
+    FlushPostponed { count: usize },
+
+    #[snafu(display("tseries family not found: {}", tf_id))]
+    TsfNotFound { tf_id: u32 },
+
+    #[snafu(display("no trash entry of tseries family {}", tf_id))]
+    TrashNotFound { tf_id: u32 },
+
+    #[snafu(display("checksum mismatch in a block of field {} at offset {}", field_id, offset))]
+    ChecksumMismatch { field_id: u64, offset: u64 },
+
+    #[snafu(display("field {} is missing from the bloom filter", field_id))]
+    MissingFromBloomFilter { field_id: u64 },
+
+    #[snafu(display("invalid tseries family options: {}", reason))]
+    InvalidTsfOption { reason: String },
+
+    #[snafu(display("invalid compaction window {:?}, expected like \"02:00-05:00\"", window))]
+    InvalidCompactionWindow { window: String },
+
+    #[snafu(display("the store is read-only until the point-in-time restore is confirmed"))]
+    RestoreNotConfirmed,
+
+    #[snafu(display("invalid line protocol {:?}: {}", line, reason))]
+    InvalidLineProtocol { line: String, reason: String },
+
+    #[snafu(display("corrupt snapshot: {}", reason))]
+    CorruptSnapshot { reason: String },
+
+    #[snafu(display("cannot import a snapshot into {}, it is not empty", dir))]
+    SnapshotTargetNotEmpty { dir: String },
+
+    #[snafu(display("tseries family {} already exists", tf_id))]
+    TsfExists { tf_id: u32 },
+
+    #[snafu(display("failed to read {}", files))]
+    ScanFailed { files: String },
 }
@@ -0,0 +1,70 @@
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+
+// The format features of the tsm files and the summary, a binary refuses a file written with a
+// required feature it does not know, instead of misreading it.
+
+/// The blocks start with the crc of the timestamps and of the values.
+pub const BLOCK_CRC: u32 = 1 << 0;
+/// The encoded timestamps and values start with the tag of their encoding.
+pub const BLOCK_ENCODING_TAGS: u32 = 1 << 1;
+/// Reserved for the second version of the tsm footer.
+pub const FOOTER_V2: u32 = 1 << 2;
+/// The blocks of a field may contain duplicate timestamps, flagged in the index.
+pub const DUPLICATE_TIMESTAMPS: u32 = 1 << 3;
+/// The encoded timestamps and values may be compressed again by zstd.
+pub const BLOCK_ZSTD: u32 = 1 << 4;
+/// The encoded values of a block with null points start with the validity bits of its points.
+pub const NULL_VALUES: u32 = 1 << 5;
+
+/// The features this binary can read.
+pub const SUPPORTED: u32 =
+    BLOCK_CRC | BLOCK_ENCODING_TAGS | DUPLICATE_TIMESTAMPS | BLOCK_ZSTD | NULL_VALUES;
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FeatureBits {
+    // a reader fails on the unknown required features
+    pub required: u32,
+    // a reader ignores the unknown optional features
+    pub optional: u32,
+}
+
+impl FeatureBits {
+    pub fn new(required: u32, optional: u32) -> Self {
+        Self { required, optional }
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        bincode::serialize(self).map_err(|e| Error::Encode { source: e })
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        bincode::deserialize(buf).map_err(|e| Error::Decode { source: e })
+    }
+
+    /// Returns Error::UnsupportedFeature with the required features this binary can not read.
+    pub fn check(&self) -> Result<()> {
+        let bits = self.required & !SUPPORTED;
+        if bits != 0 {
+            return Err(Error::UnsupportedFeature { bits });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{FeatureBits, BLOCK_CRC, BLOCK_ENCODING_TAGS, FOOTER_V2};
+    use crate::error::Error;
+
+    #[test]
+    fn test_check() {
+        assert!(FeatureBits::new(BLOCK_ENCODING_TAGS, BLOCK_CRC).check().is_ok());
+        assert!(FeatureBits::new(0, FOOTER_V2 | 1 << 31).check().is_ok());
+        match FeatureBits::new(BLOCK_ENCODING_TAGS | FOOTER_V2 | 1 << 31, 0).check() {
+            Err(Error::UnsupportedFeature { bits }) => assert_eq!(bits, FOOTER_V2 | 1 << 31),
+            res => panic!("unexpected {:?}", res),
+        }
+    }
+}
@@ -7,13 +7,17 @@ use std::{
     thread::JoinHandle,
 };
 
+use config::GLOBAL_CONFIG;
 use futures::channel::oneshot::{self, Sender};
 use once_cell::sync::OnceCell;
 use parking_lot::Mutex;
 use snafu::{ResultExt, Snafu};
 
 use crate::{
-    direct_io::{self, make_io_task, run_io_task, AsyncContext, File, IoTask, TaskType},
+    direct_io::{
+        self, io_scheduler, make_io_task, run_io_task, AsyncContext, File, IoClass, IoTask,
+        TaskType,
+    },
     error, Error, Result,
 };
 
@@ -45,7 +49,8 @@ pub fn get_file_manager() -> &'static FileManager {
 
 impl FileManager {
     fn new() -> Self {
-        let fs_options = direct_io::Options::default();
+        let mut fs_options = direct_io::Options::default();
+        fs_options.max_io_retries(GLOBAL_CONFIG.max_io_retries);
         let thread_num = fs_options.get_thread_num();
         let rt = Arc::new(AsyncContext::new(fs_options.get_thread_num()));
         let mut pool = Vec::new();
@@ -102,6 +107,11 @@ impl FileManager {
 
     pub async fn read_at(&self, file: direct_io::File, pos: u64, size: u64) {}
 
+    /// Returns the bytes written with the io priority.
+    pub fn io_bytes(&self, io_class: IoClass) -> u64 {
+        io_scheduler().bytes(io_class)
+    }
+
     pub fn put_io_task(&self, task: IoTask) -> Result<()> {
         if self.async_rt.is_closed() {
             return Err(Error::Cancel);
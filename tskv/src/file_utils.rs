@@ -1,4 +1,8 @@
-use std::path::{Path, PathBuf};
+use std::{
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
 
 use lazy_static::lazy_static;
 use regex::Regex;
@@ -65,6 +69,68 @@ pub fn get_wal_file_id(file_name: &str) -> Result<u64> {
                               })
 }
 
+// Files of a new store, written into the staging directory <db_path>/.init before they are
+// moved into <db_path>
+
+pub fn make_init_dir(path: &str) -> PathBuf {
+    Path::new(path).join(".init")
+}
+
+pub fn make_identity_file(path: &str) -> PathBuf {
+    Path::new(path).join("IDENTITY")
+}
+
+// Directories of a tseries family, the files of a family are kept apart from the others
+// under <base_dir>/<tsf_id>
+
+pub fn make_tsfamily_dir(base_dir: &str, tsf_id: u32) -> PathBuf {
+    Path::new(base_dir).join(tsf_id.to_string())
+}
+
+pub fn make_tsm_dir(base_dir: &str, tsf_id: u32) -> PathBuf {
+    make_tsfamily_dir(base_dir, tsf_id).join("tsm")
+}
+
+pub fn make_delta_dir(base_dir: &str, tsf_id: u32) -> PathBuf {
+    make_tsfamily_dir(base_dir, tsf_id).join("delta")
+}
+
+/// Moves the files of the layout before `<base_dir>/<tsf_id>`, kept in `<tsm_dir>/<tsf_id>`
+/// and `<delta_dir>/<tsf_id>`, into the directories of their tseries family under `base_dir`,
+/// returns the files moved. A file already in place is left where it is, so a move
+/// interrupted by a crash is finished by the next one.
+pub fn move_legacy_files(tsm_dir: &str, delta_dir: &str, base_dir: &str) -> Result<usize> {
+    let layouts: [(&str, fn(&str, u32) -> PathBuf); 2] =
+        [(tsm_dir, make_tsm_dir), (delta_dir, make_delta_dir)];
+    let mut moved = 0;
+    for (legacy_dir, make_dir) in layouts {
+        let entries = match fs::read_dir(legacy_dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(e).context(error::IOSnafu),
+        };
+        for entry in entries {
+            let entry = entry.context(error::IOSnafu)?;
+            let tsf_id = match entry.file_name().to_str().and_then(|n| n.parse::<u32>().ok()) {
+                Some(tsf_id) if entry.path().is_dir() => tsf_id,
+                _ => continue,
+            };
+            let dir = make_dir(base_dir, tsf_id);
+            for file in fs::read_dir(entry.path()).context(error::IOSnafu)? {
+                let file = file.context(error::IOSnafu)?;
+                let dst = dir.join(file.file_name());
+                if !file.path().is_file() || dst.exists() {
+                    continue;
+                }
+                fs::create_dir_all(&dir).context(error::IOSnafu)?;
+                fs::rename(file.path(), &dst).context(error::IOSnafu)?;
+                moved += 1;
+            }
+        }
+    }
+    Ok(moved)
+}
+
 // TSM file
 
 pub fn make_tsm_file_name(path: &str, sequence: u64) -> PathBuf {
@@ -86,6 +152,13 @@ pub fn make_delta_file_name(path: &str, sequence: u64) -> PathBuf {
     PathBuf::from(p)
 }
 
+// Trash entry of a dropped tseries family
+
+pub fn make_trash_entry(path: &str, tsf_id: u32, dropped_at: u64) -> PathBuf {
+    let p = format!("{}/{}-{}", path, tsf_id, dropped_at);
+    PathBuf::from(p)
+}
+
 // Schema file
 
 pub fn make_schema_file(path: &str, sequence: u64) -> PathBuf {
@@ -145,7 +218,8 @@ mod test {
     use super::{check_summary_file_name, make_summary_file};
     use crate::file_utils::{
         self, check_schema_file, check_wal_file_name, get_schema_file_id, get_summary_file_id,
-        get_wal_file_id, make_schema_file, make_wal_file,
+        get_wal_file_id, make_delta_dir, make_delta_file_name, make_schema_file, make_tsm_dir,
+        make_tsm_file_name, make_wal_file, move_legacy_files,
     };
 
     #[test]
@@ -186,4 +260,36 @@ mod test {
             assert_eq!(schema_file_id, 0);
         }
     }
+
+    #[test]
+    fn test_make_tsfamily_path() {
+        let tsm_dir = make_tsm_dir("/tmp/test/data", 3);
+        assert_eq!(tsm_dir.to_str().unwrap(), "/tmp/test/data/3/tsm");
+        let delta_dir = make_delta_dir("/tmp/test/data/", 3);
+        assert_eq!(delta_dir.to_str().unwrap(), "/tmp/test/data/3/delta");
+        let tsm_file = make_tsm_file_name(tsm_dir.to_str().unwrap(), 12);
+        assert_eq!(tsm_file.to_str().unwrap(), "/tmp/test/data/3/tsm/_000012.tsm");
+        let delta_file = make_delta_file_name(delta_dir.to_str().unwrap(), 12);
+        assert_eq!(delta_file.to_str().unwrap(), "/tmp/test/data/3/delta/_000012.delta");
+    }
+
+    #[test]
+    fn test_move_legacy_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name).to_string_lossy().to_string();
+        let (tsm_dir, delta_dir, base_dir) = (path("tsm"), path("delta"), path("data"));
+        std::fs::create_dir_all(format!("{}/3", tsm_dir)).unwrap();
+        std::fs::create_dir_all(format!("{}/3", delta_dir)).unwrap();
+        std::fs::write(make_tsm_file_name(&format!("{}/3", tsm_dir), 1), b"tsm").unwrap();
+        std::fs::write(make_delta_file_name(&format!("{}/3", delta_dir), 2), b"delta").unwrap();
+
+        assert_eq!(move_legacy_files(&tsm_dir, &delta_dir, &base_dir).unwrap(), 2);
+        let tsm_file = make_tsm_file_name(make_tsm_dir(&base_dir, 3).to_str().unwrap(), 1);
+        let delta_file = make_delta_file_name(make_delta_dir(&base_dir, 3).to_str().unwrap(), 2);
+        assert_eq!(std::fs::read(tsm_file).unwrap(), b"tsm");
+        assert_eq!(std::fs::read(delta_file).unwrap(), b"delta");
+        // nothing is left to move, and a store without the old directories moves nothing
+        assert_eq!(move_legacy_files(&tsm_dir, &delta_dir, &base_dir).unwrap(), 0);
+        assert_eq!(move_legacy_files(&path("none"), &path("none"), &base_dir).unwrap(), 0);
+    }
 }
@@ -77,6 +77,18 @@ impl ForwardIndex {
                        file_path: path.to_path_buf() }
     }
 
+    /// Returns true if the series and all of its fields are already in the index, the
+    /// series_info must be finished.
+    pub fn contains(&self, series_info: &SeriesInfo) -> bool {
+        let mem_series_info = match self.series_info_set.get(&series_info.series_id()) {
+            Some(info) => info,
+            None => return false,
+        };
+        let has_field =
+            |field_id: FieldId| mem_series_info.field_infos.iter().any(|f| f.id == field_id);
+        series_info.field_infos().iter().all(|f| has_field(f.field_id()))
+    }
+
     pub async fn add_series_info_if_not_exists(&mut self,
                                                mut series_info: SeriesInfo)
                                                -> ForwardIndexResult<()> {
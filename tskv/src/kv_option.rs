@@ -1,9 +1,17 @@
 #![allow(dead_code)]
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use config::GLOBAL_CONFIG;
 
-use crate::forward_index::ForwardIndexConfig;
+use crate::{
+    compaction::{CompactionFilterRef, CompactionWindow},
+    file_utils,
+    forward_index::ForwardIndexConfig,
+    Error, Result,
+};
+
+// the directories of the default options, no file can be created under it
+const UNSET_DIR: &str = "\0unset";
 
 #[derive(Clone)]
 pub struct DBOptions {
@@ -13,6 +21,12 @@ pub struct DBOptions {
     pub create_if_missing: bool,
     pub db_path: String,
     pub db_name: String,
+    // flush requests waiting for the flush job, the writes wait while as many are queued
+    pub flush_queue_capacity: usize,
+    // the tsm and delta directories of the layout before `TseriesFamOpt::base_dir`, their
+    // files are moved under the base directory of the config when the store is opened
+    pub legacy_tsm_dir: String,
+    pub legacy_delta_dir: String,
 }
 
 impl Default for DBOptions {
@@ -22,7 +36,10 @@ impl Default for DBOptions {
                max_summary_size: GLOBAL_CONFIG.max_memcache_size, // 128MB
                create_if_missing: GLOBAL_CONFIG.create_if_missing,
                db_path: GLOBAL_CONFIG.db_path.clone(),
-               db_name: GLOBAL_CONFIG.db_name.clone() }
+               db_name: GLOBAL_CONFIG.db_name.clone(),
+               flush_queue_capacity: GLOBAL_CONFIG.flush_queue_capacity,
+               legacy_tsm_dir: GLOBAL_CONFIG.tsm_dir.clone(),
+               legacy_delta_dir: GLOBAL_CONFIG.delta_dir.clone() }
     }
 }
 
@@ -31,6 +48,10 @@ pub struct Options {
     pub db: DBOptions,
     pub lrucache: CacheConfig,
     pub wal: WalConfig,
+    pub request_window: RequestWindowConfig,
+    pub scrub: ScrubConfig,
+    pub restore: RestoreConfig,
+    pub cleaner: CleanerConfig,
     // pub(crate) write_batch: WriteBatchConfig,
     pub compact_conf: CompactConfig,
     pub forward_index_conf: ForwardIndexConfig,
@@ -63,11 +84,132 @@ impl Default for WalConfig {
     }
 }
 
+#[derive(Clone)]
+pub struct RequestWindowConfig {
+    // the most recent request ids remembered
+    pub size: usize,
+    // seconds a request id is remembered
+    pub ttl_secs: u64,
+}
+
+impl Default for RequestWindowConfig {
+    fn default() -> Self {
+        Self { size: GLOBAL_CONFIG.request_window_size,
+               ttl_secs: GLOBAL_CONFIG.request_window_ttl_secs }
+    }
+}
+
+/// What the scrubber does with a file whose checksums do not match.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CorruptionPolicy {
+    /// The file is marked corrupt and reported, it stays readable.
+    Report,
+    /// The file is also removed from its version and moved into the quarantine directory.
+    Quarantine,
+}
+
+impl Default for CorruptionPolicy {
+    fn default() -> Self {
+        CorruptionPolicy::Report
+    }
+}
+
+#[derive(Clone)]
+pub struct ScrubConfig {
+    // bytes the scrubber reads per second, 0 disables the scrubber
+    pub bytes_per_sec: u64,
+    // seconds between two verifications of a file
+    pub interval_secs: u64,
+    pub corruption_policy: CorruptionPolicy,
+    // the corrupt files are moved here with the Quarantine policy
+    pub quarantine_dir: String,
+}
+
+impl Default for ScrubConfig {
+    fn default() -> Self {
+        Self { bytes_per_sec: GLOBAL_CONFIG.scrub_bytes_per_sec,
+               interval_secs: GLOBAL_CONFIG.scrub_interval_secs,
+               corruption_policy: CorruptionPolicy::default(),
+               quarantine_dir: GLOBAL_CONFIG.quarantine_dir.clone() }
+    }
+}
+
+#[derive(Clone)]
+pub struct RestoreConfig {
+    // accept writes after a point-in-time restore, the wal entries after the target are
+    // truncated then
+    pub confirmed: bool,
+    // the files written after the target are moved here once the restore is confirmed
+    pub quarantine_dir: String,
+}
+
+impl Default for RestoreConfig {
+    fn default() -> Self {
+        Self { confirmed: GLOBAL_CONFIG.restore_confirmed,
+               quarantine_dir: GLOBAL_CONFIG.quarantine_dir.clone() }
+    }
+}
+
+#[derive(Clone)]
+pub struct CleanerConfig {
+    // seconds between two cleanings, 0 disables the cleaner
+    pub interval_secs: u64,
+    // seconds a removed file, or a file of no version, is kept before it is deleted; a
+    // point-in-time restore finds the files removed no longer ago than this
+    pub safety_margin_secs: u64,
+}
+
+impl Default for CleanerConfig {
+    fn default() -> Self {
+        Self { interval_secs: GLOBAL_CONFIG.clean_interval_secs,
+               safety_margin_secs: GLOBAL_CONFIG.clean_safety_margin_secs }
+    }
+}
+
 #[allow(dead_code)]
 pub struct WriteBatchConfig {}
 
-#[derive(Default, Clone)]
-pub struct CompactConfig {}
+/// How the compaction scheduler orders the tseries families needing a compaction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompactionPolicy {
+    /// The family with the highest score goes first, a busy family may starve the others.
+    Throughput,
+    /// The families take turns in the order of their ids, each one needing a compaction is
+    /// compacted within as many picks as there are families.
+    Fair,
+}
+
+impl Default for CompactionPolicy {
+    fn default() -> Self {
+        Self::Fair
+    }
+}
+
+#[derive(Clone)]
+pub struct CompactConfig {
+    pub policy: CompactionPolicy,
+    // the local time of day the compactions run in like "02:00-05:00 local", empty runs them at
+    // any time; see `CompactionWindow::parse`
+    pub window: String,
+    // out of the window a tseries family is compacted only if its score is at least this
+    pub urgent_score: f64,
+}
+
+impl CompactConfig {
+    /// Returns the parsed window, or `Error::InvalidCompactionWindow`; the store refuses to
+    /// open with an invalid window.
+    pub fn parse_window(&self) -> Result<Option<CompactionWindow>> {
+        CompactionWindow::parse(&self.window)
+    }
+}
+
+impl Default for CompactConfig {
+    fn default() -> Self {
+        Self { policy: CompactionPolicy::default(),
+               window: GLOBAL_CONFIG.compaction_window.clone(),
+               urgent_score: GLOBAL_CONFIG.urgent_compaction_score }
+    }
+}
 
 pub struct TimeRange {}
 
@@ -78,6 +220,78 @@ pub struct QueryOption {
     series_id: u64,
 }
 
+/// How points of the same field with the same timestamp are handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DuplicatePolicy {
+    /// Only the last written point of a timestamp is kept.
+    LastWins,
+    /// All points of a timestamp are kept, in the order they were written.
+    KeepAll,
+}
+
+impl Default for DuplicatePolicy {
+    fn default() -> Self {
+        Self::LastWins
+    }
+}
+
+/// How the encoded timestamps and values of the blocks written to a level are compressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockCompression {
+    /// Only the encodings of the coders, the cheapest to write.
+    Fast,
+    /// The encodings of the coders compressed again by zstd at the level, from 1 to 22.
+    Zstd(i32),
+}
+
+impl Default for BlockCompression {
+    fn default() -> Self {
+        Self::Fast
+    }
+}
+
+impl BlockCompression {
+    /// Returns the compression of the level among the compressions of each level from level 0,
+    /// the last one is also the compression of the deeper levels.
+    pub fn of_level(level_compression: &[BlockCompression], level: usize) -> Self {
+        let level = level.min(level_compression.len().saturating_sub(1));
+        level_compression.get(level).copied().unwrap_or_default()
+    }
+}
+
+/// The implementation backing the memory caches of a tseries family.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemCacheImpl {
+    /// Cells grouped by field in a hash map, sorted when the cache turns immutable.
+    HashMap,
+    /// Cells kept ordered by (field, timestamp) in a skiplist, experimental.
+    #[cfg(feature = "skiplist")]
+    SkipList,
+}
+
+impl Default for MemCacheImpl {
+    fn default() -> Self {
+        Self::HashMap
+    }
+}
+
+/// How a string value that is not valid UTF-8 is handled when it is inserted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Utf8Policy {
+    /// The point is rejected.
+    Reject,
+    /// The invalid sequences are replaced with U+FFFD.
+    Replace,
+    /// The value is stored as raw bytes without validation.
+    Bytes,
+}
+
+impl Default for Utf8Policy {
+    fn default() -> Self {
+        Self::Bytes
+    }
+}
+
 #[derive(Clone, PartialEq)]
 pub struct TseriesFamOpt {
     pub max_level: u32,
@@ -85,27 +299,188 @@ pub struct TseriesFamOpt {
     pub level_ratio: f64,
     pub base_file_size: u64,
     pub compact_trigger: u32,
+    // total bytes of the input files of one compaction
     pub max_compact_size: u64,
-    pub tsm_dir: String,
-    pub delta_dir: String,
+    // the files of the family are in the tsm and delta directories under <base_dir>/<tf_id>
+    pub base_dir: String,
+    // the files of a dropped tseries family are kept here until purged
+    pub trash_dir: String,
+    // seconds a dropped tseries family can be restored
+    pub trash_ttl_secs: u64,
+    pub duplicate_policy: DuplicatePolicy,
+    pub utf8_policy: Utf8Policy,
+    // max cells of a field in the mutable cache, the field is flushed alone when reached
+    pub max_entry_cells: usize,
+    // the deepest level a flush may write old data to, 1 keeps every flush at level 1
+    pub max_flush_level: u32,
+    // a level with more files is compacted even if it is under its size budget
+    pub max_files_per_level: usize,
+    // input files of one compaction, the files left out are picked by the next compaction
+    pub max_compact_files: usize,
+    // files under it are merged with their small neighbors in time when no level needs a
+    // compaction, 0 disables it
+    pub small_file_threshold: u64,
+    // input files of one merge of small files
+    pub max_small_files: usize,
+    // files of a scan read at a time on the blocking threads, 1 reads them one by one
+    pub read_parallelism: usize,
+    // points late by less than it stay in the mutable cache, later points go to the delta cache
+    pub ooo_tolerance_ns: i64,
+    // the delta cache is flushed when it reaches it, whether the mutable cache is full or not
+    pub max_delta_cache_size: u64,
+    // seconds after its first point the delta cache is flushed, 0 disables it
+    pub delta_flush_age_secs: u64,
+    // the scans of the family kept to serve the identical scans again, 0 disables it
+    pub result_cache_entries: usize,
+    // milliseconds a kept scan is served, unless a write or a new version drops it earlier
+    pub result_cache_ttl_ms: u64,
+    // the fields of the family whose latest point is kept for `last_value`, 0 disables it
+    pub last_value_entries: usize,
+    pub memcache_impl: MemCacheImpl,
+    pub compaction_filter: Option<CompactionFilterRef>,
+    // the compression of the blocks written to each level, from level 0, the last one is also
+    // the compression of the deeper levels; empty writes every level `Fast`
+    pub level_compression: Vec<BlockCompression>,
+    // the blocks of a flushed file are split at the boundaries of the windows of this many
+    // nanoseconds from the epoch, on top of the limits on points and bytes; 0 disables it
+    pub flush_time_window_ns: i64,
 }
 
 impl TseriesFamOpt {
     pub fn level_file_size(&self, lvl: u32) -> u64 {
         self.base_file_size * lvl as u64 * self.compact_trigger as u64
     }
-}
 
-impl Default for TseriesFamOpt {
-    fn default() -> Self {
+    /// Returns the compression of the blocks written to the level.
+    pub fn compression(&self, level: u32) -> BlockCompression {
+        BlockCompression::of_level(&self.level_compression, level as usize)
+    }
+
+    /// Returns the directory of the tsm files of the tseries family.
+    pub fn tsm_dir(&self, tf_id: u32) -> String {
+        file_utils::make_tsm_dir(&self.base_dir, tf_id).to_string_lossy().to_string()
+    }
+
+    /// Returns the directory of the delta files of the tseries family.
+    pub fn delta_dir(&self, tf_id: u32) -> String {
+        file_utils::make_delta_dir(&self.base_dir, tf_id).to_string_lossy().to_string()
+    }
+
+    /// Returns the options of the global config.
+    pub fn from_config() -> Self {
         Self { max_level: GLOBAL_CONFIG.max_level,
                // base_file_size: 256 * 1024 * 1024,
                level_ratio: GLOBAL_CONFIG.level_ratio,
                base_file_size: GLOBAL_CONFIG.base_file_size,
                compact_trigger: GLOBAL_CONFIG.compact_trigger,
                max_compact_size: GLOBAL_CONFIG.max_compact_size,
-               tsm_dir: GLOBAL_CONFIG.tsm_dir.clone(),
-               delta_dir: GLOBAL_CONFIG.delta_dir.clone() }
+               base_dir: GLOBAL_CONFIG.base_dir.clone(),
+               trash_dir: GLOBAL_CONFIG.trash_dir.clone(),
+               trash_ttl_secs: GLOBAL_CONFIG.trash_ttl_secs,
+               duplicate_policy: DuplicatePolicy::default(),
+               utf8_policy: Utf8Policy::default(),
+               max_entry_cells: GLOBAL_CONFIG.max_entry_cells,
+               max_flush_level: GLOBAL_CONFIG.max_flush_level,
+               max_files_per_level: GLOBAL_CONFIG.max_files_per_level,
+               max_compact_files: GLOBAL_CONFIG.max_compact_files,
+               small_file_threshold: GLOBAL_CONFIG.small_file_threshold,
+               max_small_files: GLOBAL_CONFIG.max_small_files,
+               read_parallelism: GLOBAL_CONFIG.read_parallelism,
+               ooo_tolerance_ns: GLOBAL_CONFIG.ooo_tolerance_ns,
+               max_delta_cache_size: GLOBAL_CONFIG.max_delta_cache_size,
+               delta_flush_age_secs: GLOBAL_CONFIG.delta_flush_age_secs,
+               result_cache_entries: GLOBAL_CONFIG.result_cache_entries,
+               result_cache_ttl_ms: GLOBAL_CONFIG.result_cache_ttl_ms,
+               last_value_entries: GLOBAL_CONFIG.last_value_entries,
+               memcache_impl: MemCacheImpl::default(),
+               compaction_filter: None,
+               level_compression: vec![],
+               flush_time_window_ns: 0 }
+    }
+
+    /// Returns the options of the config with every directory under `base`, for the tests.
+    /// The delta cache is small and flushed only when full, and the scans and latest points
+    /// are kept for a few fields only.
+    pub fn for_testing(base: &Path) -> Self {
+        let dir = |name: &str| base.join(name).to_string_lossy().to_string();
+        Self { base_dir: dir("data"),
+               trash_dir: dir("trash"),
+               max_delta_cache_size: 1024 * 1024,
+               delta_flush_age_secs: 0,
+               result_cache_entries: 0,
+               last_value_entries: 64,
+               ..Self::from_config() }
+    }
+
+    /// Returns an error if a directory is not set, as in the default options.
+    pub fn validate(&self) -> Result<()> {
+        for (name, dir) in [("base_dir", &self.base_dir), ("trash_dir", &self.trash_dir)] {
+            if dir.is_empty() || dir.contains('\0') {
+                let reason = format!("{} is not set", name);
+                return Err(Error::InvalidTsfOption { reason });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The options of the config with directories that are not set, `validate` rejects them;
+/// `from_config` or `for_testing` returns usable options.
+impl Default for TseriesFamOpt {
+    fn default() -> Self {
+        Self { base_dir: UNSET_DIR.to_string(),
+               trash_dir: UNSET_DIR.to_string(),
+               ..Self::from_config() }
+    }
+}
+
+/// The options of a scan.
+#[derive(Debug, Clone)]
+pub struct ReadOptions {
+    // files read in one wave, the waves are merged one by one, 0 reads all files in one wave
+    pub max_concurrent_files: usize,
+    // for debugging, the points deleted by the tombstones of the files are also returned and
+    // their timestamps listed in `ScanStats::deleted`
+    pub include_deleted: bool,
+    // how the blocks decoded by the scan are admitted to the block cache
+    pub cache_policy: CachePolicy,
+    // for the real-time reads, a range newer than every point of the files is read from the
+    // memory caches without listing the files; any other range is read as usual
+    pub memory_only: bool,
+    // the timestamps of every block of the field are decoded and checked against the time
+    // range of its index entry, a block out of it is read by its timestamps and its file is
+    // rewritten by the next compaction
+    pub verify_block_ranges: bool,
+    // files of the scan read at a time, 0 uses the `read_parallelism` of the family
+    pub read_parallelism: usize,
+}
+
+impl Default for ReadOptions {
+    fn default() -> Self {
+        Self { max_concurrent_files: GLOBAL_CONFIG.max_concurrent_files,
+               include_deleted: false,
+               cache_policy: CachePolicy::Default,
+               memory_only: false,
+               verify_block_ranges: false,
+               read_parallelism: 0 }
+    }
+}
+
+/// How the blocks read by a scan are admitted to the block cache.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CachePolicy {
+    /// The blocks enter the probationary segment and are protected once they are read again,
+    /// so that a one-off scan does not evict the hot blocks.
+    Default,
+    /// The blocks are looked up but never admitted, for the backup or export scans.
+    NoFill,
+    /// The blocks enter the protected segment directly.
+    FillHighPriority,
+}
+
+impl Default for CachePolicy {
+    fn default() -> Self {
+        Self::Default
     }
 }
 
@@ -1,6 +1,13 @@
 use std::{
-    borrow::BorrowMut, cell::RefCell, collections::HashMap, ops::DerefMut, sync, sync::Arc,
+    borrow::BorrowMut,
+    cell::RefCell,
+    collections::HashMap,
+    io::{Read, Write},
+    ops::DerefMut,
+    sync,
+    sync::Arc,
     thread::JoinHandle,
+    time::Duration,
 };
 
 use ::models::{FieldInfo, InMemPoint, SeriesInfo, Tag, ValueType};
@@ -9,7 +16,6 @@ use lazy_static::lazy_static;
 use logger::{debug, error, info, init, trace, warn};
 use models::{FieldId, SeriesId, Timestamp};
 use once_cell::sync::OnceCell;
-use parking_lot::Mutex;
 use protos::{
     kv_service::{WritePointsRpcRequest, WritePointsRpcResponse, WriteRowsRpcRequest},
     models as fb_models,
@@ -18,31 +24,56 @@ use snafu::ResultExt;
 use tokio::{
     runtime::Builder,
     sync::{
-        mpsc::{self, UnboundedReceiver, UnboundedSender},
-        oneshot, RwLock,
+        mpsc::{self, Receiver, Sender, UnboundedReceiver, UnboundedSender},
+        oneshot, Mutex, Notify, RwLock,
     },
 };
 
 use crate::{
-    compaction::{run_flush_memtable_job, FlushReq},
+    cleaner,
+    compaction::{
+        self, run_flush_memtable_job, CompactionReport, CompactionScheduler, CompactionTotals,
+        DiskSpace, FlushQueue, FlushReq, LevelCompactionPicker, LogEvent,
+    },
     context::GlobalContext,
+    debug_dump::{DumpField, TsfDebugDump},
     error::{self, Result},
     file_manager::{self, FileManager},
     file_utils,
     forward_index::ForwardIndex,
     kv_option::{DBOptions, Options, QueryOption, TseriesFamDesc, TseriesFamOpt, WalConfig},
-    memcache::{DataType, MemCache},
+    lru_cache::{get_block_cache, CacheStats},
+    memcache::{check_utf8, DataType, FieldHints, MemCacheRef},
+    migrate::{self, MigrateReport},
     record_file::Reader,
+    request_window::RequestWindow,
+    restore,
     runtime::WorkerQueue,
-    summary::{Summary, SummaryProcesser, SummaryTask, VersionEdit},
-    tseries_family::{TimeRange, Version},
-    tsm::{BlockReader, TsmBlockReader, TsmIndexReader, TsmTombstone},
+    scrub::{self, ScrubReport, Throttle},
+    snapshot::{self, ExportManifest},
+    summary::{self, PendingPurge, Summary, SummaryProcesser, SummaryTask, TrashEdit, VersionEdit},
+    trash,
+    tseries_family::{TimeRange, TombstoneCompactStats, Version},
+    tsm::{BlockReader, CompressionStats, TsmBlockReader, TsmIndexReader, TsmTombstone},
     version_set,
     version_set::VersionSet,
-    wal::{self, WalEntryType, WalManager, WalTask},
+    wal::{self, RecoverTarget, WalEntryType, WalManager, WalRecord, WalTask},
+    write_stats::{RejectCounts, WriteRejectReason, WriteRejects, WriteSummary},
     Error, Task,
 };
 
+// the period of the job purging the expired trash entries
+const TRASH_PURGE_INTERVAL: Duration = Duration::from_secs(60);
+// the period of the job looking for the files due to be scrubbed
+const SCRUB_INTERVAL: Duration = Duration::from_secs(60);
+// the period of the job flushing the delta caches older than their flush age
+const DELTA_FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+// the period of the job compacting the tseries families, each run compacts until none needs it
+const COMPACTION_INTERVAL: Duration = Duration::from_secs(10);
+// the delay before the caches put back for lack of disk space are flushed again, unless another
+// request comes first
+const FLUSH_RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
 pub struct Entry {
     pub series_id: u64,
 }
@@ -54,65 +85,170 @@ pub struct TsKv {
 
     wal_sender: UnboundedSender<WalTask>,
     forward_index: Arc<RwLock<ForwardIndex>>,
+    request_window: Arc<RequestWindow>,
 
-    flush_task_sender: UnboundedSender<Arc<Mutex<Vec<FlushReq>>>>,
+    global_ctx: Arc<GlobalContext>,
+    flush_task_sender: Sender<FlushReq>,
+    // the requests sent by `flush_task_sender` waiting for the flush job of this store
+    flush_queue: Arc<FlushQueue>,
     summary_task_sender: UnboundedSender<SummaryTask>,
+    // chooses the tseries family compacted next by the compaction policy
+    compaction_scheduler: Arc<Mutex<CompactionScheduler>>,
+    // true for a store restored to an earlier point until the restore is confirmed
+    read_only: bool,
 }
 
 impl TsKv {
     pub async fn open(opt: Options) -> Result<Self> {
+        Self::open_to(opt, None).await
+    }
+
+    /// Opens the store as it was at the target, from the summary, the tombstones and the wal
+    /// kept. The store is read-only unless `opt.restore.confirmed`, then the edits, the files,
+    /// the deletes and the wal entries after the target are dropped for good; the files are
+    /// moved into the quarantine directory.
+    ///
+    /// The dropping of a tseries family is not restored. A restore to a point between an
+    /// earlier confirmed restore and its target is not supported.
+    pub async fn recover_to(opt: Options, target: RecoverTarget) -> Result<Self> {
+        Self::open_to(opt, Some(target)).await
+    }
+
+    async fn open_to(opt: Options, target: Option<RecoverTarget>) -> Result<Self> {
+        let window = opt.compact_conf.parse_window()?;
         let shared_options = Arc::new(opt);
         let kvctx = Arc::new(KvContext::new(shared_options.clone()));
-        let (flush_task_sender, flush_task_receiver) = mpsc::unbounded_channel();
-        let (version_set, summary) =
-            Self::recover(shared_options.clone(), flush_task_sender.clone()).await;
+        let flush_queue = Arc::new(FlushQueue::new(shared_options.db.flush_queue_capacity));
+        let (flush_task_sender, flush_task_receiver) = mpsc::channel(flush_queue.capacity());
         let mut fidx = ForwardIndex::new(&shared_options.forward_index_conf.path);
         fidx.load_cache_file().await.map_err(|err| Error::LogRecordErr { source: err })?;
+        let forward_index = Arc::new(RwLock::new(fidx));
+        let request_window = Arc::new(RequestWindow::new(&shared_options.request_window));
+        let (version_set, summary, read_only) = Self::recover(shared_options.clone(),
+                                                              target,
+                                                              flush_task_sender.clone(),
+                                                              forward_index.clone(),
+                                                              &request_window).await?;
         let (wal_sender, wal_receiver) = mpsc::unbounded_channel();
         let (summary_task_sender, summary_task_receiver) = mpsc::unbounded_channel();
+        let picker = LevelCompactionPicker::new(HashMap::new());
+        let compact_conf = &shared_options.compact_conf;
+        let scheduler = CompactionScheduler::new(picker, compact_conf.policy)
+            .with_window(window, compact_conf.urgent_score);
         let core = Self { options: shared_options,
                           kvctx,
-                          forward_index: Arc::new(RwLock::new(fidx)),
+                          forward_index,
+                          request_window,
                           version_set,
                           wal_sender,
+                          global_ctx: summary.global_context(),
                           flush_task_sender,
-                          summary_task_sender: summary_task_sender.clone() };
+                          flush_queue,
+                          summary_task_sender: summary_task_sender.clone(),
+                          compaction_scheduler: Arc::new(Mutex::new(scheduler)),
+                          read_only };
         core.run_wal_job(wal_receiver);
-        core.run_flush_job(flush_task_receiver,
-                           summary.global_context(),
-                           summary.version_set(),
-                           summary_task_sender.clone());
-        core.run_summary_job(summary, summary_task_receiver, summary_task_sender);
+        core.run_summary_job(summary, summary_task_receiver, summary_task_sender.clone());
+        // a read-only store flushes no cache, the replayed points are held in memory
+        if !read_only {
+            core.run_flush_job(flush_task_receiver,
+                               core.global_ctx.clone(),
+                               core.version_set.clone(),
+                               summary_task_sender);
+            core.run_purge_job();
+            core.run_scrub_job();
+            core.run_clean_job();
+            core.run_delta_flush_job();
+            core.run_compact_job();
+        }
 
         Ok(core)
     }
 
+    // returns true for a store restored to the target but not confirmed yet
     async fn recover(opt: Arc<Options>,
-                     flush_task_sender: UnboundedSender<Arc<Mutex<Vec<FlushReq>>>>)
-                     -> (Arc<RwLock<VersionSet>>, Summary) {
+                     target: Option<RecoverTarget>,
+                     flush_task_sender: Sender<FlushReq>,
+                     forward_index: Arc<RwLock<ForwardIndex>>,
+                     request_window: &RequestWindow)
+                     -> Result<(Arc<RwLock<VersionSet>>, Summary, bool)> {
         if !file_manager::try_exists(&opt.db.db_path) {
             std::fs::create_dir_all(&opt.db.db_path).context(error::IOSnafu).unwrap();
         }
+        let mut wal_manager = WalManager::new(opt.wal.clone());
+        let end_seq = match target {
+            Some(target) => wal_manager.resolve_target(target)?,
+            None => u64::MAX,
+        };
         let summary_file = file_utils::make_summary_file(&opt.db.db_path, 0);
-        let summary = if file_manager::try_exists(&summary_file) {
-            Summary::recover(&opt.db).await.unwrap()
+        // a creation of the store interrupted by a crash is undone, or finished if the
+        // summary file is in place
+        Summary::clean_init(&opt.db.db_path)?;
+        // the files of the layout before the base directory are moved under it
+        let base_dir = TseriesFamOpt::from_config().base_dir;
+        let moved = file_utils::move_legacy_files(&opt.db.legacy_tsm_dir,
+                                                  &opt.db.legacy_delta_dir,
+                                                  &base_dir)?;
+        if moved > 0 {
+            info!("moved {} files of the legacy layout under {}", moved, base_dir);
+        }
+        let (mut summary, undo) = if file_manager::try_exists(&summary_file) {
+            Summary::recover_to(&opt.db, end_seq).await?
         } else {
-            Summary::new(&opt.db).await.unwrap()
+            (Summary::new(&opt.db).await?, vec![])
         };
         let version_set = summary.version_set().clone();
-        let wal_manager = WalManager::new(opt.wal.clone());
-        wal_manager.recover(version_set.clone(),
-                            summary.global_context().clone(),
-                            flush_task_sender)
-                   .await
-                   .unwrap();
+        // finish the moves into the trash interrupted by a crash
+        for purge in version_set.read().await.trash() {
+            if let Err(e) = trash::move_to_trash(purge) {
+                warn!("failed to move tseries family {} into trash entry {}: {:?}",
+                      purge.tsf_id, purge.path, e);
+            }
+        }
+        wal_manager.recover_to(end_seq,
+                               version_set.clone(),
+                               summary.global_context().clone(),
+                               flush_task_sender,
+                               forward_index,
+                               request_window)
+                   .await?;
+
+        if end_seq == u64::MAX {
+            return Ok((version_set, summary, false));
+        }
+        warn!("store restored to wal sequence {}", end_seq);
+        if !opt.restore.confirmed {
+            return Ok((version_set, summary, true));
+        }
+        restore::confirm(&mut summary,
+                         &mut wal_manager,
+                         undo,
+                         end_seq,
+                         &opt.restore.quarantine_dir).await?;
+        Ok((version_set, summary, false))
+    }
 
-        (version_set.clone(), summary)
+    fn check_writable(&self) -> Result<()> {
+        if self.read_only {
+            return Err(Error::RestoreNotConfirmed);
+        }
+        Ok(())
     }
 
     pub async fn write(&self,
                        write_batch: WritePointsRpcRequest)
                        -> Result<WritePointsRpcResponse> {
+        self.write_with_summary(write_batch).await?;
+        Ok(WritePointsRpcResponse { version: 1, points: vec![] })
+    }
+
+    /// Writes like `write`, returns how many field values are put into the caches and how
+    /// many are rejected by reason. A retried request already applied puts nothing.
+    pub async fn write_with_summary(&self,
+                                    write_batch: WritePointsRpcRequest)
+                                    -> Result<WriteSummary> {
+        self.check_writable()?;
+        let request_id = write_batch.request_id;
         let shared_write_batch = Arc::new(write_batch.points);
         let fb_points = flatbuffers::root::<fb_models::Points>(&shared_write_batch)
             .context(error::InvalidFlatbufferSnafu)?;
@@ -120,96 +256,110 @@ impl TsKv {
         // get or create forward index
         for point in fb_points.points().unwrap() {
             let info = SeriesInfo::from_flatbuffers(&point).context(error::InvalidModelSnafu)?;
-            self.forward_index
-                .write()
-                .await
-                .add_series_info_if_not_exists(info)
-                .await
-                .context(error::ForwardIndexErrSnafu)?;
+            let mut forward_index = self.forward_index.write().await;
+            if forward_index.contains(&info) {
+                continue;
+            }
+            self.write_wal_record(WalRecord::DictUpdate { series_info: info.encode() }).await?;
+            forward_index.add_series_info_if_not_exists(info)
+                         .await
+                         .context(error::ForwardIndexErrSnafu)?;
         }
 
-        // write wal
-        let (cb, rx) = oneshot::channel();
-        self.wal_sender
-            .send(WalTask::Write { points: shared_write_batch.clone(), cb })
-            .map_err(|err| Error::Send)?;
-        let (seq, _) = rx.await.context(error::ReceiveSnafu)??;
-
-        // write memcache
-        if let Some(points) = fb_points.points() {
-            let mut version_set = self.version_set.write().await;
-            for point in points.iter() {
+        // reject invalid strings before they are logged
+        {
+            let version_set = self.version_set.read().await;
+            for point in fb_points.points().unwrap() {
                 let p = InMemPoint::from(point);
-                let sid = p.series_id();
-                if let Some(tsf) = version_set.get_tsfamily(sid) {
+                if let Some(tsf) = version_set.get_tsfamily_immut(p.series_id()) {
                     for f in p.fields().iter() {
-                        tsf.put_mutcache(f.field_id(),
-                                         &f.value,
-                                         f.value_type,
-                                         seq,
-                                         point.timestamp() as i64,
-                                         self.flush_task_sender.clone())
-                           .await
+                        let res = check_utf8(tsf.options().utf8_policy, f.value_type, &f.value);
+                        if res.is_err() {
+                            let reason = WriteRejectReason::InvalidUtf8;
+                            tsf.rejects().record(f.field_id(), point.timestamp() as i64, reason);
+                            self.global_ctx.write_rejects().add(reason, 1);
+                        }
+                        res?;
                     }
-                } else {
-                    warn!("ts_family for sid {} not found.", sid);
                 }
             }
         }
 
-        // let _ = self.kvctx.shard_write(0, write_batch).await;
-        Ok(WritePointsRpcResponse { version: 1, points: vec![] })
-    }
+        // a request retried by the client is applied once, 0 is a request without an id
+        if request_id != 0 && !self.request_window.insert(request_id) {
+            debug!("write request {} is already applied, skipped.", request_id);
+            return Ok(WriteSummary::default());
+        }
 
-    pub async fn read_point(&self, sid: SeriesId, time_range: &TimeRange, field_id: FieldId) {
-        let mut version_set = self.version_set.write().await;
-        if let Some(tsf) = version_set.get_tsfamily(sid) {
-            // get data from memcache
-            if let Some(mem_entry) = tsf.cache().read().await.data_cache.get(&field_id) {
-                info!("memcache::{}::{}", sid.clone(), field_id);
-                mem_entry.read_cell(time_range);
+        // write wal
+        let seq = if request_id == 0 {
+            let (cb, rx) = oneshot::channel();
+            self.wal_sender
+                .send(WalTask::Write { points: shared_write_batch.clone(), cb })
+                .map_err(|err| Error::Send)?;
+            rx.await.context(error::ReceiveSnafu)??.0
+        } else {
+            let record = WalRecord::Request { request_id, points: shared_write_batch.to_vec() };
+            let res = self.write_wal_record(record).await;
+            if res.is_err() {
+                self.request_window.remove(request_id);
             }
+            res?
+        };
 
-            // get data from delta_memcache
-            if let Some(mem_entry) = tsf.delta_cache().read().await.data_cache.get(&field_id) {
-                info!("delta memcache::{}::{}", sid.clone(), field_id);
-                mem_entry.read_cell(time_range);
-            }
+        // write memcache; the room in the flush queue is waited for first, not under the version
+        // set which the flush job takes too
+        self.flush_queue.wait_for_room().await;
+        let mut version_set = self.version_set.write().await;
+        let summary = put_points(&mut version_set,
+                                 &fb_points,
+                                 seq,
+                                 self.global_ctx.write_rejects(),
+                                 self.flush_task_sender.clone()).await;
 
-            // get data from im_memcache
-            for mem_cache in tsf.im_cache().iter() {
-                if let Some(mem_entry) = mem_cache.read().await.data_cache.get(&field_id) {
-                    info!("im_memcache::{}::{}", sid.clone(), field_id);
-                    mem_entry.read_cell(time_range);
-                }
-            }
+        // let _ = self.kvctx.shard_write(0, write_batch).await;
+        Ok(summary)
+    }
 
-            // get data from levelinfo
-            for level_info in tsf.version().read().await.levels_info.iter() {
-                if level_info.level == 0 {
-                    continue;
-                }
-                info!("levelinfo::{}::{}", sid.clone(), field_id);
-                level_info.read_columnfile(tsf.tf_id(), field_id, time_range);
-            }
+    pub async fn read_point(&self,
+                            sid: SeriesId,
+                            time_range: &TimeRange,
+                            field_id: FieldId)
+                            -> Result<()> {
+        for data in self.scan(sid, field_id, time_range).await? {
+            info!("{}::{}::{:?}", sid, field_id, data);
+        }
+        Ok(())
+    }
 
-            // get data from delta
-            let level_info = &tsf.version().read().await.levels_info;
-            if !level_info.is_empty() {
-                info!("delta::{}::{}", sid.clone(), field_id);
-                level_info[0].read_columnfile(tsf.tf_id(), field_id, time_range);
-            }
-        } else {
-            warn!("ts_family with sid {} not found.", sid);
+    /// Returns the points of a field of the series in the time range, sorted by timestamp and
+    /// merged from the caches and the column files; fails if a file could not be read.
+    pub async fn scan(&self,
+                      sid: SeriesId,
+                      field_id: FieldId,
+                      time_range: &TimeRange)
+                      -> Result<Vec<DataType>> {
+        let version_set = self.version_set.read().await;
+        match version_set.get_tsfamily_immut(sid) {
+            Some(tsf) => tsf.scan(field_id, time_range).await,
+            None => {
+                warn!("ts_family with sid {} not found.", sid);
+                Ok(vec![])
+            },
         }
     }
 
-    pub async fn read(&self, sids: Vec<SeriesId>, time_range: &TimeRange, fields: Vec<FieldId>) {
+    pub async fn read(&self,
+                      sids: Vec<SeriesId>,
+                      time_range: &TimeRange,
+                      fields: Vec<FieldId>)
+                      -> Result<()> {
         for sid in sids {
             for field_id in fields.iter() {
-                self.read_point(sid, time_range, *field_id).await;
+                self.read_point(sid, time_range, *field_id).await?;
             }
         }
+        Ok(())
     }
 
     pub async fn delete_series(&self,
@@ -217,60 +367,266 @@ impl TsKv {
                                min: Timestamp,
                                max: Timestamp)
                                -> Result<()> {
+        self.check_writable()?;
         let series_infos = self.forward_index.read().await.get_series_info_list(&sids);
         let timerange = TimeRange { max_ts: max, min_ts: min };
-        let path = self.options.db.db_path.clone();
         for series_info in series_infos {
             let vs = self.version_set.read().await;
             if let Some(tsf) = vs.get_tsfamily_immut(series_info.series_id()) {
-                tsf.delete_cache(&TimeRange { min_ts: min, max_ts: max }).await;
-                let version = tsf.version().read().await;
-                for level in version.levels_info() {
-                    if level.ts_range.overlaps(&timerange) {
-                        for column_file in level.files.iter() {
-                            if column_file.range().overlaps(&timerange) {
-                                let field_ids: Vec<FieldId> = series_info.field_infos()
-                                                                         .iter()
-                                                                         .map(|f| f.field_id())
-                                                                         .collect();
-                                let tombstone_manager =
-                                    TsmTombstone::with_tsm_file_id(&path, column_file.file_id())?;
-                                tombstone_manager.add_range(&field_ids, min, max)?;
-                                tombstone_manager.sync()?;
-                            }
-                        }
-                    }
-                }
+                let field_ids: Vec<FieldId> =
+                    series_info.field_infos().iter().map(|f| f.field_id()).collect();
+                // log the delete before mutating, so that it survives a crash
+                let record = WalRecord::DeleteRange { tf_id: tsf.tf_id(),
+                                                      field_ids: field_ids.clone(),
+                                                      min_ts: min,
+                                                      max_ts: max };
+                let seq = self.write_wal_record(record).await?;
+                tsf.delete_range(seq, &field_ids, &timerange).await?;
             }
         }
 
         Ok(())
     }
 
-    pub async fn insert_cache(&self, seq: u64, buf: &[u8]) -> Result<()> {
-        let ps =
-            flatbuffers::root::<fb_models::Points>(buf).context(error::InvalidFlatbufferSnafu)?;
-        if let Some(points) = ps.points() {
+    pub async fn drop_field(&self, sid: SeriesId, field_id: FieldId) -> Result<()> {
+        self.check_writable()?;
+        let vs = self.version_set.read().await;
+        if let Some(tsf) = vs.get_tsfamily_immut(sid) {
+            let seq = self.write_wal_record(WalRecord::DropField { tf_id: tsf.tf_id(), field_id })
+                          .await?;
+            tsf.delete_range(seq, &[field_id], &TimeRange::new(i64::MAX, i64::MIN)).await?;
+        } else {
+            warn!("ts_family for sid {} not found.", sid);
+        }
+
+        Ok(())
+    }
+
+    /// Flushes the caches of every tseries family into column files, returns once the files
+    /// are readable and their version edits are persisted in the summary.
+    ///
+    /// The caches the disk cannot hold are handed to the flush job, which flushes them once
+    /// there is room; `Error::FlushPostponed` is returned then.
+    pub async fn flush(&self) -> Result<()> {
+        self.check_writable()?;
+        let mut reqs = vec![];
+        {
             let mut version_set = self.version_set.write().await;
-            for point in points.iter() {
-                let p = InMemPoint::from(point);
-                // use sid to dispatch to tsfamily
-                // so if you change the colume name
-                // please keep the series id
-                let sid = p.series_id();
-                if let Some(tsf) = version_set.get_tsfamily(sid) {
-                    for f in p.fields().iter() {
-                        tsf.put_mutcache(f.field_id(),
-                                         &f.value,
-                                         f.value_type,
-                                         seq,
-                                         point.timestamp() as i64,
-                                         self.flush_task_sender.clone())
-                           .await
-                    }
+            for tsf in version_set.tsfamilies_mut() {
+                if let Some(req) = tsf.take_flush_req().await {
+                    reqs.push(req);
                 }
             }
         }
+        self.flush_reqs(reqs).await
+    }
+
+    // flushes the caches of the requests, see `flush`
+    async fn flush_reqs(&self, reqs: Vec<FlushReq>) -> Result<()> {
+        if reqs.is_empty() {
+            return Ok(());
+        }
+
+        let queue = FlushQueue::new(reqs.len());
+        for req in reqs {
+            queue.push(req);
+        }
+        let applied = run_flush_memtable_job(&queue,
+                                             self.global_ctx.clone(),
+                                             HashMap::new(),
+                                             self.version_set.clone(),
+                                             self.summary_task_sender.clone(),
+                                             &DiskSpace::default()).await?;
+        let postponed = queue.take();
+        if !postponed.is_empty() {
+            let count = postponed.iter().map(|req| req.mems.len()).sum();
+            for req in postponed {
+                self.flush_task_sender.send(req).await.map_err(|err| Error::Send)?;
+            }
+            return Err(Error::FlushPostponed { count });
+        }
+        applied.await.context(error::ReceiveSnafu)?
+    }
+
+    /// Drops the tseries family, its caches are flushed first. With `delete_files` its
+    /// directories are moved into a trash entry, from where `undrop_tsf` restores them until
+    /// the entry is purged once the `trash_ttl_secs` of the family is over; without it the
+    /// directories are left in place.
+    pub async fn drop_tsf(&self, tf_id: u32, delete_files: bool) -> Result<()> {
+        self.check_writable()?;
+        let req = match self.version_set.write().await.get_tsfamily_by_id(tf_id) {
+            Some(tsf) => tsf.take_flush_req().await,
+            None => return Err(Error::TsfNotFound { tf_id }),
+        };
+        self.flush_reqs(req.into_iter().collect()).await?;
+
+        let mut version_set = self.version_set.write().await;
+        let tsf = version_set.get_tsfamily_by_id(tf_id).ok_or(Error::TsfNotFound { tf_id })?;
+        let purge = if delete_files {
+            let opt = tsf.options();
+            let version = tsf.version().read().await;
+            let dropped_at = trash::now_secs();
+            let path = file_utils::make_trash_entry(&opt.trash_dir, tf_id, dropped_at);
+            let purge = PendingPurge { tsf_id: tf_id,
+                                       tsf_name: version.get_name().to_string(),
+                                       dropped_at,
+                                       ttl_secs: opt.trash_ttl_secs,
+                                       path: path.to_string_lossy().to_string(),
+                                       tsm_dir: opt.tsm_dir(tf_id),
+                                       delta_dir: opt.delta_dir(tf_id),
+                                       files: version.live_files(),
+                                       last_seq: version.last_seq,
+                                       max_level_ts: version.max_level_ts };
+            Some(purge)
+        } else {
+            None
+        };
+        let mut edit = VersionEdit::new();
+        edit.del_tsf(tf_id);
+        let trash_edits = purge.iter().cloned().map(TrashEdit::Add).collect();
+        summary::apply_edits(&self.summary_task_sender, vec![edit], trash_edits).await?;
+        version_set.remove_tsfamily(tf_id);
+        if let Some(purge) = purge {
+            // the entry is kept if the move fails, the move is retried by the recovery
+            let res = trash::move_to_trash(&purge);
+            version_set.add_trash(purge);
+            res?;
+        }
+        Ok(())
+    }
+
+    /// Restores the tseries family dropped with `delete_files`, with the files it had when
+    /// dropped. Returns `Error::TrashNotFound` if its trash entry is purged.
+    pub async fn undrop_tsf(&self, tf_id: u32) -> Result<()> {
+        self.check_writable()?;
+        let mut version_set = self.version_set.write().await;
+        let purge = version_set.take_trash(tf_id).ok_or(Error::TrashNotFound { tf_id })?;
+        if let Err(e) = trash::restore(&purge) {
+            version_set.add_trash(purge);
+            return Err(e);
+        }
+        let trash_edits = vec![TrashEdit::Remove { path: purge.path.clone() }];
+        let res = summary::apply_edits(&self.summary_task_sender,
+                                       purge.restore_edits(),
+                                       trash_edits).await;
+        if let Err(e) = res {
+            if let Err(e) = trash::move_to_trash(&purge) {
+                warn!("failed to move tseries family {} back into trash entry {}: {:?}",
+                      tf_id, purge.path, e);
+            }
+            version_set.add_trash(purge);
+            return Err(e);
+        }
+        version_set.restore_tsfamily(&purge, purge.options()).await;
+        Ok(())
+    }
+
+    /// Exports the live files of the tseries family into a tar archive written to `writer`,
+    /// see `snapshot::export`. The caches are flushed first, so that the archive holds every
+    /// point written before.
+    pub async fn export_snapshot(&self,
+                                 tf_id: u32,
+                                 writer: impl Write + Send + 'static)
+                                 -> Result<ExportManifest> {
+        self.check_writable()?;
+        let req = match self.version_set.write().await.get_tsfamily_by_id(tf_id) {
+            Some(tsf) => tsf.take_flush_req().await,
+            None => return Err(Error::TsfNotFound { tf_id }),
+        };
+        self.flush_reqs(req.into_iter().collect()).await?;
+        let version = match self.version_set.write().await.get_tsfamily_by_id(tf_id) {
+            Some(tsf) => tsf.version().clone(),
+            None => return Err(Error::TsfNotFound { tf_id }),
+        };
+        snapshot::export(tf_id, &version, writer).await
+    }
+
+    /// Imports an archive written by `export_snapshot` as the tseries family `tf_id`, which
+    /// must not exist: the files are extracted and checked on a blocking thread, see
+    /// `snapshot::import`, then moved into place and registered in the summary. The version
+    /// set is locked only to check the tseries family and to register it.
+    pub async fn import_snapshot(&self,
+                                 tf_id: u32,
+                                 reader: impl Read + Send + 'static)
+                                 -> Result<ExportManifest> {
+        self.check_writable()?;
+        if self.version_set.read().await.get_tsfamily_by_id(tf_id).is_some() {
+            return Err(Error::TsfExists { tf_id });
+        }
+        let opt = TseriesFamOpt::from_config();
+        let extract = {
+            let opt = opt.clone();
+            move || snapshot::import(reader, &opt, tf_id)
+        };
+        let manifest =
+            tokio::task::spawn_blocking(extract).await.expect("snapshot import panicked")?;
+
+        // the tseries family may have been created meanwhile
+        let mut version_set = self.version_set.write().await;
+        if version_set.get_tsfamily_by_id(tf_id).is_some() {
+            snapshot::discard_imported(&opt, tf_id);
+            return Err(Error::TsfExists { tf_id });
+        }
+        snapshot::install_imported(&opt, tf_id)?;
+        // the files keep their ids, the files written later must not take them
+        if let Some(max) = manifest.files.iter().map(|meta| meta.file_id).max() {
+            if self.global_ctx.file_id() <= max {
+                self.global_ctx.set_file_id(max + 1);
+            }
+        }
+        let edits = manifest.import_edits(tf_id);
+        let files = edits[1].add_files.clone();
+        if let Err(e) = summary::apply_edits(&self.summary_task_sender, edits, vec![]).await {
+            snapshot::remove_imported(&opt, tf_id);
+            return Err(e);
+        }
+        version_set.register_tsfamily(tf_id,
+                                      &manifest.tsf_name,
+                                      manifest.last_seq,
+                                      manifest.max_level_ts,
+                                      &files,
+                                      opt)
+                   .await;
+        Ok(manifest)
+    }
+
+    /// Purges the trash entries expired at `now`, in seconds since the epoch, returns the ids
+    /// of the purged tseries families. The purge job calls it with the current time.
+    pub async fn purge_trash(&self, now: u64) -> Result<Vec<u32>> {
+        self.check_writable()?;
+        trash::purge_expired(&self.version_set, &self.summary_task_sender, now).await
+    }
+
+    /// Verifies the checksums of the files not scrubbed for the scrub interval at `now`, in
+    /// seconds since the epoch, and reports the corrupt ones. The scrub job calls it with the
+    /// current time; it does not read while there is write pressure.
+    pub async fn scrub(&self, now: u64) -> Result<ScrubReport> {
+        self.check_writable()?;
+        let config = &self.options.scrub;
+        let flush_queue = self.flush_queue.clone();
+        let throttle =
+            Throttle::new(config.bytes_per_sec, move || scrub::under_write_pressure(&flush_queue));
+        scrub::scrub_once(&self.version_set, &self.summary_task_sender, config, now, throttle).await
+    }
+
+    async fn write_wal_record(&self, record: WalRecord) -> Result<u64> {
+        let (cb, rx) = oneshot::channel();
+        self.wal_sender.send(WalTask::Record { record, cb }).map_err(|err| Error::Send)?;
+        let (seq, _) = rx.await.context(error::ReceiveSnafu)??;
+        Ok(seq)
+    }
+
+    pub async fn insert_cache(&self, seq: u64, buf: &[u8]) -> Result<()> {
+        self.check_writable()?;
+        let ps =
+            flatbuffers::root::<fb_models::Points>(buf).context(error::InvalidFlatbufferSnafu)?;
+        self.flush_queue.wait_for_room().await;
+        let mut version_set = self.version_set.write().await;
+        put_points(&mut version_set,
+                   &ps,
+                   seq,
+                   self.global_ctx.write_rejects(),
+                   self.flush_task_sender.clone()).await;
 
         Ok(())
     }
@@ -293,6 +649,15 @@ impl TsKv {
                             },
                         }
                     },
+                    WalTask::Record { record, cb } => {
+                        let ret = match record.encode() {
+                            Ok(buf) => wal_manager.write(wal::WalEntryType::Record, &buf).await,
+                            Err(err) => Err(err),
+                        };
+                        if cb.send(ret).is_err() {
+                            warn!("send WAL record result failed.")
+                        }
+                    },
                 }
             }
         };
@@ -301,18 +666,44 @@ impl TsKv {
     }
 
     fn run_flush_job(&self,
-                     mut receiver: UnboundedReceiver<Arc<Mutex<Vec<FlushReq>>>>,
+                     mut receiver: Receiver<FlushReq>,
                      ctx: Arc<GlobalContext>,
                      version_set: Arc<RwLock<VersionSet>>,
                      sender: UnboundedSender<SummaryTask>) {
+        // the requests are queued as soon as they are sent, those sent during a flush are
+        // flushed together by the next one; nothing is received while the queue is full
+        let queue = self.flush_queue.clone();
+        let ready = Arc::new(Notify::new());
+        let queued = ready.clone();
+        tokio::spawn(async move {
+            loop {
+                queue.wait_for_room().await;
+                let req = match receiver.recv().await {
+                    Some(req) => req,
+                    None => break,
+                };
+                let queue_len = queue.push(req);
+                debug!("{}", LogEvent::new("flush_queued").field("queue_len", queue_len));
+                queued.notify_one();
+            }
+        });
+        let queue = self.flush_queue.clone();
         let f = async move {
-            while let Some(x) = receiver.recv().await {
-                run_flush_memtable_job(x.clone(),
+            let space = DiskSpace::default();
+            loop {
+                if queue.is_empty() {
+                    ready.notified().await;
+                } else {
+                    // the caches put back by the last flush are retried even if nothing is sent
+                    let _ = tokio::time::timeout(FLUSH_RETRY_INTERVAL, ready.notified()).await;
+                }
+                run_flush_memtable_job(&queue,
                                        ctx.clone(),
                                        HashMap::new(),
                                        version_set.clone(),
-                                       sender.clone()).await
-                                                      .unwrap();
+                                       sender.clone(),
+                                       &space).await
+                                              .unwrap();
             }
         };
         tokio::spawn(f);
@@ -335,6 +726,120 @@ impl TsKv {
         warn!("Summary task handler started");
     }
 
+    fn run_purge_job(&self) {
+        let version_set = self.version_set.clone();
+        let sender = self.summary_task_sender.clone();
+        let f = async move {
+            let mut ticker = tokio::time::interval(TRASH_PURGE_INTERVAL);
+            loop {
+                ticker.tick().await;
+                if let Err(e) = trash::purge_expired(&version_set, &sender, trash::now_secs()).await
+                {
+                    warn!("failed to purge the trash: {:?}", e);
+                }
+            }
+        };
+        tokio::spawn(f);
+        warn!("Trash purge task handler started");
+    }
+
+    fn run_scrub_job(&self) {
+        let config = self.options.scrub.clone();
+        if config.bytes_per_sec == 0 {
+            return;
+        }
+        let version_set = self.version_set.clone();
+        let sender = self.summary_task_sender.clone();
+        let flush_queue = self.flush_queue.clone();
+        let f = async move {
+            let mut ticker = tokio::time::interval(SCRUB_INTERVAL);
+            loop {
+                ticker.tick().await;
+                let flush_queue = flush_queue.clone();
+                let throttle = Throttle::new(config.bytes_per_sec, move || {
+                    scrub::under_write_pressure(&flush_queue)
+                });
+                let now = trash::now_secs();
+                if let Err(e) =
+                    scrub::scrub_once(&version_set, &sender, &config, now, throttle).await
+                {
+                    warn!("failed to scrub the files: {:?}", e);
+                }
+            }
+        };
+        tokio::spawn(f);
+        warn!("Scrub task handler started");
+    }
+
+    fn run_clean_job(&self) {
+        let config = self.options.cleaner.clone();
+        if config.interval_secs == 0 {
+            return;
+        }
+        let version_set = self.version_set.clone();
+        let f = async move {
+            let mut ticker = tokio::time::interval(Duration::from_secs(config.interval_secs));
+            loop {
+                ticker.tick().await;
+                cleaner::clean_once(&version_set, &config, trash::now_secs()).await;
+            }
+        };
+        tokio::spawn(f);
+        warn!("Cleaner task handler started");
+    }
+
+    fn run_delta_flush_job(&self) {
+        let version_set = self.version_set.clone();
+        let sender = self.flush_task_sender.clone();
+        let f = async move {
+            let mut ticker = tokio::time::interval(DELTA_FLUSH_INTERVAL);
+            loop {
+                ticker.tick().await;
+                let now = trash::now_secs();
+                let mut version_set = version_set.write().await;
+                for tsf in version_set.tsfamilies_mut() {
+                    tsf.flush_aged_delta(now, sender.clone()).await;
+                }
+            }
+        };
+        tokio::spawn(f);
+        warn!("Delta flush task handler started");
+    }
+
+    fn run_compact_job(&self) {
+        let version_set = self.version_set.clone();
+        let sender = self.summary_task_sender.clone();
+        let ctx = self.global_ctx.clone();
+        let scheduler = self.compaction_scheduler.clone();
+        let f = async move {
+            let space = DiskSpace::default();
+            // the first run waits for a period, not to compete with the recovery of the store
+            let start = tokio::time::Instant::now() + COMPACTION_INTERVAL;
+            let mut ticker = tokio::time::interval_at(start, COMPACTION_INTERVAL);
+            loop {
+                ticker.tick().await;
+                loop {
+                    let mut scheduler = scheduler.lock().await;
+                    let compacted = compaction::compact_once(&version_set,
+                                                             &mut scheduler,
+                                                             &sender,
+                                                             ctx.clone(),
+                                                             &space).await;
+                    match compacted {
+                        Ok(Some(_)) => {},
+                        Ok(None) => break,
+                        Err(e) => {
+                            warn!("failed to compact the files: {:?}", e);
+                            break;
+                        },
+                    }
+                }
+            }
+        };
+        tokio::spawn(f);
+        warn!("Compaction task handler started");
+    }
+
     pub fn start(tskv: TsKv, mut req_rx: UnboundedReceiver<Task>) {
         init();
         warn!("job 'main' starting.");
@@ -365,11 +870,162 @@ impl TsKv {
     pub fn version_set(&self) -> Arc<RwLock<VersionSet>> {
         self.version_set.clone()
     }
+
+    /// Returns the state of every tseries family without waiting for any lock, the whole
+    /// dump is unavailable while the version set is locked for writing.
+    pub fn debug_dump_all(&self) -> DumpField<Vec<TsfDebugDump>> {
+        DumpField::try_read(self.version_set.as_ref(), |vs| {
+            let mut dumps = vs.debug_dump();
+            for dump in dumps.iter_mut() {
+                dump.pending_flushes = match self.flush_queue.pending_caches(dump.tf_id) {
+                    Some(count) => DumpField::Available(count),
+                    None => DumpField::Unavailable,
+                };
+            }
+            dumps
+        })
+    }
+
+    /// Returns the state of every tseries family stored in the summary file of the database,
+    /// without opening the database; the caches of the dump are always empty.
+    pub async fn debug_dump_dir(opt: &Options) -> Result<Vec<TsfDebugDump>> {
+        Ok(Summary::read_version_set(&opt.db).await?.debug_dump())
+    }
+
+    /// Rewrites the tsm files of the database written in an older format into the current
+    /// format, and appends the new sizes of the files to the summary file, see
+    /// `migrate::migrate_version_set`. The database must not be open meanwhile; a dry run
+    /// only reports the files to migrate.
+    pub async fn migrate_dir(opt: &Options, dry_run: bool) -> Result<MigrateReport> {
+        let summary_file = file_utils::make_summary_file(&opt.db.db_path, 0);
+        if !file_manager::try_exists(&summary_file) {
+            let msg = format!("no summary file {}", summary_file.display());
+            let source = std::io::Error::new(std::io::ErrorKind::NotFound, msg);
+            return Err(Error::IO { source });
+        }
+        let mut summary = Summary::recover(&opt.db).await?;
+        let version_set = summary.version_set();
+        let (report, edits) = migrate::migrate_version_set(&version_set, dry_run).await?;
+        summary.apply_version_edit(&edits).await?;
+        Ok(report)
+    }
+
+    /// Runs one compaction of the tseries family chosen by the compaction policy, like the
+    /// compaction job does, returns its report, or None if no family needs a compaction.
+    pub async fn compact(&self) -> Result<Option<CompactionReport>> {
+        self.check_writable()?;
+        let mut scheduler = self.compaction_scheduler.lock().await;
+        compaction::compact_once(&self.version_set,
+                                 &mut scheduler,
+                                 &self.summary_task_sender,
+                                 self.global_ctx.clone(),
+                                 &DiskSpace::default()).await
+    }
+
+    /// Returns the sum of the reports of the compactions run since the database was opened.
+    pub fn compaction_totals(&self) -> CompactionTotals {
+        self.global_ctx.compaction_metrics().totals()
+    }
+
+    /// Returns the compression of the blocks written by the flushes and the compactions of the
+    /// tseries family since the database was opened, by value type.
+    pub fn compression_stats(&self, tf_id: u32) -> CompressionStats {
+        self.global_ctx.compression_metrics().of(tf_id)
+    }
+
+    /// Returns the field values rejected by the writes since the database was opened, by
+    /// reason.
+    pub fn write_rejects(&self) -> RejectCounts {
+        self.global_ctx.write_rejects().counts()
+    }
+
+    /// Returns the counters of the block cache shared by the scans of the process.
+    pub fn block_cache_stats(&self) -> CacheStats {
+        get_block_cache().stats()
+    }
+
+    /// Returns how many scans of the tseries families were served by the memory caches only,
+    /// see `ReadOptions::memory_only`.
+    pub async fn memory_only_scans(&self) -> u64 {
+        self.version_set.read().await.tsfamilies().map(|tsf| tsf.memory_only_scans()).sum()
+    }
+
+    /// Rewrites the tombstone files of every tseries family with the time ranges of each
+    /// field merged, whatever their size. The deletes bloat the files with overlapping ranges,
+    /// which are only rewritten after a delete once they are large.
+    pub async fn compact_tombstones(&self) -> Result<TombstoneCompactStats> {
+        self.check_writable()?;
+        let version_set = self.version_set.read().await;
+        let mut stats = TombstoneCompactStats::default();
+        for tsf in version_set.tsfamilies() {
+            let tsf_stats = tsf.compact_tombstones().await?;
+            stats.files += tsf_stats.files;
+            stats.bytes_before += tsf_stats.bytes_before;
+            stats.bytes_after += tsf_stats.bytes_after;
+        }
+        info!("{}",
+              LogEvent::new("tombstones_compacted").field("files", stats.files)
+                                                   .field("bytes_before", stats.bytes_before)
+                                                   .field("bytes_after", stats.bytes_after));
+        Ok(stats)
+    }
+
     pub async fn query(&self, _opt: QueryOption) -> Result<Option<Entry>> {
         Ok(None)
     }
 }
 
+/// Puts the points into the caches of their tseries families and returns what is put. The
+/// rejected values are counted by their family, and all of them in `rejects`.
+async fn put_points(version_set: &mut VersionSet,
+                    points: &fb_models::Points<'_>,
+                    seq: u64,
+                    rejects: &WriteRejects,
+                    sender: Sender<FlushReq>)
+                    -> WriteSummary {
+    let mut summary = WriteSummary::default();
+    let points = match points.points() {
+        Some(points) => points,
+        None => return summary,
+    };
+    let mut hints = FieldHints::default();
+    for point in points.iter() {
+        let p = InMemPoint::from(point);
+        // use sid to dispatch to tsfamily
+        // so if you change the colume name
+        // please keep the series id
+        let sid = p.series_id();
+        let ts = point.timestamp() as i64;
+        match version_set.get_tsfamily(sid) {
+            Some(tsf) => {
+                for f in p.fields().iter() {
+                    let res = tsf.put_mutcache_hinted(&mut hints,
+                                                      f.field_id(),
+                                                      &f.value,
+                                                      f.value_type,
+                                                      seq,
+                                                      ts,
+                                                      sender.clone())
+                                 .await;
+                    match res {
+                        Ok(()) => summary.values += 1,
+                        Err(reason) => summary.rejected.add(reason, 1),
+                    }
+                }
+            },
+            None => {
+                let reason = WriteRejectReason::UnknownSeries;
+                summary.rejected.add(reason, p.fields().len() as u64);
+                if let Some(f) = p.fields().first() {
+                    rejects.sample(f.field_id(), ts, reason);
+                }
+            },
+        }
+    }
+    rejects.add_counts(&summary.rejected);
+    summary
+}
+
 #[allow(dead_code)]
 enum KvStatus {
     Init,
@@ -401,7 +1057,7 @@ impl KvContext {
 
     pub async fn shard_write(&self,
                              partion_id: usize,
-                             mem: Arc<RwLock<MemCache>>,
+                             mem: MemCacheRef,
                              entry: WritePointsRpcRequest)
                              -> Result<()> {
         let (tx, rx) = oneshot::channel();
@@ -422,10 +1078,18 @@ impl KvContext {
 
 #[cfg(test)]
 mod test {
-    use std::sync::Arc;
+    use std::{
+        collections::HashMap,
+        ops::RangeInclusive,
+        path::Path,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
 
     use chrono::Local;
-    use config::GLOBAL_CONFIG;
     use futures::{channel::oneshot, future::join_all, SinkExt};
     use logger::{debug, error, info, warn};
     use models::{FieldInfo, SeriesInfo, Tag, ValueType};
@@ -436,11 +1100,21 @@ mod test {
     use snafu::ResultExt;
     use tokio::sync::{mpsc, oneshot::channel};
 
+    use super::put_points;
     use crate::{
-        error,
-        kv_option::{TseriesFamDesc, TseriesFamOpt, WalConfig},
-        summary::{Summary, VersionEdit},
+        compaction::{CompactionFilterRef, FlushReq, RetentionFilter},
+        debug_dump::DumpField,
+        error, file_manager,
+        forward_index::ForwardIndexConfig,
+        kv_option::{
+            CompactConfig, CompactionPolicy, DBOptions, Options, TseriesFamDesc, TseriesFamOpt,
+            WalConfig,
+        },
+        memcache::DataType,
+        summary::VersionEdit,
         tseries_family::TimeRange,
+        version_set::VersionSet,
+        write_stats::{RejectCounts, WriteRejects},
         Error, Task, TsKv,
     };
 
@@ -471,9 +1145,99 @@ mod test {
         let points = models_helper::create_random_points(&mut fbb, 1);
         fbb.finish(points, None);
         let points = fbb.finished_data().to_vec();
-        let request = kv_service::WritePointsRpcRequest { version: 1, database, points };
+        let request =
+            kv_service::WritePointsRpcRequest { version: 1, database, points, request_id: 0 };
+
+        tskv.write(request).await.unwrap();
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_write_retried_request() {
+        let tskv = get_tskv().await;
+
+        let database = "db".to_string();
+        let mut fbb = flatbuffers::FlatBufferBuilder::new();
+        let points = models_helper::create_random_points(&mut fbb, 1);
+        fbb.finish(points, None);
+        let points = fbb.finished_data().to_vec();
+        // unique across the runs, the window is rebuilt from the wal of the former runs
+        let request_id = Local::now().timestamp_nanos() as u64;
+        let request =
+            kv_service::WritePointsRpcRequest { version: 1, database, points, request_id };
+        let sid = {
+            let fb_points = flatbuffers::root::<fb_models::Points>(&request.points).unwrap();
+            let point = fb_points.points().unwrap().get(0);
+            let mut info = SeriesInfo::from_flatbuffers(&point).unwrap();
+            info.finish();
+            info.series_id()
+        };
 
+        tskv.write(request.clone()).await.unwrap();
+        let cells = {
+            let mut version_set = tskv.version_set.write().await;
+            let tsf = version_set.get_tsfamily(sid).unwrap();
+            let cells = tsf.cache().read().await.summary().cells;
+            cells
+        };
         tskv.write(request).await.unwrap();
+        let mut version_set = tskv.version_set.write().await;
+        let tsf = version_set.get_tsfamily(sid).unwrap();
+        assert_eq!(tsf.cache().read().await.summary().cells, cells);
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_flush() {
+        let tskv = get_tskv().await;
+
+        let database = "db".to_string();
+        let mut fbb = flatbuffers::FlatBufferBuilder::new();
+        let points = models_helper::create_random_points(&mut fbb, 10);
+        fbb.finish(points, None);
+        let points = fbb.finished_data().to_vec();
+        let request =
+            kv_service::WritePointsRpcRequest { version: 1, database, points, request_id: 0 };
+        let (sid, field_id) = {
+            let fb_points = flatbuffers::root::<fb_models::Points>(&request.points).unwrap();
+            let point = fb_points.points().unwrap().get(0);
+            let mut info = SeriesInfo::from_flatbuffers(&point).unwrap();
+            info.finish();
+            (info.series_id(), info.field_infos()[0].field_id())
+        };
+        tskv.write(request).await.unwrap();
+
+        let all = TimeRange::new(i64::MAX, i64::MIN);
+        let cached = tskv.scan(sid, field_id, &all).await.unwrap();
+        assert!(!cached.is_empty());
+        tskv.flush().await.unwrap();
+        // read back from the column files
+        assert_eq!(tskv.scan(sid, field_id, &all).await.unwrap().len(), cached.len());
+        let version_set = tskv.version_set.read().await;
+        let tsf = version_set.get_tsfamily_immut(sid).unwrap();
+        assert_eq!(tsf.cache().read().await.summary().cells, 0);
+        assert!(tsf.im_cache().is_empty());
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_file_id_after_reopen() {
+        let tskv = get_tskv().await;
+        let database = "db".to_string();
+        let mut fbb = flatbuffers::FlatBufferBuilder::new();
+        let points = models_helper::create_random_points(&mut fbb, 10);
+        fbb.finish(points, None);
+        let points = fbb.finished_data().to_vec();
+        let request =
+            kv_service::WritePointsRpcRequest { version: 1, database, points, request_id: 0 };
+        tskv.write(request).await.unwrap();
+        tskv.flush().await.unwrap();
+        let next_file_id = tskv.global_ctx.file_id();
+        drop(tskv);
+
+        // the ids of the flushed files are not allocated again
+        let tskv = get_tskv().await;
+        assert!(tskv.global_ctx.next_file_id() >= next_file_id);
     }
 
     // remove repeat sid and fields_id
@@ -502,7 +1266,8 @@ mod test {
         let points = models_helper::create_random_points(&mut fbb, 10);
         fbb.finish(points, None);
         let points = fbb.finished_data().to_vec();
-        let request = kv_service::WritePointsRpcRequest { version: 1, database, points };
+        let request =
+            kv_service::WritePointsRpcRequest { version: 1, database, points, request_id: 0 };
 
         tskv.write(request.clone()).await.unwrap();
 
@@ -525,8 +1290,7 @@ mod test {
         sids = sids[0..l].to_owned();
         let l = remove_duplicates(&mut fields_id);
         fields_id = fields_id[0..l].to_owned();
-        tskv.read(sids, &TimeRange::new(Local::now().timestamp_millis() + 100, 0), fields_id).await;
-        Ok(())
+        tskv.read(sids, &TimeRange::new(Local::now().timestamp_millis() + 100, 0), fields_id).await
     }
 
     #[tokio::test]
@@ -539,7 +1303,8 @@ mod test {
         let points = models_helper::create_random_points(&mut fbb, 5);
         fbb.finish(points, None);
         let points = fbb.finished_data().to_vec();
-        let request = kv_service::WritePointsRpcRequest { version: 1, database, points };
+        let request =
+            kv_service::WritePointsRpcRequest { version: 1, database, points, request_id: 0 };
 
         tskv.write(request.clone()).await.unwrap();
 
@@ -565,13 +1330,13 @@ mod test {
         tskv.read(sids.clone(),
                   &TimeRange::new(Local::now().timestamp_millis() + 100, 0),
                   fields_id.clone())
-            .await;
+            .await?;
         info!("delete delta data");
         tskv.delete_series(sids.clone(), 1, 1).await.unwrap();
         tskv.read(sids.clone(),
                   &TimeRange::new(Local::now().timestamp_millis() + 100, 0),
                   fields_id.clone())
-            .await;
+            .await?;
         Ok(())
     }
 
@@ -587,7 +1352,8 @@ mod test {
         fbb.finish(points, None);
         let points = fbb.finished_data().to_vec();
 
-        let request = kv_service::WritePointsRpcRequest { version: 1, database, points };
+        let request =
+            kv_service::WritePointsRpcRequest { version: 1, database, points, request_id: 0 };
 
         tskv.write(request).await.unwrap();
     }
@@ -619,7 +1385,7 @@ mod test {
         let points = models_helper::create_random_points(&mut fbb, 1);
         fbb.finish(points, None);
         let points = fbb.finished_data().to_vec();
-        let req = kv_service::WritePointsRpcRequest { version: 1, database, points };
+        let req = kv_service::WritePointsRpcRequest { version: 1, database, points, request_id: 0 };
 
         wal_sender.send(Task::WritePoints { req, tx }).unwrap();
 
@@ -634,14 +1400,11 @@ mod test {
     #[tokio::test]
     #[serial]
     async fn test_add_del_tsf() {
-        let tskv = get_tskv().await;
-        let opt = crate::kv_option::Options { wal: WalConfig { dir: String::from("/tmp/test/wal"),
-                                                               ..Default::default() },
-                                              ..Default::default() };
-        let shared_options = Arc::new(opt);
-        let summary = Summary::new(&shared_options.db).await.unwrap();
-        summary.global_context().next_tsf_id();
-        let tf_id = summary.global_context().max_tsf_id();
+        let tmp = tempfile::tempdir().unwrap();
+        let tskv = open_tskv_in(&tmp.path().join("db")).await;
+        tskv.global_ctx.next_tsf_id();
+        let tf_id = tskv.global_ctx.max_tsf_id();
+        let tsf_num = tskv.version_set().read().await.tsf_num();
 
         tskv.version_set()
             .write()
@@ -650,21 +1413,454 @@ mod test {
                           "hello".to_string(),
                           0,
                           0,
-                          TseriesFamOpt::default(),
+                          TseriesFamOpt::for_testing(tmp.path()),
                           tskv.summary_task_sender.clone())
-            .await;
-        assert_eq!(tskv.version_set().read().await.tsf_num(),
-                   (GLOBAL_CONFIG.tsfamily_num + 1) as usize);
+            .await
+            .unwrap();
+        assert_eq!(tskv.version_set().read().await.tsf_num(), tsf_num + 1);
 
         tskv.version_set()
             .write()
             .await
             .del_tsfamily(tf_id, "hello".to_string(), tskv.summary_task_sender.clone());
-        assert_eq!(tskv.version_set().read().await.tsf_num(), GLOBAL_CONFIG.tsfamily_num as usize);
+        assert_eq!(tskv.version_set().read().await.tsf_num(), tsf_num);
 
         info!("success");
     }
 
+    // adds a tseries family with its files under the directory holding the points 1..=10 of
+    // field 1 in its caches
+    async fn add_tsf_with_points(tskv: &TsKv, dir: &Path, name: &str) -> u32 {
+        tskv.global_ctx.next_tsf_id();
+        let tf_id = tskv.global_ctx.max_tsf_id();
+        let mut version_set = tskv.version_set.write().await;
+        version_set.add_tsfamily(tf_id,
+                                 name.to_string(),
+                                 0,
+                                 0,
+                                 TseriesFamOpt::for_testing(dir),
+                                 tskv.summary_task_sender.clone())
+                   .await
+                   .unwrap();
+        let tsf = version_set.get_tsfamily_by_id(tf_id).unwrap();
+        for ts in 1..=10_i64 {
+            tsf.put_mutcache(1,
+                             ts.to_be_bytes().as_slice(),
+                             ValueType::Integer,
+                             1,
+                             ts,
+                             tskv.flush_task_sender.clone())
+               .await;
+        }
+        tf_id
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_drop_undrop_tsf() {
+        let tmp = tempfile::tempdir().unwrap();
+        let tskv = open_tskv_in(&tmp.path().join("db")).await;
+        let tf_id = add_tsf_with_points(&tskv, tmp.path(), "undrop").await;
+        let all = TimeRange::new(i64::MAX, i64::MIN);
+
+        tskv.drop_tsf(tf_id, true).await.unwrap();
+        let purge = {
+            let mut version_set = tskv.version_set.write().await;
+            assert!(version_set.get_tsfamily_by_id(tf_id).is_none());
+            version_set.trash().iter().find(|p| p.tsf_id == tf_id).unwrap().clone()
+        };
+        // the caches are flushed before the drop, the files are moved into the trash
+        assert!(!purge.files.is_empty());
+        assert!(!file_manager::try_exists(&purge.tsm_dir));
+        assert!(file_manager::try_exists(&purge.path));
+
+        tskv.undrop_tsf(tf_id).await.unwrap();
+        assert!(file_manager::try_exists(&purge.tsm_dir));
+        assert!(!file_manager::try_exists(&purge.path));
+        let mut version_set = tskv.version_set.write().await;
+        assert!(version_set.trash().iter().all(|p| p.tsf_id != tf_id));
+        let tsf = version_set.get_tsfamily_by_id(tf_id).unwrap();
+        assert_eq!(tsf.scan(1, &all).await.unwrap().len(), 10);
+        drop(version_set);
+
+        tskv.drop_tsf(tf_id, false).await.unwrap();
+        assert!(matches!(tskv.undrop_tsf(tf_id).await, Err(Error::TrashNotFound { .. })));
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_purge_trash() {
+        let tmp = tempfile::tempdir().unwrap();
+        let tskv = open_tskv_in(&tmp.path().join("db")).await;
+        let tf_id = add_tsf_with_points(&tskv, tmp.path(), "purge").await;
+
+        tskv.drop_tsf(tf_id, true).await.unwrap();
+        let purge = {
+            let version_set = tskv.version_set.read().await;
+            version_set.trash().iter().find(|p| p.tsf_id == tf_id).unwrap().clone()
+        };
+        let expire_at = purge.dropped_at + purge.ttl_secs;
+        assert!(!tskv.purge_trash(expire_at - 1).await.unwrap().contains(&tf_id));
+        assert!(file_manager::try_exists(&purge.path));
+
+        assert!(tskv.purge_trash(expire_at).await.unwrap().contains(&tf_id));
+        assert!(!file_manager::try_exists(&purge.path));
+        assert!(matches!(tskv.undrop_tsf(tf_id).await, Err(Error::TrashNotFound { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_put_points_unknown_series() {
+        let mut fbb = flatbuffers::FlatBufferBuilder::new();
+        let points = models_helper::create_random_points(&mut fbb, 4);
+        fbb.finish(points, None);
+        let points = flatbuffers::root::<fb_models::Points>(fbb.finished_data()).unwrap();
+        // no tseries family holds a series
+        let mut version_set = VersionSet::new(&[], HashMap::new(), vec![]).await;
+        let rejects = WriteRejects::default();
+        let (sender, _) = mpsc::channel(16);
+
+        let summary = put_points(&mut version_set, &points, 1, &rejects, sender).await;
+        // 2 fields a point
+        assert_eq!(summary.values, 0);
+        assert_eq!(summary.rejected, RejectCounts { unknown_series: 8, ..Default::default() });
+        assert_eq!(summary.rejected.total(), 8);
+        assert_eq!(rejects.counts(), summary.rejected);
+    }
+
+    // the options of a store whose summary, wal and forward index are under the directory
+    fn options_in(dir: &Path) -> Options {
+        let path = |name: &str| dir.join(name).to_string_lossy().to_string();
+        let fidx = ForwardIndexConfig { path: dir.join("tskv.fidx") };
+        Options { db: DBOptions { db_path: path("db"), ..Default::default() },
+                  wal: WalConfig { dir: path("wal"), ..Default::default() },
+                  forward_index_conf: fidx,
+                  ..Default::default() }
+    }
+
+    async fn open_tskv_in(dir: &Path) -> TsKv {
+        TsKv::open(options_in(dir)).await.unwrap()
+    }
+
+    // adds a tseries family with its files under the directory holding 10 points of field 1,
+    // flushes it and waits for its file, returns the points read back
+    async fn write_and_flush(tskv: &TsKv, dir: &Path, tf_id: u32) -> usize {
+        {
+            let mut version_set = tskv.version_set.write().await;
+            version_set.add_tsfamily(tf_id,
+                                     format!("flush_queue_{}", tf_id),
+                                     0,
+                                     0,
+                                     TseriesFamOpt::for_testing(dir),
+                                     tskv.summary_task_sender.clone())
+                       .await
+                       .unwrap();
+            let tsf = version_set.get_tsfamily_by_id(tf_id).unwrap();
+            for ts in 1..=10_i64 {
+                tsf.put_mutcache(1,
+                                 ts.to_be_bytes().as_slice(),
+                                 ValueType::Integer,
+                                 1,
+                                 ts,
+                                 tskv.flush_task_sender.clone())
+                   .await;
+            }
+            tsf.flush_field(1, tskv.flush_task_sender.clone()).await;
+        }
+        let all = TimeRange::new(i64::MAX, i64::MIN);
+        for _ in 0..100 {
+            let version_set = tskv.version_set.read().await;
+            let tsf = version_set.tsfamilies().find(|tsf| tsf.tf_id() == tf_id).unwrap();
+            let files: usize =
+                tsf.version().read().await.levels_info().iter().map(|l| l.files.len()).sum();
+            if files > 0 {
+                return tsf.scan(1, &all).await.unwrap().len();
+            }
+            drop(version_set);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        panic!("tseries family {} is not flushed", tf_id);
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_independent_flush_queues() {
+        let tmp = tempfile::tempdir().unwrap();
+        let (dir_a, dir_b) = (tmp.path().join("a"), tmp.path().join("b"));
+        let (a, b) = tokio::join!(open_tskv_in(&dir_a), open_tskv_in(&dir_b));
+
+        // a request queued by a store is not seen by the other one
+        a.flush_queue.push(FlushReq::new(vec![], 0));
+        assert!(!a.flush_queue.is_empty());
+        assert!(b.flush_queue.is_empty());
+        a.flush_queue.take();
+
+        // both stores flush at the same time, each by its own flush job
+        let (flushed_a, flushed_b) =
+            tokio::join!(write_and_flush(&a, &dir_a, 131), write_and_flush(&b, &dir_b, 132));
+        assert_eq!((flushed_a, flushed_b), (10, 10));
+        for (tskv, tf_id) in [(&a, 131), (&b, 132)] {
+            assert!(tskv.flush_queue.is_empty());
+            let dumps = tskv.debug_dump_all();
+            let dump = dumps.available().unwrap().iter().find(|d| d.tf_id == tf_id).unwrap();
+            assert_eq!(dump.pending_flushes, DumpField::Available(0));
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    #[serial]
+    async fn test_concurrent_rewrites() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().join("db");
+        let tskv = open_tskv_in(&dir).await;
+        let tf_id = 138;
+        {
+            let opt = TseriesFamOpt { ooo_tolerance_ns: 0, ..TseriesFamOpt::for_testing(&dir) };
+            let mut version_set = tskv.version_set.write().await;
+            version_set.add_tsfamily(tf_id,
+                                     "concurrent_rewrites".to_string(),
+                                     0,
+                                     0,
+                                     opt,
+                                     tskv.summary_task_sender.clone())
+                       .await
+                       .unwrap();
+        }
+        let rounds = 30_i64;
+        let all = TimeRange::new(10, 1);
+        let done = Arc::new(AtomicBool::new(false));
+
+        // every round rewrites the points 1 to 10 with its number, once the caches are flushed
+        // they go to the delta cache, and writes the next point in order, which flushes the
+        // delta cache; every other round flushes the other caches too
+        let writer = {
+            let (version_set, sender, done) =
+                (tskv.version_set.clone(), tskv.flush_task_sender.clone(), done.clone());
+            tokio::spawn(async move {
+                let mut seq = 0;
+                for round in 1..=rounds {
+                    let mut version_set = version_set.write().await;
+                    let tsf = version_set.get_tsfamily_by_id(tf_id).unwrap();
+                    for ts in (1..=10).chain([10 + round]) {
+                        seq += 1;
+                        tsf.put_mutcache(1,
+                                         &round.to_be_bytes(),
+                                         ValueType::Integer,
+                                         seq,
+                                         ts,
+                                         sender.clone())
+                           .await;
+                    }
+                    let req = match round % 2 {
+                        0 => tsf.take_flush_req().await,
+                        _ => None,
+                    };
+                    drop(version_set);
+                    if let Some(req) = req {
+                        sender.send(req).await.unwrap();
+                    }
+                    tokio::task::yield_now().await;
+                }
+                done.store(true, Ordering::SeqCst);
+            })
+        };
+        // a point read is never older than the one read before it
+        let reader = {
+            let (version_set, done) = (tskv.version_set.clone(), done.clone());
+            tokio::spawn(async move {
+                let mut seen = HashMap::new();
+                let mut scans = 0;
+                while !done.load(Ordering::SeqCst) {
+                    let version_set = version_set.read().await;
+                    let tsf = version_set.tsfamilies().find(|tsf| tsf.tf_id() == tf_id).unwrap();
+                    for data in tsf.scan(1, &all).await.unwrap() {
+                        let (ts, round) = match data {
+                            DataType::I64(c) => (c.ts, c.val),
+                            _ => panic!("unexpected data type"),
+                        };
+                        let last = seen.insert(ts, round).unwrap_or(0);
+                        assert!(round >= last, "point {} went back to round {}", ts, round);
+                    }
+                    drop(version_set);
+                    scans += 1;
+                    tokio::task::yield_now().await;
+                }
+                scans
+            })
+        };
+        writer.await.unwrap();
+        assert!(reader.await.unwrap() > 0);
+
+        // the last round is read from the caches and the flushed files
+        let mut files = 0;
+        for _ in 0..100 {
+            let version_set = tskv.version_set.read().await;
+            let tsf = version_set.tsfamilies().find(|tsf| tsf.tf_id() == tf_id).unwrap();
+            files = tsf.version().read().await.levels_info().iter().map(|l| l.files.len()).sum();
+            if files > 0 {
+                break;
+            }
+            drop(version_set);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        assert!(files > 0);
+        let version_set = tskv.version_set.read().await;
+        let tsf = version_set.tsfamilies().find(|tsf| tsf.tf_id() == tf_id).unwrap();
+        let data: Vec<(i64, i64)> = tsf.scan(1, &all)
+                                       .await
+                                       .unwrap()
+                                       .into_iter()
+                                       .map(|d| match d {
+                                           DataType::I64(c) => (c.ts, c.val),
+                                           _ => panic!("unexpected data type"),
+                                       })
+                                       .collect();
+        assert_eq!(data, (1..=10).map(|ts| (ts, rounds)).collect::<Vec<_>>());
+    }
+
+    // adds a tseries family with the options, and flushes a file at level 1 for each range of
+    // points of field 1, from the oldest range
+    async fn add_tsf_with_files(tskv: &TsKv,
+                                tf_id: u32,
+                                opt: TseriesFamOpt,
+                                ranges: Vec<RangeInclusive<i64>>) {
+        tskv.version_set
+            .write()
+            .await
+            .add_tsfamily(tf_id,
+                          format!("files_{}", tf_id),
+                          0,
+                          0,
+                          opt,
+                          tskv.summary_task_sender.clone())
+            .await
+            .unwrap();
+        for range in ranges {
+            {
+                let mut version_set = tskv.version_set.write().await;
+                let tsf = version_set.get_tsfamily_by_id(tf_id).unwrap();
+                for ts in range {
+                    tsf.put_mutcache(1,
+                                     ts.to_be_bytes().as_slice(),
+                                     ValueType::Integer,
+                                     1,
+                                     ts,
+                                     tskv.flush_task_sender.clone())
+                       .await;
+                }
+            }
+            tskv.flush().await.unwrap();
+        }
+    }
+
+    // the levels of the tseries family holding live files, with their file counts
+    async fn live_levels(tskv: &TsKv, tf_id: u32) -> Vec<(u32, usize)> {
+        let version_set = tskv.version_set.read().await;
+        let tsf = version_set.tsfamilies().find(|tsf| tsf.tf_id() == tf_id).unwrap();
+        let levels = tsf.version().read().await.summary().0;
+        let mut levels: Vec<(u32, usize)> = levels.iter().map(|l| (l.level, l.files)).collect();
+        levels.sort_unstable();
+        levels.retain(|(_, files)| *files > 0);
+        levels
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_compact_through_store() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().join("db");
+        let tskv = open_tskv_in(&dir).await;
+        let tf_id = 133;
+        // the points before 16 are dropped by the compactions
+        let filter = CompactionFilterRef(Arc::new(RetentionFilter::new(16)));
+        let opt = TseriesFamOpt { max_files_per_level: 2,
+                                  small_file_threshold: 0,
+                                  compaction_filter: Some(filter),
+                                  ..TseriesFamOpt::for_testing(tmp.path()) };
+        // the larger files hold the newer points
+        add_tsf_with_files(&tskv, tf_id, opt, vec![1..=4, 11..=20, 21..=60]).await;
+
+        // the two smallest files are merged into level 2 through the filter
+        let report = tskv.compact().await.unwrap().unwrap();
+        assert_eq!((report.files_in, report.files_out), (2, 1));
+        assert_eq!((report.cells_in, report.cells_out), (14, 5));
+        assert!(tskv.compact().await.unwrap().is_none());
+        assert_eq!(tskv.compaction_totals().compactions, 1);
+        assert_eq!(live_levels(&tskv, tf_id).await, vec![(1, 1), (2, 1)]);
+        {
+            let version_set = tskv.version_set.read().await;
+            let tsf = version_set.tsfamilies().find(|tsf| tsf.tf_id() == tf_id).unwrap();
+            let all = TimeRange::new(i64::MAX, i64::MIN);
+            let points: Vec<i64> =
+                tsf.scan(1, &all).await.unwrap().iter().map(|d| d.timestamp()).collect();
+            assert_eq!(points, (16..=60).collect::<Vec<_>>());
+        }
+
+        // the edit is in the summary file
+        let dumps = TsKv::debug_dump_dir(&options_in(&dir)).await.unwrap();
+        let dump = dumps.iter().find(|d| d.tf_id == tf_id).unwrap();
+        let version = dump.version.available().unwrap();
+        let mut levels: Vec<(u32, usize)> =
+            version.levels.iter().map(|l| (l.level, l.files.len())).collect();
+        levels.sort_unstable();
+        levels.retain(|(_, files)| *files > 0);
+        assert_eq!(levels, vec![(1, 1), (2, 1)]);
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_compaction_policy() {
+        // the first family needs more compactions than the second one
+        let ranges = |files: i64| (0..files).map(|i| i * 10..=i * 10 + 9).collect::<Vec<_>>();
+        for (policy, expected) in
+            [(CompactionPolicy::Throughput, (2, 0)), (CompactionPolicy::Fair, (1, 1))]
+        {
+            let tmp = tempfile::tempdir().unwrap();
+            let dir = tmp.path().join("db");
+            let compact_conf = CompactConfig { policy, ..Default::default() };
+            let tskv = TsKv::open(Options { compact_conf, ..options_in(&dir) }).await.unwrap();
+            let opt = TseriesFamOpt { max_files_per_level: 2,
+                                      max_compact_files: 2,
+                                      small_file_threshold: 0,
+                                      ..TseriesFamOpt::for_testing(tmp.path()) };
+            add_tsf_with_files(&tskv, 134, opt.clone(), ranges(6)).await;
+            add_tsf_with_files(&tskv, 135, opt, ranges(3)).await;
+
+            // every compaction writes a file at level 2
+            for _ in 0..2 {
+                assert!(tskv.compact().await.unwrap().is_some());
+            }
+            let level_2 = |levels: Vec<(u32, usize)>| {
+                levels.iter().find(|(level, _)| *level == 2).map_or(0, |(_, files)| *files)
+            };
+            let compacted =
+                (level_2(live_levels(&tskv, 134).await), level_2(live_levels(&tskv, 135).await));
+            assert_eq!(compacted, expected, "{:?}", policy);
+        }
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_compaction_window() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().join("db");
+        let compact_conf = CompactConfig { window: "2am-5am".to_string(), ..Default::default() };
+        let opened = TsKv::open(Options { compact_conf, ..options_in(&dir) }).await;
+        assert!(matches!(opened, Err(Error::InvalidCompactionWindow { .. })));
+
+        // the window opens in two hours, the family is over its file count but not urgent
+        let now = Local::now().time();
+        let at = |hours| (now + chrono::Duration::hours(hours)).format("%H:%M").to_string();
+        let window = format!("{}-{} local", at(2), at(3));
+        let compact_conf = CompactConfig { window, urgent_score: 10.0, ..Default::default() };
+        let tskv = TsKv::open(Options { compact_conf, ..options_in(&dir) }).await.unwrap();
+        let opt = TseriesFamOpt { max_files_per_level: 2,
+                                  small_file_threshold: 0,
+                                  ..TseriesFamOpt::for_testing(tmp.path()) };
+        add_tsf_with_files(&tskv, 136, opt, vec![1..=10, 11..=20, 21..=30]).await;
+        assert!(tskv.compact().await.unwrap().is_none());
+        assert_eq!(live_levels(&tskv, 136).await, vec![(1, 3)]);
+    }
+
     #[tokio::test]
     #[serial]
     async fn test_log() {
@@ -0,0 +1,197 @@
+use std::collections::HashMap;
+
+use models::{FieldId, Timestamp, ValueType};
+use parking_lot::Mutex;
+
+use crate::{
+    lru_cache::CacheStats,
+    memcache::{decode_cell, DataType},
+    tseries_family::TimeRange,
+};
+
+/// The latest point of the fields of a tseries family, for the alerts and the dashboards asking
+/// for the current value of a field over and over. A field is filled by its first lookup from a
+/// scan, then kept up to date by the writes into it; a delete covering its point drops it.
+///
+/// Nothing is persisted, the fields are filled again after a restart. A field is kept only if
+/// nothing is written or dropped while its scan reads, see `epoch`.
+pub struct LastValueCache {
+    // None if the capacity is 0
+    inner: Option<Mutex<Fields>>,
+}
+
+struct Fields {
+    // the fields looked up, None if the field has no point
+    fields: HashMap<FieldId, Option<DataType>>,
+    cap: usize,
+    // bumped by every write and drop
+    epoch: u64,
+    stats: CacheStats,
+}
+
+impl LastValueCache {
+    /// A cache of at most `capacity` fields, disabled if `capacity` is 0.
+    pub fn new(capacity: usize) -> Self {
+        let fields = Fields { fields: HashMap::new(),
+                              cap: capacity,
+                              epoch: 0,
+                              stats: CacheStats::default() };
+        Self { inner: (capacity > 0).then(|| Mutex::new(fields)) }
+    }
+
+    /// Returns the latest point of the field if it is kept, Some(None) if the field is known to
+    /// have no point.
+    pub fn get(&self, field_id: FieldId) -> Option<Option<DataType>> {
+        let mut fields = self.inner.as_ref()?.lock();
+        let data = fields.fields.get(&field_id).cloned();
+        match data {
+            Some(_) => fields.stats.hits += 1,
+            None => fields.stats.misses += 1,
+        }
+        data
+    }
+
+    /// Returns the count of the writes and the drops, taken before a scan reads and handed to
+    /// `insert`.
+    pub fn epoch(&self) -> u64 {
+        self.inner.as_ref().map(|fields| fields.lock().epoch).unwrap_or(0)
+    }
+
+    /// Keeps the latest point of the field found by a scan, unless a write or a drop happened
+    /// since `epoch` was taken as the scan may miss it then. Any field makes room if the cache
+    /// is full.
+    pub fn insert(&self, field_id: FieldId, data: Option<DataType>, epoch: u64) {
+        let mut fields = match self.inner.as_ref() {
+            Some(fields) => fields.lock(),
+            None => return,
+        };
+        if fields.epoch != epoch {
+            fields.stats.rejected += 1;
+            return;
+        }
+        if !fields.fields.contains_key(&field_id) && fields.fields.len() >= fields.cap {
+            if let Some(evicted) = fields.fields.keys().next().copied() {
+                fields.fields.remove(&evicted);
+                fields.stats.evicted += 1;
+            }
+        }
+        fields.fields.insert(field_id, data);
+        fields.stats.admitted += 1;
+    }
+
+    /// Updates the field with a point written into it, the point is kept if its timestamp is
+    /// not older than the kept one, so a late point never replaces a newer one. The value is
+    /// decoded from `buf` only if the field is kept.
+    pub fn update(&self, field_id: FieldId, ts: Timestamp, value_type: ValueType, buf: &[u8]) {
+        if let Some(fields) = self.inner.as_ref() {
+            let mut fields = fields.lock();
+            fields.epoch += 1;
+            if let Some(last) = fields.fields.get_mut(&field_id) {
+                if last.as_ref().map_or(true, |d| ts >= d.timestamp()) {
+                    *last = Some(decode_cell(ts, value_type, buf));
+                }
+            }
+        }
+    }
+
+    /// Drops the field if its kept point is in the time range, on a delete.
+    pub fn delete(&self, field_id: FieldId, time_range: &TimeRange) {
+        if let Some(fields) = self.inner.as_ref() {
+            let mut fields = fields.lock();
+            fields.epoch += 1;
+            let covered = match fields.fields.get(&field_id) {
+                Some(Some(d)) => time_range.contains(d.timestamp()),
+                _ => false,
+            };
+            if covered {
+                fields.fields.remove(&field_id);
+            }
+        }
+    }
+
+    /// Drops every field whose kept point is in the time range, on a delete of every field.
+    pub fn delete_all(&self, time_range: &TimeRange) {
+        if let Some(fields) = self.inner.as_ref() {
+            let mut fields = fields.lock();
+            fields.epoch += 1;
+            fields.fields
+                  .retain(|_, d| !matches!(d, Some(d) if time_range.contains(d.timestamp())));
+        }
+    }
+
+    /// Drops every field, on a new version that may have changed the points.
+    pub fn clear(&self) {
+        if let Some(fields) = self.inner.as_ref() {
+            let mut fields = fields.lock();
+            fields.epoch += 1;
+            fields.fields.clear();
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.inner.as_ref().map(|fields| fields.lock().stats).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use models::ValueType;
+
+    use super::LastValueCache;
+    use crate::{memcache::DataType, tseries_family::TimeRange, DataCell};
+
+    fn ts(data: Option<Option<DataType>>) -> Option<Option<i64>> {
+        data.map(|d| d.map(|d| d.timestamp()))
+    }
+
+    #[test]
+    fn test_last_value_cache() {
+        let cache = LastValueCache::new(2);
+        let point = |ts| Some(DataType::I64(DataCell { ts, val: 1 }));
+        assert!(cache.get(1).is_none());
+
+        let epoch = cache.epoch();
+        cache.insert(1, point(5), epoch);
+        // a late point does not replace the newer one, a point of the same timestamp does
+        cache.update(1, 3, ValueType::Integer, &2_i64.to_be_bytes());
+        assert_eq!(ts(cache.get(1)), Some(Some(5)));
+        cache.update(1, 5, ValueType::Integer, &2_i64.to_be_bytes());
+        assert!(matches!(cache.get(1), Some(Some(DataType::I64(c))) if c.val == 2));
+        // a field not kept is not filled by a write
+        cache.update(2, 1, ValueType::Integer, &2_i64.to_be_bytes());
+        assert!(cache.get(2).is_none());
+
+        // a scan reading while the field is written is not kept
+        let epoch = cache.epoch();
+        cache.update(2, 1, ValueType::Integer, &2_i64.to_be_bytes());
+        cache.insert(2, None, epoch);
+        assert!(cache.get(2).is_none());
+        let epoch = cache.epoch();
+        cache.insert(2, None, epoch);
+        cache.update(2, 7, ValueType::Integer, &2_i64.to_be_bytes());
+        assert_eq!(ts(cache.get(2)), Some(Some(7)));
+
+        // a delete drops the field only if it covers the kept point
+        cache.delete(1, &TimeRange::new(4, 1));
+        assert_eq!(ts(cache.get(1)), Some(Some(5)));
+        cache.delete(1, &TimeRange::new(5, 5));
+        assert!(cache.get(1).is_none());
+        cache.delete_all(&TimeRange::new(7, 6));
+        assert!(cache.get(2).is_none());
+
+        // a field makes room
+        let epoch = cache.epoch();
+        for field_id in 1..=3 {
+            cache.insert(field_id, point(1), epoch);
+        }
+        assert_eq!((1..=3).filter(|f| cache.get(*f).is_some()).count(), 2);
+        cache.clear();
+        assert!((1..=3).all(|f| cache.get(f).is_none()));
+        let stats = cache.stats();
+        assert_eq!((stats.admitted, stats.rejected, stats.evicted), (5, 1, 1));
+
+        let disabled = LastValueCache::new(0);
+        disabled.insert(1, point(1), disabled.epoch());
+        assert!(disabled.get(1).is_none());
+    }
+}
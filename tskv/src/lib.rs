@@ -3,34 +3,199 @@
 #![allow(unreachable_patterns)]
 #![allow(unused_imports, unused_variables)]
 
+#[cfg(test)]
+mod alloc_counter;
 mod byte_utils;
+mod cleaner;
+mod clock;
 mod compaction;
 mod context;
+mod debug_dump;
 mod direct_io;
 mod error;
+mod features;
 mod file_manager;
 mod file_utils;
 mod forward_index;
 pub mod kv_option;
 mod kvcore;
+mod last_value;
+pub mod line_protocol;
 mod lru_cache;
 mod memcache;
+mod merge;
+mod migrate;
 mod reader;
 mod record_file;
+mod request_window;
+mod restore;
+mod result_cache;
 mod runtime;
 pub mod schema;
+mod scrub;
+#[cfg(feature = "skiplist")]
+mod skiplist_cache;
+mod snapshot;
 mod summary;
+#[cfg(feature = "datafusion")]
+mod table_provider;
+mod trash;
 mod tseries_family;
 mod tsm;
 mod version_set;
 mod wal;
+mod write_stats;
 
+pub use clock::{Clock, MockClock, SystemClock};
+pub use compaction::{CompactionReport, CompactionTotals, CompactionWindow};
+pub use debug_dump::{DumpField, TsfDebugDump};
 pub use error::{Error, Result};
 pub use kv_option::Options;
 pub use kvcore::TsKv;
+pub use lru_cache::CacheStats;
+pub use memcache::{CacheSummary, DataCell, DataType, FieldHints, MemCache, MemCacheTrait};
+pub use merge::MergeStream;
+pub use migrate::{MigrateOutcome, MigrateReport};
 use protos::kv_service::WritePointsRpcResponse;
+pub use scrub::{CorruptFile, MismatchedFile, RangeMismatch, ScrubReport, ScrubStats};
+#[cfg(feature = "skiplist")]
+pub use skiplist_cache::SkipListCache;
+pub use snapshot::{ExportManifest, ManifestEntry};
+#[cfg(feature = "datafusion")]
+pub use table_provider::{block_to_array, TskvTableProvider, TIME_COLUMN};
 use tokio::sync::oneshot;
+pub use tseries_family::TimeRange;
+pub use tsm::{
+    rewrite, CompressionStats, DataBlock, NumericValue, TombstoneBuilder, TombstoneIndex,
+    TombstoneSet, TypeCompression, Validity,
+};
 use utils::BloomFilter;
+pub use wal::RecoverTarget;
+pub use write_stats::{RejectCounts, WriteRejectReason, WriteSummary};
+
+/// The internals used by the benchmarks.
+#[cfg(feature = "bench")]
+pub mod bench {
+    use std::{collections::HashMap, path::PathBuf};
+
+    use models::FieldId;
+
+    use crate::{
+        compaction, kv_option::DuplicatePolicy, DataBlock, MemCacheTrait, Result, TimeRange,
+    };
+
+    /// Writes the blocks into a new tsm file, returns the file size.
+    pub fn write_blocks(path: PathBuf, block_set: HashMap<FieldId, DataBlock>) -> Result<u64> {
+        compaction::build_tsm_file(path, block_set)
+    }
+    pub use crate::{
+        file_utils::make_tsm_file_name,
+        memcache::{new_memcache, MemCacheRef},
+        summary::CompactMeta,
+        tseries_family::{LevelInfo, TseriesFamily, Version},
+        tsm::{boolean, float, integer, string, timestamp, unsigned},
+    };
+
+    /// Writes the cache into a new tsm file like a flush, returns the file size.
+    pub fn flush_cache(cache: &dyn MemCacheTrait, path: PathBuf) -> Result<u64> {
+        let mut field_size = HashMap::new();
+        let mut field_map = HashMap::new();
+        for (field_id, entry) in cache.iter_entries() {
+            field_size.insert(field_id, entry.cells.len());
+            field_map.insert(field_id, vec![entry]);
+        }
+        let block_set = compaction::build_block_set(field_size,
+                                                    field_map,
+                                                    &mut TimeRange::none(),
+                                                    DuplicatePolicy::default());
+        compaction::build_tsm_file(path, block_set)
+    }
+}
+
+/// The entries of the fuzz targets, any input must be rejected with an error rather than a
+/// panic or an allocation larger than the input allows.
+#[cfg(feature = "fuzz")]
+pub mod fuzz {
+    use std::path::PathBuf;
+
+    use crate::{
+        direct_io::FileSync,
+        file_manager::get_file_manager,
+        tsm::{
+            boolean, float, integer, string, timestamp, unsigned, BlockReader, TsmBlockReader,
+            TsmFooterReader, TsmIndexReader, MAX_DECODE_VALUES,
+        },
+    };
+
+    pub fn decode_boolean(data: &[u8]) {
+        let mut dst = vec![];
+        if boolean::decode(data, &mut dst).is_ok() {
+            assert!(dst.len() <= MAX_DECODE_VALUES);
+        }
+    }
+
+    pub fn decode_float(data: &[u8]) {
+        let mut dst = vec![];
+        if float::decode(data, &mut dst).is_ok() {
+            assert!(dst.len() <= MAX_DECODE_VALUES);
+        }
+    }
+
+    pub fn decode_integer(data: &[u8]) {
+        let mut dst = vec![];
+        if integer::decode(data, &mut dst).is_ok() {
+            assert!(dst.len() <= MAX_DECODE_VALUES);
+        }
+    }
+
+    pub fn decode_string(data: &[u8]) {
+        let mut dst = vec![];
+        if string::decode(data, &mut dst).is_ok() {
+            assert!(dst.len() <= MAX_DECODE_VALUES);
+        }
+    }
+
+    pub fn decode_timestamp(data: &[u8]) {
+        let mut dst = vec![];
+        if timestamp::decode(data, &mut dst).is_ok() {
+            assert!(dst.len() <= MAX_DECODE_VALUES);
+        }
+    }
+
+    pub fn decode_unsigned(data: &[u8]) {
+        let mut dst = vec![];
+        if unsigned::decode(data, &mut dst).is_ok() {
+            assert!(dst.len() <= MAX_DECODE_VALUES);
+        }
+    }
+
+    /// Reads the data as a whole tsm file: the footer, the index and every block in it.
+    pub fn read_tsm_file(data: &[u8]) {
+        let path = std::env::temp_dir().join(format!("tskv_fuzz_{}.tsm", std::process::id()));
+        let fs = get_file_manager();
+        let mut cursor = fs.create_file(&path).unwrap().into_cursor();
+        cursor.write(data).unwrap();
+        cursor.sync_all(FileSync::Hard).unwrap();
+
+        let len = data.len();
+        let _ = TsmFooterReader::read_bloom_filter(&mut cursor, len);
+        let _ = TsmFooterReader::read_field_ids(&mut cursor, len);
+        let _ = TsmFooterReader::read_features(&mut cursor, len);
+        let mut blocks = vec![];
+        if let Ok(index) = TsmIndexReader::try_new(&mut cursor, len) {
+            for entry in index {
+                match entry {
+                    Ok(entry) => blocks.push(entry.block),
+                    Err(_) => break,
+                }
+            }
+        }
+        let mut reader = TsmBlockReader::new(&mut cursor);
+        for block in blocks {
+            let _ = reader.decode(&block);
+        }
+    }
+}
 
 /// Returns a 64 bytes bloom filter
 #[inline(always)]
@@ -0,0 +1,365 @@
+use std::collections::BTreeMap;
+
+use models::{FieldId, Timestamp};
+
+use crate::{
+    error::{Error, Result},
+    memcache::{DataCell, DataType},
+    tseries_family::{TimeRange, TseriesFamily},
+};
+
+/// The value of a field of a line, its type is told by its suffix: `1.5` a float, `1i` an
+/// integer, `1u` an unsigned, `t` or `false` a boolean and `"text"` a string.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FieldValue {
+    Float(f64),
+    Integer(i64),
+    Unsigned(u64),
+    Boolean(bool),
+    String(String),
+}
+
+impl FieldValue {
+    /// Returns the cell of the value at the timestamp.
+    pub fn into_cell(self, ts: Timestamp) -> DataType {
+        match self {
+            FieldValue::Float(val) => DataType::F64(DataCell { ts, val }),
+            FieldValue::Integer(val) => DataType::I64(DataCell { ts, val }),
+            FieldValue::Unsigned(val) => DataType::U64(DataCell { ts, val }),
+            FieldValue::Boolean(val) => DataType::Bool(DataCell { ts, val }),
+            FieldValue::String(val) => DataType::Str(DataCell { ts, val: val.into_bytes() }),
+        }
+    }
+}
+
+impl From<&DataType> for FieldValue {
+    // a string not valid UTF-8 is written with replacement characters
+    fn from(data: &DataType) -> Self {
+        match data {
+            DataType::F64(cell) => FieldValue::Float(cell.val),
+            DataType::I64(cell) => FieldValue::Integer(cell.val),
+            DataType::U64(cell) => FieldValue::Unsigned(cell.val),
+            DataType::Bool(cell) => FieldValue::Boolean(cell.val),
+            DataType::Str(cell) => {
+                FieldValue::String(String::from_utf8_lossy(&cell.val).into_owned())
+            },
+        }
+    }
+}
+
+/// A line of InfluxDB line protocol, for moving points between the store and the InfluxDB
+/// tooling, like `cpu,host=server\ 1 usage=0.5,cores=8i,name="main \"a\"" 1000`. The commas,
+/// equal signs and spaces of the measurement, the tags and the field keys are escaped with a
+/// backslash, and so are the double quotes and backslashes of a string value. The timestamp is
+/// in nanoseconds.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Line {
+    pub measurement: String,
+    pub tags: Vec<(String, String)>,
+    pub fields: Vec<(String, FieldValue)>,
+    pub timestamp: Option<Timestamp>,
+}
+
+/// Parses a line into the points of its fields, `field_id` gives the id of a field from the
+/// line and the name of the field. A line without a timestamp takes `default_ts`; an empty
+/// line or a comment starting with `#` has no points.
+pub fn parse(line: &str,
+             default_ts: Timestamp,
+             mut field_id: impl FnMut(&Line, &str) -> FieldId)
+             -> Result<Vec<(FieldId, DataType)>> {
+    let trimmed = line.trim();
+    if trimmed.is_empty() || trimmed.starts_with('#') {
+        return Ok(vec![]);
+    }
+    let parsed = parse_line(trimmed)?;
+    let ts = parsed.timestamp.unwrap_or(default_ts);
+    let mut res = Vec::with_capacity(parsed.fields.len());
+    for (name, value) in parsed.fields.iter() {
+        res.push((field_id(&parsed, name), value.clone().into_cell(ts)));
+    }
+    Ok(res)
+}
+
+/// Parses a line into its measurement, tags, fields and timestamp.
+pub fn parse_line(line: &str) -> Result<Line> {
+    let invalid = |reason: &str| Error::InvalidLineProtocol { line: line.to_string(),
+                                                              reason: reason.to_string() };
+    let sections = split_unescaped(line.trim(), ' ', true);
+    if sections.len() < 2 || sections.len() > 3 {
+        return Err(invalid("expected a series, fields and an optional timestamp"));
+    }
+
+    let mut series = split_unescaped(sections[0], ',', false).into_iter();
+    let measurement = unescape(series.next().unwrap_or_default());
+    if measurement.is_empty() {
+        return Err(invalid("empty measurement"));
+    }
+    let mut tags = vec![];
+    for tag in series {
+        let (key, value) = split_pair(tag).ok_or_else(|| invalid("tag without a value"))?;
+        if key.is_empty() || value.is_empty() {
+            return Err(invalid("empty tag key or value"));
+        }
+        tags.push((unescape(key), unescape(value)));
+    }
+
+    let mut fields = vec![];
+    for field in split_unescaped(sections[1], ',', true) {
+        let (key, value) = split_pair(field).ok_or_else(|| invalid("field without a value"))?;
+        if key.is_empty() {
+            return Err(invalid("empty field key"));
+        }
+        let value = parse_value(value).ok_or_else(|| invalid("invalid field value"))?;
+        fields.push((unescape(key), value));
+    }
+
+    let timestamp = match sections.get(2) {
+        Some(ts) => Some(ts.parse().map_err(|_| invalid("invalid timestamp"))?),
+        None => None,
+    };
+    Ok(Line { measurement, tags, fields, timestamp })
+}
+
+/// Writes the line as line protocol, without a newline.
+pub fn format_line(line: &Line) -> String {
+    let mut res = escape(&line.measurement, &[',', ' ']);
+    for (key, value) in line.tags.iter() {
+        res.push(',');
+        res.push_str(&escape(key, &[',', '=', ' ']));
+        res.push('=');
+        res.push_str(&escape(value, &[',', '=', ' ']));
+    }
+    for (i, (key, value)) in line.fields.iter().enumerate() {
+        res.push(if i == 0 { ' ' } else { ',' });
+        res.push_str(&escape(key, &[',', '=', ' ']));
+        res.push('=');
+        res.push_str(&format_value(value));
+    }
+    if let Some(ts) = line.timestamp {
+        res.push(' ');
+        res.push_str(&ts.to_string());
+    }
+    res
+}
+
+/// Writes the points of the fields of a series as lines ordered by timestamp, one line for
+/// every timestamp with the values of all the fields at it.
+pub fn format_points(measurement: &str,
+                     tags: &[(String, String)],
+                     fields: &[(String, Vec<DataType>)])
+                     -> String {
+    let mut lines: BTreeMap<Timestamp, Vec<(String, FieldValue)>> = BTreeMap::new();
+    for (name, data) in fields.iter() {
+        for datum in data.iter() {
+            lines.entry(datum.timestamp()).or_default().push((name.clone(), datum.into()));
+        }
+    }
+    let mut res = String::new();
+    for (ts, fields) in lines {
+        let line = Line { measurement: measurement.to_string(),
+                          tags: tags.to_vec(),
+                          fields,
+                          timestamp: Some(ts) };
+        res.push_str(&format_line(&line));
+        res.push('\n');
+    }
+    res
+}
+
+/// Scans the fields of a series in the time range and writes their points as lines like
+/// `format_points`, the fields are given by their names and ids. Fails if a file could not be
+/// read, rather than exporting the points of the other files only.
+pub async fn export_scan(tsf: &TseriesFamily,
+                         measurement: &str,
+                         tags: &[(String, String)],
+                         fields: &[(String, FieldId)],
+                         time_range: &TimeRange)
+                         -> Result<String> {
+    let mut data = Vec::with_capacity(fields.len());
+    for (name, field_id) in fields.iter() {
+        data.push((name.clone(), tsf.scan(*field_id, time_range).await?));
+    }
+    Ok(format_points(measurement, tags, &data))
+}
+
+fn parse_value(value: &str) -> Option<FieldValue> {
+    if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+        return Some(FieldValue::String(unescape(&value[1..value.len() - 1])));
+    }
+    if let Some(int) = value.strip_suffix('i') {
+        return int.parse().ok().map(FieldValue::Integer);
+    }
+    if let Some(uint) = value.strip_suffix('u') {
+        return uint.parse().ok().map(FieldValue::Unsigned);
+    }
+    match value {
+        "t" | "T" | "true" | "True" | "TRUE" => return Some(FieldValue::Boolean(true)),
+        "f" | "F" | "false" | "False" | "FALSE" => return Some(FieldValue::Boolean(false)),
+        _ => {},
+    }
+    // neither NaN nor the infinities can be written
+    value.parse::<f64>().ok().filter(|v| v.is_finite()).map(FieldValue::Float)
+}
+
+fn format_value(value: &FieldValue) -> String {
+    match value {
+        FieldValue::Float(val) => val.to_string(),
+        FieldValue::Integer(val) => format!("{}i", val),
+        FieldValue::Unsigned(val) => format!("{}u", val),
+        FieldValue::Boolean(val) => val.to_string(),
+        FieldValue::String(val) => format!("\"{}\"", escape(val, &['"', '\\'])),
+    }
+}
+
+// splits at the separators not escaped by a backslash, nor in double quotes if `quotes` is set
+fn split_unescaped(s: &str, sep: char, quotes: bool) -> Vec<&str> {
+    let mut res = vec![];
+    let (mut start, mut escaped, mut quoted) = (0, false, false);
+    for (i, c) in s.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == '"' && quotes {
+            quoted = !quoted;
+        } else if c == sep && !quoted {
+            res.push(&s[start..i]);
+            start = i + 1;
+        }
+    }
+    res.push(&s[start..]);
+    res
+}
+
+// splits at the first equal sign not escaped
+fn split_pair(s: &str) -> Option<(&str, &str)> {
+    let mut escaped = false;
+    for (i, c) in s.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == '=' {
+            return Some((&s[..i], &s[i + 1..]));
+        }
+    }
+    None
+}
+
+fn escape(s: &str, chars: &[char]) -> String {
+    let mut res = String::with_capacity(s.len());
+    for c in s.chars() {
+        if chars.contains(&c) {
+            res.push('\\');
+        }
+        res.push(c);
+    }
+    res
+}
+
+// drops the backslash before an escaped character, a backslash before any other character is
+// kept as it is
+fn unescape(s: &str) -> String {
+    let mut res = String::with_capacity(s.len());
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.peek() {
+                Some(&next) if matches!(next, ',' | '=' | ' ' | '"' | '\\') => {
+                    res.push(next);
+                    chars.next();
+                    continue;
+                },
+                _ => {},
+            }
+        }
+        res.push(c);
+    }
+    res
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::HashMap, sync::Arc};
+
+    use models::{FieldId, ValueType};
+    use tokio::sync::RwLock;
+
+    use super::{export_scan, format_line, parse, parse_line, FieldValue, Line};
+    use crate::{
+        error::Error,
+        kv_option::{MemCacheImpl, TseriesFamOpt},
+        memcache::{new_memcache, DataType},
+        tseries_family::{TimeRange, TseriesFamily, Version},
+    };
+
+    fn values(points: &[(FieldId, DataType)]) -> Vec<(FieldId, i64, FieldValue)> {
+        points.iter().map(|(id, data)| (*id, data.timestamp(), data.into())).collect()
+    }
+
+    #[test]
+    fn test_line_protocol_round_trip() {
+        let lines = ["cpu,host=server\\ 1,region=west usage=0.5,cores=8i,up=true 1000",
+                     "disk\\,io,path=/a\\=b free=12u,label=\"say \\\"hi\\\", a\\\\b\" -5",
+                     "mem total=1"];
+        for line in lines {
+            assert_eq!(format_line(&parse_line(line).unwrap()), line);
+        }
+
+        let line = parse_line(lines[1]).unwrap();
+        assert_eq!(line,
+                   Line { measurement: "disk,io".to_string(),
+                          tags: vec![("path".to_string(), "/a=b".to_string())],
+                          fields: vec![("free".to_string(), FieldValue::Unsigned(12)),
+                                       ("label".to_string(),
+                                        FieldValue::String("say \"hi\", a\\b".to_string()))],
+                          timestamp: Some(-5) });
+
+        // the ids of the fields are given by the caller, the timestamp defaults
+        let ids = HashMap::from([("total", 7), ("used", 8)]);
+        let points = parse("mem total=1,used=2i", 100, |_, name| ids[name]).unwrap();
+        assert_eq!(values(&points),
+                   vec![(7, 100, FieldValue::Float(1.0)), (8, 100, FieldValue::Integer(2))]);
+        assert!(parse("# a comment", 100, |_, _| 0).unwrap().is_empty());
+
+        for line in ["cpu", "cpu usage=", "cpu usage=1x", "cpu,host usage=1", "cpu usage=1 now"] {
+            assert!(matches!(parse_line(line), Err(Error::InvalidLineProtocol { .. })), "{}", line);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_export_scan() {
+        let tmp = tempfile::tempdir().unwrap();
+        let tsf = TseriesFamily::new(0,
+                                     "db".to_string(),
+                                     new_memcache(MemCacheImpl::HashMap, 0, 4096, 0, false),
+                                     Arc::new(RwLock::new(Version::new(0,
+                                                                       0,
+                                                                       "db".to_string(),
+                                                                       vec![],
+                                                                       0))),
+                                     TseriesFamOpt::for_testing(tmp.path())).await;
+        {
+            let mut mem = tsf.cache().write().await;
+            for ts in [1, 2] {
+                mem.insert_raw(1, 1, ts, ValueType::Float, &(ts as f64 / 2.0).to_be_bytes())
+                   .unwrap();
+            }
+            mem.insert_raw(1, 2, 2, ValueType::String, b"a b").unwrap();
+        }
+        let fields: Vec<(String, FieldId)> =
+            vec![("usage".to_string(), 1), ("name".to_string(), 2)];
+        let tags = vec![("host".to_string(), "a".to_string())];
+        let text = export_scan(&tsf, "cpu", &tags, &fields, &TimeRange::new(10, 0)).await.unwrap();
+        assert_eq!(text, "cpu,host=a usage=0.5 1\ncpu,host=a usage=1,name=\"a b\" 2\n");
+
+        // the exported lines are read back into the same points
+        let ids = HashMap::from([("usage", 1), ("name", 2)]);
+        let points: Vec<(FieldId, DataType)> =
+            text.lines().flat_map(|line| parse(line, 0, |_, name| ids[name]).unwrap()).collect();
+        assert_eq!(values(&points),
+                   vec![(1, 1, FieldValue::Float(0.5)),
+                        (1, 2, FieldValue::Float(1.0)),
+                        (2, 2, FieldValue::String("a b".to_string()))]);
+    }
+}
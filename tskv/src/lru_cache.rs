@@ -1,4 +1,15 @@
-use std::{collections::HashMap, mem::swap};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    mem::swap,
+};
+
+use config::GLOBAL_CONFIG;
+use once_cell::sync::OnceCell;
+use parking_lot::Mutex;
+use serde::Serialize;
+
+use crate::{kv_option::CachePolicy, tsm::DataBlock};
 
 // No clone, no copy! That asserts that an LRUHandle exists only once.
 type LRUHandle<T> = *mut LRUNode<T>;
@@ -81,6 +92,9 @@ impl<T> LRUList<T> {
             if current.next.is_some() {
                 // Update next node's predecessor.
                 current.next.as_mut().unwrap().prev = current.prev.take();
+            } else {
+                // Removing the last node, update the tail
+                self.head.prev = current.prev.take();
             }
             (*prev).next = current.next.take();
 
@@ -134,24 +148,89 @@ impl<T> LRUList<T> {
 
 pub type CacheKey = [u8; 16];
 pub type CacheId = u64;
-type CacheEntry<T> = (T, LRUHandle<CacheKey>);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Segment {
+    Probation,
+    Protected,
+}
+
+struct CacheEntry<T> {
+    elem: T,
+    handle: LRUHandle<CacheKey>,
+    segment: Segment,
+}
+
+// the share of the capacity `Cache::segmented` reserves to the protected segment
+const PROTECTED_RATIO: f64 = 0.8;
+
+/// The counters of a `Cache`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    // elements inserted
+    pub admitted: u64,
+    // elements not inserted because of `CachePolicy::NoFill`
+    pub rejected: u64,
+    // elements removed to make room, the elements removed by `remove` are not counted
+    pub evicted: u64,
+    // elements moved from the probationary to the protected segment when read again
+    pub promoted: u64,
+}
+
+impl CacheStats {
+    pub fn hit_ratio(&self) -> f64 {
+        let lookups = self.hits + self.misses;
+        if lookups == 0 { 0.0 } else { self.hits as f64 / lookups as f64 }
+    }
+}
 
 /// Implementation of `ShardedLRUCache`.
 /// Based on a HashMap; the elements are linked in order to support the LRU ordering.
+///
+/// The cache is a segmented LRU: the elements enter a probationary segment and are moved to a
+/// protected segment of at most `protected_cap` elements when they are read again. The
+/// elements pushed out of the protected segment go back to the probationary segment, and only
+/// the probationary segment is evicted from, so a scan reading many elements once does not
+/// evict the elements read repeatedly. With a `protected_cap` of 0 it is a plain LRU.
 pub struct Cache<T> {
     // note: CacheKeys (Vec<u8>) are duplicated between list and map. If this turns out to be a
     // performance bottleneck, another layer of indirection™ can solve this by mapping the key
     // to a numeric handle that keys both list and map.
-    list: LRUList<CacheKey>,
+    probation: LRUList<CacheKey>,
+    protected: LRUList<CacheKey>,
     map: HashMap<CacheKey, CacheEntry<T>>,
     cap: usize,
+    protected_cap: usize,
     id: u64,
+    stats: CacheStats,
 }
 
+// the raw pointers of the lists only point to the nodes owned by the lists
+unsafe impl<T: Send> Send for Cache<T> {}
+
 impl<T> Cache<T> {
+    /// A plain LRU cache.
     pub fn new(capacity: usize) -> Cache<T> {
+        Self::with_protected_cap(capacity, 0)
+    }
+
+    /// A segmented LRU cache protecting `PROTECTED_RATIO` of the capacity.
+    pub fn segmented(capacity: usize) -> Cache<T> {
+        Self::with_protected_cap(capacity, (capacity as f64 * PROTECTED_RATIO) as usize)
+    }
+
+    pub fn with_protected_cap(capacity: usize, protected_cap: usize) -> Cache<T> {
         assert!(capacity > 0);
-        Cache { list: LRUList::new(), map: HashMap::with_capacity(1024), cap: capacity, id: 0 }
+        assert!(protected_cap < capacity);
+        Cache { probation: LRUList::new(),
+                protected: LRUList::new(),
+                map: HashMap::with_capacity(1024),
+                cap: capacity,
+                protected_cap,
+                id: 0,
+                stats: CacheStats::default() }
     }
 
     /// Returns an ID that is unique for this cache and that can be used to partition the cache
@@ -163,7 +242,7 @@ impl<T> Cache<T> {
 
     /// How many the cache currently contains
     pub fn count(&self) -> usize {
-        self.list.count()
+        self.probation.count() + self.protected.count()
     }
 
     /// The capacity of this cache
@@ -171,31 +250,87 @@ impl<T> Cache<T> {
         self.cap
     }
 
-    /// Insert a new element into the cache. The returned `CacheHandle` can be used for further
-    /// operations on that element.
-    /// If the capacity has been reached, the least recently used element is removed from the
-    /// cache.
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    /// Insert a new element into the cache, replacing the element of the key if any.
+    /// If the capacity has been reached, the least recently used element of the probationary
+    /// segment is removed from the cache.
     pub fn insert(&mut self, key: &CacheKey, elem: T) {
-        if self.list.count() >= self.cap {
-            if let Some(removed_key) = self.list.remove_last() {
+        self.insert_with(key, elem, CachePolicy::Default)
+    }
+
+    /// Inserts an element like `insert`, into the segment chosen by the policy.
+    pub fn insert_with(&mut self, key: &CacheKey, elem: T, policy: CachePolicy) {
+        if policy == CachePolicy::NoFill {
+            self.stats.rejected += 1;
+            return;
+        }
+        self.remove(key);
+        let segment = if policy == CachePolicy::FillHighPriority && self.protected_cap > 0 {
+            self.make_protected_room();
+            Segment::Protected
+        } else {
+            Segment::Probation
+        };
+        if self.count() >= self.cap {
+            // the protected segment is smaller than the capacity, the probation is not empty
+            if let Some(removed_key) = self.probation.remove_last() {
                 assert!(self.map.remove(&removed_key).is_some());
+                self.stats.evicted += 1;
             } else {
                 panic!("could not remove_last(); bug!");
             }
         }
 
-        let lru_handle = self.list.insert(*key);
-        self.map.insert(*key, (elem, lru_handle));
+        let handle = match segment {
+            Segment::Probation => self.probation.insert(*key),
+            Segment::Protected => self.protected.insert(*key),
+        };
+        self.map.insert(*key, CacheEntry { elem, handle, segment });
+        self.stats.admitted += 1;
     }
 
     /// Retrieve an element from the cache.
     /// If the element has been preempted from the cache in the meantime, this returns None.
     pub fn get<'a>(&'a mut self, key: &CacheKey) -> Option<&'a T> {
+        let segment = match self.map.get(key) {
+            None => {
+                self.stats.misses += 1;
+                return None;
+            },
+            Some(entry) => entry.segment,
+        };
+        self.stats.hits += 1;
+        if segment == Segment::Probation && self.protected_cap > 0 {
+            self.make_protected_room();
+            let entry = self.map.get_mut(key).unwrap();
+            self.probation.remove(entry.handle);
+            entry.handle = self.protected.insert(*key);
+            entry.segment = Segment::Protected;
+            self.stats.promoted += 1;
+        } else {
+            let entry = &self.map[key];
+            match segment {
+                Segment::Probation => self.probation.reinsert_front(entry.handle),
+                Segment::Protected => self.protected.reinsert_front(entry.handle),
+            }
+        }
+        self.map.get(key).map(|entry| &entry.elem)
+    }
+
+    /// Retrieve an element from the cache like `get`, without changing the order of the
+    /// elements.
+    pub fn peek<'a>(&'a mut self, key: &CacheKey) -> Option<&'a T> {
         match self.map.get(key) {
-            None => None,
-            Some(&(ref elem, ref lru_handle)) => {
-                self.list.reinsert_front(*lru_handle);
-                Some(elem)
+            None => {
+                self.stats.misses += 1;
+                None
+            },
+            Some(entry) => {
+                self.stats.hits += 1;
+                Some(&entry.elem)
             },
         }
     }
@@ -204,12 +339,93 @@ impl<T> Cache<T> {
     pub fn remove(&mut self, key: &CacheKey) -> Option<T> {
         match self.map.remove(key) {
             None => None,
-            Some((elem, lru_handle)) => {
-                self.list.remove(lru_handle);
-                Some(elem)
+            Some(entry) => {
+                match entry.segment {
+                    Segment::Probation => self.probation.remove(entry.handle),
+                    Segment::Protected => self.protected.remove(entry.handle),
+                };
+                Some(entry.elem)
             },
         }
     }
+
+    // moves the least recently used protected element to the probationary segment if the
+    // protected segment is full
+    fn make_protected_room(&mut self) {
+        if self.protected.count() < self.protected_cap {
+            return;
+        }
+        if let Some(key) = self.protected.remove_last() {
+            let entry = self.map.get_mut(&key).unwrap();
+            entry.handle = self.probation.insert(key);
+            entry.segment = Segment::Probation;
+        }
+    }
+}
+
+/// The decoded blocks of the tsm files shared by all the scans, keyed by the path and the
+/// length of the file and the offset of the block.
+pub struct BlockCache {
+    // None if the capacity is 0
+    inner: Option<Mutex<Cache<DataBlock>>>,
+}
+
+pub fn get_block_cache() -> &'static BlockCache {
+    static INSTANCE: OnceCell<BlockCache> = OnceCell::new();
+    INSTANCE.get_or_init(|| BlockCache::new(GLOBAL_CONFIG.block_cache_capacity))
+}
+
+impl BlockCache {
+    /// A segmented cache of at most `capacity` blocks, disabled if `capacity` is 0.
+    pub fn new(capacity: usize) -> Self {
+        Self { inner: (capacity > 0).then(|| Mutex::new(Cache::segmented(capacity))) }
+    }
+
+    /// Returns the block at `offset` of the file, with `CachePolicy::NoFill` the order of the
+    /// cached blocks is not changed.
+    pub fn get(&self, path: &str, len: u64, offset: u64, policy: CachePolicy) -> Option<DataBlock> {
+        let mut cache = self.inner.as_ref()?.lock();
+        let key = block_key(path, len, offset);
+        let block = match policy {
+            CachePolicy::NoFill => cache.peek(&key),
+            _ => cache.get(&key),
+        };
+        block.cloned()
+    }
+
+    /// Admits the block at `offset` of the file as the policy says, the block is only copied
+    /// if it is admitted.
+    pub fn insert(&self,
+                  path: &str,
+                  len: u64,
+                  offset: u64,
+                  block: &DataBlock,
+                  policy: CachePolicy) {
+        if let Some(cache) = self.inner.as_ref() {
+            let mut cache = cache.lock();
+            if policy == CachePolicy::NoFill {
+                cache.stats.rejected += 1;
+            } else {
+                cache.insert_with(&block_key(path, len, offset), block.clone(), policy);
+            }
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.inner.as_ref().map(|cache| cache.lock().stats()).unwrap_or_default()
+    }
+}
+
+// a file rewritten at the same path almost always has another length, so its blocks are not
+// mistaken for the blocks of the old file
+fn block_key(path: &str, len: u64, offset: u64) -> CacheKey {
+    let mut hasher = DefaultHasher::new();
+    path.hash(&mut hasher);
+    len.hash(&mut hasher);
+    let mut key = [0u8; 16];
+    key[..8].copy_from_slice(&hasher.finish().to_be_bytes());
+    key[8..].copy_from_slice(&offset.to_be_bytes());
+    key
 }
 
 #[cfg(test)]
@@ -220,6 +436,108 @@ mod tests {
         [a, b, c, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]
     }
 
+    fn int_key(i: u32) -> CacheKey {
+        let mut key = [0u8; 16];
+        key[..4].copy_from_slice(&i.to_be_bytes());
+        key
+    }
+
+    // reads the 50 hot keys and then scans 1000 keys with the policy, reading a hot key every
+    // two scanned keys, returns the hit ratio of the hot keys during the scan
+    fn hot_hit_ratio_with_scan(mut cache: Cache<u32>, policy: CachePolicy) -> f64 {
+        let mut read = |key: u32, policy: CachePolicy| {
+            let hit = cache.get(&int_key(key)).is_some();
+            if !hit {
+                cache.insert_with(&int_key(key), key, policy);
+            }
+            hit
+        };
+        for _ in 0..3 {
+            for key in 0..50 {
+                read(key, CachePolicy::Default);
+            }
+        }
+        let mut hot_hits = 0;
+        for i in 0..1000 {
+            read(1000 + i, policy);
+            if i % 2 == 1 {
+                hot_hits += read(i / 2 % 50, CachePolicy::Default) as u32;
+            }
+        }
+        hot_hits as f64 / 500.0
+    }
+
+    #[test]
+    fn test_blockcache_scan_resistance() {
+        let ratio = hot_hit_ratio_with_scan(Cache::segmented(100), CachePolicy::Default);
+        assert!(ratio > 0.9, "hot hit ratio {} of the segmented cache", ratio);
+        // regression guard, the same scan evicts the hot keys from a plain LRU
+        let ratio = hot_hit_ratio_with_scan(Cache::new(100), CachePolicy::Default);
+        assert!(ratio < 0.1, "hot hit ratio {} of the plain LRU", ratio);
+        // a scan not filling the cache does not evict anything
+        let ratio = hot_hit_ratio_with_scan(Cache::new(100), CachePolicy::NoFill);
+        assert_eq!(ratio, 1.0);
+    }
+
+    #[test]
+    fn test_blockcache_cache_policy() {
+        let mut cache = Cache::segmented(10);
+        for key in 0..5 {
+            cache.insert_with(&int_key(key), key, CachePolicy::FillHighPriority);
+        }
+        for key in 100..120 {
+            cache.insert_with(&int_key(key), key, CachePolicy::Default);
+        }
+        for key in 200..210 {
+            cache.insert_with(&int_key(key), key, CachePolicy::NoFill);
+        }
+        assert_eq!(cache.count(), 10);
+        for key in 0..5 {
+            assert_eq!(cache.get(&int_key(key)), Some(&key));
+        }
+        assert_eq!(cache.get(&int_key(100)), None);
+        assert_eq!(cache.get(&int_key(200)), None);
+        assert_eq!(cache.stats(),
+                   CacheStats { hits: 5,
+                                misses: 2,
+                                admitted: 25,
+                                rejected: 10,
+                                evicted: 15,
+                                promoted: 0 });
+
+        // a probationary key read again is protected, pushing out the least recently used
+        // protected key which is evicted next
+        cache.insert(&int_key(5), 5);
+        assert_eq!(cache.get(&int_key(5)), Some(&5));
+        assert_eq!(cache.stats().promoted, 1);
+        cache.insert(&int_key(300), 300);
+        assert_eq!(cache.get(&int_key(119)), Some(&119));
+        assert_eq!(cache.get(&int_key(0)), Some(&0));
+    }
+
+    #[test]
+    fn test_blockcache_block_cache() {
+        let block = DataBlock::I64 { index: 0,
+                                     ts: vec![1, 2, 3],
+                                     val: vec![10, 20, 30],
+                                     validity: None };
+        let cache = BlockCache::new(4);
+        cache.insert("a.tsm", 100, 0, &block, CachePolicy::NoFill);
+        assert_eq!(cache.get("a.tsm", 100, 0, CachePolicy::Default), None);
+        cache.insert("a.tsm", 100, 0, &block, CachePolicy::Default);
+        assert_eq!(cache.get("a.tsm", 100, 0, CachePolicy::NoFill), Some(block.clone()));
+        // another file or the same file rewritten with another length
+        assert_eq!(cache.get("b.tsm", 100, 0, CachePolicy::Default), None);
+        assert_eq!(cache.get("a.tsm", 120, 0, CachePolicy::Default), None);
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.admitted, stats.rejected), (1, 3, 1, 1));
+
+        let disabled = BlockCache::new(0);
+        disabled.insert("a.tsm", 100, 0, &block, CachePolicy::Default);
+        assert_eq!(disabled.get("a.tsm", 100, 0, CachePolicy::Default), None);
+        assert_eq!(disabled.stats(), CacheStats::default());
+    }
+
     #[test]
     fn test_blockcache_cache_add_rm() {
         let mut cache = Cache::new(128);
@@ -293,6 +611,24 @@ mod tests {
         assert_eq!(lru.count(), 3);
     }
 
+    #[test]
+    fn test_blockcache_lru_remove_last_node() {
+        let mut lru = LRUList::<usize>::new();
+
+        let h_1 = lru.insert(1);
+        lru.insert(2);
+        lru.insert(3);
+
+        assert_eq!(1, lru.remove(h_1));
+        assert_eq!(Some(2), lru.remove_last());
+        let h_4 = lru.insert(4);
+        assert_eq!(Some(3), lru.remove_last());
+        assert_eq!(4, lru.remove(h_4));
+        assert_eq!(None, lru.remove_last());
+        lru.insert(5);
+        assert_eq!(Some(5), lru.remove_last());
+    }
+
     #[test]
     fn test_blockcache_lru_1() {
         let mut lru = LRUList::<usize>::new();
@@ -1,12 +1,29 @@
-use std::{borrow::BorrowMut, collections::HashMap, mem::size_of_val, rc::Rc};
+use std::{
+    borrow::{BorrowMut, Cow},
+    collections::HashMap,
+    fmt::{self, Debug, Display},
+    mem::size_of,
+    rc::Rc,
+    sync::Arc,
+};
 
 use flatbuffers::Push;
 use futures::future::ok;
 use logger::{info, warn};
 use models::{FieldId, Timestamp, ValueType};
 use protos::models::FieldType;
+use serde::Serialize;
+use snafu::ResultExt;
+use tokio::sync::RwLock;
 
-use crate::{byte_utils, error::Result, tseries_family::TimeRange};
+#[cfg(feature = "skiplist")]
+use crate::skiplist_cache::SkipListCache;
+use crate::{
+    byte_utils,
+    error::{self, Error, Result},
+    kv_option::{MemCacheImpl, Utf8Policy},
+    tseries_family::TimeRange,
+};
 
 #[allow(dead_code)]
 #[derive(Default, Debug, Clone, Copy)]
@@ -22,6 +39,13 @@ pub type StrCell = DataCell<Byte>;
 pub type F64Cell = DataCell<f64>;
 pub type BoolCell = DataCell<bool>;
 
+impl StrCell {
+    /// Returns the value as text, fails if it is not valid UTF-8.
+    pub fn as_str(&self) -> Result<&str> {
+        std::str::from_utf8(&self.val).context(error::InvalidUtf8Snafu)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum DataType {
     U64(U64Cell),
@@ -41,9 +65,42 @@ impl DataType {
             DataType::Bool(BoolCell { ts, .. }) => ts,
         }
     }
+
+    /// Returns the bytes the cell takes in a cache, with the bytes of a string value.
+    pub fn size(&self) -> u64 {
+        let val_size = match self {
+            DataType::Str(StrCell { val, .. }) => val.len(),
+            _ => 0,
+        };
+        (size_of::<DataType>() + val_size) as u64
+    }
+
+    pub fn value_type(&self) -> ValueType {
+        match self {
+            DataType::U64(_) => ValueType::Unsigned,
+            DataType::I64(_) => ValueType::Integer,
+            DataType::Str(_) => ValueType::String,
+            DataType::F64(_) => ValueType::Float,
+            DataType::Bool(_) => ValueType::Boolean,
+        }
+    }
+
+    /// Converts the value to the value type, see `DataBlock::coerce`.
+    pub fn coerce(self, to: ValueType) -> Result<DataType> {
+        match (self, to) {
+            (DataType::I64(c), ValueType::Float) => {
+                Ok(DataType::F64(F64Cell { ts: c.ts, val: c.val as f64 }))
+            },
+            (DataType::U64(c), ValueType::Float) => {
+                Ok(DataType::F64(F64Cell { ts: c.ts, val: c.val as f64 }))
+            },
+            (data, to) if data.value_type() == to => Ok(data),
+            (data, to) => Err(Error::IncompatibleCoercion { from: data.value_type(), to }),
+        }
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct MemEntry {
     pub ts_min: i64,
     pub ts_max: i64,
@@ -68,13 +125,205 @@ impl MemEntry {
         }
     }
 
+    pub fn read_cells(&self, time_range: &TimeRange) -> Vec<DataType> {
+        self.cells.iter().filter(|data| time_range.contains(data.timestamp())).cloned().collect()
+    }
+
     pub fn overlap(&self, time_range: &TimeRange) -> bool {
         !(self.ts_min > time_range.max_ts && self.ts_max < time_range.min_ts)
     }
 
-    pub fn delete_data_cell(&mut self, time_range: &TimeRange) {
-        self.cells
-            .retain(|x| x.timestamp() < time_range.min_ts || x.timestamp() > time_range.max_ts);
+    /// Inserts the cell after the cells of earlier or the same timestamp, so that the cells
+    /// stay sorted by timestamp when they are written out of order.
+    pub fn insert_sorted(&mut self, cell: DataType) {
+        let ts = cell.timestamp();
+        if self.cells.last().map_or(true, |last| last.timestamp() <= ts) {
+            self.cells.push(cell);
+        } else {
+            let pos = self.cells.partition_point(|c| c.timestamp() <= ts);
+            self.cells.insert(pos, cell);
+        }
+    }
+
+    /// Deletes the cells in the time range, returns the size of the cells deleted.
+    pub fn delete_data_cell(&mut self, time_range: &TimeRange) -> u64 {
+        let mut deleted = 0_u64;
+        self.cells.retain(|x| {
+                      if time_range.contains(x.timestamp()) {
+                          deleted = deleted.saturating_add(x.size());
+                          return false;
+                      }
+                      true
+                  });
+        deleted
+    }
+
+    /// Returns the size of the cells.
+    pub fn size(&self) -> u64 {
+        self.cells.iter().fold(0, |size, cell| size.saturating_add(cell.size()))
+    }
+}
+
+/// The memory cache of a tseries family.
+///
+/// Implementations must keep the insertion order of the cells of the same timestamp, so that
+/// the last written point can win when merging.
+pub trait MemCacheTrait: Send + Sync + Debug {
+    fn insert_raw(&mut self,
+                  seq: u64,
+                  field_id: FieldId,
+                  ts: Timestamp,
+                  field_type: ValueType,
+                  buf: &[u8])
+                  -> Result<()>;
+
+    /// Inserts like `insert_raw`, the entry of the field is looked up in `hints` first and
+    /// remembered there. The hints must be reset with `FieldHints::renew` whenever the cache
+    /// they were filled by is replaced.
+    fn insert_raw_hinted(&mut self,
+                         hints: &mut FieldHints,
+                         seq: u64,
+                         field_id: FieldId,
+                         ts: Timestamp,
+                         field_type: ValueType,
+                         buf: &[u8])
+                         -> Result<()> {
+        let _ = hints;
+        self.insert_raw(seq, field_id, ts, field_type, buf)
+    }
+
+    /// Returns the cells of a field in the time range, sorted by timestamp.
+    fn read(&self, field_id: FieldId, time_range: &TimeRange) -> Vec<DataType>;
+
+    /// Calls `f` with every cell in the time range by reference, without cloning the cells.
+    /// The cells of a field come sorted by timestamp, the fields in no particular order.
+    fn visit(&self, time_range: &TimeRange, f: &mut dyn FnMut(FieldId, &DataType));
+
+    /// Returns the cells of a field in the time range sorted by timestamp, lazily. The cells
+    /// are borrowed from the cache if it can lend them, cloned one by one otherwise.
+    fn iter_field<'a>(&'a self,
+                      field_id: FieldId,
+                      time_range: &TimeRange)
+                      -> Box<dyn Iterator<Item = Cow<'a, DataType>> + 'a>;
+
+    fn delete_range(&mut self, time_range: &TimeRange);
+
+    fn delete_field_range(&mut self, field_id: FieldId, time_range: &TimeRange);
+
+    fn iter_entries(&self) -> Box<dyn Iterator<Item = (FieldId, Cow<'_, MemEntry>)> + '_>;
+
+    fn entry_len(&self, field_id: FieldId) -> usize;
+
+    /// Moves the data of a field into a new immutable cache.
+    fn split_field(&mut self, field_id: FieldId) -> Option<MemCacheRef>;
+
+    fn size(&self) -> u64;
+
+    fn is_full(&self) -> bool;
+
+    fn is_empty(&self) -> bool;
+
+    fn switch_to_immutable(&mut self);
+
+    fn is_immutable(&self) -> bool;
+
+    fn seq_no(&self) -> u64;
+
+    /// The wal sequences of the first and the last write of the cache.
+    fn seq_range(&self) -> (u64, u64);
+
+    fn is_delta(&self) -> bool;
+
+    fn tf_id(&self) -> u32;
+
+    fn summary(&self) -> CacheSummary;
+}
+
+/// The size and ranges of a memory cache, displayed as
+/// `tf_id=1 bytes=4096 fields=2 cells=100 ts_range=[1,99] seq_range=[3,7] immutable=false`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CacheSummary {
+    pub tf_id: u32,
+    pub bytes: u64,
+    pub fields: usize,
+    pub cells: usize,
+    // None if the cache is empty
+    pub ts_range: Option<(Timestamp, Timestamp)>,
+    // the wal sequence numbers written to the cache
+    pub seq_range: (u64, u64),
+    pub immutable: bool,
+}
+
+impl Display for CacheSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f,
+               "tf_id={} bytes={} fields={} cells={}",
+               self.tf_id, self.bytes, self.fields, self.cells)?;
+        match self.ts_range {
+            Some((min_ts, max_ts)) => write!(f, " ts_range=[{},{}]", min_ts, max_ts)?,
+            None => write!(f, " ts_range=none")?,
+        }
+        write!(f,
+               " seq_range=[{},{}] immutable={}",
+               self.seq_range.0, self.seq_range.1, self.immutable)
+    }
+}
+
+pub type MemCacheRef = Arc<RwLock<dyn MemCacheTrait>>;
+
+// the fields remembered by the hints of a writer
+const FIELD_HINTS: usize = 4;
+
+/// The positions of the entries of the fields a writer inserted into last, so that the
+/// consecutive points of a field skip looking up its entry.
+///
+/// The positions are valid in one generation of the caches, given by the tseries family and
+/// its super version id; a hint that turns out stale is only a miss, the entry is looked up.
+#[derive(Debug, Default)]
+pub struct FieldHints {
+    generation: Option<(u32, u64)>,
+    slots: [Option<(FieldId, usize)>; FIELD_HINTS],
+    // the slot replaced next
+    next: usize,
+}
+
+impl FieldHints {
+    /// Forgets the positions unless they are of the same generation.
+    pub fn renew(&mut self, tf_id: u32, super_version_id: u64) {
+        if self.generation != Some((tf_id, super_version_id)) {
+            *self = Self { generation: Some((tf_id, super_version_id)), ..Default::default() };
+        }
+    }
+
+    fn get(&self, field_id: FieldId) -> Option<usize> {
+        self.slots.iter().flatten().find(|(fid, _)| *fid == field_id).map(|(_, pos)| *pos)
+    }
+
+    fn put(&mut self, field_id: FieldId, pos: usize) {
+        match self.slots.iter_mut().flatten().find(|(fid, _)| *fid == field_id) {
+            Some(slot) => slot.1 = pos,
+            None => {
+                self.slots[self.next] = Some((field_id, pos));
+                self.next = (self.next + 1) % FIELD_HINTS;
+            },
+        }
+    }
+}
+
+pub fn new_memcache(imp: MemCacheImpl,
+                    tf_id: u32,
+                    max_size: u64,
+                    seq: u64,
+                    is_delta: bool)
+                    -> MemCacheRef {
+    match imp {
+        MemCacheImpl::HashMap => {
+            Arc::new(RwLock::new(MemCache::new(tf_id, max_size, seq, is_delta)))
+        },
+        #[cfg(feature = "skiplist")]
+        MemCacheImpl::SkipList => {
+            Arc::new(RwLock::new(SkipListCache::new(tf_id, max_size, seq, is_delta)))
+        },
     }
 }
 
@@ -87,11 +336,15 @@ pub struct MemCache {
     tf_id: u32,
     // wal seq number
     pub seq_no: u64,
+    // the smallest wal seq number written, u64::MAX if nothing is written
+    min_seq: u64,
     // max mem buffer size convert to immcache
     max_buf_size: u64,
+    // the positions of the entries of the fields
+    index: HashMap<FieldId, usize>,
     // block <field_id, buffer>
     // field_id contain the field type
-    pub data_cache: HashMap<FieldId, MemEntry>,
+    entries: Vec<(FieldId, MemEntry)>,
     // current size
     cache_size: u64,
 
@@ -100,58 +353,49 @@ pub struct MemCache {
 
 impl MemCache {
     pub fn new(tf_id: u32, max_size: u64, seq: u64, is_delta: bool) -> Self {
-        let cache = HashMap::new();
         Self { immutable: false,
                tf_id,
                max_buf_size: max_size,
-               data_cache: cache,
+               index: HashMap::new(),
+               entries: vec![],
                seq_no: seq,
+               min_seq: u64::MAX,
                cache_size: 0,
                is_delta }
     }
 
-    pub fn insert_raw(&mut self,
-                      seq: u64,
-                      field_id: FieldId,
-                      ts: Timestamp,
-                      field_type: ValueType,
-                      buf: &[u8])
-                      -> Result<()> {
-        self.seq_no = seq;
-        match field_type {
-            ValueType::Unsigned => {
-                let val = byte_utils::decode_be_u64(buf);
-                let data = DataType::U64(U64Cell { ts, val });
-                self.insert(field_id, data, ValueType::Unsigned);
-            },
-            ValueType::Integer => {
-                let val = byte_utils::decode_be_i64(buf);
-                let data = DataType::I64(I64Cell { ts, val });
-                self.insert(field_id, data, ValueType::Integer);
-            },
-            ValueType::Float => {
-                let val = byte_utils::decode_be_f64(buf);
-                let data = DataType::F64(F64Cell { ts, val });
-                self.insert(field_id, data, ValueType::Float);
-            },
-            ValueType::String => {
-                let val = Vec::from(buf);
-                let data = DataType::Str(StrCell { ts, val });
-                self.insert(field_id, data, ValueType::String);
-            },
-            ValueType::Boolean => {
-                let val = byte_utils::decode_be_bool(buf);
-                let data = DataType::Bool(BoolCell { ts, val });
-                self.insert(field_id, data, ValueType::Boolean)
-            },
-            _ => todo!(),
-        };
-        Ok(())
+    /// Inserts the value into the entry of the field, fails if the field holds values of
+    /// another type.
+    pub fn insert(&mut self,
+                  field_id: FieldId,
+                  val: DataType,
+                  value_type: ValueType)
+                  -> Result<()> {
+        let pos = self.position(field_id);
+        self.insert_at(pos, val, value_type)
+    }
+
+    // returns the position of the entry of the field, adds the entry if missing
+    fn position(&mut self, field_id: FieldId) -> usize {
+        let entries = &mut self.entries;
+        *self.index.entry(field_id).or_insert_with(|| {
+                                       entries.push((field_id, MemEntry::default()));
+                                       entries.len() - 1
+                                   })
+    }
+
+    fn get(&self, field_id: FieldId) -> Option<&MemEntry> {
+        self.index.get(&field_id).map(|pos| &self.entries[*pos].1)
     }
 
-    pub fn insert(&mut self, field_id: FieldId, val: DataType, value_type: ValueType) {
+    fn insert_at(&mut self, pos: usize, val: DataType, value_type: ValueType) -> Result<()> {
         let ts = val.timestamp();
-        let item = self.data_cache.entry(field_id).or_insert_with(MemEntry::default);
+        let (field_id, item) = &mut self.entries[pos];
+        if !item.cells.is_empty() && item.field_type != value_type {
+            return Err(Error::FieldTypeConflict { field_id: *field_id,
+                                                  expected: item.field_type,
+                                                  found: value_type });
+        }
         if item.ts_max < ts {
             item.ts_max = ts;
         }
@@ -159,34 +403,425 @@ impl MemCache {
             item.ts_min = ts
         }
         item.field_type = value_type;
-        self.cache_size += size_of_val(&val) as u64;
-        item.cells.push(val);
+        self.cache_size = self.cache_size.saturating_add(val.size());
+        item.insert_sorted(val);
+        Ok(())
+    }
+
+    pub fn flush() -> Result<()> {
+        Ok(())
+    }
+
+    pub fn max_buf_size(&self) -> u64 {
+        self.max_buf_size
+    }
+}
+
+impl MemCacheTrait for MemCache {
+    fn insert_raw(&mut self,
+                  seq: u64,
+                  field_id: FieldId,
+                  ts: Timestamp,
+                  field_type: ValueType,
+                  buf: &[u8])
+                  -> Result<()> {
+        let data = decode_cell(ts, field_type, buf);
+        self.insert(field_id, data, field_type)?;
+        self.seq_no = seq;
+        self.min_seq = self.min_seq.min(seq);
+        Ok(())
+    }
+
+    fn insert_raw_hinted(&mut self,
+                         hints: &mut FieldHints,
+                         seq: u64,
+                         field_id: FieldId,
+                         ts: Timestamp,
+                         field_type: ValueType,
+                         buf: &[u8])
+                         -> Result<()> {
+        let pos = match hints.get(field_id) {
+            Some(pos) if self.entries.get(pos).map_or(false, |(fid, _)| *fid == field_id) => pos,
+            _ => {
+                let pos = self.position(field_id);
+                hints.put(field_id, pos);
+                pos
+            },
+        };
+        self.insert_at(pos, decode_cell(ts, field_type, buf), field_type)?;
+        self.seq_no = seq;
+        self.min_seq = self.min_seq.min(seq);
+        Ok(())
+    }
+
+    fn read(&self, field_id: FieldId, time_range: &TimeRange) -> Vec<DataType> {
+        let mut data = match self.get(field_id) {
+            Some(entry) => entry.read_cells(time_range),
+            None => return vec![],
+        };
+        // stable sort keeps the insertion order of the same timestamp
+        data.sort_by_key(|d| d.timestamp());
+        data
+    }
+
+    fn visit(&self, time_range: &TimeRange, f: &mut dyn FnMut(FieldId, &DataType)) {
+        for (field_id, entry) in self.entries.iter() {
+            for cell in entry.cells.iter().filter(|c| time_range.contains(c.timestamp())) {
+                f(*field_id, cell);
+            }
+        }
+    }
+
+    fn iter_field<'a>(&'a self,
+                      field_id: FieldId,
+                      time_range: &TimeRange)
+                      -> Box<dyn Iterator<Item = Cow<'a, DataType>> + 'a> {
+        let cells = self.get(field_id).map_or(&[][..], |entry| entry.cells.as_slice());
+        // the cells are kept sorted by `MemEntry::insert_sorted`
+        let start = cells.partition_point(|c| c.timestamp() < time_range.min_ts);
+        let max_ts = time_range.max_ts;
+        Box::new(cells[start..].iter()
+                               .take_while(move |c| c.timestamp() <= max_ts)
+                               .map(Cow::Borrowed))
     }
 
-    // pub fn data_cache(&self) -> HashMap<u64, MemEntry> {
-    //     self.data_cache
-    // }
+    fn delete_range(&mut self, time_range: &TimeRange) {
+        for (_, entry) in self.entries.iter_mut() {
+            if entry.overlap(time_range) {
+                let deleted = entry.delete_data_cell(time_range);
+                self.cache_size = self.cache_size.saturating_sub(deleted);
+            }
+        }
+    }
 
-    pub fn switch_to_immutable(&mut self) {
-        for data in self.data_cache.iter_mut() {
+    fn delete_field_range(&mut self, field_id: FieldId, time_range: &TimeRange) {
+        if let Some(pos) = self.index.get(&field_id) {
+            let entry = &mut self.entries[*pos].1;
+            if entry.overlap(time_range) {
+                let deleted = entry.delete_data_cell(time_range);
+                self.cache_size = self.cache_size.saturating_sub(deleted);
+            }
+        }
+    }
+
+    fn iter_entries(&self) -> Box<dyn Iterator<Item = (FieldId, Cow<'_, MemEntry>)> + '_> {
+        Box::new(self.entries.iter().map(|(field_id, entry)| (*field_id, Cow::Borrowed(entry))))
+    }
+
+    fn entry_len(&self, field_id: FieldId) -> usize {
+        self.get(field_id).map_or(0, |entry| entry.cells.len())
+    }
+
+    fn split_field(&mut self, field_id: FieldId) -> Option<MemCacheRef> {
+        let pos = self.index.remove(&field_id)?;
+        let (_, entry) = self.entries.swap_remove(pos);
+        // the last entry takes the position of the removed one
+        if let Some((moved, _)) = self.entries.get(pos) {
+            self.index.insert(*moved, pos);
+        }
+        let size = entry.size();
+        self.cache_size = self.cache_size.saturating_sub(size);
+
+        let mut cache = MemCache::new(self.tf_id, self.max_buf_size, self.seq_no, self.is_delta);
+        cache.cache_size = size;
+        // the sequences of the writes of the field are not kept, those of the cache span them
+        cache.min_seq = self.min_seq;
+        cache.index.insert(field_id, 0);
+        cache.entries.push((field_id, entry));
+        cache.switch_to_immutable();
+        Some(Arc::new(RwLock::new(cache)))
+    }
+
+    fn size(&self) -> u64 {
+        self.cache_size
+    }
+
+    fn is_full(&self) -> bool {
+        self.cache_size >= self.max_buf_size
+    }
+
+    fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn switch_to_immutable(&mut self) {
+        for data in self.entries.iter_mut() {
             data.1.cells.sort_by(|a, b| a.timestamp().partial_cmp(&b.timestamp()).unwrap())
         }
         self.immutable = true;
     }
 
-    pub fn flush() -> Result<()> {
-        Ok(())
+    fn is_immutable(&self) -> bool {
+        self.immutable
     }
 
-    pub fn is_full(&self) -> bool {
-        self.cache_size >= self.max_buf_size
+    fn seq_no(&self) -> u64 {
+        self.seq_no
+    }
+
+    fn seq_range(&self) -> (u64, u64) {
+        (self.min_seq.min(self.seq_no), self.seq_no)
     }
 
-    pub fn tf_id(&self) -> u32 {
+    fn is_delta(&self) -> bool {
+        self.is_delta
+    }
+
+    fn tf_id(&self) -> u32 {
         self.tf_id
     }
 
-    pub fn max_buf_size(&self) -> u64 {
-        self.max_buf_size
+    fn summary(&self) -> CacheSummary {
+        let mut ts_range: Option<(Timestamp, Timestamp)> = None;
+        for (_, entry) in self.entries.iter().filter(|(_, e)| !e.cells.is_empty()) {
+            ts_range = Some(match ts_range {
+                                Some((min_ts, max_ts)) => {
+                                    (min_ts.min(entry.ts_min), max_ts.max(entry.ts_max))
+                                },
+                                None => (entry.ts_min, entry.ts_max),
+                            });
+        }
+        CacheSummary { tf_id: self.tf_id,
+                       bytes: self.cache_size,
+                       fields: self.entries.len(),
+                       cells: self.entries.iter().map(|(_, e)| e.cells.len()).sum(),
+                       ts_range,
+                       seq_range: self.seq_range(),
+                       immutable: self.immutable }
+    }
+}
+
+/// Checks a value to insert against the utf8 policy, returns the bytes to store.
+///
+/// Only string values are checked, values of other types are returned as they are.
+pub fn check_utf8(policy: Utf8Policy, field_type: ValueType, buf: &[u8]) -> Result<Cow<'_, [u8]>> {
+    if field_type != ValueType::String || policy == Utf8Policy::Bytes {
+        return Ok(Cow::Borrowed(buf));
+    }
+    match std::str::from_utf8(buf) {
+        Ok(_) => Ok(Cow::Borrowed(buf)),
+        Err(source) => match policy {
+            Utf8Policy::Reject => Err(Error::InvalidUtf8 { source }),
+            _ => Ok(Cow::Owned(String::from_utf8_lossy(buf).into_owned().into_bytes())),
+        },
+    }
+}
+
+pub(crate) fn decode_cell(ts: Timestamp, field_type: ValueType, buf: &[u8]) -> DataType {
+    match field_type {
+        ValueType::Unsigned => {
+            let val = byte_utils::decode_be_u64(buf);
+            DataType::U64(U64Cell { ts, val })
+        },
+        ValueType::Integer => {
+            let val = byte_utils::decode_be_i64(buf);
+            DataType::I64(I64Cell { ts, val })
+        },
+        ValueType::Float => {
+            let val = byte_utils::decode_be_f64(buf);
+            DataType::F64(F64Cell { ts, val })
+        },
+        ValueType::String => {
+            let val = Vec::from(buf);
+            DataType::Str(StrCell { ts, val })
+        },
+        ValueType::Boolean => {
+            let val = byte_utils::decode_be_bool(buf);
+            DataType::Bool(BoolCell { ts, val })
+        },
+        _ => todo!(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::HashMap, mem::size_of};
+
+    use models::ValueType;
+
+    use super::{
+        check_utf8, new_memcache, CacheSummary, DataType, FieldHints, I64Cell, MemCache,
+        MemCacheTrait, StrCell,
+    };
+    use crate::{
+        error::Error,
+        kv_option::{MemCacheImpl, Utf8Policy},
+        tseries_family::TimeRange,
+    };
+
+    fn values(data: Vec<DataType>) -> Vec<(i64, i64)> {
+        data.into_iter()
+            .map(|d| match d {
+                DataType::I64(I64Cell { ts, val }) => (ts, val),
+                _ => panic!("unexpected data type"),
+            })
+            .collect()
+    }
+
+    async fn test_memcache_suite(imp: MemCacheImpl) {
+        let cache = new_memcache(imp, 0, 1024 * 1024, 0, false);
+        let mut mem = cache.write().await;
+        assert!(mem.is_empty());
+        let points = [(3, 30_i64), (1, 10), (2, 20), (2, 21), (5, 50)];
+        for (seq, (ts, val)) in points.iter().enumerate() {
+            mem.insert_raw(seq as u64, 1, *ts, ValueType::Integer, &val.to_be_bytes()).unwrap();
+        }
+        mem.insert_raw(5, 2, 1, ValueType::Integer, &100_i64.to_be_bytes()).unwrap();
+        assert!(!mem.is_empty());
+        assert!(!mem.is_full());
+        assert_eq!(mem.seq_no(), 5);
+        assert_eq!(mem.entry_len(1), 5);
+        assert_eq!(mem.entry_len(2), 1);
+        assert_eq!(mem.entry_len(3), 0);
+        assert_eq!(mem.summary(),
+                   CacheSummary { tf_id: 0,
+                                  bytes: mem.size(),
+                                  fields: 2,
+                                  cells: 6,
+                                  ts_range: Some((1, 5)),
+                                  seq_range: (0, 5),
+                                  immutable: false });
+        // a value of another type is rejected, the cache is left as it is
+        assert!(matches!(mem.insert_raw(6, 1, 4, ValueType::Float, &1.5_f64.to_be_bytes()),
+                         Err(Error::FieldTypeConflict { field_id: 1, .. })));
+        assert_eq!(mem.seq_no(), 5);
+        assert_eq!(mem.entry_len(1), 5);
+
+        // sorted by timestamp, the same timestamp in the write order
+        let all = TimeRange { min_ts: i64::MIN, max_ts: i64::MAX };
+        assert_eq!(values(mem.read(1, &all)), vec![(1, 10), (2, 20), (2, 21), (3, 30), (5, 50)]);
+        assert_eq!(values(mem.read(1, &TimeRange { min_ts: 2, max_ts: 3 })),
+                   vec![(2, 20), (2, 21), (3, 30)]);
+        assert!(mem.read(3, &all).is_empty());
+
+        // the visitor sees the cells read
+        for range in [&all, &TimeRange { min_ts: 2, max_ts: 3 }] {
+            let mut counts = HashMap::new();
+            mem.visit(range, &mut |field_id, _| *counts.entry(field_id).or_insert(0) += 1);
+            for field_id in [1, 2, 3] {
+                assert_eq!(counts.get(&field_id).copied().unwrap_or(0),
+                           mem.read(field_id, range).len());
+            }
+        }
+
+        let cell_size = size_of::<DataType>() as u64;
+        assert_eq!(mem.size(), 6 * cell_size);
+        mem.delete_range(&TimeRange { min_ts: 2, max_ts: 2 });
+        assert_eq!(values(mem.read(1, &all)), vec![(1, 10), (3, 30), (5, 50)]);
+        assert_eq!(mem.size(), 4 * cell_size);
+
+        let split = mem.split_field(1).unwrap();
+        assert!(mem.split_field(1).is_none());
+        assert_eq!(mem.entry_len(1), 0);
+        assert_eq!(values(split.read().await.read(1, &all)), vec![(1, 10), (3, 30), (5, 50)]);
+        assert_eq!((mem.size(), split.read().await.size()), (cell_size, 3 * cell_size));
+
+        assert!(split.read().await.is_immutable());
+        assert!(!mem.is_immutable());
+        mem.switch_to_immutable();
+        assert!(mem.is_immutable());
+        let entries: Vec<_> = mem.iter_entries().collect();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].0, 2);
+        assert_eq!(entries[0].1.ts_min, 1);
+        assert_eq!(entries[0].1.ts_max, 1);
+        assert_eq!(values(entries[0].1.cells.clone()), vec![(1, 100)]);
+        drop(mem);
+
+        // the size goes back to 0 once every cell is deleted, whatever was inserted
+        let cache = new_memcache(imp, 0, 1024 * 1024, 0, false);
+        let mut mem = cache.write().await;
+        for ts in 0..100 {
+            mem.insert_raw(ts as u64, 1, ts, ValueType::String, &vec![b'a'; ts as usize]).unwrap();
+            mem.insert_raw(ts as u64, 2, ts, ValueType::Integer, &ts.to_be_bytes()).unwrap();
+        }
+        assert_eq!(mem.size(), 200 * cell_size + (0..100).sum::<u64>());
+        mem.delete_field_range(1, &TimeRange { min_ts: 0, max_ts: 49 });
+        assert_eq!(mem.size(), 150 * cell_size + (50..100).sum::<u64>());
+        mem.delete_range(&all);
+        assert_eq!(mem.size(), 0);
+        mem.delete_range(&all);
+        assert_eq!(mem.size(), 0);
+    }
+
+    #[test]
+    fn test_field_hints() {
+        let all = TimeRange { min_ts: i64::MIN, max_ts: i64::MAX };
+        let mut mem = MemCache::new(0, 1024 * 1024, 0, false);
+        let mut hints = FieldHints::default();
+        hints.renew(0, 1);
+        fn put(mem: &mut MemCache, hints: &mut FieldHints, field_id: u64, ts: i64) {
+            mem.insert_raw_hinted(hints, 0, field_id, ts, ValueType::Integer, &ts.to_be_bytes())
+               .unwrap()
+        }
+        for ts in 1..=3 {
+            put(&mut mem, &mut hints, 1, ts);
+            put(&mut mem, &mut hints, 2, ts * 10);
+        }
+        assert_eq!(hints.get(1), Some(0));
+        assert_eq!(hints.get(2), Some(1));
+
+        // splitting the first field moves the second one, the stale hints are only misses
+        mem.split_field(1).unwrap();
+        put(&mut mem, &mut hints, 2, 40);
+        put(&mut mem, &mut hints, 1, 4);
+        assert_eq!(values(mem.read(1, &all)), vec![(4, 4)]);
+        assert_eq!(values(mem.read(2, &all)), vec![(10, 10), (20, 20), (30, 30), (40, 40)]);
+        assert_eq!(mem.summary().fields, 2);
+
+        // a new generation forgets the positions, the same one keeps them
+        hints.renew(0, 1);
+        assert!(hints.get(2).is_some());
+        hints.renew(1, 1);
+        assert_eq!((hints.get(1), hints.get(2)), (None, None));
+    }
+
+    #[test]
+    fn test_cache_summary_display() {
+        let summary = CacheSummary { tf_id: 1,
+                                     bytes: 4096,
+                                     fields: 2,
+                                     cells: 100,
+                                     ts_range: Some((1, 99)),
+                                     seq_range: (3, 7),
+                                     immutable: false };
+        assert_eq!(summary.to_string(),
+                   "tf_id=1 bytes=4096 fields=2 cells=100 ts_range=[1,99] seq_range=[3,7] \
+                    immutable=false");
+        let summary = CacheSummary { ts_range: None, immutable: true, ..summary };
+        assert_eq!(summary.to_string(),
+                   "tf_id=1 bytes=4096 fields=2 cells=100 ts_range=none seq_range=[3,7] \
+                    immutable=true");
+    }
+
+    #[test]
+    fn test_check_utf8() {
+        let invalid = b"ab\xffc";
+        let cases = [(Utf8Policy::Reject, None),
+                     (Utf8Policy::Replace, Some("ab\u{FFFD}c".as_bytes())),
+                     (Utf8Policy::Bytes, Some(&invalid[..]))];
+        for (policy, expected) in cases {
+            let checked = check_utf8(policy, ValueType::String, invalid).ok();
+            assert_eq!(checked.as_deref(), expected);
+            assert_eq!(check_utf8(policy, ValueType::String, b"abc").unwrap().as_ref(), b"abc");
+            // only strings are checked
+            assert_eq!(check_utf8(policy, ValueType::Unsigned, invalid).unwrap().as_ref(), invalid);
+        }
+
+        let cell = StrCell { ts: 1, val: b"abc".to_vec() };
+        assert_eq!(cell.as_str().unwrap(), "abc");
+        let cell = StrCell { ts: 1, val: invalid.to_vec() };
+        assert!(cell.as_str().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_hashmap_memcache() {
+        test_memcache_suite(MemCacheImpl::HashMap).await;
+    }
+
+    #[cfg(feature = "skiplist")]
+    #[tokio::test]
+    async fn test_skiplist_memcache() {
+        test_memcache_suite(MemCacheImpl::SkipList).await;
     }
 }
@@ -0,0 +1,193 @@
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::BinaryHeap,
+    iter::Peekable,
+};
+
+use crate::{kv_option::DuplicatePolicy, memcache::DataType};
+
+/// A point merged by `MergeStream`, ordered by its timestamp.
+pub trait Timestamped {
+    fn timestamp(&self) -> i64;
+}
+
+impl Timestamped for DataType {
+    fn timestamp(&self) -> i64 {
+        DataType::timestamp(self)
+    }
+}
+
+struct HeapItem<T> {
+    ts: i64,
+    // the order of items with the same timestamp
+    rank: usize,
+    priority: usize,
+    data: T,
+}
+
+impl<T> PartialEq for HeapItem<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.ts == other.ts && self.rank == other.rank
+    }
+}
+
+impl<T> Eq for HeapItem<T> {}
+
+impl<T> PartialOrd for HeapItem<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for HeapItem<T> {
+    // the smallest timestamp comes first, on the same timestamp the higher rank comes first.
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.ts.cmp(&other.ts).then_with(|| other.rank.cmp(&self.rank))
+    }
+}
+
+/// Streaming k-way merge of time-ordered sources.
+///
+/// Each source must yield points sorted by timestamp, `DataType` or any `Timestamped`. Sources
+/// later in the list have the higher priority (they hold the newer writes): when several sources
+/// yield the same timestamp, only the point from the source with the highest priority is
+/// returned, and inside one source the last point of the same timestamp wins.
+///
+/// With `DuplicatePolicy::KeepAll` no point is dropped, points of the same timestamp are
+/// returned in the order they were written (the source with the lower priority first).
+pub struct MergeStream<I: Iterator>
+    where I::Item: Timestamped
+{
+    sources: Vec<Peekable<I>>,
+    heap: BinaryHeap<Reverse<HeapItem<I::Item>>>,
+    duplicate_policy: DuplicatePolicy,
+}
+
+impl<I: Iterator> MergeStream<I> where I::Item: Timestamped
+{
+    pub fn new(sources: Vec<I>) -> Self {
+        Self::with_policy(sources, DuplicatePolicy::LastWins)
+    }
+
+    pub fn with_policy(sources: Vec<I>, duplicate_policy: DuplicatePolicy) -> Self {
+        let sources: Vec<Peekable<I>> = sources.into_iter().map(|s| s.peekable()).collect();
+        let heap = BinaryHeap::with_capacity(sources.len());
+        let mut stream = Self { sources, heap, duplicate_policy };
+        for priority in 0..stream.sources.len() {
+            stream.advance(priority);
+        }
+        stream
+    }
+
+    fn advance(&mut self, priority: usize) {
+        let source = &mut self.sources[priority];
+        let mut data = match source.next() {
+            Some(data) => data,
+            None => return,
+        };
+        let ts = data.timestamp();
+        let rank = match self.duplicate_policy {
+            DuplicatePolicy::LastWins => {
+                while let Some(next) = source.next_if(|d| d.timestamp() == ts) {
+                    data = next;
+                }
+                priority
+            },
+            DuplicatePolicy::KeepAll => self.sources.len() - 1 - priority,
+        };
+        self.heap.push(Reverse(HeapItem { ts, rank, priority, data }));
+    }
+}
+
+impl<I: Iterator> Iterator for MergeStream<I> where I::Item: Timestamped
+{
+    type Item = I::Item;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let Reverse(top) = self.heap.pop()?;
+        self.advance(top.priority);
+        if self.duplicate_policy == DuplicatePolicy::KeepAll {
+            return Some(top.data);
+        }
+        // drop the older points of the same timestamp
+        while let Some(Reverse(item)) = self.heap.peek() {
+            if item.ts != top.ts {
+                break;
+            }
+            let priority = item.priority;
+            self.heap.pop();
+            self.advance(priority);
+        }
+        Some(top.data)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use rand::{thread_rng, Rng};
+
+    use super::MergeStream;
+    use crate::{
+        kv_option::DuplicatePolicy,
+        memcache::{DataType, I64Cell},
+    };
+
+    fn cells(points: &[(i64, i64)]) -> Vec<DataType> {
+        points.iter().map(|(ts, val)| DataType::I64(I64Cell { ts: *ts, val: *val })).collect()
+    }
+
+    fn values(data: Vec<DataType>) -> Vec<(i64, i64)> {
+        data.into_iter()
+            .map(|d| match d {
+                DataType::I64(c) => (c.ts, c.val),
+                _ => panic!("unexpected data type"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_merge_stream() {
+        let sources = vec![cells(&[(1, 10), (2, 20), (3, 30), (4, 40), (5, 50)]).into_iter(),
+                           cells(&[(2, 12), (3, 13), (4, 15)]).into_iter(),
+                           cells(&[]).into_iter(),
+                           cells(&[(4, 24), (4, 25), (6, 26)]).into_iter()];
+        let res = values(MergeStream::new(sources).collect());
+        assert_eq!(res, vec![(1, 10), (2, 12), (3, 13), (4, 25), (5, 50), (6, 26)]);
+    }
+
+    #[test]
+    fn test_merge_stream_duplicate_policy() {
+        let workload = || {
+            vec![cells(&[(1, 10), (2, 20), (2, 21)]).into_iter(),
+                 cells(&[(2, 22), (3, 30)]).into_iter()]
+        };
+        let res = values(MergeStream::with_policy(workload(), DuplicatePolicy::LastWins).collect());
+        assert_eq!(res, vec![(1, 10), (2, 22), (3, 30)]);
+
+        let res = values(MergeStream::with_policy(workload(), DuplicatePolicy::KeepAll).collect());
+        assert_eq!(res, vec![(1, 10), (2, 20), (2, 21), (2, 22), (3, 30)]);
+    }
+
+    #[test]
+    fn test_merge_stream_random() {
+        let mut rng = thread_rng();
+        for _ in 0..20 {
+            let mut expected = BTreeMap::new();
+            let mut sources = vec![];
+            for _ in 0..rng.gen_range(1..16) {
+                let mut points: Vec<(i64, i64)> =
+                    (0..rng.gen_range(0..200)).map(|_| (rng.gen_range(0..500), rng.gen()))
+                                              .collect();
+                points.sort_by_key(|p| p.0);
+                for (ts, val) in points.iter() {
+                    expected.insert(*ts, *val);
+                }
+                sources.push(cells(&points).into_iter());
+            }
+            let expected: Vec<(i64, i64)> = expected.into_iter().collect();
+            assert_eq!(values(MergeStream::new(sources).collect()), expected);
+        }
+    }
+}
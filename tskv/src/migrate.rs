@@ -0,0 +1,383 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use logger::info;
+use models::FieldId;
+use serde::Serialize;
+use snafu::ResultExt;
+use tokio::sync::RwLock;
+
+use crate::{
+    compaction::LogEvent,
+    direct_io::{FileCursor, IoClass},
+    error::{self, Error, Result},
+    file_manager::get_file_manager,
+    kv_option::{BlockCompression, DuplicatePolicy},
+    summary::{CompactMeta, VersionEdit},
+    tseries_family::ColumnFile,
+    tsm::{
+        BlockReader, DataBlock, FileBlock, TsmBlockReader, TsmFooterReader, TsmIndexReader,
+        TsmWriter,
+    },
+    version_set::VersionSet,
+};
+
+/// The extension added to the name of a tsm file for its migrated copy, written next to it
+/// before it replaces the file.
+const MIGRATED_EXT: &str = "v2";
+
+/// What `migrate_file` does with a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MigrateOutcome {
+    /// The file is in the current format already, written so or migrated before.
+    Current,
+    /// The file is to be migrated, left as it is by a dry run.
+    Pending,
+    /// The file is rewritten in the current format, with the size of the new file.
+    Migrated(u64),
+}
+
+/// The files of a migration.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct MigrateReport {
+    // files in the current format already, left as they are
+    pub current: usize,
+    // files rewritten, or to be rewritten by a dry run
+    pub migrated: usize,
+    // the sizes of the migrated files before and after, the same after a dry run
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+}
+
+/// Returns true if the tsm file is written in the current format, with the fields and the
+/// features sections.
+pub fn is_current(path: impl AsRef<Path>) -> Result<bool> {
+    let file = get_file_manager().open_file(path)?;
+    let len = file.len() as usize;
+    Ok(TsmFooterReader::read_features(&mut file.into_cursor(), len)?.is_some())
+}
+
+/// Rewrites the tsm file in the current format unless it is already, the blocks are decoded
+/// and encoded again. The new file is written next to it with the `.v2` extension, compared
+/// with it field by field, then renamed over it; the file keeps its id, so its tombstones
+/// still apply. A copy left by an interrupted migration is written again.
+pub fn migrate_file(path: impl AsRef<Path>, dry_run: bool) -> Result<MigrateOutcome> {
+    let path = path.as_ref();
+    if is_current(path)? {
+        return Ok(MigrateOutcome::Current);
+    }
+    if dry_run {
+        return Ok(MigrateOutcome::Pending);
+    }
+    let dst = path.with_extension(MIGRATED_EXT);
+    let size = rewrite(path, &dst)?;
+    if let Err(e) = verify(path, &dst) {
+        let _ = fs::remove_file(&dst);
+        return Err(e);
+    }
+    fs::rename(&dst, path).context(error::IOSnafu)?;
+    Ok(MigrateOutcome::Migrated(size))
+}
+
+// writes the blocks of every field of `src` into the new file `dst`, returns its size
+fn rewrite(src: &Path, dst: &Path) -> Result<u64> {
+    let file = get_file_manager().open_file(src)?;
+    let len = file.len() as usize;
+    let mut cursor = file.into_cursor();
+    cursor.set_io_class(IoClass::Low);
+    let fields = read_index(&mut cursor, len)?;
+    let mut writer = TsmWriter::create(dst, IoClass::Low)?;
+    let mut reader = TsmBlockReader::new(&mut cursor);
+    for (field_id, blocks) in fields {
+        let mut chunks = Vec::with_capacity(blocks.len());
+        for block in blocks.iter() {
+            chunks.push(reader.decode(block)?);
+        }
+        writer.write_chunks(HashMap::from([(field_id, chunks)]), BlockCompression::Fast)?;
+    }
+    writer.finish()
+}
+
+// returns an error unless both files hold the same fields with the same points, the blocks
+// of a field may be split differently
+fn verify(src: &Path, dst: &Path) -> Result<()> {
+    let (src_fields, dst_fields) = (read_fields(src)?, read_fields(dst)?);
+    let mismatch =
+        |field_id: FieldId| Error::MigrationMismatch { path: src.display().to_string(), field_id };
+    for (field_id, block) in src_fields.iter() {
+        match dst_fields.get(field_id) {
+            Some(other) if other.eq_bits(block) => {},
+            _ => return Err(mismatch(*field_id)),
+        }
+    }
+    match dst_fields.keys().find(|id| !src_fields.contains_key(id)) {
+        Some(field_id) => Err(mismatch(*field_id)),
+        None => Ok(()),
+    }
+}
+
+// the points of every field of the file, in a block each
+fn read_fields(path: &Path) -> Result<HashMap<FieldId, DataBlock>> {
+    let file = get_file_manager().open_file(path)?;
+    let len = file.len() as usize;
+    let mut cursor = file.into_cursor();
+    cursor.set_io_class(IoClass::Low);
+    let fields = read_index(&mut cursor, len)?;
+    let mut reader = TsmBlockReader::new(&mut cursor);
+    let mut res = HashMap::with_capacity(fields.len());
+    for (field_id, blocks) in fields {
+        let field_type = blocks[0].field_type;
+        let mut decoded = Vec::with_capacity(blocks.len());
+        for block in blocks.iter() {
+            decoded.push(reader.decode(block)?);
+        }
+        res.insert(field_id,
+                   DataBlock::merge_blocks_with(decoded, field_type, DuplicatePolicy::KeepAll));
+    }
+    Ok(res)
+}
+
+// the blocks of every field in the order of the index
+fn read_index(cursor: &mut FileCursor, len: usize) -> Result<Vec<(FieldId, Vec<FileBlock>)>> {
+    let mut fields: Vec<(FieldId, Vec<FileBlock>)> = vec![];
+    for entry in TsmIndexReader::try_new(cursor, len)? {
+        let entry = entry?;
+        let field_id = entry.field_id();
+        match fields.last_mut() {
+            Some((id, blocks)) if *id == field_id => blocks.push(entry.block),
+            _ => fields.push((field_id, vec![entry.block])),
+        }
+    }
+    Ok(fields)
+}
+
+/// Migrates the live files of every tseries family with `migrate_file`, returns the report
+/// and an edit for each tseries family replacing the metas of its files whose size in the
+/// version differs from the size on disk. A migration resumed after an interruption skips
+/// the files migrated before and still corrects their sizes; a dry run writes nothing and
+/// returns no edit.
+pub async fn migrate_version_set(version_set: &RwLock<VersionSet>,
+                                 dry_run: bool)
+                                 -> Result<(MigrateReport, Vec<VersionEdit>)> {
+    // the file, its meta, and the sequence and the max level timestamp of its version
+    let mut files: Vec<(Arc<ColumnFile>, CompactMeta, u64, i64)> = vec![];
+    for tsf in version_set.read().await.tsfamilies() {
+        let version = tsf.version().read().await;
+        for info in version.levels_info.iter() {
+            for file in info.files.iter().filter(|f| !f.is_deleted()) {
+                let meta = CompactMeta { file_id: file.file_id(),
+                                         file_size: file.size(),
+                                         range: *file.range(),
+                                         tsf_id: tsf.tf_id(),
+                                         level: info.level,
+                                         is_delta: file.is_delta(),
+                                         ..CompactMeta::new() };
+                files.push((file.clone(), meta, version.last_seq, version.max_level_ts));
+            }
+        }
+    }
+
+    let migrate = move || -> Result<(MigrateReport, Vec<VersionEdit>)> {
+        let mut report = MigrateReport::default();
+        let mut edits: HashMap<u32, VersionEdit> = HashMap::new();
+        for (file, meta, last_seq, max_level_ts) in files {
+            let path = PathBuf::from(file.path(meta.tsf_id));
+            let size = fs::metadata(&path).context(error::IOSnafu)?.len();
+            match migrate_file(&path, dry_run)? {
+                MigrateOutcome::Current => report.current += 1,
+                MigrateOutcome::Pending => {
+                    report.migrated += 1;
+                    report.bytes_before += size;
+                    report.bytes_after += size;
+                },
+                MigrateOutcome::Migrated(new_size) => {
+                    info!("{}",
+                          LogEvent::new("migrate_file").field("tf_id", meta.tsf_id)
+                                                       .field("file_id", meta.file_id)
+                                                       .field("bytes_before", size)
+                                                       .field("bytes_after", new_size));
+                    report.migrated += 1;
+                    report.bytes_before += size;
+                    report.bytes_after += new_size;
+                },
+            }
+            if dry_run {
+                continue;
+            }
+            let size = fs::metadata(&path).context(error::IOSnafu)?.len();
+            if size == meta.file_size {
+                continue;
+            }
+            let edit = edits.entry(meta.tsf_id).or_insert_with(VersionEdit::new);
+            let new_meta = CompactMeta { file_size: size, ..meta.clone() };
+            edit.del_file(meta.tsf_id, meta.clone());
+            edit.add_file(meta.level, meta.tsf_id, meta.file_id, last_seq, max_level_ts, new_meta);
+        }
+        Ok((report, edits.into_values().collect()))
+    };
+    tokio::task::spawn_blocking(migrate).await.expect("migration panicked")
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::HashMap, sync::Arc};
+
+    use tokio::sync::RwLock;
+
+    use super::{is_current, migrate_file, migrate_version_set, read_fields, MigrateOutcome};
+    use crate::{
+        compaction::build_tsm_file,
+        direct_io::FileSync,
+        file_manager::get_file_manager,
+        file_utils::make_tsm_file_name,
+        kv_option::{TseriesFamDesc, TseriesFamOpt},
+        memcache::DataType,
+        summary::CompactMeta,
+        tseries_family::{LevelInfo, TimeRange, Version},
+        tsm::{DataBlock, TsmBlockWriter, TsmFooterWriter, TsmHeaderWriter, TsmIndexWriter},
+        version_set::VersionSet,
+    };
+
+    // writes a tsm file without the fields and the features sections, like the writers before
+    // them
+    fn write_legacy_file(path: &str, block_set: HashMap<u64, DataBlock>) {
+        let file = get_file_manager().create_file(path).unwrap();
+        let mut fs_cursor = file.into_cursor();
+        TsmHeaderWriter::write_to(&mut fs_cursor).unwrap();
+        let index = TsmBlockWriter::write_to(&mut fs_cursor, block_set).unwrap();
+        let index_pos = fs_cursor.pos();
+        let bloom_filter = TsmIndexWriter::write_to(&mut fs_cursor, index).unwrap();
+        TsmFooterWriter::write_to(&mut fs_cursor, &bloom_filter, index_pos).unwrap();
+        fs_cursor.sync_all(FileSync::Hard).unwrap();
+    }
+
+    fn block_set() -> HashMap<u64, DataBlock> {
+        HashMap::from([(1,
+                        DataBlock::I64 { index: 0,
+                                         ts: (0..2500).collect(),
+                                         val: (0..2500).collect(),
+                                         validity: None }),
+                       (2,
+                        DataBlock::F64 { index: 0,
+                                         ts: vec![1, 2, 3],
+                                         val: vec![f64::NAN, -0.0, 1.5],
+                                         validity: None }),
+                       (3,
+                        DataBlock::Str { index: 0,
+                                         ts: vec![1, 2],
+                                         val: vec![b"a".to_vec(), vec![]],
+                                         validity: None }),
+                       (4,
+                        DataBlock::Bool { index: 0,
+                                          ts: vec![5],
+                                          val: vec![true],
+                                          validity: None })])
+    }
+
+    #[test]
+    fn test_migrate_file() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("_000001.tsm").to_string_lossy().to_string();
+        write_legacy_file(&path, block_set());
+        let before = read_fields(path.as_ref()).unwrap();
+        assert!(!is_current(&path).unwrap());
+
+        assert_eq!(migrate_file(&path, true).unwrap(), MigrateOutcome::Pending);
+        assert!(!is_current(&path).unwrap());
+
+        // a copy left by an interrupted migration
+        std::fs::write(tmp.path().join("_000001.v2"), b"partial").unwrap();
+        let size = match migrate_file(&path, false).unwrap() {
+            MigrateOutcome::Migrated(size) => size,
+            res => panic!("unexpected {:?}", res),
+        };
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), size);
+        assert!(!tmp.path().join("_000001.v2").exists());
+        assert!(is_current(&path).unwrap());
+        let after = read_fields(path.as_ref()).unwrap();
+        assert_eq!(after.len(), 4);
+        assert!(before.iter().all(|(id, block)| after[id].eq_bits(block)));
+
+        // a migrated file is skipped
+        assert_eq!(migrate_file(&path, false).unwrap(), MigrateOutcome::Current);
+    }
+
+    #[tokio::test]
+    async fn test_migrate_version_set() {
+        let tmp = tempfile::tempdir().unwrap();
+        let tf_id = 121;
+        let opt = TseriesFamOpt::for_testing(tmp.path());
+        let dir = opt.tsm_dir(tf_id);
+        std::fs::create_dir_all(&dir).unwrap();
+        let legacy = make_tsm_file_name(&dir, 1).to_string_lossy().to_string();
+        write_legacy_file(&legacy, block_set());
+        let current = make_tsm_file_name(&dir, 2);
+        build_tsm_file(current.clone(), block_set()).unwrap();
+        let meta = |file_id: u64, path: &str| {
+            let file_size = std::fs::metadata(path).unwrap().len();
+            CompactMeta { file_id,
+                          file_size,
+                          range: TimeRange::new(2499, 0),
+                          tsf_id: tf_id,
+                          level: 1,
+                          ..Default::default() }
+        };
+        let (legacy_meta, current_meta) = (meta(1, &legacy), meta(2, &current.to_string_lossy()));
+        let mut lvl = LevelInfo::init_in(&opt.base_dir, 1);
+        lvl.apply(&legacy_meta);
+        lvl.apply(&current_meta);
+        // the points of field 1 from 100 to 199 are deleted
+        lvl.files[0].add_tombstone(tf_id, 1, &[1], &TimeRange::new(199, 100)).unwrap();
+        let read = |lvl: &LevelInfo| -> Vec<(i64, i64)> {
+            lvl.files[0].read_field(tf_id, 1, &TimeRange::new(i64::MAX, i64::MIN))
+                        .unwrap()
+                        .into_iter()
+                        .map(|d| match d {
+                            DataType::I64(cell) => (cell.ts, cell.val),
+                            d => panic!("unexpected {:?}", d),
+                        })
+                        .collect()
+        };
+        let before = read(&lvl);
+        assert_eq!(before.len(), 2400);
+
+        let version = Version::new(tf_id, 7, "db".to_string(), vec![lvl], 0);
+        let desc = [TseriesFamDesc { name: "db".to_string(), opt: opt.clone() }];
+        let version_set = VersionSet::new(&desc,
+                                          HashMap::from([(tf_id, Arc::new(RwLock::new(version)))]),
+                                          vec![]).await;
+        let version_set = RwLock::new(version_set);
+
+        let (report, edits) = migrate_version_set(&version_set, true).await.unwrap();
+        assert_eq!((report.current, report.migrated), (1, 1));
+        assert_eq!(report.bytes_before, legacy_meta.file_size);
+        assert!(edits.is_empty());
+        assert!(!is_current(&legacy).unwrap());
+
+        let (report, edits) = migrate_version_set(&version_set, false).await.unwrap();
+        assert_eq!((report.current, report.migrated), (1, 1));
+        assert!(is_current(&legacy).unwrap());
+        let new_size = std::fs::metadata(&legacy).unwrap().len();
+        assert_eq!(report.bytes_after, new_size);
+        assert_eq!(edits.len(), 1);
+        assert_eq!(edits[0].del_files, vec![legacy_meta.clone()]);
+        assert_eq!(edits[0].add_files,
+                   vec![CompactMeta { file_size: new_size, ..legacy_meta.clone() }]);
+        assert_eq!(edits[0].seq_no, 7);
+
+        // the migrated file reads the same, its tombstones included
+        let mut lvl = LevelInfo::init_in(&opt.base_dir, 1);
+        lvl.apply(&edits[0].add_files[0]);
+        assert_eq!(read(&lvl), before);
+
+        // resumed, the sizes still stale in the version are corrected, nothing is rewritten
+        let (report, edits) = migrate_version_set(&version_set, false).await.unwrap();
+        assert_eq!((report.current, report.migrated), (2, 0));
+        assert_eq!(edits.len(), 1);
+        assert_eq!(edits[0].add_files[0].file_size, new_size);
+    }
+}
@@ -12,3 +12,8 @@ pub fn open_file(path: &Path) -> Result<direct_io::File> {
                                                                           .write(true)
                                                                           .create(true))
 }
+
+/// Opens the file for reading only, returns an error if it does not exist.
+pub fn open_file_read_only(path: &Path) -> Result<direct_io::File> {
+    file_manager::get_file_manager().open_file_with(path, fs::OpenOptions::new().read(true))
+}
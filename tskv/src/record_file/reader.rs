@@ -28,7 +28,15 @@ pub struct Reader {
 
 impl Reader {
     pub fn new(path: &Path) -> Self {
-        let file = open_file(path).unwrap();
+        Self::with_file(path, open_file(path).unwrap())
+    }
+
+    /// Opens the file for reading only, returns an error if it does not exist.
+    pub fn open(path: &Path) -> crate::Result<Self> {
+        Ok(Self::with_file(path, open_file_read_only(path)?))
+    }
+
+    fn with_file(path: &Path, file: File) -> Self {
         let mut buf = Vec::<u8>::new();
         buf.resize(READER_BUF_SIZE, 0);
         Reader { path: path.to_path_buf(),
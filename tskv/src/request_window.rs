@@ -0,0 +1,99 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+
+use crate::kv_option::RequestWindowConfig;
+
+/// The ids of the recent write requests, so that a request retried by a client is applied
+/// once. An id is forgotten when `size` newer ids are added or its ttl is over.
+#[derive(Debug)]
+pub struct RequestWindow {
+    size: usize,
+    ttl: Duration,
+    inner: Mutex<WindowInner>,
+}
+
+#[derive(Debug, Default)]
+struct WindowInner {
+    // ordered from the oldest to the newest
+    queue: VecDeque<(u64, Instant)>,
+    ids: HashSet<u64>,
+}
+
+impl WindowInner {
+    fn expire(&mut self, size: usize, ttl: Duration, now: Instant) {
+        while let Some((id, added)) = self.queue.front() {
+            if self.queue.len() <= size && now.duration_since(*added) < ttl {
+                break;
+            }
+            self.ids.remove(id);
+            self.queue.pop_front();
+        }
+    }
+}
+
+impl RequestWindow {
+    pub fn new(config: &RequestWindowConfig) -> Self {
+        Self { size: config.size,
+               ttl: Duration::from_secs(config.ttl_secs),
+               inner: Mutex::new(WindowInner::default()) }
+    }
+
+    /// Adds the id, returns false if it is already in the window.
+    pub fn insert(&self, request_id: u64) -> bool {
+        let now = Instant::now();
+        let mut inner = self.inner.lock();
+        inner.expire(self.size, self.ttl, now);
+        if !inner.ids.insert(request_id) {
+            return false;
+        }
+        inner.queue.push_back((request_id, now));
+        inner.expire(self.size, self.ttl, now);
+        true
+    }
+
+    /// Removes the id of a request that failed, so that its retry is applied.
+    pub fn remove(&self, request_id: u64) {
+        let mut inner = self.inner.lock();
+        if inner.ids.remove(&request_id) {
+            inner.queue.retain(|(id, _)| *id != request_id);
+        }
+    }
+
+    pub fn contains(&self, request_id: u64) -> bool {
+        let mut inner = self.inner.lock();
+        inner.expire(self.size, self.ttl, Instant::now());
+        inner.ids.contains(&request_id)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{thread, time::Duration};
+
+    use super::RequestWindow;
+    use crate::kv_option::RequestWindowConfig;
+
+    #[test]
+    fn test_request_window() {
+        let window = RequestWindow::new(&RequestWindowConfig { size: 2, ttl_secs: 600 });
+        assert!(window.insert(1));
+        assert!(!window.insert(1));
+        assert!(window.insert(2));
+        assert!(window.insert(3));
+        // the oldest id is forgotten
+        assert!(!window.contains(1));
+        assert!(window.contains(2) && window.contains(3));
+
+        window.remove(2);
+        assert!(window.insert(2));
+
+        let window = RequestWindow::new(&RequestWindowConfig { size: 2, ttl_secs: 1 });
+        assert!(window.insert(1));
+        thread::sleep(Duration::from_millis(1100));
+        assert!(window.insert(1));
+    }
+}
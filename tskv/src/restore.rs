@@ -0,0 +1,75 @@
+use std::{fs, path::Path};
+
+use logger::warn;
+use snafu::ResultExt;
+
+use crate::{
+    error, file_manager,
+    file_utils::{
+        make_delta_dir, make_delta_file_name, make_tsm_dir, make_tsm_file_name,
+        make_tsm_tombstone_file_name,
+    },
+    summary::{CompactMeta, Summary, VersionEdit},
+    wal::WalManager,
+    Result,
+};
+
+/// Makes the store restored to the wal sequence `end_seq` the current one: the summary undoes
+/// the edits after the end, the files they added are moved into the quarantine directory, the
+/// deletes after the end are dropped from the tombstones and the wal entries after it are
+/// removed.
+///
+/// The undoing edits get the next sequence of the wal, so the sequences never go back.
+pub async fn confirm(summary: &mut Summary,
+                     wal_manager: &mut WalManager,
+                     mut undo: Vec<VersionEdit>,
+                     end_seq: u64,
+                     quarantine_dir: &str)
+                     -> Result<()> {
+    let seq = wal_manager.current_seq_no();
+    for edit in undo.iter_mut() {
+        edit.has_seq_no = true;
+        edit.seq_no = seq;
+    }
+    summary.apply_version_edit(&undo).await?;
+
+    let version_set = summary.version_set();
+    let version_set = version_set.read().await;
+    for tsf in version_set.tsfamilies() {
+        let tf_id = tsf.tf_id();
+        let version = tsf.version().read().await;
+        for meta in undo.iter().filter(|e| e.tsf_id == tf_id).flat_map(|e| e.del_files.iter()) {
+            if let Err(e) = quarantine(&version.base_dir, tf_id, meta, quarantine_dir) {
+                warn!("failed to quarantine file {} restored away: {:?}", meta.file_id, e);
+            }
+        }
+        for file in version.levels_info.iter().flat_map(|info| info.files.iter()) {
+            file.retain_tombstones(tf_id)?;
+        }
+    }
+    wal_manager.truncate(end_seq)
+}
+
+// moves a file added after the end of the restore and its tombstones into the quarantine
+// directory
+fn quarantine(base_dir: &str, tf_id: u32, meta: &CompactMeta, quarantine_dir: &str) -> Result<()> {
+    let dir = Path::new(quarantine_dir).join(tf_id.to_string());
+    fs::create_dir_all(&dir).context(error::IOSnafu)?;
+    let (file_dir, path) = if meta.is_delta {
+        let file_dir = make_delta_dir(base_dir, tf_id).to_string_lossy().to_string();
+        let path = make_delta_file_name(&file_dir, meta.file_id);
+        (file_dir, path)
+    } else {
+        let file_dir = make_tsm_dir(base_dir, tf_id).to_string_lossy().to_string();
+        let path = make_tsm_file_name(&file_dir, meta.file_id);
+        (file_dir, path)
+    };
+    for path in [path, make_tsm_tombstone_file_name(&file_dir, meta.file_id)] {
+        if !file_manager::try_exists(&path) {
+            continue;
+        }
+        let name = path.file_name().expect("a column file has a file name");
+        fs::rename(&path, dir.join(name)).context(error::IOSnafu)?;
+    }
+    Ok(())
+}
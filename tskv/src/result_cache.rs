@@ -0,0 +1,195 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use models::FieldId;
+use parking_lot::Mutex;
+
+use crate::{lru_cache::CacheStats, memcache::DataType, tseries_family::TimeRange};
+
+/// The points returned by the latest scans of the fields of a tseries family, served again to
+/// the identical scans within `ttl`, for the dashboards reading the current values of the same
+/// series over and over. A write into a field drops its scans, a new version drops them all.
+///
+/// A scan is kept only if nothing is dropped while it reads, see `epoch`.
+pub struct ResultCache {
+    // None if the capacity is 0
+    inner: Option<Mutex<Scans>>,
+    ttl: Duration,
+}
+
+struct Scans {
+    fields: HashMap<FieldId, Vec<CachedScan>>,
+    count: usize,
+    cap: usize,
+    // bumped by every drop
+    epoch: u64,
+    stats: CacheStats,
+}
+
+struct CachedScan {
+    range: TimeRange,
+    data: Vec<DataType>,
+    at: Instant,
+}
+
+impl ResultCache {
+    /// A cache of at most `capacity` scans, disabled if `capacity` is 0.
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        let scans = Scans { fields: HashMap::new(),
+                            count: 0,
+                            cap: capacity,
+                            epoch: 0,
+                            stats: CacheStats::default() };
+        Self { inner: (capacity > 0).then(|| Mutex::new(scans)), ttl }
+    }
+
+    /// Returns the points of the scan of the field in the time range kept less than `ttl`
+    /// before `now`.
+    pub fn get(&self, field_id: FieldId, range: &TimeRange, now: Instant) -> Option<Vec<DataType>> {
+        let mut scans = self.inner.as_ref()?.lock();
+        let ttl = self.ttl;
+        let data = scans.fields
+                        .get(&field_id)
+                        .and_then(|s| s.iter().find(|s| s.range == *range))
+                        .filter(|s| now.saturating_duration_since(s.at) < ttl)
+                        .map(|s| s.data.clone());
+        match data {
+            Some(_) => scans.stats.hits += 1,
+            None => scans.stats.misses += 1,
+        }
+        data
+    }
+
+    /// Returns the count of the drops, taken before a scan reads and handed to `insert`.
+    pub fn epoch(&self) -> u64 {
+        self.inner.as_ref().map(|scans| scans.lock().epoch).unwrap_or(0)
+    }
+
+    /// Keeps the points of a scan read at `now`, unless a drop happened since `epoch` was taken
+    /// as the points may miss it then. The oldest scan makes room if the cache is full.
+    pub fn insert(&self,
+                  field_id: FieldId,
+                  range: &TimeRange,
+                  data: &[DataType],
+                  epoch: u64,
+                  now: Instant) {
+        let mut scans = match self.inner.as_ref() {
+            Some(scans) => scans.lock(),
+            None => return,
+        };
+        if scans.epoch != epoch {
+            scans.stats.rejected += 1;
+            return;
+        }
+        scans.remove(field_id, range);
+        if scans.count >= scans.cap {
+            scans.evict_oldest();
+        }
+        let scan = CachedScan { range: *range, data: data.to_vec(), at: now };
+        scans.fields.entry(field_id).or_default().push(scan);
+        scans.count += 1;
+        scans.stats.admitted += 1;
+    }
+
+    /// Drops the scans of the field, on a write or a delete.
+    pub fn invalidate(&self, field_id: FieldId) {
+        if let Some(scans) = self.inner.as_ref() {
+            let mut scans = scans.lock();
+            scans.epoch += 1;
+            if let Some(removed) = scans.fields.remove(&field_id) {
+                scans.count -= removed.len();
+            }
+        }
+    }
+
+    /// Drops every scan, on a new version or a delete of every field.
+    pub fn clear(&self) {
+        if let Some(scans) = self.inner.as_ref() {
+            let mut scans = scans.lock();
+            scans.epoch += 1;
+            scans.fields.clear();
+            scans.count = 0;
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.inner.as_ref().map(|scans| scans.lock().stats).unwrap_or_default()
+    }
+}
+
+impl Scans {
+    fn remove(&mut self, field_id: FieldId, range: &TimeRange) {
+        if let Some(scans) = self.fields.get_mut(&field_id) {
+            let len = scans.len();
+            scans.retain(|s| s.range != *range);
+            self.count -= len - scans.len();
+            if scans.is_empty() {
+                self.fields.remove(&field_id);
+            }
+        }
+    }
+
+    // the cache is small, the oldest scan is looked up among all of them
+    fn evict_oldest(&mut self) {
+        let oldest =
+            self.fields
+                .iter()
+                .flat_map(|(field_id, scans)| scans.iter().map(move |s| (s.at, *field_id, s.range)))
+                .min_by_key(|(at, ..)| *at);
+        if let Some((_, field_id, range)) = oldest {
+            self.remove(field_id, &range);
+            self.stats.evicted += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use super::ResultCache;
+    use crate::{memcache::DataType, tseries_family::TimeRange, DataCell};
+
+    #[test]
+    fn test_result_cache() {
+        let cache = ResultCache::new(2, Duration::from_millis(100));
+        let now = Instant::now();
+        let data = vec![DataType::I64(DataCell { ts: 1, val: 1 })];
+        let (range, other) = (TimeRange::new(10, 1), TimeRange::new(20, 1));
+
+        let epoch = cache.epoch();
+        cache.insert(1, &range, &data, epoch, now);
+        assert_eq!(cache.get(1, &range, now + Duration::from_millis(99)).unwrap().len(), 1);
+        assert!(cache.get(1, &other, now).is_none());
+        assert!(cache.get(2, &range, now).is_none());
+        // expired
+        assert!(cache.get(1, &range, now + Duration::from_millis(100)).is_none());
+
+        // a scan reading while the field is written is not kept
+        let epoch = cache.epoch();
+        cache.invalidate(1);
+        assert!(cache.get(1, &range, now).is_none());
+        cache.insert(1, &range, &data, epoch, now);
+        assert!(cache.get(1, &range, now).is_none());
+
+        // the oldest scan makes room
+        let epoch = cache.epoch();
+        cache.insert(1, &range, &data, epoch, now);
+        cache.insert(1, &other, &data, epoch, now + Duration::from_millis(1));
+        cache.insert(2, &range, &data, epoch, now + Duration::from_millis(2));
+        assert!(cache.get(1, &range, now).is_none());
+        assert!(cache.get(1, &other, now).is_some());
+        assert!(cache.get(2, &range, now).is_some());
+
+        cache.clear();
+        assert!(cache.get(2, &range, now).is_none());
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.admitted, stats.rejected, stats.evicted), (3, 4, 1, 1));
+
+        let disabled = ResultCache::new(0, Duration::from_millis(100));
+        disabled.insert(1, &range, &data, disabled.epoch(), now);
+        assert!(disabled.get(1, &range, now).is_none());
+    }
+}
@@ -0,0 +1,464 @@
+use std::{
+    fs,
+    path::Path,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use logger::{info, warn};
+use models::FieldId;
+use snafu::ResultExt;
+use tokio::sync::{mpsc::UnboundedSender, RwLock};
+
+use crate::{
+    compaction::{FlushQueue, LogEvent},
+    direct_io::{io_scheduler, IoClass},
+    error,
+    file_manager::get_file_manager,
+    kv_option::{CorruptionPolicy, ScrubConfig},
+    summary::{self, CompactMeta, ScrubEdit, SummaryTask, VersionEdit},
+    tseries_family::{ColumnFile, TimeRange},
+    tsm::{TsmFooterReader, TsmIndexReader, TsmReader},
+    version_set::VersionSet,
+    Error, Result,
+};
+
+// how long the scrubber sleeps before it checks the write pressure again
+const PRESSURE_POLL: Duration = Duration::from_millis(100);
+
+/// Returns true while a flush is queued in the flush queue of the store or a high priority
+/// write is pending, the scrubber reads nothing then.
+pub fn under_write_pressure(flush_queue: &FlushQueue) -> bool {
+    io_scheduler().is_high_pending() || !flush_queue.is_empty()
+}
+
+/// Paces the reads of the scrubber to a rate of bytes per second, and pauses them while
+/// `pressure` returns true.
+pub struct Throttle {
+    bytes_per_sec: u64,
+    pressure: Box<dyn Fn() -> bool + Send + Sync>,
+    start: Instant,
+    bytes: u64,
+}
+
+impl Throttle {
+    pub fn new(bytes_per_sec: u64, pressure: impl Fn() -> bool + Send + Sync + 'static) -> Self {
+        Self { bytes_per_sec, pressure: Box::new(pressure), start: Instant::now(), bytes: 0 }
+    }
+
+    /// Waits before `bytes` more bytes are read.
+    pub fn wait(&mut self, bytes: u64) {
+        while (self.pressure)() {
+            thread::sleep(PRESSURE_POLL);
+            // the time paused is not credited to the rate
+            self.start = Instant::now();
+            self.bytes = 0;
+        }
+        if self.bytes_per_sec > 0 {
+            let due = Duration::from_secs_f64(self.bytes as f64 / self.bytes_per_sec as f64);
+            if let Some(early) = due.checked_sub(self.start.elapsed()) {
+                thread::sleep(early);
+            }
+        }
+        self.bytes += bytes;
+    }
+}
+
+/// The blocks and bytes verified by a scrub.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ScrubStats {
+    pub files: usize,
+    pub blocks: usize,
+    pub bytes: u64,
+}
+
+/// A file whose checksums do not match, or that cannot be read.
+#[derive(Debug)]
+pub struct CorruptFile {
+    pub tf_id: u32,
+    pub file_id: u64,
+    pub error: Error,
+}
+
+/// A block whose timestamps fall out of the time range of its index entry, the reads relying
+/// on the index miss its points out of it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RangeMismatch {
+    pub field_id: FieldId,
+    pub offset: u64,
+    pub meta: TimeRange,
+    pub decoded: TimeRange,
+}
+
+/// A file with blocks out of the time range of their index entries, it is rewritten by the
+/// next compaction.
+#[derive(Debug)]
+pub struct MismatchedFile {
+    pub tf_id: u32,
+    pub file_id: u64,
+    pub blocks: Vec<RangeMismatch>,
+}
+
+/// The result of a scrub pass.
+#[derive(Debug, Default)]
+pub struct ScrubReport {
+    pub stats: ScrubStats,
+    pub corrupt: Vec<CorruptFile>,
+    pub mismatched: Vec<MismatchedFile>,
+}
+
+/// Verifies the checksums of every block of the tsm file, the blocks are read with low
+/// priority after waiting for the throttle. Every field of the index must also be in the bloom
+/// filter of the footer, or its reads would miss the file. Only the timestamps are decoded,
+/// the blocks out of the time range of their index entries are returned.
+pub fn verify_file(path: impl AsRef<Path>,
+                   throttle: &mut Throttle,
+                   stats: &mut ScrubStats)
+                   -> Result<Vec<RangeMismatch>> {
+    let file = get_file_manager().open_file(path)?;
+    let len = file.len() as usize;
+    let mut fs_cursor = file.into_cursor();
+    fs_cursor.set_io_class(IoClass::Low);
+    let mut entries = vec![];
+    for entry in TsmIndexReader::try_new(&mut fs_cursor, len)? {
+        entries.push(entry?);
+    }
+    let bloom_filter = TsmFooterReader::read_bloom_filter(&mut fs_cursor, len)?;
+    if let Some(entry) =
+        entries.iter().find(|e| !bloom_filter.contains(&e.field_id().to_be_bytes()[..]))
+    {
+        return Err(Error::MissingFromBloomFilter { field_id: entry.field_id() });
+    }
+    let mut reader = TsmReader::new(&mut fs_cursor, len);
+    let mut mismatches = vec![];
+    for entry in entries {
+        throttle.wait(entry.block.size);
+        let block = reader.read_raw_block(&entry.block)?;
+        if crc32fast::hash(&block.ts) != block.ts_crc
+           || crc32fast::hash(&block.val) != block.val_crc
+        {
+            return Err(Error::ChecksumMismatch { field_id: entry.field_id(),
+                                                 offset: entry.block.offset });
+        }
+        let ts = block.timestamps()?;
+        if let (Some(min_ts), Some(max_ts)) = (ts.iter().min(), ts.iter().max()) {
+            if *min_ts < entry.block.min_ts || *max_ts > entry.block.max_ts {
+                let meta = TimeRange::new(entry.block.max_ts, entry.block.min_ts);
+                mismatches.push(RangeMismatch { field_id: entry.field_id(),
+                                                offset: entry.block.offset,
+                                                meta,
+                                                decoded: TimeRange::new(*max_ts, *min_ts) });
+            }
+        }
+        stats.blocks += 1;
+        stats.bytes += entry.block.size;
+    }
+    stats.files += 1;
+    Ok(mismatches)
+}
+
+/// Verifies the live files of every tseries family not verified for `interval_secs` at `now`,
+/// in seconds since the epoch, the least recently verified first. A corrupt file is marked,
+/// reported and handled by the corruption policy; the results are written to the summary.
+/// A file with blocks out of their index ranges is not corrupt, it is marked for the next
+/// compaction to rewrite it. The files picked by a compaction are left to a later scrub.
+pub async fn scrub_once(version_set: &RwLock<VersionSet>,
+                        summary_task_sender: &UnboundedSender<SummaryTask>,
+                        config: &ScrubConfig,
+                        now: u64,
+                        mut throttle: Throttle)
+                        -> Result<ScrubReport> {
+    let mut files: Vec<(u32, Arc<ColumnFile>)> = vec![];
+    for tsf in version_set.read().await.tsfamilies() {
+        let version = tsf.version().read().await;
+        for file in version.levels_info.iter().flat_map(|info| info.files.iter()) {
+            if !file.is_deleted()
+               && !file.is_pending_compaction()
+               && !file.is_corrupt()
+               && now.saturating_sub(file.scrubbed_at()) >= config.interval_secs
+            {
+                files.push((tsf.tf_id(), file.clone()));
+            }
+        }
+    }
+    files.sort_by_key(|(_, file)| file.scrubbed_at());
+
+    let verify = move || {
+        let mut stats = ScrubStats::default();
+        let mut results = Vec::with_capacity(files.len());
+        for (tf_id, file) in files {
+            let res = verify_file(file.path(tf_id), &mut throttle, &mut stats);
+            results.push((tf_id, file, res));
+        }
+        (stats, results)
+    };
+    let (stats, results) = tokio::task::spawn_blocking(verify).await.expect("scrub panicked");
+
+    let mut report = ScrubReport { stats, ..Default::default() };
+    let mut edits = vec![];
+    let mut scrub_edits = vec![];
+    for (tf_id, file, res) in results {
+        // a file compacted away meanwhile is gone, not corrupt
+        if file.is_deleted() {
+            continue;
+        }
+        let corrupt = res.is_err();
+        scrub_edits.push(ScrubEdit { tsf_id: tf_id,
+                                     file_id: file.file_id(),
+                                     scrubbed_at: now,
+                                     corrupt });
+        file.set_scrubbed_at(now);
+        let error = match res {
+            Ok(blocks) if blocks.is_empty() => continue,
+            Ok(blocks) => {
+                file.mark_range_mismatch();
+                warn!("{}",
+                      LogEvent::new("scrub_range_mismatch").field("tf_id", tf_id)
+                                                           .field("file_id", file.file_id())
+                                                           .field("blocks", blocks.len()));
+                report.mismatched.push(MismatchedFile { tf_id, file_id: file.file_id(), blocks });
+                continue;
+            },
+            Err(e) => e,
+        };
+        file.mark_corrupt();
+        warn!("{}",
+              LogEvent::new("scrub_corrupt").field("tf_id", tf_id)
+                                            .field("file_id", file.file_id())
+                                            .field("error", &error));
+        if config.corruption_policy == CorruptionPolicy::Quarantine {
+            match quarantine(tf_id, &file, &config.quarantine_dir) {
+                Ok(edit) => edits.push(edit),
+                Err(e) => warn!("failed to quarantine file {}: {:?}", file.file_id(), e),
+            }
+        }
+        report.corrupt.push(CorruptFile { tf_id, file_id: file.file_id(), error });
+    }
+    info!("{}",
+          LogEvent::new("scrub_done").field("files", report.stats.files)
+                                     .field("bytes", report.stats.bytes)
+                                     .field("corrupt", report.corrupt.len())
+                                     .field("mismatched", report.mismatched.len()));
+    if !scrub_edits.is_empty() {
+        summary::apply_scrub_edits(summary_task_sender, edits, scrub_edits).await?;
+    }
+    Ok(report)
+}
+
+// removes the file from its version and moves it into the quarantine directory, returns the
+// edit deleting it from the summary
+fn quarantine(tf_id: u32, file: &ColumnFile, quarantine_dir: &str) -> Result<VersionEdit> {
+    let dir = Path::new(quarantine_dir).join(tf_id.to_string());
+    fs::create_dir_all(&dir).context(error::IOSnafu)?;
+    let path = file.path(tf_id);
+    let name = Path::new(&path).file_name().expect("a column file has a file name");
+    fs::rename(&path, dir.join(name)).context(error::IOSnafu)?;
+    file.mark_removed();
+    let mut edit = VersionEdit::new();
+    edit.del_file(tf_id,
+                  CompactMeta { file_id: file.file_id(),
+                                file_size: file.size(),
+                                range: *file.range(),
+                                is_delta: file.is_delta(),
+                                ..CompactMeta::new() });
+    Ok(edit)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::HashMap,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            mpsc as std_mpsc, Arc,
+        },
+        thread,
+        time::Duration,
+    };
+
+    use tokio::sync::{mpsc, RwLock};
+
+    use super::{scrub_once, verify_file, RangeMismatch, ScrubStats, Throttle};
+    use crate::{
+        compaction::build_tsm_file,
+        direct_io::FileSync,
+        error::Error,
+        file_manager::get_file_manager,
+        file_utils::make_tsm_file_name,
+        kv_option::{ScrubConfig, TseriesFamDesc, TseriesFamOpt},
+        new_bloom_filter,
+        summary::CompactMeta,
+        tseries_family::{LevelInfo, TimeRange, Version},
+        tsm::{
+            DataBlock, FileBlock, TsmBlockWriter, TsmFooterWriter, TsmHeaderWriter, TsmIndexReader,
+            TsmIndexWriter,
+        },
+        version_set::VersionSet,
+    };
+
+    static PAUSED: AtomicBool = AtomicBool::new(true);
+
+    fn no_pressure() -> bool {
+        false
+    }
+
+    fn paused() -> bool {
+        PAUSED.load(Ordering::Acquire)
+    }
+
+    // writes the tsm file of one block with the values of field 1
+    fn write_file(dir: &str, file_id: u64, val: i64) -> String {
+        let mut block_set = HashMap::new();
+        block_set.insert(1,
+                         DataBlock::I64 { index: 0,
+                                          ts: vec![1, 2, 3],
+                                          val: vec![val; 3],
+                                          validity: None });
+        let fname = make_tsm_file_name(dir, file_id);
+        build_tsm_file(fname.clone(), block_set).unwrap();
+        fname.to_string_lossy().to_string()
+    }
+
+    // overwrites the values of every block of the file
+    fn corrupt_file(fname: &str) {
+        let file = get_file_manager().open_file(fname).unwrap();
+        let len = file.len();
+        let mut fs_cursor = file.into_cursor();
+        let blocks: Vec<FileBlock> =
+            TsmIndexReader::try_new(&mut fs_cursor, len as usize).unwrap()
+                                                                 .map(|entry| entry.unwrap().block)
+                                                                 .collect();
+        for block in blocks {
+            let junk = vec![0xFF; (block.offset + block.size - block.val_off - 4) as usize];
+            fs_cursor.write_at(block.val_off + 4, &junk).unwrap();
+        }
+        fs_cursor.sync_all(FileSync::Hard).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_scrub_corrupt_file() {
+        let tmp = tempfile::tempdir().unwrap();
+        let tf_id = 119;
+        let opt = TseriesFamOpt::for_testing(tmp.path());
+        let dir = opt.tsm_dir(tf_id);
+        std::fs::create_dir_all(&dir).unwrap();
+        let cold = write_file(&dir, 1, 1);
+        write_file(&dir, 2, 2);
+        corrupt_file(&cold);
+        let mut lvl = LevelInfo::init_in(&opt.base_dir, 1);
+        lvl.apply(&CompactMeta { file_id: 1, range: TimeRange::new(3, 1), ..Default::default() });
+        lvl.apply(&CompactMeta { file_id: 2, range: TimeRange::new(3, 1), ..Default::default() });
+        let version = Version::new(tf_id, 0, "db".to_string(), vec![lvl], 0);
+        let desc = [TseriesFamDesc { name: "db".to_string(), opt }];
+        let version_set = VersionSet::new(&desc,
+                                          HashMap::from([(tf_id, Arc::new(RwLock::new(version)))]),
+                                          vec![]).await;
+        let version_set = RwLock::new(version_set);
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let summary = tokio::spawn(async move {
+            let task = receiver.recv().await.unwrap();
+            let _ = task.cb.send(Ok(()));
+            task
+        });
+
+        let config = ScrubConfig { interval_secs: 100, ..Default::default() };
+        let throttle = Throttle::new(0, no_pressure);
+        let report = scrub_once(&version_set, &sender, &config, 1000, throttle).await.unwrap();
+        assert_eq!(report.stats.files, 1);
+        assert_eq!(report.corrupt.len(), 1);
+        assert_eq!((report.corrupt[0].tf_id, report.corrupt[0].file_id), (tf_id, 1));
+        assert!(matches!(report.corrupt[0].error, Error::ChecksumMismatch { field_id: 1, .. }));
+
+        let task = summary.await.unwrap();
+        let mut scrubbed: Vec<_> =
+            task.scrub_edits.iter().map(|e| (e.file_id, e.scrubbed_at, e.corrupt)).collect();
+        scrubbed.sort();
+        assert_eq!(scrubbed, vec![(1, 1000, true), (2, 1000, false)]);
+        let mut version_set_w = version_set.write().await;
+        let tsf = version_set_w.get_tsfamily_by_id(tf_id).unwrap();
+        let files = tsf.version().read().await.levels_info[0].files.clone();
+        drop(version_set_w);
+        assert!(files[0].is_corrupt() && !files[1].is_corrupt());
+        assert_eq!(files[1].scrubbed_at(), 1000);
+
+        // nothing is due before the interval is over, the corrupt file is not scrubbed again
+        let throttle = Throttle::new(0, no_pressure);
+        let report = scrub_once(&version_set, &sender, &config, 1099, throttle).await.unwrap();
+        assert_eq!(report.stats, ScrubStats::default());
+        assert!(report.corrupt.is_empty());
+    }
+
+    #[test]
+    fn test_scrub_pauses_under_pressure() {
+        let tmp = tempfile::tempdir().unwrap();
+        let tf_id = 120;
+        let dir = TseriesFamOpt::for_testing(tmp.path()).tsm_dir(tf_id);
+        std::fs::create_dir_all(&dir).unwrap();
+        let fname = write_file(&dir, 1, 1);
+        let (tx, rx) = std_mpsc::channel();
+        thread::spawn(move || {
+            let mut stats = ScrubStats::default();
+            let res = verify_file(&fname, &mut Throttle::new(0, paused), &mut stats);
+            tx.send(res.map(|_| stats)).unwrap();
+        });
+        assert!(rx.recv_timeout(Duration::from_millis(300)).is_err());
+        PAUSED.store(false, Ordering::Release);
+        let stats = rx.recv_timeout(Duration::from_secs(5)).unwrap().unwrap();
+        assert_eq!((stats.files, stats.blocks), (1, 1));
+    }
+
+    #[test]
+    fn test_verify_missing_bloom_field() {
+        let tmp = tempfile::tempdir().unwrap();
+        let fname = tmp.path().join("_000001.tsm");
+        let file = get_file_manager().create_file(&fname).unwrap();
+        let mut fs_cursor = file.into_cursor();
+        TsmHeaderWriter::write_to(&mut fs_cursor).unwrap();
+        let block = DataBlock::I64 { index: 0, ts: vec![1, 2, 3], val: vec![1; 3], validity: None };
+        let block_set = HashMap::from([(1, block.clone()), (2, block)]);
+        let index = TsmBlockWriter::write_to(&mut fs_cursor, block_set).unwrap();
+        let index_pos = fs_cursor.pos();
+        TsmIndexWriter::write_to(&mut fs_cursor, index).unwrap();
+        // field 2 is left out of the bloom filter of the footer
+        let mut bloom_filter = new_bloom_filter();
+        bloom_filter.insert(&1_u64.to_be_bytes()[..]);
+        assert!(!bloom_filter.contains(&2_u64.to_be_bytes()[..]));
+        TsmFooterWriter::write_to(&mut fs_cursor, &bloom_filter, index_pos).unwrap();
+        fs_cursor.sync_all(FileSync::Hard).unwrap();
+
+        let mut stats = ScrubStats::default();
+        let res = verify_file(&fname, &mut Throttle::new(0, no_pressure), &mut stats);
+        assert!(matches!(res, Err(Error::MissingFromBloomFilter { field_id: 2 })));
+        assert_eq!(stats, ScrubStats::default());
+    }
+
+    #[test]
+    fn test_verify_range_mismatch() {
+        let tmp = tempfile::tempdir().unwrap();
+        let fname = tmp.path().join("_000001.tsm");
+        let file = get_file_manager().create_file(&fname).unwrap();
+        let mut fs_cursor = file.into_cursor();
+        TsmHeaderWriter::write_to(&mut fs_cursor).unwrap();
+        let block =
+            DataBlock::I64 { index: 0, ts: (1..=10).collect(), val: vec![1; 10], validity: None };
+        let block_set = HashMap::from([(1, block.clone()), (2, block)]);
+        let mut index = TsmBlockWriter::write_to(&mut fs_cursor, block_set).unwrap();
+        // the index entry of the block of field 2 says [1, 5]
+        index.get_mut(&2).unwrap()[0].max_ts = 5;
+        let offset = index[&2][0].offset;
+        let index_pos = fs_cursor.pos();
+        let bloom_filter = TsmIndexWriter::write_to(&mut fs_cursor, index).unwrap();
+        TsmFooterWriter::write_to(&mut fs_cursor, &bloom_filter, index_pos).unwrap();
+        fs_cursor.sync_all(FileSync::Hard).unwrap();
+
+        let mut stats = ScrubStats::default();
+        let res = verify_file(&fname, &mut Throttle::new(0, no_pressure), &mut stats).unwrap();
+        assert_eq!(res,
+                   vec![RangeMismatch { field_id: 2,
+                                        offset,
+                                        meta: TimeRange::new(5, 1),
+                                        decoded: TimeRange::new(10, 1) }]);
+        assert_eq!((stats.files, stats.blocks), (1, 2));
+    }
+}
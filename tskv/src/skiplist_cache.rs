@@ -0,0 +1,234 @@
+use std::{borrow::Cow, collections::HashMap, sync::Arc};
+
+use crossbeam_skiplist::SkipMap;
+use models::{FieldId, Timestamp, ValueType};
+use tokio::sync::RwLock;
+
+use crate::{
+    error::{Error, Result},
+    memcache::{decode_cell, CacheSummary, DataType, MemCacheRef, MemCacheTrait, MemEntry},
+    tseries_family::TimeRange,
+};
+
+// (field_id, timestamp, insertion number), the insertion number keeps the write order of the
+// cells with the same timestamp
+type CellKey = (FieldId, Timestamp, u64);
+
+/// A memory cache keeping the cells ordered by (field, timestamp) in a skiplist, so that
+/// reading a field never needs a sort.
+#[derive(Debug)]
+pub struct SkipListCache {
+    immutable: bool,
+    tf_id: u32,
+    seq_no: u64,
+    // the smallest wal seq number written, u64::MAX if nothing is written
+    min_seq: u64,
+    max_buf_size: u64,
+    cells: SkipMap<CellKey, DataType>,
+    // <field_id, (field_type, number of cells)>
+    fields: HashMap<FieldId, (ValueType, usize)>,
+    insert_no: u64,
+    cache_size: u64,
+    is_delta: bool,
+}
+
+impl SkipListCache {
+    pub fn new(tf_id: u32, max_size: u64, seq: u64, is_delta: bool) -> Self {
+        Self { immutable: false,
+               tf_id,
+               seq_no: seq,
+               min_seq: u64::MAX,
+               max_buf_size: max_size,
+               cells: SkipMap::new(),
+               fields: HashMap::new(),
+               insert_no: 0,
+               cache_size: 0,
+               is_delta }
+    }
+
+    fn field_range(field_id: FieldId,
+                   min_ts: Timestamp,
+                   max_ts: Timestamp)
+                   -> std::ops::RangeInclusive<CellKey> {
+        (field_id, min_ts, 0)..=(field_id, max_ts, u64::MAX)
+    }
+}
+
+impl MemCacheTrait for SkipListCache {
+    fn insert_raw(&mut self,
+                  seq: u64,
+                  field_id: FieldId,
+                  ts: Timestamp,
+                  field_type: ValueType,
+                  buf: &[u8])
+                  -> Result<()> {
+        match self.fields.get(&field_id) {
+            Some((expected, len)) if *len > 0 && *expected != field_type => {
+                return Err(Error::FieldTypeConflict { field_id,
+                                                      expected: *expected,
+                                                      found: field_type });
+            },
+            _ => {},
+        }
+        self.seq_no = seq;
+        self.min_seq = self.min_seq.min(seq);
+        let data = decode_cell(ts, field_type, buf);
+        self.cache_size = self.cache_size.saturating_add(data.size());
+        self.cells.insert((field_id, ts, self.insert_no), data);
+        self.insert_no += 1;
+        let field = self.fields.entry(field_id).or_insert((field_type, 0));
+        field.0 = field_type;
+        field.1 += 1;
+        Ok(())
+    }
+
+    fn read(&self, field_id: FieldId, time_range: &TimeRange) -> Vec<DataType> {
+        self.cells
+            .range(Self::field_range(field_id, time_range.min_ts, time_range.max_ts))
+            .map(|e| e.value().clone())
+            .collect()
+    }
+
+    fn visit(&self, time_range: &TimeRange, f: &mut dyn FnMut(FieldId, &DataType)) {
+        for field_id in self.fields.keys() {
+            let range = Self::field_range(*field_id, time_range.min_ts, time_range.max_ts);
+            for e in self.cells.range(range) {
+                f(*field_id, e.value());
+            }
+        }
+    }
+
+    fn iter_field<'a>(&'a self,
+                      field_id: FieldId,
+                      time_range: &TimeRange)
+                      -> Box<dyn Iterator<Item = Cow<'a, DataType>> + 'a> {
+        // the entries of the skiplist only lend their values while they are held
+        let range = Self::field_range(field_id, time_range.min_ts, time_range.max_ts);
+        Box::new(self.cells.range(range).map(|e| Cow::Owned(e.value().clone())))
+    }
+
+    fn delete_range(&mut self, time_range: &TimeRange) {
+        for (field_id, field) in self.fields.iter_mut() {
+            let range = Self::field_range(*field_id, time_range.min_ts, time_range.max_ts);
+            for e in self.cells.range(range) {
+                e.remove();
+                field.1 -= 1;
+                self.cache_size = self.cache_size.saturating_sub(e.value().size());
+            }
+        }
+    }
+
+    fn delete_field_range(&mut self, field_id: FieldId, time_range: &TimeRange) {
+        if let Some(field) = self.fields.get_mut(&field_id) {
+            let range = Self::field_range(field_id, time_range.min_ts, time_range.max_ts);
+            for e in self.cells.range(range) {
+                e.remove();
+                field.1 -= 1;
+                self.cache_size = self.cache_size.saturating_sub(e.value().size());
+            }
+        }
+    }
+
+    fn iter_entries(&self) -> Box<dyn Iterator<Item = (FieldId, Cow<'_, MemEntry>)> + '_> {
+        Box::new(self.fields.iter().map(move |(field_id, (field_type, _))| {
+                                       let cells: Vec<DataType> =
+                                           self.cells
+                                               .range(Self::field_range(*field_id,
+                                                                        i64::MIN,
+                                                                        i64::MAX))
+                                               .map(|e| e.value().clone())
+                                               .collect();
+                                       let entry =
+                                           MemEntry { ts_min:
+                                                          cells.first()
+                                                               .map_or(i64::MAX, |c| c.timestamp()),
+                                                      ts_max:
+                                                          cells.last()
+                                                               .map_or(i64::MIN, |c| c.timestamp()),
+                                                      field_type: *field_type,
+                                                      cells };
+                                       (*field_id, Cow::Owned(entry))
+                                   }))
+    }
+
+    fn entry_len(&self, field_id: FieldId) -> usize {
+        self.fields.get(&field_id).map_or(0, |field| field.1)
+    }
+
+    fn split_field(&mut self, field_id: FieldId) -> Option<MemCacheRef> {
+        let (field_type, len) = self.fields.remove(&field_id)?;
+        let mut cache =
+            SkipListCache::new(self.tf_id, self.max_buf_size, self.seq_no, self.is_delta);
+        let mut size = 0_u64;
+        for e in self.cells.range(Self::field_range(field_id, i64::MIN, i64::MAX)) {
+            size = size.saturating_add(e.value().size());
+            cache.cells.insert(*e.key(), e.value().clone());
+            e.remove();
+        }
+        self.cache_size = self.cache_size.saturating_sub(size);
+        cache.cache_size = size;
+        // the sequences of the writes of the field are not kept, those of the cache span them
+        cache.min_seq = self.min_seq;
+        cache.fields.insert(field_id, (field_type, len));
+        cache.switch_to_immutable();
+        Some(Arc::new(RwLock::new(cache)))
+    }
+
+    fn size(&self) -> u64 {
+        self.cache_size
+    }
+
+    fn is_full(&self) -> bool {
+        self.cache_size >= self.max_buf_size
+    }
+
+    fn is_empty(&self) -> bool {
+        self.cells.is_empty()
+    }
+
+    fn switch_to_immutable(&mut self) {
+        // cells are always sorted
+        self.immutable = true;
+    }
+
+    fn is_immutable(&self) -> bool {
+        self.immutable
+    }
+
+    fn seq_no(&self) -> u64 {
+        self.seq_no
+    }
+
+    fn seq_range(&self) -> (u64, u64) {
+        (self.min_seq.min(self.seq_no), self.seq_no)
+    }
+
+    fn is_delta(&self) -> bool {
+        self.is_delta
+    }
+
+    fn tf_id(&self) -> u32 {
+        self.tf_id
+    }
+
+    fn summary(&self) -> CacheSummary {
+        let mut ts_range: Option<(Timestamp, Timestamp)> = None;
+        for field_id in self.fields.keys() {
+            let cells = || self.cells.range(Self::field_range(*field_id, i64::MIN, i64::MAX));
+            if let (Some(first), Some(last)) = (cells().next(), cells().next_back()) {
+                let (min_ts, max_ts) = (first.key().1, last.key().1);
+                ts_range = Some(match ts_range {
+                                    Some((lo, hi)) => (lo.min(min_ts), hi.max(max_ts)),
+                                    None => (min_ts, max_ts),
+                                });
+            }
+        }
+        CacheSummary { tf_id: self.tf_id,
+                       bytes: self.cache_size,
+                       fields: self.fields.len(),
+                       cells: self.fields.values().map(|f| f.1).sum(),
+                       ts_range,
+                       seq_range: self.seq_range(),
+                       immutable: self.immutable }
+    }
+}
@@ -0,0 +1,418 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Read, Write},
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+use tokio::sync::RwLock;
+
+use crate::{
+    direct_io::{FileCursor, IoClass},
+    error,
+    features::FeatureBits,
+    file_manager::{self, get_file_manager},
+    file_utils,
+    kv_option::TseriesFamOpt,
+    summary::{CompactMeta, VersionEdit},
+    tseries_family::{ColumnFile, Version},
+    tsm::TsmFooterReader,
+    Error, Result,
+};
+
+/// The name of the manifest in an exported archive, written after the files.
+const MANIFEST: &str = "MANIFEST";
+// the directories of the files in an exported archive
+const TSM_DIR: &str = "tsm";
+const DELTA_DIR: &str = "delta";
+
+/// A file in an exported archive.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ManifestEntry {
+    // the path in the archive, `tsm/` or `delta/` and the file name
+    pub path: String,
+    pub size: u64,
+    pub crc: u32,
+}
+
+/// What an archive written by `export` holds: the live files of a tseries family and their
+/// tombstones, checked against their sizes and crcs when imported.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ExportManifest {
+    pub tsf_id: u32,
+    pub tsf_name: String,
+    pub last_seq: u64,
+    pub max_level_ts: i64,
+    // the format features of the tsm files, the bits of all of them together
+    pub features: FeatureBits,
+    // the live files, registered again by the import
+    pub files: Vec<CompactMeta>,
+    pub entries: Vec<ManifestEntry>,
+}
+
+impl ExportManifest {
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        bincode::serialize(self).map_err(|e| Error::Encode { source: e })
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        bincode::deserialize(buf).map_err(|e| Error::Decode { source: e })
+    }
+
+    /// Returns the edits registering the files of the archive imported as the tseries family
+    /// `tf_id`, like `PendingPurge::restore_edits`.
+    pub fn import_edits(&self, tf_id: u32) -> Vec<VersionEdit> {
+        let mut add_tsf = VersionEdit::new();
+        add_tsf.add_tsf(tf_id, self.tsf_name.clone(), self.last_seq);
+        let files = self.files.iter().map(|meta| CompactMeta { tsf_id: tf_id, ..meta.clone() });
+        let add_files = VersionEdit { tsf_id: tf_id,
+                                      seq_no: self.last_seq,
+                                      add_files: files.collect(),
+                                      max_level_ts: self.max_level_ts,
+                                      ..VersionEdit::new() };
+        vec![add_tsf, add_files]
+    }
+}
+
+// reads a file through a cursor, computing the crc of the bytes read
+struct CrcReader {
+    cursor: FileCursor,
+    hasher: crc32fast::Hasher,
+    read: u64,
+}
+
+impl Read for CrcReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.cursor.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.read += n as u64;
+        Ok(n)
+    }
+}
+
+/// Writes the live files of the version of the tseries family and their tombstones into a tar
+/// archive, with the manifest last, and returns the manifest. The files are not compressed again,
+/// they are read with the low io priority. The caches are not exported, the caller flushes them
+/// first.
+///
+/// The files are taken from the version when the export starts, and held until it ends, so
+/// that neither a compaction nor the cleaner removes them while they are read. The archive is
+/// written on a blocking thread.
+pub async fn export(tf_id: u32,
+                    version: &RwLock<Version>,
+                    writer: impl Write + Send + 'static)
+                    -> Result<ExportManifest> {
+    let (files, manifest) = {
+        let version = version.read().await;
+        let files: Vec<Arc<ColumnFile>> = version.levels_info()
+                                                 .iter()
+                                                 .flat_map(|info| info.files.iter())
+                                                 .filter(|f| !f.is_deleted())
+                                                 .cloned()
+                                                 .collect();
+        let manifest = ExportManifest { tsf_id: tf_id,
+                                        tsf_name: version.get_name().to_string(),
+                                        last_seq: version.last_seq,
+                                        max_level_ts: version.max_level_ts,
+                                        features: FeatureBits::default(),
+                                        files: version.live_files(),
+                                        entries: vec![] };
+        (files, manifest)
+    };
+    let write = move || write_archive(tf_id, &files, manifest, writer);
+    tokio::task::spawn_blocking(write).await.expect("snapshot export panicked")
+}
+
+fn write_archive(tf_id: u32,
+                 files: &[Arc<ColumnFile>],
+                 mut manifest: ExportManifest,
+                 writer: impl Write)
+                 -> Result<ExportManifest> {
+    let mut builder = tar::Builder::new(writer);
+    for file in files.iter() {
+        let dir = if file.is_delta() { DELTA_DIR } else { TSM_DIR };
+        let (mut cursor, len) = file.file_reader(tf_id)?;
+        if let Some(features) = TsmFooterReader::read_features(&mut cursor, len as usize)? {
+            manifest.features.required |= features.required;
+            manifest.features.optional |= features.optional;
+        }
+        manifest.entries.push(append_file(&mut builder, dir, &file.path(tf_id))?);
+        let tombstone = file.tombstone_path(tf_id);
+        if file_manager::try_exists(&tombstone) {
+            manifest.entries.push(append_file(&mut builder, dir, &tombstone)?);
+        }
+    }
+    let buf = manifest.encode()?;
+    let mut header = tar::Header::new_gnu();
+    header.set_size(buf.len() as u64);
+    header.set_mode(0o644);
+    builder.append_data(&mut header, MANIFEST, buf.as_slice()).context(error::IOSnafu)?;
+    builder.into_inner().context(error::IOSnafu)?.flush().context(error::IOSnafu)?;
+    Ok(manifest)
+}
+
+// appends the file as `dir/<file name>`, returns its entry of the manifest
+fn append_file<W: Write>(builder: &mut tar::Builder<W>,
+                         dir: &str,
+                         path: &str)
+                         -> Result<ManifestEntry> {
+    let file = get_file_manager().open_file(path)?;
+    let size = file.len();
+    let mut cursor = file.into_cursor();
+    cursor.set_io_class(IoClass::Low);
+    let name = Path::new(path).file_name().expect("a column file has a file name");
+    let entry_path = format!("{}/{}", dir, name.to_string_lossy());
+    let mut reader = CrcReader { cursor, hasher: crc32fast::Hasher::new(), read: 0 };
+    let mut header = tar::Header::new_gnu();
+    header.set_size(size);
+    header.set_mode(0o644);
+    builder.append_data(&mut header, &entry_path, (&mut reader).take(size))
+           .context(error::IOSnafu)?;
+    // a file cut short while read leaves the archive padded with zeros
+    if reader.read != size {
+        return Err(Error::CorruptSnapshot { reason: format!("{} changed while exported", path) });
+    }
+    Ok(ManifestEntry { path: entry_path, size, crc: reader.hasher.finalize() })
+}
+
+/// Extracts an archive written by `export` into the staging directory of the tseries family
+/// `tf_id` and returns its manifest; `install_imported` moves the files into the directories
+/// of the family, and the caller registers them with `ExportManifest::import_edits`.
+///
+/// Every file is checked against its size and crc in the manifest, and the format features
+/// of the files against the features this binary reads. Nothing is left behind if the
+/// archive fails any check. An import of the family already extracting is refused.
+pub fn import(reader: impl Read, opt: &TseriesFamOpt, tf_id: u32) -> Result<ExportManifest> {
+    let staging = staging_dir(opt, tf_id);
+    fs::create_dir_all(&opt.base_dir).context(error::IOSnafu)?;
+    if let Err(e) = fs::create_dir(&staging) {
+        return match e.kind() {
+            io::ErrorKind::AlreadyExists => {
+                Err(Error::SnapshotTargetNotEmpty { dir: staging.to_string_lossy().to_string() })
+            },
+            _ => Err(e).context(error::IOSnafu),
+        };
+    }
+    let (tsm_dir, delta_dir) = (staging.join(TSM_DIR), staging.join(DELTA_DIR));
+    let res = match fs::create_dir(&tsm_dir).and_then(|_| fs::create_dir(&delta_dir)) {
+        Ok(()) => extract(reader, &tsm_dir.to_string_lossy(), &delta_dir.to_string_lossy()),
+        Err(e) => Err(e).context(error::IOSnafu),
+    };
+    if res.is_err() {
+        discard_imported(opt, tf_id);
+    }
+    res
+}
+
+/// Moves the files extracted by `import` into the directories of the tseries family `tf_id`,
+/// which must not hold any file yet. The files are only renamed, the caller may hold the lock
+/// of the version set meanwhile. Nothing is left behind if they cannot be moved.
+pub fn install_imported(opt: &TseriesFamOpt, tf_id: u32) -> Result<()> {
+    let staging = staging_dir(opt, tf_id);
+    let (tsm_dir, delta_dir) = (opt.tsm_dir(tf_id), opt.delta_dir(tf_id));
+    let used = |dir: &str| fs::read_dir(dir).map_or(false, |mut entries| entries.next().is_some());
+    if let Some(dir) = [&tsm_dir, &delta_dir].into_iter().find(|dir| used(dir)) {
+        discard_imported(opt, tf_id);
+        return Err(Error::SnapshotTargetNotEmpty { dir: dir.clone() });
+    }
+    let install = || -> io::Result<()> {
+        fs::create_dir_all(file_utils::make_tsfamily_dir(&opt.base_dir, tf_id))?;
+        for (name, dir) in [(TSM_DIR, &tsm_dir), (DELTA_DIR, &delta_dir)] {
+            // an empty directory is replaced
+            let _ = fs::remove_dir(dir);
+            fs::rename(staging.join(name), dir)?;
+        }
+        Ok(())
+    };
+    let res = install().context(error::IOSnafu);
+    if res.is_err() {
+        remove_imported(opt, tf_id);
+    }
+    discard_imported(opt, tf_id);
+    res
+}
+
+/// Removes the directories of the tseries family `tf_id` an import installed into, when the
+/// files cannot be registered.
+pub fn remove_imported(opt: &TseriesFamOpt, tf_id: u32) {
+    for dir in [opt.tsm_dir(tf_id), opt.delta_dir(tf_id)] {
+        let _ = fs::remove_dir_all(dir);
+    }
+}
+
+/// Removes the files extracted by `import` without moving them into place.
+pub fn discard_imported(opt: &TseriesFamOpt, tf_id: u32) {
+    let _ = fs::remove_dir_all(staging_dir(opt, tf_id));
+}
+
+// the directory an import of the tseries family extracts into, under the base directory
+fn staging_dir(opt: &TseriesFamOpt, tf_id: u32) -> PathBuf {
+    Path::new(&opt.base_dir).join(format!(".import-{}", tf_id))
+}
+
+fn extract(reader: impl Read, tsm_dir: &str, delta_dir: &str) -> Result<ExportManifest> {
+    let corrupt = |reason: String| Error::CorruptSnapshot { reason };
+    let mut extracted = HashMap::new();
+    let mut manifest = None;
+    let mut archive = tar::Archive::new(reader);
+    for entry in archive.entries().context(error::IOSnafu)? {
+        let mut entry = entry.context(error::IOSnafu)?;
+        let path = entry.path().context(error::IOSnafu)?.to_string_lossy().to_string();
+        if path == MANIFEST {
+            let mut buf = vec![];
+            entry.read_to_end(&mut buf).context(error::IOSnafu)?;
+            manifest = Some(ExportManifest::decode(&buf)?);
+            continue;
+        }
+        // only a file name under one of the two directories is extracted
+        let mut components = Path::new(&path).components();
+        let dir = match components.next() {
+            Some(Component::Normal(dir)) if dir == TSM_DIR => tsm_dir,
+            Some(Component::Normal(dir)) if dir == DELTA_DIR => delta_dir,
+            _ => return Err(corrupt(format!("unexpected entry {}", path))),
+        };
+        let name = match (components.next(), components.next()) {
+            (Some(Component::Normal(name)), None) => name,
+            _ => return Err(corrupt(format!("unexpected entry {}", path))),
+        };
+        let mut file = fs::OpenOptions::new().write(true)
+                                             .create_new(true)
+                                             .open(Path::new(dir).join(name))
+                                             .context(error::IOSnafu)?;
+        let mut hasher = crc32fast::Hasher::new();
+        let mut buf = vec![0; 64 * 1024];
+        let mut size = 0;
+        loop {
+            let n = entry.read(&mut buf).context(error::IOSnafu)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+            file.write_all(&buf[..n]).context(error::IOSnafu)?;
+            size += n as u64;
+        }
+        file.sync_all().context(error::IOSnafu)?;
+        extracted.insert(path, (size, hasher.finalize()));
+    }
+
+    let manifest = manifest.ok_or_else(|| corrupt("no manifest".to_string()))?;
+    manifest.features.check()?;
+    for entry in manifest.entries.iter() {
+        match extracted.remove(&entry.path) {
+            Some((size, crc)) if size == entry.size && crc == entry.crc => {},
+            Some(_) => return Err(corrupt(format!("{} does not match its crc", entry.path))),
+            None => return Err(corrupt(format!("{} is missing", entry.path))),
+        }
+    }
+    if let Some(path) = extracted.keys().next() {
+        return Err(corrupt(format!("{} is not in the manifest", path)));
+    }
+    Ok(manifest)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::HashMap, sync::Arc};
+
+    use tokio::sync::RwLock;
+
+    use super::{export, import, install_imported, staging_dir};
+    use crate::{
+        compaction::flush::build_tsm_file,
+        error::Error,
+        file_utils::make_tsm_file_name,
+        kv_option::{MemCacheImpl, TseriesFamOpt},
+        memcache::{new_memcache, DataType},
+        summary::CompactMeta,
+        tseries_family::{LevelInfo, TimeRange, TseriesFamily, Version},
+        tsm::DataBlock,
+    };
+
+    async fn open(tf_id: u32, opt: &TseriesFamOpt, files: &[CompactMeta]) -> TseriesFamily {
+        let mut version = Version::new(tf_id, 5, "db".to_string(), vec![], 6);
+        version.base_dir = opt.base_dir.clone();
+        for meta in files {
+            version.apply_file(&CompactMeta { tsf_id: tf_id, ..meta.clone() });
+        }
+        TseriesFamily::new(tf_id,
+                           "db".to_string(),
+                           new_memcache(MemCacheImpl::HashMap, tf_id, 4096, 0, false),
+                           Arc::new(RwLock::new(version)),
+                           opt.clone()).await
+    }
+
+    async fn scan(tsf: &TseriesFamily) -> Vec<(i64, i64)> {
+        let data = tsf.scan(1, &TimeRange::new(i64::MAX, i64::MIN)).await.unwrap();
+        data.into_iter()
+            .map(|d| match d {
+                DataType::I64(c) => (c.ts, c.val),
+                _ => panic!("unexpected data type"),
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_export_import() {
+        let tmp = tempfile::tempdir().unwrap();
+        let tf_id = 129;
+        let opt = TseriesFamOpt::for_testing(&tmp.path().join("src"));
+        let dir = opt.tsm_dir(tf_id);
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut files = vec![];
+        for (file_id, level, ts) in [(1, 2, vec![1, 2, 3]), (2, 1, vec![3, 4, 6])] {
+            let mut block_set = HashMap::new();
+            let val = ts.clone();
+            block_set.insert(1, DataBlock::I64 { index: 0, ts: ts.clone(), val, validity: None });
+            let file_size = build_tsm_file(make_tsm_file_name(&dir, file_id), block_set).unwrap();
+            files.push(CompactMeta { file_id,
+                                     file_size,
+                                     range: TimeRange::new(ts[ts.len() - 1], ts[0]),
+                                     level,
+                                     ..Default::default() });
+        }
+        let tsf = open(tf_id, &opt, &files).await;
+        tsf.delete_range(5, &[1], &TimeRange::new(2, 2)).await.unwrap();
+        let expected = scan(&tsf).await;
+        assert_eq!(expected, vec![(1, 1), (3, 3), (4, 4), (6, 6)]);
+
+        let path = tmp.path().join("archive.tar");
+        let manifest =
+            export(tf_id, tsf.version(), std::fs::File::create(&path).unwrap()).await.unwrap();
+        let archive = std::fs::read(&path).unwrap();
+        assert_eq!((manifest.tsf_id, manifest.last_seq, manifest.max_level_ts), (tf_id, 5, 6));
+        assert_eq!(manifest.files.len(), 2);
+        // the tombstone of the first file is exported with it
+        assert_eq!(manifest.entries.len(), 3);
+        assert_ne!(manifest.features.required, 0);
+
+        // the imported files hold the same points, the tombstone applied
+        let dst = TseriesFamOpt::for_testing(&tmp.path().join("dst"));
+        let imported = import(archive.as_slice(), &dst, 7).unwrap();
+        assert_eq!(imported, manifest);
+        install_imported(&dst, 7).unwrap();
+        assert!(!staging_dir(&dst, 7).exists());
+        let edits = imported.import_edits(7);
+        assert!(edits[1].add_files.iter().all(|meta| meta.tsf_id == 7));
+        let tsf = open(7, &dst, &edits[1].add_files).await;
+        assert_eq!(scan(&tsf).await, expected);
+        // the directories must be empty, the installed files are left alone
+        import(archive.as_slice(), &dst, 7).unwrap();
+        assert!(install_imported(&dst, 7).is_err());
+        assert!(!staging_dir(&dst, 7).exists());
+        assert_eq!(scan(&tsf).await, expected);
+
+        // a byte flipped in the first file is detected, nothing is left behind
+        let mut corrupt = archive.clone();
+        corrupt[512 + 16] ^= 0xff;
+        let bad = TseriesFamOpt::for_testing(&tmp.path().join("bad"));
+        match import(corrupt.as_slice(), &bad, 7) {
+            Err(Error::CorruptSnapshot { reason }) => assert!(reason.contains("crc"), "{}", reason),
+            res => panic!("unexpected {:?}", res),
+        }
+        assert!(!staging_dir(&bad, 7).exists());
+        assert!(!std::path::Path::new(&bad.tsm_dir(7)).exists());
+    }
+}
@@ -1,19 +1,28 @@
-use std::{borrow::Borrow, collections::HashMap, sync::Arc};
+use std::{
+    borrow::Borrow,
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::Path,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 use futures::TryFutureExt;
 use libc::write;
 use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
 use tokio::sync::{mpsc::UnboundedSender, oneshot::Sender, RwLock};
 
 use crate::{
     context::GlobalContext,
-    error::{Error, Result},
-    file_utils,
+    error::{self, Error, Result},
+    features::FeatureBits,
+    file_manager, file_utils,
     kv_option::{DBOptions, TseriesFamDesc, TseriesFamOpt},
     kvcore::KvContext,
     record_file::{Reader, Writer},
-    tseries_family::{LevelInfo, Version},
+    tseries_family::{LevelInfo, TimeRange, Version},
     version_set::VersionSet,
 };
 
@@ -22,8 +31,10 @@ const MAX_BATCH_SIZE: usize = 64;
 pub struct CompactMeta {
     pub file_id: u64, // file id
     pub file_size: u64,
-    pub ts_min: i64,
-    pub ts_max: i64,
+    // encoded as the ts_min and ts_max pair it replaced
+    pub range: TimeRange,
+    // the tseries family and the level the file belongs to
+    pub tsf_id: u32,
     pub level: u32,
     pub high_seq: u64,
     pub low_seq: u64,
@@ -33,8 +44,8 @@ impl CompactMeta {
     pub fn new() -> Self {
         Self { file_id: 0,
                file_size: 0,
-               ts_min: i64::MAX,
-               ts_max: i64::MIN,
+               range: TimeRange::none(),
+               tsf_id: 0,
                level: 0,
                high_seq: u64::MIN,
                low_seq: u64::MIN,
@@ -42,7 +53,72 @@ impl CompactMeta {
     }
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
+/// A `CompactMeta` written before a file carried its tseries family.
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct LegacyCompactMeta {
+    file_id: u64,
+    file_size: u64,
+    ts_min: i64,
+    ts_max: i64,
+    level: u32,
+    high_seq: u64,
+    low_seq: u64,
+    is_delta: bool,
+}
+
+impl LegacyCompactMeta {
+    fn upgrade(self, tsf_id: u32) -> CompactMeta {
+        CompactMeta { file_id: self.file_id,
+                      file_size: self.file_size,
+                      range: TimeRange::new(self.ts_max, self.ts_min),
+                      tsf_id,
+                      level: self.level,
+                      high_seq: self.high_seq,
+                      low_seq: self.low_seq,
+                      is_delta: self.is_delta }
+    }
+}
+
+/// A `VersionEdit` written before a file carried its tseries family, the files of it belong
+/// to the tseries family of the edit.
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct LegacyVersionEdit {
+    level: u32,
+    has_seq_no: bool,
+    seq_no: u64,
+    has_file_id: bool,
+    file_id: u64,
+    add_files: Vec<LegacyCompactMeta>,
+    del_files: Vec<LegacyCompactMeta>,
+    del_tsf: bool,
+    add_tsf: bool,
+    tsf_id: u32,
+    tsf_name: String,
+    max_level_ts: i64,
+}
+
+impl LegacyVersionEdit {
+    fn upgrade(self) -> VersionEdit {
+        let tsf_id = self.tsf_id;
+        let upgrade = |files: Vec<LegacyCompactMeta>| -> Vec<CompactMeta> {
+            files.into_iter().map(|meta| meta.upgrade(tsf_id)).collect()
+        };
+        VersionEdit { level: self.level,
+                      has_seq_no: self.has_seq_no,
+                      seq_no: self.seq_no,
+                      has_file_id: self.has_file_id,
+                      file_id: self.file_id,
+                      add_files: upgrade(self.add_files),
+                      del_files: upgrade(self.del_files),
+                      del_tsf: self.del_tsf,
+                      add_tsf: self.add_tsf,
+                      tsf_id,
+                      tsf_name: self.tsf_name,
+                      max_level_ts: self.max_level_ts }
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub struct VersionEdit {
     pub level: u32,
 
@@ -83,13 +159,22 @@ impl VersionEdit {
     pub fn decode(buf: &[u8]) -> Result<Self> {
         bincode::deserialize(buf).map_err(|e| Error::Decode { source: (e) })
     }
+    /// Decodes an edit written before a file carried its tseries family.
+    fn decode_legacy(buf: &[u8]) -> Result<Self> {
+        let edit: LegacyVersionEdit =
+            bincode::deserialize(buf).map_err(|e| Error::Decode { source: (e) })?;
+        Ok(edit.upgrade())
+    }
     pub fn add_file(&mut self,
                     level: u32,
                     tsf_id: u32,
                     file_id: u64,
                     seq_no: u64,
                     max_level_ts: i64,
-                    meta: CompactMeta) {
+                    mut meta: CompactMeta) {
+        meta.tsf_id = tsf_id;
+        meta.level = level;
+        self.tsf_id = tsf_id;
         self.has_file_id = true;
         self.file_id = file_id;
         self.has_seq_no = true;
@@ -109,8 +194,37 @@ impl VersionEdit {
         self.del_tsf = true;
         self.tsf_id = tsf_if;
     }
-    // todo:
-    pub fn del_file(&mut self) {}
+    pub fn del_file(&mut self, tsf_id: u32, mut meta: CompactMeta) {
+        meta.tsf_id = tsf_id;
+        self.tsf_id = tsf_id;
+        self.del_files.push(meta);
+    }
+
+    /// Splits the edit into an edit for each tseries family whose files it adds or deletes.
+    /// The sequence and the file id go with every part, the max level timestamp only with
+    /// the part of the tseries family of the edit.
+    pub fn split_by_tsf(self) -> Vec<VersionEdit> {
+        if self.add_tsf || self.del_tsf {
+            return vec![self];
+        }
+        let template = VersionEdit { level: self.level,
+                                     has_seq_no: self.has_seq_no,
+                                     seq_no: self.seq_no,
+                                     has_file_id: self.has_file_id,
+                                     file_id: self.file_id,
+                                     ..VersionEdit::new() };
+        let part = |tsf_id: u32| VersionEdit { tsf_id, ..template.clone() };
+        let mut parts = BTreeMap::new();
+        parts.insert(self.tsf_id,
+                     VersionEdit { max_level_ts: self.max_level_ts, ..part(self.tsf_id) });
+        for meta in self.add_files {
+            parts.entry(meta.tsf_id).or_insert_with(|| part(meta.tsf_id)).add_files.push(meta);
+        }
+        for meta in self.del_files {
+            parts.entry(meta.tsf_id).or_insert_with(|| part(meta.tsf_id)).del_files.push(meta);
+        }
+        parts.into_values().collect()
+    }
 
     pub fn set_log_seq(&mut self, file_id: u64) {
         self.file_id = file_id;
@@ -120,14 +234,109 @@ impl VersionEdit {
     }
 }
 
+/// A dropped tseries family whose directories wait in a trash entry until they are purged
+/// or restored.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct PendingPurge {
+    pub tsf_id: u32,
+    pub tsf_name: String,
+    // seconds since the epoch the tseries family was dropped at
+    pub dropped_at: u64,
+    pub ttl_secs: u64,
+    // the trash entry and the directories moved into it
+    pub path: String,
+    pub tsm_dir: String,
+    pub delta_dir: String,
+    // the live files of the tseries family when dropped, registered again when restored
+    pub files: Vec<CompactMeta>,
+    pub last_seq: u64,
+    pub max_level_ts: i64,
+}
+
+impl PendingPurge {
+    pub fn is_expired(&self, now: u64) -> bool {
+        now.saturating_sub(self.dropped_at) >= self.ttl_secs
+    }
+
+    /// Returns the options of the config with the directories and the ttl the tseries family
+    /// was dropped with, the tsm directory being `<base_dir>/<tf_id>/tsm`.
+    pub fn options(&self) -> TseriesFamOpt {
+        let config = TseriesFamOpt::from_config();
+        let base_dir = Path::new(&self.tsm_dir).parent().and_then(Path::parent);
+        let trash_dir = Path::new(&self.path).parent();
+        let dir = |dir: Option<&Path>, default: String| {
+            dir.map_or(default, |dir| dir.to_string_lossy().to_string())
+        };
+        TseriesFamOpt { base_dir: dir(base_dir, config.base_dir.clone()),
+                        trash_dir: dir(trash_dir, config.trash_dir.clone()),
+                        trash_ttl_secs: self.ttl_secs,
+                        ..config }
+    }
+
+    /// Returns the edits registering the tseries family and its files again.
+    pub fn restore_edits(&self) -> Vec<VersionEdit> {
+        let mut add_tsf = VersionEdit::new();
+        add_tsf.add_tsf(self.tsf_id, self.tsf_name.clone(), self.last_seq);
+        // the sequence is not set, so that an older one does not overwrite the current one
+        let add_files = VersionEdit { tsf_id: self.tsf_id,
+                                      seq_no: self.last_seq,
+                                      add_files: self.files.clone(),
+                                      max_level_ts: self.max_level_ts,
+                                      ..VersionEdit::new() };
+        vec![add_tsf, add_files]
+    }
+}
+
+/// Adds or removes a trash entry, written to the summary with its own record type.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub enum TrashEdit {
+    Add(PendingPurge),
+    // the entry is restored or purged
+    Remove { path: String },
+}
+
+impl TrashEdit {
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        bincode::serialize(self).map_err(|e| Error::Encode { source: (e) })
+    }
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        bincode::deserialize(buf).map_err(|e| Error::Decode { source: (e) })
+    }
+}
+
+/// The result of verifying the checksums of a file, written to the summary with its own
+/// record type; the last one of a file wins.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct ScrubEdit {
+    pub tsf_id: u32,
+    pub file_id: u64,
+    // seconds since the epoch the file was verified at
+    pub scrubbed_at: u64,
+    pub corrupt: bool,
+}
+
+impl ScrubEdit {
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        bincode::serialize(self).map_err(|e| Error::Encode { source: (e) })
+    }
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        bincode::deserialize(buf).map_err(|e| Error::Decode { source: (e) })
+    }
+}
+
 use config::GLOBAL_CONFIG;
-use logger::{debug, info};
+use logger::{debug, info, warn};
 use num_enum::{IntoPrimitive, TryFromPrimitive};
 
 #[derive(Debug, Eq, PartialEq, IntoPrimitive, TryFromPrimitive)]
 #[repr(u8)]
 enum EditType {
-    SummaryEdit, // 0
+    // written before a file carried its tseries family
+    LegacySummaryEdit, // 0
+    Features,          // 1
+    SummaryEdit,       // 2
+    Trash,             // 3
+    Scrub,             // 4
 }
 
 pub struct Summary {
@@ -138,62 +347,196 @@ pub struct Summary {
 }
 
 impl Summary {
-    // create a new summary file
+    /// Creates the summary of a new store. The summary file and the IDENTITY marker are written
+    /// and synced in the staging directory `<db_path>/.init`, then moved into the db path with
+    /// the summary last: the store is created once its summary file is in place. What a
+    /// creation interrupted by a crash left is removed first, see `clean_init`.
     pub async fn new(db_opt: &DBOptions) -> Result<Self> {
-        let db = VersionEdit::new();
-        let mut w = Writer::new(&file_utils::make_summary_file(&db_opt.db_path, 0));
-        let buf = db.encode()?;
+        Self::clean_init(&db_opt.db_path)?;
+        Self::stage_init(&db_opt.db_path).await?;
+        Self::commit_init(&db_opt.db_path)?;
+        Self::recover(db_opt).await
+    }
+
+    /// Removes what a creation of the store interrupted by a crash left: the staging directory,
+    /// and the IDENTITY marker if the summary file is not in place, as the creation never
+    /// completed.
+    pub fn clean_init(db_path: &str) -> Result<()> {
+        let init_dir = file_utils::make_init_dir(db_path);
+        if file_manager::try_exists(&init_dir) {
+            fs::remove_dir_all(&init_dir).context(error::IOSnafu)?;
+        }
+        let identity = file_utils::make_identity_file(db_path);
+        if !file_manager::try_exists(file_utils::make_summary_file(db_path, 0))
+           && file_manager::try_exists(&identity)
+        {
+            fs::remove_file(&identity).context(error::IOSnafu)?;
+        }
+        Ok(())
+    }
+
+    // writes the files of a new store into the staging directory and syncs them
+    async fn stage_init(db_path: &str) -> Result<()> {
+        let init_dir = file_utils::make_init_dir(db_path);
+        fs::create_dir_all(&init_dir).context(error::IOSnafu)?;
+        let init_path = init_dir.to_string_lossy().to_string();
+
+        let mut w = Writer::new(&file_utils::make_summary_file(&init_path, 0));
+        // no format feature is used by the summary yet
+        let buf = FeatureBits::default().encode()?;
+        let _ = w.write_record(1, EditType::Features.into(), &buf)
+                 .map_err(|e| Error::LogRecordErr { source: (e) })
+                 .await?;
+        let buf = VersionEdit::new().encode()?;
         let _ = w.write_record(1, EditType::SummaryEdit.into(), &buf)
                  .map_err(|e| Error::LogRecordErr { source: (e) })
                  .await?;
         w.hard_sync().map_err(|e| Error::LogRecordErr { source: e }).await?;
-        Self::recover(db_opt).await
+
+        // the store is told apart by the time it was created at and the process creating it
+        let created_at =
+            SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0);
+        let identity = format!("{:x}-{:x}\n", created_at, std::process::id());
+        let path = file_utils::make_identity_file(&init_path);
+        fs::write(&path, identity).context(error::IOSnafu)?;
+        sync_path(&path)?;
+        sync_path(&init_dir)
+    }
+
+    // moves the staged files into the db path, the summary file last, then removes the staging
+    // directory
+    fn commit_init(db_path: &str) -> Result<()> {
+        let init_path = file_utils::make_init_dir(db_path).to_string_lossy().to_string();
+        fs::rename(file_utils::make_identity_file(&init_path),
+                   file_utils::make_identity_file(db_path)).context(error::IOSnafu)?;
+        fs::rename(file_utils::make_summary_file(&init_path, 0),
+                   file_utils::make_summary_file(db_path, 0)).context(error::IOSnafu)?;
+        sync_path(db_path)?;
+        fs::remove_dir_all(&init_path).context(error::IOSnafu)
     }
 
     pub async fn recover(db_opt: &DBOptions) -> Result<Self> {
+        Ok(Self::recover_to(db_opt, u64::MAX).await?.0)
+    }
+
+    /// Recovers the versions as they were at the wal sequence `end_seq`, also returns the
+    /// edits undoing the ones after it, see `recover_version_to`.
+    pub async fn recover_to(db_opt: &DBOptions, end_seq: u64) -> Result<(Self, Vec<VersionEdit>)> {
         let writer = Writer::new(&file_utils::make_summary_file(&db_opt.db_path, 0));
         let ctx = Arc::new(GlobalContext::default());
         let rd = Box::new(Reader::new(&file_utils::make_summary_file(&db_opt.db_path, 0)));
-        let vs = Self::recover_version(rd, &ctx).await?;
+        let (vs, undo) = Self::recover_version_to(rd, &ctx, end_seq).await?;
 
-        Ok(Self { file_no: 0, version_set: Arc::new(RwLock::new(vs)), ctx, writer })
+        Ok((Self { file_no: 0, version_set: Arc::new(RwLock::new(vs)), ctx, writer }, undo))
+    }
+
+    /// Reads the versions of the summary file without opening it for writing, returns an
+    /// error if there is no summary file.
+    pub async fn read_version_set(db_opt: &DBOptions) -> Result<VersionSet> {
+        let rd = Box::new(Reader::open(&file_utils::make_summary_file(&db_opt.db_path, 0))?);
+        Self::recover_version(rd, &GlobalContext::default()).await
     }
 
     // recover from summary file
-    pub async fn recover_version(mut rd: Box<Reader>, ctx: &GlobalContext) -> Result<VersionSet> {
+    pub async fn recover_version(rd: Box<Reader>, ctx: &GlobalContext) -> Result<VersionSet> {
+        Ok(Self::recover_version_to(rd, ctx, u64::MAX).await?.0)
+    }
+
+    // recover from summary file, the edits of a wal sequence after end_seq are skipped, returns
+    // an edit for each tseries family deleting the files they add and adding back the files
+    // they delete, its sequence is left to the caller
+    pub async fn recover_version_to(mut rd: Box<Reader>,
+                                    ctx: &GlobalContext,
+                                    end_seq: u64)
+                                    -> Result<(VersionSet, Vec<VersionEdit>)> {
         let mut tf_cfg = vec![];
         let mut edits: HashMap<u32, Vec<VersionEdit>> = HashMap::default();
         let mut tf_names: HashMap<u32, String> = HashMap::default();
+        let mut trash: BTreeMap<String, PendingPurge> = BTreeMap::new();
+        let mut scrubs: HashMap<(u32, u64), ScrubEdit> = HashMap::new();
+        let tf_opt = TseriesFamOpt::from_config();
+        tf_opt.validate()?;
         for i in 0..GLOBAL_CONFIG.tsfamily_num {
             edits.insert(i, vec![]);
             let name = format!("default{}", i);
             tf_names.insert(i, name.clone());
-            tf_cfg.push(TseriesFamDesc { name, opt: TseriesFamOpt::default() });
+            tf_cfg.push(TseriesFamDesc { name, opt: tf_opt.clone() });
         }
         ctx.set_max_tsf_idy(GLOBAL_CONFIG.tsfamily_num - 1);
         loop {
             let res = rd.read_record().await.map_err(|e| Error::LogRecordErr { source: (e) });
             match res {
+                Ok(result) if result.data_type == u8::from(EditType::Features) => {
+                    FeatureBits::decode(&result.data)?.check()?;
+                },
+                Ok(result) if result.data_type == u8::from(EditType::Trash) => {
+                    match TrashEdit::decode(&result.data)? {
+                        TrashEdit::Add(purge) => {
+                            for meta in purge.files.iter() {
+                                ctx.mark_log_number_used(meta.file_id);
+                            }
+                            trash.insert(purge.path.clone(), purge);
+                        },
+                        TrashEdit::Remove { path } => {
+                            trash.remove(&path);
+                        },
+                    }
+                },
+                Ok(result) if result.data_type == u8::from(EditType::Scrub) => {
+                    let scrub = ScrubEdit::decode(&result.data)?;
+                    scrubs.insert((scrub.tsf_id, scrub.file_id), scrub);
+                },
                 Ok(result) => {
-                    let ed = VersionEdit::decode(&result.data)?;
+                    let ed = if result.data_type == u8::from(EditType::LegacySummaryEdit) {
+                        let ed = VersionEdit::decode_legacy(&result.data)?;
+                        if !ed.add_files.is_empty() || !ed.del_files.is_empty() {
+                            warn!("summary edit without the tseries families of its files, \
+                                   assumed to be of tseries family {}",
+                                  ed.tsf_id);
+                        }
+                        ed
+                    } else {
+                        VersionEdit::decode(&result.data)?
+                    };
+                    // every file id ever used is skipped, also those of a dropped family
+                    if ed.has_file_id {
+                        ctx.mark_log_number_used(ed.file_id);
+                    }
+                    for meta in ed.add_files.iter().chain(ed.del_files.iter()) {
+                        ctx.mark_log_number_used(meta.file_id);
+                    }
                     if ed.add_tsf {
                         ctx.set_max_tsf_idy(ed.tsf_id);
                         edits.insert(ed.tsf_id, vec![]);
                         tf_names.insert(ed.tsf_id, ed.tsf_name.clone());
-                        tf_cfg.push(TseriesFamDesc { name: ed.tsf_name,
-                                                     opt: TseriesFamOpt::default() });
+                        tf_cfg.push(TseriesFamDesc { name: ed.tsf_name, opt: tf_opt.clone() });
                     } else if ed.del_tsf {
                         edits.remove(&ed.tsf_id);
                         tf_names.remove(&ed.tsf_id);
-                    } else if let Some(data) = edits.get_mut(&ed.tsf_id) {
-                        data.push(ed);
+                    } else {
+                        for part in ed.split_by_tsf() {
+                            if let Some(data) = edits.get_mut(&part.tsf_id) {
+                                data.push(part);
+                            }
+                        }
                     }
                 },
                 Err(_) => break,
             }
         }
 
+        // an entry of a live tseries family is left by an undrop interrupted by a crash
+        let mut pending = vec![];
+        for purge in trash.into_values() {
+            if edits.contains_key(&purge.tsf_id) {
+                warn!("trash entry {} of live tseries family {} ignored", purge.path, purge.tsf_id);
+                continue;
+            }
+            pending.push(purge);
+        }
+
         let mut versions = HashMap::new();
+        let mut undo = vec![];
         for (id, eds) in edits {
             let tsf_name = tf_names.get(&id).unwrap().to_owned();
             // let cf_opts = cf_options.remove(cf_name).unwrap_or_default();
@@ -201,13 +544,16 @@ impl Summary {
             let mut files: HashMap<u64, CompactMeta> = HashMap::new();
             let mut max_log = 0;
             let mut max_level_ts = i64::MIN;
+            let mut skipped = VersionEdit::new();
             for e in eds {
+                if e.has_seq_no && e.seq_no > end_seq {
+                    skipped.del_files.extend(e.add_files);
+                    skipped.add_files.extend(e.del_files);
+                    continue;
+                }
                 if e.has_seq_no {
                     ctx.set_last_seq(e.seq_no);
                 }
-                if e.has_file_id {
-                    ctx.set_file_id(e.file_id);
-                }
                 max_log = std::cmp::max(max_log, e.seq_no);
                 max_level_ts = std::cmp::max(max_level_ts, e.max_level_ts);
                 for m in e.del_files {
@@ -217,6 +563,15 @@ impl Summary {
                     files.insert(m.file_id, m);
                 }
             }
+            if !skipped.del_files.is_empty() || !skipped.add_files.is_empty() {
+                // a file deleted after the end is added back as it is in the version
+                skipped.add_files.retain(|m| files.contains_key(&m.file_id));
+                for m in skipped.add_files.iter_mut() {
+                    *m = files[&m.file_id].clone();
+                }
+                skipped.tsf_id = id;
+                undo.push(skipped);
+            }
             let mut levels = HashMap::new();
             let test: CompactMeta = CompactMeta::default();
             // according files map to recover levels_info;
@@ -226,11 +581,22 @@ impl Summary {
             }
             let mut lvls: Vec<LevelInfo> = levels.into_values().collect();
             lvls.reverse();
+            for file in lvls.iter().flat_map(|info| info.files.iter()) {
+                if end_seq != u64::MAX {
+                    file.restore_until(end_seq);
+                }
+                if let Some(scrub) = scrubs.get(&(id, file.file_id())) {
+                    file.set_scrubbed_at(scrub.scrubbed_at);
+                    if scrub.corrupt {
+                        file.mark_corrupt();
+                    }
+                }
+            }
             let ver = Version::new(id, max_log, tsf_name, lvls, max_level_ts);
             versions.insert(id, Arc::new(RwLock::new(ver)));
         }
-        let vs = VersionSet::new(&tf_cfg, versions);
-        Ok(vs.await)
+        let vs = VersionSet::new(&tf_cfg, versions, pending);
+        Ok((vs.await, undo))
     }
     // apply version edit to summary file
     // and write to memory struct
@@ -246,6 +612,34 @@ impl Summary {
         Ok(())
     }
 
+    // apply the trash edits to summary file
+    pub async fn apply_trash_edit(&mut self, eds: &[TrashEdit]) -> Result<()> {
+        for edit in eds {
+            let buf = edit.encode()?;
+            let _ = self.writer
+                        .write_record(1, EditType::Trash.into(), &buf)
+                        .map_err(|e| Error::LogRecordErr { source: (e) })
+                        .await?;
+            self.writer.hard_sync().map_err(|e| Error::LogRecordErr { source: e }).await?;
+        }
+        Ok(())
+    }
+
+    // apply the scrub edits to summary file
+    pub async fn apply_scrub_edit(&mut self, eds: &[ScrubEdit]) -> Result<()> {
+        for edit in eds {
+            let buf = edit.encode()?;
+            let _ = self.writer
+                        .write_record(1, EditType::Scrub.into(), &buf)
+                        .map_err(|e| Error::LogRecordErr { source: (e) })
+                        .await?;
+        }
+        if !eds.is_empty() {
+            self.writer.hard_sync().map_err(|e| Error::LogRecordErr { source: e }).await?;
+        }
+        Ok(())
+    }
+
     pub fn version_set(&self) -> Arc<RwLock<VersionSet>> {
         self.version_set.clone()
     }
@@ -259,11 +653,13 @@ pub struct SummaryProcesser {
     summary: Box<Summary>,
     cbs: Vec<Sender<Result<()>>>,
     edits: Vec<VersionEdit>,
+    trash_edits: Vec<TrashEdit>,
+    scrub_edits: Vec<ScrubEdit>,
 }
 
 impl SummaryProcesser {
     pub fn new(summary: Box<Summary>) -> Self {
-        Self { summary, cbs: vec![], edits: vec![] }
+        Self { summary, cbs: vec![], edits: vec![], trash_edits: vec![], scrub_edits: vec![] }
     }
 
     pub fn batch(&mut self, mut task: SummaryTask) -> bool {
@@ -271,14 +667,29 @@ impl SummaryProcesser {
         if task.edits.len() == 1 && (task.edits[0].del_tsf || task.edits[0].add_tsf) {
             need_apply = true;
         }
+        if !task.trash_edits.is_empty() {
+            need_apply = true;
+        }
         self.edits.append(&mut task.edits);
+        self.trash_edits.append(&mut task.trash_edits);
+        self.scrub_edits.append(&mut task.scrub_edits);
         self.cbs.push(task.cb);
         need_apply
     }
 
     pub async fn apply(&mut self) {
         let edits = std::mem::take(&mut self.edits);
-        match self.summary.apply_version_edit(&edits).await {
+        let trash_edits = std::mem::take(&mut self.trash_edits);
+        let scrub_edits = std::mem::take(&mut self.scrub_edits);
+        let res = match self.summary.apply_version_edit(&edits).await {
+            Ok(()) => self.summary.apply_trash_edit(&trash_edits).await,
+            Err(e) => Err(e),
+        };
+        let res = match res {
+            Ok(()) => self.summary.apply_scrub_edit(&scrub_edits).await,
+            Err(e) => Err(e),
+        };
+        match res {
             Ok(()) => {
                 for cb in self.cbs.drain(..) {
                     let _ = cb.send(Ok(()));
@@ -300,9 +711,41 @@ impl SummaryProcesser {
 #[derive(Debug)]
 pub struct SummaryTask {
     pub edits: Vec<VersionEdit>,
+    // written after the edits
+    pub trash_edits: Vec<TrashEdit>,
+    // written after the trash edits
+    pub scrub_edits: Vec<ScrubEdit>,
     pub cb: Sender<Result<()>>,
 }
 
+/// Sends the edits to the summary job, returns once they are written to the summary file.
+pub async fn apply_edits(sender: &UnboundedSender<SummaryTask>,
+                         edits: Vec<VersionEdit>,
+                         trash_edits: Vec<TrashEdit>)
+                         -> Result<()> {
+    let (cb, rx) = tokio::sync::oneshot::channel();
+    let task = SummaryTask { edits, trash_edits, scrub_edits: vec![], cb };
+    sender.send(task).map_err(|err| Error::Send)?;
+    rx.await.map_err(|source| Error::Receive { source })?
+}
+
+/// Sends the edits and the scrub results to the summary job, returns once they are written to
+/// the summary file.
+pub async fn apply_scrub_edits(sender: &UnboundedSender<SummaryTask>,
+                               edits: Vec<VersionEdit>,
+                               scrub_edits: Vec<ScrubEdit>)
+                               -> Result<()> {
+    let (cb, rx) = tokio::sync::oneshot::channel();
+    let task = SummaryTask { edits, trash_edits: vec![], scrub_edits, cb };
+    sender.send(task).map_err(|err| Error::Send)?;
+    rx.await.map_err(|source| Error::Receive { source })?
+}
+
+// syncs the file or the directory, a directory is synced for the entries renamed into it
+fn sync_path(path: impl AsRef<Path>) -> Result<()> {
+    fs::File::open(path).and_then(|f| f.sync_all()).context(error::IOSnafu)
+}
+
 #[derive(Clone)]
 pub struct SummaryScheduler {
     sender: UnboundedSender<SummaryTask>,
@@ -326,6 +769,267 @@ fn test_version_edit() {
     assert_eq!(ve2, ve);
 }
 
+#[test]
+fn test_compact_meta_encoding() {
+    // the range is encoded as the ts_min and ts_max pair it replaced
+    let meta = CompactMeta { file_id: 1,
+                             file_size: 2,
+                             range: TimeRange::new(20, 10),
+                             tsf_id: 3,
+                             level: 4,
+                             high_seq: 5,
+                             low_seq: 6,
+                             is_delta: true };
+    let pair = (1_u64, 2_u64, 10_i64, 20_i64, 3_u32, 4_u32, 5_u64, 6_u64, true);
+    let buf = bincode::serialize(&meta).unwrap();
+    assert_eq!(buf, bincode::serialize(&pair).unwrap());
+    assert_eq!(bincode::deserialize::<CompactMeta>(&buf).unwrap(), meta);
+}
+
+#[tokio::test]
+async fn test_summary_features() {
+    let db_path = "/tmp/test/summary_features".to_string();
+    let _ = std::fs::remove_dir_all(&db_path);
+    std::fs::create_dir_all(&db_path).unwrap();
+    let opt = DBOptions { db_path: db_path.clone(), ..Default::default() };
+    Summary::new(&opt).await.unwrap();
+
+    // an unknown optional feature is ignored
+    let mut w = Writer::new(&file_utils::make_summary_file(&db_path, 0));
+    let buf = FeatureBits::new(0, 1 << 31).encode().unwrap();
+    w.write_record(1, EditType::Features.into(), &buf).await.unwrap();
+    w.hard_sync().await.unwrap();
+    assert!(Summary::recover(&opt).await.is_ok());
+
+    // an unknown required feature is refused
+    let buf = FeatureBits::new(1 << 31, 0).encode().unwrap();
+    w.write_record(1, EditType::Features.into(), &buf).await.unwrap();
+    w.hard_sync().await.unwrap();
+    assert!(matches!(Summary::recover(&opt).await,
+                     Err(Error::UnsupportedFeature { bits }) if bits == 1 << 31));
+}
+
+#[tokio::test]
+async fn test_summary_read_only() {
+    let dir = tempfile::tempdir().unwrap();
+    let db_path = dir.path().to_string_lossy().to_string();
+    let opt = DBOptions { db_path: db_path.clone(), ..Default::default() };
+
+    // a directory without a summary file is refused, and left without one
+    assert!(Summary::read_version_set(&opt).await.is_err());
+    assert!(!file_utils::make_summary_file(&db_path, 0).exists());
+
+    Summary::new(&opt).await.unwrap();
+    assert!(Summary::read_version_set(&opt).await.is_ok());
+}
+
+#[tokio::test]
+async fn test_summary_mixed_tsf_edits() {
+    let db_path = "/tmp/test/summary_mixed_tsf".to_string();
+    let _ = std::fs::remove_dir_all(&db_path);
+    std::fs::create_dir_all(&db_path).unwrap();
+    let opt = DBOptions { db_path: db_path.clone(), ..Default::default() };
+    Summary::new(&opt).await.unwrap();
+
+    let meta =
+        |file_id: u64| CompactMeta { file_id, range: TimeRange::new(10, 1), ..Default::default() };
+    let mut w = Writer::new(&file_utils::make_summary_file(&db_path, 0));
+    // the files of both tseries families in one edit
+    let mut edit = VersionEdit::new();
+    edit.add_file(1, 0, 1, 1, 0, meta(1));
+    edit.add_file(2, 1, 2, 1, 0, meta(2));
+    w.write_record(1, EditType::SummaryEdit.into(), &edit.encode().unwrap()).await.unwrap();
+    // an edit of the old format, its file belongs to the tseries family of the edit
+    let legacy = LegacyVersionEdit { tsf_id: 1,
+                                     add_files: vec![LegacyCompactMeta { file_id: 3,
+                                                                         level: 1,
+                                                                         ..Default::default() }],
+                                     ..Default::default() };
+    let buf = bincode::serialize(&legacy).unwrap();
+    w.write_record(1, EditType::LegacySummaryEdit.into(), &buf).await.unwrap();
+    let mut edit = VersionEdit::new();
+    edit.del_file(1, meta(2));
+    edit.add_file(2, 0, 4, 2, 0, meta(4));
+    w.write_record(1, EditType::SummaryEdit.into(), &edit.encode().unwrap()).await.unwrap();
+    w.hard_sync().await.unwrap();
+
+    let summary = Summary::recover(&opt).await.unwrap();
+    let version_set = summary.version_set();
+    let mut version_set = version_set.write().await;
+    for (tf_id, expected) in [(0, vec![(1, 1), (2, 4)]), (1, vec![(1, 3)])] {
+        let version = version_set.get_tsfamily_by_id(tf_id).unwrap().version().read().await;
+        let mut files: Vec<(u32, u64)> =
+            version.levels_info
+                   .iter()
+                   .flat_map(|info| info.files.iter().map(move |f| (info.level, f.file_id())))
+                   .collect();
+        files.sort_unstable();
+        assert_eq!(files, expected, "files of ts_family {}", tf_id);
+    }
+}
+
+#[tokio::test]
+async fn test_summary_file_id() {
+    let db_path = "/tmp/test/summary_file_id".to_string();
+    let _ = std::fs::remove_dir_all(&db_path);
+    std::fs::create_dir_all(&db_path).unwrap();
+    let opt = DBOptions { db_path: db_path.clone(), ..Default::default() };
+    Summary::new(&opt).await.unwrap();
+
+    let meta =
+        |file_id: u64| CompactMeta { file_id, range: TimeRange::new(10, 1), ..Default::default() };
+    let mut w = Writer::new(&file_utils::make_summary_file(&db_path, 0));
+    let mut edit = VersionEdit::new();
+    edit.add_file(1, 0, 5, 1, 0, meta(5));
+    w.write_record(1, EditType::SummaryEdit.into(), &edit.encode().unwrap()).await.unwrap();
+    // the ids of a dropped tseries family are not reused either
+    let mut edit = VersionEdit::new();
+    edit.add_file(1, 1, 9, 1, 0, meta(9));
+    w.write_record(1, EditType::SummaryEdit.into(), &edit.encode().unwrap()).await.unwrap();
+    let mut edit = VersionEdit::new();
+    edit.del_tsf(1);
+    w.write_record(1, EditType::SummaryEdit.into(), &edit.encode().unwrap()).await.unwrap();
+    w.hard_sync().await.unwrap();
+
+    let summary = Summary::recover(&opt).await.unwrap();
+    assert_eq!(summary.global_context().next_file_id(), 10);
+}
+
+#[tokio::test]
+async fn test_summary_recover_to() {
+    let db_path = "/tmp/test/summary_recover_to".to_string();
+    let _ = std::fs::remove_dir_all(&db_path);
+    std::fs::create_dir_all(&db_path).unwrap();
+    let opt = DBOptions { db_path: db_path.clone(), ..Default::default() };
+    Summary::new(&opt).await.unwrap();
+
+    let meta =
+        |file_id: u64| CompactMeta { file_id, range: TimeRange::new(10, 1), ..Default::default() };
+    let mut w = Writer::new(&file_utils::make_summary_file(&db_path, 0));
+    let mut edit = VersionEdit::new();
+    edit.add_file(1, 0, 1, 1, 0, meta(1));
+    w.write_record(1, EditType::SummaryEdit.into(), &edit.encode().unwrap()).await.unwrap();
+    // a compaction after the end of the restore
+    let mut edit = VersionEdit::new();
+    edit.add_file(2, 0, 2, 5, 0, meta(2));
+    edit.del_file(0, meta(1));
+    w.write_record(1, EditType::SummaryEdit.into(), &edit.encode().unwrap()).await.unwrap();
+    w.hard_sync().await.unwrap();
+
+    let files = |summary: &Summary| {
+        let version_set = summary.version_set();
+        async move {
+            let mut version_set = version_set.write().await;
+            let version = version_set.get_tsfamily_by_id(0).unwrap().version().read().await;
+            version.levels_info
+                   .iter()
+                   .flat_map(|info| info.files.iter().map(|f| f.file_id()))
+                   .collect::<Vec<_>>()
+        }
+    };
+    let (mut summary, mut undo) = Summary::recover_to(&opt, 3).await.unwrap();
+    assert_eq!(files(&summary).await, vec![1]);
+    assert_eq!(undo.len(), 1);
+    assert_eq!(undo[0].del_files.iter().map(|m| m.file_id).collect::<Vec<_>>(), vec![2]);
+    assert_eq!(undo[0].add_files, vec![CompactMeta { tsf_id: 0, level: 1, ..meta(1) }]);
+
+    // once undone, the later edits are undone on every recover
+    undo[0].has_seq_no = true;
+    undo[0].seq_no = 6;
+    summary.apply_version_edit(&undo).await.unwrap();
+    let summary = Summary::recover(&opt).await.unwrap();
+    assert_eq!(files(&summary).await, vec![1]);
+}
+
+#[tokio::test]
+async fn test_summary_trash() {
+    let db_path = "/tmp/test/summary_trash".to_string();
+    let _ = std::fs::remove_dir_all(&db_path);
+    std::fs::create_dir_all(&db_path).unwrap();
+    let opt = DBOptions { db_path: db_path.clone(), ..Default::default() };
+    Summary::new(&opt).await.unwrap();
+
+    let purge = |tsf_id: u32, path: &str| PendingPurge { tsf_id,
+                                                         tsf_name: format!("default{}", tsf_id),
+                                                         dropped_at: 1,
+                                                         ttl_secs: 10,
+                                                         path: path.to_string(),
+                                                         tsm_dir: String::new(),
+                                                         delta_dir: String::new(),
+                                                         files: vec![],
+                                                         last_seq: 0,
+                                                         max_level_ts: i64::MIN };
+    let mut w = Writer::new(&file_utils::make_summary_file(&db_path, 0));
+    for tsf_id in [0, 1] {
+        let mut edit = VersionEdit::new();
+        edit.del_tsf(tsf_id);
+        w.write_record(1, EditType::SummaryEdit.into(), &edit.encode().unwrap()).await.unwrap();
+    }
+    for edit in [TrashEdit::Add(purge(0, "trash/0-1")),
+                 TrashEdit::Add(purge(1, "trash/1-1")),
+                 TrashEdit::Remove { path: "trash/1-1".to_string() }]
+    {
+        w.write_record(1, EditType::Trash.into(), &edit.encode().unwrap()).await.unwrap();
+    }
+    // an undrop interrupted before its entry is removed
+    let restored = purge(2, "trash/2-1");
+    w.write_record(1, EditType::Trash.into(), &TrashEdit::Add(restored.clone()).encode().unwrap())
+     .await
+     .unwrap();
+    for edit in restored.restore_edits() {
+        w.write_record(1, EditType::SummaryEdit.into(), &edit.encode().unwrap()).await.unwrap();
+    }
+    w.hard_sync().await.unwrap();
+
+    let summary = Summary::recover(&opt).await.unwrap();
+    let version_set = summary.version_set();
+    let version_set = version_set.read().await;
+    assert_eq!(version_set.trash(), &[purge(0, "trash/0-1")]);
+}
+
+#[tokio::test]
+async fn test_summary_init_crash() {
+    let db_path = "/tmp/test/summary_init_crash".to_string();
+    let opt = DBOptions { db_path: db_path.clone(), ..Default::default() };
+    let summary_file = file_utils::make_summary_file(&db_path, 0);
+    let identity = file_utils::make_identity_file(&db_path);
+    let init_dir = file_utils::make_init_dir(&db_path);
+    let init_path = init_dir.to_string_lossy().to_string();
+
+    // a crash while the summary is staged, after staging, after the IDENTITY marker is moved
+    // and after the summary file is moved
+    for step in 0..4 {
+        let _ = fs::remove_dir_all(&db_path);
+        fs::create_dir_all(&db_path).unwrap();
+        if step == 0 {
+            fs::create_dir_all(&init_dir).unwrap();
+            fs::write(file_utils::make_summary_file(&init_path, 0), [1, 2, 3]).unwrap();
+        } else {
+            Summary::stage_init(&db_path).await.unwrap();
+        }
+        if step >= 2 {
+            fs::rename(file_utils::make_identity_file(&init_path), &identity).unwrap();
+        }
+        if step >= 3 {
+            fs::rename(file_utils::make_summary_file(&init_path, 0), &summary_file).unwrap();
+        }
+
+        Summary::clean_init(&db_path).unwrap();
+        assert!(!file_manager::try_exists(&init_dir), "step {}", step);
+        // the store is created once its summary file is in place, else nothing is left
+        let created = step == 3;
+        assert_eq!(file_manager::try_exists(&summary_file), created, "step {}", step);
+        assert_eq!(file_manager::try_exists(&identity), created, "step {}", step);
+        if created {
+            Summary::recover(&opt).await.unwrap();
+        } else {
+            Summary::new(&opt).await.unwrap();
+            assert!(file_manager::try_exists(&identity));
+            assert!(!file_manager::try_exists(&init_dir));
+        }
+    }
+}
+
 fn test_enum_convert() {
     let t = EditType::SummaryEdit;
     let i: u8 = t.into();
@@ -0,0 +1,513 @@
+use std::{any::Any, collections::BTreeMap, sync::Arc};
+
+use async_trait::async_trait;
+use datafusion::{
+    arrow::{
+        array::{ArrayRef, BooleanArray, Float64Array, Int64Array, StringArray, UInt64Array},
+        datatypes::{DataType as ArrowType, Field, Schema, SchemaRef},
+        record_batch::RecordBatch,
+    },
+    datasource::{TableProvider, TableType},
+    error::{DataFusionError, Result},
+    execution::context::SessionState,
+    logical_expr::{Operator, TableProviderFilterPushDown},
+    physical_plan::{memory::MemoryExec, ExecutionPlan},
+    prelude::Expr,
+    scalar::ScalarValue,
+};
+use models::{FieldId, FieldInfo, ValueType};
+use tokio::sync::RwLock;
+
+use crate::{
+    kv_option::ReadOptions, memcache::DataType, tseries_family::TimeRange, tsm::DataBlock,
+    version_set::VersionSet,
+};
+
+/// The name of the column of the timestamps.
+pub const TIME_COLUMN: &str = "time";
+// the rows of a record batch at most
+const BATCH_ROWS: usize = 8192;
+
+/// A tseries family as a table of DataFusion: the column `time` of the timestamps as i64,
+/// then a column for each field, named by the field dictionary. A row holds the points of a
+/// timestamp, the fields without a point of it are null.
+///
+/// The selected fields and the filters comparing `time` with an integer are pushed down into
+/// the scan of the tseries family. The limit is not: every selected field is scanned, but once
+/// `limit` rows are merged, the fields after are scanned only up to the last of them.
+///
+/// The scan is not streamed: every row of the time range is read and merged in memory before
+/// the first batch is returned, so a query without a narrow time range or a limit holds the
+/// whole table in memory.
+pub struct TskvTableProvider {
+    version_set: Arc<RwLock<VersionSet>>,
+    tf_id: u32,
+    // the fields of the columns after the time
+    fields: Vec<(FieldId, ValueType)>,
+    schema: SchemaRef,
+    read_opts: ReadOptions,
+}
+
+impl TskvTableProvider {
+    /// Returns the table of the fields of the tseries family, fails if a field has no known
+    /// value type.
+    pub fn try_new(version_set: Arc<RwLock<VersionSet>>,
+                   tf_id: u32,
+                   fields: &[FieldInfo])
+                   -> Result<Self> {
+        let mut columns = vec![Field::new(TIME_COLUMN, ArrowType::Int64, false)];
+        for info in fields {
+            let name = String::from_utf8_lossy(info.name());
+            columns.push(Field::new(&name, arrow_type(&name, info.value_type())?, true));
+        }
+        Ok(Self { version_set,
+                  tf_id,
+                  fields: fields.iter().map(|f| (f.field_id(), f.value_type())).collect(),
+                  schema: Arc::new(Schema::new(columns)),
+                  read_opts: ReadOptions::default() })
+    }
+
+    pub fn with_read_options(mut self, read_opts: ReadOptions) -> Self {
+        self.read_opts = read_opts;
+        self
+    }
+
+    // reads the rows of the columns in the projection in timestamp order, the first `limit`
+    // of them if set
+    async fn read(&self,
+                  projection: &[usize],
+                  time_range: &TimeRange,
+                  limit: Option<usize>)
+                  -> Result<BTreeMap<i64, Vec<Option<DataType>>>> {
+        let mut rows: BTreeMap<i64, Vec<Option<DataType>>> = BTreeMap::new();
+        let mut time_range = *time_range;
+        if time_range.min_ts > time_range.max_ts {
+            return Ok(rows);
+        }
+        let version_set = self.version_set.read().await;
+        let tsf = version_set.tsfamilies()
+                             .find(|tsf| tsf.tf_id() == self.tf_id)
+                             .ok_or_else(|| {
+                                 DataFusionError::Execution(format!("tseries family {} not found",
+                                                                    self.tf_id))
+                             })?;
+        let fields: Vec<_> =
+            projection.iter().filter(|i| **i > 0).map(|i| self.fields[i - 1]).collect();
+        for (i, (field_id, value_type)) in fields.iter().enumerate() {
+            let (data, stats) = tsf.scan_with(*field_id, &time_range, &self.read_opts).await;
+            stats.check().map_err(external)?;
+            for cell in data {
+                let cell = cell.coerce(*value_type).map_err(external)?;
+                let row = rows.entry(cell.timestamp()).or_insert_with(|| vec![None; fields.len()]);
+                row[i] = Some(cell);
+            }
+            cut_rows(&mut rows, limit, &mut time_range);
+        }
+        // only the time is selected, a row for every timestamp of a field
+        if fields.is_empty() {
+            for (field_id, _) in self.fields.iter() {
+                for ts in tsf.timestamps(*field_id, &time_range).await.map_err(external)? {
+                    rows.entry(ts).or_default();
+                }
+                cut_rows(&mut rows, limit, &mut time_range);
+            }
+        }
+        Ok(rows)
+    }
+}
+
+#[async_trait]
+impl TableProvider for TskvTableProvider {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+
+    fn table_type(&self) -> TableType {
+        TableType::Base
+    }
+
+    async fn scan(&self,
+                  _ctx: &SessionState,
+                  projection: &Option<Vec<usize>>,
+                  filters: &[Expr],
+                  limit: Option<usize>)
+                  -> Result<Arc<dyn ExecutionPlan>> {
+        let mut time_range = TimeRange::new(i64::MAX, i64::MIN);
+        for filter in filters {
+            narrow_time_range(filter, &mut time_range);
+        }
+        let projection = match projection {
+            Some(projection) => projection.clone(),
+            None => (0..self.schema.fields().len()).collect(),
+        };
+        let schema = Arc::new(self.schema.project(&projection)?);
+        let rows: Vec<_> = self.read(&projection, &time_range, limit).await?.into_iter().collect();
+
+        let mut batches = vec![];
+        for chunk in rows.chunks(BATCH_ROWS) {
+            let mut columns: Vec<ArrayRef> = vec![];
+            let mut field = 0;
+            for i in projection.iter() {
+                if *i == 0 {
+                    columns.push(Arc::new(chunk.iter()
+                                               .map(|(ts, _)| Some(*ts))
+                                               .collect::<Int64Array>()));
+                    continue;
+                }
+                let cells = chunk.iter().map(|(_, row)| row[field].as_ref());
+                columns.push(build_column(self.fields[i - 1].1, cells));
+                field += 1;
+            }
+            batches.push(RecordBatch::try_new(schema.clone(), columns)?);
+        }
+        Ok(Arc::new(MemoryExec::try_new(&[batches], schema, None)?))
+    }
+
+    fn supports_filter_pushdown(&self, filter: &Expr) -> Result<TableProviderFilterPushDown> {
+        let mut time_range = TimeRange::new(i64::MAX, i64::MIN);
+        if narrow_time_range(filter, &mut time_range) {
+            Ok(TableProviderFilterPushDown::Exact)
+        } else {
+            Ok(TableProviderFilterPushDown::Unsupported)
+        }
+    }
+}
+
+// keeps the first `limit` rows; once there are as many, a later timestamp can not be in the
+// result, so the range of the next scans ends at the last row kept
+fn cut_rows<T>(rows: &mut BTreeMap<i64, T>, limit: Option<usize>, time_range: &mut TimeRange) {
+    let limit = match limit {
+        Some(limit) => limit,
+        None => return,
+    };
+    if let Some(ts) = rows.keys().nth(limit).copied() {
+        rows.split_off(&ts);
+    }
+    if rows.len() == limit {
+        if let Some(ts) = rows.keys().next_back() {
+            time_range.max_ts = time_range.max_ts.min(*ts);
+        }
+    }
+}
+
+fn external(e: crate::Error) -> DataFusionError {
+    DataFusionError::Execution(e.to_string())
+}
+
+fn arrow_type(name: &str, value_type: ValueType) -> Result<ArrowType> {
+    match value_type {
+        ValueType::Float => Ok(ArrowType::Float64),
+        ValueType::Integer => Ok(ArrowType::Int64),
+        ValueType::Unsigned => Ok(ArrowType::UInt64),
+        ValueType::Boolean => Ok(ArrowType::Boolean),
+        ValueType::String => Ok(ArrowType::Utf8),
+        ValueType::Unknown => {
+            Err(DataFusionError::Plan(format!("field {} has no known value type", name)))
+        },
+    }
+}
+
+// the cells are converted to the value type already
+fn build_column<'a>(value_type: ValueType,
+                    cells: impl Iterator<Item = Option<&'a DataType>>)
+                    -> ArrayRef {
+    match value_type {
+        ValueType::Float => Arc::new(cells.map(|c| match c {
+                                              Some(DataType::F64(c)) => Some(c.val),
+                                              _ => None,
+                                          })
+                                          .collect::<Float64Array>()),
+        ValueType::Integer => Arc::new(cells.map(|c| match c {
+                                                Some(DataType::I64(c)) => Some(c.val),
+                                                _ => None,
+                                            })
+                                            .collect::<Int64Array>()),
+        ValueType::Unsigned => Arc::new(cells.map(|c| match c {
+                                                 Some(DataType::U64(c)) => Some(c.val),
+                                                 _ => None,
+                                             })
+                                             .collect::<UInt64Array>()),
+        ValueType::Boolean => Arc::new(cells.map(|c| match c {
+                                                Some(DataType::Bool(c)) => Some(c.val),
+                                                _ => None,
+                                            })
+                                            .collect::<BooleanArray>()),
+        _ => Arc::new(cells.map(|c| match c {
+                               Some(DataType::Str(c)) => {
+                                   Some(String::from_utf8_lossy(&c.val).to_string())
+                               },
+                               _ => None,
+                           })
+                           .collect::<StringArray>()),
+    }
+}
+
+/// Returns the values of the block as an Arrow array of the value type, the null points of the
+/// block are the nulls of the array.
+pub fn block_to_array(block: &DataBlock) -> ArrayRef {
+    let points = 0..block.len();
+    match block {
+        DataBlock::F64 { .. } => {
+            Arc::new(points.map(|i| block.val_at::<f64>(i)).collect::<Float64Array>())
+        },
+        DataBlock::I64 { .. } => {
+            Arc::new(points.map(|i| block.val_at::<i64>(i)).collect::<Int64Array>())
+        },
+        DataBlock::U64 { .. } => {
+            Arc::new(points.map(|i| block.val_at::<u64>(i)).collect::<UInt64Array>())
+        },
+        DataBlock::Bool { .. } => {
+            Arc::new(points.map(|i| block.bool_at(i)).collect::<BooleanArray>())
+        },
+        DataBlock::Str { .. } => {
+            let str_at = |i| block.str_at(i).map(|s| String::from_utf8_lossy(s).to_string());
+            Arc::new(points.map(str_at).collect::<StringArray>())
+        },
+    }
+}
+
+// narrows the time range by a filter comparing the time with an integer, returns false for
+// any other filter
+fn narrow_time_range(filter: &Expr, time_range: &mut TimeRange) -> bool {
+    let is_time = |e: &Expr| matches!(e, Expr::Column(c) if c.name == TIME_COLUMN);
+    let int = |e: &Expr| match e {
+        Expr::Literal(ScalarValue::Int64(Some(v))) => Some(*v),
+        _ => None,
+    };
+    let (min_ts, max_ts) = match filter {
+        Expr::BinaryExpr { left, op, right } => {
+            let (left, right) = (left.as_ref(), right.as_ref());
+            // the operator as if the time is on the left
+            let (op, ts) = match (is_time(left), int(left), is_time(right), int(right)) {
+                (true, _, _, Some(ts)) => (*op, ts),
+                (_, Some(ts), true, _) => match op {
+                    Operator::Lt => (Operator::Gt, ts),
+                    Operator::LtEq => (Operator::GtEq, ts),
+                    Operator::Gt => (Operator::Lt, ts),
+                    Operator::GtEq => (Operator::LtEq, ts),
+                    op => (*op, ts),
+                },
+                _ => return false,
+            };
+            // no timestamp is before i64::MIN or after i64::MAX
+            let empty = (i64::MAX, i64::MIN);
+            match op {
+                Operator::Eq => (ts, ts),
+                Operator::Lt => ts.checked_sub(1).map_or(empty, |ts| (i64::MIN, ts)),
+                Operator::LtEq => (i64::MIN, ts),
+                Operator::Gt => ts.checked_add(1).map_or(empty, |ts| (ts, i64::MAX)),
+                Operator::GtEq => (ts, i64::MAX),
+                _ => return false,
+            }
+        },
+        Expr::Between { expr, negated: false, low, high } if is_time(expr.as_ref()) => {
+            match (int(low.as_ref()), int(high.as_ref())) {
+                (Some(low), Some(high)) => (low, high),
+                _ => return false,
+            }
+        },
+        _ => return false,
+    };
+    time_range.min_ts = time_range.min_ts.max(min_ts);
+    time_range.max_ts = time_range.max_ts.min(max_ts);
+    true
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::{BTreeMap, HashMap},
+        sync::Arc,
+    };
+
+    use datafusion::{
+        arrow::array::{Array, BooleanArray, Float64Array, Int64Array, StringArray, UInt64Array},
+        datasource::TableProvider,
+        logical_expr::TableProviderFilterPushDown,
+        prelude::{col, lit, SessionContext},
+    };
+    use models::{FieldInfo, ValueType};
+    use tokio::sync::RwLock;
+
+    use super::{block_to_array, cut_rows, narrow_time_range, TskvTableProvider};
+    use crate::{
+        compaction::build_tsm_file,
+        file_utils::make_tsm_file_name,
+        kv_option::{TseriesFamDesc, TseriesFamOpt},
+        summary::CompactMeta,
+        tseries_family::{LevelInfo, TimeRange, Version},
+        tsm::DataBlock,
+        version_set::VersionSet,
+    };
+
+    #[tokio::test]
+    async fn test_table_provider_sql() {
+        let tmp = tempfile::tempdir().unwrap();
+        let tf_id = 0;
+        let opt = TseriesFamOpt::for_testing(tmp.path());
+        let cpu = FieldInfo::new(1, b"cpu".to_vec(), ValueType::Float);
+        let mem = FieldInfo::new(1, b"mem".to_vec(), ValueType::Integer);
+        // a flushed file of cpu at every timestamp and mem at the even ones
+        let tsm_dir = opt.tsm_dir(tf_id);
+        std::fs::create_dir_all(&tsm_dir).unwrap();
+        let ts: Vec<i64> = (1..=20).collect();
+        let cpu_block = DataBlock::F64 { index: 0,
+                                         ts: ts.clone(),
+                                         val: ts.iter().map(|t| *t as f64 / 2.0).collect(),
+                                         validity: None };
+        let even_ts = ts.iter().copied().filter(|t| t % 2 == 0).collect();
+        let mem_block =
+            DataBlock::I64 { index: 0, ts: even_ts, val: (1..=10).collect(), validity: None };
+        let blocks = HashMap::from([(cpu.field_id(), cpu_block), (mem.field_id(), mem_block)]);
+        build_tsm_file(make_tsm_file_name(&tsm_dir, 1), blocks).unwrap();
+        let mut lvl = LevelInfo::init_in(&opt.base_dir, 1);
+        lvl.apply(&CompactMeta { file_id: 1, range: TimeRange::new(20, 1), ..Default::default() });
+        let version = Version::new(tf_id, 1, "db".to_string(), vec![lvl], 0);
+        let desc = [TseriesFamDesc { name: "db".to_string(), opt }];
+        let versions = HashMap::from([(tf_id, Arc::new(RwLock::new(version)))]);
+        let version_set = Arc::new(RwLock::new(VersionSet::new(&desc, versions, vec![]).await));
+
+        let table = TskvTableProvider::try_new(version_set, tf_id, &[cpu, mem]).unwrap();
+        assert_eq!(table.supports_filter_pushdown(&col("time").gt(lit(3_i64))).unwrap(),
+                   TableProviderFilterPushDown::Exact);
+        assert_eq!(table.supports_filter_pushdown(&col("cpu").gt(lit(3_i64))).unwrap(),
+                   TableProviderFilterPushDown::Unsupported);
+        let ctx = SessionContext::new();
+        ctx.register_table("m", Arc::new(table)).unwrap();
+
+        let query = |sql: &'static str| {
+            let ctx = ctx.clone();
+            async move {
+                let batches = ctx.sql(sql).await.unwrap().collect().await.unwrap();
+                let mut rows = vec![];
+                for batch in batches {
+                    let time = batch.column(0).as_any().downcast_ref::<Int64Array>().unwrap();
+                    for i in 0..batch.num_rows() {
+                        let value = match batch.column(1).as_any().downcast_ref::<Float64Array>() {
+                            Some(cpu) => cpu.value(i),
+                            // a null mem is NaN
+                            None => {
+                                let mem = batch.column(1).as_any().downcast_ref::<Int64Array>();
+                                let mem = mem.unwrap();
+                                if mem.is_valid(i) {
+                                    mem.value(i) as f64
+                                } else {
+                                    f64::NAN
+                                }
+                            },
+                        };
+                        rows.push((time.value(i), value));
+                    }
+                }
+                rows
+            }
+        };
+        let rows = query("SELECT time, cpu FROM m WHERE time BETWEEN 3 AND 17 LIMIT 10").await;
+        assert_eq!(rows, (3..=12).map(|t| (t, t as f64 / 2.0)).collect::<Vec<_>>());
+
+        // a filter on a field is left to DataFusion, a field without a point is null
+        let rows = query("SELECT time, mem FROM m WHERE cpu > 8 AND time < 20").await;
+        assert_eq!(rows.iter().map(|(t, _)| *t).collect::<Vec<_>>(), vec![17, 18, 19]);
+        assert!(rows[0].1.is_nan() && rows[2].1.is_nan());
+        assert_eq!(rows[1].1, 9.0);
+    }
+
+    #[test]
+    fn test_cut_rows() {
+        let mut rows: BTreeMap<i64, ()> = (1..=5).map(|ts| (ts * 2, ())).collect();
+        let mut time_range = TimeRange::new(100, 0);
+        cut_rows(&mut rows, None, &mut time_range);
+        assert_eq!((rows.len(), time_range.max_ts), (5, 100));
+
+        // the next scans end at the last row kept
+        cut_rows(&mut rows, Some(3), &mut time_range);
+        assert_eq!(rows.keys().copied().collect::<Vec<_>>(), vec![2, 4, 6]);
+        assert_eq!(time_range.max_ts, 6);
+
+        // under the limit, the range is kept
+        let mut time_range = TimeRange::new(100, 0);
+        cut_rows(&mut rows, Some(4), &mut time_range);
+        assert_eq!((rows.len(), time_range.max_ts), (3, 100));
+    }
+
+    #[test]
+    fn test_narrow_time_range() {
+        let narrow = |filter| {
+            let mut time_range = TimeRange::new(i64::MAX, i64::MIN);
+            assert!(narrow_time_range(&filter, &mut time_range));
+            (time_range.min_ts, time_range.max_ts)
+        };
+        assert_eq!(narrow(col("time").lt(lit(10_i64))), (i64::MIN, 9));
+        assert_eq!(narrow(lit(10_i64).lt(col("time"))), (11, i64::MAX));
+        // no timestamp is out of the range of i64, the range is empty
+        assert_eq!(narrow(col("time").lt(lit(i64::MIN))), (i64::MAX, i64::MIN));
+        assert_eq!(narrow(col("time").gt(lit(i64::MAX))), (i64::MAX, i64::MIN));
+        assert_eq!(narrow(col("time").lt_eq(lit(i64::MIN))), (i64::MIN, i64::MIN));
+    }
+
+    #[test]
+    fn test_block_to_array() {
+        // nulls at the first, the 3rd and the last of 10 points
+        let nulls = [0, 2, 9];
+        let ts: Vec<i64> = (1..=10).collect();
+        let with_nulls = |mut block: DataBlock| {
+            block.set_validity(Some((0..10).map(|i| !nulls.contains(&i)).collect()));
+            block
+        };
+        let floats = with_nulls(DataBlock::F64 { index: 0,
+                                                 ts: ts.clone(),
+                                                 val: ts.iter().map(|t| *t as f64).collect(),
+                                                 validity: None });
+        let array = block_to_array(&floats);
+        let array = array.as_any().downcast_ref::<Float64Array>().unwrap();
+        assert_eq!(array.len(), 10);
+        assert_eq!(array.null_count(), 3);
+        for i in 0..10 {
+            assert_eq!(array.is_null(i), nulls.contains(&i));
+            if array.is_valid(i) {
+                assert_eq!(array.value(i), ts[i] as f64);
+            }
+        }
+
+        let integers = with_nulls(DataBlock::I64 { index: 0,
+                                                   ts: ts.clone(),
+                                                   val: ts.clone(),
+                                                   validity: None });
+        let unsigned = with_nulls(DataBlock::U64 { index: 0,
+                                                   ts: ts.clone(),
+                                                   val: ts.iter().map(|t| *t as u64).collect(),
+                                                   validity: None });
+        let bools = with_nulls(DataBlock::Bool { index: 0,
+                                                 ts: ts.clone(),
+                                                 val: vec![true; 10],
+                                                 validity: None });
+        let strs = with_nulls(DataBlock::Str { index: 0,
+                                               ts: ts.clone(),
+                                               val: ts.iter()
+                                                      .map(|t| t.to_string().into_bytes())
+                                                      .collect(),
+                                               validity: None });
+        let array = block_to_array(&integers);
+        let array = array.as_any().downcast_ref::<Int64Array>().unwrap();
+        assert_eq!((array.null_count(), array.value(1)), (3, 2));
+        let array = block_to_array(&unsigned);
+        let array = array.as_any().downcast_ref::<UInt64Array>().unwrap();
+        assert_eq!((array.null_count(), array.value(3)), (3, 4));
+        let array = block_to_array(&bools);
+        let array = array.as_any().downcast_ref::<BooleanArray>().unwrap();
+        assert_eq!((array.null_count(), array.value(1)), (3, true));
+        let array = block_to_array(&strs);
+        let array = array.as_any().downcast_ref::<StringArray>().unwrap();
+        assert_eq!((array.null_count(), array.value(8)), (3, "9"));
+        assert!(array.is_null(9));
+
+        // a block without nulls maps to an array without nulls
+        let array =
+            block_to_array(&DataBlock::I64 { index: 0, ts: vec![1], val: vec![1], validity: None });
+        assert_eq!((array.len(), array.null_count()), (1, 0));
+    }
+}
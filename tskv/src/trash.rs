@@ -0,0 +1,93 @@
+use std::{
+    fs,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use logger::warn;
+use snafu::ResultExt;
+use tokio::sync::{mpsc::UnboundedSender, RwLock};
+
+use crate::{
+    error, file_manager,
+    summary::{self, PendingPurge, SummaryTask, TrashEdit},
+    version_set::VersionSet,
+    Error, Result,
+};
+
+// the directories of a tseries family in its trash entry
+const TSM_DIR: &str = "tsm";
+const DELTA_DIR: &str = "delta";
+
+/// Returns the seconds since the epoch, the clock of the trash entries.
+pub fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Moves the directories of the tseries family into its trash entry. A directory already
+/// moved is skipped, so that a move interrupted by a crash is finished by moving again.
+pub fn move_to_trash(purge: &PendingPurge) -> Result<()> {
+    let entry = Path::new(&purge.path);
+    fs::create_dir_all(entry).context(error::IOSnafu)?;
+    for (dir, name) in [(&purge.tsm_dir, TSM_DIR), (&purge.delta_dir, DELTA_DIR)] {
+        if file_manager::try_exists(dir) {
+            fs::rename(dir, entry.join(name)).context(error::IOSnafu)?;
+        }
+    }
+    Ok(())
+}
+
+/// Moves the directories of the tseries family back and removes its trash entry.
+pub fn restore(purge: &PendingPurge) -> Result<()> {
+    let entry = Path::new(&purge.path);
+    if !file_manager::try_exists(entry) {
+        return Err(Error::TrashNotFound { tf_id: purge.tsf_id });
+    }
+    for (dir, name) in [(&purge.tsm_dir, TSM_DIR), (&purge.delta_dir, DELTA_DIR)] {
+        let src = entry.join(name);
+        if !file_manager::try_exists(&src) {
+            continue;
+        }
+        if let Some(parent) = Path::new(dir).parent() {
+            fs::create_dir_all(parent).context(error::IOSnafu)?;
+        }
+        fs::rename(src, dir).context(error::IOSnafu)?;
+    }
+    fs::remove_dir(entry).context(error::IOSnafu)
+}
+
+/// Deletes the trash entry with the directories in it.
+pub fn purge(pending: &PendingPurge) -> Result<()> {
+    if file_manager::try_exists(&pending.path) {
+        fs::remove_dir_all(&pending.path).context(error::IOSnafu)?;
+    }
+    Ok(())
+}
+
+/// Purges the trash entries expired at `now`, in seconds since the epoch, and removes them
+/// from the summary. Returns the ids of the purged tseries families; an entry failing to be
+/// purged is kept for the next purge.
+pub async fn purge_expired(version_set: &RwLock<VersionSet>,
+                           summary_task_sender: &UnboundedSender<SummaryTask>,
+                           now: u64)
+                           -> Result<Vec<u32>> {
+    let expired = version_set.write().await.take_expired_trash(now);
+    let mut purged = vec![];
+    let mut trash_edits = vec![];
+    for entry in expired {
+        match purge(&entry) {
+            Ok(()) => {
+                purged.push(entry.tsf_id);
+                trash_edits.push(TrashEdit::Remove { path: entry.path });
+            },
+            Err(e) => {
+                warn!("failed to purge trash entry {}: {:?}", entry.path, e);
+                version_set.write().await.add_trash(entry);
+            },
+        }
+    }
+    if !trash_edits.is_empty() {
+        summary::apply_edits(summary_task_sender, vec![], trash_edits).await?;
+    }
+    Ok(purged)
+}
@@ -1,45 +1,64 @@
 use std::{
-    borrow::{Borrow, BorrowMut},
+    borrow::{Borrow, BorrowMut, Cow},
     cell::{Ref, RefCell},
-    cmp::min,
+    cmp::{min, Reverse},
+    collections::{HashMap, HashSet},
+    fmt::{self, Display},
     mem::replace,
     ops::{Deref, DerefMut},
+    panic::{self, AssertUnwindSafe},
     rc::Rc,
     sync::{
-        atomic::{AtomicBool, AtomicU64, Ordering},
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
         Arc,
     },
+    thread,
+    time::{Duration, Instant},
 };
 
 use config::GLOBAL_CONFIG;
 use crossbeam::channel::internal::SelectHandle;
-use lazy_static::lazy_static;
+use futures::{stream, StreamExt};
 use logger::{debug, info, warn};
-use models::{FieldId, ValueType};
+use models::{FieldId, Timestamp, ValueType};
+use once_cell::sync::OnceCell;
 use parking_lot::Mutex;
-use tokio::sync::{mpsc::UnboundedSender, RwLock};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc::Sender, OwnedRwLockReadGuard, RwLock};
 use utils::BloomFilter;
 
 use crate::{
-    compaction::FlushReq,
+    compaction::{send_flush_req, FlushReq, LogEvent},
+    debug_dump::{DumpField, OptionsDump, TsfDebugDump, VersionDump},
     direct_io::FileCursor,
-    file_manager::get_file_manager,
-    kv_option::TseriesFamOpt,
-    memcache::{DataType, MemCache},
-    new_bloom_filter,
+    file_manager::{self, get_file_manager},
+    file_utils::{self, make_delta_file_name, make_tsm_file_name, make_tsm_tombstone_file_name},
+    kv_option::{DuplicatePolicy, ReadOptions, TseriesFamOpt},
+    last_value::LastValueCache,
+    lru_cache::CacheStats,
+    memcache::{
+        check_utf8, new_memcache, CacheSummary, DataType, FieldHints, MemCacheRef, MemCacheTrait,
+    },
+    merge::MergeStream,
+    result_cache::ResultCache,
     summary::{CompactMeta, VersionEdit},
-    tsm::{BlockReader, TsmBlockReader, TsmIndexReader},
+    trash,
+    tsm::{
+        BlockReader, DataBlock, FileBlock, TombstoneIndex, TombstoneSet, TsmBlockReader,
+        TsmFooterReader, TsmIndexReader, TsmTombstone,
+    },
+    write_stats::{WriteRejectReason, WriteRejects},
     Error,
 };
 
-lazy_static! {
-    pub static ref FLUSH_REQ: Arc<Mutex<Vec<FlushReq>>> = Arc::new(Mutex::new(vec![]));
-}
-
-#[derive(Default, Debug)]
+/// An inclusive range of timestamps, empty if `min_ts` is over `max_ts`. Ranges are ordered
+/// by `min_ts`, then by `max_ts`.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
 pub struct TimeRange {
-    pub max_ts: i64,
+    // declared in the order of the ordering, and of the ts_min and ts_max pair the edits were
+    // encoded with before they held a range
     pub min_ts: i64,
+    pub max_ts: i64,
 }
 
 impl TimeRange {
@@ -47,9 +66,53 @@ impl TimeRange {
         Self { max_ts, min_ts }
     }
 
+    /// The empty range to merge ranges into.
+    pub fn none() -> Self {
+        Self { min_ts: i64::MAX, max_ts: i64::MIN }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.min_ts > self.max_ts
+    }
+
+    /// Returns the smallest range holding both ranges.
+    pub fn merge(&self, other: &TimeRange) -> TimeRange {
+        if other.is_empty() {
+            return *self;
+        }
+        if self.is_empty() {
+            return *other;
+        }
+        Self { min_ts: self.min_ts.min(other.min_ts), max_ts: self.max_ts.max(other.max_ts) }
+    }
+
+    /// Returns `max_ts - min_ts`, 0 for an empty range.
+    pub fn duration(&self) -> u64 {
+        if self.is_empty() {
+            return 0;
+        }
+        // the difference of any two i64 fits in a u64
+        self.max_ts.wrapping_sub(self.min_ts) as u64
+    }
+
+    /// Returns the part of the range within `bounds`, None if they do not overlap.
+    pub fn clamp(&self, bounds: &TimeRange) -> Option<TimeRange> {
+        let range =
+            Self { min_ts: self.min_ts.max(bounds.min_ts), max_ts: self.max_ts.min(bounds.max_ts) };
+        if range.is_empty() {
+            None
+        } else {
+            Some(range)
+        }
+    }
+
     pub fn overlaps(&self, range: &TimeRange) -> bool {
         !(self.min_ts > range.max_ts || self.max_ts < range.min_ts)
     }
+
+    pub fn contains(&self, ts: i64) -> bool {
+        ts >= self.min_ts && ts <= self.max_ts
+    }
 }
 
 #[derive(Debug)]
@@ -57,10 +120,39 @@ pub struct ColumnFile {
     file_id: u64,
     being_compact: AtomicBool,
     deleted: AtomicBool,
+    // seconds since the epoch the file was marked removed, 0 while it is live
+    removed_at: AtomicU64,
     range: TimeRange, // file time range
     size: u64,        // file size
-    field_id_bloom_filter: BloomFilter,
+    // the wal sequences of the first and the last write of the points of the file
+    seq_range: (u64, u64),
+    // loaded from the file on the first probe or by the warm task
+    field_presence: OnceCell<FieldPresence>,
+    // loaded on the first read or by the warm task, reset when a delete is appended
+    tombstones: Mutex<Option<Arc<TombstoneSet>>>,
+    // the deletes after this wal sequence are ignored, u64::MAX unless the store is restored
+    // to an earlier point
+    tombstone_end_seq: AtomicU64,
     is_delta: bool,
+    read_count: AtomicU64,
+    // seconds since the epoch the checksums of the file were last verified, 0 if never
+    scrubbed_at: AtomicU64,
+    corrupt: AtomicBool,
+    // a block was found out of the time range of its index entry, see `mark_range_mismatch`
+    range_mismatch: AtomicBool,
+    // the base directory of the tseries family the file belongs to
+    base_dir: String,
+    // the reads of the file panic, for the tests of the failed reads
+    #[cfg(test)]
+    panic_on_read: AtomicBool,
+}
+
+/// The fields of a column file, files written before the sorted field ids are probed with the
+/// bloom filter of the footer.
+#[derive(Debug)]
+enum FieldPresence {
+    Sorted(Vec<FieldId>),
+    Bloom(BloomFilter),
 }
 
 impl ColumnFile {
@@ -73,18 +165,41 @@ impl ColumnFile {
     pub fn range(&self) -> &TimeRange {
         &self.range
     }
+    pub fn is_delta(&self) -> bool {
+        self.is_delta
+    }
 
-    pub fn file_reader(&self, tf_id: u32) -> Result<(FileCursor, u64), Error> {
-        let fs = get_file_manager();
-        let ts_cf = TseriesFamOpt::default();
-        let fs = if self.is_delta {
-            let p = format!("/_{:06}.delta", self.file_id());
-            fs.open_file(ts_cf.delta_dir + tf_id.to_string().as_str() + p.as_str())
+    /// The wal sequences of the first and the last write of the points of the file,
+    /// `(0, u64::MAX)` for a file that does not record them.
+    pub fn seq_range(&self) -> (u64, u64) {
+        self.seq_range
+    }
+
+    fn dir(&self, tf_id: u32) -> String {
+        let dir = if self.is_delta {
+            file_utils::make_delta_dir(&self.base_dir, tf_id)
+        } else {
+            file_utils::make_tsm_dir(&self.base_dir, tf_id)
+        };
+        dir.to_string_lossy().to_string()
+    }
+
+    pub fn path(&self, tf_id: u32) -> String {
+        let path = if self.is_delta {
+            make_delta_file_name(&self.dir(tf_id), self.file_id)
         } else {
-            let p = format!("/_{:06}.tsm", self.file_id());
-            fs.open_file(ts_cf.tsm_dir + tf_id.to_string().as_str() + p.as_str())
+            make_tsm_file_name(&self.dir(tf_id), self.file_id)
         };
-        match fs {
+        path.to_string_lossy().to_string()
+    }
+
+    pub fn tombstone_path(&self, tf_id: u32) -> String {
+        make_tsm_tombstone_file_name(&self.dir(tf_id), self.file_id).to_string_lossy().to_string()
+    }
+
+    pub fn file_reader(&self, tf_id: u32) -> Result<(FileCursor, u64), Error> {
+        self.read_count.fetch_add(1, Ordering::Relaxed);
+        match get_file_manager().open_file(self.path(tf_id)) {
             Ok(v) => {
                 let len = v.len();
                 Ok((v.into_cursor(), len))
@@ -96,6 +211,546 @@ impl ColumnFile {
     pub fn overlap(&self, time_range: &TimeRange) -> bool {
         self.range.overlaps(time_range)
     }
+
+    pub fn read_field(&self,
+                      tf_id: u32,
+                      field_id: FieldId,
+                      time_range: &TimeRange)
+                      -> Result<Vec<DataType>, Error> {
+        self.read_field_with(tf_id, field_id, time_range, &ReadOptions::default())
+    }
+
+    /// Returns the points of a field in the time range like `read_field`, with the points
+    /// deleted by the tombstones of the file if `include_deleted` is set. The decoded blocks
+    /// are admitted to the block cache as the `cache_policy` says.
+    ///
+    /// With `verify_block_ranges` a block whose timestamps fall out of the time range of its
+    /// index entry is logged and read by the range of its timestamps instead, see
+    /// `widen_block_ranges`.
+    pub fn read_field_with(&self,
+                           tf_id: u32,
+                           field_id: FieldId,
+                           time_range: &TimeRange,
+                           read_opts: &ReadOptions)
+                           -> Result<Vec<DataType>, Error> {
+        let tombstones = if read_opts.include_deleted {
+            TombstoneIndex::default()
+        } else {
+            self.tombstone_index(tf_id, field_id)?
+        };
+        let (mut fs_cursor, len) = self.file_reader(tf_id)?;
+        let index = TsmIndexReader::try_new(&mut fs_cursor, len as usize)?;
+        let mut blocks = Vec::new();
+        for res in index {
+            let entry = res?;
+            if entry.field_id() == field_id {
+                blocks.push(entry.block);
+            }
+        }
+        if read_opts.verify_block_ranges {
+            self.widen_block_ranges(tf_id, field_id, &mut fs_cursor, &mut blocks)?;
+        }
+        // skip the blocks deleted as a whole
+        blocks.retain(|block| {
+                  let block_range = TimeRange::new(block.max_ts, block.min_ts);
+                  time_range.overlaps(&block_range) && !tombstones.covers(&block_range)
+              });
+
+        let path = self.path(tf_id);
+        TsmBlockReader::new(&mut fs_cursor).with_block_cache(&path, len, read_opts.cache_policy)
+                                           .read_data(&blocks, time_range, &tombstones)
+    }
+
+    /// Returns the blocks of a field in the order of the index, each sorted by timestamp, with
+    /// the points deleted by the tombstones of the file removed and the null points kept. The
+    /// blocks are decoded whole, whatever the time ranges of their index entries; a compaction
+    /// merges them.
+    pub fn read_field_blocks(&self,
+                             tf_id: u32,
+                             field_id: FieldId)
+                             -> Result<Vec<DataBlock>, Error> {
+        let tombstones = self.tombstone_index(tf_id, field_id)?;
+        let (mut fs_cursor, len) = self.file_reader(tf_id)?;
+        let mut blocks = Vec::new();
+        for res in TsmIndexReader::try_new(&mut fs_cursor, len as usize)? {
+            let entry = res?;
+            if entry.field_id() == field_id {
+                blocks.push(entry.block);
+            }
+        }
+        let mut reader = TsmBlockReader::new(&mut fs_cursor);
+        let mut res = Vec::with_capacity(blocks.len());
+        for block in blocks.iter() {
+            let mut data = reader.decode(block)?;
+            data.sort(DuplicatePolicy::KeepAll);
+            tombstones.filter(&mut data);
+            res.push(data);
+        }
+        Ok(res)
+    }
+
+    // sets the time range of the blocks whose timestamps fall out of it to the range of their
+    // timestamps, so that the reads neither skip them nor trim their points by the index; the
+    // file is marked to be rewritten by the next compaction
+    fn widen_block_ranges(&self,
+                          tf_id: u32,
+                          field_id: FieldId,
+                          fs_cursor: &mut FileCursor,
+                          blocks: &mut [FileBlock])
+                          -> Result<(), Error> {
+        let mut reader = TsmBlockReader::new(fs_cursor);
+        for block in blocks.iter_mut() {
+            let ts = reader.decode_timestamps_only(block)?;
+            let (min_ts, max_ts) = match (ts.iter().min(), ts.iter().max()) {
+                (Some(min_ts), Some(max_ts)) => (*min_ts, *max_ts),
+                _ => continue,
+            };
+            if min_ts >= block.min_ts && max_ts <= block.max_ts {
+                continue;
+            }
+            warn!("{}",
+                  LogEvent::new("block_range_mismatch").field("tf_id", tf_id)
+                                                       .field("file_id", self.file_id)
+                                                       .field("field_id", field_id)
+                                                       .field("offset", block.offset)
+                                                       .field("meta_min_ts", block.min_ts)
+                                                       .field("meta_max_ts", block.max_ts)
+                                                       .field("min_ts", min_ts)
+                                                       .field("max_ts", max_ts));
+            self.mark_range_mismatch();
+            block.min_ts = min_ts;
+            block.max_ts = max_ts;
+        }
+        Ok(())
+    }
+
+    /// Returns the timestamps of a field in the time range, only the timestamps of the blocks
+    /// are decoded.
+    pub fn read_timestamps(&self,
+                           tf_id: u32,
+                           field_id: FieldId,
+                           time_range: &TimeRange)
+                           -> Result<Vec<i64>, Error> {
+        let tombstones = self.tombstone_index(tf_id, field_id)?;
+        let (mut fs_cursor, len) = self.file_reader(tf_id)?;
+        let index = TsmIndexReader::try_new(&mut fs_cursor, len as usize)?;
+        let mut blocks = Vec::new();
+        for res in index {
+            let entry = res?;
+            let block_range = TimeRange::new(entry.block.max_ts, entry.block.min_ts);
+            if entry.field_id() == field_id
+               && time_range.overlaps(&block_range)
+               && !tombstones.covers(&block_range)
+            {
+                blocks.push(entry.block);
+            }
+        }
+
+        let mut reader = TsmBlockReader::new(&mut fs_cursor);
+        let mut res = Vec::new();
+        for block in blocks.iter() {
+            let ts = reader.decode_timestamps_only(block)?;
+            res.extend(ts.into_iter()
+                         .filter(|ts| time_range.contains(*ts) && !tombstones.contains(*ts)));
+        }
+        Ok(res)
+    }
+
+    fn field_presence(&self, tf_id: u32) -> Result<&FieldPresence, Error> {
+        self.field_presence.get_or_try_init(|| {
+                               let (mut fs_cursor, len) = self.file_reader(tf_id)?;
+                               let len = len as usize;
+                               match TsmFooterReader::read_field_ids(&mut fs_cursor, len)? {
+                                   Some(field_ids) => Ok(FieldPresence::Sorted(field_ids)),
+                                   None => {
+                                       let bloom_filter =
+                                           TsmFooterReader::read_bloom_filter(&mut fs_cursor, len)?;
+                                       Ok(FieldPresence::Bloom(bloom_filter))
+                                   },
+                               }
+                           })
+    }
+
+    /// Returns the sorted ids of the fields in [lo, hi] stored in this file, None if the file
+    /// was written without the field ids.
+    pub fn fields_in_range(&self,
+                           tf_id: u32,
+                           lo: FieldId,
+                           hi: FieldId)
+                           -> Result<Option<Vec<FieldId>>, Error> {
+        match self.field_presence(tf_id)? {
+            FieldPresence::Sorted(field_ids) => Ok(Some(sorted_range(field_ids, lo, hi).to_vec())),
+            FieldPresence::Bloom(_) => Ok(None),
+        }
+    }
+
+    /// Returns the sorted ids of the given fields that may be stored in this file. The answer
+    /// is exact if the file has the sorted field ids, otherwise every field is probed in the
+    /// bloom filter and false positives are possible.
+    pub fn probe_fields(&self, tf_id: u32, field_ids: &[FieldId]) -> Result<Vec<FieldId>, Error> {
+        let mut query = field_ids.to_vec();
+        query.sort_unstable();
+        query.dedup();
+        let (lo, hi) = match (query.first(), query.last()) {
+            (Some(lo), Some(hi)) => (*lo, *hi),
+            _ => return Ok(vec![]),
+        };
+        match self.field_presence(tf_id)? {
+            FieldPresence::Sorted(stored) => {
+                // merge the query with the stored ids in [lo, hi]
+                let mut stored = sorted_range(stored, lo, hi).iter().peekable();
+                query.retain(|fid| {
+                         while stored.next_if(|s| *s < fid).is_some() {}
+                         stored.peek() == Some(&fid)
+                     });
+                Ok(query)
+            },
+            FieldPresence::Bloom(bloom_filter) => {
+                query.retain(|fid| bloom_filter.contains(&fid.to_be_bytes()));
+                Ok(query)
+            },
+        }
+    }
+
+    /// Returns the merged tombstone ranges of a field in this file.
+    pub fn tombstone_index(&self, tf_id: u32, field_id: FieldId) -> Result<TombstoneIndex, Error> {
+        Ok(self.tombstone_set(tf_id)?.index(field_id))
+    }
+
+    fn tombstone_set(&self, tf_id: u32) -> Result<Arc<TombstoneSet>, Error> {
+        let mut slot = self.tombstones.lock();
+        if let Some(set) = slot.as_ref() {
+            return Ok(set.clone());
+        }
+        let end_seq = self.tombstone_end_seq.load(Ordering::Acquire);
+        let set = Arc::new(TombstoneSet::load_until(&self.dir(tf_id), self.file_id, end_seq)?);
+        *slot = Some(set.clone());
+        Ok(set)
+    }
+
+    /// Returns the largest wal sequence of the deletes of this file, 0 if none has one.
+    pub fn tombstone_seq(&self, tf_id: u32) -> Result<u64, Error> {
+        Ok(self.tombstone_set(tf_id)?.max_seq())
+    }
+
+    /// Appends the deleted time range of the fields to the tombstone file of this file, with
+    /// the wal sequence of the delete.
+    pub fn add_tombstone(&self,
+                         tf_id: u32,
+                         seq: u64,
+                         field_ids: &[FieldId],
+                         time_range: &TimeRange)
+                         -> Result<(), Error> {
+        // held until the loaded tombstones are reset, so no read caches the file before it
+        let mut slot = self.tombstones.lock();
+        let add = |tombstone: TsmTombstone| {
+            tombstone.add_range_at(seq, field_ids, time_range.min_ts, time_range.max_ts)?;
+            tombstone.sync()
+        };
+        let dir = self.dir(tf_id);
+        let res = TsmTombstone::with_tsm_file_id(&dir, self.file_id).and_then(add);
+        if res.is_ok() {
+            // the deletes are written, a failed rewrite leaves the file as it was
+            if let Err(e) = TsmTombstone::compact(&dir, self.file_id, false) {
+                warn!("failed to compact the tombstones of file {}: {:?}", self.file_id, e);
+            }
+        }
+        *slot = None;
+        res
+    }
+
+    /// Rewrites the tombstone file of this file with the time ranges of each field merged,
+    /// returns its sizes before and after, see `TsmTombstone::compact`.
+    pub fn compact_tombstones(&self, tf_id: u32) -> Result<Option<(u64, u64)>, Error> {
+        let mut slot = self.tombstones.lock();
+        let res = TsmTombstone::compact(&self.dir(tf_id), self.file_id, true);
+        *slot = None;
+        res
+    }
+
+    /// Ignores the deletes of this file after the wal sequence `end_seq`, as they were not
+    /// logged yet at the point the store is restored to.
+    pub fn restore_until(&self, end_seq: u64) {
+        let mut slot = self.tombstones.lock();
+        self.tombstone_end_seq.store(end_seq, Ordering::Release);
+        *slot = None;
+    }
+
+    /// Drops the deletes ignored since `restore_until` from the tombstone file, the deletes
+    /// appended after it are not ignored.
+    pub fn retain_tombstones(&self, tf_id: u32) -> Result<(), Error> {
+        let mut slot = self.tombstones.lock();
+        let end_seq = self.tombstone_end_seq.load(Ordering::Acquire);
+        let path = make_tsm_tombstone_file_name(&self.dir(tf_id), self.file_id);
+        if end_seq != u64::MAX && file_manager::try_exists(&path) {
+            TsmTombstone::with_tsm_file_id(&self.dir(tf_id), self.file_id)?.retain_until(end_seq)?;
+        }
+        self.tombstone_end_seq.store(u64::MAX, Ordering::Release);
+        *slot = None;
+        Ok(())
+    }
+
+    /// Loads the fields and the tombstones of the file, the reads and the probes after it
+    /// never wait for the disk to get them.
+    pub fn warm(&self, tf_id: u32) -> Result<(), Error> {
+        self.field_presence(tf_id)?;
+        self.tombstone_set(tf_id)?;
+        Ok(())
+    }
+
+    /// Returns true if the fields and the tombstones of the file are loaded.
+    pub fn is_warm(&self) -> bool {
+        self.field_presence.get().is_some() && self.tombstones.lock().is_some()
+    }
+}
+
+/// Warms the files on a background thread, so that a version is published with its new files
+/// before their fields and tombstones are loaded.
+pub fn spawn_warm(tf_id: u32, files: Vec<Arc<ColumnFile>>) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        for file in files {
+            if let Err(e) = file.warm(tf_id) {
+                warn!("failed to warm file {} of tseries family {}: {:?}",
+                      file.file_id(),
+                      tf_id,
+                      e);
+            }
+        }
+    })
+}
+
+/// The files read by a scan.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ScanStats {
+    pub files: usize,
+    pub waves: usize,
+    pub peak_open_files: usize,
+    // the sorted timestamps of the points returned only because of `include_deleted`
+    pub deleted: Vec<Timestamp>,
+    // true if the range was read from the memory caches only, see `ReadOptions::memory_only`
+    pub memory_only: bool,
+    // true if the points are the ones of an identical scan kept by the result cache
+    pub cached: bool,
+    // the file_id of the files whose read failed or panicked, with the reason; their points
+    // are missing from the scan
+    pub failed_files: Vec<(u64, String)>,
+}
+
+impl ScanStats {
+    /// Fails if a file could not be read, so that a query does not return a scan missing the
+    /// points of the file.
+    pub fn check(&self) -> Result<(), Error> {
+        if self.failed_files.is_empty() {
+            return Ok(());
+        }
+        let files = self.failed_files
+                        .iter()
+                        .map(|(file_id, reason)| format!("file {}: {}", file_id, reason))
+                        .collect::<Vec<_>>()
+                        .join(", ");
+        Err(Error::ScanFailed { files })
+    }
+}
+
+/// The tombstone files rewritten by a compaction of the tombstones.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct TombstoneCompactStats {
+    pub files: usize,
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+}
+
+// the files being read at the same time
+#[derive(Default)]
+struct OpenFiles {
+    open: AtomicUsize,
+    peak: AtomicUsize,
+}
+
+impl OpenFiles {
+    fn read<T>(&self, f: impl FnOnce() -> T) -> T {
+        let open = self.open.fetch_add(1, Ordering::AcqRel) + 1;
+        self.peak.fetch_max(open, Ordering::AcqRel);
+        let res = f();
+        self.open.fetch_sub(1, Ordering::AcqRel);
+        res
+    }
+}
+
+// sorts the sources, ordered from the oldest to the newest, and merges them
+fn merge_sources(sources: Vec<Vec<DataType>>, duplicate_policy: DuplicatePolicy) -> Vec<DataType> {
+    let sources = sources.into_iter()
+                         .map(|mut data| {
+                             data.sort_by_key(|d| d.timestamp());
+                             data.into_iter()
+                         })
+                         .collect();
+    MergeStream::with_policy(sources, duplicate_policy).collect()
+}
+
+// the files written before the sequences were recorded have none
+fn seq_range_of(meta: &CompactMeta) -> (u64, u64) {
+    match meta.high_seq {
+        0 => (0, u64::MAX),
+        high_seq => (meta.low_seq, high_seq),
+    }
+}
+
+/// Orders the sources of a merge from the oldest to the newest write. Each source comes with
+/// the wal sequences of its first and its last write, and the sources are given by kind from
+/// the oldest to the newest. A source whose writes all come before the first write of another
+/// goes before it, the sources whose writes interleave keep the order they are given in; if
+/// the two rules disagree on a source the given order is kept.
+fn order_by_seq<T>(mut sources: Vec<(T, (u64, u64))>) -> Vec<T> {
+    let mut ordered = Vec::with_capacity(sources.len());
+    while !sources.is_empty() {
+        // no other source has all its writes before the first write of the next one, nor is
+        // given before it with writes interleaving its own
+        let is_next = |i: usize| {
+            let (low, high) = sources[i].1;
+            sources.iter()
+                   .enumerate()
+                   .all(|(j, (_, (l, h)))| j == i || (*h >= low && (j > i || *l > high)))
+        };
+        let pos = (0..sources.len()).find(|i| is_next(*i)).unwrap_or(0);
+        ordered.push(sources.remove(pos).0);
+    }
+    ordered
+}
+
+// a source of the points merged by a scan
+enum ScanSource {
+    File(Arc<ColumnFile>),
+    Points(Vec<DataType>),
+}
+
+/// Reads the files, ordered from the oldest to the newest, in waves of at most
+/// `max_concurrent_files` files. Every wave is merged into the points of the waves before
+/// it, which are older, so the result is the same as merging all files at once.
+async fn read_files_in_waves(tf_id: u32,
+                             files: &[Arc<ColumnFile>],
+                             field_id: FieldId,
+                             time_range: &TimeRange,
+                             opts: &TseriesFamOpt,
+                             read_opts: &ReadOptions,
+                             stats: &mut ScanStats)
+                             -> Vec<Vec<DataType>> {
+    let open_files = Arc::new(OpenFiles::default());
+    let parallelism = match read_opts.read_parallelism {
+        0 => opts.read_parallelism,
+        n => n,
+    };
+    let max_files = read_opts.max_concurrent_files;
+    stats.files += files.len();
+    let failed = &mut stats.failed_files;
+    let sources = if max_files == 0 || files.len() <= max_files {
+        stats.waves += 1;
+        read_files(tf_id,
+                   files,
+                   field_id,
+                   time_range,
+                   parallelism,
+                   read_opts,
+                   &open_files,
+                   failed).await
+    } else {
+        let mut merged = vec![];
+        for wave in files.chunks(max_files) {
+            let mut sources = vec![merged];
+            sources.extend(read_files(tf_id,
+                                      wave,
+                                      field_id,
+                                      time_range,
+                                      parallelism,
+                                      read_opts,
+                                      &open_files,
+                                      failed).await);
+            merged = merge_sources(sources, opts.duplicate_policy);
+            stats.waves += 1;
+        }
+        vec![merged]
+    };
+    stats.peak_open_files = stats.peak_open_files.max(open_files.peak.load(Ordering::Acquire));
+    sources
+}
+
+// returns the message of a panic caught by `catch_unwind`
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+    match (payload.downcast_ref::<&str>(), payload.downcast_ref::<String>()) {
+        (Some(msg), _) => msg.to_string(),
+        (_, Some(msg)) => msg.clone(),
+        _ => "unknown panic".to_string(),
+    }
+}
+
+/// Reads a field from the files on the blocking pool of the runtime, up to `parallelism` files
+/// at a time, so a scan of many files holds no more threads than that. The results are in the
+/// order of the files whatever order the reads complete in. A scan dropped before its end
+/// starts no more reads.
+///
+/// A file whose read fails or panics gives no points and is added to `failed`, the panic
+/// neither stops the other reads nor takes the query down.
+#[allow(clippy::too_many_arguments)]
+async fn read_files(tf_id: u32,
+                    files: &[Arc<ColumnFile>],
+                    field_id: FieldId,
+                    time_range: &TimeRange,
+                    parallelism: usize,
+                    read_opts: &ReadOptions,
+                    open_files: &Arc<OpenFiles>,
+                    failed: &mut Vec<(u64, String)>)
+                    -> Vec<Vec<DataType>> {
+    let read = |file: Arc<ColumnFile>| {
+        let (time_range, read_opts, open_files) =
+            (*time_range, read_opts.clone(), open_files.clone());
+        tokio::task::spawn_blocking(move || {
+            let read_file = || {
+                #[cfg(test)]
+                if file.panic_on_read.load(Ordering::Acquire) {
+                    panic!("injected panic");
+                }
+                file.read_field_with(tf_id, field_id, &time_range, &read_opts)
+            };
+            match open_files.read(|| panic::catch_unwind(AssertUnwindSafe(read_file))) {
+                Ok(Ok(data)) => Ok(data),
+                Ok(Err(e)) => {
+                    warn!("{:?}", e);
+                    Err(e.to_string())
+                },
+                Err(payload) => {
+                    let reason = format!("panicked: {}", panic_message(payload.as_ref()));
+                    warn!("{}",
+                          LogEvent::new("file_read_panicked").field("tf_id", tf_id)
+                                                             .field("file_id", file.file_id())
+                                                             .field("reason", &reason));
+                    Err(reason)
+                },
+            }
+        })
+    };
+    let results: Vec<_> =
+        stream::iter(files.iter().cloned()).map(read).buffered(parallelism.max(1)).collect().await;
+    results.into_iter()
+           .zip(files)
+           .map(|(res, file)| match res {
+               Ok(Ok(data)) => data,
+               Ok(Err(reason)) => {
+                   failed.push((file.file_id(), reason));
+                   vec![]
+               },
+               Err(e) => {
+                   failed.push((file.file_id(), e.to_string()));
+                   vec![]
+               },
+           })
+           .collect()
+}
+
+// the sub-slice of the sorted ids in [lo, hi]
+fn sorted_range(field_ids: &[FieldId], lo: FieldId, hi: FieldId) -> &[FieldId] {
+    let start = field_ids.partition_point(|fid| *fid < lo);
+    let end = field_ids.partition_point(|fid| *fid <= hi);
+    &field_ids[start..end.max(start)]
 }
 
 impl ColumnFile {
@@ -104,19 +759,74 @@ impl ColumnFile {
     }
 
     pub fn mark_removed(&self) {
+        self.removed_at.store(trash::now_secs(), Ordering::Release);
         self.deleted.store(true, Ordering::Release);
     }
 
+    /// Returns the seconds since the epoch the file was marked removed, 0 while it is live.
+    pub fn removed_at(&self) -> u64 {
+        self.removed_at.load(Ordering::Acquire)
+    }
+
     pub fn mark_compaction(&self) {
         self.being_compact.store(true, Ordering::Release);
     }
 
+    pub fn unmark_compaction(&self) {
+        self.being_compact.store(false, Ordering::Release);
+    }
+
     pub fn is_pending_compaction(&self) -> bool {
         self.being_compact.load(Ordering::Acquire)
     }
 
+    /// Returns false only if the field is surely not in this file, so it is true until the
+    /// fields of the file are loaded.
     pub fn contains_field_id(&self, field_id: FieldId) -> bool {
-        self.field_id_bloom_filter.contains(&field_id.to_be_bytes())
+        match self.field_presence.get() {
+            Some(FieldPresence::Sorted(field_ids)) => field_ids.binary_search(&field_id).is_ok(),
+            Some(FieldPresence::Bloom(bloom_filter)) => {
+                bloom_filter.contains(&field_id.to_be_bytes())
+            },
+            None => true,
+        }
+    }
+
+    /// Returns how many times the file has been opened for reading.
+    pub fn read_count(&self) -> u64 {
+        self.read_count.load(Ordering::Relaxed)
+    }
+
+    pub fn scrubbed_at(&self) -> u64 {
+        self.scrubbed_at.load(Ordering::Acquire)
+    }
+
+    pub fn set_scrubbed_at(&self, scrubbed_at: u64) {
+        self.scrubbed_at.store(scrubbed_at, Ordering::Release);
+    }
+
+    /// Returns true if the scrubber found a block of the file not matching its checksums.
+    pub fn is_corrupt(&self) -> bool {
+        self.corrupt.load(Ordering::Acquire)
+    }
+
+    pub fn mark_corrupt(&self) {
+        self.corrupt.store(true, Ordering::Release);
+    }
+
+    /// Returns true if a block of the file was found with timestamps out of the time range of
+    /// its index entry, the file is picked by the next compaction to write a correct index.
+    pub fn has_range_mismatch(&self) -> bool {
+        self.range_mismatch.load(Ordering::Acquire)
+    }
+
+    pub fn mark_range_mismatch(&self) {
+        self.range_mismatch.store(true, Ordering::Release);
+    }
+
+    #[cfg(test)]
+    pub fn set_panic_on_read(&self, panic_on_read: bool) {
+        self.panic_on_read.store(panic_on_read, Ordering::Release);
     }
 }
 
@@ -127,34 +837,53 @@ pub struct LevelInfo {
     pub cur_size: u64,
     pub max_size: u64,
     pub ts_range: TimeRange,
+    // the files applied to the level are in the directories of the family under it
+    pub base_dir: String,
 }
 
 impl LevelInfo {
     pub fn init(level: u32) -> Self {
+        Self::init_in(&GLOBAL_CONFIG.base_dir, level)
+    }
+
+    /// Creates the level of a tseries family whose files are under the base directory.
+    pub fn init_in(base_dir: &str, level: u32) -> Self {
         Self { files: Vec::new(),
                level,
                cur_size: 0,
                max_size: 0,
-               ts_range: TimeRange { max_ts: 0, min_ts: 0 } }
+               ts_range: TimeRange { max_ts: 0, min_ts: 0 },
+               base_dir: base_dir.to_string() }
     }
     pub fn apply(&mut self, delta: &CompactMeta) {
         self.files.push(Arc::new(ColumnFile { file_id: delta.file_id,
                                               being_compact: AtomicBool::new(false),
                                               deleted: AtomicBool::new(false),
-                                              range: TimeRange::new(delta.ts_max,
-                                                                    delta.ts_min),
+                                              removed_at: AtomicU64::new(0),
+                                              range: delta.range,
                                               size: delta.file_size,
-                                              field_id_bloom_filter: new_bloom_filter(),
-                                              is_delta: delta.is_delta }));
+                                              seq_range: seq_range_of(delta),
+                                              field_presence: OnceCell::new(),
+                                              tombstones: Mutex::new(None),
+                                              tombstone_end_seq: AtomicU64::new(u64::MAX),
+                                              is_delta: delta.is_delta,
+                                              read_count: AtomicU64::new(0),
+                                              scrubbed_at: AtomicU64::new(0),
+                                              corrupt: AtomicBool::new(false),
+                                              range_mismatch: AtomicBool::new(false),
+                                              base_dir: self.base_dir.clone(),
+                                              #[cfg(test)]
+                                              panic_on_read: AtomicBool::new(false) }));
         self.cur_size += delta.file_size;
-        if self.ts_range.max_ts < delta.ts_max {
-            self.ts_range.max_ts = delta.ts_max;
-        }
-        if self.ts_range.min_ts > delta.ts_max {
-            self.ts_range.min_ts = delta.ts_min;
-        }
+        self.ts_range = self.ts_range.merge(&delta.range);
     }
-    pub fn read_columnfile(&self, tf_id: u32, field_id: FieldId, time_range: &TimeRange) {
+    /// Logs and returns the points of a field in the time range, from every file of the level.
+    pub fn read_columnfile(&self,
+                           tf_id: u32,
+                           field_id: FieldId,
+                           time_range: &TimeRange)
+                           -> Vec<DataType> {
+        let mut res = vec![];
         for file in self.files.iter() {
             if file.is_deleted() || !file.overlap(time_range) {
                 continue;
@@ -170,15 +899,16 @@ impl LevelInfo {
             let mut blocks = Vec::new();
             for res in &mut index.unwrap() {
                 let entry = res.unwrap();
-                let key = entry.field_id();
-                if key == field_id {
+                let block_range = TimeRange::new(entry.block.max_ts, entry.block.min_ts);
+                if entry.field_id() == field_id && time_range.overlaps(&block_range) {
                     blocks.push(entry.block);
                 }
             }
 
             let mut block_reader = TsmBlockReader::new(&mut fs_cursor);
-            block_reader.read_blocks(&blocks, time_range);
+            res.extend(block_reader.read_blocks(&blocks, time_range));
         }
+        res
     }
 
     pub fn level(&self) -> u32 {
@@ -193,6 +923,8 @@ pub struct Version {
     pub max_level_ts: i64,
     pub name: String,
     pub levels_info: Vec<LevelInfo>,
+    // the levels added to the version are created under it
+    pub base_dir: String,
 }
 
 impl Version {
@@ -202,7 +934,59 @@ impl Version {
                levels_info: Vec<LevelInfo>,
                max_level_ts: i64)
                -> Self {
-        Self { id, last_seq, name, levels_info, max_level_ts }
+        let mut version = Self { id,
+                                 last_seq,
+                                 name,
+                                 levels_info,
+                                 max_level_ts,
+                                 base_dir: GLOBAL_CONFIG.base_dir.clone() };
+        let removed = version.reconcile();
+        if !removed.is_empty() {
+            warn!("tseries family {} has duplicate file ids {:?}, the older files are removed",
+                  id, removed);
+        }
+        version
+    }
+
+    /// Returns an error if a file id is held by more than one file of the levels.
+    pub fn check_file_ids(&self) -> Result<(), Error> {
+        let mut file_ids = HashSet::new();
+        for file in self.levels_info.iter().flat_map(|info| info.files.iter()) {
+            if !file_ids.insert(file.file_id()) {
+                return Err(Error::DuplicateFileId { file_id: file.file_id() });
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes the files whose ids are also held by a newer file, that is the file at a lower
+    /// level or applied later to the same level, and returns the ids of the removed files.
+    pub fn reconcile(&mut self) -> Vec<u64> {
+        let mut newest = HashMap::new();
+        for info in self.levels_info.iter() {
+            for (i, file) in info.files.iter().enumerate() {
+                let key = (Reverse(info.level), i);
+                let newest_key = newest.entry(file.file_id()).or_insert(key);
+                if key > *newest_key {
+                    *newest_key = key;
+                }
+            }
+        }
+        let mut removed = Vec::new();
+        for info in self.levels_info.iter_mut() {
+            let level = info.level;
+            let mut i = 0;
+            info.files.retain(|file| {
+                          let keep = newest[&file.file_id()] == (Reverse(level), i);
+                          if !keep {
+                              removed.push(file.file_id());
+                          }
+                          i += 1;
+                          keep
+                      });
+            info.cur_size = info.files.iter().map(|f| f.size()).sum();
+        }
+        removed
     }
 
     pub fn get_name(&self) -> &str {
@@ -213,17 +997,170 @@ impl Version {
         &self.levels_info
     }
 
+    /// Returns the live files overlapping the time range, from the oldest to the newest.
+    ///
+    /// The inputs of an in-flight compaction stay live until its output is committed, so a
+    /// snapshot holds either the inputs or the output of a compaction, never both.
+    pub fn snapshot(&self, time_range: &TimeRange) -> Vec<Arc<ColumnFile>> {
+        let mut levels: Vec<&LevelInfo> = self.levels_info.iter().collect();
+        // higher levels hold the older data, level 0 holds the delta files
+        levels.sort_by(|a, b| b.level.cmp(&a.level));
+        levels.iter()
+              .flat_map(|level| level.files.iter())
+              .filter(|file| !file.is_deleted() && file.overlap(time_range))
+              .cloned()
+              .collect()
+    }
+
+    /// Installs the output of a compaction and marks its inputs removed, in one step under
+    /// the write lock of the version. The output files are warmed after they are published,
+    /// the returned handle joins the warm task.
+    pub fn commit_compaction(&mut self, edit: &VersionEdit) -> thread::JoinHandle<()> {
+        let mut added = Vec::with_capacity(edit.add_files.len());
+        for meta in edit.add_files.iter() {
+            added.extend(self.apply_file(meta));
+        }
+        for meta in edit.del_files.iter().filter(|meta| meta.tsf_id == self.id) {
+            let files = self.levels_info.iter().flat_map(|info| info.files.iter());
+            for file in files.filter(|f| f.file_id() == meta.file_id) {
+                file.mark_removed();
+                file.unmark_compaction();
+            }
+        }
+        spawn_warm(self.id, added)
+    }
+
+    /// Adds the file to the level of the meta, the level is created if missing. Returns None
+    /// if the file belongs to another tseries family.
+    pub fn apply_file(&mut self, meta: &CompactMeta) -> Option<Arc<ColumnFile>> {
+        if meta.tsf_id != self.id {
+            warn!("file {} of ts_family {} not applied to ts_family {}",
+                  meta.file_id, meta.tsf_id, self.id);
+            return None;
+        }
+        let info = match self.levels_info.iter().position(|info| info.level == meta.level) {
+            Some(i) => &mut self.levels_info[i],
+            None => {
+                self.levels_info.push(LevelInfo::init_in(&self.base_dir, meta.level));
+                self.levels_info.last_mut().unwrap()
+            },
+        };
+        info.apply(meta);
+        info.files.last().cloned()
+    }
+
+    /// Takes the files marked removed at or before `removed_before`, in seconds since the
+    /// epoch, out of the levels if nothing but the version holds them. A file still read, or
+    /// picked by a compaction, is held by its reader too and kept for a later take.
+    pub fn take_unreferenced(&mut self, removed_before: u64) -> Vec<Arc<ColumnFile>> {
+        let mut taken = vec![];
+        for info in self.levels_info.iter_mut() {
+            info.files.retain(|file| {
+                          let unreferenced = file.is_deleted()
+                                             && file.removed_at() <= removed_before
+                                             && Arc::strong_count(file) == 1;
+                          if unreferenced {
+                              taken.push(file.clone());
+                          }
+                          !unreferenced
+                      });
+        }
+        taken
+    }
+
+    /// Returns the metas of the live files of every level.
+    pub fn live_files(&self) -> Vec<CompactMeta> {
+        let mut metas = vec![];
+        for info in self.levels_info.iter() {
+            for file in info.files.iter().filter(|f| !f.is_deleted()) {
+                metas.push(CompactMeta { file_id: file.file_id(),
+                                         file_size: file.size(),
+                                         range: *file.range(),
+                                         tsf_id: self.id,
+                                         level: info.level,
+                                         is_delta: file.is_delta(),
+                                         ..CompactMeta::new() });
+            }
+        }
+        metas
+    }
+
+    /// Returns a copy of the version holding its live files, shared with the version, for the
+    /// compaction picker to read without the lock. Every level from 0 to the deepest level of
+    /// the options is at the index of its number, its budget is `level_file_size` of the level.
+    pub fn compaction_view(&self, opts: &TseriesFamOpt) -> Version {
+        let max_level =
+            self.levels_info.iter().map(|info| info.level).fold(opts.max_level, u32::max);
+        let mut levels: Vec<LevelInfo> =
+            (0..=max_level).map(|level| LevelInfo::init_in(&self.base_dir, level)).collect();
+        for info in self.levels_info.iter() {
+            let level = &mut levels[info.level as usize];
+            for file in info.files.iter().filter(|f| !f.is_deleted()) {
+                level.cur_size += file.size();
+                level.ts_range = level.ts_range.merge(file.range());
+                level.files.push(file.clone());
+            }
+        }
+        for level in levels.iter_mut() {
+            level.max_size = opts.level_file_size(level.level.max(1));
+        }
+        Version { id: self.id,
+                  last_seq: self.last_seq,
+                  max_level_ts: self.max_level_ts,
+                  name: self.name.clone(),
+                  levels_info: levels,
+                  base_dir: self.base_dir.clone() }
+    }
+
     // todo:
     pub fn get_ts_overlap(&self, level: u32, ts_min: i64, ts_max: i64) -> Vec<Arc<ColumnFile>> {
         vec![]
     }
+
+    pub fn summary(&self) -> LevelsSummary {
+        LevelsSummary(self.levels_info
+                          .iter()
+                          .map(|info| {
+                              let files = info.files.iter().filter(|f| !f.is_deleted());
+                              LevelSummary { level: info.level,
+                                             files: files.clone().count(),
+                                             bytes: files.map(|f| f.size()).sum() }
+                          })
+                          .collect())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LevelSummary {
+    pub level: u32,
+    pub files: usize,
+    pub bytes: u64,
+}
+
+/// The live files and bytes of every level, displayed as `L0:1/4096,L1:2/8192`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LevelsSummary(pub Vec<LevelSummary>);
+
+impl Display for LevelsSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0.is_empty() {
+            return write!(f, "none");
+        }
+        for (i, level) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+            write!(f, "L{}:{}/{}", level.level, level.files, level.bytes)?;
+        }
+        Ok(())
+    }
 }
 
 pub struct SuperVersion {
     pub id: u32,
-    pub delta_mut_cache: Arc<RwLock<MemCache>>,
-    pub mut_cache: Arc<RwLock<MemCache>>,
-    pub immut_cache: Vec<Arc<RwLock<MemCache>>>,
+    pub delta_mut_cache: MemCacheRef,
+    pub mut_cache: MemCacheRef,
+    pub immut_cache: Arc<Vec<MemCacheRef>>,
     pub cur_version: Arc<RwLock<Version>>,
     pub opt: Arc<TseriesFamOpt>,
     pub version_id: u64,
@@ -231,22 +1168,107 @@ pub struct SuperVersion {
 
 impl SuperVersion {
     pub fn new(id: u32,
-               delta_mut_cache: Arc<RwLock<MemCache>>,
-               mut_cache: Arc<RwLock<MemCache>>,
-               immut_cache: Vec<Arc<RwLock<MemCache>>>,
+               delta_mut_cache: MemCacheRef,
+               mut_cache: MemCacheRef,
+               immut_cache: Arc<Vec<MemCacheRef>>,
                cur_version: Arc<RwLock<Version>>,
                opt: Arc<TseriesFamOpt>,
                version_id: u64)
                -> Self {
         Self { id, delta_mut_cache, mut_cache, immut_cache, cur_version, opt, version_id }
     }
+
+    pub async fn summary(&self) -> SuperVersionSummary {
+        let mut immut_caches = Vec::with_capacity(self.immut_cache.len());
+        for cache in self.immut_cache.iter() {
+            immut_caches.push(cache.read().await.summary());
+        }
+        SuperVersionSummary { tf_id: self.id,
+                              version_id: self.version_id,
+                              mut_cache: self.mut_cache.read().await.summary(),
+                              delta_cache: self.delta_mut_cache.read().await.summary(),
+                              immut_caches,
+                              levels: self.cur_version.read().await.summary() }
+    }
+}
+
+/// The caches and levels of a super version, displayed as
+/// `tf_id=1 version_id=2 mut_bytes=1024 mut_cells=10 delta_bytes=0 delta_cells=0 immut_caches=1
+/// immut_bytes=4096 levels=L0:0/0,L1:2/8192`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SuperVersionSummary {
+    pub tf_id: u32,
+    pub version_id: u64,
+    pub mut_cache: CacheSummary,
+    pub delta_cache: CacheSummary,
+    pub immut_caches: Vec<CacheSummary>,
+    pub levels: LevelsSummary,
+}
+
+impl Display for SuperVersionSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f,
+               "tf_id={} version_id={} mut_bytes={} mut_cells={} delta_bytes={} delta_cells={} \
+                immut_caches={} immut_bytes={} levels={}",
+               self.tf_id,
+               self.version_id,
+               self.mut_cache.bytes,
+               self.mut_cache.cells,
+               self.delta_cache.bytes,
+               self.delta_cache.cells,
+               self.immut_caches.len(),
+               self.immut_caches.iter().map(|c| c.bytes).sum::<u64>(),
+               self.levels)
+    }
+}
+
+// whether the caches are the same one, compared by identity
+fn same_cache(a: &MemCacheRef, b: &MemCacheRef) -> bool {
+    let addr = |mem: &MemCacheRef| Arc::as_ptr(mem) as *const u8;
+    addr(a) == addr(b)
+}
+
+// whether the cache is one of the caches, compared by identity
+fn contains_cache(mems: &[MemCacheRef], mem: &MemCacheRef) -> bool {
+    mems.iter().any(|m| same_cache(m, mem))
+}
+
+/// The points of a field in the read-locked memory caches of a tseries family, see
+/// `TseriesFamily::memory_iter`.
+pub struct MemoryPoints {
+    // from the oldest to the newest
+    caches: Vec<OwnedRwLockReadGuard<dyn MemCacheTrait>>,
+    field_id: FieldId,
+    time_range: TimeRange,
+    duplicate_policy: DuplicatePolicy,
+}
+
+impl MemoryPoints {
+    /// Merges the points of the caches lazily in timestamp order, a cell is cloned when the
+    /// merge reaches it; a newer cache overrides the points of the same timestamp in the older
+    /// ones.
+    pub fn iter(&self) -> MergeStream<Box<dyn Iterator<Item = DataType> + '_>> {
+        let sources = self.caches
+                          .iter()
+                          .map(|cache| {
+                              let cells = cache.iter_field(self.field_id, &self.time_range);
+                              Box::new(cells.map(Cow::into_owned))
+                              as Box<dyn Iterator<Item = DataType> + '_>
+                          })
+                          .collect();
+        MergeStream::with_policy(sources, self.duplicate_policy)
+    }
 }
 
 pub struct TseriesFamily {
     tf_id: u32,
-    delta_mut_cache: Arc<RwLock<MemCache>>,
-    mut_cache: Arc<RwLock<MemCache>>,
-    immut_cache: Vec<Arc<RwLock<MemCache>>>,
+    delta_mut_cache: MemCacheRef,
+    mut_cache: MemCacheRef,
+    // replaced by a new list on every change and never changed in place, so that a super
+    // version keeps exactly the caches it was published with
+    immut_cache: Arc<Vec<MemCacheRef>>,
+    // caches handed to the flush job, still read until the files holding them are published
+    flushing: Vec<MemCacheRef>,
     // todo: need to del RwLock in memcache
     super_version: Arc<SuperVersion>,
     super_version_id: AtomicU64,
@@ -256,27 +1278,38 @@ pub struct TseriesFamily {
     seq_no: u64,
     immut_ts_min: i64,
     mut_ts_max: i64,
+    // seconds since the epoch of the first point in the delta cache, None if it is empty
+    delta_since: Option<u64>,
+    rejects: WriteRejects,
+    // the scans served by the memory caches only
+    memory_only_scans: AtomicU64,
+    result_cache: ResultCache,
+    last_values: LastValueCache,
 }
 
 // todo: cal ref count
 impl TseriesFamily {
     pub async fn new(tf_id: u32,
                      name: String,
-                     cache: MemCache,
+                     cache: MemCacheRef,
                      version: Arc<RwLock<Version>>,
                      opt: TseriesFamOpt)
                      -> Self {
-        let mm = Arc::new(RwLock::new(cache));
+        let mm = cache;
         let cf = Arc::new(opt);
+        version.write().await.base_dir = cf.base_dir.clone();
         let seq = version.read().await.last_seq;
         let max_level_ts = version.read().await.max_level_ts;
-        let delta_mm =
-            Arc::new(RwLock::new(MemCache::new(tf_id, GLOBAL_CONFIG.max_memcache_size, seq, true)));
+        let delta_mm = new_memcache(cf.memcache_impl, tf_id, cf.max_delta_cache_size, seq, true);
+        let result_cache = ResultCache::new(cf.result_cache_entries,
+                                            Duration::from_millis(cf.result_cache_ttl_ms));
+        let last_values = LastValueCache::new(cf.last_value_entries);
         Self { tf_id,
                seq_no: seq,
                delta_mut_cache: delta_mm.clone(),
                mut_cache: mm.clone(),
                immut_cache: Default::default(),
+               flushing: vec![],
                super_version: Arc::new(SuperVersion::new(tf_id,
                                                          delta_mm,
                                                          mm,
@@ -288,160 +1321,687 @@ impl TseriesFamily {
                version,
                opts: cf,
                immut_ts_min: max_level_ts,
-               mut_ts_max: i64::MIN }
-    }
-
-    pub async fn switch_memcache(&mut self, cache: Arc<RwLock<MemCache>>) {
-        self.super_version.mut_cache.write().await.switch_to_immutable();
-        self.immut_cache.push(self.mut_cache.clone());
-        self.super_version_id.fetch_add(1, Ordering::SeqCst);
-        let vers = SuperVersion::new(self.tf_id,
-                                     cache.clone(),
-                                     self.delta_mut_cache.clone(),
-                                     self.immut_cache.clone(),
-                                     self.version.clone(),
-                                     self.opts.clone(),
-                                     self.super_version_id.load(Ordering::SeqCst));
-        self.super_version = Arc::new(vers);
-        self.mut_cache = cache;
+               mut_ts_max: i64::MIN,
+               delta_since: None,
+               rejects: WriteRejects::default(),
+               memory_only_scans: AtomicU64::new(0),
+               result_cache,
+               last_values }
+    }
+
+    pub async fn switch_memcache(&mut self, cache: MemCacheRef) {
+        let old = self.mut_cache.clone();
+        self.switch_cache(&old, cache).await;
     }
 
     pub async fn switch_to_immutable(&mut self) {
-        self.super_version.mut_cache.write().await.switch_to_immutable();
-
-        self.immut_cache.push(self.mut_cache.clone());
-        self.mut_cache = Arc::from(RwLock::new(MemCache::new(self.tf_id,
-                                                             GLOBAL_CONFIG.max_memcache_size,
-                                                             self.seq_no,
-                                                             false)));
-        self.super_version_id.fetch_add(1, Ordering::SeqCst);
-        let vers = SuperVersion::new(self.tf_id,
-                                     self.delta_mut_cache.clone(),
-                                     self.mut_cache.clone(),
-                                     self.immut_cache.clone(),
-                                     self.version.clone(),
-                                     self.opts.clone(),
-                                     self.super_version_id.load(Ordering::SeqCst));
-        self.super_version = Arc::new(vers);
-    }
-
-    async fn wrap_delta_flush_req(&mut self, sender: UnboundedSender<Arc<Mutex<Vec<FlushReq>>>>) {
-        let mut req_mem = vec![];
-        req_mem.push((self.tf_id, self.delta_mut_cache.clone()));
-        self.delta_mut_cache =
-            Arc::new(RwLock::new(MemCache::new(self.tf_id,
-                                               GLOBAL_CONFIG.max_memcache_size,
-                                               self.seq_no,
-                                               true)));
-        self.super_version_id.fetch_add(1, Ordering::SeqCst);
-        let vers = SuperVersion::new(self.tf_id,
-                                     self.delta_mut_cache.clone(),
-                                     self.mut_cache.clone(),
-                                     self.immut_cache.clone(),
-                                     self.version.clone(),
-                                     self.opts.clone(),
-                                     self.super_version_id.load(Ordering::SeqCst));
-        self.super_version = Arc::new(vers);
-        FLUSH_REQ.lock().push(FlushReq { mems: req_mem, wait_req: 0 });
-        info!("delta flush_req send,now req queue len : {}", FLUSH_REQ.lock().len());
-        sender.send(FLUSH_REQ.clone()).expect("error send flush req to kvcore");
-    }
-
-    fn wrap_flush_req(&mut self, sender: UnboundedSender<Arc<Mutex<Vec<FlushReq>>>>) {
-        let mut req_mem = vec![];
-        for i in self.immut_cache.iter() {
-            req_mem.push((self.tf_id, i.clone()));
-        }
-        self.immut_cache = vec![];
-        self.super_version_id.fetch_add(1, Ordering::SeqCst);
-        let vers = SuperVersion::new(self.tf_id,
-                                     self.delta_mut_cache.clone(),
-                                     self.mut_cache.clone(),
-                                     self.immut_cache.clone(),
-                                     self.version.clone(),
-                                     self.opts.clone(),
-                                     self.super_version_id.load(Ordering::SeqCst));
-        self.super_version = Arc::new(vers);
-        FLUSH_REQ.lock().push(FlushReq { mems: req_mem, wait_req: 0 });
-        info!("flush_req send,now req queue len : {}", FLUSH_REQ.lock().len());
-        sender.send(FLUSH_REQ.clone()).expect("error send flush req to kvcore");
+        let old = self.mut_cache.clone();
+        let cache = new_memcache(self.opts.memcache_impl,
+                                 self.tf_id,
+                                 GLOBAL_CONFIG.max_memcache_size,
+                                 self.seq_no,
+                                 false);
+        self.switch_cache(&old, cache).await;
     }
 
-    // todo(Subsegment) : (&mut self) will case performance regression.we must get writeLock to get
-    // version_set when we insert each point
-    pub async fn put_mutcache(&mut self,
-                              fid: u64,
-                              val: &[u8],
-                              dtype: ValueType,
-                              seq: u64,
-                              ts: i64,
-                              sender: UnboundedSender<Arc<Mutex<Vec<FlushReq>>>>) {
-        if self.immut_ts_min == i64::MIN {
-            self.immut_ts_min = ts;
+    /// Switches `old` to immutable and `cache` becomes the mutable cache. Returns false if
+    /// `old` has been switched already, so that exactly one switch happens per fill even if
+    /// several writers observed the same full cache.
+    async fn switch_cache(&mut self, old: &MemCacheRef, cache: MemCacheRef) -> bool {
+        {
+            let mut mem = old.write().await;
+            if mem.is_immutable() {
+                return false;
+            }
+            mem.switch_to_immutable();
         }
 
-        if ts >= self.immut_ts_min {
+        let mut immut_cache = self.immut_cache.as_ref().clone();
+        immut_cache.push(old.clone());
+        self.immut_cache = Arc::new(immut_cache);
+        self.mut_cache = cache;
+        self.renew_super_version();
+        true
+    }
+
+    // moves the watermark of the levels up to the newest point put in order, once the caches
+    // holding it are flushed. It never moves back: after a restart `mut_ts_max` starts over,
+    // and a cache holding only the points let in by `ooo_tolerance_ns` would lower it, sending
+    // the points behind the flushed ones to the mutable cache instead of the delta cache.
+    async fn advance_max_level_ts(&mut self) {
+        let mut version = self.version.write().await;
+        let ts = self.mut_ts_max.max(self.immut_ts_min).max(version.max_level_ts);
+        if self.mut_ts_max < ts {
+            warn!("{}",
+                  LogEvent::new("max_level_ts_kept").field("tf_id", self.tf_id)
+                                                    .field("mut_ts_max", self.mut_ts_max)
+                                                    .field("max_level_ts", ts));
+        }
+        self.immut_ts_min = ts;
+        version.max_level_ts = ts;
+    }
+
+    // publishes a super version of the current caches and version
+    fn renew_super_version(&mut self) {
+        let id = self.super_version_id.fetch_add(1, Ordering::SeqCst) + 1;
+        self.super_version = Arc::new(SuperVersion::new(self.tf_id,
+                                                        self.delta_mut_cache.clone(),
+                                                        self.mut_cache.clone(),
+                                                        self.immut_cache.clone(),
+                                                        self.version.clone(),
+                                                        self.opts.clone(),
+                                                        id));
+        self.debug_check_super_version();
+    }
+
+    // the super version holds exactly the current caches and version, a read through it would
+    // miss the data of any other cache. Checked in debug builds only.
+    fn debug_check_super_version(&self) {
+        let sv = &self.super_version;
+        debug_assert_eq!(sv.id, self.tf_id);
+        debug_assert_eq!(sv.version_id, self.super_version_id.load(Ordering::SeqCst));
+        debug_assert!(same_cache(&sv.mut_cache, &self.mut_cache));
+        debug_assert!(same_cache(&sv.delta_mut_cache, &self.delta_mut_cache));
+        debug_assert!(Arc::ptr_eq(&sv.immut_cache, &self.immut_cache));
+        debug_assert!(Arc::ptr_eq(&sv.cur_version, &self.version));
+        debug_assert!(Arc::ptr_eq(&sv.opt, &self.opts));
+        // a cache is read through one place only
+        debug_assert!(!contains_cache(&sv.immut_cache, &sv.mut_cache));
+        debug_assert!(sv.immut_cache.iter().all(|mem| !contains_cache(&self.flushing, mem)));
+    }
+
+    // moves the immutable caches to the flushing ones, the immutable caches are replaced by a
+    // new list without exactly them
+    fn start_flush(&mut self, mems: &[MemCacheRef]) {
+        let immut_cache = self.immut_cache.iter().filter(|m| !contains_cache(mems, m)).cloned();
+        self.immut_cache = Arc::new(immut_cache.collect());
+        self.flushing.extend(mems.iter().cloned());
+    }
+
+    /// Replaces the version with `new` in one step under its lock, a reader sees either the
+    /// old or the new version and never a version in between. The super version is renewed so
+    /// that readers holding the old one can tell it changed.
+    pub async fn install_version(&mut self, new: Version) {
+        *self.version.write().await = new;
+        self.result_cache.clear();
+        // a compaction filter may have changed the latest points
+        if self.opts.compaction_filter.is_some() {
+            self.last_values.clear();
+        }
+        self.renew_super_version();
+    }
+
+    /// Commits the edit of a compaction of the family into its version, see
+    /// `Version::commit_compaction`. The kept scans are dropped if the compaction filter may
+    /// have changed the points.
+    pub async fn commit_compaction(&self, edit: &VersionEdit) {
+        self.version.write().await.commit_compaction(edit);
+        if self.opts.compaction_filter.is_some() {
+            self.result_cache.clear();
+            self.last_values.clear();
+        }
+    }
+
+    // the boundary of the delta cache is kept by the version, so that it does not move back
+    // after a restart while the delta files hold points behind it. The flush of the delta
+    // cache records it with the file.
+    async fn keep_delta_boundary(&self) {
+        let mut version = self.version.write().await;
+        version.max_level_ts = version.max_level_ts.max(self.immut_ts_min);
+    }
+
+    async fn wrap_delta_flush_req(&mut self, sender: Sender<FlushReq>) {
+        self.keep_delta_boundary().await;
+        let mut req_mem = vec![];
+        req_mem.push((self.tf_id, self.delta_mut_cache.clone()));
+        self.flushing.push(self.delta_mut_cache.clone());
+        self.delta_mut_cache = new_memcache(self.opts.memcache_impl,
+                                            self.tf_id,
+                                            self.opts.max_delta_cache_size,
+                                            self.seq_no,
+                                            true);
+        self.delta_since = None;
+        self.renew_super_version();
+        info!("{}", LogEvent::new("delta_flush_req").field("tf_id", self.tf_id));
+        send_flush_req(&sender, FlushReq { mems: req_mem, wait_req: 0 });
+    }
+
+    fn wrap_flush_req(&mut self, sender: Sender<FlushReq>) {
+        let mems = self.immut_cache.clone();
+        let req_mem: Vec<(u32, MemCacheRef)> =
+            mems.iter().map(|mem| (self.tf_id, mem.clone())).collect();
+        self.start_flush(&mems);
+        self.renew_super_version();
+        let req_count = req_mem.len();
+        info!("{}",
+              LogEvent::new("flush_req").field("tf_id", self.tf_id).field("req_count", req_count));
+        send_flush_req(&sender, FlushReq { mems: req_mem, wait_req: 0 });
+    }
+
+    /// Switches the mutable cache to immutable and takes every cache holding data out of the
+    /// super version, the caller flushes them; returns None if there is nothing to flush.
+    pub async fn take_flush_req(&mut self) -> Option<FlushReq> {
+        if !self.mut_cache.read().await.is_empty() {
+            self.switch_to_immutable().await;
+        }
+        let tf_id = self.tf_id;
+        let mems = self.immut_cache.clone();
+        let mut req_mem: Vec<(u32, MemCacheRef)> =
+            mems.iter().map(|mem| (tf_id, mem.clone())).collect();
+        self.start_flush(&mems);
+        if !req_mem.is_empty() {
+            self.advance_max_level_ts().await;
+        }
+        if !self.delta_mut_cache.read().await.is_empty() {
+            self.keep_delta_boundary().await;
+            req_mem.push((tf_id, self.delta_mut_cache.clone()));
+            self.flushing.push(self.delta_mut_cache.clone());
+            self.delta_mut_cache = new_memcache(self.opts.memcache_impl,
+                                                self.tf_id,
+                                                self.opts.max_delta_cache_size,
+                                                self.seq_no,
+                                                true);
+            self.delta_since = None;
+        }
+        if req_mem.is_empty() {
+            return None;
+        }
+        self.renew_super_version();
+        info!("{}",
+              LogEvent::new("manual_flush_req").field("tf_id", self.tf_id)
+                                               .field("req_count", req_mem.len()));
+        Some(FlushReq { mems: req_mem, wait_req: 0 })
+    }
+
+    /// Flushes the data of a field in the mutable and immutable caches, without waiting for
+    /// the whole cache to be full.
+    pub async fn flush_field(&mut self, field_id: FieldId, sender: Sender<FlushReq>) {
+        let mut req_mem = vec![];
+        // the older data of the field go first
+        for mem in self.immut_cache.iter().chain(std::iter::once(&self.mut_cache)) {
+            if let Some(cache) = mem.write().await.split_field(field_id) {
+                req_mem.push((self.tf_id, cache));
+            }
+        }
+        for (_, cache) in req_mem.iter() {
+            self.flushing.push(cache.clone());
+        }
+        if req_mem.is_empty() {
+            return;
+        }
+        let req_count = req_mem.len();
+        info!("{}",
+              LogEvent::new("field_flush_req").field("tf_id", self.tf_id)
+                                              .field("field_id", field_id)
+                                              .field("req_count", req_count));
+        send_flush_req(&sender, FlushReq { mems: req_mem, wait_req: 0 });
+    }
+
+    /// Stops reading the flushed caches, called under the same lock that publishes the files
+    /// holding their data so that a read sees the data in exactly one of them.
+    pub fn finish_flush(&mut self, mems: &[MemCacheRef]) {
+        self.flushing.retain(|m| !contains_cache(mems, m));
+    }
+
+    // todo(Subsegment) : (&mut self) will case performance regression.we must get writeLock to get
+    // version_set when we insert each point
+    pub async fn put_mutcache(&mut self,
+                              fid: u64,
+                              val: &[u8],
+                              dtype: ValueType,
+                              seq: u64,
+                              ts: i64,
+                              sender: Sender<FlushReq>) {
+        let _ =
+            self.put_mutcache_hinted(&mut FieldHints::default(), fid, val, dtype, seq, ts, sender)
+                .await;
+    }
+
+    /// Puts a point like `put_mutcache`, the entries of the fields found in the mutable cache
+    /// are remembered in `hints`, so that the next points of a batch into the same fields skip
+    /// looking them up. The hints are renewed when the super version changes, as a switched
+    /// cache does not hold the remembered entries.
+    ///
+    /// Returns why the point is not put, the rejected point is counted in `rejects`.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn put_mutcache_hinted(&mut self,
+                                     hints: &mut FieldHints,
+                                     fid: u64,
+                                     val: &[u8],
+                                     dtype: ValueType,
+                                     seq: u64,
+                                     ts: i64,
+                                     sender: Sender<FlushReq>)
+                                     -> Result<(), WriteRejectReason> {
+        let val = match check_utf8(self.opts.utf8_policy, dtype, val) {
+            Ok(val) => val,
+            Err(_) => return Err(self.reject(fid, ts, WriteRejectReason::InvalidUtf8)),
+        };
+        if self.immut_ts_min == i64::MIN {
+            self.immut_ts_min = ts;
+        }
+
+        let mut entry_full = false;
+        // a point a little late does not need the delta cache
+        let late = self.immut_ts_min.saturating_sub(ts);
+        if ts >= self.immut_ts_min || late < self.opts.ooo_tolerance_ns {
             if ts > self.mut_ts_max {
                 self.mut_ts_max = ts;
             }
+            hints.renew(self.tf_id, self.super_version.version_id);
             let mut mem = self.super_version.mut_cache.write().await;
-            let _ = mem.insert_raw(seq, fid, ts, dtype, val);
+            if mem.insert_raw_hinted(hints, seq, fid, ts, dtype, &val).is_err() {
+                return Err(self.reject(fid, ts, WriteRejectReason::TypeConflict));
+            }
+            entry_full = mem.entry_len(fid) >= self.opts.max_entry_cells;
         } else {
             let mut delta_mem = self.super_version.delta_mut_cache.write().await;
-            let _ = delta_mem.insert_raw(seq, fid, ts, dtype, val);
+            if delta_mem.insert_raw(seq, fid, ts, dtype, &val).is_err() {
+                return Err(self.reject(fid, ts, WriteRejectReason::TypeConflict));
+            }
+            self.delta_since.get_or_insert_with(trash::now_secs);
         }
-        if ts >= self.immut_ts_min && !self.delta_mut_cache.read().await.data_cache.is_empty() {
+        self.result_cache.invalidate(fid);
+        self.last_values.update(fid, ts, dtype, &val);
+        if ts >= self.immut_ts_min && !self.delta_mut_cache.read().await.is_empty() {
             self.wrap_delta_flush_req(sender.clone()).await
         }
 
-        if self.super_version.mut_cache.read().await.is_full() {
-            info!("mut_cache full,switch to immutable");
-            self.switch_to_immutable().await;
-            if self.immut_cache.len() >= GLOBAL_CONFIG.max_immemcache_num {
-                self.immut_ts_min = self.mut_ts_max;
-                self.version.write().await.max_level_ts = self.mut_ts_max;
-                self.wrap_flush_req(sender.clone());
+        if entry_full {
+            info!("{}",
+                  LogEvent::new("field_full").field("tf_id", self.tf_id).field("field_id", fid));
+            self.flush_field(fid, sender.clone()).await;
+        }
+
+        let full_cache = self.super_version.mut_cache.clone();
+        if full_cache.read().await.is_full() {
+            let cache = new_memcache(self.opts.memcache_impl,
+                                     self.tf_id,
+                                     GLOBAL_CONFIG.max_memcache_size,
+                                     self.seq_no,
+                                     false);
+            // another writer may have switched the cache already
+            if self.switch_cache(&full_cache, cache).await {
+                info!("{} {}",
+                      LogEvent::new("switch_to_immutable"),
+                      full_cache.read().await.summary());
+                if self.immut_cache.len() >= GLOBAL_CONFIG.max_immemcache_num {
+                    self.advance_max_level_ts().await;
+                    self.wrap_flush_req(sender.clone());
+                }
             }
         }
 
         if self.super_version.delta_mut_cache.read().await.is_full() {
             self.wrap_delta_flush_req(sender.clone()).await;
+        } else {
+            self.flush_aged_delta(trash::now_secs(), sender).await;
         }
+        Ok(())
     }
 
-    pub async fn delete_cache(&self, time_range: &TimeRange) {
-        for i in self.mut_cache.write().await.data_cache.iter_mut() {
-            if i.1.overlap(time_range) {
-                i.1.delete_data_cell(time_range);
+    fn reject(&self, fid: u64, ts: i64, reason: WriteRejectReason) -> WriteRejectReason {
+        self.rejects.record(fid, ts, reason);
+        reason
+    }
+
+    /// The points rejected by `put_mutcache` since the family is opened.
+    pub fn rejects(&self) -> &WriteRejects {
+        &self.rejects
+    }
+
+    /// The counters of the scans served again from memory, see `ResultCache`.
+    pub fn result_cache_stats(&self) -> CacheStats {
+        self.result_cache.stats()
+    }
+
+    /// The counters of the lookups of the latest points, see `last_value`.
+    pub fn last_value_stats(&self) -> CacheStats {
+        self.last_values.stats()
+    }
+
+    /// Returns how many scans were served by the memory caches only, see
+    /// `ReadOptions::memory_only`.
+    pub fn memory_only_scans(&self) -> u64 {
+        self.memory_only_scans.load(Ordering::Relaxed)
+    }
+
+    /// Flushes the delta cache if its first point was put `delta_flush_age_secs` before `now`,
+    /// so that the points written only out of order are flushed even if neither cache fills.
+    /// Returns true if a flush is requested.
+    pub async fn flush_aged_delta(&mut self, now: u64, sender: Sender<FlushReq>) -> bool {
+        let age = self.opts.delta_flush_age_secs;
+        match self.delta_since {
+            Some(since) if age > 0 && now >= since.saturating_add(age) => {
+                self.wrap_delta_flush_req(sender).await;
+                true
+            },
+            _ => false,
+        }
+    }
+
+    /// Returns the points of a field in the time range, merged from the disk and the memory
+    /// of this tseries family; a newer write of the same timestamp overrides the older one.
+    ///
+    /// The sources are merged from the oldest to the newest write of a timestamp, by the wal
+    /// sequences of their writes: a source whose writes all come after those of another one
+    /// overrides it. The sources whose writes interleave are merged by kind: the files of the
+    /// levels, the caches of the levels, the delta files, then the delta caches. A timestamp
+    /// behind `immut_ts_min` is written to the delta cache only, and the boundary never moves
+    /// back, the version keeps it over a restart, so of two such sources holding a timestamp
+    /// the delta one holds its newer write. A point lookup found in the mutable cache or a
+    /// delta cache never touches the column files.
+    ///
+    /// Fails if a file could not be read, see `ScanStats::check`; `scan_with` returns the
+    /// points of the other files then.
+    pub async fn scan(&self,
+                      field_id: FieldId,
+                      time_range: &TimeRange)
+                      -> Result<Vec<DataType>, Error> {
+        let (data, stats) = self.scan_with(field_id, time_range, &ReadOptions::default()).await;
+        stats.check()?;
+        Ok(data)
+    }
+
+    /// Returns the latest point of a field, None if it has none. The point is kept in memory
+    /// and updated by the writes, a field not kept yet is filled from a scan of all its points.
+    pub async fn last_value(&self, field_id: FieldId) -> Option<DataType> {
+        if let Some(data) = self.last_values.get(field_id) {
+            return data;
+        }
+        let epoch = self.last_values.epoch();
+        let all = TimeRange::new(i64::MAX, i64::MIN);
+        let (mut data, stats) = self.scan_with(field_id, &all, &ReadOptions::default()).await;
+        let data = data.pop();
+        if stats.failed_files.is_empty() {
+            self.last_values.insert(field_id, data.clone(), epoch);
+        }
+        data
+    }
+
+    /// Returns the latest points of the fields like `last_value`, in the order of `field_ids`.
+    pub async fn last_values(&self, field_ids: &[FieldId]) -> Vec<Option<DataType>> {
+        let mut res = Vec::with_capacity(field_ids.len());
+        for field_id in field_ids {
+            res.push(self.last_value(*field_id).await);
+        }
+        res
+    }
+
+    /// Returns the points of a field like `scan`, with the values converted to the value type
+    /// the caller expects; fails if a file could not be read or a value cannot be converted,
+    /// see `DataBlock::coerce`.
+    pub async fn scan_as(&self,
+                         field_id: FieldId,
+                         time_range: &TimeRange,
+                         value_type: ValueType)
+                         -> Result<Vec<DataType>, Error> {
+        self.scan(field_id, time_range).await?.into_iter().map(|d| d.coerce(value_type)).collect()
+    }
+
+    /// Returns the points of a field in the time range, and the files read for them.
+    ///
+    /// With `include_deleted` the points deleted by the tombstones of the files are returned
+    /// too, and the timestamps having only deleted points are listed in `ScanStats::deleted`;
+    /// the field is read a second time without them to tell them apart. A delete drops the
+    /// points of the memory caches at once, those are never returned.
+    ///
+    /// With `memory_only` a range starting after `Version::max_level_ts` is read from the
+    /// caches without listing the files, as every point of the files is at or before it; a
+    /// range starting at or before it is read from the files too.
+    ///
+    /// Without `include_deleted` the points of an identical scan kept by the result cache of
+    /// the family are returned without reading anything, and `ScanStats::cached` is set.
+    pub async fn scan_with(&self,
+                           field_id: FieldId,
+                           time_range: &TimeRange,
+                           read_opts: &ReadOptions)
+                           -> (Vec<DataType>, ScanStats) {
+        let mut stats = ScanStats::default();
+        if !read_opts.include_deleted {
+            let now = Instant::now();
+            if let Some(data) = self.result_cache.get(field_id, time_range, now) {
+                return (data, ScanStats { cached: true, ..stats });
+            }
+            let epoch = self.result_cache.epoch();
+            let data = self.read_merged(field_id, time_range, read_opts, &mut stats).await;
+            if stats.failed_files.is_empty() {
+                self.result_cache.insert(field_id, time_range, &data, epoch, now);
+            }
+            if stats.memory_only {
+                self.memory_only_scans.fetch_add(1, Ordering::Relaxed);
+            }
+            return (data, stats);
+        }
+        let live_opts = ReadOptions { include_deleted: false, ..read_opts.clone() };
+        let live =
+            self.read_merged(field_id, time_range, &live_opts, &mut ScanStats::default()).await;
+        let data = self.read_merged(field_id, time_range, read_opts, &mut stats).await;
+        let live: HashSet<Timestamp> = live.iter().map(|d| d.timestamp()).collect();
+        stats.deleted =
+            data.iter().map(|d| d.timestamp()).filter(|ts| !live.contains(ts)).collect();
+        stats.deleted.dedup();
+        if stats.memory_only {
+            self.memory_only_scans.fetch_add(1, Ordering::Relaxed);
+        }
+        (data, stats)
+    }
+
+    async fn read_merged(&self,
+                         field_id: FieldId,
+                         time_range: &TimeRange,
+                         read_opts: &ReadOptions,
+                         stats: &mut ScanStats)
+                         -> Vec<DataType> {
+        let duplicate_policy = self.opts.duplicate_policy;
+        // with KeepAll the older duplicates on disk are also needed
+        let is_point =
+            time_range.min_ts == time_range.max_ts && duplicate_policy == DuplicatePolicy::LastWins;
+        let (caches, delta_caches) = self.ordered_caches().await;
+        let mut cache_data = Vec::with_capacity(caches.len());
+        for mem in caches.iter() {
+            let mem = mem.read().await;
+            cache_data.push((mem.read(field_id, time_range), mem.seq_range()));
+        }
+        // late points reach the delta caches out of order, `read` returns them sorted
+        let mut delta_data = Vec::with_capacity(delta_caches.len());
+        for mem in delta_caches.iter() {
+            let mem = mem.read().await;
+            delta_data.push((mem.read(field_id, time_range), mem.seq_range()));
+        }
+        // the mutable cache never shares a timestamp with the delta sources
+        let found = delta_data.iter().any(|(d, _)| !d.is_empty())
+                    || cache_data.last().map_or(false, |(d, _)| !d.is_empty());
+        let cached = |data: Vec<(Vec<DataType>, (u64, u64))>| {
+            data.into_iter()
+                .filter(|(d, _)| !d.is_empty())
+                .map(|(d, seq_range)| (ScanSource::Points(d), seq_range))
+        };
+
+        // sources are listed by kind from the oldest to the newest, see `scan`
+        let mut sources = vec![];
+        if is_point && found {
+            sources.extend(cached(cache_data));
+        } else if read_opts.memory_only
+                  && time_range.min_ts > self.version.read().await.max_level_ts
+        {
+            stats.memory_only = true;
+            sources.extend(cached(cache_data));
+        } else {
+            let mut files = self.version.read().await.snapshot(time_range);
+            files.retain(|f| f.contains_field_id(field_id));
+            let (delta_files, files): (Vec<_>, Vec<_>) =
+                files.into_iter().partition(|f| f.is_delta());
+            let file = |f: Arc<ColumnFile>| {
+                let seq_range = f.seq_range();
+                (ScanSource::File(f), seq_range)
+            };
+            sources.extend(files.into_iter().map(file));
+            sources.extend(cached(cache_data));
+            sources.extend(delta_files.into_iter().map(file));
+        }
+        sources.extend(cached(delta_data));
+
+        // the files of a kind next to each other are read together, in waves
+        let mut data = vec![];
+        let mut files: Vec<Arc<ColumnFile>> = vec![];
+        for source in order_by_seq(sources) {
+            let run_ends = match &source {
+                ScanSource::File(f) => files.last().map_or(false, |l| l.is_delta() != f.is_delta()),
+                ScanSource::Points(_) => true,
+            };
+            if run_ends && !files.is_empty() {
+                data.extend(read_files_in_waves(self.tf_id, &files, field_id, time_range,
+                                                &self.opts, read_opts, stats).await);
+                files.clear();
             }
+            match source {
+                ScanSource::File(f) => files.push(f),
+                ScanSource::Points(points) => data.push(points),
+            }
+        }
+        if !files.is_empty() {
+            data.extend(read_files_in_waves(self.tf_id, &files, field_id, time_range, &self.opts,
+                                            read_opts, stats).await);
+        }
+
+        merge_sources(data, duplicate_policy)
+    }
+
+    /// Returns the points of a field in the memory caches, see `MemoryPoints::iter`. The caches
+    /// stay read-locked until the points are dropped.
+    pub async fn memory_iter(&self, field_id: FieldId, time_range: &TimeRange) -> MemoryPoints {
+        // sources are ordered from the oldest to the newest, like those of `scan`
+        let (caches, delta_caches) = self.ordered_caches().await;
+        let mut locked = vec![];
+        for mem in caches.into_iter().chain(delta_caches.into_iter()) {
+            let mem = mem.read_owned().await;
+            let seq_range = mem.seq_range();
+            locked.push((mem, seq_range));
+        }
+        MemoryPoints { caches: order_by_seq(locked),
+                       field_id,
+                       time_range: *time_range,
+                       duplicate_policy: self.opts.duplicate_policy }
+    }
+
+    /// Returns the sorted distinct timestamps of a field in the time range, from the memory
+    /// caches and the files, without decoding any value on disk.
+    pub async fn timestamps(&self,
+                            field_id: FieldId,
+                            time_range: &TimeRange)
+                            -> Result<Vec<i64>, Error> {
+        let mut res = vec![];
+        let caches = self.flushing.iter().chain(self.immut_cache.iter());
+        for mem in caches.chain([&self.delta_mut_cache, &self.mut_cache]) {
+            res.extend(mem.read().await.read(field_id, time_range).iter().map(|d| d.timestamp()));
         }
-        for i in self.delta_mut_cache.write().await.data_cache.iter_mut() {
-            if i.1.overlap(time_range) {
-                i.1.delete_data_cell(time_range);
+        let version = self.version.read().await;
+        for level in version.levels_info.iter() {
+            for file in level.files.iter() {
+                if file.is_deleted() || !file.overlap(time_range) {
+                    continue;
+                }
+                res.extend(file.read_timestamps(self.tf_id, field_id, time_range)?);
             }
         }
+        res.sort_unstable();
+        res.dedup();
+        Ok(res)
+    }
+
+    /// Returns the caches of the levels and the delta caches, each ordered from the oldest to
+    /// the newest write.
+    async fn ordered_caches(&self) -> (Vec<MemCacheRef>, Vec<MemCacheRef>) {
+        let (mut caches, mut delta_caches) = (vec![], vec![]);
+        for mem in self.flushing.iter() {
+            if mem.read().await.is_delta() {
+                delta_caches.push(mem.clone());
+            } else {
+                caches.push(mem.clone());
+            }
+        }
+        caches.extend(self.immut_cache.iter().cloned());
+        caches.push(self.mut_cache.clone());
+        delta_caches.push(self.delta_mut_cache.clone());
+        (caches, delta_caches)
+    }
+
+    // the flushing caches are left alone, the flush job holds them until it takes the lock of
+    // the version set to publish its files
+    pub async fn delete_cache(&self, time_range: &TimeRange) {
+        self.mut_cache.write().await.delete_range(time_range);
+        self.delta_mut_cache.write().await.delete_range(time_range);
         for memcache in self.immut_cache.iter() {
-            for i in memcache.write().await.data_cache.iter_mut() {
-                if i.1.overlap(time_range) {
-                    i.1.delete_data_cell(time_range);
+            memcache.write().await.delete_range(time_range);
+        }
+        self.result_cache.clear();
+        self.last_values.delete_all(time_range);
+    }
+
+    /// Deletes the points of the fields in the time range from the caches and the files,
+    /// `seq` is the wal sequence of the delete.
+    ///
+    /// Deleting the same range again is harmless, so it is safe to replay from the wal.
+    pub async fn delete_range(&self,
+                              seq: u64,
+                              field_ids: &[FieldId],
+                              time_range: &TimeRange)
+                              -> Result<(), Error> {
+        for field_id in field_ids {
+            self.mut_cache.write().await.delete_field_range(*field_id, time_range);
+            self.delta_mut_cache.write().await.delete_field_range(*field_id, time_range);
+            for memcache in self.immut_cache.iter() {
+                memcache.write().await.delete_field_range(*field_id, time_range);
+            }
+        }
+
+        let version = self.version.read().await;
+        let files = version.levels_info()
+                           .iter()
+                           .filter(|level| level.ts_range.overlaps(time_range))
+                           .flat_map(|level| level.files.iter())
+                           .filter(|file| !file.is_deleted() && file.range().overlaps(time_range));
+        let res =
+            files.map(|file| file.add_tombstone(self.tf_id, seq, field_ids, time_range)).collect();
+        // dropped once the points are deleted, so that no scan reading before keeps them
+        for field_id in field_ids {
+            self.result_cache.invalidate(*field_id);
+            self.last_values.delete(*field_id, time_range);
+        }
+        res
+    }
+
+    /// Rewrites the tombstone files of the files of the version, see
+    /// `ColumnFile::compact_tombstones`.
+    pub async fn compact_tombstones(&self) -> Result<TombstoneCompactStats, Error> {
+        let mut stats = TombstoneCompactStats::default();
+        let version = self.version.read().await;
+        for level in version.levels_info() {
+            for file in level.files.iter().filter(|f| !f.is_deleted()) {
+                if let Some((before, after)) = file.compact_tombstones(self.tf_id)? {
+                    stats.files += 1;
+                    stats.bytes_before += before;
+                    stats.bytes_after += after;
                 }
             }
         }
+        Ok(stats)
     }
 
     pub fn tf_id(&self) -> u32 {
         self.tf_id
     }
 
-    pub fn cache(&self) -> &Arc<RwLock<MemCache>> {
+    pub fn cache(&self) -> &MemCacheRef {
         &self.mut_cache
     }
 
-    pub fn delta_cache(&self) -> &Arc<RwLock<MemCache>> {
+    pub fn delta_cache(&self) -> &MemCacheRef {
         &self.delta_mut_cache
     }
 
-    pub fn im_cache(&self) -> &Vec<Arc<RwLock<MemCache>>> {
+    pub fn im_cache(&self) -> &[MemCacheRef] {
         &self.immut_cache
     }
 
@@ -449,38 +2009,130 @@ impl TseriesFamily {
         &self.version
     }
 
+    pub fn options(&self) -> &Arc<TseriesFamOpt> {
+        &self.opts
+    }
+
     pub fn imut_ts_min(&self) -> i64 {
         self.immut_ts_min
     }
+
+    /// Returns the state of this tseries family for debugging; a cache or the version whose
+    /// lock is held by someone else is reported unavailable instead of waited for.
+    pub fn debug_dump(&self) -> TsfDebugDump {
+        let cache = |mem: &MemCacheRef| DumpField::try_read(mem.as_ref(), |c| c.summary());
+        TsfDebugDump { tf_id: self.tf_id,
+                       seq_no: self.seq_no,
+                       super_version_id: self.super_version_id.load(Ordering::Relaxed),
+                       immut_ts_min: self.immut_ts_min,
+                       mut_ts_max: self.mut_ts_max,
+                       mut_cache: cache(&self.mut_cache),
+                       delta_cache: cache(&self.delta_mut_cache),
+                       immut_caches: self.immut_cache.iter().map(cache).collect(),
+                       // the flush queue belongs to the store, see `TsKv::debug_dump_all`
+                       pending_flushes: DumpField::Unavailable,
+                       rejected: self.rejects.counts(),
+                       version: DumpField::try_read(self.version.as_ref(), VersionDump::new),
+                       options: OptionsDump::new(&self.opts) }
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use std::sync::Arc;
+    use std::{
+        collections::{HashMap, HashSet},
+        path::Path,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+    };
 
     use logger::info;
     use models::ValueType;
     use tokio::sync::{mpsc, RwLock};
 
     use crate::{
-        kv_option::TseriesFamOpt,
-        memcache::MemCache,
-        tseries_family::{TimeRange, TseriesFamily, Version},
+        compaction::{
+            build_tsm_file, compact_kernel, compact_req, run_compaction_job, DiskSpace, FlushReq,
+            FlushTask,
+        },
+        context::GlobalContext,
+        debug_dump::DumpField,
+        direct_io::FileSync,
+        error::Error,
+        file_manager::get_file_manager,
+        file_utils::{make_delta_file_name, make_tsm_file_name},
+        kv_option::{
+            DuplicatePolicy, MemCacheImpl, ReadOptions, TseriesFamDesc, TseriesFamOpt, Utf8Policy,
+        },
+        memcache::{new_memcache, DataType, FieldHints, MemCacheRef},
+        summary::{CompactMeta, VersionEdit},
+        trash,
+        tseries_family::{
+            contains_cache, order_by_seq, same_cache, spawn_warm, LevelInfo, ScanStats, TimeRange,
+            TseriesFamily, Version,
+        },
+        tsm::{
+            DataBlock, FileBlock, TsmBlockWriter, TsmFooterReader, TsmFooterWriter,
+            TsmHeaderWriter, TsmIndexReader, TsmIndexWriter,
+        },
+        version_set::VersionSet,
+        write_stats::{RejectCounts, WriteRejectReason},
     };
 
+    // a tseries family of the version, named "db", with a mutable cache of `cache_size` bytes
+    async fn new_tsf_of(tf_id: u32,
+                        version: Arc<RwLock<Version>>,
+                        cache_size: u64,
+                        opt: TseriesFamOpt)
+                        -> TseriesFamily {
+        let cache = new_memcache(MemCacheImpl::HashMap, tf_id, cache_size, 0, false);
+        TseriesFamily::new(tf_id, "db".to_string(), cache, version, opt).await
+    }
+
+    // a tseries family of a new version of the levels, see `new_tsf_of`
+    async fn new_tsf(tf_id: u32,
+                     levels: Vec<LevelInfo>,
+                     cache_size: u64,
+                     opt: TseriesFamOpt)
+                     -> TseriesFamily {
+        let version = Version::new(tf_id, 0, "db".to_string(), levels, 0);
+        new_tsf_of(tf_id, Arc::new(RwLock::new(version)), cache_size, opt).await
+    }
+
+    #[test]
+    fn test_time_range() {
+        let (a, b) = (TimeRange::new(10, 1), TimeRange::new(20, 5));
+        assert_eq!(a.merge(&b), TimeRange::new(20, 1));
+        assert_eq!(TimeRange::none().merge(&a), a);
+        assert_eq!(a.merge(&TimeRange::new(0, 5)), a);
+        assert!(TimeRange::none().is_empty());
+
+        assert_eq!(a.duration(), 9);
+        assert_eq!(TimeRange::new(3, 3).duration(), 0);
+        assert_eq!(TimeRange::none().duration(), 0);
+        assert_eq!(TimeRange::new(i64::MAX, i64::MIN).duration(), u64::MAX);
+
+        assert_eq!(a.clamp(&b), Some(TimeRange::new(10, 5)));
+        assert_eq!(a.clamp(&TimeRange::new(i64::MAX, i64::MIN)), Some(a));
+        assert_eq!(a.clamp(&TimeRange::new(30, 11)), None);
+
+        // ordered by min_ts, then by max_ts
+        let mut ranges = vec![b, TimeRange::new(2, 1), a, TimeRange::new(100, 0)];
+        ranges.sort();
+        assert_eq!(ranges,
+                   vec![TimeRange::new(100, 0), TimeRange::new(2, 1), a, TimeRange::new(20, 5)]);
+        let distinct: HashSet<TimeRange> = [a, a, b].into_iter().collect();
+        assert_eq!(distinct.len(), 2);
+    }
+
     #[tokio::test]
     pub async fn test_tsf_delete() {
-        let tcfg = TseriesFamOpt::default();
-        let mut tsf = TseriesFamily::new(0,
-                                         "db".to_string(),
-                                         MemCache::new(0, 500, 0, false),
-                                         Arc::new(RwLock::new(Version::new(0,
-                                                                           0,
-                                                                           "db".to_string(),
-                                                                           vec![],
-                                                                           0))),
-                                         tcfg).await;
-        let (flush_task_sender, flush_task_receiver) = mpsc::unbounded_channel();
+        let tmp = tempfile::tempdir().unwrap();
+        let tcfg = TseriesFamOpt::for_testing(tmp.path());
+        let mut tsf = new_tsf(0, vec![], 500, tcfg).await;
+        let (flush_task_sender, flush_task_receiver) = mpsc::channel(16);
         tsf.put_mutcache(0,
                          10_i32.to_be_bytes().as_slice(),
                          ValueType::Integer,
@@ -488,8 +2140,1620 @@ mod test {
                          0,
                          flush_task_sender)
            .await;
-        assert_eq!(tsf.mut_cache.read().await.data_cache.get(&0).unwrap().cells.len(), 1);
+        assert_eq!(tsf.mut_cache.read().await.entry_len(0), 1);
         tsf.delete_cache(&TimeRange { max_ts: 0, min_ts: 0 }).await;
-        assert_eq!(tsf.mut_cache.read().await.data_cache.get(&0).unwrap().cells.len(), 0);
+        assert_eq!(tsf.mut_cache.read().await.entry_len(0), 0);
+    }
+
+    #[tokio::test]
+    pub async fn test_tsf_flush_only_delta() {
+        let tmp = tempfile::tempdir().unwrap();
+        let opt =
+            TseriesFamOpt { delta_flush_age_secs: 60, ..TseriesFamOpt::for_testing(tmp.path()) };
+        let desc = [TseriesFamDesc { name: "db".to_string(), opt: opt.clone() }];
+        // every point is older than the data already flushed
+        let version = Arc::new(RwLock::new(Version::new(0, 0, "db".to_string(), vec![], 1000)));
+        let version_set = VersionSet::new(&desc, HashMap::from([(0, version)]), vec![]).await;
+        let version_set = Arc::new(RwLock::new(version_set));
+        let (flush_task_sender, mut flush_task_receiver) = mpsc::channel(16);
+
+        let mem = {
+            let mut version_set = version_set.write().await;
+            let tsf = version_set.get_tsfamily_by_id(0).unwrap();
+            for ts in 1..=10_i64 {
+                tsf.put_mutcache(1,
+                                 ts.to_be_bytes().as_slice(),
+                                 ValueType::Integer,
+                                 1,
+                                 ts,
+                                 flush_task_sender.clone())
+                   .await;
+            }
+            assert!(tsf.mut_cache.read().await.is_empty());
+            assert_eq!(tsf.delta_mut_cache.read().await.entry_len(1), 10);
+            assert!(flush_task_receiver.try_recv().is_err());
+
+            // the mutable cache never fills, the delta cache is flushed once old enough
+            let now = trash::now_secs();
+            assert!(!tsf.flush_aged_delta(now, flush_task_sender.clone()).await);
+            assert!(tsf.flush_aged_delta(now + 60, flush_task_sender.clone()).await);
+            assert!(flush_task_receiver.try_recv().is_ok());
+            assert!(tsf.delta_mut_cache.read().await.is_empty());
+            assert!(!tsf.flush_aged_delta(now + 120, flush_task_sender.clone()).await);
+            tsf.flushing.last().unwrap().clone()
+        };
+        assert!(mem.read().await.is_delta());
+
+        let kernel = Arc::new(GlobalContext::new());
+        let mut task = FlushTask::new(vec![mem],
+                                      0,
+                                      opt.tsm_dir(0),
+                                      opt.delta_dir(0),
+                                      DuplicatePolicy::default(),
+                                      opt.max_flush_level,
+                                      opt.level_compression.clone(),
+                                      opt.flush_time_window_ns);
+        let mut edits = vec![];
+        task.run(version_set.clone(), kernel, &mut edits).await.unwrap();
+        assert_eq!(edits.len(), 1);
+        let meta = &edits[0].add_files[0];
+        assert!(meta.is_delta);
+        assert!(make_delta_file_name(&opt.delta_dir(0), meta.file_id).exists());
+
+        let version_set = version_set.read().await;
+        let tsf = version_set.get_tsfamily_immut(0).unwrap();
+        assert!(tsf.flushing.is_empty());
+        assert_eq!(tsf.scan(1, &TimeRange::new(i64::MAX, i64::MIN)).await.unwrap().len(), 10);
+    }
+
+    #[tokio::test]
+    pub async fn test_tsf_field_hints_switch() {
+        let tmp = tempfile::tempdir().unwrap();
+        let tcfg = TseriesFamOpt::for_testing(tmp.path());
+        let mut tsf = new_tsf(0, vec![], 4096, tcfg).await;
+        let (flush_task_sender, _) = mpsc::channel(16);
+        // one batch of two fields, the mutable cache is switched in the middle of it
+        let mut hints = FieldHints::default();
+        for ts in 1..=6_i64 {
+            if ts == 4 {
+                tsf.switch_to_immutable().await;
+            }
+            for fid in [1, 2] {
+                tsf.put_mutcache_hinted(&mut hints,
+                                        fid,
+                                        ts.to_be_bytes().as_slice(),
+                                        ValueType::Integer,
+                                        0,
+                                        ts,
+                                        flush_task_sender.clone())
+                   .await;
+            }
+        }
+        for fid in [1, 2] {
+            assert_eq!(tsf.immut_cache[0].read().await.entry_len(fid), 3);
+            assert_eq!(tsf.mut_cache.read().await.entry_len(fid), 3);
+            let ts: Vec<i64> = tsf.scan(fid, &TimeRange::new(i64::MAX, i64::MIN))
+                                  .await
+                                  .unwrap()
+                                  .iter()
+                                  .map(|d| d.timestamp())
+                                  .collect();
+            assert_eq!(ts, vec![1, 2, 3, 4, 5, 6]);
+        }
+    }
+
+    #[tokio::test]
+    pub async fn test_tsf_utf8_policy() {
+        let tmp = tempfile::tempdir().unwrap();
+        let invalid = b"ab\xffc";
+        let cases = [(Utf8Policy::Reject, vec![]),
+                     (Utf8Policy::Replace, vec!["ab\u{FFFD}c".as_bytes().to_vec()]),
+                     (Utf8Policy::Bytes, vec![invalid.to_vec()])];
+        for (utf8_policy, expected) in cases {
+            let opt = TseriesFamOpt { utf8_policy, ..TseriesFamOpt::for_testing(tmp.path()) };
+            let mut tsf = new_tsf(0, vec![], 4096, opt).await;
+            let (flush_task_sender, _) = mpsc::channel(16);
+            tsf.put_mutcache(1, invalid, ValueType::String, 0, 1, flush_task_sender).await;
+            let values: Vec<Vec<u8>> = tsf.scan(1, &TimeRange::new(1, 1))
+                                          .await
+                                          .unwrap()
+                                          .into_iter()
+                                          .map(|d| match d {
+                                              DataType::Str(cell) => cell.val,
+                                              _ => panic!("unexpected data type"),
+                                          })
+                                          .collect();
+            assert_eq!(values, expected);
+        }
+    }
+
+    #[tokio::test]
+    pub async fn test_tsf_write_rejects() {
+        let tmp = tempfile::tempdir().unwrap();
+        let opt = TseriesFamOpt { utf8_policy: Utf8Policy::Reject,
+                                  ..TseriesFamOpt::for_testing(tmp.path()) };
+        let mut tsf = new_tsf(0, vec![], 4096, opt).await;
+        async fn put(tsf: &mut TseriesFamily,
+                     fid: u64,
+                     val: &[u8],
+                     dtype: ValueType,
+                     ts: i64)
+                     -> Result<(), WriteRejectReason> {
+            let (sender, _) = mpsc::channel(16);
+            tsf.put_mutcache_hinted(&mut FieldHints::default(), fid, val, dtype, 0, ts, sender)
+               .await
+        }
+
+        assert_eq!(put(&mut tsf, 1, &1_i64.to_be_bytes(), ValueType::Integer, 1).await, Ok(()));
+        assert_eq!(put(&mut tsf, 1, &1.5_f64.to_be_bytes(), ValueType::Float, 2).await,
+                   Err(WriteRejectReason::TypeConflict));
+        assert_eq!(put(&mut tsf, 2, b"ab\xffc", ValueType::String, 1).await,
+                   Err(WriteRejectReason::InvalidUtf8));
+        assert_eq!(put(&mut tsf, 2, b"abc", ValueType::String, 2).await, Ok(()));
+        assert_eq!(put(&mut tsf, 2, &1_i64.to_be_bytes(), ValueType::Integer, 3).await,
+                   Err(WriteRejectReason::TypeConflict));
+
+        let expected = RejectCounts { invalid_utf8: 1, type_conflict: 2, unknown_series: 0 };
+        assert_eq!(tsf.rejects().counts(), expected);
+        assert_eq!(tsf.debug_dump().rejected, expected);
+        // the rejected points are not in the cache
+        assert_eq!(tsf.scan(1, &TimeRange::new(i64::MAX, i64::MIN)).await.unwrap().len(), 1);
+        assert_eq!(tsf.scan(2, &TimeRange::new(i64::MAX, i64::MIN)).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    pub async fn test_tsf_ooo_tolerance() {
+        let tmp = tempfile::tempdir().unwrap();
+        let opt = TseriesFamOpt { ooo_tolerance_ns: 10, ..TseriesFamOpt::for_testing(tmp.path()) };
+        let mut tsf = new_tsf(0, vec![], 4096, opt).await;
+        tsf.immut_ts_min = 1000;
+        let (flush_task_sender, _) = mpsc::channel(16);
+        for ts in [1005, 995, 900, 992] {
+            tsf.put_mutcache(1,
+                             ts.to_be_bytes().as_slice(),
+                             ValueType::Integer,
+                             0,
+                             ts,
+                             flush_task_sender.clone())
+               .await;
+        }
+
+        let cell_ts = |cache: &MemCacheRef| {
+            let cache = cache.clone();
+            async move {
+                let cache = cache.read().await;
+                cache.iter_entries()
+                     .flat_map(|(_, entry)| {
+                         entry.cells.iter().map(|c| c.timestamp()).collect::<Vec<_>>()
+                     })
+                     .collect::<Vec<_>>()
+            }
+        };
+        // the slightly late points are kept sorted in the mutable cache
+        assert_eq!(cell_ts(&tsf.super_version.mut_cache).await, vec![992, 995, 1005]);
+        assert_eq!(cell_ts(&tsf.super_version.delta_mut_cache).await, vec![900]);
+    }
+
+    #[tokio::test]
+    pub async fn test_tsf_max_level_ts_monotonic() {
+        let tmp = tempfile::tempdir().unwrap();
+        let opt = TseriesFamOpt { ooo_tolerance_ns: 100, ..TseriesFamOpt::for_testing(tmp.path()) };
+        let version = Arc::new(RwLock::new(Version::new(0, 0, "db".to_string(), vec![], 0)));
+        let open = || new_tsf_of(0, version.clone(), 4096, opt.clone());
+        async fn put(tsf: &mut TseriesFamily, ts: i64) {
+            let (sender, _) = mpsc::channel(16);
+            tsf.put_mutcache(0, &ts.to_be_bytes(), ValueType::Integer, 0, ts, sender).await;
+        }
+
+        let mut tsf = open().await;
+        for ts in [1000, 1010, 1020] {
+            put(&mut tsf, ts).await;
+        }
+        assert!(tsf.take_flush_req().await.is_some());
+        assert_eq!(version.read().await.max_level_ts, 1020);
+
+        // reopened, the mutable cache holds only slightly late points when it is flushed
+        let mut tsf = open().await;
+        for ts in [950, 960] {
+            put(&mut tsf, ts).await;
+        }
+        assert!(tsf.take_flush_req().await.is_some());
+        assert_eq!(version.read().await.max_level_ts, 1020);
+        assert_eq!(tsf.imut_ts_min(), 1020);
+
+        // a point late by more than the tolerance from the flushed ones goes to the delta cache
+        put(&mut tsf, 910).await;
+        assert_eq!(tsf.super_version.mut_cache.read().await.entry_len(0), 0);
+        assert_eq!(tsf.super_version.delta_mut_cache.read().await.entry_len(0), 1);
+    }
+
+    #[tokio::test]
+    pub async fn test_tsf_delta_boundary_kept() {
+        let tmp = tempfile::tempdir().unwrap();
+        let opt = TseriesFamOpt { ooo_tolerance_ns: 0, ..TseriesFamOpt::for_testing(tmp.path()) };
+        let version = Arc::new(RwLock::new(Version::new(0, 0, "db".to_string(), vec![], i64::MIN)));
+        let open = || new_tsf_of(0, version.clone(), 4096, opt.clone());
+        let (sender, mut receiver) = mpsc::channel(16);
+
+        // the first point sets the boundary of the delta cache, the next point in order after
+        // a late one flushes the delta cache
+        let mut tsf = open().await;
+        for ts in [1000, 900, 1010] {
+            tsf.put_mutcache(0, &ts.to_be_bytes(), ValueType::Integer, 0, ts, sender.clone()).await;
+        }
+        assert!(receiver.try_recv().is_ok());
+        assert_eq!(version.read().await.max_level_ts, 1000);
+
+        // reopened before the mutable cache is flushed, a point behind the delta file still goes
+        // to the delta cache
+        let mut tsf = open().await;
+        assert_eq!(tsf.imut_ts_min(), 1000);
+        tsf.put_mutcache(0, &950_i64.to_be_bytes(), ValueType::Integer, 0, 950, sender).await;
+        assert_eq!(tsf.super_version.mut_cache.read().await.entry_len(0), 0);
+        assert_eq!(tsf.super_version.delta_mut_cache.read().await.entry_len(0), 1);
+    }
+
+    #[tokio::test]
+    pub async fn test_tsf_scan_unsorted_delta_cache() {
+        let tmp = tempfile::tempdir().unwrap();
+        let opt = TseriesFamOpt { ooo_tolerance_ns: 0, ..TseriesFamOpt::for_testing(tmp.path()) };
+        let mut tsf = new_tsf(0, vec![], 4096, opt).await;
+        tsf.immut_ts_min = 1000;
+        let (flush_task_sender, _) = mpsc::channel(16);
+        // the delta cache is not flushed before the first point in order after the late ones
+        for (ts, val) in [(1000_i64, 1000_i64), (900, 1), (850, 2), (950, 3), (850, 4), (870, 5)] {
+            tsf.put_mutcache(0,
+                             val.to_be_bytes().as_slice(),
+                             ValueType::Integer,
+                             0,
+                             ts,
+                             flush_task_sender.clone())
+               .await;
+        }
+        assert_eq!(tsf.super_version.delta_mut_cache.read().await.entry_len(0), 5);
+
+        let values = |data: Vec<DataType>| -> Vec<(i64, i64)> {
+            data.into_iter()
+                .map(|d| match d {
+                    DataType::I64(c) => (c.ts, c.val),
+                    _ => panic!("unexpected data type"),
+                })
+                .collect()
+        };
+        let expected = vec![(850, 4), (870, 5), (900, 1), (950, 3), (1000, 1000)];
+        let data = tsf.scan(0, &TimeRange::new(i64::MAX, i64::MIN)).await.unwrap();
+        assert_eq!(values(data), expected);
+        let data = tsf.memory_iter(0, &TimeRange::new(i64::MAX, i64::MIN)).await.iter().collect();
+        assert_eq!(values(data), expected);
+        let data = tsf.scan(0, &TimeRange::new(900, 850)).await.unwrap();
+        assert_eq!(values(data), vec![(850, 4), (870, 5), (900, 1)]);
+    }
+
+    #[tokio::test]
+    pub async fn test_tsf_scan_memory_first() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut lvl = LevelInfo::init(1);
+        lvl.apply(&CompactMeta { file_id: 1,
+                                 range: TimeRange::new(100, 0),
+                                 level: 1,
+                                 ..Default::default() });
+        let mut tsf = new_tsf(0, vec![lvl], 500, TseriesFamOpt::for_testing(tmp.path())).await;
+        let (flush_task_sender, flush_task_receiver) = mpsc::channel(16);
+        tsf.put_mutcache(0,
+                         10_i64.to_be_bytes().as_slice(),
+                         ValueType::Integer,
+                         0,
+                         10,
+                         flush_task_sender)
+           .await;
+        let file = tsf.version().read().await.levels_info[0].files[0].clone();
+
+        let data = tsf.scan(0, &TimeRange::new(10, 10)).await.unwrap();
+        assert_eq!(data.len(), 1);
+        assert_eq!(data[0].timestamp(), 10);
+        assert_eq!(file.read_count(), 0);
+
+        let data = tsf.scan(0, &TimeRange::new(20, 20)).await.unwrap();
+        assert!(data.is_empty());
+        assert_eq!(file.read_count(), 1);
+    }
+
+    async fn scan_duplicates(duplicate_policy: DuplicatePolicy) -> usize {
+        let tmp = tempfile::tempdir().unwrap();
+        let opt = TseriesFamOpt { duplicate_policy, ..TseriesFamOpt::for_testing(tmp.path()) };
+        let mut tsf = new_tsf(0, vec![], 4096, opt).await;
+        let (flush_task_sender, flush_task_receiver) = mpsc::channel(16);
+        for (ts, val) in [(1_i64, 10_i64), (2, 20), (2, 21), (2, 22), (3, 30)] {
+            tsf.put_mutcache(0,
+                             val.to_be_bytes().as_slice(),
+                             ValueType::Integer,
+                             0,
+                             ts,
+                             flush_task_sender.clone())
+               .await;
+        }
+        tsf.scan(0, &TimeRange::new(3, 1)).await.unwrap().len()
+    }
+
+    #[tokio::test]
+    pub async fn test_tsf_scan_duplicate_policy() {
+        assert_eq!(scan_duplicates(DuplicatePolicy::LastWins).await, 3);
+        assert_eq!(scan_duplicates(DuplicatePolicy::KeepAll).await, 5);
+    }
+
+    #[tokio::test]
+    pub async fn test_tsf_parallel_scan() {
+        let tmp = tempfile::tempdir().unwrap();
+        let tf_id = 108;
+        let opt = TseriesFamOpt::for_testing(tmp.path());
+        let dir = opt.tsm_dir(tf_id);
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut lvl = LevelInfo::init_in(&opt.base_dir, 1);
+        for file_id in 1..=16_u64 {
+            // every file overrides most of the points of the files before it
+            let ts: Vec<i64> = (0..20000).map(|t| t + file_id as i64 * 100).collect();
+            let meta = CompactMeta { file_id,
+                                     range: TimeRange::new(ts[ts.len() - 1], ts[0]),
+                                     level: 1,
+                                     ..Default::default() };
+            let mut block_set = HashMap::new();
+            block_set.insert(1,
+                             DataBlock::I64 { index: 0,
+                                              val: vec![file_id as i64; ts.len()],
+                                              ts,
+                                              validity: None });
+            build_tsm_file(make_tsm_file_name(&dir, file_id), block_set).unwrap();
+            lvl.apply(&meta);
+        }
+        let version = Arc::new(RwLock::new(Version::new(tf_id, 0, "db".to_string(), vec![lvl], 0)));
+
+        let time_range = TimeRange::new(i64::MAX, i64::MIN);
+        let mut results = vec![];
+        for read_parallelism in [1, 8] {
+            let opt = TseriesFamOpt { read_parallelism, ..opt.clone() };
+            let tsf = new_tsf_of(tf_id, version.clone(), 4096, opt).await;
+            let (data, stats) = tsf.scan_with(1, &time_range, &ReadOptions::default()).await;
+            // the files are read in one wave by at most `read_parallelism` reads at a time
+            assert_eq!((stats.files, stats.waves), (16, 1));
+            assert!(stats.peak_open_files >= 1 && stats.peak_open_files <= read_parallelism,
+                    "{:?}",
+                    stats);
+            let data: Vec<(i64, i64)> = data.into_iter()
+                                            .map(|d| match d {
+                                                DataType::I64(c) => (c.ts, c.val),
+                                                _ => panic!("unexpected data type"),
+                                            })
+                                            .collect();
+            results.push(data);
+        }
+        assert_eq!(results[0].len(), 20000 + 1500);
+        assert_eq!(results[0].last(), Some(&(21599, 16)));
+        assert_eq!(results[0], results[1]);
+    }
+
+    #[tokio::test]
+    pub async fn test_tsf_scan_in_waves() {
+        let tmp = tempfile::tempdir().unwrap();
+        let tf_id = 110;
+        let opt = TseriesFamOpt::for_testing(tmp.path());
+        let dir = opt.tsm_dir(tf_id);
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut lvl = LevelInfo::init_in(&opt.base_dir, 1);
+        for file_id in 1..=1000_u64 {
+            // every file overrides two points of the file before it
+            let ts: Vec<i64> = (0..3).map(|t| t + file_id as i64).collect();
+            let meta = CompactMeta { file_id,
+                                     range: TimeRange::new(ts[2], ts[0]),
+                                     level: 1,
+                                     ..Default::default() };
+            let mut block_set = HashMap::new();
+            block_set.insert(1,
+                             DataBlock::I64 { index: 0,
+                                              val: vec![file_id as i64; 3],
+                                              ts,
+                                              validity: None });
+            build_tsm_file(make_tsm_file_name(&dir, file_id), block_set).unwrap();
+            lvl.apply(&meta);
+        }
+        let version = Arc::new(RwLock::new(Version::new(tf_id, 0, "db".to_string(), vec![lvl], 0)));
+        let opt = TseriesFamOpt { read_parallelism: 32, ..opt.clone() };
+        let tsf = new_tsf_of(tf_id, version, 4096, opt).await;
+
+        let time_range = TimeRange::new(i64::MAX, i64::MIN);
+        let read_opts =
+            |max_concurrent_files| ReadOptions { max_concurrent_files, ..Default::default() };
+        let (all, stats) = tsf.scan_with(1, &time_range, &read_opts(0)).await;
+        assert_eq!(stats.waves, 1);
+        let (waves, stats) = tsf.scan_with(1, &time_range, &read_opts(16)).await;
+        assert_eq!(stats, ScanStats { files: 1000, waves: 63, ..stats.clone() });
+        assert!(stats.peak_open_files <= 16);
+
+        let values = |data: Vec<DataType>| {
+            data.into_iter()
+                .map(|d| match d {
+                    DataType::I64(c) => (c.ts, c.val),
+                    _ => panic!("unexpected data type"),
+                })
+                .collect::<Vec<_>>()
+        };
+        let expected: Vec<(i64, i64)> = (1..=1002).map(|t| (t, t.min(1000))).collect();
+        assert_eq!(values(all), expected);
+        assert_eq!(values(waves), expected);
+    }
+
+    #[tokio::test]
+    pub async fn test_tsf_scan_file_panic() {
+        let tmp = tempfile::tempdir().unwrap();
+        let tf_id = 130;
+        let opt = TseriesFamOpt::for_testing(tmp.path());
+        let dir = opt.tsm_dir(tf_id);
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut lvl = LevelInfo::init_in(&opt.base_dir, 1);
+        for file_id in 1..=8_u64 {
+            let ts = vec![file_id as i64];
+            let meta = CompactMeta { file_id,
+                                     range: TimeRange::new(ts[0], ts[0]),
+                                     level: 1,
+                                     ..Default::default() };
+            let mut block_set = HashMap::new();
+            block_set.insert(1, DataBlock::I64 { index: 0, val: vec![1], ts, validity: None });
+            build_tsm_file(make_tsm_file_name(&dir, file_id), block_set).unwrap();
+            lvl.apply(&meta);
+        }
+        let version = Arc::new(RwLock::new(Version::new(tf_id, 0, "db".to_string(), vec![lvl], 0)));
+        let tsf = new_tsf_of(tf_id, version, 4096, opt).await;
+
+        let time_range = TimeRange::new(i64::MAX, i64::MIN);
+        let file = tsf.version().read().await.levels_info()[0].files[2].clone();
+        assert_eq!(file.file_id(), 3);
+        file.set_panic_on_read(true);
+        // read one by one and by a pool of threads, the other files are still read
+        for read_parallelism in [1, 4] {
+            let read_opts = ReadOptions { read_parallelism, ..Default::default() };
+            let (data, stats) = tsf.scan_with(1, &time_range, &read_opts).await;
+            assert_eq!(data.iter().map(|d| d.timestamp()).collect::<Vec<_>>(),
+                       vec![1, 2, 4, 5, 6, 7, 8]);
+            assert_eq!(stats.failed_files, vec![(3, "panicked: injected panic".to_string())]);
+            let err = stats.check().unwrap_err().to_string();
+            assert!(err.contains("file 3: panicked"), "{}", err);
+        }
+        // a plain scan fails rather than return the points of the other files
+        assert!(matches!(tsf.scan(1, &time_range).await, Err(Error::ScanFailed { .. })));
+        file.set_panic_on_read(false);
+
+        // the failed scans were not kept by the result cache
+        let (data, stats) = tsf.scan_with(1, &time_range, &ReadOptions::default()).await;
+        assert!(!stats.cached);
+        assert!(stats.check().is_ok());
+        assert_eq!(data.len(), 8);
+    }
+
+    #[tokio::test]
+    pub async fn test_tsf_scan_during_compaction() {
+        let tmp = tempfile::tempdir().unwrap();
+        let tf_id = 112;
+        let opt = TseriesFamOpt::for_testing(tmp.path());
+        let dir = opt.tsm_dir(tf_id);
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut lvl = LevelInfo::init_in(&opt.base_dir, 1);
+        for (file_id, ts) in [(1, vec![1, 2, 3, 4]), (2, vec![3, 4, 5, 6])] {
+            let meta = CompactMeta { file_id,
+                                     range: TimeRange::new(ts[ts.len() - 1], ts[0]),
+                                     level: 1,
+                                     ..Default::default() };
+            let mut block_set = HashMap::new();
+            block_set.insert(1,
+                             DataBlock::I64 { index: 0,
+                                              val: vec![file_id as i64; 4],
+                                              ts,
+                                              validity: None });
+            build_tsm_file(make_tsm_file_name(&dir, file_id), block_set).unwrap();
+            lvl.apply(&meta);
+        }
+        let inputs = lvl.files.clone();
+        let version = Arc::new(RwLock::new(Version::new(tf_id, 0, "db".to_string(), vec![lvl], 0)));
+        let tsf = new_tsf_of(tf_id, version.clone(), 4096, opt.clone()).await;
+
+        // the output is written but not committed yet
+        inputs.iter().for_each(|f| f.mark_compaction());
+        let kernel = compact_kernel(&inputs);
+        let req = compact_req(tf_id, inputs.clone(), 2, Arc::new(opt));
+        let (edit, _) =
+            run_compaction_job(req, kernel, &DiskSpace::default()).await.unwrap().unwrap();
+
+        let values = |data: Vec<DataType>| {
+            data.into_iter()
+                .map(|d| match d {
+                    DataType::I64(c) => (c.ts, c.val),
+                    _ => panic!("unexpected data type"),
+                })
+                .collect::<Vec<_>>()
+        };
+        let expected = vec![(1, 1), (2, 1), (3, 2), (4, 2), (5, 2), (6, 2)];
+        let time_range = TimeRange::new(i64::MAX, i64::MIN);
+        let (during, stats) = tsf.scan_with(1, &time_range, &ReadOptions::default()).await;
+        assert_eq!(stats.files, 2);
+        assert_eq!(values(during), expected);
+
+        version.write().await.commit_compaction(&edit);
+        assert!(inputs.iter().all(|f| f.is_deleted() && !f.is_pending_compaction()));
+        let (after, stats) = tsf.scan_with(1, &time_range, &ReadOptions::default()).await;
+        assert_eq!(stats.files, 1);
+        assert_eq!(values(after), expected);
+    }
+
+    #[tokio::test]
+    pub async fn test_tsf_warm_after_publish() {
+        let tmp = tempfile::tempdir().unwrap();
+        let tf_id = 114;
+        let opt = TseriesFamOpt::for_testing(tmp.path());
+        let dir = opt.tsm_dir(tf_id);
+        std::fs::create_dir_all(&dir).unwrap();
+        let meta = |file_id: u64| CompactMeta { file_id,
+                                                range: TimeRange::new(3, 1),
+                                                level: 1,
+                                                ..Default::default() };
+
+        // publishing never reads the files, even the ones missing on disk
+        let mut version = Version::new(tf_id, 0, "db".to_string(), vec![], 0);
+        let mut edit = VersionEdit::new();
+        for file_id in 1001..=2000 {
+            edit.add_file(1, tf_id, file_id, 0, 0, meta(file_id));
+        }
+        version.commit_compaction(&edit).join().unwrap();
+        let published = &version.levels_info[0].files;
+        assert_eq!(published.len(), 1000);
+        assert!(published.iter().all(|f| !f.is_warm() && f.contains_field_id(1)));
+
+        // every file holds the field of its id
+        let mut lvl = LevelInfo::init_in(&opt.base_dir, 1);
+        for file_id in 1..=20_u64 {
+            let block =
+                DataBlock::I64 { index: 0, ts: vec![1, 2, 3], val: vec![1; 3], validity: None };
+            let path = make_tsm_file_name(&dir, file_id);
+            build_tsm_file(path, HashMap::from([(file_id, block)])).unwrap();
+            lvl.apply(&meta(file_id));
+        }
+        let files = lvl.files.clone();
+        let version = Arc::new(RwLock::new(Version::new(tf_id, 0, "db".to_string(), vec![lvl], 0)));
+        let tsf = new_tsf_of(tf_id, version, 4096, opt).await;
+        let time_range = TimeRange::new(i64::MAX, i64::MIN);
+        let (data, stats) = tsf.scan_with(7, &time_range, &ReadOptions::default()).await;
+        assert_eq!((data.len(), stats.files), (3, 20));
+
+        // once warm, the files without the field are pruned
+        spawn_warm(tf_id, files.clone()).join().unwrap();
+        assert!(files.iter().all(|f| f.is_warm() && !f.contains_field_id(100)));
+        let (data, stats) = tsf.scan_with(7, &time_range, &ReadOptions::default()).await;
+        assert_eq!((data.len(), stats.files), (3, 1));
+
+        // a delete after the warm is seen by the next read
+        files[6].add_tombstone(tf_id, 1, &[7], &TimeRange::new(2, 2)).unwrap();
+        let (data, _) = tsf.scan_with(7, &time_range, &ReadOptions::default()).await;
+        assert_eq!(data.iter().map(|d| d.timestamp()).collect::<Vec<_>>(), vec![1, 3]);
+    }
+
+    #[tokio::test]
+    pub async fn test_tsf_flush_field() {
+        let tmp = tempfile::tempdir().unwrap();
+        let opt = TseriesFamOpt { max_entry_cells: 4, ..TseriesFamOpt::for_testing(tmp.path()) };
+        let mut tsf = new_tsf(0, vec![], 4096, opt).await;
+        let (flush_task_sender, mut flush_task_receiver) = mpsc::channel(16);
+        tsf.put_mutcache(1,
+                         1_i64.to_be_bytes().as_slice(),
+                         ValueType::Integer,
+                         0,
+                         1,
+                         flush_task_sender.clone())
+           .await;
+        for ts in 1_i64..4 {
+            tsf.put_mutcache(0,
+                             ts.to_be_bytes().as_slice(),
+                             ValueType::Integer,
+                             0,
+                             ts,
+                             flush_task_sender.clone())
+               .await;
+        }
+        assert_eq!(tsf.mut_cache.read().await.entry_len(0), 3);
+        assert!(flush_task_receiver.try_recv().is_err());
+
+        tsf.put_mutcache(0,
+                         4_i64.to_be_bytes().as_slice(),
+                         ValueType::Integer,
+                         0,
+                         4,
+                         flush_task_sender.clone())
+           .await;
+        assert!(flush_task_receiver.try_recv().is_ok());
+        assert_eq!(tsf.mut_cache.read().await.entry_len(0), 0);
+        assert_eq!(tsf.mut_cache.read().await.entry_len(1), 1);
+    }
+
+    #[tokio::test]
+    pub async fn test_tsf_memory_iter() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut tsf = new_tsf(0, vec![], 4096, TseriesFamOpt::for_testing(tmp.path())).await;
+        async fn write(mem: &MemCacheRef, points: &[(i64, i64)]) {
+            for (ts, val) in points {
+                mem.write()
+                   .await
+                   .insert_raw(0, 0, *ts, ValueType::Integer, &val.to_be_bytes())
+                   .unwrap();
+            }
+        }
+        write(&tsf.mut_cache, &[(1, 10), (2, 20), (3, 30)]).await;
+        tsf.switch_to_immutable().await;
+        write(&tsf.delta_mut_cache, &[(3, 33), (5, 50)]).await;
+        write(&tsf.mut_cache, &[(4, 40), (2, 22)]).await;
+
+        let values = |data: Vec<DataType>| -> Vec<(i64, i64)> {
+            data.into_iter()
+                .map(|d| match d {
+                    DataType::I64(c) => (c.ts, c.val),
+                    _ => panic!("unexpected data type"),
+                })
+                .collect()
+        };
+        let data = tsf.memory_iter(0, &TimeRange::new(i64::MAX, i64::MIN)).await.iter().collect();
+        assert_eq!(values(data), vec![(1, 10), (2, 22), (3, 33), (4, 40), (5, 50)]);
+        let data = tsf.memory_iter(0, &TimeRange::new(3, 2)).await.iter().collect();
+        assert_eq!(values(data), vec![(2, 22), (3, 33)]);
+        let points = tsf.memory_iter(0, &TimeRange::new(i64::MAX, i64::MIN)).await;
+        let data = points.iter().take(1).collect();
+        assert_eq!(values(data), vec![(1, 10)]);
+
+        // the cells are borrowed from the caches, not read into a copy
+        let cache = tsf.mut_cache.read().await;
+        let mut cells = cache.iter_field(0, &TimeRange::new(i64::MAX, i64::MIN));
+        assert!(matches!(cells.next(), Some(std::borrow::Cow::Borrowed(d)) if d.timestamp() == 2));
+    }
+
+    #[tokio::test]
+    pub async fn test_tsf_read_while_flushing() {
+        let tmp = tempfile::tempdir().unwrap();
+        let tf_id = 116;
+        let opt = TseriesFamOpt { ooo_tolerance_ns: 0, ..TseriesFamOpt::for_testing(tmp.path()) };
+        let desc = [TseriesFamDesc { name: "db".to_string(), opt: opt.clone() }];
+        let version = Version::new(tf_id, 0, "db".to_string(), vec![], i64::MIN);
+        let version_set = VersionSet::new(&desc,
+                                          HashMap::from([(tf_id, Arc::new(RwLock::new(version)))]),
+                                          vec![]).await;
+        let version_set = Arc::new(RwLock::new(version_set));
+        let kernel = Arc::new(GlobalContext::new());
+        kernel.set_file_id(116_000);
+        let (flush_task_sender, _) = mpsc::channel(16);
+        let put = |ts: i64, val: i64| {
+            let version_set = version_set.clone();
+            let sender = flush_task_sender.clone();
+            async move {
+                let mut version_set = version_set.write().await;
+                let tsf = version_set.get_tsfamily_by_id(tf_id).unwrap();
+                tsf.put_mutcache(1, &val.to_be_bytes(), ValueType::Integer, 0, ts, sender).await;
+            }
+        };
+        let take = || {
+            let version_set = version_set.clone();
+            async move {
+                let mut version_set = version_set.write().await;
+                version_set.get_tsfamily_by_id(tf_id).unwrap().take_flush_req().await.unwrap()
+            }
+        };
+        let flush = |req: FlushReq| {
+            let version_set = version_set.clone();
+            let kernel = kernel.clone();
+            let opt = opt.clone();
+            async move {
+                let mems = req.mems.into_iter().map(|(_, mem)| mem).collect();
+                let mut task = FlushTask::new(mems,
+                                              tf_id,
+                                              opt.tsm_dir(tf_id),
+                                              opt.delta_dir(tf_id),
+                                              opt.duplicate_policy,
+                                              opt.max_flush_level,
+                                              opt.level_compression.clone(),
+                                              opt.flush_time_window_ns);
+                task.run(version_set, kernel, &mut vec![]).await.unwrap();
+            }
+        };
+        let scan = |max_ts: i64, min_ts: i64| {
+            let version_set = version_set.clone();
+            async move {
+                let mut version_set = version_set.write().await;
+                let tsf = version_set.get_tsfamily_by_id(tf_id).unwrap();
+                tsf.scan(1, &TimeRange::new(max_ts, min_ts))
+                   .await
+                   .unwrap()
+                   .into_iter()
+                   .map(|d| match d {
+                       DataType::I64(c) => (c.ts, c.val),
+                       _ => panic!("unexpected data type"),
+                   })
+                   .collect::<Vec<_>>()
+            }
+        };
+
+        for ts in 1..=10 {
+            put(ts, 1).await;
+        }
+        // the boundary moves to 10, the caches are in flight
+        let old = take().await;
+        assert_eq!(scan(i64::MAX, i64::MIN).await, (1..=10).map(|ts| (ts, 1)).collect::<Vec<_>>());
+
+        // the rewrites behind the boundary go to the delta cache, and are flushed first
+        put(5, 2).await;
+        assert_eq!(scan(5, 5).await, vec![(5, 2)]);
+        let delta = take().await;
+        put(5, 3).await;
+        put(7, 3).await;
+        flush(delta).await;
+        assert_eq!(scan(5, 5).await, vec![(5, 3)]);
+
+        // the older cache is published last, the newer writes still win
+        flush(old).await;
+        let expected: Vec<(i64, i64)> =
+            (1..=10).map(|ts| (ts, if ts == 5 || ts == 7 { 3 } else { 1 })).collect();
+        assert_eq!(scan(i64::MAX, i64::MIN).await, expected);
+        assert_eq!(scan(5, 5).await, vec![(5, 3)]);
+        let mut version_set = version_set.write().await;
+        assert!(version_set.get_tsfamily_by_id(tf_id).unwrap().flushing.is_empty());
+    }
+
+    #[tokio::test]
+    pub async fn test_tsf_install_version() {
+        let tmp = tempfile::tempdir().unwrap();
+        let tf_id = 118;
+        let opt = TseriesFamOpt::for_testing(tmp.path());
+        let dir = opt.tsm_dir(tf_id);
+        std::fs::create_dir_all(&dir).unwrap();
+        // the same points in both versions, with the values of the file id
+        let level = |file_id: u64, level: u32| {
+            let mut block_set = HashMap::new();
+            block_set.insert(1,
+                             DataBlock::I64 { index: 0,
+                                              ts: vec![1, 2, 3],
+                                              val: vec![file_id as i64; 3],
+                                              validity: None });
+            build_tsm_file(make_tsm_file_name(&dir, file_id), block_set).unwrap();
+            let mut lvl = LevelInfo::init_in(&opt.base_dir, level);
+            lvl.apply(&CompactMeta { file_id,
+                                     range: TimeRange::new(3, 1),
+                                     level,
+                                     ..Default::default() });
+            lvl
+        };
+        let old = Version::new(tf_id,
+                               0,
+                               "db".to_string(),
+                               vec![LevelInfo::init_in(&opt.base_dir, 0), level(1, 1)],
+                               0);
+        let tsf = new_tsf_of(tf_id, Arc::new(RwLock::new(old)), 4096, opt.clone()).await;
+        let id_before = tsf.debug_dump().super_version_id;
+        let tsf = Arc::new(RwLock::new(tsf));
+
+        let reader = {
+            let tsf = tsf.clone();
+            tokio::spawn(async move {
+                let mut seen = vec![];
+                for _ in 0..50 {
+                    let data = tsf.read()
+                                  .await
+                                  .scan(1, &TimeRange::new(i64::MAX, i64::MIN))
+                                  .await
+                                  .unwrap();
+                    let values: Vec<i64> = data.into_iter()
+                                               .map(|d| match d {
+                                                   DataType::I64(c) => c.val,
+                                                   _ => panic!("unexpected data type"),
+                                               })
+                                               .collect();
+                    seen.push(values);
+                    tokio::task::yield_now().await;
+                }
+                seen
+            })
+        };
+        let new = Version::new(tf_id,
+                               0,
+                               "db".to_string(),
+                               vec![LevelInfo::init_in(&opt.base_dir, 0),
+                                    LevelInfo::init_in(&opt.base_dir, 1),
+                                    level(2, 2)],
+                               0);
+        tsf.write().await.install_version(new).await;
+
+        // every read saw all the points of exactly one version
+        let seen = reader.await.unwrap();
+        assert!(seen.iter().all(|values| values == &vec![1; 3] || values == &vec![2; 3]));
+        let tsf = tsf.read().await;
+        assert_eq!(tsf.scan(1, &TimeRange::new(i64::MAX, i64::MIN)).await.unwrap().len(), 3);
+        assert_eq!(tsf.version().read().await.levels_info.len(), 3);
+        assert_eq!(tsf.debug_dump().super_version_id, id_before + 1);
+    }
+
+    #[tokio::test]
+    pub async fn test_tsf_switch_once() {
+        let tmp = tempfile::tempdir().unwrap();
+        let tsf = new_tsf(0, vec![], 200, TseriesFamOpt::for_testing(tmp.path())).await;
+        let tsf = Arc::new(RwLock::new(tsf));
+
+        // both writers observed the same full cache
+        let full = tsf.read().await.mut_cache.clone();
+        let switch = |full: MemCacheRef| {
+            let tsf = tsf.clone();
+            async move {
+                let cache = new_memcache(MemCacheImpl::HashMap, 0, 200, 0, false);
+                tsf.write().await.switch_cache(&full, cache).await
+            }
+        };
+        let (a, b) = tokio::join!(switch(full.clone()), switch(full.clone()));
+        assert!(a ^ b);
+        assert_eq!(tsf.read().await.immut_cache.len(), 1);
+    }
+
+    #[tokio::test]
+    pub async fn test_super_version_swaps() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut tsf = new_tsf(0, vec![], 200, TseriesFamOpt::for_testing(tmp.path())).await;
+        let matches = |tsf: &TseriesFamily| {
+            let sv = &tsf.super_version;
+            sv.version_id == tsf.super_version_id.load(Ordering::SeqCst)
+            && same_cache(&sv.mut_cache, &tsf.mut_cache)
+            && same_cache(&sv.delta_mut_cache, &tsf.delta_mut_cache)
+            && Arc::ptr_eq(&sv.immut_cache, &tsf.immut_cache)
+            && Arc::ptr_eq(&sv.cur_version, &tsf.version)
+        };
+        let (sender, _receiver) = mpsc::channel(16);
+
+        tsf.switch_memcache(new_memcache(MemCacheImpl::HashMap, 0, 200, 0, false)).await;
+        assert!(matches(&tsf));
+        assert_eq!(tsf.super_version.immut_cache.len(), 1);
+        tsf.switch_to_immutable().await;
+        assert!(matches(&tsf));
+        assert_eq!(tsf.super_version.immut_cache.len(), 2);
+        tsf.wrap_flush_req(sender.clone());
+        assert!(matches(&tsf));
+        assert!(tsf.super_version.immut_cache.is_empty());
+        let delta = tsf.delta_mut_cache.clone();
+        tsf.wrap_delta_flush_req(sender).await;
+        assert!(matches(&tsf));
+        assert!(!same_cache(&tsf.super_version.delta_mut_cache, &delta));
+        assert_eq!(tsf.super_version.version_id, 4);
+    }
+
+    #[tokio::test]
+    pub async fn test_tsf_concurrent_fill() {
+        let tmp = tempfile::tempdir().unwrap();
+        let tsf = new_tsf(0, vec![], 200, TseriesFamOpt::for_testing(tmp.path())).await;
+        let tsf = Arc::new(RwLock::new(tsf));
+        let (flush_task_sender, flush_task_receiver) = mpsc::channel(16);
+
+        // two writers go past the fill threshold at the same time
+        let write = |field_id: u64| {
+            let tsf = tsf.clone();
+            let sender = flush_task_sender.clone();
+            async move {
+                for ts in 1_i64..=10 {
+                    tsf.write()
+                       .await
+                       .put_mutcache(field_id,
+                                     ts.to_be_bytes().as_slice(),
+                                     ValueType::Integer,
+                                     0,
+                                     ts,
+                                     sender.clone())
+                       .await;
+                    tokio::task::yield_now().await;
+                }
+            }
+        };
+        tokio::join!(write(0), write(1));
+
+        let tsf = tsf.read().await;
+        assert_eq!(tsf.immut_cache.len(), 1);
+        let mut points = 0;
+        for mem in tsf.immut_cache.iter().chain([&tsf.mut_cache]) {
+            let mem = mem.read().await;
+            points += mem.entry_len(0) + mem.entry_len(1);
+        }
+        assert_eq!(points, 20);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    pub async fn test_immut_cache_snapshots() {
+        let tmp = tempfile::tempdir().unwrap();
+        let tsf = new_tsf(0, vec![], 4096, TseriesFamOpt::for_testing(tmp.path())).await;
+        let tsf = Arc::new(RwLock::new(tsf));
+        let count = 200_i64;
+        let done = Arc::new(AtomicBool::new(false));
+
+        // every switched cache holds one point
+        let writer = {
+            let (tsf, done) = (tsf.clone(), done.clone());
+            tokio::spawn(async move {
+                for ts in 1..=count {
+                    let mut tsf = tsf.write().await;
+                    tsf.mut_cache
+                       .write()
+                       .await
+                       .insert_raw(1, 1, ts, ValueType::Integer, &ts.to_be_bytes())
+                       .unwrap();
+                    tsf.switch_to_immutable().await;
+                    drop(tsf);
+                    tokio::task::yield_now().await;
+                }
+                done.store(true, Ordering::SeqCst);
+            })
+        };
+        // the flushed caches are finished a moment after they are taken, like the flush job
+        let flusher = {
+            let (tsf, done) = (tsf.clone(), done.clone());
+            tokio::spawn(async move {
+                let mut flushed = vec![];
+                while !done.load(Ordering::SeqCst) {
+                    let req = tsf.write().await.take_flush_req().await;
+                    tokio::task::yield_now().await;
+                    if let Some(req) = req {
+                        let mems: Vec<MemCacheRef> =
+                            req.mems.into_iter().map(|(_, mem)| mem).collect();
+                        tsf.write().await.finish_flush(&mems);
+                        for mem in mems {
+                            let ts: Vec<i64> = mem.read()
+                                                  .await
+                                                  .read(1, &TimeRange::new(i64::MAX, i64::MIN))
+                                                  .iter()
+                                                  .map(|d| d.timestamp())
+                                                  .collect();
+                            flushed.push((Arc::downgrade(&mem), ts));
+                        }
+                    }
+                }
+                flushed
+            })
+        };
+        let reader = {
+            let (tsf, done) = (tsf.clone(), done.clone());
+            tokio::spawn(async move {
+                let mut snapshots = 0;
+                while !done.load(Ordering::SeqCst) {
+                    let super_version = tsf.read().await.super_version.clone();
+                    let mut seen = vec![];
+                    for mem in super_version.immut_cache.iter() {
+                        assert!(!contains_cache(&seen, mem), "a cache is listed twice");
+                        let mem_read = mem.read().await;
+                        assert!(mem_read.is_immutable());
+                        assert_eq!(mem_read.entry_len(1), 1);
+                        drop(mem_read);
+                        seen.push(mem.clone());
+                    }
+                    snapshots += 1;
+                    tokio::task::yield_now().await;
+                }
+                snapshots
+            })
+        };
+        writer.await.unwrap();
+        let flushed = flusher.await.unwrap();
+        assert!(reader.await.unwrap() > 0);
+
+        let tsf = tsf.read().await;
+        let mut points: Vec<i64> = flushed.iter().flat_map(|(_, ts)| ts.clone()).collect();
+        for mem in tsf.immut_cache.iter().chain(tsf.flushing.iter()) {
+            let mem = mem.read().await;
+            let data = mem.read(1, &TimeRange::new(i64::MAX, i64::MIN));
+            points.extend(data.iter().map(|d| d.timestamp()));
+        }
+        points.sort_unstable();
+        assert_eq!(points, (1..=count).collect::<Vec<_>>());
+        // no super version or list holds a finished cache any longer
+        assert!(flushed.iter().all(|(mem, _)| mem.upgrade().is_none()));
+    }
+
+    #[test]
+    fn test_column_file_fields() {
+        let tmp = tempfile::tempdir().unwrap();
+        let tf_id = 102;
+        let opt = TseriesFamOpt::for_testing(tmp.path());
+        let dir = opt.tsm_dir(tf_id);
+        std::fs::create_dir_all(&dir).unwrap();
+        let block_set = || {
+            let mut block_set = HashMap::new();
+            for i in 0..10_000_u64 {
+                block_set.insert(i * 2,
+                                 DataBlock::I64 { index: 0,
+                                                  ts: vec![1],
+                                                  val: vec![1],
+                                                  validity: None });
+            }
+            block_set
+        };
+        let mut lvl = LevelInfo::init_in(&opt.base_dir, 1);
+        let meta =
+            |file_id| CompactMeta { file_id, range: TimeRange::new(1, 1), ..Default::default() };
+
+        build_tsm_file(make_tsm_file_name(&dir, 1), block_set()).unwrap();
+        lvl.apply(&meta(1));
+
+        // a file written without the field ids
+        let file = get_file_manager().create_file(make_tsm_file_name(&dir, 2)).unwrap();
+        let mut fs_cursor = file.into_cursor();
+        TsmHeaderWriter::write_to(&mut fs_cursor).unwrap();
+        let index = TsmBlockWriter::write_to(&mut fs_cursor, block_set()).unwrap();
+        let index_pos = fs_cursor.pos();
+        let bloom_filter = TsmIndexWriter::write_to(&mut fs_cursor, index).unwrap();
+        TsmFooterWriter::write_to(&mut fs_cursor, &bloom_filter, index_pos).unwrap();
+        fs_cursor.sync_all(FileSync::Hard).unwrap();
+        lvl.apply(&meta(2));
+
+        let query: Vec<u64> = (0..20_000).rev().collect();
+        let expected: Vec<u64> = (0..10_000).map(|i| i * 2).collect();
+        let (new_file, old_file) = (&lvl.files[0], &lvl.files[1]);
+
+        assert_eq!(new_file.fields_in_range(tf_id, 100, 109).unwrap(),
+                   Some(vec![100, 102, 104, 106, 108]));
+        assert_eq!(new_file.fields_in_range(tf_id, 20_000, u64::MAX).unwrap(), Some(vec![]));
+        assert_eq!(new_file.probe_fields(tf_id, &query).unwrap(), expected);
+        let data = new_file.read_field(tf_id, 19_998, &TimeRange::new(1, 1)).unwrap();
+        assert_eq!(data.len(), 1);
+
+        // per field probing of the bloom filter never misses a field of the fast path
+        let (mut fs_cursor, len) = new_file.file_reader(tf_id).unwrap();
+        let bloom_filter =
+            TsmFooterReader::read_bloom_filter(&mut fs_cursor, len as usize).unwrap();
+        assert!(expected.iter().all(|fid| bloom_filter.contains(&fid.to_be_bytes())));
+
+        assert_eq!(old_file.fields_in_range(tf_id, 100, 109).unwrap(), None);
+        let probed = old_file.probe_fields(tf_id, &query).unwrap();
+        assert!(expected.iter().all(|fid| probed.binary_search(fid).is_ok()));
+        let data = old_file.read_field(tf_id, 19_998, &TimeRange::new(1, 1)).unwrap();
+        assert_eq!(data.len(), 1);
+    }
+
+    #[tokio::test]
+    pub async fn test_tsf_timestamps() {
+        let tmp = tempfile::tempdir().unwrap();
+        let tf_id = 103;
+        let opt = TseriesFamOpt::for_testing(tmp.path());
+        let dir = opt.tsm_dir(tf_id);
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut block_set = HashMap::new();
+        block_set.insert(1,
+                         DataBlock::I64 { index: 0,
+                                          ts: vec![1, 3, 5, 7],
+                                          val: vec![1; 4],
+                                          validity: None });
+        block_set.insert(2,
+                         DataBlock::I64 { index: 0,
+                                          ts: vec![2, 4],
+                                          val: vec![2; 2],
+                                          validity: None });
+        let fname = make_tsm_file_name(&dir, 1);
+        build_tsm_file(fname.clone(), block_set).unwrap();
+        let mut lvl = LevelInfo::init_in(&opt.base_dir, 1);
+        lvl.apply(&CompactMeta { file_id: 1, range: TimeRange::new(7, 1), ..Default::default() });
+
+        let tsf = new_tsf(tf_id, vec![lvl], 4096, opt.clone()).await;
+        for ts in [5, 6] {
+            tsf.mut_cache
+               .write()
+               .await
+               .insert_raw(1, 1, ts, ValueType::Integer, &10_i64.to_be_bytes())
+               .unwrap();
+        }
+        let time_range = TimeRange::new(6, 2);
+        let expected: Vec<i64> =
+            tsf.scan(1, &time_range).await.unwrap().iter().map(|d| d.timestamp()).collect();
+        assert_eq!(expected, vec![3, 5, 6]);
+
+        // overwrite the values on disk, the timestamps are still readable
+        let file = get_file_manager().open_file(&fname).unwrap();
+        let len = file.len();
+        let mut fs_cursor = file.into_cursor();
+        let blocks: Vec<FileBlock> =
+            TsmIndexReader::try_new(&mut fs_cursor, len as usize).unwrap()
+                                                                 .map(|entry| entry.unwrap().block)
+                                                                 .collect();
+        for block in blocks {
+            let junk = vec![0xFF; (block.offset + block.size - block.val_off) as usize];
+            fs_cursor.write_at(block.val_off, &junk).unwrap();
+        }
+        fs_cursor.sync_all(FileSync::Hard).unwrap();
+        assert_eq!(tsf.timestamps(1, &time_range).await.unwrap(), expected);
+    }
+
+    #[tokio::test]
+    pub async fn test_tsf_scan_include_deleted() {
+        let tmp = tempfile::tempdir().unwrap();
+        let tf_id = 122;
+        let opt = TseriesFamOpt::for_testing(tmp.path());
+        let dir = opt.tsm_dir(tf_id);
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut block_set = HashMap::new();
+        block_set.insert(1,
+                         DataBlock::I64 { index: 0,
+                                          ts: vec![1, 2, 3, 4, 5],
+                                          val: vec![1; 5],
+                                          validity: None });
+        build_tsm_file(make_tsm_file_name(&dir, 1), block_set).unwrap();
+        let mut lvl = LevelInfo::init_in(&opt.base_dir, 1);
+        lvl.apply(&CompactMeta { file_id: 1, range: TimeRange::new(5, 1), ..Default::default() });
+        let file = lvl.files[0].clone();
+
+        let tsf = new_tsf(tf_id, vec![lvl], 4096, opt).await;
+        // 2 and 3 are deleted, 3 is written again after the delete
+        file.add_tombstone(tf_id, 1, &[1], &TimeRange::new(3, 2)).unwrap();
+        tsf.mut_cache
+           .write()
+           .await
+           .insert_raw(1, 1, 3, ValueType::Integer, &10_i64.to_be_bytes())
+           .unwrap();
+        let values = |data: Vec<DataType>| {
+            data.into_iter()
+                .map(|d| match d {
+                    DataType::I64(c) => (c.ts, c.val),
+                    _ => panic!("unexpected data type"),
+                })
+                .collect::<Vec<_>>()
+        };
+        let time_range = TimeRange::new(i64::MAX, i64::MIN);
+
+        let (data, stats) = tsf.scan_with(1, &time_range, &ReadOptions::default()).await;
+        assert_eq!(values(data), vec![(1, 1), (3, 10), (4, 1), (5, 1)]);
+        assert!(stats.deleted.is_empty());
+
+        let read_opts = ReadOptions { include_deleted: true, ..Default::default() };
+        let (data, stats) = tsf.scan_with(1, &time_range, &read_opts).await;
+        assert_eq!(values(data), vec![(1, 1), (2, 1), (3, 10), (4, 1), (5, 1)]);
+        assert_eq!(stats.deleted, vec![2]);
+        assert_eq!(stats.files, 1);
+    }
+
+    #[test]
+    fn test_order_by_seq() {
+        // a source written after another goes after it whatever its kind, the sources whose
+        // writes interleave keep their order
+        let sources = vec![('a', (300, 400)), ('b', (50, 60)), ('c', (55, 500))];
+        assert_eq!(order_by_seq(sources), vec!['b', 'a', 'c']);
+        // a file not recording its writes interleaves with every source
+        let sources = vec![('a', (0, u64::MAX)), ('b', (5, 6)), ('c', (1, 2))];
+        assert_eq!(order_by_seq(sources), vec!['a', 'c', 'b']);
+        // the rules disagree on every source
+        let sources = vec![('a', (50, 60)), ('b', (1, 100)), ('c', (1, 10))];
+        assert_eq!(order_by_seq(sources), vec!['a', 'b', 'c']);
+    }
+
+    #[tokio::test]
+    pub async fn test_tsf_scan_by_write_seq() {
+        let tmp = tempfile::tempdir().unwrap();
+        let tf_id = 137;
+        let opt = TseriesFamOpt::for_testing(tmp.path());
+        let dir = opt.delta_dir(tf_id);
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut block_set = HashMap::new();
+        block_set.insert(1,
+                         DataBlock::I64 { index: 0,
+                                          ts: vec![1, 2],
+                                          val: vec![1; 2],
+                                          validity: None });
+        build_tsm_file(make_delta_file_name(&dir, 1), block_set).unwrap();
+        // the delta file holds the writes from 50 to 60
+        let mut lvl = LevelInfo::init_in(&opt.base_dir, 0);
+        lvl.apply(&CompactMeta { file_id: 1,
+                                 range: TimeRange::new(2, 1),
+                                 low_seq: 50,
+                                 high_seq: 60,
+                                 is_delta: true,
+                                 ..Default::default() });
+        let tsf = new_tsf(tf_id, vec![lvl], 4096, opt).await;
+        // the mutable cache rewrites a point after the delta file, as it does when the boundary
+        // of the delta cache moved back
+        tsf.mut_cache
+           .write()
+           .await
+           .insert_raw(300, 1, 1, ValueType::Integer, &2_i64.to_be_bytes())
+           .unwrap();
+        let values = |data: Vec<DataType>| {
+            data.into_iter()
+                .map(|d| match d {
+                    DataType::I64(c) => (c.ts, c.val),
+                    _ => panic!("unexpected data type"),
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let data = tsf.scan(1, &TimeRange::new(i64::MAX, i64::MIN)).await.unwrap();
+        assert_eq!(values(data), vec![(1, 2), (2, 1)]);
+        let data = tsf.scan(1, &TimeRange::new(1, 1)).await.unwrap();
+        assert_eq!(values(data), vec![(1, 2)]);
+    }
+
+    #[tokio::test]
+    pub async fn test_tsf_memory_only_scan() {
+        let tmp = tempfile::tempdir().unwrap();
+        let tf_id = 123;
+        let opt = TseriesFamOpt::for_testing(tmp.path());
+        let dir = opt.tsm_dir(tf_id);
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut block_set = HashMap::new();
+        block_set.insert(1,
+                         DataBlock::I64 { index: 0,
+                                          ts: vec![1, 2, 3],
+                                          val: vec![1; 3],
+                                          validity: None });
+        build_tsm_file(make_tsm_file_name(&dir, 1), block_set).unwrap();
+        let mut lvl = LevelInfo::init_in(&opt.base_dir, 1);
+        lvl.apply(&CompactMeta { file_id: 1, range: TimeRange::new(3, 1), ..Default::default() });
+        let file = lvl.files[0].clone();
+
+        // the points up to 3 are flushed to the files
+        let version = Version::new(tf_id, 0, "db".to_string(), vec![lvl], 3);
+        let tsf = new_tsf_of(tf_id, Arc::new(RwLock::new(version)), 4096, opt).await;
+        for ts in 4..=6 {
+            tsf.mut_cache
+               .write()
+               .await
+               .insert_raw(1, 1, ts, ValueType::Integer, &10_i64.to_be_bytes())
+               .unwrap();
+        }
+        let values = |data: Vec<DataType>| {
+            data.into_iter()
+                .map(|d| match d {
+                    DataType::I64(c) => (c.ts, c.val),
+                    _ => panic!("unexpected data type"),
+                })
+                .collect::<Vec<_>>()
+        };
+        let read_opts = ReadOptions { memory_only: true, ..Default::default() };
+
+        // a range newer than the files never lists nor opens them
+        let (data, stats) = tsf.scan_with(1, &TimeRange::new(i64::MAX, 4), &read_opts).await;
+        assert_eq!(values(data), vec![(4, 10), (5, 10), (6, 10)]);
+        assert!(stats.memory_only);
+        assert_eq!((stats.files, stats.waves), (0, 0));
+        assert_eq!(file.read_count(), 0);
+        assert_eq!(tsf.memory_only_scans(), 1);
+
+        // a range straddling the files reads them too
+        let (data, stats) = tsf.scan_with(1, &TimeRange::new(i64::MAX, 2), &read_opts).await;
+        assert_eq!(values(data), vec![(2, 1), (3, 1), (4, 10), (5, 10), (6, 10)]);
+        assert!(!stats.memory_only);
+        assert_eq!(stats.files, 1);
+        assert_eq!(file.read_count(), 1);
+        assert_eq!(tsf.memory_only_scans(), 1);
+    }
+
+    #[tokio::test]
+    pub async fn test_tsf_result_cache() {
+        let tmp = tempfile::tempdir().unwrap();
+        let tf_id = 124;
+        let opt = TseriesFamOpt { result_cache_entries: 16,
+                                  result_cache_ttl_ms: 60_000,
+                                  ..TseriesFamOpt::for_testing(tmp.path()) };
+        let dir = opt.tsm_dir(tf_id);
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut block_set = HashMap::new();
+        block_set.insert(1,
+                         DataBlock::I64 { index: 0,
+                                          ts: vec![1, 2, 3],
+                                          val: vec![1; 3],
+                                          validity: None });
+        build_tsm_file(make_tsm_file_name(&dir, 1), block_set).unwrap();
+        let mut lvl = LevelInfo::init_in(&opt.base_dir, 1);
+        lvl.apply(&CompactMeta { file_id: 1, range: TimeRange::new(3, 1), ..Default::default() });
+        let file = lvl.files[0].clone();
+        let version = Version::new(tf_id, 0, "db".to_string(), vec![lvl], 3);
+        let mut tsf = new_tsf_of(tf_id, Arc::new(RwLock::new(version)), 4096, opt).await;
+        let values = |data: Vec<DataType>| {
+            data.into_iter()
+                .map(|d| match d {
+                    DataType::I64(c) => (c.ts, c.val),
+                    _ => panic!("unexpected data type"),
+                })
+                .collect::<Vec<_>>()
+        };
+        let time_range = TimeRange::new(i64::MAX, i64::MIN);
+        let read_opts = ReadOptions::default();
+
+        let (data, stats) = tsf.scan_with(1, &time_range, &read_opts).await;
+        assert_eq!(values(data), vec![(1, 1), (2, 1), (3, 1)]);
+        assert!(!stats.cached);
+        assert_eq!(file.read_count(), 1);
+
+        // the identical scan opens no file
+        let (data, stats) = tsf.scan_with(1, &time_range, &read_opts).await;
+        assert_eq!(values(data), vec![(1, 1), (2, 1), (3, 1)]);
+        assert!(stats.cached);
+        assert_eq!(stats.files, 0);
+        assert_eq!(file.read_count(), 1);
+        // another range is read
+        tsf.scan_with(1, &TimeRange::new(2, 1), &read_opts).await;
+        assert_eq!(file.read_count(), 2);
+
+        // a write into the field drops its scans
+        let (flush_task_sender, _) = mpsc::channel(16);
+        tsf.put_mutcache(1, &10_i64.to_be_bytes(), ValueType::Integer, 1, 4, flush_task_sender)
+           .await;
+        let (data, stats) = tsf.scan_with(1, &time_range, &read_opts).await;
+        assert_eq!(values(data), vec![(1, 1), (2, 1), (3, 1), (4, 10)]);
+        assert!(!stats.cached);
+        assert_eq!(file.read_count(), 3);
+        let stats = tsf.result_cache_stats();
+        assert_eq!((stats.hits, stats.admitted), (1, 3));
+    }
+
+    #[tokio::test]
+    pub async fn test_tsf_last_value() {
+        let tmp = tempfile::tempdir().unwrap();
+        let tf_id = 128;
+        let opt =
+            TseriesFamOpt { last_value_entries: 16, ..TseriesFamOpt::for_testing(tmp.path()) };
+        let dir = opt.tsm_dir(tf_id);
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut block_set = HashMap::new();
+        block_set.insert(1,
+                         DataBlock::I64 { index: 0,
+                                          ts: vec![1, 2, 3],
+                                          val: vec![1, 2, 3],
+                                          validity: None });
+        build_tsm_file(make_tsm_file_name(&dir, 1), block_set).unwrap();
+        let mut lvl = LevelInfo::init_in(&opt.base_dir, 1);
+        lvl.apply(&CompactMeta { file_id: 1, range: TimeRange::new(3, 1), ..Default::default() });
+        let version = Arc::new(RwLock::new(Version::new(tf_id, 0, "db".to_string(), vec![lvl], 3)));
+        let open = || new_tsf_of(tf_id, version.clone(), 4096, opt.clone());
+        let value = |data: Option<DataType>| match data {
+            Some(DataType::I64(c)) => Some((c.ts, c.val)),
+            None => None,
+            _ => panic!("unexpected data type"),
+        };
+        let mut tsf = open().await;
+        let (flush_task_sender, _) = mpsc::channel(16);
+
+        // filled from the file, then served from memory
+        assert_eq!(value(tsf.last_value(1).await), Some((3, 3)));
+        assert_eq!(value(tsf.last_value(1).await), Some((3, 3)));
+        assert_eq!(tsf.last_values(&[1, 2]).await.len(), 2);
+        let stats = tsf.last_value_stats();
+        assert_eq!((stats.hits, stats.misses), (2, 2));
+
+        // the newest timestamp is kept, not the last point written
+        for (ts, val) in [(10, 10), (5, 5)] {
+            tsf.put_mutcache(1,
+                             &(val as i64).to_be_bytes(),
+                             ValueType::Integer,
+                             1,
+                             ts,
+                             flush_task_sender.clone())
+               .await;
+        }
+        tsf.put_mutcache(2, &7_i64.to_be_bytes(), ValueType::Integer, 2, 7, flush_task_sender)
+           .await;
+        let last = tsf.last_values(&[1, 2]).await;
+        assert_eq!(last.into_iter().map(value).collect::<Vec<_>>(),
+                   vec![Some((10, 10)), Some((7, 7))]);
+        assert_eq!(tsf.last_value_stats().misses, 2);
+
+        // a delete of an older point keeps the field, a delete of its point drops it
+        tsf.delete_range(3, &[1], &TimeRange::new(6, 5)).await.unwrap();
+        assert_eq!(value(tsf.last_value(1).await), Some((10, 10)));
+        tsf.delete_range(4, &[1], &TimeRange::new(10, 10)).await.unwrap();
+        assert_eq!(value(tsf.last_value(1).await), Some((3, 3)));
+        assert_eq!(tsf.last_value_stats().misses, 3);
+
+        // nothing is kept over a restart, the field is filled again
+        let tsf = open().await;
+        assert_eq!(value(tsf.last_value(1).await), Some((3, 3)));
+        assert_eq!(tsf.last_value_stats().misses, 1);
+    }
+
+    #[test]
+    fn test_read_columnfile_boundary() {
+        let tmp = tempfile::tempdir().unwrap();
+        let tf_id = 117;
+        let opt = TseriesFamOpt::for_testing(tmp.path());
+        let dir = opt.tsm_dir(tf_id);
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut block_set = HashMap::new();
+        block_set.insert(1,
+                         DataBlock::I64 { index: 0,
+                                          ts: vec![1, 3, 5],
+                                          val: vec![1; 3],
+                                          validity: None });
+        build_tsm_file(make_tsm_file_name(&dir, 1), block_set).unwrap();
+        let mut lvl = LevelInfo::init_in(&opt.base_dir, 1);
+        lvl.apply(&CompactMeta { file_id: 1, range: TimeRange::new(5, 1), ..Default::default() });
+
+        // the block ends where the query starts
+        let data = lvl.read_columnfile(tf_id, 1, &TimeRange::new(10, 5));
+        assert_eq!(data.iter().map(|d| d.timestamp()).collect::<Vec<_>>(), vec![5]);
+        let data = lvl.read_columnfile(tf_id, 1, &TimeRange::new(1, 1));
+        assert_eq!(data.iter().map(|d| d.timestamp()).collect::<Vec<_>>(), vec![1]);
+        assert!(lvl.read_columnfile(tf_id, 1, &TimeRange::new(10, 6)).is_empty());
+    }
+
+    #[test]
+    fn test_read_field_range_mismatch() {
+        let tmp = tempfile::tempdir().unwrap();
+        let tf_id = 125;
+        let opt = TseriesFamOpt::for_testing(tmp.path());
+        let dir = opt.tsm_dir(tf_id);
+        std::fs::create_dir_all(&dir).unwrap();
+        // the index entry of the block says [1, 5], its timestamps are 1..=10
+        let file = get_file_manager().create_file(make_tsm_file_name(&dir, 1)).unwrap();
+        let mut fs_cursor = file.into_cursor();
+        TsmHeaderWriter::write_to(&mut fs_cursor).unwrap();
+        let block =
+            DataBlock::I64 { index: 0, ts: (1..=10).collect(), val: vec![1; 10], validity: None };
+        let mut index =
+            TsmBlockWriter::write_to(&mut fs_cursor, HashMap::from([(1, block)])).unwrap();
+        index.get_mut(&1).unwrap()[0].max_ts = 5;
+        let index_pos = fs_cursor.pos();
+        let bloom_filter = TsmIndexWriter::write_to(&mut fs_cursor, index).unwrap();
+        TsmFooterWriter::write_to(&mut fs_cursor, &bloom_filter, index_pos).unwrap();
+        fs_cursor.sync_all(FileSync::Hard).unwrap();
+        let mut lvl = LevelInfo::init_in(&opt.base_dir, 1);
+        lvl.apply(&CompactMeta { file_id: 1, range: TimeRange::new(10, 1), ..Default::default() });
+        let file = lvl.files[0].clone();
+
+        // the block is skipped by the index
+        let range = TimeRange::new(8, 6);
+        assert!(file.read_field(tf_id, 1, &range).unwrap().is_empty());
+        assert!(!file.has_range_mismatch());
+
+        let read_opts = ReadOptions { verify_block_ranges: true, ..Default::default() };
+        let data = file.read_field_with(tf_id, 1, &range, &read_opts).unwrap();
+        assert_eq!(data.iter().map(|d| d.timestamp()).collect::<Vec<_>>(), vec![6, 7, 8]);
+        assert!(file.has_range_mismatch());
+    }
+
+    #[tokio::test]
+    pub async fn test_tsf_base_dir() {
+        let tmp = tempfile::tempdir().unwrap();
+        let tf_id = 121;
+        let opt = TseriesFamOpt::for_testing(tmp.path());
+        let base = tmp.path().join("data").join("121");
+        assert_eq!(Path::new(&opt.tsm_dir(tf_id)), base.join("tsm"));
+        assert_eq!(Path::new(&opt.delta_dir(tf_id)), base.join("delta"));
+        std::fs::create_dir_all(opt.tsm_dir(tf_id)).unwrap();
+        let mut block_set = HashMap::new();
+        block_set.insert(1,
+                         DataBlock::I64 { index: 0,
+                                          ts: vec![1, 2],
+                                          val: vec![1; 2],
+                                          validity: None });
+        build_tsm_file(make_tsm_file_name(&opt.tsm_dir(tf_id), 1), block_set).unwrap();
+
+        let version = Version::new(tf_id, 0, "db".to_string(), vec![], 0);
+        let tsf = new_tsf_of(tf_id, Arc::new(RwLock::new(version)), 4096, opt).await;
+        let meta = |file_id: u64, is_delta: bool| CompactMeta { file_id,
+                                                                range: TimeRange::new(2, 1),
+                                                                tsf_id: tf_id,
+                                                                level: u32::from(!is_delta),
+                                                                is_delta,
+                                                                ..Default::default() };
+        let mut version = tsf.version().write().await;
+        let tsm = version.apply_file(&meta(1, false)).unwrap();
+        let delta = version.apply_file(&meta(2, true)).unwrap();
+        drop(version);
+        // the files of the family are resolved under its own base directory
+        assert_eq!(Path::new(&tsm.path(tf_id)), base.join("tsm").join("_000001.tsm"));
+        assert_eq!(Path::new(&delta.path(tf_id)), base.join("delta").join("_000002.delta"));
+        let data = tsm.read_field(tf_id, 1, &TimeRange::new(2, 1)).unwrap();
+        assert_eq!(data.iter().map(|d| d.timestamp()).collect::<Vec<_>>(), vec![1, 2]);
+    }
+
+    #[tokio::test]
+    pub async fn test_super_version_summary() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut lvl = LevelInfo::init(1);
+        lvl.apply(&CompactMeta { file_id: 1,
+                                 file_size: 4096,
+                                 range: TimeRange::new(100, 0),
+                                 level: 1,
+                                 ..Default::default() });
+        let tsf = new_tsf(0,
+                          vec![LevelInfo::init(0), lvl],
+                          4096,
+                          TseriesFamOpt::for_testing(tmp.path())).await;
+        tsf.mut_cache
+           .write()
+           .await
+           .insert_raw(1, 1, 10, ValueType::Integer, &10_i64.to_be_bytes())
+           .unwrap();
+
+        let summary = tsf.super_version.summary().await;
+        assert_eq!(summary.mut_cache.cells, 1);
+        assert_eq!(summary.mut_cache.ts_range, Some((10, 10)));
+        assert_eq!(summary.to_string(),
+                   format!("tf_id=0 version_id=0 mut_bytes={} mut_cells=1 delta_bytes=0 \
+                            delta_cells=0 immut_caches=0 immut_bytes=0 levels=L0:0/0,L1:1/4096",
+                           summary.mut_cache.bytes));
+    }
+
+    #[tokio::test]
+    pub async fn test_tsf_scan_as() {
+        let tmp = tempfile::tempdir().unwrap();
+        let tsf = new_tsf(0, vec![], 4096, TseriesFamOpt::for_testing(tmp.path())).await;
+        {
+            let mut cache = tsf.mut_cache.write().await;
+            for (ts, val) in [(1, -2_i64), (2, 3)] {
+                cache.insert_raw(1, 1, ts, ValueType::Integer, &val.to_be_bytes()).unwrap();
+            }
+            cache.insert_raw(1, 2, 1, ValueType::String, b"1.5").unwrap();
+        }
+
+        let time_range = TimeRange::new(i64::MAX, i64::MIN);
+        let data = tsf.scan_as(1, &time_range, ValueType::Float).await.unwrap();
+        let data: Vec<(i64, f64)> = data.into_iter()
+                                        .map(|d| match d {
+                                            DataType::F64(c) => (c.ts, c.val),
+                                            _ => panic!("unexpected data type"),
+                                        })
+                                        .collect();
+        assert_eq!(data, vec![(1, -2.0), (2, 3.0)]);
+        assert!(matches!(tsf.scan_as(2, &time_range, ValueType::Float).await,
+                         Err(Error::IncompatibleCoercion { from: ValueType::String,
+                                                           to: ValueType::Float })));
+    }
+
+    #[tokio::test]
+    pub async fn test_tsf_debug_dump() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut lvl = LevelInfo::init(1);
+        lvl.apply(&CompactMeta { file_id: 1,
+                                 file_size: 4096,
+                                 range: TimeRange::new(100, 0),
+                                 level: 1,
+                                 ..Default::default() });
+        lvl.files[0].mark_compaction();
+        let tsf = new_tsf(0, vec![lvl], 4096, TseriesFamOpt::for_testing(tmp.path())).await;
+
+        // a writer holds the mutable cache
+        let writer = tsf.mut_cache.write().await;
+        let dump = tsf.debug_dump();
+        assert_eq!(dump.mut_cache, DumpField::Unavailable);
+        assert!(dump.delta_cache.is_available());
+        let version = dump.version.available().unwrap();
+        assert_eq!(version.pending_compaction_files, 1);
+        assert_eq!(version.levels[0].files[0].file_id, 1);
+        drop(writer);
+        assert!(tsf.debug_dump().mut_cache.is_available());
+
+        let writer = tsf.version.write().await;
+        assert!(!tsf.debug_dump().version.is_available());
+        drop(writer);
+        assert!(tsf.debug_dump().version.is_available());
+    }
+
+    #[test]
+    fn test_version_duplicate_file_ids() {
+        let meta = |file_id, level, file_size| CompactMeta { file_id,
+                                                             level,
+                                                             file_size,
+                                                             range: TimeRange::new(10, 1),
+                                                             ..Default::default() };
+        let levels = || {
+            let mut lvl1 = LevelInfo::init(1);
+            lvl1.apply(&meta(1, 1, 10));
+            lvl1.apply(&meta(2, 1, 20));
+            lvl1.apply(&meta(2, 1, 30));
+            let mut lvl2 = LevelInfo::init(2);
+            lvl2.apply(&meta(1, 2, 40));
+            lvl2.apply(&meta(3, 2, 50));
+            vec![lvl2, lvl1]
+        };
+
+        let version = Version { levels_info: levels(), ..Default::default() };
+        assert!(matches!(version.check_file_ids(), Err(Error::DuplicateFileId { .. })));
+
+        let version = Version::new(0, 0, "db".to_string(), levels(), 0);
+        version.check_file_ids().unwrap();
+        let files = |i: usize| {
+            version.levels_info[i].files.iter().map(|f| (f.file_id(), f.size())).collect::<Vec<_>>()
+        };
+        // the lower level and the later applied file are kept
+        assert_eq!(files(0), vec![(3, 50)]);
+        assert_eq!(files(1), vec![(1, 10), (2, 30)]);
+        assert_eq!(version.levels_info[0].cur_size, 50);
+        assert_eq!(version.levels_info[1].cur_size, 40);
     }
 }
@@ -1,41 +1,57 @@
 // use std::fmt::Error;
 
+use std::borrow::Cow;
+
+use integer_encoding::VarInt;
 use models::ValueType;
 use protos::models::FieldType;
 
 use super::coders;
 use crate::{
     error::{Error, Result},
+    kv_option::DuplicatePolicy,
     memcache::{BoolCell, Byte, DataType, F64Cell, I64Cell, StrCell, U64Cell},
+    merge::{MergeStream, Timestamped},
 };
 
+// the largest delta packed by simple8b, larger deltas are written uncompressed
+const SIMPLE8B_MAX_VALUE: u64 = (1 << 60) - 1;
+
+/// The points of a field, a value for each timestamp. A block with null points has a validity,
+/// the value of a null point is only a placeholder; a block without one, as blocks of written
+/// points are, has a value at every point.
 #[derive(Debug, Clone, PartialEq)]
 pub enum DataBlock {
-    U64 { index: u32, ts: Vec<i64>, val: Vec<u64> },
-    I64 { index: u32, ts: Vec<i64>, val: Vec<i64> },
-    Str { index: u32, ts: Vec<i64>, val: Vec<Byte> },
-    F64 { index: u32, ts: Vec<i64>, val: Vec<f64> },
-    Bool { index: u32, ts: Vec<i64>, val: Vec<bool> },
+    U64 { index: u32, ts: Vec<i64>, val: Vec<u64>, validity: Option<Validity> },
+    I64 { index: u32, ts: Vec<i64>, val: Vec<i64>, validity: Option<Validity> },
+    Str { index: u32, ts: Vec<i64>, val: Vec<Byte>, validity: Option<Validity> },
+    F64 { index: u32, ts: Vec<i64>, val: Vec<f64>, validity: Option<Validity> },
+    Bool { index: u32, ts: Vec<i64>, val: Vec<bool>, validity: Option<Validity> },
 }
 
 impl DataBlock {
     pub fn new(size: usize, field_type: ValueType) -> Self {
         match field_type {
-            ValueType::Unsigned => {
-                Self::U64 { index: 0, ts: Vec::with_capacity(size), val: Vec::with_capacity(size) }
-            },
-            ValueType::Integer => {
-                Self::I64 { index: 0, ts: Vec::with_capacity(size), val: Vec::with_capacity(size) }
-            },
-            ValueType::Float => {
-                Self::F64 { index: 0, ts: Vec::with_capacity(size), val: Vec::with_capacity(size) }
-            },
-            ValueType::String => {
-                Self::Str { index: 0, ts: Vec::with_capacity(size), val: Vec::with_capacity(size) }
-            },
-            ValueType::Boolean => {
-                Self::Bool { index: 0, ts: Vec::with_capacity(size), val: Vec::with_capacity(size) }
-            },
+            ValueType::Unsigned => Self::U64 { index: 0,
+                                               ts: Vec::with_capacity(size),
+                                               val: Vec::with_capacity(size),
+                                               validity: None },
+            ValueType::Integer => Self::I64 { index: 0,
+                                              ts: Vec::with_capacity(size),
+                                              val: Vec::with_capacity(size),
+                                              validity: None },
+            ValueType::Float => Self::F64 { index: 0,
+                                            ts: Vec::with_capacity(size),
+                                            val: Vec::with_capacity(size),
+                                            validity: None },
+            ValueType::String => Self::Str { index: 0,
+                                             ts: Vec::with_capacity(size),
+                                             val: Vec::with_capacity(size),
+                                             validity: None },
+            ValueType::Boolean => Self::Bool { index: 0,
+                                               ts: Vec::with_capacity(size),
+                                               val: Vec::with_capacity(size),
+                                               validity: None },
             ValueType::Unknown => {
                 todo!()
             },
@@ -44,76 +60,188 @@ impl DataBlock {
     pub fn insert(&mut self, data: DataType) {
         match data {
             DataType::Bool(item) => {
-                if let Self::Bool { ts, val, index } = self {
+                if let Self::Bool { ts, val, index, .. } = self {
                     ts.push(item.ts);
                     val.push(item.val);
                 }
             },
             DataType::U64(item) => {
-                if let Self::U64 { ts, val, index } = self {
+                if let Self::U64 { ts, val, index, .. } = self {
                     ts.push(item.ts);
                     val.push(item.val);
                 }
             },
             DataType::I64(item) => {
-                if let Self::I64 { ts, val, index } = self {
+                if let Self::I64 { ts, val, index, .. } = self {
                     ts.push(item.ts);
                     val.push(item.val);
                 }
             },
             DataType::Str(item) => {
-                if let Self::Str { ts, val, index } = self {
+                if let Self::Str { ts, val, index, .. } = self {
                     ts.push(item.ts);
                     val.push(item.val);
                 }
             },
             DataType::F64(item) => {
-                if let Self::F64 { ts, val, index } = self {
+                if let Self::F64 { ts, val, index, .. } = self {
                     ts.push(item.ts);
                     val.push(item.val);
                 }
             },
         }
+        self.fill_validity();
     }
 
     pub fn time_range(&self, start: usize, end: usize) -> (i64, i64) {
+        let ts = self.ts();
+        (ts[start], ts[end - 1])
+    }
+
+    /// Returns the timestamp at the index, None if the index is out of range.
+    pub fn ts_at(&self, i: usize) -> Option<i64> {
+        self.ts().get(i).copied()
+    }
+
+    /// Returns the value at the index, None if the index is out of range, the point is null or
+    /// the block holds values of another type.
+    pub fn val_at<T: NumericValue>(&self, i: usize) -> Option<T> {
+        if self.is_null(i) {
+            return None;
+        }
+        T::values(self).and_then(|val| val.get(i).copied())
+    }
+
+    pub fn bool_at(&self, i: usize) -> Option<bool> {
+        match self {
+            DataBlock::Bool { val, .. } if !self.is_null(i) => val.get(i).copied(),
+            _ => None,
+        }
+    }
+
+    /// Returns the string at the index without cloning it.
+    pub fn str_at(&self, i: usize) -> Option<&[u8]> {
+        match self {
+            DataBlock::Str { val, .. } if !self.is_null(i) => val.get(i).map(|v| &v[..]),
+            _ => None,
+        }
+    }
+
+    /// Returns the validity of the points, None if every point has a value.
+    pub fn validity(&self) -> Option<&Validity> {
+        match self {
+            DataBlock::U64 { validity, .. } => validity.as_ref(),
+            DataBlock::I64 { validity, .. } => validity.as_ref(),
+            DataBlock::Str { validity, .. } => validity.as_ref(),
+            DataBlock::F64 { validity, .. } => validity.as_ref(),
+            DataBlock::Bool { validity, .. } => validity.as_ref(),
+        }
+    }
+
+    fn validity_mut(&mut self) -> &mut Option<Validity> {
+        match self {
+            DataBlock::U64 { validity, .. } => validity,
+            DataBlock::I64 { validity, .. } => validity,
+            DataBlock::Str { validity, .. } => validity,
+            DataBlock::F64 { validity, .. } => validity,
+            DataBlock::Bool { validity, .. } => validity,
+        }
+    }
+
+    /// Sets the validity of the points, a validity without null point is dropped. Panics if it
+    /// is not of the length of the block.
+    pub fn set_validity(&mut self, validity: Option<Validity>) {
+        if let Some(v) = validity.as_ref() {
+            assert_eq!(v.len(), self.len(), "validity of another length than the block");
+        }
+        *self.validity_mut() = validity.filter(|v| v.null_count() > 0);
+    }
+
+    /// Returns true if the point at the index is null.
+    pub fn is_null(&self, i: usize) -> bool {
+        self.validity().map_or(false, |v| i < v.len() && !v.is_valid(i))
+    }
+
+    pub fn null_count(&self) -> usize {
+        self.validity().map_or(0, |v| v.null_count())
+    }
+
+    /// Appends a null point at the timestamp, the default of the value type is its value.
+    pub fn push_null(&mut self, timestamp: i64) {
+        let len = self.len();
         match self {
-            DataBlock::U64 { ts, .. } => (ts[start].to_owned(), ts[end - 1].to_owned()),
-            DataBlock::I64 { ts, .. } => (ts[start].to_owned(), ts[end - 1].to_owned()),
-            DataBlock::Str { ts, .. } => (ts[start].to_owned(), ts[end - 1].to_owned()),
-            DataBlock::F64 { ts, .. } => (ts[start].to_owned(), ts[end - 1].to_owned()),
-            DataBlock::Bool { ts, .. } => (ts[start].to_owned(), ts[end - 1].to_owned()),
+            DataBlock::U64 { ts, val, .. } => {
+                ts.push(timestamp);
+                val.push(0);
+            },
+            DataBlock::I64 { ts, val, .. } => {
+                ts.push(timestamp);
+                val.push(0);
+            },
+            DataBlock::Str { ts, val, .. } => {
+                ts.push(timestamp);
+                val.push(vec![]);
+            },
+            DataBlock::F64 { ts, val, .. } => {
+                ts.push(timestamp);
+                val.push(0.0);
+            },
+            DataBlock::Bool { ts, val, .. } => {
+                ts.push(timestamp);
+                val.push(false);
+            },
+        }
+        self.validity_mut().get_or_insert_with(|| Validity::new_valid(len)).push(false);
+    }
+
+    // the points appended with a value after the validity are valid
+    fn fill_validity(&mut self) {
+        let len = self.len();
+        if let Some(v) = self.validity_mut() {
+            while v.len() < len {
+                v.push(true);
+            }
         }
     }
+
+    // replaces the validity by `f` of it, dropped if no point is null any more
+    fn map_validity(&mut self, f: impl FnOnce(&Validity) -> Validity) {
+        let validity = self.validity_mut();
+        if let Some(v) = validity.as_ref() {
+            let v = f(v);
+            *validity = if v.null_count() > 0 { Some(v) } else { None };
+        }
+    }
+
     pub fn batch_insert(&mut self, cells: &[DataType]) {
         for iter in cells.iter() {
             match iter {
                 DataType::U64(item) => {
-                    if let Self::U64 { ts, val, index } = self {
+                    if let Self::U64 { ts, val, index, .. } = self {
                         ts.push(item.ts);
                         val.push(item.val);
                     }
                 },
                 DataType::I64(item) => {
-                    if let Self::I64 { ts, val, index } = self {
+                    if let Self::I64 { ts, val, index, .. } = self {
                         ts.push(item.ts);
                         val.push(item.val);
                     }
                 },
                 DataType::Str(item) => {
-                    if let Self::Str { ts, val, index } = self {
+                    if let Self::Str { ts, val, index, .. } = self {
                         ts.push(item.ts);
                         val.push(item.val.clone());
                     }
                 },
                 DataType::F64(item) => {
-                    if let Self::F64 { ts, val, index } = self {
+                    if let Self::F64 { ts, val, index, .. } = self {
                         ts.push(item.ts);
                         val.push(item.val);
                     }
                 },
                 DataType::Bool(item) => {
-                    if let Self::Bool { ts, val, index } = self {
+                    if let Self::Bool { ts, val, index, .. } = self {
                         ts.push(item.ts);
                         val.push(item.val);
                     }
@@ -121,6 +249,7 @@ impl DataBlock {
                 _ => todo!(),
             }
         }
+        self.fill_validity();
     }
 
     pub fn len(&self) -> usize {
@@ -143,153 +272,1119 @@ impl DataBlock {
     }
     pub fn get_type(&self) -> DataType {
         match &self {
-            DataBlock::U64 { index, ts, val } => DataType::U64(U64Cell::default()),
-            DataBlock::I64 { index, ts, val } => DataType::I64(I64Cell::default()),
-            DataBlock::Str { index, ts, val } => DataType::Str(StrCell::default()),
-            DataBlock::F64 { index, ts, val } => DataType::F64(F64Cell::default()),
-            DataBlock::Bool { index, ts, val } => DataType::Bool(BoolCell::default()),
+            DataBlock::U64 { .. } => DataType::U64(U64Cell::default()),
+            DataBlock::I64 { .. } => DataType::I64(I64Cell::default()),
+            DataBlock::Str { .. } => DataType::Str(StrCell::default()),
+            DataBlock::F64 { .. } => DataType::F64(F64Cell::default()),
+            DataBlock::Bool { .. } => DataType::Bool(BoolCell::default()),
+        }
+    }
+    pub fn ts(&self) -> &[i64] {
+        match self {
+            DataBlock::U64 { ts, .. } => ts,
+            DataBlock::I64 { ts, .. } => ts,
+            DataBlock::Str { ts, .. } => ts,
+            DataBlock::F64 { ts, .. } => ts,
+            DataBlock::Bool { ts, .. } => ts,
+        }
+    }
+    /// Returns true if any timestamp appears more than once, the timestamps must be sorted.
+    pub fn has_duplicates(&self) -> bool {
+        self.ts().windows(2).any(|w| w[0] == w[1])
+    }
+    /// Keeps the points whose flag in `keep` is true.
+    pub fn retain(&mut self, keep: &[bool]) {
+        fn retain_vec<T>(v: &mut Vec<T>, keep: &[bool]) {
+            let mut i = 0;
+            v.retain(|_| {
+                 i += 1;
+                 keep[i - 1]
+             });
+        }
+        match self {
+            DataBlock::U64 { ts, val, .. } => {
+                retain_vec(ts, keep);
+                retain_vec(val, keep);
+            },
+            DataBlock::I64 { ts, val, .. } => {
+                retain_vec(ts, keep);
+                retain_vec(val, keep);
+            },
+            DataBlock::Str { ts, val, .. } => {
+                retain_vec(ts, keep);
+                retain_vec(val, keep);
+            },
+            DataBlock::F64 { ts, val, .. } => {
+                retain_vec(ts, keep);
+                retain_vec(val, keep);
+            },
+            DataBlock::Bool { ts, val, .. } => {
+                retain_vec(ts, keep);
+                retain_vec(val, keep);
+            },
         }
+        self.map_validity(|v| (0..v.len()).filter(|i| keep[*i]).map(|i| v.is_valid(i)).collect());
     }
+
+    /// Returns true if the timestamps never decrease.
+    pub fn is_sorted(&self) -> bool {
+        self.ts().windows(2).all(|w| w[0] <= w[1])
+    }
+    /// Sorts the points by timestamp, the points of the same timestamp keep their order, and
+    /// only the last of them is kept with `LastWins`.
+    pub fn sort(&mut self, duplicate_policy: DuplicatePolicy) {
+        fn permute<T: Clone>(v: &mut Vec<T>, order: &[usize]) {
+            *v = order.iter().map(|i| v[*i].clone()).collect();
+        }
+        let order = {
+            let ts = self.ts();
+            let mut order: Vec<usize> = (0..ts.len()).collect();
+            order.sort_by_key(|i| ts[*i]);
+            if duplicate_policy == DuplicatePolicy::LastWins {
+                order.dedup_by(|later, earlier| {
+                         let same = ts[*later] == ts[*earlier];
+                         if same {
+                             *earlier = *later;
+                         }
+                         same
+                     });
+            }
+            order
+        };
+        match self {
+            DataBlock::U64 { ts, val, .. } => {
+                permute(ts, &order);
+                permute(val, &order);
+            },
+            DataBlock::I64 { ts, val, .. } => {
+                permute(ts, &order);
+                permute(val, &order);
+            },
+            DataBlock::Str { ts, val, .. } => {
+                permute(ts, &order);
+                permute(val, &order);
+            },
+            DataBlock::F64 { ts, val, .. } => {
+                permute(ts, &order);
+                permute(val, &order);
+            },
+            DataBlock::Bool { ts, val, .. } => {
+                permute(ts, &order);
+                permute(val, &order);
+            },
+        }
+        self.map_validity(|v| order.iter().map(|i| v.is_valid(*i)).collect());
+    }
+
+    /// Splits the sorted block into blocks each within one time window, the windows are
+    /// `window_ns` long and aligned to the epoch, so that the blocks of every field end at the
+    /// same boundaries. Returns the block as it is if `window_ns` is not positive, and no block
+    /// if it holds no point.
+    pub fn partition_by_time(&self, window_ns: i64) -> Vec<DataBlock> {
+        if window_ns <= 0 {
+            return vec![self.clone()];
+        }
+        let ts = self.ts();
+        let mut ranges = vec![];
+        let mut start = 0;
+        while start < ts.len() {
+            let window = ts[start].div_euclid(window_ns);
+            let end = start + ts[start..].partition_point(|t| t.div_euclid(window_ns) == window);
+            ranges.push((start, end));
+            start = end;
+        }
+        ranges.into_iter().map(|(start, end)| self.slice(start, end)).collect()
+    }
+
+    /// Returns a copy of the points in [start, end), unread.
+    pub fn slice(&self, start: usize, end: usize) -> DataBlock {
+        let validity = self.validity().map(|v| v.slice(start, end)).filter(|v| v.null_count() > 0);
+        match self {
+            DataBlock::U64 { ts, val, .. } => DataBlock::U64 { index: 0,
+                                                               ts: ts[start..end].to_vec(),
+                                                               val: val[start..end].to_vec(),
+                                                               validity },
+            DataBlock::I64 { ts, val, .. } => DataBlock::I64 { index: 0,
+                                                               ts: ts[start..end].to_vec(),
+                                                               val: val[start..end].to_vec(),
+                                                               validity },
+            DataBlock::Str { ts, val, .. } => DataBlock::Str { index: 0,
+                                                               ts: ts[start..end].to_vec(),
+                                                               val: val[start..end].to_vec(),
+                                                               validity },
+            DataBlock::F64 { ts, val, .. } => DataBlock::F64 { index: 0,
+                                                               ts: ts[start..end].to_vec(),
+                                                               val: val[start..end].to_vec(),
+                                                               validity },
+            DataBlock::Bool { ts, val, .. } => DataBlock::Bool { index: 0,
+                                                                 ts: ts[start..end].to_vec(),
+                                                                 val: val[start..end].to_vec(),
+                                                                 validity },
+        }
+    }
+
+    /// Returns a copy of the block with `f` applied to every value, the timestamps are left
+    /// untouched. Only a block whose values are of type `T` is mapped, other blocks (strings,
+    /// booleans or another numeric type) are returned as unchanged clones.
+    pub fn map_values<T, F>(&self, f: F) -> DataBlock
+        where T: NumericValue,
+              F: Fn(T) -> T
+    {
+        let mut block = self.clone();
+        if let Some(val) = T::values_mut(&mut block) {
+            for v in val.iter_mut() {
+                *v = f(*v);
+            }
+        }
+        block
+    }
+
+    /// Converts the values to the value type, only integers and unsigned integers can be
+    /// promoted to floats. A u64 or i64 beyond 2^53 in magnitude is rounded to the nearest
+    /// float, so the promotion is lossy for such values.
+    pub fn coerce(self, to: ValueType) -> Result<DataBlock> {
+        match (self, to) {
+            (block, to) if block.field_type() == to => Ok(block),
+            (DataBlock::I64 { index, ts, val, validity }, ValueType::Float) => {
+                let val = val.into_iter().map(|v| v as f64).collect();
+                Ok(DataBlock::F64 { index, ts, val, validity })
+            },
+            (DataBlock::U64 { index, ts, val, validity }, ValueType::Float) => {
+                let val = val.into_iter().map(|v| v as f64).collect();
+                Ok(DataBlock::F64 { index, ts, val, validity })
+            },
+            (block, to) => Err(Error::IncompatibleCoercion { from: block.field_type(), to }),
+        }
+    }
+
+    /// Returns true if the blocks are equal, the floats are compared by their bit patterns, so
+    /// that a NaN equals itself and -0.0 differs from 0.0. Encoders keep the bit patterns, a
+    /// block must be `eq_bits` to itself after a round trip through a tsm file.
+    pub fn eq_bits(&self, other: &DataBlock) -> bool {
+        match (self, other) {
+            (DataBlock::F64 { index, ts, val, validity },
+             DataBlock::F64 { index: other_index,
+                              ts: other_ts,
+                              val: other_val,
+                              validity: other_validity, }) => {
+                index == other_index
+                && ts == other_ts
+                && validity == other_validity
+                && val.len() == other_val.len()
+                && val.iter().zip(other_val).all(|(a, b)| a.to_bits() == b.to_bits())
+            },
+            _ => self == other,
+        }
+    }
+
     pub fn is_empty(&self) -> bool {
         match &self {
-            DataBlock::U64 { index, ts, val } => *index == ts.len() as u32,
-            DataBlock::I64 { index, ts, val } => *index == ts.len() as u32,
-            DataBlock::Str { index, ts, val } => *index == ts.len() as u32,
-            DataBlock::F64 { index, ts, val } => *index == ts.len() as u32,
-            DataBlock::Bool { index, ts, val } => *index == ts.len() as u32,
+            DataBlock::U64 { index, ts, .. } => *index == ts.len() as u32,
+            DataBlock::I64 { index, ts, .. } => *index == ts.len() as u32,
+            DataBlock::Str { index, ts, .. } => *index == ts.len() as u32,
+            DataBlock::F64 { index, ts, .. } => *index == ts.len() as u32,
+            DataBlock::Bool { index, ts, .. } => *index == ts.len() as u32,
         }
     }
+    /// Returns the next point with a value, the null points are skipped.
     pub fn next(&mut self) -> Option<DataType> {
+        self.skip_nulls();
+        self.take_next()
+    }
+
+    // returns the next point, null or not, a null point holds the default of the value type
+    fn next_point(&mut self) -> Option<BlockPoint> {
+        let i = match self {
+            DataBlock::U64 { index, .. }
+            | DataBlock::I64 { index, .. }
+            | DataBlock::Str { index, .. }
+            | DataBlock::F64 { index, .. }
+            | DataBlock::Bool { index, .. } => *index as usize,
+        };
+        let null = self.is_null(i);
+        self.take_next().map(|data| BlockPoint { data, null })
+    }
+
+    fn take_next(&mut self) -> Option<DataType> {
         if self.is_empty() {
             return None;
         }
         match self {
-            DataBlock::U64 { index, ts, val } => {
+            DataBlock::U64 { index, ts, val, .. } => {
                 let i = *index as usize;
                 *index += 1;
                 Some(DataType::U64(U64Cell { ts: ts[i], val: val[i] }))
             },
-            DataBlock::I64 { index, ts, val } => {
+            DataBlock::I64 { index, ts, val, .. } => {
                 let i = *index as usize;
                 *index += 1;
                 Some(DataType::I64(I64Cell { ts: ts[i], val: val[i] }))
             },
-            DataBlock::Str { index, ts, val } => {
+            DataBlock::Str { index, ts, val, .. } => {
                 let i = *index as usize;
                 *index += 1;
                 Some(DataType::Str(StrCell { ts: ts[i], val: val[i].clone() }))
             },
-            DataBlock::F64 { index, ts, val } => {
+            DataBlock::F64 { index, ts, val, .. } => {
                 let i = *index as usize;
                 *index += 1;
                 Some(DataType::F64(F64Cell { ts: ts[i], val: val[i] }))
             },
-            DataBlock::Bool { index, ts, val } => {
+            DataBlock::Bool { index, ts, val, .. } => {
                 let i = *index as usize;
                 *index += 1;
                 Some(DataType::Bool(BoolCell { ts: ts[i], val: val[i] }))
             },
         }
     }
-    // last write win
-    pub fn merge_blocks(mut blocks: Vec<Self>) -> Self {
-        if blocks.len() == 1 {
-            return blocks.remove(0);
-        }
-
-        let mut res =
-            Self::new(blocks.first().unwrap().len(), blocks.first().unwrap().field_type());
-        let mut buf = vec![None; blocks.len()];
-        loop {
-            match Self::rebuild_vec(&mut blocks, &mut buf) {
-                Some(min) => {
-                    let mut data = None;
-                    for item in &mut buf {
-                        if let Some(it) = item {
-                            if it.timestamp() == min {
-                                data = item.take();
-                            }
-                        }
-                    }
-                    if let Some(it) = data {
-                        res.insert(it);
-                    }
-                },
-                None => return res,
-            }
+    // moves the index past the null points
+    fn skip_nulls(&mut self) {
+        match self {
+            DataBlock::U64 { index, validity: Some(v), .. }
+            | DataBlock::I64 { index, validity: Some(v), .. }
+            | DataBlock::Str { index, validity: Some(v), .. }
+            | DataBlock::F64 { index, validity: Some(v), .. }
+            | DataBlock::Bool { index, validity: Some(v), .. } => {
+                while (*index as usize) < v.len() && !v.is_valid(*index as usize) {
+                    *index += 1;
+                }
+            },
+            _ => {},
         }
     }
-    fn rebuild_vec(blocks: &mut [Self], dst: &mut Vec<Option<DataType>>) -> Option<i64> {
-        let mut min_ts = None;
-        for (block, dst) in blocks.iter_mut().zip(dst) {
-            if dst.is_none() {
-                *dst = block.next();
-            }
 
-            if let Some(pair) = dst {
-                match min_ts {
-                    Some(min) => {
-                        if pair.timestamp() < min {
-                            min_ts = Some(pair.timestamp());
-                        }
-                    },
-                    None => min_ts = Some(pair.timestamp()),
-                }
-            };
+    // last write win
+    pub fn merge_blocks(blocks: Vec<Self>, field_type: ValueType) -> Self {
+        Self::merge_blocks_with(blocks, field_type, DuplicatePolicy::LastWins)
+    }
+
+    /// Merges blocks of the field type, the later block holds the newer writes. The blocks with
+    /// no point left are skipped, an empty block of the field type is returned if all are. A
+    /// null point is merged like a value, a newer null point hides the older values of its
+    /// timestamp.
+    pub fn merge_blocks_with(blocks: Vec<Self>,
+                             field_type: ValueType,
+                             duplicate_policy: DuplicatePolicy)
+                             -> Self {
+        // a single chunk holds all the points
+        let mut chunks = Self::merge_blocks_chunked_with(blocks, usize::MAX, duplicate_policy);
+        chunks.pop().unwrap_or_else(|| Self::new(0, field_type))
+    }
+
+    /// Merges blocks like `merge_blocks_with`, for a field whose type changed over time: every
+    /// block is coerced to the field type first, see `coerce`. Only integers and unsigned
+    /// integers are promoted, and only to floats, so older i64 or u64 blocks merge with newer
+    /// f64 blocks into a f64 block; the values beyond 2^53 in magnitude are rounded. Any other
+    /// mix of types fails with `IncompatibleCoercion` before anything is merged.
+    pub fn merge_blocks_coerced(blocks: Vec<Self>,
+                                field_type: ValueType,
+                                duplicate_policy: DuplicatePolicy)
+                                -> Result<Self> {
+        let blocks = blocks.into_iter().map(|b| b.coerce(field_type)).collect::<Result<Vec<_>>>()?;
+        Ok(Self::merge_blocks_with(blocks, field_type, duplicate_policy))
+    }
+
+    // last write win
+    pub fn merge_blocks_chunked(blocks: Vec<Self>, max_points: usize) -> Vec<Self> {
+        Self::merge_blocks_chunked_with(blocks, max_points, DuplicatePolicy::LastWins)
+    }
+
+    /// Merges blocks like `merge_blocks_with` into blocks of at most `max_points` points each,
+    /// emitted in timestamp order while merging. The duplicates of a timestamp are resolved
+    /// before a point reaches a block, so the winner never depends on where a block ends.
+    pub fn merge_blocks_chunked_with(blocks: Vec<Self>,
+                                     max_points: usize,
+                                     duplicate_policy: DuplicatePolicy)
+                                     -> Vec<Self> {
+        let mut blocks: Vec<Self> = blocks.into_iter().filter(|b| !b.is_empty()).collect();
+        match blocks.len() {
+            0 => return vec![],
+            1 if blocks[0].len() <= max_points => return blocks,
+            _ => {},
+        }
+
+        let field_type = blocks[0].field_type();
+        let capacity = blocks[0].len().min(max_points);
+        let mut res = vec![];
+        let mut chunk = Self::new(capacity, field_type);
+        let sources = blocks.into_iter()
+                            .map(|mut blk| std::iter::from_fn(move || blk.next_point()))
+                            .collect();
+        for point in MergeStream::with_policy(sources, duplicate_policy) {
+            if chunk.len() == max_points {
+                res.push(std::mem::replace(&mut chunk, Self::new(capacity, field_type)));
+            }
+            if point.null {
+                chunk.push_null(point.data.timestamp());
+            } else {
+                chunk.insert(point.data);
+            }
         }
-        min_ts
+        res.push(chunk);
+        res
     }
-    // todo:
+
+    /// Encodes the points in [start, end), only the values of the points not null are encoded,
+    /// behind the validity bits of the points if some are null.
     pub fn encode(&self, start: usize, end: usize) -> Result<(Vec<u8>, Vec<u8>)> {
         let mut ts_buf = vec![];
         let mut data_buf = vec![];
+        let validity = self.validity().map(|v| v.slice(start, end)).filter(|v| v.null_count() > 0);
+        let validity = validity.as_ref();
         match self {
             DataBlock::Bool { ts, val, .. } => {
                 coders::timestamp::encode(&ts[start..end], &mut ts_buf)
                     .map_err(|e| Error::WriteTsmErr { reason: e.to_string() })?;
-                coders::boolean::encode(&val[start..end], &mut data_buf)
+                coders::boolean::encode(&present(&val[start..end], validity), &mut data_buf)
                     .map_err(|e| Error::WriteTsmErr { reason: e.to_string() })?;
             },
             DataBlock::U64 { ts, val, .. } => {
                 coders::timestamp::encode(&ts[start..end], &mut ts_buf)
                     .map_err(|e| Error::WriteTsmErr { reason: e.to_string() })?;
-                coders::unsigned::encode(&val[start..end], &mut data_buf)
+                coders::unsigned::encode(&present(&val[start..end], validity), &mut data_buf)
                     .map_err(|e| Error::WriteTsmErr { reason: e.to_string() })?;
             },
             DataBlock::I64 { ts, val, .. } => {
                 coders::timestamp::encode(&ts[start..end], &mut ts_buf)
                     .map_err(|e| Error::WriteTsmErr { reason: e.to_string() })?;
-                coders::integer::encode(&val[start..end], &mut data_buf)
+                coders::integer::encode(&present(&val[start..end], validity), &mut data_buf)
                     .map_err(|e| Error::WriteTsmErr { reason: e.to_string() })?;
             },
             DataBlock::Str { ts, val, .. } => {
                 coders::timestamp::encode(&ts[start..end], &mut ts_buf)
                     .map_err(|e| Error::WriteTsmErr { reason: e.to_string() })?;
                 let strs: Vec<&[u8]> = val.iter().map(|str| &str[..]).collect();
-                coders::string::encode(&strs[start..end], &mut data_buf)
+                coders::string::encode(&present(&strs[start..end], validity), &mut data_buf)
                     .map_err(|e| Error::WriteTsmErr { reason: e.to_string() })?;
             },
             DataBlock::F64 { ts, val, .. } => {
                 coders::timestamp::encode(&ts[start..end], &mut ts_buf)
                     .map_err(|e| Error::WriteTsmErr { reason: e.to_string() })?;
-                coders::float::encode(&val[start..end], &mut data_buf)
+                coders::float::encode(&present(&val[start..end], validity), &mut data_buf)
                     .map_err(|e| Error::WriteTsmErr { reason: e.to_string() })?;
             },
         }
+        if let Some(v) = validity {
+            let values = std::mem::take(&mut data_buf);
+            coders::validity::encode(v.bits(), v.len(), &values, &mut data_buf);
+        }
         Ok((ts_buf, data_buf))
     }
     pub fn decode() {}
+
+    /// Returns about how many bytes the points in [start, end) take once encoded into a tsm
+    /// block, with its checksums, without encoding them. The timestamps and the integers are
+    /// sized by the widths of their deltas and the floats by their XORs, like their coders do;
+    /// the strings are sized as if not compressed, so a block of strings is seldom estimated
+    /// under its encoded size.
+    pub fn estimate_encoded_size(&self, start: usize, end: usize) -> usize {
+        if start >= end {
+            return 0;
+        }
+        let ts = &self.ts()[start..end];
+        let ts_deltas: Vec<u64> = ts.windows(2).map(|w| w[1].wrapping_sub(w[0]) as u64).collect();
+        let ts_size = estimate_packed_size(&ts_deltas, 1, true);
+        let val_size = match self {
+            DataBlock::U64 { val, .. } => {
+                let val: Vec<i64> = val[start..end].iter().map(|v| *v as i64).collect();
+                estimate_integers_size(&val)
+            },
+            DataBlock::I64 { val, .. } => estimate_integers_size(&val[start..end]),
+            DataBlock::F64 { val, .. } => estimate_floats_size(&val[start..end]),
+            DataBlock::Bool { .. } => 1 + (end - start).required_space() + (end - start + 7) / 8,
+            DataBlock::Str { val, .. } => {
+                1 + val[start..end].iter()
+                                   .map(|s| s.len().required_space() + s.len())
+                                   .sum::<usize>()
+            },
+        };
+        // the validity bits before the values of a block with nulls
+        let validity_size = match self.validity() {
+            Some(_) => 1 + (end - start).required_space() + (end - start + 7) / 8,
+            None => 0,
+        };
+        // a crc32 before the timestamps and one before the values
+        8 + ts_size + val_size + validity_size
+    }
+}
+
+// the values of the points not null, all of them without a validity
+fn present<'a, T: Clone>(val: &'a [T], validity: Option<&Validity>) -> Cow<'a, [T]> {
+    match validity {
+        Some(v) => Cow::Owned(val.iter()
+                                 .enumerate()
+                                 .filter(|(i, _)| v.is_valid(*i))
+                                 .map(|(_, x)| x.clone())
+                                 .collect()),
+        None => Cow::Borrowed(val),
+    }
+}
+
+// the size of the deltas after the first value as the timestamp and integer coders write them:
+// run length encoded if at least `min_run` are all the same, else packed by simple8b at the
+// width of the largest one, scaled down by the largest power of 10 dividing them all if
+// `scaled`, or uncompressed if one is too large for simple8b
+fn estimate_packed_size(deltas: &[u64], min_run: usize, scaled: bool) -> usize {
+    let mut div: u64 = 1;
+    if scaled {
+        div = 1_000_000_000_000;
+        for delta in deltas.iter() {
+            while div > 1 && delta % div != 0 {
+                div /= 10;
+            }
+        }
+    }
+    if deltas.len() >= min_run && deltas.windows(2).all(|w| w[0] == w[1]) {
+        if let Some(delta) = deltas.first() {
+            return 1 + 8 + (delta / div).required_space() + (deltas.len() + 1).required_space();
+        }
+    }
+    let max = deltas.iter().max().copied().unwrap_or(0);
+    if max > SIMPLE8B_MAX_VALUE {
+        return 1 + 8 * (deltas.len() + 1);
+    }
+    let per_word = match 64 - (max / div).leading_zeros() {
+        0 | 1 => 60,
+        2 => 30,
+        3 => 20,
+        4 => 15,
+        5 => 12,
+        6 => 10,
+        7 => 8,
+        8 => 7,
+        9 | 10 => 6,
+        11 | 12 => 5,
+        13..=15 => 4,
+        16..=20 => 3,
+        21..=30 => 2,
+        _ => 1,
+    };
+    1 + 8 + (deltas.len() + per_word - 1) / per_word * 8
+}
+
+// the zig-zag encoded deltas of the integers are packed
+fn estimate_integers_size(val: &[i64]) -> usize {
+    let deltas: Vec<u64> = val.windows(2)
+                              .map(|w| {
+                                  let delta = w[1].wrapping_sub(w[0]);
+                                  ((delta << 1) ^ (delta >> 63)) as u64
+                              })
+                              .collect();
+    estimate_packed_size(&deltas, 2, false)
+}
+
+// counts the bits the Gorilla coder writes for the XOR of every value with the previous one
+fn estimate_floats_size(val: &[f64]) -> usize {
+    // a block holding the sentinel of the coder is written uncompressed
+    const SENTINEL: u64 = 0x7ff8_0000_0000_00ff;
+    if val.iter().any(|v| v.to_bits() == SENTINEL) {
+        return 1 + 8 * val.len();
+    }
+    let mut bits = 8 + 64;
+    let mut prev = val[0].to_bits();
+    let (mut prev_leading, mut prev_trailing) = (None, 0);
+    for cur in val[1..].iter().map(|v| v.to_bits()).chain(std::iter::once(SENTINEL)) {
+        let xor = cur ^ prev;
+        prev = cur;
+        if xor == 0 {
+            bits += 1;
+            continue;
+        }
+        let leading = (xor.leading_zeros() & 0b0001_1111) as usize;
+        let trailing = xor.trailing_zeros() as usize;
+        match prev_leading {
+            Some(prev_leading) if leading >= prev_leading && trailing >= prev_trailing => {
+                bits += 2 + 64 - prev_leading - prev_trailing;
+            },
+            _ => {
+                prev_leading = Some(leading);
+                prev_trailing = trailing;
+                bits += 2 + 5 + 6 + 64 - leading - trailing;
+            },
+        }
+    }
+    (bits + 7) / 8
+}
+
+// a point of a block merged with the null points kept
+struct BlockPoint {
+    data: DataType,
+    null: bool,
+}
+
+impl Timestamped for BlockPoint {
+    fn timestamp(&self) -> i64 {
+        self.data.timestamp()
+    }
+}
+
+/// Which points of a block have a value, a bit for each point in the layout of the null
+/// buffers of Arrow: the lowest bit of the first byte is the first point, set if the point has
+/// a value and cleared if it is null. The bits past the last point are always cleared.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Validity {
+    bits: Vec<u8>,
+    len: usize,
+}
+
+impl Validity {
+    /// Returns the validity of `len` points all with a value.
+    pub fn new_valid(len: usize) -> Self {
+        std::iter::repeat(true).take(len).collect()
+    }
+
+    /// Returns the validity of `len` points from their bits, panics if there are fewer bits.
+    pub fn from_bits(bits: &[u8], len: usize) -> Self {
+        let mut bits = bits[..(len + 7) / 8].to_vec();
+        if len % 8 != 0 {
+            if let Some(last) = bits.last_mut() {
+                *last &= (1 << (len % 8)) - 1;
+            }
+        }
+        Self { bits, len }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn bits(&self) -> &[u8] {
+        &self.bits
+    }
+
+    /// Returns true if the point at the index has a value, panics if it is out of range.
+    pub fn is_valid(&self, i: usize) -> bool {
+        assert!(i < self.len, "validity index {} out of range {}", i, self.len);
+        self.bits[i / 8] & (1 << (i % 8)) != 0
+    }
+
+    pub fn null_count(&self) -> usize {
+        self.len - self.bits.iter().map(|b| b.count_ones() as usize).sum::<usize>()
+    }
+
+    pub fn push(&mut self, valid: bool) {
+        if self.len % 8 == 0 {
+            self.bits.push(0);
+        }
+        if valid {
+            self.bits[self.len / 8] |= 1 << (self.len % 8);
+        }
+        self.len += 1;
+    }
+
+    /// Returns the validity of the points in [start, end).
+    pub fn slice(&self, start: usize, end: usize) -> Self {
+        (start..end).map(|i| self.is_valid(i)).collect()
+    }
+}
+
+impl FromIterator<bool> for Validity {
+    fn from_iter<I: IntoIterator<Item = bool>>(iter: I) -> Self {
+        let mut validity = Self::default();
+        for valid in iter {
+            validity.push(valid);
+        }
+        validity
+    }
+}
+
+/// The value type of a numeric block.
+pub trait NumericValue: Copy {
+    /// Returns the values of the block if they are of this type.
+    fn values(block: &DataBlock) -> Option<&[Self]>;
+
+    /// Returns the values of the block if they are of this type.
+    fn values_mut(block: &mut DataBlock) -> Option<&mut Vec<Self>>;
+}
+
+impl NumericValue for u64 {
+    fn values(block: &DataBlock) -> Option<&[Self]> {
+        match block {
+            DataBlock::U64 { val, .. } => Some(val),
+            _ => None,
+        }
+    }
+
+    fn values_mut(block: &mut DataBlock) -> Option<&mut Vec<Self>> {
+        match block {
+            DataBlock::U64 { val, .. } => Some(val),
+            _ => None,
+        }
+    }
+}
+
+impl NumericValue for i64 {
+    fn values(block: &DataBlock) -> Option<&[Self]> {
+        match block {
+            DataBlock::I64 { val, .. } => Some(val),
+            _ => None,
+        }
+    }
+
+    fn values_mut(block: &mut DataBlock) -> Option<&mut Vec<Self>> {
+        match block {
+            DataBlock::I64 { val, .. } => Some(val),
+            _ => None,
+        }
+    }
+}
+
+impl NumericValue for f64 {
+    fn values(block: &DataBlock) -> Option<&[Self]> {
+        match block {
+            DataBlock::F64 { val, .. } => Some(val),
+            _ => None,
+        }
+    }
+
+    fn values_mut(block: &mut DataBlock) -> Option<&mut Vec<Self>> {
+        match block {
+            DataBlock::F64 { val, .. } => Some(val),
+            _ => None,
+        }
+    }
 }
 
 #[test]
 fn merge_blocks() {
     let res = DataBlock::merge_blocks(vec![DataBlock::U64 { index: 0,
                                                             ts: vec![1, 2, 3, 4, 5],
-                                                            val: vec![10, 20, 30, 40, 50] },
+                                                            val: vec![10, 20, 30, 40, 50],
+                                                            validity: None },
                                            DataBlock::U64 { index: 0,
                                                             ts: vec![2, 3, 4],
-                                                            val: vec![12, 13, 15] },]);
+                                                            val: vec![12, 13, 15],
+                                                            validity: None },],
+                                      ValueType::Unsigned);
 
     assert_eq!(res,
-               DataBlock::U64 { index: 0, ts: vec![1, 2, 3, 4, 5], val: vec![10, 12, 13, 15, 50] },);
+               DataBlock::U64 { index: 0,
+                                ts: vec![1, 2, 3, 4, 5],
+                                val: vec![10, 12, 13, 15, 50],
+                                validity: None },);
+}
+
+#[test]
+fn merge_empty_blocks() {
+    let empty = || DataBlock::new(0, ValueType::Integer);
+    let res = DataBlock::merge_blocks(vec![], ValueType::Float);
+    assert_eq!(res, DataBlock::F64 { index: 0, ts: vec![], val: vec![], validity: None });
+
+    let res = DataBlock::merge_blocks(vec![empty(), empty()], ValueType::Integer);
+    assert_eq!(res, DataBlock::I64 { index: 0, ts: vec![], val: vec![], validity: None });
+
+    let res = DataBlock::merge_blocks(vec![empty(),
+                                           DataBlock::I64 { index: 0,
+                                                            ts: vec![1, 3],
+                                                            val: vec![10, 30],
+                                                            validity: None },
+                                           empty(),
+                                           DataBlock::I64 { index: 0,
+                                                            ts: vec![2, 3],
+                                                            val: vec![20, 31],
+                                                            validity: None },
+                                           empty()],
+                                      ValueType::Integer);
+    assert_eq!(res,
+               DataBlock::I64 { index: 0,
+                                ts: vec![1, 2, 3],
+                                val: vec![10, 20, 31],
+                                validity: None });
+
+    // a block with all the points read is skipped too
+    let mut read = DataBlock::I64 { index: 0, ts: vec![5], val: vec![50], validity: None };
+    while read.next().is_some() {}
+    let res = DataBlock::merge_blocks(vec![read, empty()], ValueType::Integer);
+    assert_eq!(res, DataBlock::I64 { index: 0, ts: vec![], val: vec![], validity: None });
+}
+
+#[test]
+fn merge_blocks_coerced() {
+    // the field was written as integers and unsigned integers, then as floats
+    let blocks =
+        vec![DataBlock::I64 { index: 0, ts: vec![1, 2, 3], val: vec![10, 20, 30], validity: None },
+             DataBlock::U64 { index: 0, ts: vec![3, 5], val: vec![31, 50], validity: None },
+             DataBlock::F64 { index: 0, ts: vec![2, 4], val: vec![2.5, 4.5], validity: None }];
+    let res = DataBlock::merge_blocks_coerced(blocks.clone(),
+                                              ValueType::Float,
+                                              DuplicatePolicy::LastWins).unwrap();
+    assert_eq!(res,
+               DataBlock::F64 { index: 0,
+                                ts: vec![1, 2, 3, 4, 5],
+                                val: vec![10.0, 2.5, 31.0, 4.5, 50.0],
+                                validity: None });
+
+    // nothing is converted to integers
+    assert!(matches!(DataBlock::merge_blocks_coerced(blocks,
+                                                     ValueType::Integer,
+                                                     DuplicatePolicy::LastWins),
+                     Err(Error::IncompatibleCoercion { from: ValueType::Unsigned,
+                                                       to: ValueType::Integer })));
+    let blocks =
+        vec![DataBlock::Str { index: 0, ts: vec![1], val: vec![b"1".to_vec()], validity: None },
+             DataBlock::F64 { index: 0, ts: vec![2], val: vec![2.0], validity: None }];
+    assert!(DataBlock::merge_blocks_coerced(blocks, ValueType::Float, DuplicatePolicy::LastWins)
+            .is_err());
+}
+
+#[test]
+fn merge_blocks_chunked() {
+    let blocks =
+        vec![DataBlock::I64 { index: 0,
+                              ts: (0..1000).collect(),
+                              val: vec![1; 1000],
+                              validity: None },
+             DataBlock::I64 { index: 0,
+                              ts: (500..1500).collect(),
+                              val: vec![2; 1000],
+                              validity: None },
+             DataBlock::I64 { index: 0, ts: vec![999, 1000], val: vec![3, 3], validity: None }];
+    let res = DataBlock::merge_blocks_chunked(blocks.clone(), 1000);
+    assert_eq!(res.iter().map(|b| b.len()).collect::<Vec<_>>(), vec![1000, 500]);
+    assert_eq!(res[0].ts(), (0..1000).collect::<Vec<_>>());
+    assert_eq!(res[1].ts(), (1000..1500).collect::<Vec<_>>());
+    // the newest point of the timestamps around the chunk boundary wins
+    let val = |block: &DataBlock, i: usize| match block {
+        DataBlock::I64 { val, .. } => val[i],
+        _ => panic!("unexpected data type"),
+    };
+    assert_eq!((val(&res[0], 499), val(&res[0], 500), val(&res[0], 999)), (1, 2, 3));
+    assert_eq!((val(&res[1], 0), val(&res[1], 1)), (3, 2));
+
+    let merged = DataBlock::merge_blocks(blocks, ValueType::Integer);
+    assert_eq!(merged.ts(), (0..1500).collect::<Vec<_>>());
+    assert_eq!(DataBlock::merge_blocks_chunked(vec![], 1000), vec![]);
+
+    // a duplicate kept across the boundary stays in timestamp order
+    let blocks =
+        vec![DataBlock::U64 { index: 0, ts: vec![1, 2], val: vec![10, 20], validity: None },
+             DataBlock::U64 { index: 0, ts: vec![2, 3], val: vec![21, 30], validity: None }];
+    let res = DataBlock::merge_blocks_chunked_with(blocks, 2, DuplicatePolicy::KeepAll);
+    assert_eq!(res,
+               vec![DataBlock::U64 { index: 0,
+                                     ts: vec![1, 2],
+                                     val: vec![10, 20],
+                                     validity: None },
+                    DataBlock::U64 { index: 0,
+                                     ts: vec![2, 3],
+                                     val: vec![21, 30],
+                                     validity: None }]);
+}
+
+#[test]
+fn merge_blocks_duplicate_policy() {
+    let blocks = || {
+        vec![DataBlock::U64 { index: 0,
+                              ts: vec![1, 2, 2, 3],
+                              val: vec![10, 20, 21, 30],
+                              validity: None },
+             DataBlock::U64 { index: 0, ts: vec![2, 4], val: vec![22, 40], validity: None }]
+    };
+    let res =
+        DataBlock::merge_blocks_with(blocks(), ValueType::Unsigned, DuplicatePolicy::LastWins);
+    assert_eq!(res.len(), 4);
+    assert!(!res.has_duplicates());
+
+    let res = DataBlock::merge_blocks_with(blocks(), ValueType::Unsigned, DuplicatePolicy::KeepAll);
+    assert_eq!(res,
+               DataBlock::U64 { index: 0,
+                                ts: vec![1, 2, 2, 2, 3, 4],
+                                val: vec![10, 20, 21, 22, 30, 40],
+                                validity: None });
+    assert!(res.has_duplicates());
+}
+
+#[test]
+fn ts_at_val_at() {
+    let block =
+        DataBlock::I64 { index: 0, ts: vec![1, 2, 3], val: vec![10, 20, 30], validity: None };
+    assert_eq!((block.ts_at(0), block.val_at::<i64>(0)), (Some(1), Some(10)));
+    assert_eq!((block.ts_at(2), block.val_at::<i64>(2)), (Some(3), Some(30)));
+    assert_eq!((block.ts_at(3), block.val_at::<i64>(3)), (None, None));
+    // the values of another type
+    assert_eq!(block.val_at::<u64>(0), None);
+    assert_eq!(block.bool_at(0), None);
+    assert_eq!(block.str_at(0), None);
+
+    let block = DataBlock::Str { index: 0,
+                                 ts: vec![1, 2],
+                                 val: vec![b"a".to_vec(), b"b".to_vec()],
+                                 validity: None };
+    assert_eq!((block.str_at(0), block.str_at(1), block.str_at(2)),
+               (Some(&b"a"[..]), Some(&b"b"[..]), None));
+    let block =
+        DataBlock::Bool { index: 0, ts: vec![1, 2], val: vec![true, false], validity: None };
+    assert_eq!((block.bool_at(0), block.bool_at(1), block.bool_at(2)),
+               (Some(true), Some(false), None));
+    let block = DataBlock::F64 { index: 0, ts: vec![], val: vec![], validity: None };
+    assert_eq!((block.ts_at(0), block.val_at::<f64>(0)), (None, None));
+}
+
+#[test]
+fn map_values() {
+    let celsius = DataBlock::F64 { index: 0,
+                                   ts: vec![1, 2, 3],
+                                   val: vec![0.0, 100.0, -40.0],
+                                   validity: None };
+    let fahrenheit = celsius.map_values(|v: f64| v * 1.8 + 32.0);
+    assert_eq!(fahrenheit,
+               DataBlock::F64 { index: 0,
+                                ts: vec![1, 2, 3],
+                                val: vec![32.0, 212.0, -40.0],
+                                validity: None });
+    assert_eq!(fahrenheit.ts(), celsius.ts());
+
+    let block = DataBlock::I64 { index: 0, ts: vec![1, 2], val: vec![10, 20], validity: None };
+    assert_eq!(block.map_values(|v: i64| v * 10),
+               DataBlock::I64 { index: 0, ts: vec![1, 2], val: vec![100, 200], validity: None });
+    // blocks of another value type are not mapped
+    assert_eq!(block.map_values(|v: f64| v * 10.0), block);
+    let block = DataBlock::Str { index: 0, ts: vec![1], val: vec![b"a".to_vec()], validity: None };
+    assert_eq!(block.map_values(|v: u64| v + 1), block);
+}
+
+#[test]
+fn coerce() {
+    let block = DataBlock::I64 { index: 0, ts: vec![1, 2], val: vec![-3, 4], validity: None };
+    assert_eq!(block.coerce(ValueType::Float).unwrap(),
+               DataBlock::F64 { index: 0, ts: vec![1, 2], val: vec![-3.0, 4.0], validity: None });
+    let block = DataBlock::U64 { index: 0, ts: vec![1], val: vec![(1 << 53) + 1], validity: None };
+    assert_eq!(block.coerce(ValueType::Float).unwrap(),
+               DataBlock::F64 { index: 0,
+                                ts: vec![1],
+                                val: vec![(1_u64 << 53) as f64],
+                                validity: None });
+    let block = DataBlock::Bool { index: 0, ts: vec![1], val: vec![true], validity: None };
+    assert_eq!(block.clone().coerce(ValueType::Boolean).unwrap(), block);
+
+    let block =
+        DataBlock::Str { index: 0, ts: vec![1], val: vec![b"1.5".to_vec()], validity: None };
+    assert!(matches!(block.coerce(ValueType::Float),
+                     Err(Error::IncompatibleCoercion { from: ValueType::String,
+                                                       to: ValueType::Float })));
+}
+
+#[test]
+fn eq_bits() {
+    let block = DataBlock::F64 { index: 0,
+                                 ts: vec![1, 2, 3],
+                                 val: vec![f64::NAN, -0.0, 1.0],
+                                 validity: None };
+    assert_ne!(block, block.clone());
+    assert!(block.eq_bits(&block.clone()));
+    let zero = DataBlock::F64 { index: 0,
+                                ts: vec![1, 2, 3],
+                                val: vec![f64::NAN, 0.0, 1.0],
+                                validity: None };
+    assert!(!block.eq_bits(&zero));
+    let payload = DataBlock::F64 { index: 0,
+                                   ts: vec![1, 2, 3],
+                                   val: vec![f64::from_bits(0x7ff8_0000_0000_0002), -0.0, 1.0],
+                                   validity: None };
+    assert!(!block.eq_bits(&payload));
+
+    let block = DataBlock::I64 { index: 0, ts: vec![1], val: vec![1], validity: None };
+    assert!(block.eq_bits(&block.clone()));
+    assert!(!block.eq_bits(&DataBlock::U64 { index: 0,
+                                             ts: vec![1],
+                                             val: vec![1],
+                                             validity: None }));
+}
+
+#[test]
+fn sort() {
+    let block = DataBlock::I64 { index: 0,
+                                 ts: vec![3, 1, 2, 1, 3],
+                                 val: vec![1, 2, 3, 4, 5],
+                                 validity: None };
+    assert!(!block.is_sorted());
+
+    let mut last_wins = block.clone();
+    last_wins.sort(DuplicatePolicy::LastWins);
+    assert!(last_wins.is_sorted());
+    assert_eq!(last_wins,
+               DataBlock::I64 { index: 0, ts: vec![1, 2, 3], val: vec![4, 3, 5], validity: None });
+
+    let mut keep_all = block;
+    keep_all.sort(DuplicatePolicy::KeepAll);
+    assert_eq!(keep_all,
+               DataBlock::I64 { index: 0,
+                                ts: vec![1, 1, 2, 3, 3],
+                                val: vec![2, 4, 3, 1, 5],
+                                validity: None });
+    assert!(keep_all.is_sorted());
+}
+
+#[test]
+fn partition_by_time() {
+    const HOUR: i64 = 3_600_000_000_000;
+    // a day of points a minute apart, from half past midnight
+    let start = 1_600_000_000 / 86_400 * 86_400 * 1_000_000_000 + HOUR / 2;
+    let ts: Vec<i64> = (0..24 * 60).map(|i| start + i * 60_000_000_000).collect();
+    let block = DataBlock::F64 { index: 0,
+                                 ts: ts.clone(),
+                                 val: ts.iter().map(|t| *t as f64).collect(),
+                                 validity: None };
+    let parts = block.partition_by_time(HOUR);
+    // the first and the last hours are halves
+    assert_eq!(parts.len(), 25);
+    assert_eq!(parts.iter().map(|b| b.len()).collect::<Vec<_>>(),
+               [vec![30], vec![60; 23], vec![30]].concat());
+    for part in parts.iter() {
+        let (min_ts, max_ts) = part.time_range(0, part.len());
+        let window_start = min_ts.div_euclid(HOUR) * HOUR;
+        assert!(min_ts >= window_start && max_ts < window_start + HOUR);
+        assert_eq!(part.val_at::<f64>(0), Some(min_ts as f64));
+    }
+    assert_eq!(parts.iter().flat_map(|b| b.ts().to_vec()).collect::<Vec<_>>(), ts);
+
+    // the windows before the epoch are aligned too
+    let block = DataBlock::Bool { index: 0,
+                                  ts: vec![-11, -10, -1, 0, 9],
+                                  val: vec![true; 5],
+                                  validity: None };
+    assert_eq!(block.partition_by_time(10).iter().map(|b| b.ts().to_vec()).collect::<Vec<_>>(),
+               vec![vec![-11], vec![-10, -1], vec![0, 9]]);
+    assert_eq!(block.partition_by_time(0), vec![block.clone()]);
+    assert!(DataBlock::new(0, ValueType::Integer).partition_by_time(10).is_empty());
+}
+
+#[test]
+fn estimate_encoded_size() {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    let mut rng = StdRng::seed_from_u64(42);
+    let n = 1000;
+    let regular_ts: Vec<i64> = (0..n as i64).map(|i| 1_600_000_000_000 + i * 1000).collect();
+    let mut jittered_ts = Vec::with_capacity(n);
+    let mut t = 1_600_000_000_000_000_000_i64;
+    for _ in 0..n {
+        t += rng.gen_range(1..1_000_000);
+        jittered_ts.push(t);
+    }
+    let mut walk = 0_i64;
+    let walk: Vec<i64> = (0..n).map(|_| {
+                                   walk += rng.gen_range(-100..100);
+                                   walk
+                               })
+                               .collect();
+    let random_str: Vec<Vec<u8>> =
+        (0..n).map(|_| (0..rng.gen_range(1..64)).map(|_| rng.gen()).collect()).collect();
+    let blocks =
+        vec![DataBlock::I64 { index: 0, ts: regular_ts.clone(), val: vec![7; n], validity: None },
+             DataBlock::I64 { index: 0,
+                              ts: jittered_ts.clone(),
+                              val: walk.clone(),
+                              validity: None },
+             DataBlock::U64 { index: 0,
+                              ts: regular_ts.clone(),
+                              val: walk.iter().map(|v| v.unsigned_abs()).collect(),
+                              validity: None },
+             DataBlock::F64 { index: 0,
+                              ts: jittered_ts.clone(),
+                              val: walk.iter().map(|v| *v as f64 * 0.25).collect(),
+                              validity: None },
+             DataBlock::F64 { index: 0,
+                              ts: regular_ts.clone(),
+                              val: (0..n).map(|_| rng.gen()).collect(),
+                              validity: None },
+             DataBlock::Bool { index: 0,
+                               ts: regular_ts.clone(),
+                               val: (0..n).map(|_| rng.gen()).collect(),
+                               validity: None },
+             DataBlock::Str { index: 0, ts: jittered_ts, val: random_str, validity: None }];
+    for block in blocks.iter() {
+        for (start, end) in [(0, n), (0, 1), (100, 357), (999, 1000)] {
+            let (ts_buf, val_buf) = block.encode(start, end).unwrap();
+            let actual = ts_buf.len() + val_buf.len() + 8;
+            let estimate = block.estimate_encoded_size(start, end);
+            assert!(estimate * 10 >= actual * 9 && estimate * 10 <= actual * 11 + 160,
+                    "{:?} [{}, {}) estimated {} bytes, encoded {}",
+                    block.field_type(),
+                    start,
+                    end,
+                    estimate,
+                    actual);
+        }
+    }
+    assert_eq!(blocks[0].estimate_encoded_size(10, 10), 0);
+
+    // strings that compress well are not estimated under their encoded size
+    let block = DataBlock::Str { index: 0,
+                                 ts: regular_ts,
+                                 val: (0..n).map(|i| format!("host-{}", i % 10).into_bytes())
+                                            .collect(),
+                                 validity: None };
+    let (ts_buf, val_buf) = block.encode(0, n).unwrap();
+    assert!(block.estimate_encoded_size(0, n) >= ts_buf.len() + val_buf.len() + 8);
+}
+
+#[test]
+fn null_points() {
+    let mut block = DataBlock::new(0, ValueType::Integer);
+    block.insert(DataType::I64(I64Cell { ts: 3, val: 30 }));
+    block.push_null(1);
+    block.insert(DataType::I64(I64Cell { ts: 2, val: 20 }));
+    block.push_null(3);
+    assert_eq!(block.ts(), &[3, 1, 2, 3]);
+    assert_eq!(block.null_count(), 2);
+    assert_eq!((block.is_null(0), block.is_null(1), block.is_null(2)), (false, true, false));
+    assert_eq!((block.val_at::<i64>(0), block.val_at::<i64>(1)), (Some(30), None));
+    assert_eq!(block.validity().map(|v| v.bits()), Some(&[0b0101][..]));
+
+    // the validity follows the points
+    let mut sorted = block.clone();
+    sorted.sort(DuplicatePolicy::KeepAll);
+    assert_eq!(sorted.ts(), &[1, 2, 3, 3]);
+    assert_eq!((0..4).map(|i| sorted.is_null(i)).collect::<Vec<_>>(),
+               vec![true, false, false, true]);
+    let mut last_wins = block.clone();
+    last_wins.sort(DuplicatePolicy::LastWins);
+    assert_eq!((last_wins.ts(), last_wins.null_count()), (&[1, 2, 3][..], 2));
+    assert_eq!(last_wins.slice(1, 3).null_count(), 1);
+    // a block left without null points has no validity
+    assert_eq!(last_wins.slice(1, 2).validity(), None);
+    let mut retained = block.clone();
+    retained.retain(&[true, false, true, false]);
+    assert_eq!(retained,
+               DataBlock::I64 { index: 0, ts: vec![3, 2], val: vec![30, 20], validity: None });
+
+    // the null points are skipped when read, and kept when merged
+    let mut read = block.clone();
+    let mut points = vec![];
+    while let Some(DataType::I64(c)) = read.next() {
+        points.push((c.ts, c.val));
+    }
+    assert_eq!(points, vec![(3, 30), (2, 20)]);
+    let other = DataBlock::I64 { index: 0, ts: vec![2, 4], val: vec![22, 40], validity: None };
+    let merged = DataBlock::merge_blocks(vec![sorted.clone(), other], ValueType::Integer);
+    assert_eq!(merged.ts(), &[1, 2, 3, 4]);
+    // the null point written last at 3 hides the value before it, the newer value at 2 wins
+    assert_eq!((0..4).map(|i| merged.val_at::<i64>(i)).collect::<Vec<_>>(),
+               vec![None, Some(22), None, Some(40)]);
+    let older = DataBlock::I64 { index: 0, ts: vec![1], val: vec![10], validity: None };
+    let merged = DataBlock::merge_blocks(vec![older, sorted], ValueType::Integer);
+    assert_eq!((merged.ts(), merged.null_count()), (&[1, 2, 3][..], 2));
+
+    // only the values of the points not null are encoded
+    let (_, with_nulls) = block.encode(0, 4).unwrap();
+    let (_, values) = retained.encode(0, 2).unwrap();
+    assert!(coders::validity::is_prefixed(&with_nulls));
+    assert!(with_nulls.ends_with(&values));
+    assert!(!coders::validity::is_prefixed(&block.encode(0, 1).unwrap().1));
+}
+
+#[test]
+fn validity() {
+    let validity: Validity = [true, false, true].into_iter().collect();
+    assert_eq!((validity.len(), validity.null_count(), validity.bits()), (3, 1, &[0b101][..]));
+    assert_eq!(validity.slice(1, 3), [false, true].into_iter().collect::<Validity>());
+    // the bits past the points are cleared
+    assert_eq!(Validity::from_bits(&[0xff, 0xff], 10), Validity::new_valid(10));
+    assert_eq!(Validity::from_bits(&[0xff, 0xff], 10).bits(), &[0xff, 0b11]);
+    assert_eq!(Validity::new_valid(0).null_count(), 0);
 }
@@ -60,13 +60,23 @@ pub fn encode(src: &[bool], dst: &mut Vec<u8>) -> Result<(), Box<dyn Error>> {
 
 /// Decodes a slice of bytes into a destination vector of `bool`s.
 pub fn decode(src: &[u8], dst: &mut Vec<bool>) -> Result<(), Box<dyn Error>> {
+    decode_limit(src, dst, super::MAX_DECODE_VALUES)
+}
+
+/// Decodes like `decode`, fails if the slice holds more than `max_count` values.
+pub fn decode_limit(src: &[u8],
+                    dst: &mut Vec<bool>,
+                    max_count: usize)
+                    -> Result<(), Box<dyn Error>> {
     if src.is_empty() {
         return Ok(());
     }
 
     // First byte stores the encoding type, only have the bit packed format
-    // currently so ignore for now.
-    assert_eq!(src[0], BOOLEAN_COMPRESSED_BIT_PACKED << 4);
+    // currently.
+    if src[0] != BOOLEAN_COMPRESSED_BIT_PACKED << 4 {
+        return Err(From::from("boolean decoder: invalid encoding"));
+    }
     let src = &src[HEADER_LEN..];
 
     let (count, num_bytes_read) = u64::decode_var(src).ok_or("boolean decoder: invalid count")?;
@@ -79,6 +89,7 @@ pub fn decode(src: &[u8], dst: &mut Vec<bool>) -> Result<(), Box<dyn Error>> {
     // Shouldn't happen - TSM file was truncated/corrupted. This is what the Go code
     // does
     count = cmp::min(min, count);
+    super::check_count(count, max_count)?;
 
     if dst.capacity() < count {
         dst.reserve_exact(count - dst.capacity());
@@ -96,6 +107,28 @@ pub fn decode(src: &[u8], dst: &mut Vec<bool>) -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
+/// Decodes the values `start..end` of the slice, fewer if it holds fewer, the bits of the
+/// other values are not read.
+pub fn decode_range(src: &[u8],
+                    dst: &mut Vec<bool>,
+                    start: usize,
+                    end: usize)
+                    -> Result<(), Box<dyn Error>> {
+    if src.is_empty() {
+        return Ok(());
+    }
+    if src[0] != BOOLEAN_COMPRESSED_BIT_PACKED << 4 {
+        return Err(From::from("boolean decoder: invalid encoding"));
+    }
+    let src = &src[HEADER_LEN..];
+    let (count, num_bytes_read) = u64::decode_var(src).ok_or("boolean decoder: invalid count")?;
+    let count: usize = count.try_into()?;
+    let src = &src[num_bytes_read..];
+    let end = cmp::min(end, cmp::min(src.len() * 8, count));
+    dst.extend((start..end).map(|i| src[i / 8] & (128 >> (i % 8)) != 0));
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -179,4 +212,25 @@ mod tests {
         let expected: Vec<_> = (0..10).map(|i| i % 2 == 0).collect();
         assert_eq!(dst, expected);
     }
+
+    #[test]
+    fn decode_corrupted() {
+        let mut dst = vec![];
+        // an unknown encoding
+        assert!(decode(&[0, 1, 128], &mut dst).is_err());
+        assert!(decode_limit(&[16, 10, 170, 128], &mut dst, 9).is_err());
+        assert!(dst.is_empty());
+    }
+
+    #[test]
+    fn decode_range() {
+        let values: Vec<bool> = (0..1000).map(|i| i % 3 == 0 || i % 7 == 0).collect();
+        let mut enc = vec![];
+        encode(&values, &mut enc).expect("failed to encode");
+        for (start, end) in super::super::TEST_RANGES {
+            let mut dst = vec![];
+            super::decode_range(&enc, &mut dst, start, end).expect("failed to decode");
+            assert_eq!(dst, super::super::expected_range(&values, start, end));
+        }
+    }
 }
@@ -0,0 +1,93 @@
+use std::{borrow::Cow, error::Error};
+
+use integer_encoding::VarInt;
+
+/// The tag of an encoded slice compressed again by zstd, in the 4 high bits of the first byte
+/// like the tags of the coders, none of which uses it.
+const ZSTD_COMPRESSED: u8 = 0xf << 4;
+
+/// The largest decompressed slice, a larger length in the header is corrupted and must not be
+/// allocated.
+const MAX_DECOMPRESSED_LEN: usize = 1 << 30;
+
+/// Compresses the slice written by a coder with zstd at the level, behind the tag and the
+/// length of the slice. The slice is returned as it is if zstd does not make it smaller.
+pub fn compress(src: Vec<u8>, level: i32) -> Result<Vec<u8>, Box<dyn Error>> {
+    if src.is_empty() {
+        return Ok(src);
+    }
+    let compressed = zstd::bulk::compress(&src, level)?;
+    let len = src.len() as u64;
+    if 1 + len.required_space() + compressed.len() >= src.len() {
+        return Ok(src);
+    }
+    let mut dst = Vec::with_capacity(1 + len.required_space() + compressed.len());
+    dst.push(ZSTD_COMPRESSED);
+    dst.extend_from_slice(&len.encode_var_vec());
+    dst.extend_from_slice(&compressed);
+    Ok(dst)
+}
+
+/// Returns true if the slice was compressed by `compress`.
+pub fn is_compressed(src: &[u8]) -> bool {
+    src.first() == Some(&ZSTD_COMPRESSED)
+}
+
+/// Returns the slice written by a coder, decompressed first if `compress` compressed it.
+pub fn decompress(src: &[u8]) -> Result<Cow<'_, [u8]>, Box<dyn Error>> {
+    if !is_compressed(src) {
+        return Ok(Cow::Borrowed(src));
+    }
+    let (len, n) = u64::decode_var(&src[1..]).ok_or("invalid decompressed length")?;
+    let len = len as usize;
+    if len > MAX_DECOMPRESSED_LEN {
+        return Err("invalid decompressed length".into());
+    }
+    let dst = zstd::bulk::decompress(&src[1 + n..], len)?;
+    if dst.len() != len {
+        return Err("decompressed length mismatch".into());
+    }
+    Ok(Cow::Owned(dst))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{compress, decompress, ZSTD_COMPRESSED};
+    use crate::tsm::coders::{float, string};
+
+    #[test]
+    fn zstd_roundtrip() {
+        let values: Vec<Vec<u8>> =
+            (0..1000).map(|i| format!("host-{}", i % 7).into_bytes()).collect();
+        let values: Vec<&[u8]> = values.iter().map(|v| v.as_slice()).collect();
+        let mut encoded = vec![];
+        string::encode(&values, &mut encoded).unwrap();
+
+        let compressed = compress(encoded.clone(), 19).unwrap();
+        assert_eq!(compressed[0], ZSTD_COMPRESSED);
+        assert!(compressed.len() < encoded.len());
+        assert_eq!(decompress(&compressed).unwrap().as_ref(), encoded.as_slice());
+        // a slice not compressed by zstd is returned as it is
+        assert_eq!(decompress(&encoded).unwrap().as_ref(), encoded.as_slice());
+    }
+
+    #[test]
+    fn zstd_incompressible() {
+        let mut encoded = vec![];
+        float::encode(&[1.5], &mut encoded).unwrap();
+        assert_eq!(compress(encoded.clone(), 3).unwrap(), encoded);
+        assert!(compress(vec![], 3).unwrap().is_empty());
+    }
+
+    #[test]
+    fn zstd_corrupted() {
+        let compressed = compress(vec![0; 4096], 3).unwrap();
+        assert_eq!(compressed[0], ZSTD_COMPRESSED);
+
+        let mut wrong_len = compressed.clone();
+        wrong_len[1] ^= 1;
+        assert!(decompress(&wrong_len).is_err());
+        assert!(decompress(&compressed[..compressed.len() - 1]).is_err());
+        assert!(decompress(&[ZSTD_COMPRESSED]).is_err());
+    }
+}
@@ -9,6 +9,10 @@ use std::error::Error;
 const SENTINEL: u64 = 0x7ff8_0000_0000_00ff; // in the quiet NaN range.
 const SENTINEL_INFLUXDB: u64 = 0x7ff8_0000_0000_0001; // legacy NaN value used by InfluxDB
 
+// the first byte of a block, the high 4 bits are the encoding type
+const ENCODING_UNCOMPRESSED: u8 = 0;
+const ENCODING_GORILLA: u8 = 1 << 4;
+
 fn is_sentinel_f64(v: f64, sentinel: u64) -> bool {
     v.to_bits() == sentinel
 }
@@ -22,12 +26,20 @@ fn is_sentinel_u64(v: u64, sentinel: u64) -> bool {
 /// paper. Each subsequent value is compared to the previous and the XOR of the
 /// two is determined. Leading and trailing zero bits are then analysed and
 /// representations based on those are stored.
+///
+/// The bit pattern of every value is kept, including NaN payloads and -0.0, so
+/// decoded values must be compared with `to_bits()`. A block holding the
+/// sentinel NaN is stored uncompressed.
 #[allow(clippy::many_single_char_names)]
 pub fn encode(src: &[f64], dst: &mut Vec<u8>) -> Result<(), Box<dyn Error>> {
     dst.clear(); // reset buffer.
     if src.is_empty() {
         return Ok(());
     }
+    if src.iter().any(|v| is_sentinel_f64(*v, SENTINEL)) {
+        encode_uncompressed(src, dst);
+        return Ok(());
+    }
     if dst.capacity() < 9 {
         dst.reserve_exact(9 - dst.capacity()); // room for encoding type, block
         // size and a value
@@ -35,7 +47,7 @@ pub fn encode(src: &[f64], dst: &mut Vec<u8>) -> Result<(), Box<dyn Error>> {
 
     // write encoding type
     let mut n = 8; // N.B, this is the number of bits written
-    dst.push((1 << 4) as u8); // write compression type
+    dst.push(ENCODING_GORILLA); // write compression type
 
     // write the first value into the block
     let first = src[0];
@@ -49,9 +61,6 @@ pub fn encode(src: &[f64], dst: &mut Vec<u8>) -> Result<(), Box<dyn Error>> {
         let x;
         if i < src.len() {
             x = src[i];
-            if is_sentinel_f64(x, SENTINEL) {
-                return Err(From::from("unsupported value"));
-            }
         } else {
             x = f64::from_bits(SENTINEL);
         }
@@ -315,9 +324,42 @@ const BIT_MASK: [u64; 64] = [0xffff_ffff_ffff_ffff,
                              0x3fff_ffff_ffff_ffff,
                              0x7fff_ffff_ffff_ffff];
 
+fn encode_uncompressed(src: &[f64], dst: &mut Vec<u8>) {
+    dst.reserve_exact(1 + src.len() * 8);
+    dst.push(ENCODING_UNCOMPRESSED);
+    for v in src {
+        dst.extend_from_slice(&v.to_bits().to_be_bytes());
+    }
+}
+
+fn decode_uncompressed(src: &[u8],
+                       dst: &mut Vec<f64>,
+                       max_count: usize)
+                       -> Result<(), Box<dyn Error>> {
+    if src.len() % 8 != 0 {
+        return Err(From::from("unexpected end of block"));
+    }
+    super::check_count(src.len() / 8, max_count)?;
+    dst.reserve_exact(src.len() / 8);
+    for chunk in src.chunks_exact(8) {
+        let mut buf: [u8; 8] = [0; 8];
+        buf.copy_from_slice(chunk);
+        dst.push(f64::from_bits(u64::from_be_bytes(buf)));
+    }
+    Ok(())
+}
+
 /// decode decodes the provided slice of bytes into a vector of f64 values.
 pub fn decode(src: &[u8], dst: &mut Vec<f64>) -> Result<(), Box<dyn Error>> {
-    decode_with_sentinel(src, dst, SENTINEL)
+    decode_limit(src, dst, super::MAX_DECODE_VALUES)
+}
+
+/// Decodes like `decode`, fails if the slice holds more than `max_count` values.
+pub fn decode_limit(src: &[u8],
+                    dst: &mut Vec<f64>,
+                    max_count: usize)
+                    -> Result<(), Box<dyn Error>> {
+    decode_with_sentinel(src, dst, SENTINEL, max_count, usize::MAX)
 }
 
 /// decode_influxdb decodes the provided slice of bytes, which must have been
@@ -328,21 +370,54 @@ pub fn decode(src: &[u8], dst: &mut Vec<f64>) -> Result<(), Box<dyn Error>> {
 /// compression of f64 blocks we may be able to clean this API and not have
 /// multiple methods.
 pub fn decode_influxdb(src: &[u8], dst: &mut Vec<f64>) -> Result<(), Box<dyn Error>> {
-    decode_with_sentinel(src, dst, SENTINEL_INFLUXDB)
+    decode_with_sentinel(src, dst, SENTINEL_INFLUXDB, super::MAX_DECODE_VALUES, usize::MAX)
+}
+
+/// Decodes the values `start..end` of the slice, fewer if it holds fewer. The uncompressed
+/// values are read directly, the gorilla ones are decoded up to `end` only.
+pub fn decode_range(src: &[u8],
+                    dst: &mut Vec<f64>,
+                    start: usize,
+                    end: usize)
+                    -> Result<(), Box<dyn Error>> {
+    if src.first() == Some(&ENCODING_UNCOMPRESSED) {
+        let src = &src[1..];
+        if src.len() % 8 != 0 {
+            return Err(From::from("unexpected end of block"));
+        }
+        let count = src.len() / 8;
+        let (start, end) = (start.min(count), end.min(count));
+        return decode_uncompressed(&src[start * 8..end.max(start) * 8], dst, end);
+    }
+    let mut values = Vec::with_capacity(end);
+    decode_with_sentinel(src, &mut values, SENTINEL, super::MAX_DECODE_VALUES, end)?;
+    dst.extend_from_slice(values.get(start..).unwrap_or_default());
+    Ok(())
 }
 
-/// decode decodes a slice of bytes into a vector of floats.
+/// decode decodes a slice of bytes into a vector of floats, stopping after `until` values.
 #[allow(clippy::many_single_char_names)]
 #[allow(clippy::useless_let_if_seq)]
 fn decode_with_sentinel(src: &[u8],
                         dst: &mut Vec<f64>,
-                        sentinel: u64)
+                        sentinel: u64,
+                        max_count: usize,
+                        until: usize)
                         -> Result<(), Box<dyn Error>> {
-    if src.len() < 9 {
+    if src.is_empty() || until == 0 {
         return Ok(());
     }
+    if src[0] == ENCODING_UNCOMPRESSED {
+        return decode_uncompressed(&src[1..], dst, max_count);
+    }
+    if src.len() < 9 {
+        return Err(From::from("not enough data to decode packed float"));
+    }
+    super::check_count(1, max_count)?;
+    let max_count = max_count.saturating_add(dst.len());
+    let until = until.saturating_add(dst.len());
 
-    let mut i = 1; // skip first byte as it's the encoding, which is always gorilla
+    let mut i = 1; // skip first byte as it's the encoding, which is gorilla here
     let mut buf: [u8; 8] = [0; 8];
 
     // the first decoded value
@@ -350,6 +425,9 @@ fn decode_with_sentinel(src: &[u8],
     let mut val = u64::from_be_bytes(buf);
     i += 8;
     dst.push(f64::from_bits(val));
+    if dst.len() >= until {
+        return Ok(());
+    }
 
     // decode the rest of the values
     let mut br_cached_val;
@@ -408,7 +486,11 @@ fn decode_with_sentinel(src: &[u8],
         br_valid_bits -= 1;
         br_cached_val = br_cached_val.rotate_left(1);
         if br_cached_val & 1 == 0 {
+            super::check_count(dst.len() + 1, max_count)?;
             dst.push(f64::from_bits(val));
+            if dst.len() >= until {
+                break;
+            }
             continue;
         }
 
@@ -451,6 +533,9 @@ fn decode_with_sentinel(src: &[u8],
                     Err(e) => return Err(e),
                 }
 
+                if br_valid_bits < bits_01 {
+                    return Err(From::from("unexpected end of block"));
+                }
                 br_cached_val = br_cached_val.rotate_left(bits_01 as u32);
                 br_valid_bits -= bits_01;
                 lm_bits &= !BIT_MASK[(bits_01 & 0x3f) as usize];
@@ -460,6 +545,9 @@ fn decode_with_sentinel(src: &[u8],
             lm_bits &= 0x7ff;
             let leading_n = (lm_bits >> 6) as u8 & 0x1f; // 5 bits leading
             meaningful_n = (lm_bits & 0x3f) as u8; // 6 bits meaningful
+            if leading_n + meaningful_n > 64 {
+                return Err(From::from("invalid count of meaningful bits"));
+            }
             if meaningful_n > 0 {
                 trailing_n = 64 - leading_n - meaningful_n;
             } else {
@@ -490,8 +578,11 @@ fn decode_with_sentinel(src: &[u8],
                 Err(e) => return Err(e),
             }
 
+            if br_valid_bits < m_bits {
+                return Err(From::from("unexpected end of block"));
+            }
             br_cached_val = br_cached_val.rotate_left(m_bits as u32);
-            br_valid_bits = br_valid_bits.wrapping_sub(m_bits);
+            br_valid_bits -= m_bits;
             s_bits &= !BIT_MASK[(m_bits & 0x3f) as usize];
             s_bits |= br_cached_val & BIT_MASK[(m_bits & 0x3f) as usize];
         }
@@ -502,7 +593,11 @@ fn decode_with_sentinel(src: &[u8],
         if is_sentinel_u64(val, sentinel) {
             break;
         }
+        super::check_count(dst.len() + 1, max_count)?;
         dst.push(f64::from_bits(val));
+        if dst.len() >= until {
+            break;
+        }
     }
     Ok(())
 }
@@ -562,6 +657,35 @@ mod tests {
         }
     }
 
+    #[test]
+    fn encode_preserves_bits() {
+        let special = [f64::from_bits(super::SENTINEL),
+                       f64::from_bits(super::SENTINEL_INFLUXDB),
+                       f64::from_bits(0x7ff0_0000_0000_0002), // signalling NaN
+                       f64::from_bits(0xfff8_dead_beef_0001), // negative NaN with a payload
+                       f64::NAN,
+                       -0.0,
+                       0.0,
+                       -0.0,
+                       f64::from_bits(1), // smallest subnormal
+                       f64::MIN_POSITIVE / 2.0,
+                       -f64::MIN_POSITIVE / 3.0];
+        let bits = |v: &[f64]| v.iter().map(|f| f.to_bits()).collect::<Vec<u64>>();
+        let cases = [(special[1..].to_vec(), super::ENCODING_GORILLA),
+                     (special.to_vec(), super::ENCODING_UNCOMPRESSED),
+                     (special.iter().rev().cloned().collect(), super::ENCODING_UNCOMPRESSED),
+                     (vec![special[0]], super::ENCODING_UNCOMPRESSED)];
+        for (src, encoding) in cases {
+            let mut dst = vec![];
+            super::encode(&src, &mut dst).expect("failed to encode src");
+            assert_eq!(dst[0], encoding);
+
+            let mut got = vec![];
+            super::decode(&dst, &mut got).expect("failed to decode");
+            assert_eq!(bits(&got), bits(&src));
+        }
+    }
+
     #[test]
     fn encode() {
         struct Test {
@@ -1669,4 +1793,44 @@ mod tests {
         super::decode_influxdb(&enc_influxdb, &mut got).expect("failed to decode");
         assert_eq!(got, exp);
     }
+
+    #[test]
+    fn decode_corrupted() {
+        let mut got = vec![];
+        // a gorilla block that stops before its first value
+        assert!(super::decode(&[16, 0, 0], &mut got).is_err());
+
+        // 31 leading and 63 meaningful bits of a 64-bit value
+        let enc = [16, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xf8, 0, 0, 0, 0, 0, 0];
+        assert!(super::decode(&enc, &mut got).is_err());
+
+        let mut enc = vec![];
+        super::encode(&[1.0, 2.0, 3.0], &mut enc).expect("failed to encode");
+        got.clear();
+        assert!(super::decode_limit(&enc, &mut got, 2).is_err());
+        got.clear();
+        super::decode_limit(&enc, &mut got, 3).expect("failed to decode");
+        assert_eq!(got, vec![1.0, 2.0, 3.0]);
+    }
+
+    #[test]
+    fn decode_range() {
+        let gorilla: Vec<f64> = (0..1000).map(|i| (i as f64 * 0.37).sin()).collect();
+        let mut uncompressed = gorilla.clone();
+        uncompressed[500] = f64::from_bits(super::SENTINEL);
+        let cases =
+            [(gorilla, super::ENCODING_GORILLA), (uncompressed, super::ENCODING_UNCOMPRESSED)];
+        for (values, encoding) in cases {
+            let mut enc = vec![];
+            super::encode(&values, &mut enc).expect("failed to encode");
+            assert_eq!(enc[0], encoding);
+            for (start, end) in super::super::TEST_RANGES {
+                let mut got = vec![];
+                super::decode_range(&enc, &mut got, start, end).expect("failed to decode");
+                let exp = super::super::expected_range(&values, start, end);
+                let bits = |v: &[f64]| v.iter().map(|v| v.to_bits()).collect::<Vec<_>>();
+                assert_eq!(bits(&got), bits(&exp), "{:?}", (encoding, start, end));
+            }
+        }
+    }
 }
@@ -124,29 +124,73 @@ fn encode_rle(v: u64, delta: u64, count: u64, dst: &mut Vec<u8>) {
 
 /// decode decodes a slice of bytes into a vector of signed integers.
 pub fn decode(src: &[u8], dst: &mut Vec<i64>) -> Result<(), Box<dyn Error>> {
+    decode_limit(src, dst, super::MAX_DECODE_VALUES)
+}
+
+/// Decodes like `decode`, fails if the slice holds more than `max_count` values.
+pub fn decode_limit(src: &[u8],
+                    dst: &mut Vec<i64>,
+                    max_count: usize)
+                    -> Result<(), Box<dyn Error>> {
     if src.is_empty() {
         return Ok(());
     }
     let encoding = &src[0] >> 4;
     match encoding {
         encoding if encoding == Encoding::Uncompressed as u8 => {
-            decode_uncompressed(&src[1..], dst) // first byte not used
+            decode_uncompressed(&src[1..], dst, max_count) // first byte not used
+        },
+        encoding if encoding == Encoding::Rle as u8 => decode_rle(&src[1..], dst, max_count),
+        encoding if encoding == Encoding::Simple8b as u8 => {
+            decode_simple8b(&src[1..], dst, max_count)
         },
-        encoding if encoding == Encoding::Rle as u8 => decode_rle(&src[1..], dst),
-        encoding if encoding == Encoding::Simple8b as u8 => decode_simple8b(&src[1..], dst),
         _ => Err(From::from("invalid block encoding")),
     }
 }
 
-fn decode_uncompressed(src: &[u8], dst: &mut Vec<i64>) -> Result<(), Box<dyn Error>> {
+/// Decodes the values `start..end` of the slice, fewer if it holds fewer. The run-length
+/// encoded values are computed directly, the others are decoded up to `end` only.
+pub fn decode_range(src: &[u8],
+                    dst: &mut Vec<i64>,
+                    start: usize,
+                    end: usize)
+                    -> Result<(), Box<dyn Error>> {
+    if src.is_empty() || start >= end {
+        return Ok(());
+    }
+    let encoding = &src[0] >> 4;
+    let mut values = Vec::with_capacity(end);
+    match encoding {
+        encoding if encoding == Encoding::Uncompressed as u8 => {
+            let len = (src.len() - 1).min(end.saturating_mul(8));
+            if len & 0x7 != 0 {
+                return Err(From::from("invalid uncompressed block length"));
+            }
+            decode_uncompressed(&src[1..1 + len], &mut values, end)?;
+        },
+        encoding if encoding == Encoding::Rle as u8 => {
+            return decode_rle_range(&src[1..], dst, start, end);
+        },
+        encoding if encoding == Encoding::Simple8b as u8 => {
+            decode_simple8b_until(&src[1..], &mut values, super::MAX_DECODE_VALUES, end)?;
+        },
+        _ => return Err(From::from("invalid block encoding")),
+    }
+    dst.extend_from_slice(values.get(start..).unwrap_or_default());
+    Ok(())
+}
+
+fn decode_uncompressed(src: &[u8],
+                       dst: &mut Vec<i64>,
+                       max_count: usize)
+                       -> Result<(), Box<dyn Error>> {
     if src.is_empty() || src.len() & 0x7 != 0 {
         return Err(From::from("invalid uncompressed block length"));
     }
 
     let count = src.len() / 8;
-    if dst.capacity() < count {
-        dst.reserve_exact(count - dst.capacity());
-    }
+    super::check_count(count, max_count)?;
+    dst.reserve_exact(count);
     let mut i = 0;
     let mut prev: i64 = 0;
     let mut buf: [u8; 8] = [0; 8];
@@ -161,7 +205,7 @@ fn decode_uncompressed(src: &[u8], dst: &mut Vec<i64>) -> Result<(), Box<dyn Err
 
 // decode_rle decodes an RLE encoded slice containing only unsigned into the
 // destination vector.
-fn decode_rle(src: &[u8], dst: &mut Vec<i64>) -> Result<(), Box<dyn Error>> {
+fn decode_rle(src: &[u8], dst: &mut Vec<i64>, max_count: usize) -> Result<(), Box<dyn Error>> {
     if src.len() < 8 {
         return Err(From::from("not enough data to decode using RLE"));
     }
@@ -172,10 +216,9 @@ fn decode_rle(src: &[u8], dst: &mut Vec<i64>) -> Result<(), Box<dyn Error>> {
     i += n;
 
     let (count, _n) = usize::decode_var(&src[i..]).ok_or("unable to decode count")?;
-
-    if dst.capacity() < count {
-        dst.reserve_exact(count - dst.capacity());
-    }
+    // the first value is stored besides the repeated ones
+    super::check_count(count.saturating_add(1), max_count)?;
+    dst.reserve_exact(count + 1);
 
     // TODO(edd): this should be possible to do in-place without copy.
     let mut a: [u8; 8] = [0; 8];
@@ -193,22 +236,54 @@ fn decode_rle(src: &[u8], dst: &mut Vec<i64>) -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
-fn decode_simple8b(src: &[u8], dst: &mut Vec<i64>) -> Result<(), Box<dyn Error>> {
+// decodes the values `start..end` of an RLE encoded slice, each value is the first one plus
+// a multiple of the delta
+fn decode_rle_range(src: &[u8],
+                    dst: &mut Vec<i64>,
+                    start: usize,
+                    end: usize)
+                    -> Result<(), Box<dyn Error>> {
+    if src.len() < 8 {
+        return Err(From::from("not enough data to decode using RLE"));
+    }
+    let (delta, n) = u64::decode_var(&src[8..]).ok_or("unable to decode delta")?;
+    let (count, _n) = usize::decode_var(&src[8 + n..]).ok_or("unable to decode count")?;
+    let end = end.min(count.saturating_add(1));
+    let mut a: [u8; 8] = [0; 8];
+    a.copy_from_slice(&src[0..8]);
+    let first = zig_zag_decode(u64::from_be_bytes(a));
+    let delta_z = zig_zag_decode(delta);
+    dst.extend((start..end).map(|i| first.wrapping_add(delta_z.wrapping_mul(i as i64))));
+    Ok(())
+}
+
+fn decode_simple8b(src: &[u8], dst: &mut Vec<i64>, max_count: usize) -> Result<(), Box<dyn Error>> {
+    decode_simple8b_until(src, dst, max_count, usize::MAX)
+}
+
+// decodes like `decode_simple8b`, stopping after `until` values
+fn decode_simple8b_until(src: &[u8],
+                         dst: &mut Vec<i64>,
+                         max_count: usize,
+                         until: usize)
+                         -> Result<(), Box<dyn Error>> {
     if src.len() < 8 {
         return Err(From::from("not enough data to decode packed integer."));
     }
+    super::check_count(1, max_count)?;
 
     // TODO(edd): pre-allocate res by counting bytes in encoded slice?
     let mut res = vec![];
+    simple8b::decode_until(&src[8..], &mut res, max_count - 1, until.saturating_sub(1))?;
     let mut buf: [u8; 8] = [0; 8];
     buf.copy_from_slice(&src[0..8]);
-    dst.push(zig_zag_decode(u64::from_be_bytes(buf)));
+    let mut next = zig_zag_decode(u64::from_be_bytes(buf));
+    dst.reserve_exact(res.len() + 1);
+    dst.push(next);
 
-    simple8b::decode(&src[8..], &mut res);
     // TODO(edd): fix this. It's copying, which is slowwwwwwwww.
-    let mut next = dst[0];
     for v in &res {
-        next += zig_zag_decode(*v);
+        next = next.wrapping_add(zig_zag_decode(*v));
         dst.push(next);
     }
     Ok(())
@@ -364,4 +439,49 @@ mod tests {
         assert_eq!(dec.len(), values.len());
         assert_eq!(dec, values);
     }
+
+    #[test]
+    fn decode_corrupted() {
+        let mut dec = vec![];
+        // a simple8b block ending in a truncated word
+        let mut enc = vec![(Encoding::Simple8b as u8) << 4];
+        enc.extend_from_slice(&[0; 11]);
+        assert!(decode(&enc, &mut dec).is_err());
+
+        // an rle block repeating a value u64::MAX times
+        let mut enc = vec![(Encoding::Rle as u8) << 4];
+        enc.extend_from_slice(&[0; 8]);
+        enc.push(2);
+        enc.extend_from_slice(&[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01]);
+        assert!(decode(&enc, &mut dec).is_err());
+
+        let mut enc = vec![];
+        encode(&[1, 2, 3], &mut enc).expect("encoding failed");
+        assert!(decode_limit(&enc, &mut dec, 2).is_err());
+        assert!(dec.is_empty());
+        decode_limit(&enc, &mut dec, 3).expect("failed to decode");
+        assert_eq!(dec, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn decode_range() {
+        let rle: Vec<i64> = (0..1000).map(|i| i * 3 - 100).collect();
+        let simple8b: Vec<i64> = (0..1000).map(|i| (i * 7919) % 1000 - 500).collect();
+        let uncompressed: Vec<i64> =
+            (0..1000).map(|i: i64| (i * i).wrapping_mul(1 << 59)).collect();
+        for (values, encoding) in [(rle, Encoding::Rle),
+                                   (simple8b, Encoding::Simple8b),
+                                   (uncompressed, Encoding::Uncompressed)]
+        {
+            let mut enc = vec![];
+            encode(&values, &mut enc).expect("encoding failed");
+            assert_eq!(enc[0] >> 4, encoding as u8);
+            for (start, end) in super::super::TEST_RANGES {
+                let mut dec = vec![];
+                super::decode_range(&enc, &mut dec, start, end).expect("failed to decode");
+                let exp = super::super::expected_range(&values, start, end);
+                assert_eq!(dec, exp, "{:?}", (encoding, start, end));
+            }
+        }
+    }
 }
@@ -1,13 +1,39 @@
 pub mod boolean;
+pub mod compress;
 pub mod float;
 pub mod integer;
 mod simple8b;
 pub mod string;
 pub mod timestamp;
 pub mod unsigned;
+pub mod validity;
 
 /// Max number of bytes needed to store a varint-encoded 32-bit integer.
 const MAX_VAR_INT_32: usize = 5;
 
 /// Max number of bytes needed to store a varint-encoded 64-bit integer.
 const MAX_VAR_INT_64: usize = 10;
+
+/// The most values a decoder produces when the caller gives no smaller bound, a corrupted
+/// count never allocates more.
+pub const MAX_DECODE_VALUES: usize = 1 << 20;
+
+// fails if `count` values decoded in one call exceed the bound of the caller
+fn check_count(count: usize, max_count: usize) -> Result<(), Box<dyn std::error::Error>> {
+    if count > max_count {
+        return Err(format!("{} values exceed the limit of {}", count, max_count).into());
+    }
+    Ok(())
+}
+
+// the ranges decoded by the tests of `decode_range` from 1000 values, some past the end
+#[cfg(test)]
+const TEST_RANGES: [(usize, usize); 8] =
+    [(0, 0), (0, 10), (5, 15), (990, 1000), (0, 1000), (500, 2000), (1200, 1300), (10, 5)];
+
+// the values `decode_range` returns from all the values of a slice
+#[cfg(test)]
+fn expected_range<T: Clone>(values: &[T], start: usize, end: usize) -> Vec<T> {
+    let start = start.min(values.len());
+    values[start..end.clamp(start, values.len())].to_vec()
+}
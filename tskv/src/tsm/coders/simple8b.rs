@@ -74,8 +74,22 @@ pub fn encode(src: &[u64], dst: &mut Vec<u8>) -> Result<(), Box<dyn Error>> {
 }
 
 /// decode decodes and unpacks the binary-encoded values stored in src into
-/// dst.
-pub fn decode(src: &[u8], dst: &mut Vec<u64>) {
+/// dst, fails if src is not made of whole words or holds more than `max_count`
+/// values.
+pub fn decode(src: &[u8], dst: &mut Vec<u64>, max_count: usize) -> Result<(), Box<dyn Error>> {
+    decode_until(src, dst, max_count, usize::MAX)
+}
+
+/// Decodes like `decode`, the words after the one holding the first `until` values are not
+/// read, `dst` holds at most `until` values.
+pub fn decode_until(src: &[u8],
+                    dst: &mut Vec<u64>,
+                    max_count: usize,
+                    until: usize)
+                    -> Result<(), Box<dyn Error>> {
+    if src.len() % 8 != 0 {
+        return Err(From::from("simple8b decoder: truncated word"));
+    }
     let mut i = 0;
     let mut j = 0;
     let mut buf: [u8; 8] = [0; 8];
@@ -86,8 +100,16 @@ pub fn decode(src: &[u8], dst: &mut Vec<u64>) {
         buf.copy_from_slice(&src[i..i + 8]);
         j += decode_value(u64::from_be_bytes(buf), &mut dst[j..]);
         i += 8;
+        if let Err(e) = super::check_count(j, max_count) {
+            dst.truncate(0);
+            return Err(e);
+        }
+        if j >= until {
+            break;
+        }
     }
-    dst.truncate(j);
+    dst.truncate(j.min(until));
+    Ok(())
 }
 
 fn decode_value(v: u64, dst: &mut [u64]) -> usize {
@@ -232,7 +254,7 @@ mod tests {
         let mut decoded = vec![];
         encode(&src, &mut encoded).expect("failed to encode");
         assert_eq!(encoded.len(), 16); // verify vector is truncated.
-        decode(&encoded, &mut decoded);
+        decode(&encoded, &mut decoded, usize::MAX).unwrap();
         assert_eq!(decoded.to_vec(), src, "{}", "mixed sizes");
     }
 
@@ -244,7 +266,7 @@ mod tests {
         let mut decoded = vec![];
         encode(&src, &mut encoded).expect("failed to encode");
         assert_eq!(encoded.len(), 24); // verify vector is truncated.
-        decode(&encoded, &mut decoded);
+        decode(&encoded, &mut decoded, usize::MAX).unwrap();
         assert_eq!(decoded.to_vec(), src, "{}", "mixed sizes");
     }
 
@@ -257,6 +279,15 @@ mod tests {
         assert_eq!(result.unwrap_err().to_string(), "value out of bounds");
     }
 
+    #[test]
+    fn test_decode_corrupted() {
+        let mut decoded = vec![];
+        assert!(decode(&[0; 12], &mut decoded, usize::MAX).is_err());
+        // a selector 0 word holds 240 ones
+        assert!(decode(&[0; 8], &mut decoded, 239).is_err());
+        assert!(decoded.is_empty());
+    }
+
     #[test]
     fn test_encode() {
         struct Test {
@@ -286,7 +317,7 @@ mod tests {
             let mut encoded = vec![];
             encode(&test.input, &mut encoded).expect("failed to encode");
             let mut decoded = vec![];
-            decode(&encoded, &mut decoded);
+            decode(&encoded, &mut decoded, usize::MAX).unwrap();
             assert_eq!(decoded.to_vec(), test.input, "{}", test.name);
         }
 
@@ -298,7 +329,7 @@ mod tests {
         let mut encoded = vec![];
         encode(&input, &mut encoded).expect("failed to encode");
         let mut decoded = vec![];
-        decode(&encoded, &mut decoded);
+        decode(&encoded, &mut decoded, usize::MAX).unwrap();
         assert_eq!(decoded.to_vec(), input, "{}", "120 ones");
 
         input = ones(240)();
@@ -307,7 +338,7 @@ mod tests {
         let mut encoded = vec![];
         encode(&input, &mut encoded).expect("failed to encode");
         let mut decoded = vec![];
-        decode(&encoded, &mut decoded);
+        decode(&encoded, &mut decoded, usize::MAX).unwrap();
         assert_eq!(decoded.to_vec(), input, "{}", "119 ones");
 
         input = ones(241)();
@@ -316,7 +347,7 @@ mod tests {
         let mut encoded = vec![];
         encode(&input, &mut encoded).expect("failed to encode");
         let mut decoded = vec![];
-        decode(&encoded, &mut decoded);
+        decode(&encoded, &mut decoded, usize::MAX).unwrap();
         assert_eq!(decoded.to_vec(), input, "{}", "239 ones");
     }
 
@@ -76,44 +76,110 @@ pub fn encode(src: &[&[u8]], dst: &mut Vec<u8>) -> Result<(), Box<dyn Error>> {
 /// of vectors of bytes representing string data, which may or may not be valid
 /// UTF-8.
 pub fn decode(src: &[u8], dst: &mut Vec<Vec<u8>>) -> Result<(), Box<dyn Error>> {
+    decode_limit(src, dst, super::MAX_DECODE_VALUES)
+}
+
+/// Decodes like `decode`, fails if the slice holds more than `max_count` strings.
+pub fn decode_limit(src: &[u8],
+                    dst: &mut Vec<Vec<u8>>,
+                    max_count: usize)
+                    -> Result<(), Box<dyn Error>> {
     if src.is_empty() {
         return Ok(());
     }
-
-    let mut decoder = snap::raw::Decoder::new();
-    // First byte stores the encoding type, only have snappy format
-    // currently so ignore for now.
-    let decoded_bytes = decoder.decompress_vec(&src[HEADER_LEN..])?;
+    let mut cursor = StrBlockCursor::new(src, max_count)?;
 
     if dst.capacity() == 0 {
         dst.reserve_exact(64);
     }
+    while let Some(s) = cursor.next_value()? {
+        dst.push(s.to_vec());
+    }
 
-    let num_decoded_bytes = decoded_bytes.len();
-    let mut i = 0;
+    Ok(())
+}
 
-    while i < num_decoded_bytes {
-        let (length, num_bytes_read) =
-            u64::decode_var(&decoded_bytes[i..]).ok_or("invalid encoded string length")?;
-        let length: usize = length.try_into()?;
+/// Decodes the strings `start..end` of the slice, fewer if it holds fewer. The strings before
+/// `start` are skipped without being copied and the ones from `end` are not read.
+pub fn decode_range(src: &[u8],
+                    dst: &mut Vec<Vec<u8>>,
+                    start: usize,
+                    end: usize)
+                    -> Result<(), Box<dyn Error>> {
+    if src.is_empty() || start >= end {
+        return Ok(());
+    }
+    let mut cursor = StrBlockCursor::new(src, end)?;
+    for _ in 0..start {
+        if cursor.next_value()?.is_none() {
+            return Ok(());
+        }
+    }
+    for _ in start..end {
+        match cursor.next_value()? {
+            Some(s) => dst.push(s.to_vec()),
+            None => break,
+        }
+    }
+    Ok(())
+}
+
+/// Decodes the strings of an encoded slice one at a time, so that only the decompressed
+/// slice is held rather than a vector per string.
+pub struct StrBlockCursor {
+    decoded_bytes: Vec<u8>,
+    pos: usize,
+    count: usize,
+    max_count: usize,
+}
 
-        let lower = i + num_bytes_read;
-        let upper = lower + length;
+impl StrBlockCursor {
+    /// Decompresses the slice, the strings are decoded by `next_value`; fails like `decode`
+    /// if more than `max_count` strings are read.
+    pub fn new(src: &[u8], max_count: usize) -> Result<Self, Box<dyn Error>> {
+        let mut cursor = Self { decoded_bytes: vec![], pos: 0, count: 0, max_count };
+        if src.is_empty() {
+            return Ok(cursor);
+        }
+        // First byte stores the encoding type, only have snappy format currently.
+        if src[0] != STRING_COMPRESSED_SNAPPY << 4 {
+            return Err("invalid block encoding".into());
+        }
+        let compressed = &src[HEADER_LEN..];
+        // a snappy copy of 3 bytes expands to at most 64 bytes, a larger length in the
+        // header is corrupted and must not be allocated
+        let decompressed_len = snap::raw::decompress_len(compressed)?;
+        if decompressed_len > (compressed.len() / 3 + 1) * 64 {
+            return Err("invalid decompressed length".into());
+        }
+
+        let mut decoder = snap::raw::Decoder::new();
+        cursor.decoded_bytes = decoder.decompress_vec(compressed)?;
+        Ok(cursor)
+    }
 
-        if upper < lower {
-            return Err("length overflow".into());
+    /// Returns the next string, or None after the last one.
+    pub fn next_value(&mut self) -> Result<Option<&[u8]>, Box<dyn Error>> {
+        let num_decoded_bytes = self.decoded_bytes.len();
+        if self.pos >= num_decoded_bytes {
+            return Ok(None);
         }
+        let (length, num_bytes_read) =
+            u64::decode_var(&self.decoded_bytes[self.pos..]).ok_or("invalid encoded string length")?;
+        let length: usize = length.try_into()?;
+
+        let lower = self.pos + num_bytes_read;
+        let upper = lower.checked_add(length).ok_or("length overflow")?;
         if upper > num_decoded_bytes {
             return Err("short buffer".into());
         }
-
-        dst.push(decoded_bytes[lower..upper].to_vec());
+        self.count += 1;
+        super::check_count(self.count, self.max_count)?;
 
         // The length of this string plus the length of the variable byte encoded length
-        i += length + num_bytes_read;
+        self.pos = upper;
+        Ok(Some(&self.decoded_bytes[lower..upper]))
     }
-
-    Ok(())
 }
 
 #[cfg(test)]
@@ -229,4 +295,93 @@ mod tests {
         decode(&src, &mut dst).expect("failed to decode src");
         assert_eq!(dst, vec![&[b'\xC0'][..]]);
     }
+
+    #[test]
+    fn decode_corrupted() {
+        let mut dst = vec![];
+        // a snappy header claiming u32::MAX decompressed bytes
+        let src = vec![16, 0xff, 0xff, 0xff, 0xff, 0x0f, 0];
+        assert!(decode(&src, &mut dst).is_err());
+
+        // an unknown compression type
+        assert!(decode(&[32, 2, 4, 1, 192], &mut dst).is_err());
+
+        let mut src = vec![];
+        encode(&[&b"a"[..], &b"b"[..], &b"c"[..]], &mut src).expect("failed to encode");
+        assert!(decode_limit(&src, &mut dst, 2).is_err());
+        dst.clear();
+        decode_limit(&src, &mut dst, 3).expect("failed to decode src");
+        assert_eq!(dst.len(), 3);
+    }
+
+    #[test]
+    fn cursor_same_as_decode() {
+        let strings: Vec<Vec<u8>> =
+            (0..1000_usize).map(|i| format!("value_{}", i).into_bytes().repeat(i % 7)).collect();
+        let src: Vec<&[u8]> = strings.iter().map(|s| &s[..]).collect();
+        let mut encoded = vec![];
+        encode(&src, &mut encoded).expect("failed to encode");
+        let mut cursor = StrBlockCursor::new(&encoded, 1000).expect("failed to decompress");
+        let mut decoded = vec![];
+        while let Some(s) = cursor.next_value().expect("failed to decode") {
+            decoded.push(s.to_vec());
+        }
+        assert_eq!(decoded, strings);
+
+        assert!(StrBlockCursor::new(&[], 0).unwrap().next_value().unwrap().is_none());
+        let mut cursor = StrBlockCursor::new(&encoded, 999).unwrap();
+        assert!((0..1000).map(|_| cursor.next_value().map(|_| ())).any(|res| res.is_err()));
+    }
+
+    #[test]
+    fn cursor_bounded_memory() {
+        use rand::{rngs::StdRng, RngCore, SeedableRng};
+
+        use crate::{alloc_counter::peak_bytes, tsm::MAX_DECODE_VALUES};
+
+        // 100 random strings of 64 KB
+        let mut rng = StdRng::seed_from_u64(42);
+        let strings: Vec<Vec<u8>> = (0..100).map(|_| {
+                                                let mut s = vec![0; 64 * 1024];
+                                                rng.fill_bytes(&mut s);
+                                                s
+                                            })
+                                            .collect();
+        let total: usize = strings.iter().map(|s| s.len()).sum();
+        let src: Vec<&[u8]> = strings.iter().map(|s| &s[..]).collect();
+        let mut encoded = vec![];
+        encode(&src, &mut encoded).expect("failed to encode");
+
+        // the decompressed slice and a copy of every string
+        let (decoded, decode_peak) = peak_bytes(|| {
+            let mut dst = vec![];
+            decode(&encoded, &mut dst).expect("failed to decode");
+            dst.len()
+        });
+        // only the decompressed slice
+        let (bytes, cursor_peak) = peak_bytes(|| {
+            let mut cursor = StrBlockCursor::new(&encoded, MAX_DECODE_VALUES).unwrap();
+            let mut bytes = 0;
+            while let Some(s) = cursor.next_value().unwrap() {
+                bytes += s.len();
+            }
+            bytes
+        });
+        assert_eq!((decoded, bytes), (100, total));
+        assert!(decode_peak >= 2 * total, "{} bytes held by decode", decode_peak);
+        assert!(cursor_peak < total + total / 100, "{} bytes held by the cursor", cursor_peak);
+    }
+
+    #[test]
+    fn decode_range() {
+        let values: Vec<Vec<u8>> = (0..1000).map(|i| format!("value {}", i).into_bytes()).collect();
+        let src: Vec<&[u8]> = values.iter().map(|v| v.as_slice()).collect();
+        let mut enc = vec![];
+        encode(&src, &mut enc).expect("failed to encode src");
+        for (start, end) in super::super::TEST_RANGES {
+            let mut dst = vec![];
+            super::decode_range(&enc, &mut dst, start, end).expect("failed to decode");
+            assert_eq!(dst, super::super::expected_range(&values, start, end));
+        }
+    }
 }
@@ -149,36 +149,46 @@ fn encode_rle(v: u64, delta: u64, count: u64, dst: &mut Vec<u8>) {
 /// decode decodes a slice of bytes encoded using encode back into a
 /// vector of signed integers.
 pub fn decode(src: &[u8], dst: &mut Vec<i64>) -> Result<(), Box<dyn Error>> {
+    decode_limit(src, dst, super::MAX_DECODE_VALUES)
+}
+
+/// Decodes like `decode`, fails if the slice holds more than `max_count` values.
+pub fn decode_limit(src: &[u8],
+                    dst: &mut Vec<i64>,
+                    max_count: usize)
+                    -> Result<(), Box<dyn Error>> {
     if src.is_empty() {
         return Ok(());
     }
     let encoding = &src[0] >> 4;
     match encoding {
         encoding if encoding == Encoding::Uncompressed as u8 => {
-            decode_uncompressed(&src[1..], dst) // first byte not used
+            decode_uncompressed(&src[1..], dst, max_count) // first byte not used
         },
-        encoding if encoding == Encoding::Rle as u8 => decode_rle(src, dst),
-        encoding if encoding == Encoding::Simple8b as u8 => decode_simple8b(src, dst),
+        encoding if encoding == Encoding::Rle as u8 => decode_rle(src, dst, max_count),
+        encoding if encoding == Encoding::Simple8b as u8 => decode_simple8b(src, dst, max_count),
         _ => Err(From::from("invalid block encoding")),
     }
 }
 
 // decode_uncompressed writes the binary encoded values in src into dst.
-fn decode_uncompressed(src: &[u8], dst: &mut Vec<i64>) -> Result<(), Box<dyn Error>> {
+fn decode_uncompressed(src: &[u8],
+                       dst: &mut Vec<i64>,
+                       max_count: usize)
+                       -> Result<(), Box<dyn Error>> {
     if src.is_empty() || src.len() & 0x7 != 0 {
         return Err(From::from("invalid uncompressed block length"));
     }
 
     let count = src.len() / 8;
-    if dst.capacity() < count {
-        dst.reserve_exact(count - dst.capacity());
-    }
+    super::check_count(count, max_count)?;
+    dst.reserve_exact(count);
     let mut i = 0;
-    let mut prev = 0;
+    let mut prev: i64 = 0;
     let mut buf: [u8; 8] = [0; 8];
     while i < src.len() {
         buf.copy_from_slice(&src[i..i + 8]);
-        prev += i64::from_be_bytes(buf);
+        prev = prev.wrapping_add(i64::from_be_bytes(buf));
         dst.push(prev); // N.B - signed integer...
         i += 8;
     }
@@ -187,7 +197,7 @@ fn decode_uncompressed(src: &[u8], dst: &mut Vec<i64>) -> Result<(), Box<dyn Err
 
 // decode_rle decodes an RLE encoded slice containing only unsigned into the
 // destination vector.
-fn decode_rle(src: &[u8], dst: &mut Vec<i64>) -> Result<(), Box<dyn Error>> {
+fn decode_rle(src: &[u8], dst: &mut Vec<i64>, max_count: usize) -> Result<(), Box<dyn Error>> {
     if src.len() < 9 {
         return Err(From::from("not enough data to decode using RLE"));
     }
@@ -200,15 +210,13 @@ fn decode_rle(src: &[u8], dst: &mut Vec<i64>) -> Result<(), Box<dyn Error>> {
     let mut a: [u8; 8] = [0; 8];
     a.copy_from_slice(&src[i..i + 8]);
     i += 8;
-    let (mut delta, n) = u64::decode_var(&src[i..]).ok_or("unable to decode delta")?;
+    let (delta, n) = u64::decode_var(&src[i..]).ok_or("unable to decode delta")?;
     i += n;
-    delta *= scaler;
+    let delta = delta.wrapping_mul(scaler);
 
     let (count, _n) = usize::decode_var(&src[i..]).ok_or("unable to decode count")?;
-
-    if dst.capacity() < count {
-        dst.reserve_exact(count - dst.capacity());
-    }
+    super::check_count(count, max_count)?;
+    dst.reserve_exact(count);
 
     let mut first = i64::from_be_bytes(a);
     for _ in 0..count {
@@ -218,33 +226,26 @@ fn decode_rle(src: &[u8], dst: &mut Vec<i64>) -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
-fn decode_simple8b(src: &[u8], dst: &mut Vec<i64>) -> Result<(), Box<dyn Error>> {
+fn decode_simple8b(src: &[u8], dst: &mut Vec<i64>, max_count: usize) -> Result<(), Box<dyn Error>> {
     if src.len() < 9 {
         return Err(From::from("not enough data to decode packed timestamp"));
     }
+    super::check_count(1, max_count)?;
 
     let scaler = 10_u64.pow((src[0] & 0b0000_1111) as u32);
 
     // TODO(edd): pre-allocate res by counting bytes in encoded slice?
     let mut res = vec![];
+    simple8b::decode(&src[9..], &mut res, max_count - 1)?;
     let mut buf: [u8; 8] = [0; 8];
     buf.copy_from_slice(&src[1..9]);
-    dst.push(i64::from_be_bytes(buf));
-
-    simple8b::decode(&src[9..], &mut res);
-    let mut next = dst[dst.len() - 1];
-    if scaler > 1 {
-        // TODO(edd): fix this. It's copying, which is slowwwwwwwww.
-        for v in &res {
-            next += (v * scaler) as i64;
-            dst.push(next);
-        }
-        return Ok(());
-    }
+    let mut next = i64::from_be_bytes(buf);
+    dst.reserve_exact(res.len() + 1);
+    dst.push(next);
 
     // TODO(edd): fix this. It's copying, which is slowwwwwwwww.
     for v in &res {
-        next += *v as i64;
+        next = next.wrapping_add(v.wrapping_mul(scaler) as i64);
         dst.push(next);
     }
     Ok(())
@@ -352,4 +353,28 @@ mod tests {
             assert_eq!(got, exp, "{}", test.name);
         }
     }
+
+    #[test]
+    fn decode_corrupted() {
+        let mut got = vec![];
+        // an rle block repeating a timestamp u64::MAX times
+        let mut enc = vec![(Encoding::Rle as u8) << 4];
+        enc.extend_from_slice(&[0; 8]);
+        enc.push(1);
+        enc.extend_from_slice(&[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01]);
+        assert!(decode(&enc, &mut got).is_err());
+
+        // a simple8b block ending in a truncated word
+        let mut enc = vec![(Encoding::Simple8b as u8) << 4];
+        enc.extend_from_slice(&[0; 12]);
+        assert!(decode(&enc, &mut got).is_err());
+
+        // deltas scaled past i64::MAX wrap instead of panicking
+        let mut enc = vec![(Encoding::Rle as u8) << 4 | 0x0f];
+        enc.extend_from_slice(&[0; 8]);
+        enc.extend_from_slice(&[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01]);
+        enc.push(2);
+        decode(&enc, &mut got).expect("failed to decode");
+        assert_eq!(got.len(), 2);
+    }
 }
@@ -15,19 +15,39 @@ pub fn encode(src: &[u64], dst: &mut Vec<u8>) -> Result<(), Box<dyn Error>> {
 
 /// Decodes a slice of bytes into a destination vector of unsigned integers.
 pub fn decode(src: &[u8], dst: &mut Vec<u64>) -> Result<(), Box<dyn Error>> {
+    decode_limit(src, dst, super::MAX_DECODE_VALUES)
+}
+
+/// Decodes like `decode`, fails if the slice holds more than `max_count` values.
+pub fn decode_limit(src: &[u8],
+                    dst: &mut Vec<u64>,
+                    max_count: usize)
+                    -> Result<(), Box<dyn Error>> {
     if src.is_empty() {
         return Ok(());
     }
     let mut signed_results = vec![];
-    super::integer::decode(src, &mut signed_results)?;
+    super::integer::decode_limit(src, &mut signed_results, max_count)?;
     dst.clear();
-    dst.reserve_exact(signed_results.len() - dst.capacity());
+    dst.reserve_exact(signed_results.len());
     for s in signed_results {
         dst.push(s as u64);
     }
     Ok(())
 }
 
+/// Decodes the values `start..end` of the slice like `integer::decode_range`.
+pub fn decode_range(src: &[u8],
+                    dst: &mut Vec<u64>,
+                    start: usize,
+                    end: usize)
+                    -> Result<(), Box<dyn Error>> {
+    let mut signed_results = vec![];
+    super::integer::decode_range(src, &mut signed_results, start, end)?;
+    dst.extend(signed_results.into_iter().map(|s| s as u64));
+    Ok(())
+}
+
 // Converts a slice of `u64` values to a `Vec<i64>`.
 // TODO(edd): this is expensive as it copies. There are cheap
 // but unsafe alternatives to look into such as std::mem::transmute
@@ -155,4 +175,26 @@ mod tests {
         assert_eq!(dec.len(), values.len());
         assert_eq!(dec, values);
     }
+
+    #[test]
+    fn decode_into_spare_capacity() {
+        let mut enc = vec![];
+        encode(&[5], &mut enc).expect("encoding failed");
+        // the capacity used to be subtracted from the decoded length
+        let mut dec = Vec::with_capacity(16);
+        decode(&enc, &mut dec).expect("failed to decode");
+        assert_eq!(dec, vec![5]);
+    }
+
+    #[test]
+    fn decode_range() {
+        let values: Vec<u64> = (0..1000).map(|i| (i * 7919) % 1000 + u64::MAX / 2).collect();
+        let mut enc = vec![];
+        encode(&values, &mut enc).expect("encoding failed");
+        for (start, end) in super::super::TEST_RANGES {
+            let mut dec = vec![];
+            super::decode_range(&enc, &mut dec, start, end).expect("failed to decode");
+            assert_eq!(dec, super::super::expected_range(&values, start, end));
+        }
+    }
 }
@@ -0,0 +1,84 @@
+use std::error::Error;
+
+use integer_encoding::VarInt;
+
+use super::{check_count, MAX_DECODE_VALUES};
+
+/// The tag of the encoded values of a block with nulls, in the 4 high bits of the first byte
+/// like the tags of the coders, none of which uses it.
+const NULLS_PREFIXED: u8 = 0xe << 4;
+
+/// Writes the validity bits of `len` points before the slice a coder wrote for the values of
+/// the points not null: the tag, the count of points as a varint, then the bits, one byte
+/// for every 8 points. Blocks without nulls are written without the prefix.
+pub fn encode(bits: &[u8], len: usize, values: &[u8], dst: &mut Vec<u8>) {
+    let n = (len + 7) / 8;
+    dst.clear();
+    dst.reserve(1 + (len as u64).required_space() + n + values.len());
+    dst.push(NULLS_PREFIXED);
+    dst.extend_from_slice(&(len as u64).encode_var_vec());
+    dst.extend_from_slice(&bits[..n]);
+    dst.extend_from_slice(values);
+}
+
+/// Returns true if the slice starts with the validity bits written by `encode`.
+pub fn is_prefixed(src: &[u8]) -> bool {
+    src.first() == Some(&NULLS_PREFIXED)
+}
+
+/// Splits the slice written by `encode` into the count of points, their validity bits and the
+/// slice of the values. A slice without the prefix is returned as it is with no bits.
+pub fn decode(src: &[u8]) -> Result<(Option<(usize, &[u8])>, &[u8]), Box<dyn Error>> {
+    if !is_prefixed(src) {
+        return Ok((None, src));
+    }
+    let (len, i) = u64::decode_var(&src[1..]).ok_or("invalid validity length")?;
+    let len = len as usize;
+    check_count(len, MAX_DECODE_VALUES)?;
+    let start = 1 + i;
+    let end = start + (len + 7) / 8;
+    if end > src.len() {
+        return Err("validity bits past the end of the block".into());
+    }
+    Ok((Some((len, &src[start..end])), &src[end..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, encode, is_prefixed};
+    use crate::tsm::coders::integer;
+
+    #[test]
+    fn validity_roundtrip() {
+        let mut values = vec![];
+        integer::encode(&[1, 2, 3], &mut values).unwrap();
+        // the 1st, 3rd and 10th of 10 points
+        let bits = [0b0000_0101, 0b0000_0010];
+        let mut dst = vec![];
+        encode(&bits, 10, &values, &mut dst);
+        assert!(is_prefixed(&dst));
+        assert!(!is_prefixed(&values));
+
+        let (validity, rest) = decode(&dst).unwrap();
+        assert_eq!(validity, Some((10, &bits[..])));
+        assert_eq!(rest, values.as_slice());
+        // a slice without nulls is its values
+        assert_eq!(decode(&values).unwrap(), (None, values.as_slice()));
+        assert_eq!(decode(&[]).unwrap(), (None, &[][..]));
+    }
+
+    #[test]
+    fn validity_all_null() {
+        let mut dst = vec![];
+        encode(&[0; 2], 9, &[], &mut dst);
+        assert_eq!(decode(&dst).unwrap(), (Some((9, &[0, 0][..])), &[][..]));
+    }
+
+    #[test]
+    fn validity_corrupted() {
+        let mut dst = vec![];
+        encode(&[0xff; 2], 16, &[], &mut dst);
+        assert!(decode(&dst[..dst.len() - 1]).is_err());
+        assert!(decode(&dst[..1]).is_err());
+    }
+}
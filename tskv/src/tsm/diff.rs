@@ -0,0 +1,203 @@
+use std::{cmp::Ordering, path::Path, vec};
+
+use models::FieldId;
+
+use crate::{
+    direct_io::{FileCursor, IoClass},
+    error::Result,
+    file_manager::get_file_manager,
+    memcache::DataType,
+    tseries_family::TimeRange,
+    tsm::{BlockReader, DataBlock, FileBlock, TsmBlockReader, TsmIndexReader},
+};
+
+/// A difference between the points of a field in two tsm files, see `diff_field`.
+#[derive(Debug, Clone)]
+pub enum Diff {
+    /// The point is in the first file only.
+    OnlyInA(DataType),
+    /// The point is in the second file only.
+    OnlyInB(DataType),
+    /// Both files hold a point of the timestamp, with different values.
+    Changed { a: DataType, b: DataType },
+}
+
+impl Diff {
+    pub fn timestamp(&self) -> i64 {
+        match self {
+            Diff::OnlyInA(data) | Diff::OnlyInB(data) => data.timestamp(),
+            Diff::Changed { a, .. } => a.timestamp(),
+        }
+    }
+}
+
+// the points of a field of a file in a time range, the blocks are decoded one at a time
+struct FieldPoints {
+    cursor: FileCursor,
+    blocks: vec::IntoIter<FileBlock>,
+    block: Option<DataBlock>,
+    range: TimeRange,
+}
+
+impl FieldPoints {
+    fn open(path: &Path, field_id: FieldId, range: TimeRange) -> Result<Self> {
+        let file = get_file_manager().open_file(path)?;
+        let len = file.len() as usize;
+        let mut cursor = file.into_cursor();
+        cursor.set_io_class(IoClass::Low);
+        let mut blocks = vec![];
+        for entry in TsmIndexReader::try_new(&mut cursor, len)? {
+            let entry = entry?;
+            let block = entry.block;
+            if entry.field_id() == field_id
+               && range.overlaps(&TimeRange::new(block.max_ts, block.min_ts))
+            {
+                blocks.push(block);
+            }
+        }
+        blocks.sort_by_key(|b| b.min_ts);
+        Ok(Self { cursor, blocks: blocks.into_iter(), block: None, range })
+    }
+
+    fn next(&mut self) -> Result<Option<DataType>> {
+        loop {
+            if let Some(block) = self.block.as_mut() {
+                while let Some(data) = block.next() {
+                    if self.range.contains(data.timestamp()) {
+                        return Ok(Some(data));
+                    }
+                }
+            }
+            match self.blocks.next() {
+                Some(block) => {
+                    self.block = Some(TsmBlockReader::new(&mut self.cursor).decode(&block)?);
+                },
+                None => return Ok(None),
+            }
+        }
+    }
+}
+
+// the floats are compared by their bit patterns, like `DataBlock::eq_bits`
+fn same_value(a: &DataType, b: &DataType) -> bool {
+    match (a, b) {
+        (DataType::U64(a), DataType::U64(b)) => a.val == b.val,
+        (DataType::I64(a), DataType::I64(b)) => a.val == b.val,
+        (DataType::Str(a), DataType::Str(b)) => a.val == b.val,
+        (DataType::F64(a), DataType::F64(b)) => a.val.to_bits() == b.val.to_bits(),
+        (DataType::Bool(a), DataType::Bool(b)) => a.val == b.val,
+        _ => false,
+    }
+}
+
+/// Compares the points of the field in the time range held by the tsm files `a` and `b`,
+/// returns the differences ordered by timestamp. The points are read as they are in the files,
+/// the tombstones are not applied.
+///
+/// Both files are read once, a block at a time, in the order of the timestamps; the blocks
+/// of the field in a file must not overlap, as written by the flushes and the compactions.
+/// The duplicates of a timestamp in a file are paired in their order with the other file's.
+pub fn diff_field(a: impl AsRef<Path>,
+                  b: impl AsRef<Path>,
+                  field_id: FieldId,
+                  range: &TimeRange)
+                  -> Result<Vec<Diff>> {
+    let mut a_points = FieldPoints::open(a.as_ref(), field_id, *range)?;
+    let mut b_points = FieldPoints::open(b.as_ref(), field_id, *range)?;
+    let mut diffs = vec![];
+    let (mut a_next, mut b_next) = (a_points.next()?, b_points.next()?);
+    loop {
+        match (a_next.take(), b_next.take()) {
+            (None, None) => break,
+            (Some(a), None) => {
+                diffs.push(Diff::OnlyInA(a));
+                a_next = a_points.next()?;
+            },
+            (None, Some(b)) => {
+                diffs.push(Diff::OnlyInB(b));
+                b_next = b_points.next()?;
+            },
+            (Some(a), Some(b)) => match a.timestamp().cmp(&b.timestamp()) {
+                Ordering::Less => {
+                    diffs.push(Diff::OnlyInA(a));
+                    a_next = a_points.next()?;
+                    b_next = Some(b);
+                },
+                Ordering::Greater => {
+                    diffs.push(Diff::OnlyInB(b));
+                    a_next = Some(a);
+                    b_next = b_points.next()?;
+                },
+                Ordering::Equal => {
+                    if !same_value(&a, &b) {
+                        diffs.push(Diff::Changed { a, b });
+                    }
+                    a_next = a_points.next()?;
+                    b_next = b_points.next()?;
+                },
+            },
+        }
+    }
+    Ok(diffs)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::{diff_field, Diff};
+    use crate::{
+        direct_io::IoClass,
+        kv_option::BlockCompression,
+        memcache::DataType,
+        tseries_family::TimeRange,
+        tsm::{DataBlock, TsmWriter},
+    };
+
+    fn write(path: &str, ts: Vec<i64>, val: Vec<f64>) {
+        let mut writer = TsmWriter::create(path, IoClass::High).unwrap();
+        let field_1 = DataBlock::F64 { index: 0, ts, val, validity: None };
+        // field 2 is the same in both files
+        let field_2 =
+            DataBlock::F64 { index: 0, ts: vec![2, 9], val: vec![1.0, 2.0], validity: None };
+        writer.write_chunks(HashMap::from([(1, vec![field_1]), (2, vec![field_2])]),
+                            BlockCompression::Fast)
+              .unwrap();
+        writer.finish().unwrap();
+    }
+
+    fn describe(diff: &Diff) -> (&'static str, i64) {
+        let kind = match diff {
+            Diff::OnlyInA(_) => "a",
+            Diff::OnlyInB(_) => "b",
+            Diff::Changed { a: DataType::F64(a), b: DataType::F64(b) } => {
+                assert_ne!(a.val, b.val);
+                "changed"
+            },
+            Diff::Changed { .. } => panic!("unexpected data type"),
+        };
+        (kind, diff.timestamp())
+    }
+
+    #[test]
+    fn test_diff_field() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = dir.path().join("a.tsm").to_string_lossy().to_string();
+        let b = dir.path().join("b.tsm").to_string_lossy().to_string();
+        write(&a, vec![1, 2, 3, 4, 6], vec![1.0, 2.0, 3.0, 4.0, 6.0]);
+        write(&b, vec![1, 2, 4, 5, 6, 7], vec![1.0, 2.5, 4.0, 5.0, 6.0, 7.0]);
+
+        let all = TimeRange::new(i64::MAX, i64::MIN);
+        let diffs = diff_field(&a, &b, 1, &all).unwrap();
+        assert_eq!(diffs.iter().map(describe).collect::<Vec<_>>(),
+                   vec![("changed", 2), ("a", 3), ("b", 5), ("b", 7)]);
+        assert!(diff_field(&a, &b, 2, &all).unwrap().is_empty());
+        assert!(diff_field(&a, &a, 1, &all).unwrap().is_empty());
+
+        // only the points in the range are compared
+        let diffs = diff_field(&a, &b, 1, &TimeRange::new(5, 3)).unwrap();
+        assert_eq!(diffs.iter().map(describe).collect::<Vec<_>>(), vec![("a", 3), ("b", 5)]);
+        let diffs = diff_field(&b, &a, 1, &TimeRange::new(6, 6)).unwrap();
+        assert!(diffs.is_empty());
+    }
+}
@@ -5,7 +5,7 @@ use crate::{byte_utils::decode_be_u64, error::Result, tsm::FileBlock, Error};
 /// ```text
 /// +-------------+---------+
 /// | field_id    | 8 bytes |
-/// | field_type  | 1 bytes |
+/// | field_type  | 1 bytes | (the highest bit is set if blocks may contain duplicates)
 /// | block_count | 2 bytes |
 /// | blocks      | -       |
 /// +-------------+---------+
@@ -17,6 +17,7 @@ pub struct IndexEntry {
     pub count: u16,
     pub block: FileBlock,
     pub curr_block: u16,
+    pub may_have_duplicates: bool,
 }
 
 /// ```text
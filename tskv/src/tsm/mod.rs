@@ -1,7 +1,10 @@
 mod block;
 mod coders;
+pub mod diff;
 mod index;
 mod reader;
+pub mod rewrite;
+mod stats;
 mod tombstone;
 mod writer;
 
@@ -9,16 +12,33 @@ pub use block::*;
 pub use coders::*;
 pub use index::*;
 pub use reader::*;
-pub use tombstone::{Tombstone, TsmTombstone};
+pub use stats::*;
+pub use tombstone::{Tombstone, TombstoneBuilder, TombstoneIndex, TombstoneSet, TsmTombstone};
 pub use writer::*;
 
 // MAX_BLOCK_VALUES is the maximum number of values a TSM block can store.
-const MAX_BLOCK_VALUES: usize = 1000;
+pub(crate) const MAX_BLOCK_VALUES: usize = 1000;
+
+// MAX_BLOCK_BYTES is about the most bytes a TSM block is encoded into, blocks estimated
+// larger are cut into fewer values.
+pub(crate) const MAX_BLOCK_BYTES: usize = 4 * 1024 * 1024;
 
 const BLOOM_FILTER_SIZE: usize = 64;
 
 const FOOTER_SIZE: usize = BLOOM_FILTER_SIZE + 8; // 72
 
+// Ends the optional section of sorted field ids written before the footer, "FLDS".
+const FIELDS_MAGIC: u32 = 0x464C4453;
+
+// Ends the optional section of the format features written before the footer, "FEAT".
+const FEATURES_MAGIC: u32 = 0x46454154;
+
+const FEATURES_SIZE: usize = 12;
+
+// The highest bit of the index entry type is set if the blocks of the field may contain
+// duplicate timestamps.
+const DUPLICATES_FLAG: u8 = 0x80;
+
 pub trait BlockReader {
     fn decode(&mut self, block: &FileBlock) -> crate::error::Result<DataBlock>;
 }
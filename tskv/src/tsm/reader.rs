@@ -1,21 +1,37 @@
 use std::{
+    borrow::Cow,
+    cmp::Reverse,
+    collections::{BinaryHeap, VecDeque},
     io::{Read, Seek, SeekFrom},
+    ops::Range,
     sync::Arc,
 };
 
 use integer_encoding::VarInt;
 use logger::info;
 use models::{FieldId, ValueType};
+use utils::BloomFilter;
 
-use super::{coders, BLOOM_FILTER_SIZE, FOOTER_SIZE, MAX_BLOCK_VALUES};
+use super::{
+    coders, BLOOM_FILTER_SIZE, DUPLICATES_FLAG, FEATURES_MAGIC, FEATURES_SIZE, FIELDS_MAGIC,
+    FOOTER_SIZE, MAX_BLOCK_VALUES,
+};
 use crate::{
-    byte_utils::decode_be_u16,
+    byte_utils::{decode_be_u16, decode_be_u32, decode_be_u64},
     direct_io::{File, FileCursor},
     error::{Error, Result},
+    features::FeatureBits,
+    kv_option::CachePolicy,
+    lru_cache::get_block_cache,
+    memcache::{DataType, StrCell},
     tseries_family::TimeRange,
-    tsm::{BlockReader, DataBlock, IndexEntry},
+    tsm::{BlockReader, DataBlock, IndexEntry, TombstoneIndex, Validity},
 };
 
+// a string block larger than it in the file is decoded one value at a time by `read_data`, the
+// values out of the time range or deleted are never copied
+const STREAM_STR_BLOCK_SIZE: u64 = 1024 * 1024;
+
 #[derive(Debug, Clone)]
 pub struct FileBlock {
     pub min_ts: i64,
@@ -26,6 +42,8 @@ pub struct FileBlock {
     pub field_type: ValueType,
     // pub field_id: u64,
     pub reader_idx: usize,
+    // timestamps are not strictly increasing if true
+    pub may_have_duplicates: bool,
 }
 
 impl FileBlock {
@@ -37,21 +55,60 @@ impl FileBlock {
                size: 0,
                field_type: ValueType::Unknown,
                val_off: 0,
-               reader_idx: 0 }
+               reader_idx: 0,
+               may_have_duplicates: false }
+    }
+}
+
+/// A block as it is encoded in a tsm file, so that it can be copied to another file without
+/// being decoded and encoded again.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RawBlock {
+    pub field_type: ValueType,
+    pub min_ts: i64,
+    pub max_ts: i64,
+    pub may_have_duplicates: bool,
+    pub ts_crc: u32,
+    pub ts: Vec<u8>,
+    pub val_crc: u32,
+    pub val: Vec<u8>,
+}
+
+impl RawBlock {
+    /// Decodes the timestamps of the block, the values are left encoded.
+    pub fn timestamps(&self) -> Result<Vec<i64>> {
+        let mut ts = Vec::with_capacity(MAX_BLOCK_VALUES);
+        let data = decompress(&self.ts)?;
+        coders::timestamp::decode_limit(&data, &mut ts, MAX_BLOCK_VALUES)
+            .map_err(|e| Error::ReadTsmErr { reason: e.to_string() })?;
+        Ok(ts)
     }
 }
 
 // #[derive(Debug)]
 pub struct TsmBlockReader<'a> {
     reader: &'a mut FileCursor,
+    // the path and the length of the file if the blocks go through the block cache
+    cache: Option<(&'a str, u64, CachePolicy)>,
 }
 
 impl<'a> TsmBlockReader<'a> {
     pub fn new(reader: &'a mut FileCursor) -> Self {
-        Self { reader }
+        Self { reader, cache: None }
     }
 
-    pub fn read_blocks(&mut self, blocks: &Vec<FileBlock>, time_range: &TimeRange) {
+    /// Decodes the blocks of `read_data` through the block cache, admitting them as the policy
+    /// says.
+    pub fn with_block_cache(mut self, path: &'a str, len: u64, policy: CachePolicy) -> Self {
+        self.cache = Some((path, len, policy));
+        self
+    }
+
+    pub fn read_blocks(&mut self,
+                       blocks: &Vec<FileBlock>,
+                       time_range: &TimeRange)
+                       -> Vec<DataType> {
+        let mut res = Vec::new();
         for block in blocks {
             let mut data = self.decode(block).expect("error decoding block data");
             let mut loopp = true;
@@ -59,85 +116,580 @@ impl<'a> TsmBlockReader<'a> {
                 let datum = data.next();
                 match datum {
                     Some(datum) => {
-                        if datum.timestamp() > time_range.min_ts
-                           && datum.timestamp() < time_range.max_ts
-                        {
+                        if time_range.contains(datum.timestamp()) {
                             info!("{:?}", datum.clone());
+                            res.push(datum);
                         }
                     },
                     None => loopp = false,
                 }
             }
         }
+        res
+    }
+
+    pub fn read_data(&mut self,
+                     blocks: &[FileBlock],
+                     time_range: &TimeRange,
+                     tombstones: &TombstoneIndex)
+                     -> Result<Vec<DataType>> {
+        let mut res = Vec::new();
+        for block in blocks {
+            if block.field_type == ValueType::String && block.size > STREAM_STR_BLOCK_SIZE {
+                self.read_str_values(block, time_range, tombstones, &mut res)?;
+                continue;
+            }
+            let mut data = self.decode_window(block, time_range, tombstones)?;
+            tombstones.filter(&mut data);
+            while let Some(datum) = data.next() {
+                if time_range.contains(datum.timestamp()) {
+                    res.push(datum);
+                }
+            }
+        }
+        Ok(res)
+    }
+
+    // decodes the points of a block from the first to the last one in the time range and not
+    // deleted, the values out of them are not decoded. A block in the time range as a whole
+    // is decoded whole through the block cache, and so is a block whose timestamps may be
+    // unsorted.
+    fn decode_window(&mut self,
+                     block: &FileBlock,
+                     time_range: &TimeRange,
+                     tombstones: &TombstoneIndex)
+                     -> Result<DataBlock> {
+        let block_range = TimeRange::new(block.max_ts, block.min_ts);
+        let whole = time_range.contains(block.min_ts) && time_range.contains(block.max_ts);
+        if block.may_have_duplicates
+           || whole && (self.cache.is_some() || !tombstones.overlaps(&block_range))
+        {
+            return self.decode_cached(block);
+        }
+        if let Some((path, len, policy)) = self.cache {
+            if let Some(data) = get_block_cache().get(path, len, block.offset, policy) {
+                return Ok(data);
+            }
+        }
+        let (data, ts, idx) = self.read_block_ts(block)?;
+        let mut start = ts.partition_point(|t| *t < time_range.min_ts);
+        let mut end = ts.partition_point(|t| *t <= time_range.max_ts);
+        while start < end && tombstones.contains(ts[start]) {
+            start += 1;
+        }
+        while start < end && tombstones.contains(ts[end - 1]) {
+            end -= 1;
+        }
+        decode_values(block.field_type, &data[idx..], ts, start..end)
+    }
+
+    // decodes a block through the block cache if the reader has one, the cached blocks are not
+    // filtered by the tombstones
+    fn decode_cached(&mut self, block: &FileBlock) -> Result<DataBlock> {
+        let (path, len, policy) = match self.cache {
+            Some(v) => v,
+            None => return self.decode(block),
+        };
+        let cache = get_block_cache();
+        if let Some(data) = cache.get(path, len, block.offset, policy) {
+            return Ok(data);
+        }
+        let data = self.decode(block)?;
+        cache.insert(path, len, block.offset, &data, policy);
+        Ok(data)
+    }
+
+    // reads the values of a string block in the time range and not deleted into `res`, the
+    // strings are decoded one by one rather than into a `DataBlock`
+    fn read_str_values(&mut self,
+                       block: &FileBlock,
+                       time_range: &TimeRange,
+                       tombstones: &TombstoneIndex,
+                       res: &mut Vec<DataType>)
+                       -> Result<()> {
+        let (data, ts, idx) = self.read_block_ts(block)?;
+        let val = decompress(&data[idx..])?;
+        let (validity, val_buf) = split_validity(&val, ts.len())?;
+        let count = ts.len() - validity.as_ref().map_or(0, |v| v.null_count());
+        let mut cursor = coders::string::StrBlockCursor::new(val_buf, count)
+            .map_err(|e| Error::ReadTsmErr { reason: e.to_string() })?;
+        drop(val);
+        drop(data);
+        for (i, ts) in ts.into_iter().enumerate() {
+            // a null point has no value
+            if validity.as_ref().map_or(false, |v| !v.is_valid(i)) {
+                continue;
+            }
+            let val = match cursor.next_value() {
+                Ok(Some(val)) => val,
+                Ok(None) => {
+                    let reason = format!("fewer values than timestamps in block {:?}", block);
+                    return Err(Error::ReadTsmErr { reason });
+                },
+                Err(e) => return Err(Error::ReadTsmErr { reason: e.to_string() }),
+            };
+            if time_range.contains(ts) && !tombstones.contains(ts) {
+                res.push(DataType::Str(StrCell { ts, val: val.to_vec() }));
+            }
+        }
+        Ok(())
+    }
+
+    /// Decodes the timestamps of a block, the values are never read.
+    pub fn decode_timestamps_only(&mut self, block: &FileBlock) -> Result<Vec<i64>> {
+        // skip the 32-bit CRC checksum at beginning of block
+        let start = block.offset + 4;
+        self.reader
+            .seek(SeekFrom::Start(start))
+            .map_err(|e| Error::ReadTsmErr { reason: e.to_string() })?;
+        let len = match block.val_off.checked_sub(start) {
+            Some(n) if n <= block.size as u64 => n,
+            _ => {
+                let reason = format!("invalid block meta {:?}", block);
+                return Err(Error::ReadTsmErr { reason });
+            },
+        };
+        let mut data: Vec<u8> = vec![0; len as usize];
+        self.reader.read(&mut data).map_err(|e| Error::ReadTsmErr { reason: e.to_string() })?;
+
+        let mut ts = Vec::with_capacity(MAX_BLOCK_VALUES);
+        let data = decompress(&data)?;
+        coders::timestamp::decode_limit(&data, &mut ts, MAX_BLOCK_VALUES)
+            .map_err(|e| Error::ReadTsmErr { reason: e.to_string() })?;
+        Ok(ts)
     }
 }
 
-impl<'a> BlockReader for TsmBlockReader<'a> {
-    fn decode(&mut self, block: &FileBlock) -> Result<DataBlock> {
+/// Reads the blocks of a tsm file by their position in the index rather than by time range.
+pub struct TsmReader<'a> {
+    reader: &'a mut FileCursor,
+    len: usize,
+}
+
+impl<'a> TsmReader<'a> {
+    pub fn new(reader: &'a mut FileCursor, len: usize) -> Self {
+        Self { reader, len }
+    }
+
+    /// Reads the encoded block without decoding it.
+    pub fn read_raw_block(&mut self, block: &FileBlock) -> Result<RawBlock> {
+        let val_start = block.val_off.checked_sub(block.offset).map(|n| n as usize);
+        let val_start = match val_start {
+            Some(n) if n >= 4 && n + 4 <= block.size as usize => n,
+            _ => {
+                let reason = format!("invalid block meta {:?}", block);
+                return Err(Error::ReadTsmErr { reason });
+            },
+        };
+        self.reader
+            .seek(SeekFrom::Start(block.offset))
+            .map_err(|e| Error::ReadTsmErr { reason: e.to_string() })?;
+        let mut data: Vec<u8> = vec![0; block.size as usize];
+        self.reader.read(&mut data).map_err(|e| Error::ReadTsmErr { reason: e.to_string() })?;
+
+        Ok(RawBlock { field_type: block.field_type,
+                      min_ts: block.min_ts,
+                      max_ts: block.max_ts,
+                      may_have_duplicates: block.may_have_duplicates,
+                      ts_crc: decode_be_u32(&data[0..4]),
+                      ts: data[4..val_start].to_vec(),
+                      val_crc: decode_be_u32(&data[val_start..val_start + 4]),
+                      val: data[val_start + 4..].to_vec() })
+    }
+
+    /// Decodes the nth block of the field, counted from 0 in the order of the index.
+    pub fn read_nth_block(&mut self, field_id: FieldId, n: usize) -> Result<DataBlock> {
+        let mut count = 0;
+        let mut found = None;
+        for res in TsmIndexReader::try_new(self.reader, self.len)? {
+            let entry = res?;
+            if entry.field_id() != field_id {
+                continue;
+            }
+            count = entry.count as usize;
+            if entry.curr_block as usize == n + 1 {
+                found = Some(entry.block);
+                break;
+            }
+        }
+        match found {
+            Some(block) => TsmBlockReader::new(self.reader).decode(&block),
+            None => Err(Error::BlockOutOfRange { field_id, n, count }),
+        }
+    }
+
+    /// Returns the points of every field of the file ordered by timestamp, the points of the
+    /// same timestamp by field id. Only the index and the current block of each field are held
+    /// in memory, the blocks are decoded as the points are consumed.
+    pub fn iter_sorted(&mut self) -> Result<SortedDataIter<'_>> {
+        let mut fields: Vec<(FieldId, VecDeque<FileBlock>)> = vec![];
+        for res in TsmIndexReader::try_new(self.reader, self.len)? {
+            let entry = res?;
+            let field_id = entry.field_id();
+            match fields.last_mut() {
+                Some((id, blocks)) if *id == field_id => blocks.push_back(entry.block),
+                _ => fields.push((field_id, VecDeque::from([entry.block]))),
+            }
+        }
+        let mut iter = SortedDataIter { reader: self.reader,
+                                        cursors: fields.iter().map(|_| None).collect(),
+                                        heads: fields.iter().map(|_| None).collect(),
+                                        fields,
+                                        heap: BinaryHeap::new() };
+        for slot in 0..iter.fields.len() {
+            iter.advance(slot)?;
+        }
+        Ok(iter)
+    }
+}
+
+/// The points of every field of a tsm file ordered by timestamp, merged from the blocks of the
+/// fields one block per field at a time. Returned by `TsmReader::iter_sorted`.
+pub struct SortedDataIter<'a> {
+    reader: &'a mut FileCursor,
+    // the blocks of each field not decoded yet, in the order of the index
+    fields: Vec<(FieldId, VecDeque<FileBlock>)>,
+    // the decoded block of each field whose points are being merged
+    cursors: Vec<Option<DataBlock>>,
+    // the next point of each field, None once the field is done
+    heads: Vec<Option<DataType>>,
+    // (timestamp, field id, slot) of the heads
+    heap: BinaryHeap<Reverse<(i64, FieldId, usize)>>,
+}
+
+impl<'a> SortedDataIter<'a> {
+    // moves the head of the field in the slot to its next point, decoding its next block if
+    // the current one is done
+    fn advance(&mut self, slot: usize) -> Result<()> {
+        loop {
+            if let Some(datum) = self.cursors[slot].as_mut().and_then(|block| block.next()) {
+                self.heap.push(Reverse((datum.timestamp(), self.fields[slot].0, slot)));
+                self.heads[slot] = Some(datum);
+                return Ok(());
+            }
+            match self.fields[slot].1.pop_front() {
+                Some(block) => {
+                    self.cursors[slot] = Some(TsmBlockReader::new(self.reader).decode(&block)?)
+                },
+                None => {
+                    self.cursors[slot] = None;
+                    return Ok(());
+                },
+            }
+        }
+    }
+}
+
+impl<'a> Iterator for SortedDataIter<'a> {
+    type Item = Result<(FieldId, DataType)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let Reverse((_, field_id, slot)) = self.heap.pop()?;
+        let datum = self.heads[slot].take()?;
+        if let Err(e) = self.advance(slot) {
+            // the field can not be read any further
+            self.fields[slot].1.clear();
+            return Some(Err(e));
+        }
+        Some(Ok((field_id, datum)))
+    }
+}
+
+// the encoded timestamps or values of a block, decompressed if zstd compressed them
+fn decompress(buf: &[u8]) -> Result<Cow<'_, [u8]>> {
+    coders::compress::decompress(buf).map_err(|e| Error::ReadTsmErr { reason: e.to_string() })
+}
+
+impl<'a> TsmBlockReader<'a> {
+    // reads a block and decodes its timestamps, returns the block, the timestamps and the
+    // offset of the encoded values in the block
+    fn read_block_ts(&mut self, block: &FileBlock) -> Result<(Vec<u8>, Vec<i64>, usize)> {
         self.reader
             .seek(SeekFrom::Start(block.offset))
             .map_err(|e| Error::ReadTsmErr { reason: ("seek tsmblock err".to_string()) })?;
 
+        // the values start after the timestamps, each part begins with a 32-bit crc
+        let val_start = block.val_off.checked_sub(block.offset).map(|n| n as usize);
+        let val_start = match val_start {
+            Some(n) if n >= 4 && n + 4 <= block.size as usize => n,
+            _ => {
+                let reason = format!("invalid block meta {:?}", block);
+                return Err(Error::ReadTsmErr { reason });
+            },
+        };
         let mut data: Vec<u8> = vec![0; block.size as usize];
         self.reader
             .read(&mut data)
             .map_err(|e| Error::ReadTsmErr { reason: ("read tsmblock err".to_string()) })?;
 
         // TODO: skip 32-bit CRC checksum at beginning of block for now
-        let mut idx = 4;
-
-        let len = block.val_off - block.offset - 4; // 32-bit crc
         // first decode the timestamp block.
         let mut ts = Vec::with_capacity(MAX_BLOCK_VALUES); // 1000 is the max block size
-        coders::timestamp::decode(&data[idx..idx+(len as usize)], &mut ts)
-                    .map_err(|e| Error::ReadTsmErr { reason: e.to_string() })?;
-        idx += len as usize;
+        let ts_buf = decompress(&data[4..val_start])?;
+        coders::timestamp::decode_limit(&ts_buf, &mut ts, MAX_BLOCK_VALUES)
+            .map_err(|e| Error::ReadTsmErr { reason: e.to_string() })?;
+        drop(ts_buf);
         //// TODO: skip 32-bit data CRC checksum at beginning of block for now
-        idx += 4;
-        match block.field_type {
-            ValueType::Float => {
-                // values will be same length as time-stamps.
-                let mut val = Vec::with_capacity(ts.len());
-                coders::float::decode(&data[idx..], &mut val)
-                    .map_err(|e| Error::ReadTsmErr { reason: e.to_string() })?;
-
-                Ok(DataBlock::F64 { index: 0, ts, val })
-            },
-            ValueType::Integer => {
-                // values will be same length as time-stamps.
-                let mut val = Vec::with_capacity(ts.len());
-                coders::integer::decode(&data[idx..], &mut val)
-                    .map_err(|e| Error::ReadTsmErr { reason: e.to_string() })?;
+        Ok((data, ts, val_start + 4))
+    }
+}
 
-                Ok(DataBlock::I64 { index: 0, ts, val })
-            },
-            ValueType::Boolean => {
-                // values will be same length as time-stamps.
-                let mut val = Vec::with_capacity(ts.len());
-                coders::boolean::decode(&data[idx..], &mut val)
-                    .map_err(|e| Error::ReadTsmErr { reason: e.to_string() })?;
+impl<'a> BlockReader for TsmBlockReader<'a> {
+    fn decode(&mut self, block: &FileBlock) -> Result<DataBlock> {
+        let (data, ts, idx) = self.read_block_ts(block)?;
+        let buf = decompress(&data[idx..])?;
+        decode_block(block.field_type, ts, &buf)
+    }
+}
 
-                Ok(DataBlock::Bool { index: 0, ts, val })
-            },
-            ValueType::String => {
-                // values will be same length as time-stamps.
-                let mut val = Vec::with_capacity(ts.len());
-                coders::string::decode(&data[idx..], &mut val)
-                    .map_err(|e| Error::ReadTsmErr { reason: e.to_string() })?;
-                Ok(DataBlock::Str { index: 0, ts, val })
-            },
-            ValueType::Unsigned => {
-                // values will be same length as time-stamps.
-                let mut val = Vec::with_capacity(ts.len());
-                coders::unsigned::decode(&data[idx..], &mut val)
-                    .map_err(|e| Error::ReadTsmErr { reason: e.to_string() })?;
-                Ok(DataBlock::U64 { index: 0, ts, val })
-            },
-            _ => {
-                Err(Error::ReadTsmErr { reason: format!("cannot decode block {:?} with no unknown value type",
-                                                        block.field_type) })
-            },
+// decodes the decompressed value slice of a block into a block with the timestamps
+fn decode_block(field_type: ValueType, ts: Vec<i64>, buf: &[u8]) -> Result<DataBlock> {
+    // only the values of the points not null are encoded
+    let (validity, val_buf) = split_validity(buf, ts.len())?;
+    let count = ts.len() - validity.as_ref().map_or(0, |v| v.null_count());
+    match field_type {
+        ValueType::Float => {
+            // values will be same length as time-stamps.
+            let mut val = Vec::with_capacity(ts.len());
+            coders::float::decode_limit(val_buf, &mut val, count)
+                .map_err(|e| Error::ReadTsmErr { reason: e.to_string() })?;
+            let val = with_nulls(val, validity.as_ref())?;
+            Ok(DataBlock::F64 { index: 0, ts, val, validity })
+        },
+        ValueType::Integer => {
+            // values will be same length as time-stamps.
+            let mut val = Vec::with_capacity(ts.len());
+            coders::integer::decode_limit(val_buf, &mut val, count)
+                .map_err(|e| Error::ReadTsmErr { reason: e.to_string() })?;
+            let val = with_nulls(val, validity.as_ref())?;
+            Ok(DataBlock::I64 { index: 0, ts, val, validity })
+        },
+        ValueType::Boolean => {
+            // values will be same length as time-stamps.
+            let mut val = Vec::with_capacity(ts.len());
+            coders::boolean::decode_limit(val_buf, &mut val, count)
+                .map_err(|e| Error::ReadTsmErr { reason: e.to_string() })?;
+            let val = with_nulls(val, validity.as_ref())?;
+            Ok(DataBlock::Bool { index: 0, ts, val, validity })
+        },
+        ValueType::String => {
+            // values will be same length as time-stamps.
+            let mut val = Vec::with_capacity(ts.len());
+            coders::string::decode_limit(val_buf, &mut val, count)
+                .map_err(|e| Error::ReadTsmErr { reason: e.to_string() })?;
+            let val = with_nulls(val, validity.as_ref())?;
+            Ok(DataBlock::Str { index: 0, ts, val, validity })
+        },
+        ValueType::Unsigned => {
+            // values will be same length as time-stamps.
+            let mut val = Vec::with_capacity(ts.len());
+            coders::unsigned::decode_limit(val_buf, &mut val, count)
+                .map_err(|e| Error::ReadTsmErr { reason: e.to_string() })?;
+            let val = with_nulls(val, validity.as_ref())?;
+            Ok(DataBlock::U64 { index: 0, ts, val, validity })
+        },
+        _ => {
+            Err(Error::ReadTsmErr { reason: format!("cannot decode block {:?} with no unknown value type",
+                                                    field_type) })
+        },
+    }
+}
+
+// decodes the values `range` of the value slice of a block, into a block with the timestamps
+// of those values taken from `ts`
+fn decode_values(field_type: ValueType,
+                 buf: &[u8],
+                 mut ts: Vec<i64>,
+                 range: Range<usize>)
+                 -> Result<DataBlock> {
+    let (start, end) = (range.start, range.end);
+    if start >= end && field_type != ValueType::Unknown {
+        return Ok(DataBlock::new(0, field_type));
+    }
+    let val_buf = decompress(buf).map_err(|e| Error::ReadTsmErr { reason: e.to_string() })?;
+    if coders::validity::is_prefixed(&val_buf) {
+        // the values of a block with nulls are not at the positions of their points
+        return Ok(decode_block(field_type, ts, &val_buf)?.slice(start, end));
+    }
+    ts.truncate(end);
+    ts.drain(..start);
+    let count = ts.len();
+    match field_type {
+        ValueType::Float => {
+            let mut val = Vec::with_capacity(count);
+            let res = coders::float::decode_range(&val_buf, &mut val, start, end);
+            check_decoded(res, val.len(), count)?;
+            Ok(DataBlock::F64 { index: 0, ts, val, validity: None })
+        },
+        ValueType::Integer => {
+            let mut val = Vec::with_capacity(count);
+            let res = coders::integer::decode_range(&val_buf, &mut val, start, end);
+            check_decoded(res, val.len(), count)?;
+            Ok(DataBlock::I64 { index: 0, ts, val, validity: None })
+        },
+        ValueType::Boolean => {
+            let mut val = Vec::with_capacity(count);
+            let res = coders::boolean::decode_range(&val_buf, &mut val, start, end);
+            check_decoded(res, val.len(), count)?;
+            Ok(DataBlock::Bool { index: 0, ts, val, validity: None })
+        },
+        ValueType::String => {
+            let mut val = Vec::with_capacity(count);
+            let res = coders::string::decode_range(&val_buf, &mut val, start, end);
+            check_decoded(res, val.len(), count)?;
+            Ok(DataBlock::Str { index: 0, ts, val, validity: None })
+        },
+        ValueType::Unsigned => {
+            let mut val = Vec::with_capacity(count);
+            let res = coders::unsigned::decode_range(&val_buf, &mut val, start, end);
+            check_decoded(res, val.len(), count)?;
+            Ok(DataBlock::U64 { index: 0, ts, val, validity: None })
+        },
+        _ => {
+            let reason = format!("cannot decode block with unknown value type {:?}", field_type);
+            Err(Error::ReadTsmErr { reason })
+        },
+    }
+}
+
+// fails if a value decoder failed or decoded another count of values than the timestamps
+fn check_decoded(res: std::result::Result<(), Box<dyn std::error::Error>>,
+                 len: usize,
+                 count: usize)
+                 -> Result<()> {
+    res.map_err(|e| Error::ReadTsmErr { reason: e.to_string() })?;
+    if len != count {
+        let reason = format!("{} values for {} timestamps in block", len, count);
+        return Err(Error::ReadTsmErr { reason });
+    }
+    Ok(())
+}
+
+// splits the validity bits off the decompressed value slice of a block of `len` points,
+// returns the validity if the block has null points and the values of the other points
+fn split_validity(buf: &[u8], len: usize) -> Result<(Option<Validity>, &[u8])> {
+    let (validity, val_buf) =
+        coders::validity::decode(buf).map_err(|e| Error::ReadTsmErr { reason: e.to_string() })?;
+    match validity {
+        Some((n, _)) if n != len => {
+            let reason = format!("{} validity bits for {} timestamps in block", n, len);
+            Err(Error::ReadTsmErr { reason })
+        },
+        Some((n, bits)) => Ok((Some(Validity::from_bits(bits, n)), val_buf)),
+        None => Ok((None, val_buf)),
+    }
+}
+
+// puts the decoded values of the points not null at their points, the null points get the
+// default value
+fn with_nulls<T: Default>(val: Vec<T>, validity: Option<&Validity>) -> Result<Vec<T>> {
+    let validity = match validity {
+        Some(v) => v,
+        None => return Ok(val),
+    };
+    let count = validity.len() - validity.null_count();
+    if val.len() != count {
+        let reason = format!("{} values for {} points not null in block", val.len(), count);
+        return Err(Error::ReadTsmErr { reason });
+    }
+    let mut val = val.into_iter();
+    let mut res = Vec::with_capacity(validity.len());
+    for i in 0..validity.len() {
+        let v = if validity.is_valid(i) { val.next() } else { None };
+        res.push(v.unwrap_or_default());
+    }
+    Ok(res)
+}
+
+pub struct TsmFooterReader {}
+
+impl TsmFooterReader {
+    pub fn read_index_offset(r: &mut FileCursor, len: usize) -> Result<u64> {
+        Self::check_len(len)?;
+        let mut buf = [0u8; 8];
+        r.seek(SeekFrom::Start((len - 8) as u64))
+         .map_err(|e| Error::ReadTsmErr { reason: e.to_string() })?;
+        r.read(&mut buf).map_err(|e| Error::ReadTsmErr { reason: e.to_string() })?;
+        Ok(u64::from_be_bytes(buf))
+    }
+
+    pub fn read_bloom_filter(r: &mut FileCursor, len: usize) -> Result<BloomFilter> {
+        Self::check_len(len)?;
+        let mut buf = vec![0u8; BLOOM_FILTER_SIZE];
+        r.seek(SeekFrom::Start((len - FOOTER_SIZE) as u64))
+         .map_err(|e| Error::ReadTsmErr { reason: e.to_string() })?;
+        r.read(&mut buf).map_err(|e| Error::ReadTsmErr { reason: e.to_string() })?;
+        Ok(BloomFilter::with_data(&buf))
+    }
+
+    /// Reads the sorted ids of the fields in the file, returns None if the file was written
+    /// without them.
+    pub fn read_field_ids(r: &mut FileCursor, len: usize) -> Result<Option<Vec<FieldId>>> {
+        let index_offset = Self::read_index_offset(r, len)?;
+        let fields_len = Self::fields_len(r, len, index_offset)?;
+        if fields_len == 0 {
+            return Ok(None);
+        }
+        let features_len = Self::features(r, len, index_offset)?.map_or(0, |_| FEATURES_SIZE);
+        let mut buf = vec![0u8; fields_len - 8];
+        r.seek(SeekFrom::Start((len - FOOTER_SIZE - features_len - fields_len) as u64))
+         .map_err(|e| Error::ReadTsmErr { reason: e.to_string() })?;
+        r.read(&mut buf).map_err(|e| Error::ReadTsmErr { reason: e.to_string() })?;
+        Ok(Some(buf.chunks_exact(8).map(decode_be_u64).collect()))
+    }
+
+    /// Reads the format features of the file, the file was written without them if None.
+    pub fn read_features(r: &mut FileCursor, len: usize) -> Result<Option<FeatureBits>> {
+        let index_offset = Self::read_index_offset(r, len)?;
+        Self::features(r, len, index_offset)
+    }
+
+    /// Returns the features section between the fields and the footer, None if the file has
+    /// none.
+    fn features(r: &mut FileCursor, len: usize, index_offset: u64) -> Result<Option<FeatureBits>> {
+        // the section can not overlap the index
+        if ((FEATURES_SIZE + FOOTER_SIZE) as u64) > (len as u64).saturating_sub(index_offset) {
+            return Ok(None);
         }
+        let mut buf = [0u8; FEATURES_SIZE];
+        r.seek(SeekFrom::Start((len - FOOTER_SIZE - FEATURES_SIZE) as u64))
+         .map_err(|e| Error::ReadTsmErr { reason: e.to_string() })?;
+        r.read(&mut buf).map_err(|e| Error::ReadTsmErr { reason: e.to_string() })?;
+        if decode_be_u32(&buf[8..12]) != FEATURES_MAGIC {
+            return Ok(None);
+        }
+        Ok(Some(FeatureBits::new(decode_be_u32(&buf[0..4]), decode_be_u32(&buf[4..8]))))
+    }
+
+    fn check_len(len: usize) -> Result<()> {
+        if len < FOOTER_SIZE {
+            return Err(Error::ReadTsmErr { reason: format!("file of {} bytes has no footer",
+                                                           len) });
+        }
+        Ok(())
+    }
+
+    /// Returns the length of the fields section between the index and the features, 0 if the
+    /// file has none.
+    fn fields_len(r: &mut FileCursor, len: usize, index_offset: u64) -> Result<usize> {
+        let features_len = Self::features(r, len, index_offset)?.map_or(0, |_| FEATURES_SIZE);
+        if len < FOOTER_SIZE + features_len + 8 {
+            return Ok(0);
+        }
+        let end = len - FOOTER_SIZE - features_len;
+        let mut buf = [0u8; 8];
+        r.seek(SeekFrom::Start((end - 8) as u64))
+         .map_err(|e| Error::ReadTsmErr { reason: e.to_string() })?;
+        r.read(&mut buf).map_err(|e| Error::ReadTsmErr { reason: e.to_string() })?;
+        if decode_be_u32(&buf[4..8]) != FIELDS_MAGIC {
+            return Ok(0);
+        }
+        let fields_len = decode_be_u32(&buf[0..4]) as usize * 8 + 8;
+        // the section can not overlap the index
+        if fields_len as u64 > (end as u64).saturating_sub(index_offset) {
+            return Ok(0);
+        }
+        Ok(fields_len)
     }
 }
 
@@ -145,6 +697,8 @@ pub struct TsmIndexReader<'a> {
     r: &'a mut FileCursor,
     buf: [u8; 8],
 
+    // the blocks are written before the index
+    index_offset: u64,
     curr_offset: u64,
     end_offset: u64,
 
@@ -154,17 +708,28 @@ pub struct TsmIndexReader<'a> {
 
 impl<'a> TsmIndexReader<'a> {
     pub fn try_new(r: &'a mut FileCursor, len: usize) -> Result<Self> {
-        r.seek(SeekFrom::End(-8)).map_err(|e| Error::ReadTsmErr { reason: e.to_string() })?;
-        let mut buf = [0u8; 8];
-        r.read(&mut buf).map_err(|e| Error::ReadTsmErr { reason: e.to_string() })?;
-        let index_offset = u64::from_be_bytes(buf);
+        let index_offset = TsmFooterReader::read_index_offset(r, len)?;
+        // refuse the files written with the features this binary can not read
+        let features = TsmFooterReader::features(r, len, index_offset)?;
+        if let Some(features) = features {
+            features.check()?;
+        }
+        let features_len = features.map_or(0, |_| FEATURES_SIZE);
+        let fields_len = TsmFooterReader::fields_len(r, len, index_offset)?;
+        let end_offset = (len - FOOTER_SIZE - features_len - fields_len) as u64;
+        if index_offset > end_offset {
+            let reason =
+                format!("index offset {} is beyond the index end {}", index_offset, end_offset);
+            return Err(Error::ReadTsmErr { reason });
+        }
         r.seek(SeekFrom::Start(index_offset))
          .map_err(|e| Error::ReadTsmErr { reason: e.to_string() })?;
 
         Ok(Self { r,
-                  buf,
+                  buf: [0u8; 8],
+                  index_offset,
                   curr_offset: index_offset,
-                  end_offset: (len - FOOTER_SIZE) as u64,
+                  end_offset,
                   curr: None,
                   next: None })
     }
@@ -180,17 +745,22 @@ impl<'a> TsmIndexReader<'a> {
             .read(&mut self.buf[..3])
             .map_err(|e| Error::ReadTsmErr { reason: e.to_string() })?;
         self.curr_offset += 3;
-        let block_type = ValueType::from(self.buf[0]);
+        let block_type = ValueType::from(self.buf[0] & !DUPLICATES_FLAG);
+        let may_have_duplicates = self.buf[0] & DUPLICATES_FLAG != 0;
         let count = decode_be_u16(&self.buf[1..3]);
 
         Ok(IndexEntry { key: field_id,
                         block_type,
                         count,
                         curr_block: 1,
-                        block: self.next_block_entry(block_type)? })
+                        may_have_duplicates,
+                        block: self.next_block_entry(block_type, may_have_duplicates)? })
     }
 
-    fn next_block_entry(&mut self, field_type: ValueType) -> Result<FileBlock> {
+    fn next_block_entry(&mut self,
+                        field_type: ValueType,
+                        may_have_duplicates: bool)
+                        -> Result<FileBlock> {
         // read min time on block entry
         self.r.read(&mut self.buf[..]).map_err(|e| Error::ReadTsmErr { reason: e.to_string() })?;
         self.curr_offset += 8;
@@ -216,13 +786,24 @@ impl<'a> TsmIndexReader<'a> {
         self.curr_offset += 8;
         let val_off = u64::from_be_bytes(self.buf);
 
+        // a corrupted entry must not make the block readers allocate past the file
+        let block_end = offset.checked_add(size).filter(|end| *end <= self.index_offset);
+        let val_start =
+            val_off.checked_sub(offset).filter(|n| *n >= 4 && *n <= size.saturating_sub(4));
+        if block_end.is_none() || val_start.is_none() {
+            let reason = format!("invalid block entry at offset {} of size {} with values at {}",
+                                 offset, size, val_off);
+            return Err(Error::ReadTsmErr { reason });
+        }
+
         Ok(FileBlock { min_ts,
                        max_ts,
                        offset,
                        field_type,
                        size: size as u64,
                        val_off,
-                       reader_idx: 0 })
+                       reader_idx: 0,
+                       may_have_duplicates })
     }
 }
 
@@ -230,7 +811,7 @@ impl<'a> Iterator for TsmIndexReader<'a> {
     type Item = Result<IndexEntry>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.curr_offset == self.end_offset {
+        if self.curr_offset >= self.end_offset {
             // end of entries
             return None;
         }
@@ -239,7 +820,7 @@ impl<'a> Iterator for TsmIndexReader<'a> {
             Some(curr) => {
                 if curr.curr_block < curr.count {
                     let mut next = curr.clone();
-                    match self.next_block_entry(next.block_type) {
+                    match self.next_block_entry(next.block_type, next.may_have_duplicates) {
                         Ok(block) => next.block = block,
                         Err(e) => return Some(Err(e)),
                     }
@@ -0,0 +1,158 @@
+use std::{
+    collections::{hash_map, HashMap},
+    path::Path,
+};
+
+use models::FieldId;
+
+use crate::{
+    direct_io::IoClass,
+    error::{Error, Result},
+    file_manager::get_file_manager,
+    tsm::{IndexEntry, TsmFooterReader, TsmIndexReader, TsmReader, TsmWriter},
+};
+
+/// Writes the tsm file `src` into a new file `dst` with the field ids changed by `map`, the
+/// fields not in the map keep their ids. The blocks are copied verbatim, only the index, the
+/// fields and the bloom filter are written again. Returns the size of the new file.
+///
+/// Fails with `Error::DuplicateFieldId` before writing anything if two fields of the file
+/// would end up with the same id. The tombstones of `src` are not rewritten.
+pub fn remap_field_ids(src: impl AsRef<Path>,
+                       dst: impl AsRef<Path>,
+                       map: HashMap<FieldId, FieldId>)
+                       -> Result<u64> {
+    let (src, dst) = (src.as_ref(), dst.as_ref());
+    if src == dst {
+        let reason = format!("cannot rewrite {} into itself", src.display());
+        return Err(Error::WriteTsmErr { reason });
+    }
+    let file = get_file_manager().open_file(src)?;
+    let len = file.len() as usize;
+    let mut cursor = file.into_cursor();
+    cursor.set_io_class(IoClass::Low);
+    let mut entries: Vec<IndexEntry> = vec![];
+    for entry in TsmIndexReader::try_new(&mut cursor, len)? {
+        entries.push(entry?);
+    }
+
+    // the target id of every field of the file, checked before the new file is created
+    let mut targets: HashMap<FieldId, FieldId> = HashMap::new();
+    for entry in entries.iter() {
+        let from = entry.field_id();
+        targets.insert(from, map.get(&from).copied().unwrap_or(from));
+    }
+    let mut sources: HashMap<FieldId, FieldId> = HashMap::with_capacity(targets.len());
+    for (from, to) in targets.iter() {
+        match sources.entry(*to) {
+            hash_map::Entry::Vacant(e) => {
+                e.insert(*from);
+            },
+            hash_map::Entry::Occupied(e) => {
+                let (from, other) = (*from.min(e.get()), *from.max(e.get()));
+                return Err(Error::DuplicateFieldId { from, other, to: *to });
+            },
+        }
+    }
+
+    let features = TsmFooterReader::read_features(&mut cursor, len)?;
+    let mut writer = TsmWriter::create(dst, IoClass::Low)?;
+    if let Some(features) = features {
+        writer.require(features.required);
+    }
+    let mut reader = TsmReader::new(&mut cursor, len);
+    for entry in entries.iter() {
+        let block = reader.read_raw_block(&entry.block)?;
+        writer.write_raw(targets[&entry.field_id()], &block)?;
+    }
+    writer.finish()
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::remap_field_ids;
+    use crate::{
+        direct_io::IoClass,
+        error::Error,
+        file_manager::{self, get_file_manager},
+        kv_option::BlockCompression,
+        tsm::{DataBlock, TsmFooterReader, TsmReader, TsmWriter},
+    };
+
+    #[test]
+    fn test_remap_field_ids() {
+        let dir = "/tmp/test/tsm_rewrite";
+        let _ = std::fs::remove_dir_all(dir);
+        std::fs::create_dir_all(dir).unwrap();
+        let (src, dst) = (format!("{}/src.tsm", dir), format!("{}/dst.tsm", dir));
+        let block = |n: i64| DataBlock::I64 { index: 0,
+                                              ts: (0..n).collect(),
+                                              val: vec![n; n as usize],
+                                              validity: None };
+        let mut writer = TsmWriter::create(&src, IoClass::High).unwrap();
+        // field 1 is written in 3 blocks
+        writer.write_chunks(HashMap::from([(1, vec![block(2500)]),
+                                           (2, vec![block(20)]),
+                                           (3, vec![block(30)])]),
+                            BlockCompression::Zstd(3))
+              .unwrap();
+        writer.finish().unwrap();
+
+        // 1 and 2 are swapped, 3 is left as it is
+        let map = HashMap::from([(1, 2), (2, 1), (7, 8)]);
+        remap_field_ids(&src, &dst, map).unwrap();
+
+        let file = get_file_manager().open_file(&dst).unwrap();
+        let len = file.len() as usize;
+        let mut cursor = file.into_cursor();
+        assert_eq!(TsmFooterReader::read_field_ids(&mut cursor, len).unwrap(), Some(vec![1, 2, 3]));
+        let bloom_filter = TsmFooterReader::read_bloom_filter(&mut cursor, len).unwrap();
+        assert!(bloom_filter.contains(&1_u64.to_be_bytes()[..]));
+        let src_features = {
+            let file = get_file_manager().open_file(&src).unwrap();
+            let len = file.len() as usize;
+            TsmFooterReader::read_features(&mut file.into_cursor(), len).unwrap()
+        };
+        assert_eq!(TsmFooterReader::read_features(&mut cursor, len).unwrap(), src_features);
+
+        let mut reader = TsmReader::new(&mut cursor, len);
+        assert_eq!(reader.read_nth_block(2, 0).unwrap(),
+                   DataBlock::I64 { index: 0,
+                                    ts: (0..500).collect(),
+                                    val: vec![2500; 500],
+                                    validity: None });
+        assert_eq!(reader.read_nth_block(2, 2).unwrap(),
+                   DataBlock::I64 { index: 0,
+                                    ts: (1500..2500).collect(),
+                                    val: vec![2500; 1000],
+                                    validity: None });
+        assert_eq!(reader.read_nth_block(1, 0).unwrap(), block(20));
+        assert_eq!(reader.read_nth_block(3, 0).unwrap(), block(30));
+    }
+
+    #[test]
+    fn test_remap_duplicate_field_ids() {
+        let dir = "/tmp/test/tsm_rewrite_duplicate";
+        let _ = std::fs::remove_dir_all(dir);
+        std::fs::create_dir_all(dir).unwrap();
+        let (src, dst) = (format!("{}/src.tsm", dir), format!("{}/dst.tsm", dir));
+        let block = DataBlock::I64 { index: 0, ts: vec![1, 2], val: vec![1, 2], validity: None };
+        let mut writer = TsmWriter::create(&src, IoClass::High).unwrap();
+        writer.write_chunks(HashMap::from([(1, vec![block.clone()]), (2, vec![block])]),
+                            BlockCompression::Fast)
+              .unwrap();
+        writer.finish().unwrap();
+
+        // 1 is mapped onto 2, which keeps its id
+        let res = remap_field_ids(&src, &dst, HashMap::from([(1, 2)]));
+        assert!(matches!(res, Err(Error::DuplicateFieldId { from: 1, other: 2, to: 2 })));
+        // two fields mapped onto the same id
+        let res = remap_field_ids(&src, &dst, HashMap::from([(1, 5), (2, 5)]));
+        assert!(matches!(res, Err(Error::DuplicateFieldId { from: 1, other: 2, to: 5 })));
+        assert!(!file_manager::try_exists(&dst));
+        // a file cannot be rewritten in place
+        assert!(remap_field_ids(&src, &src, HashMap::from([(1, 3)])).is_err());
+    }
+}
@@ -0,0 +1,152 @@
+use std::collections::{BTreeMap, HashMap};
+
+use models::ValueType;
+use parking_lot::Mutex;
+
+use super::{coders, DataBlock};
+
+/// The blocks of a value type written into tsm files: their points before and after they are
+/// encoded, and how many blocks each encoding of the values was chosen for.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct TypeCompression {
+    pub blocks: u64,
+    pub points: u64,
+    // 8 bytes a timestamp, the values as they are in memory
+    pub raw_bytes: u64,
+    // the timestamps and the values as written, with the checksums and after zstd
+    pub encoded_bytes: u64,
+    // the blocks whose values zstd made smaller
+    pub zstd_blocks: u64,
+    pub encodings: BTreeMap<&'static str, u64>,
+}
+
+impl TypeCompression {
+    /// Returns the encoded bytes over the raw bytes, 0 if nothing was written.
+    pub fn ratio(&self) -> f64 {
+        if self.raw_bytes == 0 {
+            return 0.0;
+        }
+        self.encoded_bytes as f64 / self.raw_bytes as f64
+    }
+
+    fn merge(&mut self, other: &TypeCompression) {
+        self.blocks += other.blocks;
+        self.points += other.points;
+        self.raw_bytes += other.raw_bytes;
+        self.encoded_bytes += other.encoded_bytes;
+        self.zstd_blocks += other.zstd_blocks;
+        for (encoding, blocks) in other.encodings.iter() {
+            *self.encodings.entry(*encoding).or_insert(0) += blocks;
+        }
+    }
+}
+
+/// The compression of the blocks written into tsm files, by value type.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct CompressionStats {
+    types: Vec<(ValueType, TypeCompression)>,
+}
+
+impl CompressionStats {
+    /// Returns the compression of the blocks of the value type, None if none was written.
+    pub fn of(&self, value_type: ValueType) -> Option<&TypeCompression> {
+        self.types.iter().find(|(t, _)| *t == value_type).map(|(_, c)| c)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &(ValueType, TypeCompression)> {
+        self.types.iter()
+    }
+
+    pub fn merge(&mut self, other: &CompressionStats) {
+        for (value_type, compression) in other.types.iter() {
+            self.entry(*value_type).merge(compression);
+        }
+    }
+
+    /// Records the block of the points in [start, end) written as `ts_buf` and `val_buf`, the
+    /// values encoded by `encoding`, see `value_encoding`.
+    pub(crate) fn record(&mut self,
+                         block: &DataBlock,
+                         start: usize,
+                         end: usize,
+                         encoding: &'static str,
+                         ts_buf: &[u8],
+                         val_buf: &[u8]) {
+        let field_type = block.field_type();
+        let n = (end - start) as u64;
+        let raw_values = match block {
+            DataBlock::Str { val, .. } => val[start..end].iter().map(|s| s.len() as u64).sum(),
+            DataBlock::Bool { .. } => n,
+            _ => n * 8,
+        };
+        let compression = self.entry(field_type);
+        compression.blocks += 1;
+        compression.points += n;
+        compression.raw_bytes += n * 8 + raw_values;
+        compression.encoded_bytes += (ts_buf.len() + val_buf.len() + 8) as u64;
+        if coders::compress::is_compressed(val_buf) {
+            compression.zstd_blocks += 1;
+        }
+        *compression.encodings.entry(encoding).or_insert(0) += 1;
+    }
+
+    fn entry(&mut self, value_type: ValueType) -> &mut TypeCompression {
+        match self.types.iter().position(|(t, _)| *t == value_type) {
+            Some(i) => &mut self.types[i].1,
+            None => {
+                self.types.push((value_type, TypeCompression::default()));
+                &mut self.types.last_mut().unwrap().1
+            },
+        }
+    }
+}
+
+/// Returns the name of the encoding a coder chose for the values of a block, from the tag in
+/// the 4 high bits of the first byte of the slice it wrote, behind the validity bits if any.
+/// The slice must not be compressed by zstd yet.
+pub fn value_encoding(field_type: ValueType, values: &[u8]) -> &'static str {
+    let values = match coders::validity::decode(values) {
+        Ok((_, values)) => values,
+        Err(_) => return "unknown",
+    };
+    let tag = match values.first() {
+        Some(b) => b >> 4,
+        None => return "empty",
+    };
+    match (field_type, tag) {
+        (ValueType::Integer | ValueType::Unsigned | ValueType::Float, 0) => "uncompressed",
+        (ValueType::Integer | ValueType::Unsigned, 1) => "simple8b",
+        (ValueType::Integer | ValueType::Unsigned, 2) => "rle",
+        (ValueType::Float, 1) => "gorilla",
+        (ValueType::Boolean, 1) => "bitpacked",
+        (ValueType::String, 1) => "snappy",
+        _ => "unknown",
+    }
+}
+
+/// The compression of the blocks written by the flushes and the compactions of every tseries
+/// family since the database was opened.
+#[derive(Debug, Default)]
+pub struct CompressionMetrics {
+    families: Mutex<HashMap<u32, CompressionStats>>,
+}
+
+impl CompressionMetrics {
+    pub fn record(&self, tf_id: u32, stats: &CompressionStats) {
+        self.families.lock().entry(tf_id).or_default().merge(stats);
+    }
+
+    /// Returns the compression of the blocks written for the tseries family.
+    pub fn of(&self, tf_id: u32) -> CompressionStats {
+        self.families.lock().get(&tf_id).cloned().unwrap_or_default()
+    }
+
+    /// Returns the compression of the blocks written for every tseries family.
+    pub fn totals(&self) -> CompressionStats {
+        let mut totals = CompressionStats::default();
+        for stats in self.families.lock().values() {
+            totals.merge(stats);
+        }
+        totals
+    }
+}
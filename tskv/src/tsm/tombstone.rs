@@ -1,5 +1,5 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     fmt::write,
     io::SeekFrom,
     path::{Path, PathBuf},
@@ -11,7 +11,7 @@ use models::{FieldId, SeriesId, Timestamp, ValueType};
 use parking_lot::{Mutex, RwLock};
 use snafu::ResultExt;
 
-use super::{FileBlock, IndexEntry, TsmIndexReader};
+use super::{DataBlock, FileBlock, IndexEntry, TsmIndexReader};
 use crate::{
     byte_utils,
     direct_io::{File, FileCursor, FileSync},
@@ -21,27 +21,65 @@ use crate::{
 };
 
 const TOMBSTONE_FILE_SUFFI: &str = ".tombstone";
+// The extension of a tombstone file being rewritten by `TsmTombstone::compact`.
+const COMPACTING_EXT: &str = "compacting";
 const TOMBSTONE_MAGIC: u32 = 0x544F4D42;
+// The header of the files of framed segments, "TOM2".
+const TOMBSTONE_MAGIC_V2: u32 = 0x544F4D32;
+const HEADER_SIZE: usize = 4;
+const SEGMENT_HEADER_SIZE: usize = 8;
+
+const RECORD_FIELD: u8 = 0;
+const RECORD_FIELD_RANGE: u8 = 1;
+const RECORD_SEQ: u8 = 2;
+// The smallest record, of a field.
+const RECORD_MIN_SIZE: usize = 25;
+
+/// A tombstone file holding more records than this is rewritten by `TsmTombstone::compact`.
+const COMPACT_RECORDS: usize = 1024;
+/// A tombstone file larger than this is rewritten by `TsmTombstone::compact`.
+const COMPACT_BYTES: u64 = 64 * 1024;
 
 #[derive(Debug, Clone, Copy)]
 pub struct Tombstone {
     pub field_id: FieldId,
-    pub min_ts: Timestamp,
-    pub max_ts: Timestamp,
+    pub range: TimeRange,
+}
+
+/// The deleted time range of the fields whose ids are in [field_id_start, field_id_end].
+#[derive(Debug, Clone, Copy)]
+pub struct FieldRangeTombstone {
+    pub field_id_start: FieldId,
+    pub field_id_end: FieldId,
+    pub range: TimeRange,
 }
 
 /// Tombstones for a tsm file
 ///
 /// - file_name: _%06d.tombstone
-/// - header: b"TOMB" 4 bytes
-/// - loop begin
-/// - - field_id: u64 8 bytes
-/// - - min: i64 8 bytes
-/// - - max: i64 8 bytes
+/// - header: b"TOM2" 4 bytes
+/// - loop begin, a segment written at once
+/// - - len: u32 4 bytes, the length of the records
+/// - - crc: u32 4 bytes, the crc of the records
+/// - - optional, kind: u8 1 byte 2, seq: u64 8 bytes, the wal sequence of the deletes
+/// - - loop begin
+/// - - - kind: u8 1 byte, 0 for a field, 1 for a range of field ids
+/// - - - field_id: u64 8 bytes, or field_id_start and field_id_end: u64 16 bytes
+/// - - - min: i64 8 bytes
+/// - - - max: i64 8 bytes
+/// - - loop end
 /// - loop end
+///
+/// The files with the header b"TOMB" hold the records of a field without any segment.
+/// The segments without a wal sequence are older than any point-in-time restore.
 pub struct TsmTombstone {
     path: PathBuf,
+    // false for the files with the header b"TOMB"
+    framed: bool,
     tombstones: RwLock<Vec<Tombstone>>,
+    range_tombstones: RwLock<Vec<FieldRangeTombstone>>,
+    // the largest wal sequence of the loaded segments, 0 if none has one
+    max_seq: RwLock<u64>,
     file_cursor: Mutex<FileCursor>,
 }
 
@@ -54,88 +92,290 @@ impl TsmTombstone {
         }
         let file = file_manager::get_file_manager().open_create_file(&tombstone_path)?;
         let mut file_cursor = file.into_cursor();
-        if is_new {
+        let framed = if is_new || file_cursor.len() < HEADER_SIZE as u64 {
             Self::write_header_to(&mut file_cursor)?;
             file_cursor.sync_data(FileSync::Hard).context(error::IOSnafu)?;
-        }
+            true
+        } else {
+            let mut header = [0_u8; HEADER_SIZE];
+            file_cursor.read(&mut header).context(error::ReadFileSnafu)?;
+            byte_utils::decode_be_u32(&header) != TOMBSTONE_MAGIC
+        };
 
         Ok(Self { path: tombstone_path,
+                  framed,
                   tombstones: RwLock::new(vec![]),
+                  range_tombstones: RwLock::new(vec![]),
+                  max_seq: RwLock::new(0),
                   file_cursor: Mutex::new(file_cursor) })
     }
 
     pub fn load(&self) -> Result<()> {
+        self.load_until(u64::MAX)
+    }
+
+    /// Loads the tombstones, the segments of a wal sequence after `end_seq` are skipped.
+    pub fn load_until(&self, end_seq: u64) -> Result<()> {
         let mut file_cursor = self.file_cursor.lock();
         let mut tombstones = self.tombstones.write();
+        let mut range_tombstones = self.range_tombstones.write();
+        let mut max_seq = self.max_seq.write();
         tombstones.truncate(0);
-        let file_len = file_cursor.len();
+        range_tombstones.truncate(0);
+        *max_seq = 0;
 
-        const HEADER_SIZE: usize = 4;
-        let mut header = vec![0_u8; HEADER_SIZE];
-        // TODO: unable to read tombstone file
-        file_cursor.read(&mut header).context(error::ReadFileSnafu)?;
+        let buf = Self::read_all(&mut file_cursor)?;
+        if buf.len() < HEADER_SIZE {
+            return Ok(());
+        }
+        if !self.framed {
+            for rec in buf[HEADER_SIZE..].chunks_exact(24) {
+                let field_id = byte_utils::decode_be_u64(&rec[0..8]);
+                let min_ts = byte_utils::decode_be_i64(&rec[8..16]);
+                let max_ts = byte_utils::decode_be_i64(&rec[16..24]);
+                tombstones.push(Tombstone { field_id, range: TimeRange::new(max_ts, min_ts) });
+            }
+            return Ok(());
+        }
 
-        const BUF_SIZE: usize = 1024 * 64;
-        let (mut buf, buf_len) = if file_len < BUF_SIZE as u64 {
-            let buf_len = (file_len - 4) as usize;
-            (vec![0_u8; buf_len], buf_len)
-        } else {
-            (vec![0_u8; BUF_SIZE], BUF_SIZE)
-        };
+        for (start, end) in Self::segments(&buf) {
+            let records = &buf[start + SEGMENT_HEADER_SIZE..end];
+            let seq = Self::segment_seq(records);
+            if seq > end_seq {
+                continue;
+            }
+            *max_seq = (*max_seq).max(seq);
+            Self::decode_segment(records, &mut tombstones, &mut range_tombstones)?;
+        }
+
+        Ok(())
+    }
+
+    /// Drops the segments of a wal sequence after `end_seq` from the file.
+    pub fn retain_until(&self, end_seq: u64) -> Result<()> {
+        if !self.framed {
+            return Ok(());
+        }
+        let mut file_cursor = self.file_cursor.lock();
+        let buf = Self::read_all(&mut file_cursor)?;
+        if buf.len() < HEADER_SIZE {
+            return Ok(());
+        }
+        let mut kept = buf[..HEADER_SIZE].to_vec();
+        for (start, end) in Self::segments(&buf) {
+            if Self::segment_seq(&buf[start + SEGMENT_HEADER_SIZE..end]) <= end_seq {
+                kept.extend_from_slice(&buf[start..end]);
+            }
+        }
+        if kept.len() == buf.len() {
+            return Ok(());
+        }
+        file_cursor.seek(SeekFrom::Start(0))
+                   .and_then(|_| file_cursor.write(&kept))
+                   .context(error::IOSnafu)?;
+        file_cursor.set_len(kept.len() as u64);
+        file_cursor.sync_all(FileSync::Hard).context(error::IOSnafu)
+    }
+
+    fn read_all(file_cursor: &mut FileCursor) -> Result<Vec<u8>> {
+        let mut buf = vec![0_u8; file_cursor.len() as usize];
+        file_cursor.seek(SeekFrom::Start(0)).context(error::ReadFileSnafu)?;
+        file_cursor.read(&mut buf).context(error::ReadFileSnafu)?;
+        Ok(buf)
+    }
+
+    // returns the (start, end) of the intact segments of a framed file
+    fn segments(buf: &[u8]) -> Vec<(usize, usize)> {
+        let mut segments = vec![];
         let mut pos = HEADER_SIZE;
-        while pos < buf_len {
-            file_cursor.seek(SeekFrom::Start(pos as u64)).context(error::ReadFileSnafu)?;
-            file_cursor.read(&mut buf).context(error::ReadFileSnafu)?;
-            let mut buf_pos = 0;
-            while buf_pos < buf_len {
-                let field_id = byte_utils::decode_be_u64(&buf[buf_pos..buf_pos + 8]);
-                buf_pos += 8;
-                let min = byte_utils::decode_be_i64(&buf[buf_pos..buf_pos + 8]);
-                buf_pos += 8;
-                let max = byte_utils::decode_be_i64(&buf[buf_pos..buf_pos + 8]);
-                buf_pos += 8;
-                let tombstone = Tombstone { field_id, min_ts: min, max_ts: max };
-                tombstones.push(tombstone);
-            }
-            pos += buf_len
+        while pos + SEGMENT_HEADER_SIZE <= buf.len() {
+            let len = byte_utils::decode_be_u32(&buf[pos..pos + 4]) as usize;
+            let crc = byte_utils::decode_be_u32(&buf[pos + 4..pos + 8]);
+            let start = pos + SEGMENT_HEADER_SIZE;
+            // a segment torn by a crash is ignored with the ones after it
+            if start + len > buf.len() || crc32fast::hash(&buf[start..start + len]) != crc {
+                break;
+            }
+            segments.push((pos, start + len));
+            pos = start + len;
         }
+        segments
+    }
 
+    // returns the wal sequence of the records of a segment, 0 if they have none
+    fn segment_seq(records: &[u8]) -> u64 {
+        match records.first() {
+            Some(&RECORD_SEQ) if records.len() >= 9 => byte_utils::decode_be_u64(&records[1..9]),
+            _ => 0,
+        }
+    }
+
+    fn decode_segment(mut buf: &[u8],
+                      tombstones: &mut Vec<Tombstone>,
+                      range_tombstones: &mut Vec<FieldRangeTombstone>)
+                      -> Result<()> {
+        let i64_at = |buf: &[u8], pos: usize| byte_utils::decode_be_i64(&buf[pos..pos + 8]);
+        let u64_at = |buf: &[u8], pos: usize| byte_utils::decode_be_u64(&buf[pos..pos + 8]);
+        while !buf.is_empty() {
+            match buf[0] {
+                RECORD_SEQ if buf.len() >= 9 => {
+                    buf = &buf[9..];
+                },
+                RECORD_FIELD if buf.len() >= 25 => {
+                    let range = TimeRange::new(i64_at(buf, 17), i64_at(buf, 9));
+                    tombstones.push(Tombstone { field_id: u64_at(buf, 1), range });
+                    buf = &buf[25..];
+                },
+                RECORD_FIELD_RANGE if buf.len() >= 33 => {
+                    let range = TimeRange::new(i64_at(buf, 25), i64_at(buf, 17));
+                    range_tombstones.push(FieldRangeTombstone { field_id_start: u64_at(buf, 1),
+                                                                field_id_end: u64_at(buf, 9),
+                                                                range });
+                    buf = &buf[33..];
+                },
+                _ => {
+                    let reason = "invalid tombstone record".to_string();
+                    return Err(Error::ReadTsmErr { reason });
+                },
+            }
+        }
         Ok(())
     }
 
     fn write_header_to(writer: &mut FileCursor) -> Result<()> {
         writer.seek(SeekFrom::Start(0))
-              .and_then(|_| writer.write(&TOMBSTONE_MAGIC.to_be_bytes()[..]))
+              .and_then(|_| writer.write(&TOMBSTONE_MAGIC_V2.to_be_bytes()[..]))
               .context(error::IOSnafu)?;
 
         Ok(())
     }
 
-    fn write_to(writer: &mut FileCursor, tombstone: &Tombstone) -> Result<()> {
-        writer.seek(SeekFrom::End(0)).context(error::IOSnafu)?;
-        writer.write(&tombstone.field_id.to_be_bytes()[..]).context(error::IOSnafu)?;
+    /// Encodes the tombstones of the fields, the contiguous field ids are merged into a range.
+    fn encode_records(field_ids: &[FieldId], min: Timestamp, max: Timestamp, buf: &mut Vec<u8>) {
+        let mut field_ids = field_ids.to_vec();
+        field_ids.sort_unstable();
+        field_ids.dedup();
+        let mut i = 0;
+        while i < field_ids.len() {
+            let mut j = i;
+            while j + 1 < field_ids.len() && field_ids[j + 1] == field_ids[j] + 1 {
+                j += 1;
+            }
+            Self::encode_record(field_ids[i], field_ids[j], min, max, buf);
+            i = j + 1;
+        }
+    }
+
+    // encodes the tombstone of the fields in [start, end], a field record if they are one
+    fn encode_record(start: FieldId,
+                     end: FieldId,
+                     min: Timestamp,
+                     max: Timestamp,
+                     buf: &mut Vec<u8>) {
+        if start == end {
+            buf.push(RECORD_FIELD);
+        } else {
+            buf.push(RECORD_FIELD_RANGE);
+            buf.extend_from_slice(&start.to_be_bytes()[..]);
+        }
+        buf.extend_from_slice(&end.to_be_bytes()[..]);
+        buf.extend_from_slice(&min.to_be_bytes()[..]);
+        buf.extend_from_slice(&max.to_be_bytes()[..]);
+    }
 
-        Ok(())
+    // frames the records into a segment
+    fn encode_segment(records: &[u8], buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&(records.len() as u32).to_be_bytes()[..]);
+        buf.extend_from_slice(&crc32fast::hash(records).to_be_bytes()[..]);
+        buf.extend_from_slice(records);
     }
 
+    /// Appends the tombstones of the fields in one write.
     pub fn add_range(&self, field_ids: &[FieldId], min: Timestamp, max: Timestamp) -> Result<()> {
-        let mut file_cursor = self.file_cursor.lock();
-        for field_id in field_ids.iter() {
-            Self::write_to(&mut file_cursor,
-                           &Tombstone { field_id: *field_id, min_ts: min, max_ts: max })?;
+        self.add_ranges(&[(field_ids.to_vec(), min, max)])
+    }
+
+    /// Appends the tombstones of the fields in one write, noting the wal sequence of the
+    /// delete so that a point-in-time restore before it can skip them.
+    pub fn add_range_at(&self,
+                        seq: u64,
+                        field_ids: &[FieldId],
+                        min: Timestamp,
+                        max: Timestamp)
+                        -> Result<()> {
+        self.append(seq, &[(field_ids.to_vec(), min, max)])
+    }
+
+    /// Appends the tombstones of each group of fields and its time range in one write.
+    pub fn add_ranges(&self, ranges: &[(Vec<FieldId>, Timestamp, Timestamp)]) -> Result<()> {
+        self.append(0, ranges)
+    }
+
+    // appends the ranges in one write, the wal sequence is noted if it is not 0
+    fn append(&self, seq: u64, ranges: &[(Vec<FieldId>, Timestamp, Timestamp)]) -> Result<()> {
+        let mut buf = Vec::new();
+        if self.framed {
+            let mut records = Vec::new();
+            if seq != 0 {
+                records.push(RECORD_SEQ);
+                records.extend_from_slice(&seq.to_be_bytes()[..]);
+            }
+            for (field_ids, min, max) in ranges {
+                Self::encode_records(field_ids, *min, *max, &mut records);
+            }
+            Self::encode_segment(&records, &mut buf);
+        } else {
+            for (field_ids, min, max) in ranges {
+                for field_id in field_ids.iter() {
+                    buf.extend_from_slice(&field_id.to_be_bytes()[..]);
+                    buf.extend_from_slice(&min.to_be_bytes()[..]);
+                    buf.extend_from_slice(&max.to_be_bytes()[..]);
+                }
+            }
         }
+        let mut file_cursor = self.file_cursor.lock();
+        file_cursor.seek(SeekFrom::End(0))
+                   .and_then(|_| file_cursor.write(&buf))
+                   .context(error::IOSnafu)?;
         Ok(())
     }
 
     pub fn overlaps(&self, timerange: &TimeRange) -> bool {
-        let tombstones = self.tombstones.read();
-        for t in tombstones.iter() {
-            if t.max_ts >= timerange.max_ts && t.min_ts <= timerange.min_ts {
-                return true;
-            }
-        }
+        let covers = |range: &TimeRange| {
+            range.max_ts >= timerange.max_ts && range.min_ts <= timerange.min_ts
+        };
+        self.tombstones.read().iter().any(|t| covers(&t.range))
+        || self.range_tombstones.read().iter().any(|t| covers(&t.range))
+    }
+
+    pub fn tombstones(&self) -> Vec<Tombstone> {
+        self.tombstones.read().clone()
+    }
 
-        false
+    pub fn range_tombstones(&self) -> Vec<FieldRangeTombstone> {
+        self.range_tombstones.read().clone()
+    }
+
+    /// Returns the largest wal sequence of the loaded tombstones, 0 if none has one.
+    pub fn max_seq(&self) -> u64 {
+        *self.max_seq.read()
+    }
+
+    /// Builds the merged tombstone ranges of a field, the tombstones must be loaded.
+    pub fn index(&self, field_id: FieldId) -> TombstoneIndex {
+        let tombstones = self.tombstones.read();
+        let range_tombstones = self.range_tombstones.read();
+        let ranges = tombstones.iter()
+                               .filter(|t| t.field_id == field_id)
+                               .map(|t| (t.range.min_ts, t.range.max_ts))
+                               .chain(range_tombstones.iter()
+                                                      .filter(|t| {
+                                                          t.field_id_start <= field_id
+                                                          && field_id <= t.field_id_end
+                                                      })
+                                                      .map(|t| (t.range.min_ts, t.range.max_ts)))
+                               .collect();
+        TombstoneIndex::new(ranges)
     }
 
     pub fn sync(&self) -> Result<()> {
@@ -143,18 +383,359 @@ impl TsmTombstone {
         file_cursor.sync_all(FileSync::Hard).context(error::IOSnafu)?;
         Ok(())
     }
+
+    /// Rewrites the tombstone file of a tsm file with the time ranges of each field merged,
+    /// once it holds more than `COMPACT_RECORDS` records or `COMPACT_BYTES` bytes, or always
+    /// with `force`. Returns the sizes of the file before and after, None if it is not
+    /// rewritten, as it is small or would not shrink by a quarter without `force`.
+    ///
+    /// The deletes of each wal sequence are kept in a segment of their own, without the
+    /// ranges the deletes of a smaller sequence already cover, so that a point-in-time
+    /// restore loads the same deletes from the rewritten file. The file is written next to
+    /// the old one and renamed over it, a crash leaves either of them.
+    pub fn compact(path: &str, file_id: u64, force: bool) -> Result<Option<(u64, u64)>> {
+        let tombstone_path = file_utils::make_tsm_tombstone_file_name(path, file_id);
+        if !file_manager::try_exists(&tombstone_path) {
+            return Ok(None);
+        }
+        let tombstone = Self::with_tsm_file_id(path, file_id)?;
+        let buf = {
+            let mut file_cursor = tombstone.file_cursor.lock();
+            let len = file_cursor.len();
+            if !force && len <= COMPACT_BYTES && len < (COMPACT_RECORDS * RECORD_MIN_SIZE) as u64 {
+                return Ok(None);
+            }
+            Self::read_all(&mut file_cursor)?
+        };
+
+        // the records of every wal sequence by the fields they delete
+        type Deletes = BTreeMap<(FieldId, FieldId), Vec<(Timestamp, Timestamp)>>;
+        let mut seqs: BTreeMap<u64, Deletes> = BTreeMap::new();
+        let mut records = 0;
+        let mut add = |seq: u64, tombstones: Vec<Tombstone>, ranges: Vec<FieldRangeTombstone>| {
+            let deletes = seqs.entry(seq).or_default();
+            records += tombstones.len() + ranges.len();
+            for t in tombstones {
+                deletes.entry((t.field_id, t.field_id))
+                       .or_default()
+                       .push((t.range.min_ts, t.range.max_ts));
+            }
+            for t in ranges {
+                deletes.entry((t.field_id_start, t.field_id_end))
+                       .or_default()
+                       .push((t.range.min_ts, t.range.max_ts));
+            }
+        };
+        if tombstone.framed {
+            for (start, end) in Self::segments(&buf) {
+                let segment = &buf[start + SEGMENT_HEADER_SIZE..end];
+                let (mut tombstones, mut ranges) = (vec![], vec![]);
+                Self::decode_segment(segment, &mut tombstones, &mut ranges)?;
+                add(Self::segment_seq(segment), tombstones, ranges);
+            }
+        } else {
+            tombstone.load()?;
+            add(0, tombstone.tombstones(), vec![]);
+        }
+        drop(tombstone);
+        if !force && records <= COMPACT_RECORDS && buf.len() as u64 <= COMPACT_BYTES {
+            return Ok(None);
+        }
+
+        let max_seq = seqs.keys().next_back().cloned().unwrap_or(0);
+        let mut deleted: HashMap<(FieldId, FieldId), TombstoneIndex> = HashMap::new();
+        let mut compacted = TOMBSTONE_MAGIC_V2.to_be_bytes().to_vec();
+        for (seq, deletes) in seqs {
+            let mut records = Vec::new();
+            if seq != 0 {
+                records.push(RECORD_SEQ);
+                records.extend_from_slice(&seq.to_be_bytes()[..]);
+            }
+            let header_len = records.len();
+            for ((start, end), ranges) in deletes {
+                let index = TombstoneIndex::new(ranges);
+                let before = deleted.entry((start, end)).or_default();
+                for (min, max) in index.subtract(before).ranges() {
+                    Self::encode_record(start, end, *min, *max, &mut records);
+                }
+                let merged = before.ranges().iter().chain(index.ranges()).cloned().collect();
+                *before = TombstoneIndex::new(merged);
+            }
+            // the largest sequence is kept for `max_seq` even if its deletes are all older
+            if records.len() > header_len || (seq != 0 && seq == max_seq) {
+                Self::encode_segment(&records, &mut compacted);
+            }
+        }
+        let (old_len, new_len) = (buf.len() as u64, compacted.len() as u64);
+        if !force && new_len > old_len / 4 * 3 {
+            return Ok(None);
+        }
+
+        let tmp_path = tombstone_path.with_extension(COMPACTING_EXT);
+        let file = file_manager::get_file_manager().create_file(&tmp_path)?;
+        let mut file_cursor = file.into_cursor();
+        file_cursor.write(&compacted).context(error::IOSnafu)?;
+        file_cursor.sync_all(FileSync::Hard).context(error::IOSnafu)?;
+        drop(file_cursor);
+        std::fs::rename(&tmp_path, &tombstone_path).context(error::IOSnafu)?;
+        if let Some(dir) = tombstone_path.parent() {
+            std::fs::File::open(dir).and_then(|dir| dir.sync_all()).context(error::IOSnafu)?;
+        }
+        Ok(Some((old_len, new_len)))
+    }
+}
+
+/// Collects the deletes of fields, the time ranges of a field are coalesced before they are
+/// written to a tombstone file.
+#[derive(Debug, Default, Clone)]
+pub struct TombstoneBuilder {
+    deletes: BTreeMap<FieldId, Vec<(Timestamp, Timestamp)>>,
+}
+
+impl TombstoneBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, field_id: FieldId, time_range: &TimeRange) -> &mut Self {
+        self.deletes.entry(field_id).or_default().push((time_range.min_ts, time_range.max_ts));
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deletes.is_empty()
+    }
+
+    /// Returns the coalesced deletes without writing them.
+    pub fn build(&self) -> TombstoneSet {
+        let fields = self.deletes
+                         .iter()
+                         .map(|(field_id, ranges)| (*field_id, TombstoneIndex::new(ranges.clone())))
+                         .filter(|(_, index)| !index.is_empty())
+                         .collect();
+        TombstoneSet { fields, field_ranges: vec![], max_seq: 0 }
+    }
+
+    /// Appends the coalesced deletes to the tombstone file in one segment, the deletes
+    /// already in the file are kept.
+    pub fn write_to(&self, tombstone: &TsmTombstone) -> Result<()> {
+        // the fields deleted in the same time range share the records of a field range
+        let mut groups: BTreeMap<(Timestamp, Timestamp), Vec<FieldId>> = BTreeMap::new();
+        for (field_id, index) in self.build().fields {
+            for range in index.ranges() {
+                groups.entry(*range).or_default().push(field_id);
+            }
+        }
+        if groups.is_empty() {
+            return Ok(());
+        }
+        let ranges: Vec<(Vec<FieldId>, Timestamp, Timestamp)> =
+            groups.into_iter().map(|((min, max), field_ids)| (field_ids, min, max)).collect();
+        tombstone.add_ranges(&ranges)?;
+        tombstone.sync()
+    }
+}
+
+/// The coalesced deletes of a tombstone file.
+#[derive(Debug, Default, Clone)]
+pub struct TombstoneSet {
+    fields: BTreeMap<FieldId, TombstoneIndex>,
+    field_ranges: Vec<FieldRangeTombstone>,
+    // the largest wal sequence of the deletes, 0 if none has one
+    max_seq: u64,
+}
+
+impl TombstoneSet {
+    /// Reads the tombstone file of a tsm file, the set is empty if there is no such file.
+    pub fn load(path: &str, file_id: u64) -> Result<Self> {
+        Self::load_until(path, file_id, u64::MAX)
+    }
+
+    /// Reads the tombstone file of a tsm file without the deletes after the wal sequence
+    /// `end_seq`, see `TsmTombstone::load_until`.
+    pub fn load_until(path: &str, file_id: u64, end_seq: u64) -> Result<Self> {
+        let tombstone_path = file_utils::make_tsm_tombstone_file_name(path, file_id);
+        if !file_manager::try_exists(&tombstone_path) {
+            return Ok(Self::default());
+        }
+        let tombstone = TsmTombstone::with_tsm_file_id(path, file_id)?;
+        tombstone.load_until(end_seq)?;
+        Ok(Self::from_tombstone(&tombstone))
+    }
+
+    /// Builds the set from the loaded tombstones.
+    pub fn from_tombstone(tombstone: &TsmTombstone) -> Self {
+        let mut deletes: BTreeMap<FieldId, Vec<(Timestamp, Timestamp)>> = BTreeMap::new();
+        for t in tombstone.tombstones.read().iter() {
+            deletes.entry(t.field_id).or_default().push((t.range.min_ts, t.range.max_ts));
+        }
+        let fields = deletes.into_iter()
+                            .map(|(field_id, ranges)| (field_id, TombstoneIndex::new(ranges)))
+                            .filter(|(_, index)| !index.is_empty())
+                            .collect();
+        Self { fields, field_ranges: tombstone.range_tombstones(), max_seq: tombstone.max_seq() }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty() && self.field_ranges.is_empty()
+    }
+
+    /// Returns the largest wal sequence of the deletes, 0 if none has one.
+    pub fn max_seq(&self) -> u64 {
+        self.max_seq
+    }
+
+    /// Returns the merged deleted ranges of a field.
+    pub fn index(&self, field_id: FieldId) -> TombstoneIndex {
+        let in_ranges = self.field_ranges
+                            .iter()
+                            .filter(|t| t.field_id_start <= field_id && field_id <= t.field_id_end)
+                            .map(|t| (t.range.min_ts, t.range.max_ts));
+        match self.fields.get(&field_id) {
+            Some(index) if self.field_ranges.is_empty() => index.clone(),
+            Some(index) => {
+                TombstoneIndex::new(index.ranges().iter().cloned().chain(in_ranges).collect())
+            },
+            None => TombstoneIndex::new(in_ranges.collect()),
+        }
+    }
+
+    /// Returns true if any point of the field in the time range is deleted.
+    pub fn overlaps(&self, field_id: FieldId, time_range: &TimeRange) -> bool {
+        self.index(field_id).overlaps(time_range)
+    }
+
+    pub fn contains(&self, field_id: FieldId, ts: Timestamp) -> bool {
+        self.index(field_id).contains(ts)
+    }
+}
+
+/// The tombstone ranges of a field in a tsm file, sorted and merged, so that a block is
+/// filtered in one sweep instead of checking every point against every range.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct TombstoneIndex {
+    // (min_ts, max_ts) both inclusive, sorted and not overlapping
+    ranges: Vec<(Timestamp, Timestamp)>,
+}
+
+impl TombstoneIndex {
+    pub fn new(mut ranges: Vec<(Timestamp, Timestamp)>) -> Self {
+        ranges.retain(|(min, max)| min <= max);
+        ranges.sort_unstable();
+        let mut merged: Vec<(Timestamp, Timestamp)> = Vec::with_capacity(ranges.len());
+        for (min, max) in ranges {
+            match merged.last_mut() {
+                Some(last) if min <= last.1.saturating_add(1) => last.1 = last.1.max(max),
+                _ => merged.push((min, max)),
+            }
+        }
+        Self { ranges: merged }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    pub fn ranges(&self) -> &[(Timestamp, Timestamp)] {
+        &self.ranges
+    }
+
+    // returns the parts of the ranges not in the ranges of `other`
+    fn subtract(&self, other: &TombstoneIndex) -> TombstoneIndex {
+        let mut ranges = vec![];
+        let mut j = 0;
+        for (min, max) in self.ranges.iter().cloned() {
+            while j < other.ranges.len() && other.ranges[j].1 < min {
+                j += 1;
+            }
+            let mut start = Some(min);
+            for (other_min, other_max) in other.ranges[j..].iter().cloned() {
+                let from = match start {
+                    Some(from) if other_min <= max => from,
+                    _ => break,
+                };
+                if other_min > from {
+                    ranges.push((from, other_min - 1));
+                }
+                start = if other_max < max { Some(other_max + 1) } else { None };
+            }
+            if let Some(from) = start {
+                ranges.push((from, max));
+            }
+        }
+        Self { ranges }
+    }
+
+    // index of the first range not ending before ts
+    fn search(&self, ts: Timestamp) -> usize {
+        self.ranges.partition_point(|(_, max)| *max < ts)
+    }
+
+    /// Returns true if any point in the time range is deleted.
+    pub fn overlaps(&self, timerange: &TimeRange) -> bool {
+        match self.ranges.get(self.search(timerange.min_ts)) {
+            Some((min, _)) => *min <= timerange.max_ts,
+            None => false,
+        }
+    }
+
+    /// Returns true if all points in the time range are deleted.
+    pub fn covers(&self, timerange: &TimeRange) -> bool {
+        match self.ranges.get(self.search(timerange.min_ts)) {
+            Some((min, max)) => *min <= timerange.min_ts && *max >= timerange.max_ts,
+            None => false,
+        }
+    }
+
+    pub fn contains(&self, ts: Timestamp) -> bool {
+        match self.ranges.get(self.search(ts)) {
+            Some((min, _)) => *min <= ts,
+            None => false,
+        }
+    }
+
+    /// Removes the deleted points from a block, the timestamps of the block must be sorted.
+    pub fn filter(&self, block: &mut DataBlock) {
+        if self.ranges.is_empty() || block.len() == 0 {
+            return;
+        }
+        let ts = block.ts();
+        let block_range = TimeRange::new(ts[ts.len() - 1], ts[0]);
+        if !self.overlaps(&block_range) {
+            return;
+        }
+        let mut i = self.search(ts[0]);
+        let keep: Vec<bool> = ts.iter()
+                                .map(|t| {
+                                    while i < self.ranges.len() && self.ranges[i].1 < *t {
+                                        i += 1;
+                                    }
+                                    !(i < self.ranges.len() && self.ranges[i].0 <= *t)
+                                })
+                                .collect();
+        block.retain(&keep);
+    }
 }
 
 #[cfg(test)]
 mod test {
     use std::sync::Arc;
 
-    use super::TsmTombstone;
-    use crate::{byte_utils, file_manager, tseries_family::TimeRange, tsm::TsmIndexReader};
+    use rand::{thread_rng, Rng};
+
+    use super::{TombstoneBuilder, TombstoneIndex, TombstoneSet, TsmTombstone};
+    use crate::{
+        byte_utils, file_manager,
+        file_utils::make_tsm_tombstone_file_name,
+        tseries_family::TimeRange,
+        tsm::{DataBlock, TsmIndexReader},
+    };
 
     #[test]
     fn test_write_read() {
-        let tsm_tombstone = TsmTombstone::with_tsm_file_id("/tmp/", 1).unwrap();
+        let tmp = tempfile::tempdir().unwrap();
+        let tsm_tombstone =
+            TsmTombstone::with_tsm_file_id(tmp.path().to_str().unwrap(), 1).unwrap();
         // tsm_tombstone.load().unwrap();
         tsm_tombstone.add_range(&[1, 2, 3], 1, 100).unwrap();
         tsm_tombstone.sync().unwrap();
@@ -162,4 +743,214 @@ mod test {
         tsm_tombstone.load().unwrap();
         let b = tsm_tombstone.overlaps(&TimeRange { max_ts: 2, min_ts: 99 });
     }
+
+    #[test]
+    fn test_batched_range_tombstones() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_str().unwrap();
+        let tombstone = TsmTombstone::with_tsm_file_id(dir, 1).unwrap();
+        // the columns of a series, and a field of another series
+        let mut field_ids: Vec<u64> = (100..10100).rev().collect();
+        field_ids.push(20000);
+        tombstone.add_range(&field_ids, 10, 20).unwrap();
+        tombstone.sync().unwrap();
+
+        // one segment of a range record and a field record
+        let len = std::fs::metadata(make_tsm_tombstone_file_name(dir, 1)).unwrap().len();
+        assert_eq!(len, 4 + 8 + 33 + 25);
+        let tombstone = TsmTombstone::with_tsm_file_id(dir, 1).unwrap();
+        tombstone.load().unwrap();
+        let ranges = tombstone.range_tombstones();
+        assert_eq!(ranges.len(), 1);
+        assert_eq!((ranges[0].field_id_start, ranges[0].field_id_end), (100, 10099));
+        assert_eq!(ranges[0].range, TimeRange::new(20, 10));
+        assert_eq!(tombstone.tombstones().len(), 1);
+
+        for field_id in [100, 5000, 10099, 20000] {
+            let mut block = DataBlock::I64 { index: 0,
+                                             ts: (0..30).collect(),
+                                             val: vec![1; 30],
+                                             validity: None };
+            tombstone.index(field_id).filter(&mut block);
+            let ts: Vec<i64> = (0..10).chain(21..30).collect();
+            assert_eq!(block, DataBlock::I64 { index: 0, ts, val: vec![1; 19], validity: None });
+        }
+        for field_id in [99, 10100, 19999] {
+            assert!(tombstone.index(field_id).is_empty());
+        }
+    }
+
+    #[test]
+    fn test_tombstones_until_seq() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_str().unwrap();
+        let tombstone = TsmTombstone::with_tsm_file_id(dir, 1).unwrap();
+        tombstone.add_range(&[1], 1, 10).unwrap();
+        tombstone.add_range_at(5, &[2], 1, 10).unwrap();
+        tombstone.add_range_at(9, &[3], 1, 10).unwrap();
+        tombstone.sync().unwrap();
+
+        let set = TombstoneSet::load_until(dir, 1, 5).unwrap();
+        assert!(!set.index(1).is_empty() && !set.index(2).is_empty());
+        assert!(set.index(3).is_empty());
+        assert_eq!(set.max_seq(), 5);
+        assert_eq!(TombstoneSet::load(dir, 1).unwrap().max_seq(), 9);
+
+        // the deletes after the sequence are gone from the file
+        let tombstone = TsmTombstone::with_tsm_file_id(dir, 1).unwrap();
+        tombstone.retain_until(5).unwrap();
+        let len = std::fs::metadata(make_tsm_tombstone_file_name(dir, 1)).unwrap().len();
+        assert_eq!(len, 4 + (8 + 25) + (8 + 9 + 25));
+        let set = TombstoneSet::load(dir, 1).unwrap();
+        assert!(set.index(3).is_empty());
+        assert_eq!(set.max_seq(), 5);
+    }
+
+    #[test]
+    fn test_compact_tombstones() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_str().unwrap();
+        let tombstone = TsmTombstone::with_tsm_file_id(dir, 1).unwrap();
+        for i in 0..5000_i64 {
+            // overlapping deletes of a field without a wal sequence
+            tombstone.add_range(&[1], i * 10, i * 10 + 25).unwrap();
+            // the same hundred deletes of a field range over and over, at the sequences 1..=5000
+            let min = (i % 100) * 10;
+            tombstone.add_range_at(i as u64 + 1, &[2, 3], min, min + 500).unwrap();
+        }
+        tombstone.sync().unwrap();
+        drop(tombstone);
+        let path = make_tsm_tombstone_file_name(dir, 1);
+        let len = std::fs::metadata(&path).unwrap().len();
+        let sets = |dir: &str| {
+            [u64::MAX, 2500, 50, 0].map(|end_seq| {
+                                       TombstoneSet::load_until(dir, 1, end_seq).unwrap()
+                                   })
+        };
+        let before = sets(dir);
+
+        // not rewritten while it is small
+        assert!(TsmTombstone::compact(dir, 2, false).unwrap().is_none());
+        assert_eq!(TsmTombstone::compact(dir, 1, false).unwrap(),
+                   Some((len, std::fs::metadata(&path).unwrap().len())));
+        let compacted = std::fs::metadata(&path).unwrap().len();
+        assert!(compacted * 50 < len);
+        assert!(TsmTombstone::compact(dir, 1, false).unwrap().is_none());
+
+        // the same deletes are read at every sequence
+        for (before, after) in before.iter().zip(sets(dir).iter()) {
+            assert_eq!(before.max_seq(), after.max_seq());
+            for field_id in 0..5 {
+                assert_eq!(before.index(field_id), after.index(field_id));
+                let block = DataBlock::I64 { index: 0,
+                                             ts: (0..60000).step_by(7).collect(),
+                                             val: vec![1; 8572],
+                                             validity: None };
+                let (mut expected, mut filtered) = (block.clone(), block);
+                before.index(field_id).filter(&mut expected);
+                after.index(field_id).filter(&mut filtered);
+                assert_eq!(expected, filtered);
+            }
+        }
+        assert_eq!(before[0].max_seq(), 5000);
+        assert_eq!(before[0].index(1).ranges(), &[(0, 50015)]);
+        assert_eq!(before[2].index(2).ranges(), &[(0, 990)]);
+    }
+
+    #[test]
+    fn test_tombstone_builder() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_str().unwrap();
+
+        let mut builder = TombstoneBuilder::new();
+        builder.add(1, &TimeRange::new(20, 10))
+               .add(1, &TimeRange::new(30, 15))
+               .add(2, &TimeRange::new(30, 10))
+               .add(3, &TimeRange::new(200, 100));
+        assert_eq!(builder.build().index(1).ranges(), &[(10, 30)]);
+        builder.write_to(&TsmTombstone::with_tsm_file_id(dir, 1).unwrap()).unwrap();
+
+        // the second round is appended to the file written by the first one
+        let mut builder = TombstoneBuilder::new();
+        builder.add(1, &TimeRange::new(40, 31))
+               .add(3, &TimeRange::new(60, 50))
+               .add(4, &TimeRange::new(0, 0));
+        builder.write_to(&TsmTombstone::with_tsm_file_id(dir, 1).unwrap()).unwrap();
+        TombstoneBuilder::new().write_to(&TsmTombstone::with_tsm_file_id(dir, 1).unwrap()).unwrap();
+
+        let set = TombstoneSet::load(dir, 1).unwrap();
+        assert_eq!(set.index(1).ranges(), &[(10, 40)]);
+        assert_eq!(set.index(2).ranges(), &[(10, 30)]);
+        assert_eq!(set.index(3).ranges(), &[(50, 60), (100, 200)]);
+        assert_eq!(set.index(4).ranges(), &[(0, 0)]);
+        assert!(set.index(5).is_empty());
+        assert!(!set.overlaps(3, &TimeRange::new(99, 61)));
+        assert!(set.overlaps(3, &TimeRange::new(100, 61)));
+        assert!(set.contains(2, 10));
+        assert!(!set.contains(2, 31));
+
+        // no file is created for the tsm files without deletes
+        assert!(TombstoneSet::load(dir, 2).unwrap().is_empty());
+        assert!(!std::path::Path::new(&make_tsm_tombstone_file_name(dir, 2)).exists());
+    }
+
+    #[test]
+    fn test_tombstone_index() {
+        let index =
+            TombstoneIndex::new(vec![(10, 20), (1, 3), (15, 30), (4, 5), (50, 40), (40, 45)]);
+        assert_eq!(index.ranges(), &[(1, 5), (10, 30), (40, 45)]);
+
+        assert!(index.overlaps(&TimeRange::new(10, 6)));
+        assert!(!index.overlaps(&TimeRange::new(9, 6)));
+        assert!(!index.overlaps(&TimeRange::new(100, 46)));
+        assert!(index.covers(&TimeRange::new(30, 10)));
+        assert!(!index.covers(&TimeRange::new(31, 10)));
+        assert!(index.contains(45));
+        assert!(!index.contains(46));
+
+        let other = TombstoneIndex::new(vec![(0, 1), (3, 3), (12, 14), (20, 35), (45, 50)]);
+        assert_eq!(index.subtract(&other).ranges(),
+                   &[(2, 2), (4, 5), (10, 11), (15, 19), (40, 44)]);
+        assert!(index.subtract(&index).is_empty());
+        assert_eq!(index.subtract(&TombstoneIndex::default()), index);
+
+        let mut block = DataBlock::I64 { index: 0,
+                                         ts: (0..50).collect(),
+                                         val: (0..50).map(|v| v * 10).collect(),
+                                         validity: None };
+        index.filter(&mut block);
+        let ts: Vec<i64> = (0..50).filter(|t| !index.contains(*t)).collect();
+        let val = ts.iter().map(|v| v * 10).collect();
+        assert_eq!(block, DataBlock::I64 { index: 0, ts, val, validity: None });
+    }
+
+    #[test]
+    fn test_tombstone_index_random() {
+        let mut rng = thread_rng();
+        for _ in 0..100 {
+            let ranges: Vec<(i64, i64)> =
+                (0..rng.gen_range(0..50)).map(|_| {
+                                             let min = rng.gen_range(0..1000);
+                                             (min, min + rng.gen_range(0..50))
+                                         })
+                                         .collect();
+            let mut ts: Vec<i64> =
+                (0..rng.gen_range(0..500)).map(|_| rng.gen_range(0..1100)).collect();
+            ts.sort_unstable();
+
+            // the naive filter checks every point against every range
+            let expected: Vec<i64> =
+                ts.iter()
+                  .filter(|t| !ranges.iter().any(|(min, max)| min <= *t && *t <= max))
+                  .cloned()
+                  .collect();
+            let mut block = DataBlock::I64 { index: 0, ts: ts.clone(), val: ts, validity: None };
+            TombstoneIndex::new(ranges).filter(&mut block);
+            assert_eq!(block,
+                       DataBlock::I64 { index: 0,
+                                        ts: expected.clone(),
+                                        val: expected,
+                                        validity: None });
+        }
+    }
 }
@@ -1,26 +1,35 @@
 use std::{
     collections::HashMap,
-    io::{Seek, SeekFrom, Write},
+    io::{ErrorKind, Seek, SeekFrom, Write},
+    path::PathBuf,
 };
 
+use logger::warn;
 use models::FieldId;
 use snafu::ResultExt;
 use utils::{BkdrHasher, BloomFilter};
 
-use super::{block, IndexEntry, MAX_BLOCK_VALUES};
+use super::{
+    block, IndexEntry, DUPLICATES_FLAG, FEATURES_MAGIC, FEATURES_SIZE, FIELDS_MAGIC,
+    MAX_BLOCK_BYTES, MAX_BLOCK_VALUES,
+};
 use crate::{
-    direct_io::{FileCursor, FileSync},
+    direct_io::{FileCursor, FileSync, IoClass},
     error::{self, Error, Result},
+    features::{self, FeatureBits},
+    file_manager,
+    kv_option::BlockCompression,
     new_bloom_filter,
-    tsm::{DataBlock, FileBlock},
+    tsm::{coders, value_encoding, CompressionStats, DataBlock, FileBlock, RawBlock},
 };
 
-// A TSM file is composed for four sections: header, blocks, index and the footer.
+// A TSM file is composed for six sections: header, blocks, index, fields, features and the
+// footer.
 //
-// ┌────────┬────────────────────────────────────┬─────────────┬──────────────┐
-// │ Header │               Blocks               │    Index    │    Footer    │
-// │5 bytes │              N bytes               │   N bytes   │   8 bytes    │
-// └────────┴────────────────────────────────────┴─────────────┴──────────────┘
+// ┌────────┬─────────────────────────┬─────────────┬──────────┬──────────┬──────────────┐
+// │ Header │         Blocks          │    Index    │  Fields  │ Features │    Footer    │
+// │5 bytes │         N bytes         │   N bytes   │ N bytes  │ 12 bytes │   72 bytes   │
+// └────────┴─────────────────────────┴─────────────┴──────────┴──────────┴──────────────┘
 //
 // ┌───────────────────┐
 // │      Header       │
@@ -37,6 +46,8 @@ use crate::{
 // │  CRC    │ ts      │  CRC    │  value  │
 // │ 4 bytes │ N bytes │ 4 bytes │ N bytes │
 // └─────────┴─────────┴─────────┴─────────┴
+// The values of a block with null points start with the validity bits of its points, only the
+// values of the other points are encoded.
 //
 // ┌──────────────────────────────────────────────────────────────────────┐
 // │                               Index                                  │
@@ -44,12 +55,29 @@ use crate::{
 // │ fieldId │ Type │ Count │Min Time │Max Time │ Offset │  Size  │Valoff │
 // │ 8 bytes │1 byte│2 bytes│ 8 bytes │ 8 bytes │8 bytes │8 bytes │8 bytes│
 // └─────────┴──────┴───────┴─────────┴─────────┴────────┴────────┴───────┘
+// The highest bit of Type is set if the blocks may contain duplicate timestamps.
+//
+// ┌─────────────────────────────────┐
+// │             Fields              │
+// ├───────────┬─────────┬───────────┤
+// │ fieldIds  │  Count  │   Magic   │
+// │ 8*N bytes │ 4 bytes │  4 bytes  │
+// └───────────┴─────────┴───────────┘
+// The sorted ids of the fields in the file, files written before it have no such section.
+//
+// ┌─────────────────────────────────┐
+// │            Features             │
+// ├──────────┬──────────┬───────────┤
+// │ Required │ Optional │   Magic   │
+// │ 4 bytes  │ 4 bytes  │  4 bytes  │
+// └──────────┴──────────┴───────────┘
+// The format features used by the file, files written before it have no such section.
 //
 // ┌─────────────────────────┐
 // │ Footer                  │
 // ├───────────────┬─────────┤
 // │ Bloom Filter  │Index Ofs│
-// │ 8 bytes       │ 8 bytes │
+// │ 64 bytes      │ 8 bytes │
 // └───────────────┴─────────┘
 
 const HEADER_LEN: u64 = 5;
@@ -111,6 +139,38 @@ impl TsmFooterWriter {
         Ok(())
     }
 }
+
+pub struct TsmFieldsWriter {}
+
+impl TsmFieldsWriter {
+    /// Writes the ids of the fields in the file, `field_ids` must be sorted.
+    pub fn write_to(writer: &mut FileCursor, field_ids: &[FieldId]) -> Result<()> {
+        let mut buf = Vec::with_capacity(field_ids.len() * 8 + 8);
+        for fid in field_ids {
+            buf.extend_from_slice(&fid.to_be_bytes()[..]);
+        }
+        buf.extend_from_slice(&(field_ids.len() as u32).to_be_bytes()[..]);
+        buf.extend_from_slice(&FIELDS_MAGIC.to_be_bytes()[..]);
+        writer.write(&buf[..]).map_err(|e| Error::WriteTsmErr { reason: e.to_string() })?;
+
+        Ok(())
+    }
+}
+
+pub struct TsmFeaturesWriter {}
+
+impl TsmFeaturesWriter {
+    pub fn write_to(writer: &mut FileCursor, features: &FeatureBits) -> Result<()> {
+        let mut buf = Vec::with_capacity(FEATURES_SIZE);
+        buf.extend_from_slice(&features.required.to_be_bytes()[..]);
+        buf.extend_from_slice(&features.optional.to_be_bytes()[..]);
+        buf.extend_from_slice(&FEATURES_MAGIC.to_be_bytes()[..]);
+        writer.write(&buf[..]).map_err(|e| Error::WriteTsmErr { reason: e.to_string() })?;
+
+        Ok(())
+    }
+}
+
 pub struct TsmIndexWriter {}
 
 impl TsmIndexWriter {
@@ -121,7 +181,10 @@ impl TsmIndexWriter {
         for (fid, blks) in indexs {
             let mut buf = Vec::new();
             let block = blks.first().unwrap();
-            let typ: u8 = block.field_type.into();
+            let mut typ: u8 = block.field_type.into();
+            if blks.iter().any(|b| b.may_have_duplicates) {
+                typ |= DUPLICATES_FLAG;
+            }
             let cnt: u16 = blks.len() as u16;
             buf.extend_from_slice(&fid.to_be_bytes()[..]);
             buf.extend_from_slice(&typ.to_be_bytes()[..]);
@@ -146,29 +209,88 @@ pub struct TsmBlockWriter {}
 
 impl TsmBlockWriter {
     pub(crate) fn write_to(writer: &mut FileCursor,
-                           mut block_set: HashMap<FieldId, DataBlock>)
+                           block_set: HashMap<FieldId, DataBlock>)
                            -> Result<HashMap<FieldId, Vec<FileBlock>>> {
+        let chunks = block_set.into_iter().map(|(fid, block)| (fid, vec![block])).collect();
+        let mut stats = CompressionStats::default();
+        Self::write_chunks_to(writer, chunks, BlockCompression::Fast, &mut stats)
+    }
+
+    /// Writes the blocks of every field one after another, the blocks of a field must be
+    /// ordered by timestamp. A block over `MAX_BLOCK_VALUES` points or estimated over
+    /// `MAX_BLOCK_BYTES` is split. The encoded blocks are compressed by `compression`, and
+    /// recorded into `stats`.
+    pub(crate) fn write_chunks_to(writer: &mut FileCursor,
+                                  chunk_set: HashMap<FieldId, Vec<DataBlock>>,
+                                  compression: BlockCompression,
+                                  stats: &mut CompressionStats)
+                                  -> Result<HashMap<FieldId, Vec<FileBlock>>> {
         let mut res = HashMap::new();
-        for (fid, block) in block_set.iter_mut() {
-            let index = Self::write_one_to(writer, block)?;
-            res.insert(*fid, index);
+        for (fid, chunks) in chunk_set.iter() {
+            let mut index = vec![];
+            for block in chunks.iter().filter(|b| b.len() > 0) {
+                index.extend(Self::write_one_to(writer, block, compression, stats)?);
+            }
+            if !index.is_empty() {
+                res.insert(*fid, index);
+            }
         }
         Ok(res)
     }
 
-    fn write_one_to(writer: &mut FileCursor, block: &DataBlock) -> Result<Vec<FileBlock>> {
+    /// Appends an encoded block verbatim after checking its crc and that its timestamps match
+    /// the meta, returns the meta of the written block.
+    pub fn write_raw_to(writer: &mut FileCursor, block: &RawBlock) -> Result<FileBlock> {
+        let invalid = |reason: String| Error::WriteTsmErr { reason };
+        if crc32fast::hash(&block.ts) != block.ts_crc
+           || crc32fast::hash(&block.val) != block.val_crc
+        {
+            return Err(invalid("raw block crc mismatch".to_string()));
+        }
+        let ts = block.timestamps().map_err(|e| invalid(e.to_string()))?;
+        if ts.first() != Some(&block.min_ts) || ts.last() != Some(&block.max_ts) {
+            return Err(invalid("raw block time range mismatch".to_string()));
+        }
+
+        let offset = writer.pos();
+        writer.write(&block.ts_crc.to_be_bytes()[..])
+              .and_then(|_| writer.write(&block.ts))
+              .map_err(|e| Error::WriteTsmErr { reason: e.to_string() })?;
+        let val_off = writer.pos();
+        writer.write(&block.val_crc.to_be_bytes()[..])
+              .and_then(|_| writer.write(&block.val))
+              .map_err(|e| Error::WriteTsmErr { reason: e.to_string() })?;
+        Ok(FileBlock { min_ts: block.min_ts,
+                       max_ts: block.max_ts,
+                       offset,
+                       size: (block.ts.len() + block.val.len() + 8) as u64,
+                       val_off,
+                       field_type: block.field_type,
+                       reader_idx: 0,
+                       may_have_duplicates: block.may_have_duplicates })
+    }
+
+    fn write_one_to(writer: &mut FileCursor,
+                    block: &DataBlock,
+                    compression: BlockCompression,
+                    stats: &mut CompressionStats)
+                    -> Result<Vec<FileBlock>> {
         let field_type = block.field_type();
-        let len = block.len();
-        let n = (len - 1) / MAX_BLOCK_VALUES + 1;
-        let mut res = Vec::with_capacity(n);
-        let mut i = 0;
-        let mut last_index = 0;
-        while i < n {
-            let start = last_index;
-            let end = len % MAX_BLOCK_VALUES + i * MAX_BLOCK_VALUES;
-            last_index = end;
+        let may_have_duplicates = block.has_duplicates();
+        let ranges = Self::split(block);
+        let mut res = Vec::with_capacity(ranges.len());
+        for (start, end) in ranges {
             let (min_ts, max_ts) = block.time_range(start, end);
-            let (ts_buf, data_buf) = block.encode(start, end)?;
+            let (mut ts_buf, mut data_buf) = block.encode(start, end)?;
+            // the encoding of the values is only known before zstd
+            let encoding = value_encoding(field_type, &data_buf);
+            if let BlockCompression::Zstd(level) = compression {
+                ts_buf = coders::compress::compress(ts_buf, level)
+                    .map_err(|e| Error::WriteTsmErr { reason: e.to_string() })?;
+                data_buf = coders::compress::compress(data_buf, level)
+                    .map_err(|e| Error::WriteTsmErr { reason: e.to_string() })?;
+            }
+            stats.record(block, start, end, encoding, &ts_buf, &data_buf);
             // fill data if err occur reset the pos
             let offset = writer.pos();
             writer.write(&crc32fast::hash(&ts_buf).to_be_bytes()[..])
@@ -186,11 +308,168 @@ impl TsmBlockWriter {
                                  size: size as u64,
                                  val_off,
                                  field_type,
-                                 reader_idx: 0 });
-            i += 1;
+                                 reader_idx: 0,
+                                 may_have_duplicates });
         }
         Ok(res)
     }
+
+    // the ranges of the points written into blocks: by `MAX_BLOCK_VALUES` points, the first
+    // block holding the remainder, every one cut again into the fewest blocks estimated within
+    // `MAX_BLOCK_BYTES`
+    fn split(block: &DataBlock) -> Vec<(usize, usize)> {
+        let len = block.len();
+        let n = (len - 1) / MAX_BLOCK_VALUES + 1;
+        let mut res = Vec::with_capacity(n);
+        let mut start = 0;
+        for i in 0..n {
+            let end = len - (n - 1 - i) * MAX_BLOCK_VALUES;
+            while start < end {
+                // the furthest cut estimated within the limit, at least one point
+                let (mut lo, mut hi) = (start + 1, end);
+                while lo < hi {
+                    let mid = lo + (hi - lo + 1) / 2;
+                    if block.estimate_encoded_size(start, mid) <= MAX_BLOCK_BYTES {
+                        lo = mid;
+                    } else {
+                        hi = mid - 1;
+                    }
+                }
+                res.push((start, lo));
+                start = lo;
+            }
+        }
+        res
+    }
+}
+
+/// Writes a new tsm file: the header when created, the blocks by `write_chunks`, then the
+/// index, the fields, the features and the footer by `finish`, which is the only way to
+/// complete the file. A writer dropped before `finish` succeeds, on an error or a panic,
+/// removes the file, so that no file without an index or a footer is left behind.
+pub struct TsmWriter {
+    path: PathBuf,
+    cursor: FileCursor,
+    index: HashMap<FieldId, Vec<FileBlock>>,
+    required: u32,
+    // the size of the file once finished
+    finished: Option<u64>,
+    compression: CompressionStats,
+}
+
+impl TsmWriter {
+    /// Creates the file and writes its header, the file is written with the io priority.
+    pub fn create(path: impl Into<PathBuf>, io_class: IoClass) -> Result<Self> {
+        let path = path.into();
+        let file = file_manager::get_file_manager().create_file(&path)?;
+        let mut cursor = file.into_cursor();
+        cursor.set_io_class(io_class);
+        let mut writer = Self { path,
+                                cursor,
+                                index: HashMap::new(),
+                                required: features::BLOCK_ENCODING_TAGS,
+                                finished: None,
+                                compression: CompressionStats::default() };
+        TsmHeaderWriter::write_to(&mut writer.cursor)?;
+        Ok(writer)
+    }
+
+    /// Writes the blocks of every field like `TsmBlockWriter::write_chunks_to`, the blocks of
+    /// a field must follow those written before by timestamp.
+    pub fn write_chunks(&mut self,
+                        chunk_set: HashMap<FieldId, Vec<DataBlock>>,
+                        compression: BlockCompression)
+                        -> Result<()> {
+        if self.finished.is_some() {
+            let reason = format!("{} is finished", self.path.display());
+            return Err(Error::WriteTsmErr { reason });
+        }
+        let has_nulls = chunk_set.values().flatten().any(|b| b.null_count() > 0);
+        let index = TsmBlockWriter::write_chunks_to(&mut self.cursor,
+                                                    chunk_set,
+                                                    compression,
+                                                    &mut self.compression)?;
+        for (field_id, blocks) in index {
+            self.index.entry(field_id).or_insert_with(Vec::new).extend(blocks);
+        }
+        if compression != BlockCompression::Fast {
+            self.required |= features::BLOCK_ZSTD;
+        }
+        if has_nulls {
+            self.required |= features::NULL_VALUES;
+        }
+        Ok(())
+    }
+
+    /// Returns the compression of the blocks encoded by `write_chunks`, the raw blocks are not
+    /// counted.
+    pub fn compression_stats(&self) -> &CompressionStats {
+        &self.compression
+    }
+
+    /// Appends an encoded block of the field verbatim like `TsmBlockWriter::write_raw_to`, the
+    /// block must follow those of the field written before by timestamp.
+    pub fn write_raw(&mut self, field_id: FieldId, block: &RawBlock) -> Result<()> {
+        if self.finished.is_some() {
+            let reason = format!("{} is finished", self.path.display());
+            return Err(Error::WriteTsmErr { reason });
+        }
+        let block = TsmBlockWriter::write_raw_to(&mut self.cursor, block)?;
+        self.index.entry(field_id).or_insert_with(Vec::new).push(block);
+        Ok(())
+    }
+
+    /// Adds required format features to those of the file, the features of the file the raw
+    /// blocks are copied from.
+    pub fn require(&mut self, features: u32) {
+        self.required |= features;
+    }
+
+    /// Writes the index, the fields, the features and the footer, and syncs the file. Returns
+    /// the size of the file; calling it again once it succeeded only returns the size.
+    pub fn finish(&mut self) -> Result<u64> {
+        if let Some(len) = self.finished {
+            return Ok(len);
+        }
+        let index = std::mem::take(&mut self.index);
+        let mut field_ids: Vec<FieldId> = index.keys().cloned().collect();
+        field_ids.sort_unstable();
+        if index.values().flatten().any(|b| b.may_have_duplicates) {
+            self.required |= features::DUPLICATE_TIMESTAMPS;
+        }
+        let features = FeatureBits::new(self.required, features::BLOCK_CRC);
+        let index_pos = self.cursor.pos();
+        let bloom_filter = TsmIndexWriter::write_to(&mut self.cursor, index)?;
+        // a field missing from the bloom filter would never be read from the file
+        debug_assert!(field_ids.iter().all(|fid| bloom_filter.contains(&fid.to_be_bytes()[..])),
+                      "a field of {} is missing from the bloom filter",
+                      self.path.display());
+        TsmFieldsWriter::write_to(&mut self.cursor, &field_ids)?;
+        TsmFeaturesWriter::write_to(&mut self.cursor, &features)?;
+        TsmFooterWriter::write_to(&mut self.cursor, &bloom_filter, index_pos)?;
+        self.cursor
+            .sync_all(FileSync::Hard)
+            .map_err(|e| Error::WriteTsmErr { reason: e.to_string() })?;
+        let len = self.cursor.len();
+        self.finished = Some(len);
+        Ok(len)
+    }
+}
+
+impl Drop for TsmWriter {
+    fn drop(&mut self) {
+        if self.finished.is_some() {
+            return;
+        }
+        // the pages not written out yet must not be written to the file once removed
+        self.cursor.discard();
+        match std::fs::remove_file(&self.path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                warn!("failed to remove unfinished tsm file {}: {}", self.path.display(), e)
+            },
+            _ => {},
+        }
+    }
 }
 
 #[cfg(test)]
@@ -201,12 +480,18 @@ mod test {
     use models::FieldId;
 
     use crate::{
-        direct_io::FileSync,
+        compaction::build_tsm_file,
+        direct_io::{FileSync, IoClass},
+        error::Error,
+        features::{self, FeatureBits},
         file_manager::{self, get_file_manager, FileManager},
-        memcache::StrCell,
+        kv_option::BlockCompression,
+        memcache::{DataType, StrCell},
+        tseries_family::TimeRange,
         tsm::{
-            coders, BlockReader, DataBlock, FileBlock, TsmBlockReader, TsmBlockWriter,
-            TsmFooterWriter, TsmHeaderWriter, TsmIndexReader, TsmIndexWriter,
+            coders, BlockReader, DataBlock, FileBlock, RawBlock, TombstoneIndex, TsmBlockReader,
+            TsmBlockWriter, TsmFeaturesWriter, TsmFieldsWriter, TsmFooterReader, TsmFooterWriter,
+            TsmHeaderWriter, TsmIndexReader, TsmIndexWriter, TsmReader, TsmWriter, Validity,
         },
     };
 
@@ -229,8 +514,14 @@ mod test {
 
         TsmHeaderWriter::write_to(&mut fs_cursor).unwrap();
 
-        let data = vec![DataBlock::U64 { index: 0, ts: vec![2, 3, 4], val: vec![12, 13, 15] },
-                        DataBlock::U64 { index: 0, ts: vec![2, 3, 4], val: vec![101, 102, 103] }];
+        let data = vec![DataBlock::U64 { index: 0,
+                                         ts: vec![2, 3, 4],
+                                         val: vec![12, 13, 15],
+                                         validity: None },
+                        DataBlock::U64 { index: 0,
+                                         ts: vec![2, 3, 4],
+                                         val: vec![101, 102, 103],
+                                         validity: None }];
 
         let mut file_block_map: HashMap<FieldId, Vec<FileBlock>> = HashMap::new();
         for (k, v) in data.iter().enumerate() {
@@ -268,11 +559,13 @@ mod test {
             HashMap::from([(0,
                             DataBlock::U64 { index: 0,
                                              ts: vec![2, 3, 4],
-                                             val: vec![12, 13, 15] }),
+                                             val: vec![12, 13, 15],
+                                             validity: None }),
                            (1,
                             DataBlock::U64 { index: 0,
                                              ts: vec![2, 3, 4],
-                                             val: vec![101, 102, 103] })]);
+                                             val: vec![101, 102, 103],
+                                             validity: None })]);
 
         for (i, block) in blocks.iter().enumerate() {
             let data = block_reader.decode(block).expect("error decoding block data");
@@ -281,4 +574,510 @@ mod test {
         }
         info!("read test finish");
     }
+
+    #[test]
+    fn test_read_nth_block() {
+        let fs = get_file_manager();
+        let file = fs.create_file("./nth_block_test.tsm").unwrap();
+        let mut fs_cursor = file.into_cursor();
+        TsmHeaderWriter::write_to(&mut fs_cursor).unwrap();
+        // 3 blocks of 500, 1000 and 1000 values
+        let block = DataBlock::I64 { index: 0,
+                                     ts: (0..2500).collect(),
+                                     val: (0..2500).collect(),
+                                     validity: None };
+        let file_block_map =
+            TsmBlockWriter::write_to(&mut fs_cursor, HashMap::from([(1, block)])).unwrap();
+        let index_pos = fs_cursor.pos();
+        let bloom_filter = TsmIndexWriter::write_to(&mut fs_cursor, file_block_map).unwrap();
+        TsmFooterWriter::write_to(&mut fs_cursor, &bloom_filter, index_pos).unwrap();
+        fs_cursor.sync_all(FileSync::Hard).unwrap();
+
+        let file = fs.open_file("./nth_block_test.tsm").unwrap();
+        let len = file.len() as usize;
+        let mut fs_cursor = file.into_cursor();
+        let mut reader = TsmReader::new(&mut fs_cursor, len);
+        assert_eq!(reader.read_nth_block(1, 0).unwrap(),
+                   DataBlock::I64 { index: 0,
+                                    ts: (0..500).collect(),
+                                    val: (0..500).collect(),
+                                    validity: None });
+        assert_eq!(reader.read_nth_block(1, 2).unwrap(),
+                   DataBlock::I64 { index: 0,
+                                    ts: (1500..2500).collect(),
+                                    val: (1500..2500).collect(),
+                                    validity: None });
+        match reader.read_nth_block(1, 3) {
+            Err(Error::BlockOutOfRange { field_id: 1, n: 3, count: 3 }) => {},
+            res => panic!("unexpected {:?}", res),
+        }
+        assert!(matches!(reader.read_nth_block(2, 0),
+                         Err(Error::BlockOutOfRange { count: 0, .. })));
+    }
+
+    #[test]
+    fn test_iter_sorted() {
+        let fs = get_file_manager();
+        let path = "./iter_sorted_test.tsm";
+        // field 1 at the even timestamps in 2 blocks, field 2 at every third one in 1 block
+        let ts_1: Vec<i64> = (0..1500).map(|i| i * 2).collect();
+        let ts_2: Vec<i64> = (0..1000).map(|i| i * 3).collect();
+        let block_set =
+            HashMap::from([(1,
+                            DataBlock::I64 { index: 0,
+                                             ts: ts_1.clone(),
+                                             val: ts_1.clone(),
+                                             validity: None }),
+                           (2,
+                            DataBlock::F64 { index: 0,
+                                             ts: ts_2.clone(),
+                                             val: ts_2.iter().map(|t| *t as f64).collect(),
+                                             validity: None })]);
+        build_tsm_file(path.into(), block_set).unwrap();
+
+        let file = fs.open_file(path).unwrap();
+        let len = file.len() as usize;
+        let mut fs_cursor = file.into_cursor();
+        let mut reader = TsmReader::new(&mut fs_cursor, len);
+        let points: Vec<(FieldId, DataType)> =
+            reader.iter_sorted().unwrap().map(|res| res.unwrap()).collect();
+
+        let mut expected: Vec<(i64, FieldId)> =
+            ts_1.iter().map(|t| (*t, 1)).chain(ts_2.iter().map(|t| (*t, 2))).collect();
+        expected.sort_unstable();
+        let keys: Vec<(i64, FieldId)> =
+            points.iter().map(|(field_id, datum)| (datum.timestamp(), *field_id)).collect();
+        assert_eq!(keys, expected);
+        for (field_id, datum) in points {
+            match (field_id, datum) {
+                (1, DataType::I64(c)) => assert_eq!(c.val, c.ts),
+                (2, DataType::F64(c)) => assert_eq!(c.val, c.ts as f64),
+                res => panic!("unexpected {:?}", res),
+            }
+        }
+    }
+
+    #[test]
+    fn test_writer_drop_unfinished() {
+        let dir = tempfile::tempdir().unwrap();
+        let chunk_set = || {
+            let block = DataBlock::I64 { index: 0,
+                                         ts: (0..2500).collect(),
+                                         val: (0..2500).collect(),
+                                         validity: None };
+            HashMap::from([(1, vec![block])])
+        };
+
+        // the blocks are written but not the index nor the footer
+        let unfinished = dir.path().join("_000001.tsm");
+        let mut writer = TsmWriter::create(&unfinished, IoClass::High).unwrap();
+        writer.write_chunks(chunk_set(), BlockCompression::Fast).unwrap();
+        drop(writer);
+        assert!(!unfinished.exists());
+
+        let finished = dir.path().join("_000002.tsm");
+        let mut writer = TsmWriter::create(&finished, IoClass::High).unwrap();
+        writer.write_chunks(chunk_set(), BlockCompression::Fast).unwrap();
+        let len = writer.finish().unwrap();
+        assert_eq!(writer.finish().unwrap(), len);
+        assert!(writer.write_chunks(chunk_set(), BlockCompression::Fast).is_err());
+        drop(writer);
+        let file = get_file_manager().open_file(&finished).unwrap();
+        assert_eq!(file.len(), len);
+        let mut fs_cursor = file.into_cursor();
+        let mut reader = TsmReader::new(&mut fs_cursor, len as usize);
+        assert_eq!(reader.read_nth_block(1, 2).unwrap(),
+                   DataBlock::I64 { index: 0,
+                                    ts: (1500..2500).collect(),
+                                    val: (1500..2500).collect(),
+                                    validity: None });
+    }
+
+    fn write_with_features(path: &str, features: FeatureBits) {
+        let file = get_file_manager().create_file(path).unwrap();
+        let mut fs_cursor = file.into_cursor();
+        TsmHeaderWriter::write_to(&mut fs_cursor).unwrap();
+        let block =
+            DataBlock::I64 { index: 0, ts: vec![1, 2, 3], val: vec![1, 2, 3], validity: None };
+        let file_block_map =
+            TsmBlockWriter::write_to(&mut fs_cursor, HashMap::from([(1, block)])).unwrap();
+        let index_pos = fs_cursor.pos();
+        let bloom_filter = TsmIndexWriter::write_to(&mut fs_cursor, file_block_map).unwrap();
+        TsmFieldsWriter::write_to(&mut fs_cursor, &[1]).unwrap();
+        TsmFeaturesWriter::write_to(&mut fs_cursor, &features).unwrap();
+        TsmFooterWriter::write_to(&mut fs_cursor, &bloom_filter, index_pos).unwrap();
+        fs_cursor.sync_all(FileSync::Hard).unwrap();
+    }
+
+    #[test]
+    fn test_tsm_features() {
+        let fs = get_file_manager();
+        let unknown = 1 << 31;
+
+        // an unknown optional feature is ignored
+        write_with_features("./features_optional_test.tsm", FeatureBits::new(0, unknown));
+        let file = fs.open_file("./features_optional_test.tsm").unwrap();
+        let len = file.len() as usize;
+        let mut fs_cursor = file.into_cursor();
+        assert_eq!(TsmFooterReader::read_features(&mut fs_cursor, len).unwrap(),
+                   Some(FeatureBits::new(0, unknown)));
+        assert_eq!(TsmFooterReader::read_field_ids(&mut fs_cursor, len).unwrap(), Some(vec![1]));
+        let entries: Vec<_> =
+            TsmIndexReader::try_new(&mut fs_cursor, len).unwrap().map(|e| e.unwrap()).collect();
+        assert_eq!(entries.len(), 1);
+        let data = TsmBlockReader::new(&mut fs_cursor).decode(&entries[0].block).unwrap();
+        assert_eq!(data,
+                   DataBlock::I64 { index: 0,
+                                    ts: vec![1, 2, 3],
+                                    val: vec![1, 2, 3],
+                                    validity: None });
+
+        // an unknown required feature is refused
+        write_with_features("./features_required_test.tsm", FeatureBits::new(unknown, 0));
+        let file = fs.open_file("./features_required_test.tsm").unwrap();
+        let len = file.len() as usize;
+        let mut fs_cursor = file.into_cursor();
+        match TsmIndexReader::try_new(&mut fs_cursor, len) {
+            Err(Error::UnsupportedFeature { bits }) => assert_eq!(bits, unknown),
+            _ => panic!("the unknown required feature is not refused"),
+        }
+
+        // the writer records the features it used
+        let block =
+            DataBlock::I64 { index: 0, ts: vec![1, 1, 2], val: vec![1, 2, 3], validity: None };
+        build_tsm_file("./features_writer_test.tsm".into(), HashMap::from([(1, block)])).unwrap();
+        let file = fs.open_file("./features_writer_test.tsm").unwrap();
+        let len = file.len() as usize;
+        let mut fs_cursor = file.into_cursor();
+        let required = features::BLOCK_ENCODING_TAGS | features::DUPLICATE_TIMESTAMPS;
+        assert_eq!(TsmFooterReader::read_features(&mut fs_cursor, len).unwrap(),
+                   Some(FeatureBits::new(required, features::BLOCK_CRC)));
+    }
+
+    fn read_raw_blocks(path: &str) -> Vec<(FieldId, FileBlock, RawBlock, DataBlock)> {
+        let file = get_file_manager().open_file(path).unwrap();
+        let len = file.len() as usize;
+        let mut fs_cursor = file.into_cursor();
+        let entries: Vec<_> =
+            TsmIndexReader::try_new(&mut fs_cursor, len).unwrap().map(|e| e.unwrap()).collect();
+        let mut res = vec![];
+        for entry in entries {
+            let raw = TsmReader::new(&mut fs_cursor, len).read_raw_block(&entry.block).unwrap();
+            let data = TsmBlockReader::new(&mut fs_cursor).decode(&entry.block).unwrap();
+            res.push((entry.field_id(), entry.block, raw, data));
+        }
+        res
+    }
+
+    #[test]
+    fn test_copy_raw_blocks() {
+        let src = DataBlock::I64 { index: 0,
+                                   ts: (0..2500).collect(),
+                                   val: (0..2500).collect(),
+                                   validity: None };
+        let block_set = HashMap::from([(1, src),
+                                       (2,
+                                        DataBlock::F64 { index: 0,
+                                                         ts: vec![1, 2],
+                                                         val: vec![0.5, 1.5],
+                                                         validity: None })]);
+        build_tsm_file("./raw_src_test.tsm".into(), block_set).unwrap();
+        let blocks = read_raw_blocks("./raw_src_test.tsm");
+        assert_eq!(blocks.len(), 4);
+
+        let file = get_file_manager().create_file("./raw_dst_test.tsm").unwrap();
+        let mut fs_cursor = file.into_cursor();
+        TsmHeaderWriter::write_to(&mut fs_cursor).unwrap();
+        let mut index: HashMap<FieldId, Vec<FileBlock>> = HashMap::new();
+        for (field_id, _, raw, _) in blocks.iter() {
+            let block = TsmBlockWriter::write_raw_to(&mut fs_cursor, raw).unwrap();
+            index.entry(*field_id).or_default().push(block);
+        }
+        let index_pos = fs_cursor.pos();
+        let bloom_filter = TsmIndexWriter::write_to(&mut fs_cursor, index).unwrap();
+        TsmFooterWriter::write_to(&mut fs_cursor, &bloom_filter, index_pos).unwrap();
+        fs_cursor.sync_all(FileSync::Hard).unwrap();
+
+        let mut copied = read_raw_blocks("./raw_dst_test.tsm");
+        let key = |b: &(FieldId, FileBlock, RawBlock, DataBlock)| (b.0, b.1.min_ts);
+        let mut blocks = blocks;
+        blocks.sort_by_key(key);
+        copied.sort_by_key(key);
+        assert_eq!(copied.len(), blocks.len());
+        for (src, dst) in blocks.iter().zip(copied.iter()) {
+            assert_eq!(src.0, dst.0);
+            assert_eq!(src.2, dst.2);
+            assert_eq!(src.3, dst.3);
+        }
+
+        // a corrupted block is not copied
+        let mut raw = blocks[0].2.clone();
+        raw.val[0] ^= 0xff;
+        assert!(TsmBlockWriter::write_raw_to(&mut fs_cursor, &raw).is_err());
+        let mut raw = blocks[0].2.clone();
+        raw.max_ts += 1;
+        assert!(TsmBlockWriter::write_raw_to(&mut fs_cursor, &raw).is_err());
+    }
+
+    #[test]
+    fn test_read_corrupted_index() {
+        let read_index = |path: &str| {
+            let file = get_file_manager().open_file(path).unwrap();
+            let len = file.len() as usize;
+            let mut fs_cursor = file.into_cursor();
+            TsmIndexReader::try_new(&mut fs_cursor, len)?.collect::<Result<Vec<_>, _>>()
+        };
+
+        // shorter than a footer
+        let file = get_file_manager().create_file("./corrupted_index_test_1.tsm").unwrap();
+        let mut fs_cursor = file.into_cursor();
+        TsmHeaderWriter::write_to(&mut fs_cursor).unwrap();
+        fs_cursor.sync_all(FileSync::Hard).unwrap();
+        assert!(read_index("./corrupted_index_test_1.tsm").is_err());
+
+        // a footer pointing past the end of the index
+        let file = get_file_manager().create_file("./corrupted_index_test_2.tsm").unwrap();
+        let mut fs_cursor = file.into_cursor();
+        TsmHeaderWriter::write_to(&mut fs_cursor).unwrap();
+        let block =
+            DataBlock::I64 { index: 0, ts: vec![1, 2, 3], val: vec![1, 2, 3], validity: None };
+        let file_block_map =
+            TsmBlockWriter::write_to(&mut fs_cursor, HashMap::from([(1, block)])).unwrap();
+        let index_pos = fs_cursor.pos();
+        let bloom_filter = TsmIndexWriter::write_to(&mut fs_cursor, file_block_map).unwrap();
+        TsmFooterWriter::write_to(&mut fs_cursor, &bloom_filter, index_pos + 1024).unwrap();
+        fs_cursor.sync_all(FileSync::Hard).unwrap();
+        assert!(read_index("./corrupted_index_test_2.tsm").is_err());
+
+        // a block entry larger than the file
+        let file = get_file_manager().create_file("./corrupted_index_test_3.tsm").unwrap();
+        let mut fs_cursor = file.into_cursor();
+        TsmHeaderWriter::write_to(&mut fs_cursor).unwrap();
+        let block =
+            DataBlock::I64 { index: 0, ts: vec![1, 2, 3], val: vec![1, 2, 3], validity: None };
+        let mut file_block_map =
+            TsmBlockWriter::write_to(&mut fs_cursor, HashMap::from([(1, block)])).unwrap();
+        for blocks in file_block_map.values_mut() {
+            blocks[0].size = u64::MAX / 2;
+        }
+        let index_pos = fs_cursor.pos();
+        let bloom_filter = TsmIndexWriter::write_to(&mut fs_cursor, file_block_map).unwrap();
+        TsmFooterWriter::write_to(&mut fs_cursor, &bloom_filter, index_pos).unwrap();
+        fs_cursor.sync_all(FileSync::Hard).unwrap();
+        assert!(read_index("./corrupted_index_test_3.tsm").is_err());
+    }
+
+    #[test]
+    fn test_read_large_str_block() {
+        use rand::{rngs::StdRng, RngCore, SeedableRng};
+
+        // 100 random strings of 64 KB are split into two blocks by bytes, both larger than the
+        // streaming threshold
+        let mut rng = StdRng::seed_from_u64(7);
+        let val: Vec<Vec<u8>> = (0..100).map(|_| {
+                                            let mut s = vec![0; 64 * 1024];
+                                            rng.fill_bytes(&mut s);
+                                            s
+                                        })
+                                        .collect();
+        let block = DataBlock::Str { index: 0, ts: (1..=100).collect(), val, validity: None };
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("large_str_test.tsm");
+        build_tsm_file(path.clone(), HashMap::from([(1, block.clone())])).unwrap();
+
+        let file = get_file_manager().open_file(&path).unwrap();
+        let len = file.len() as usize;
+        let mut fs_cursor = file.into_cursor();
+        let blocks: Vec<FileBlock> =
+            TsmIndexReader::try_new(&mut fs_cursor, len).unwrap()
+                                                        .map(|e| e.unwrap().block)
+                                                        .collect();
+        assert_eq!(blocks.len(), 2);
+        assert!(blocks.iter().all(|b| b.size > 1024 * 1024 && b.size <= 4 * 1024 * 1024));
+
+        let mut reader = TsmBlockReader::new(&mut fs_cursor);
+        let decoded: Vec<i64> =
+            blocks.iter().flat_map(|b| reader.decode(b).unwrap().ts().to_vec()).collect();
+        assert_eq!(decoded, block.ts());
+        let time_range = TimeRange::new(90, 11);
+        let tombstones = TombstoneIndex::new(vec![(20, 29)]);
+        let data = reader.read_data(&blocks, &time_range, &tombstones).unwrap();
+        let data: Vec<(i64, Vec<u8>)> = data.into_iter()
+                                            .map(|d| match d {
+                                                DataType::Str(c) => (c.ts, c.val),
+                                                _ => panic!("unexpected data type"),
+                                            })
+                                            .collect();
+        let expected: Vec<(i64, Vec<u8>)> = match block {
+            DataBlock::Str { ts, val, .. } => {
+                ts.into_iter()
+                  .zip(val)
+                  .filter(|(ts, _)| time_range.contains(*ts) && !tombstones.contains(*ts))
+                  .collect()
+            },
+            _ => unreachable!(),
+        };
+        assert_eq!(data.len(), 70);
+        assert_eq!(data, expected);
+    }
+
+    #[test]
+    fn test_read_data_window() {
+        let ts: Vec<i64> = (1..=1000).collect();
+        let floats = ts.iter().map(|t| *t as f64 * 0.5).collect();
+        let integers = ts.iter().map(|t| t * 3).collect();
+        let unsigned = ts.iter().map(|t| *t as u64 * 7919 % 1000).collect();
+        let bools = ts.iter().map(|t| t % 3 == 0).collect();
+        let strs = ts.iter().map(|t| format!("v{}", t).into_bytes()).collect();
+        let blocks = HashMap::from([(1,
+                                     DataBlock::F64 { index: 0,
+                                                      ts: ts.clone(),
+                                                      val: floats,
+                                                      validity: None }),
+                                    (2,
+                                     DataBlock::I64 { index: 0,
+                                                      ts: ts.clone(),
+                                                      val: integers,
+                                                      validity: None }),
+                                    (3,
+                                     DataBlock::U64 { index: 0,
+                                                      ts: ts.clone(),
+                                                      val: unsigned,
+                                                      validity: None }),
+                                    (4,
+                                     DataBlock::Bool { index: 0,
+                                                       ts: ts.clone(),
+                                                       val: bools,
+                                                       validity: None }),
+                                    (5,
+                                     DataBlock::Str { index: 0, ts, val: strs, validity: None })]);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("read_window_test.tsm");
+        build_tsm_file(path.clone(), blocks.clone()).unwrap();
+
+        let file = get_file_manager().open_file(&path).unwrap();
+        let len = file.len() as usize;
+        let mut fs_cursor = file.into_cursor();
+        let entries: Vec<_> =
+            TsmIndexReader::try_new(&mut fs_cursor, len).unwrap().map(|e| e.unwrap()).collect();
+        let mut reader = TsmBlockReader::new(&mut fs_cursor);
+        // the head and the tail of the blocks, a window whose end is deleted, the whole blocks
+        // with their ends deleted, and a window deleted as a whole
+        let cases = [(TimeRange::new(10, 1), vec![]),
+                     (TimeRange::new(1000, 991), vec![]),
+                     (TimeRange::new(600, 400), vec![(550, 700)]),
+                     (TimeRange::new(2000, 0), vec![(1, 20), (990, 1000)]),
+                     (TimeRange::new(1000, 1), vec![]),
+                     (TimeRange::new(300, 200), vec![(150, 350)])];
+        for (time_range, deleted) in cases {
+            let tombstones = TombstoneIndex::new(deleted);
+            for (field_id, block) in blocks.iter() {
+                let field_blocks: Vec<FileBlock> = entries.iter()
+                                                          .filter(|e| e.field_id() == *field_id)
+                                                          .map(|e| e.block.clone())
+                                                          .collect();
+                let data = reader.read_data(&field_blocks, &time_range, &tombstones).unwrap();
+                let mut block = block.clone();
+                let mut expected = vec![];
+                while let Some(datum) = block.next() {
+                    let ts = datum.timestamp();
+                    if time_range.contains(ts) && !tombstones.contains(ts) {
+                        expected.push(format!("{:?}", datum));
+                    }
+                }
+                let data: Vec<String> = data.iter().map(|d| format!("{:?}", d)).collect();
+                assert_eq!(data, expected, "field {} in {:?}", field_id, time_range);
+            }
+        }
+    }
+
+    #[test]
+    fn test_read_null_values() {
+        let ts: Vec<i64> = (1..=1000).collect();
+        // nulls scattered over the blocks, and a block of nulls only
+        let validity: Validity = ts.iter().map(|t| t % 7 != 0 && t % 11 != 3).collect();
+        let nulls = Some(validity.clone());
+        let floats = ts.iter().map(|t| *t as f64 * 0.5).collect();
+        let integers = ts.iter().map(|t| t * 3).collect();
+        let unsigned = ts.iter().map(|t| *t as u64 * 7919 % 1000).collect();
+        let bools = ts.iter().map(|t| t % 3 == 0).collect();
+        let strs = ts.iter().map(|t| format!("v{}", t).into_bytes()).collect();
+        let all_null = Some(Validity::from_bits(&[0; 125], 1000));
+        let blocks = HashMap::from([(1,
+                                     DataBlock::F64 { index: 0,
+                                                      ts: ts.clone(),
+                                                      val: floats,
+                                                      validity: nulls.clone() }),
+                                    (2,
+                                     DataBlock::I64 { index: 0,
+                                                      ts: ts.clone(),
+                                                      val: integers,
+                                                      validity: nulls.clone() }),
+                                    (3,
+                                     DataBlock::U64 { index: 0,
+                                                      ts: ts.clone(),
+                                                      val: unsigned,
+                                                      validity: nulls.clone() }),
+                                    (4,
+                                     DataBlock::Bool { index: 0,
+                                                       ts: ts.clone(),
+                                                       val: bools,
+                                                       validity: nulls.clone() }),
+                                    (5,
+                                     DataBlock::Str { index: 0,
+                                                      ts: ts.clone(),
+                                                      val: strs,
+                                                      validity: nulls }),
+                                    (6,
+                                     DataBlock::I64 { index: 0,
+                                                      ts,
+                                                      val: vec![0; 1000],
+                                                      validity: all_null })]);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("read_null_values_test.tsm");
+        build_tsm_file(path.clone(), blocks.clone()).unwrap();
+
+        let file = get_file_manager().open_file(&path).unwrap();
+        let len = file.len() as usize;
+        let mut fs_cursor = file.into_cursor();
+        let required = features::BLOCK_ENCODING_TAGS | features::NULL_VALUES;
+        assert_eq!(TsmFooterReader::read_features(&mut fs_cursor, len).unwrap(),
+                   Some(FeatureBits::new(required, features::BLOCK_CRC)));
+        let entries: Vec<_> =
+            TsmIndexReader::try_new(&mut fs_cursor, len).unwrap().map(|e| e.unwrap()).collect();
+        // the points with a value, the placeholders of the null points are not written
+        let values = |mut block: DataBlock| {
+            let mut res = vec![];
+            while let Some(datum) = block.next() {
+                res.push(format!("{:?}", datum));
+            }
+            res
+        };
+        let mut reader = TsmBlockReader::new(&mut fs_cursor);
+        for entry in entries.iter() {
+            let block = &blocks[&entry.field_id()];
+            let data = reader.decode(&entry.block).unwrap();
+            assert_eq!((data.ts(), data.validity()), (block.ts(), block.validity()));
+            assert_eq!(values(data), values(block.clone()), "field {}", entry.field_id());
+        }
+
+        // the windows of the blocks are decoded from all their values
+        let tombstones = TombstoneIndex::new(vec![(1, 20)]);
+        for time_range in [TimeRange::new(1000, 1), TimeRange::new(600, 400)] {
+            for entry in entries.iter() {
+                let data =
+                    reader.read_data(&[entry.block.clone()], &time_range, &tombstones).unwrap();
+                let mut block = blocks[&entry.field_id()].clone();
+                let mut expected = vec![];
+                while let Some(datum) = block.next() {
+                    let ts = datum.timestamp();
+                    if time_range.contains(ts) && !tombstones.contains(ts) {
+                        expected.push(format!("{:?}", datum));
+                    }
+                }
+                let data: Vec<String> = data.iter().map(|d| format!("{:?}", d)).collect();
+                assert_eq!(data, expected, "field {} in {:?}", entry.field_id(), time_range);
+            }
+        }
+    }
 }
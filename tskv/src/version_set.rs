@@ -10,20 +10,25 @@ use logger::error;
 use tokio::sync::{mpsc::UnboundedSender, oneshot, RwLock};
 
 use crate::{
+    debug_dump::TsfDebugDump,
     kv_option::{TseriesFamDesc, TseriesFamOpt},
-    memcache::MemCache,
-    summary::{SummaryTask, VersionEdit},
+    memcache::new_memcache,
+    summary::{CompactMeta, PendingPurge, SummaryTask, VersionEdit},
     tseries_family::{TseriesFamily, Version},
+    Result,
 };
 
 pub struct VersionSet {
     ts_families: HashMap<u32, TseriesFamily>,
     ts_families_names: HashMap<String, u32>,
+    // the dropped tseries families waiting to be purged or restored
+    trash: Vec<PendingPurge>,
 }
 
 impl VersionSet {
     pub async fn new(desc: &[TseriesFamDesc],
-                     vers_set: HashMap<u32, Arc<RwLock<Version>>>)
+                     vers_set: HashMap<u32, Arc<RwLock<Version>>>,
+                     trash: Vec<PendingPurge>)
                      -> Self {
         let mut ts_families = HashMap::new();
         let mut ts_families_names = HashMap::new();
@@ -34,10 +39,11 @@ impl VersionSet {
                 if item.name == name {
                     let tf = TseriesFamily::new(id,
                                                 name.clone(),
-                                                MemCache::new(id,
-                                                              GLOBAL_CONFIG.max_memcache_size,
-                                                              seq,
-                                                              false),
+                                                new_memcache(item.opt.memcache_impl,
+                                                             id,
+                                                             GLOBAL_CONFIG.max_memcache_size,
+                                                             seq,
+                                                             false),
                                                 ver.clone(),
                                                 item.opt.clone()).await;
                     ts_families.insert(id, tf);
@@ -46,19 +52,22 @@ impl VersionSet {
             }
         }
 
-        Self { ts_families, ts_families_names }
+        Self { ts_families, ts_families_names, trash }
     }
 
     pub fn new_default() -> Self {
-        Self { ts_families: Default::default(), ts_families_names: Default::default() }
+        Self { ts_families: Default::default(),
+               ts_families_names: Default::default(),
+               trash: vec![] }
     }
 
     pub async fn switch_memcache(&mut self, tf_id: u32, seq: u64) {
         let tf = self.ts_families.get_mut(&tf_id).unwrap();
-        let mem = Arc::new(RwLock::new(MemCache::new(tf_id,
-                                                     GLOBAL_CONFIG.max_memcache_size,
-                                                     seq,
-                                                     false)));
+        let mem = new_memcache(tf.options().memcache_impl,
+                               tf_id,
+                               GLOBAL_CONFIG.max_memcache_size,
+                               seq,
+                               false);
         tf.switch_memcache(mem).await;
     }
 
@@ -80,19 +89,35 @@ impl VersionSet {
         self.ts_families.get_mut(&partid)
     }
 
+    pub fn get_tsfamily_by_id(&mut self, tf_id: u32) -> Option<&mut TseriesFamily> {
+        self.ts_families.get_mut(&tf_id)
+    }
+
+    pub fn tsfamilies(&self) -> impl Iterator<Item = &TseriesFamily> {
+        self.ts_families.values()
+    }
+
+    pub fn tsfamilies_mut(&mut self) -> impl Iterator<Item = &mut TseriesFamily> {
+        self.ts_families.values_mut()
+    }
+
+    /// Adds the tseries family, returns an error without adding it if the options are invalid.
     pub async fn add_tsfamily(&mut self,
                               tf_id: u32,
                               name: String,
                               seq_no: u64,
                               file_id: u64,
                               opt: TseriesFamOpt,
-                              summary_task_sender: UnboundedSender<SummaryTask>) {
+                              summary_task_sender: UnboundedSender<SummaryTask>)
+                              -> Result<()> {
+        opt.validate()?;
         let tf = TseriesFamily::new(tf_id,
                                     name.clone(),
-                                    MemCache::new(tf_id,
-                                                  GLOBAL_CONFIG.max_memcache_size,
-                                                  seq_no,
-                                                  false),
+                                    new_memcache(opt.memcache_impl,
+                                                 tf_id,
+                                                 GLOBAL_CONFIG.max_memcache_size,
+                                                 seq_no,
+                                                 false),
                                     Arc::new(RwLock::new(Version::new(tf_id,
                                                                       file_id,
                                                                       name.clone(),
@@ -106,10 +131,12 @@ impl VersionSet {
         edit.add_tsf(tf_id, "hello".to_string(), 0);
         edits.push(edit);
         let (task_state_sender, task_state_receiver) = oneshot::channel();
-        let task = SummaryTask { edits, cb: task_state_sender };
+        let task =
+            SummaryTask { edits, trash_edits: vec![], scrub_edits: vec![], cb: task_state_sender };
         if let Err(_) = summary_task_sender.send(task) {
             error!("failed to send Summary task,the edits not be loaded!")
         }
+        Ok(())
     }
 
     pub fn del_tsfamily(&mut self,
@@ -127,13 +154,94 @@ impl VersionSet {
         edit.del_tsf(tf_id);
         edits.push(edit);
         let (task_state_sender, task_state_receiver) = oneshot::channel();
-        let task = SummaryTask { edits, cb: task_state_sender };
+        let task =
+            SummaryTask { edits, trash_edits: vec![], scrub_edits: vec![], cb: task_state_sender };
         if let Err(_) = summary_task_sender.send(task) {
             error!("failed to send Summary task,the edits not be loaded!")
         }
     }
 
+    /// Removes the tseries family without writing the summary.
+    pub fn remove_tsfamily(&mut self, tf_id: u32) -> Option<TseriesFamily> {
+        let tsf = self.ts_families.remove(&tf_id)?;
+        self.ts_families_names.retain(|_, id| *id != tf_id);
+        Some(tsf)
+    }
+
+    /// Registers the tseries family of the trash entry again with the files it had when
+    /// dropped, without writing the summary.
+    pub async fn restore_tsfamily(&mut self, purge: &PendingPurge, opt: TseriesFamOpt) {
+        self.register_tsfamily(purge.tsf_id,
+                               &purge.tsf_name,
+                               purge.last_seq,
+                               purge.max_level_ts,
+                               &purge.files,
+                               opt)
+            .await;
+    }
+
+    /// Registers a tseries family holding the files, without writing the summary.
+    pub async fn register_tsfamily(&mut self,
+                                   tf_id: u32,
+                                   name: &str,
+                                   last_seq: u64,
+                                   max_level_ts: i64,
+                                   files: &[CompactMeta],
+                                   opt: TseriesFamOpt) {
+        let mut version = Version::new(tf_id, last_seq, name.to_string(), vec![], max_level_ts);
+        version.base_dir = opt.base_dir.clone();
+        for meta in files.iter() {
+            version.apply_file(meta);
+        }
+        let tf = TseriesFamily::new(tf_id,
+                                    name.to_string(),
+                                    new_memcache(opt.memcache_impl,
+                                                 tf_id,
+                                                 GLOBAL_CONFIG.max_memcache_size,
+                                                 last_seq,
+                                                 false),
+                                    Arc::new(RwLock::new(version)),
+                                    opt).await;
+        self.ts_families.insert(tf_id, tf);
+        self.ts_families_names.insert(name.to_string(), tf_id);
+    }
+
+    pub fn trash(&self) -> &[PendingPurge] {
+        &self.trash
+    }
+
+    pub fn add_trash(&mut self, purge: PendingPurge) {
+        self.trash.push(purge);
+    }
+
+    /// Removes and returns the latest trash entry of the tseries family.
+    pub fn take_trash(&mut self, tf_id: u32) -> Option<PendingPurge> {
+        let i = self.trash
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, purge)| purge.tsf_id == tf_id)
+                    .max_by_key(|(_, purge)| purge.dropped_at)
+                    .map(|(i, _)| i)?;
+        Some(self.trash.remove(i))
+    }
+
+    /// Removes and returns the trash entries expired at `now`, in seconds since the epoch.
+    pub fn take_expired_trash(&mut self, now: u64) -> Vec<PendingPurge> {
+        let (expired, kept): (Vec<_>, Vec<_>) =
+            std::mem::take(&mut self.trash).into_iter().partition(|purge| purge.is_expired(now));
+        self.trash = kept;
+        expired
+    }
+
     pub fn tsf_num(&self) -> usize {
         self.ts_families.len()
     }
+
+    /// Returns the state of every tseries family, ordered by id.
+    pub fn debug_dump(&self) -> Vec<TsfDebugDump> {
+        let mut dumps: Vec<TsfDebugDump> =
+            self.ts_families.values().map(|tf| tf.debug_dump()).collect();
+        dumps.sort_by_key(|dump| dump.tf_id);
+        dumps
+    }
 }
@@ -7,22 +7,27 @@ use std::{
 
 use lazy_static::lazy_static;
 use logger::{debug, info, warn};
-use parking_lot::Mutex;
+use models::{FieldId, SeriesInfo, Timestamp};
 use protos::models as fb_models;
 use regex::Regex;
+use serde::{Deserialize, Serialize};
 use snafu::prelude::*;
-use tokio::sync::{mpsc::UnboundedSender, oneshot, RwLock};
+use tokio::sync::{mpsc::Sender, oneshot, RwLock};
 use walkdir::IntoIter;
 
 use crate::{
     byte_utils,
     compaction::FlushReq,
     context::GlobalContext,
-    direct_io::{File, FileCursor, FileSync},
+    direct_io::{io_scheduler, File, FileCursor, FileSync, IoClass},
     error::{self, Error, Result},
     file_manager::{self, FileManager},
-    file_utils, kv_option,
-    memcache::MemCache,
+    file_utils,
+    forward_index::ForwardIndex,
+    kv_option,
+    memcache::{FieldHints, MemCache},
+    request_window::RequestWindow,
+    tseries_family::TimeRange,
     version_set::VersionSet,
 };
 
@@ -32,12 +37,21 @@ const SEGMENT_SIZE: u64 = 1073741824; // 1 GiB
 
 const BLOCK_HEADER_SIZE: usize = 17;
 
+// the records of version 1 have no time
+const WAL_RECORD_VERSION_UNTIMED: u8 = 1;
+const WAL_RECORD_VERSION: u8 = 2;
+
 pub enum WalTask {
     Write {
         points: Arc<Vec<u8>>,
         // (seq_no, writen_size)
         cb: oneshot::Sender<Result<(u64, usize)>>,
     },
+    Record {
+        record: WalRecord,
+        // (seq_no, writen_size)
+        cb: oneshot::Sender<Result<(u64, usize)>>,
+    },
 }
 
 #[repr(u8)]
@@ -46,6 +60,7 @@ pub enum WalEntryType {
     Write   = 1,
     Delete  = 2,
     DeleteRange = 3,
+    Record  = 4,
     Unknown = 127,
 }
 
@@ -55,11 +70,92 @@ impl From<u8> for WalEntryType {
             1 => WalEntryType::Write,
             2 => WalEntryType::Delete,
             3 => WalEntryType::DeleteRange,
+            4 => WalEntryType::Record,
             _ => WalEntryType::Unknown,
         }
     }
 }
 
+/// A typed operation logged in a `WalEntryType::Record` entry.
+///
+/// Replaying a record must be idempotent: a record may be replayed after it was already
+/// applied, e.g. if the process crashed before the summary noted the last sequence.
+///
+/// Encoded as a version byte, the unix time in nanoseconds the record is logged at as an i64,
+/// then the bincode encoding of the record. The records of version 1 have no time.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum WalRecord {
+    /// Points of a tseries family, encoded as flatbuffers `Points`.
+    Write {
+        tf_id: u32,
+        rows: Vec<u8>,
+    },
+    DeleteRange {
+        tf_id: u32,
+        field_ids: Vec<FieldId>,
+        min_ts: Timestamp,
+        max_ts: Timestamp,
+    },
+    DropField {
+        tf_id: u32,
+        field_id: FieldId,
+    },
+    /// A series added to the forward index, encoded by `SeriesInfo::encode`.
+    DictUpdate {
+        series_info: Vec<u8>,
+    },
+    /// Points of a write request with a client supplied id, encoded as flatbuffers `Points`.
+    Request {
+        request_id: u64,
+        points: Vec<u8>,
+    },
+}
+
+impl WalRecord {
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        self.encode_at(chrono::Utc::now().timestamp_nanos())
+    }
+
+    /// Encodes the record logged at the unix time in nanoseconds.
+    pub fn encode_at(&self, logged_at: i64) -> Result<Vec<u8>> {
+        let mut buf = vec![WAL_RECORD_VERSION];
+        buf.extend_from_slice(&logged_at.to_be_bytes());
+        bincode::serialize_into(&mut buf, self).context(error::EncodeSnafu)?;
+        Ok(buf)
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        Ok(Self::decode_with_time(buf)?.1)
+    }
+
+    /// Decodes the record and the time it was logged at, None for a record of version 1.
+    pub fn decode_with_time(buf: &[u8]) -> Result<(Option<i64>, Self)> {
+        match buf.first() {
+            Some(&WAL_RECORD_VERSION) if buf.len() >= 9 => {
+                let logged_at = byte_utils::decode_be_i64(&buf[1..9]);
+                let record = bincode::deserialize(&buf[9..]).context(error::DecodeSnafu)?;
+                Ok((Some(logged_at), record))
+            },
+            Some(&WAL_RECORD_VERSION_UNTIMED) => {
+                Ok((None, bincode::deserialize(&buf[1..]).context(error::DecodeSnafu)?))
+            },
+            Some(v) => Err(Error::InvalidWalRecord { reason: format!("unknown version {}", v) }),
+            None => Err(Error::InvalidWalRecord { reason: "empty record".to_string() }),
+        }
+    }
+}
+
+/// The last operation a point-in-time restore replays from the wal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecoverTarget {
+    /// The sequence of the last wal entry replayed.
+    Seq(u64),
+    /// The unix time in nanoseconds, the first record logged after it is not replayed, nor
+    /// any entry after that record. The points written without a request id are logged
+    /// without a time, they are replayed up to the next record.
+    Timestamp(i64),
+}
+
 pub struct WalEntryBlock {
     pub typ: WalEntryType,
     pub seq: u64,
@@ -145,7 +241,14 @@ impl WalWriter {
         } else {
             file.read_at(0, &mut header_buf[..]).context(error::IOSnafu)?;
             min_sequence = byte_utils::decode_be_u64(&header_buf[4..12]);
-            max_sequence = byte_utils::decode_be_u64(&header_buf[12..20]);
+            // the header is written when the segment is rolled, the sequence of the segment
+            // being written is after its last entry
+            let mut seq = byte_utils::decode_be_u64(&header_buf[12..20]);
+            let mut reader = WalReader::new(file.clone().into_cursor())?;
+            while let Some(e) = reader.next_wal_entry() {
+                seq = seq.max(e.seq + 1);
+            }
+            max_sequence = seq;
         }
         let size = file.len();
 
@@ -163,38 +266,38 @@ impl WalWriter {
         let typ = typ as u8;
         let mut pos = self.size;
         let mut seq = self.max_sequence;
-
-        self.file
-            // write type
-            .write_at(pos, &[typ])
-            .and_then(|size| {
-                // write seq
-                pos += size as u64;
-                self.file.write_at(pos, &seq.to_be_bytes())
-            })
-            .and_then(|size| {
-                // write crc
-                pos += size as u64;
-                let crc = crc32fast::hash(data);
-                self.file.write_at(pos, &crc.to_be_bytes())
-            })
-            .and_then(|size| {
-                // write len
-                pos += size as u64;
-                let len = data.len() as u32;
-                self.file.write_at(pos, &len.to_be_bytes())
-            })
-            .and_then(|size| {
-                // write data
-                pos += size as u64;
-                self.file.write_at(pos, data)
-            })
-            .and_then(|size| {
-                // sync
-                pos += size as u64;
-                if self.config.sync { self.file.sync_all(FileSync::Soft) } else { Ok(()) }
-            })
-            .context(error::IOSnafu)?;
+        let scheduler = io_scheduler();
+
+        // write type
+        scheduler.write_at(IoClass::High, &self.file, pos, &[typ])
+                 .and_then(|size| {
+                     // write seq
+                     pos += size as u64;
+                     scheduler.write_at(IoClass::High, &self.file, pos, &seq.to_be_bytes())
+                 })
+                 .and_then(|size| {
+                     // write crc
+                     pos += size as u64;
+                     let crc = crc32fast::hash(data);
+                     scheduler.write_at(IoClass::High, &self.file, pos, &crc.to_be_bytes())
+                 })
+                 .and_then(|size| {
+                     // write len
+                     pos += size as u64;
+                     let len = data.len() as u32;
+                     scheduler.write_at(IoClass::High, &self.file, pos, &len.to_be_bytes())
+                 })
+                 .and_then(|size| {
+                     // write data
+                     pos += size as u64;
+                     scheduler.write_at(IoClass::High, &self.file, pos, data)
+                 })
+                 .and_then(|size| {
+                     // sync
+                     pos += size as u64;
+                     if self.config.sync { self.file.sync_all(FileSync::Soft) } else { Ok(()) }
+                 })
+                 .context(error::IOSnafu)?;
 
         seq += 1;
 
@@ -280,102 +383,126 @@ impl WalManager {
         self.current_file.write(typ, data).await
     }
 
+    // returns the readers of the segments, ordered by their ids
+    fn segment_readers(&self) -> Result<Vec<(PathBuf, WalReader)>> {
+        let mut segments = vec![];
+        for file_name in file_manager::list_file_names(&self.current_dir) {
+            let id = file_utils::get_wal_file_id(&file_name)?;
+            segments.push((id, self.current_dir.join(file_name)));
+        }
+        segments.sort();
+        let mut readers = vec![];
+        for (id, path) in segments {
+            let file = WalWriter::open(id, &path, Arc::new(kv_option::WalConfig::default()))?;
+            readers.push((path, WalReader::new(file.file.into())?));
+        }
+        Ok(readers)
+    }
+
+    /// Returns the sequence of the first wal entry after the target, `u64::MAX` if every
+    /// entry is before it.
+    pub fn resolve_target(&self, target: RecoverTarget) -> Result<u64> {
+        let ts = match target {
+            RecoverTarget::Seq(seq) => return Ok(seq.saturating_add(1)),
+            RecoverTarget::Timestamp(ts) => ts,
+        };
+        for (_, mut reader) in self.segment_readers()? {
+            while let Some(e) = reader.next_wal_entry() {
+                if e.typ != WalEntryType::Record {
+                    continue;
+                }
+                if let (Some(logged_at), _) = WalRecord::decode_with_time(&e.buf)? {
+                    if logged_at > ts {
+                        return Ok(e.seq);
+                    }
+                }
+            }
+        }
+        Ok(u64::MAX)
+    }
+
+    /// Removes the entries from the sequence `end_seq` on. The sequences never go back, the
+    /// next entry written still gets the one after the last entry removed. The segments are
+    /// kept, those after the target are left empty.
+    pub fn truncate(&mut self, end_seq: u64) -> Result<()> {
+        let next_seq = self.current_seq_no();
+        for (path, mut reader) in self.segment_readers()? {
+            let mut pos = reader.cursor.pos();
+            while let Some(e) = reader.next_wal_entry() {
+                if e.seq >= end_seq {
+                    break;
+                }
+                pos = reader.cursor.pos();
+            }
+            let mut header = reader.header_buf;
+            let file = reader.cursor.into_file();
+            if pos == file.len() {
+                continue;
+            }
+            header[12..20].copy_from_slice(&next_seq.to_be_bytes());
+            file.write_at(0, &header).context(error::IOSnafu)?;
+            file.set_len(pos);
+            file.sync_all(FileSync::Hard).context(error::IOSnafu)?;
+            warn!("wal segment {} truncated at sequence {}", path.display(), end_seq);
+        }
+        let (id, path) = (self.current_file.id, self.current_file.path.clone());
+        self.current_file = WalWriter::open(id, path, self.config.clone())?;
+        Ok(())
+    }
+
     pub async fn recover(&self,
                          version_set: Arc<RwLock<VersionSet>>,
                          global_context: Arc<GlobalContext>,
-                         flush_task_sender: UnboundedSender<Arc<Mutex<Vec<FlushReq>>>>)
+                         flush_task_sender: Sender<FlushReq>,
+                         forward_index: Arc<RwLock<ForwardIndex>>,
+                         request_window: &RequestWindow)
                          -> Result<()> {
+        self.recover_to(u64::MAX,
+                        version_set,
+                        global_context,
+                        flush_task_sender,
+                        forward_index,
+                        request_window)
+            .await
+    }
+
+    /// Replays the entries before the sequence `end_seq`, see `resolve_target`.
+    pub async fn recover_to(&self,
+                            end_seq: u64,
+                            version_set: Arc<RwLock<VersionSet>>,
+                            global_context: Arc<GlobalContext>,
+                            flush_task_sender: Sender<FlushReq>,
+                            forward_index: Arc<RwLock<ForwardIndex>>,
+                            request_window: &RequestWindow)
+                            -> Result<()> {
         let min_log_seq = global_context.last_seq();
-        warn!("recovering version set from seq '{}'", &min_log_seq);
+        warn!("recovering version set from seq '{}' to '{}'", &min_log_seq, end_seq);
 
-        let wal_files = file_manager::list_file_names(&self.current_dir);
-        for file_name in wal_files {
-            let id = file_utils::get_wal_file_id(&file_name)?;
-            let tmp_walfile = WalWriter::open(id,
-                                              self.current_dir.join(file_name),
-                                              Arc::new(kv_option::WalConfig::default()))?;
-            let mut reader = WalReader::new(tmp_walfile.file.into())?;
+        for (_, mut reader) in self.segment_readers()? {
             if reader.max_sequence < min_log_seq {
                 continue;
             }
             let mut version_set = version_set.write().await;
             while let Some(e) = reader.next_wal_entry() {
+                if e.seq >= end_seq {
+                    continue;
+                }
                 if e.seq < min_log_seq {
+                    // the points are flushed, but a retry of the request is still ignored
+                    if e.typ == WalEntryType::Record {
+                        if let WalRecord::Request { request_id, .. } = WalRecord::decode(&e.buf)? {
+                            request_window.insert(request_id);
+                        }
+                    }
                     continue;
                 }
                 match e.typ {
                     WalEntryType::Write => {
-                        let entry = flatbuffers::root::<fb_models::Points>(&e.buf)
-                        .context(error::InvalidFlatbufferSnafu)?;
-                        if let Some(points) = entry.points() {
-                            for p in points.iter() {
-                                let mut point_tags: Vec<models::Tag> = vec![];
-                                let sid = if let Some(tags) = p.tags() {
-                                    for t in tags.iter() {
-                                        let tag_key = if let Some(tag_key) = t.key() {
-                                            tag_key.to_vec()
-                                        } else {
-                                            continue;
-                                        };
-                                        let tag_value = if let Some(tag_value) = t.value() {
-                                            tag_value.to_vec()
-                                        } else {
-                                            vec![]
-                                        };
-                                        point_tags.push(models::Tag::new(tag_key, tag_value));
-                                    }
-                                    models::generate_series_id(&point_tags)
-                                } else {
-                                    // TODO error: no tags
-                                    0
-                                };
-                                if let Some(tsf) = version_set.get_tsfamily(sid) {
-                                    if let Some(fields) = p.fields() {
-                                        for f in fields.iter() {
-                                            let fid = if let Some(field_name) = f.name() {
-                                                models::generate_field_id(&field_name.to_vec(), sid)
-                                            } else {
-                                                // TODO error: no field name
-                                                0
-                                            };
-                                            let val = if let Some(value) = f.value() {
-                                                value
-                                            } else {
-                                                &[0_u8; 0][..]
-                                            };
-                                            let dtype = match f.type_() {
-                                                fb_models::FieldType::Float => {
-                                                    models::ValueType::Float
-                                                },
-                                                fb_models::FieldType::Integer => {
-                                                    models::ValueType::Integer
-                                                },
-                                                fb_models::FieldType::Unsigned => {
-                                                    models::ValueType::Unsigned
-                                                },
-                                                fb_models::FieldType::Boolean => {
-                                                    models::ValueType::Boolean
-                                                },
-                                                fb_models::FieldType::String => {
-                                                    models::ValueType::String
-                                                },
-                                                _ => models::ValueType::Unknown,
-                                            };
-                                            // todo: change fbs timestamp to i64
-                                            tsf.put_mutcache(fid,
-                                                             val,
-                                                             dtype,
-                                                             e.seq,
-                                                             p.timestamp() as i64,
-                                                             flush_task_sender.clone())
-                                               .await
-                                        }
-                                    }
-                                } else {
-                                    // TODO error: no tseries family
-                                }
-                            }
-                        }
+                        Self::recover_points(&mut version_set,
+                                             None,
+                                             e.seq,
+                                             &e.buf,
+                                             &flush_task_sender).await?;
                     },
                     WalEntryType::Delete => {
                         // TODO delete a memcache entry
@@ -383,12 +510,142 @@ impl WalManager {
                     WalEntryType::DeleteRange => {
                         // TODO delete range in a memcache
                     },
+                    WalEntryType::Record => {
+                        let record = WalRecord::decode(&e.buf)?;
+                        Self::recover_record(&mut version_set,
+                                             e.seq,
+                                             record,
+                                             &flush_task_sender,
+                                             &forward_index,
+                                             request_window).await?;
+                    },
                     _ => {},
                 };
             }
         }
         Ok(())
     }
+
+    async fn recover_record(version_set: &mut VersionSet,
+                            seq: u64,
+                            record: WalRecord,
+                            flush_task_sender: &Sender<FlushReq>,
+                            forward_index: &RwLock<ForwardIndex>,
+                            request_window: &RequestWindow)
+                            -> Result<()> {
+        match record {
+            WalRecord::Write { tf_id, rows } => {
+                Self::recover_points(version_set, Some(tf_id), seq, &rows, flush_task_sender).await
+            },
+            // the deletes are tagged with the sequence after their entries, like when written
+            WalRecord::DeleteRange { tf_id, field_ids, min_ts, max_ts } => {
+                match version_set.get_tsfamily_by_id(tf_id) {
+                    Some(tsf) => {
+                        let time_range = TimeRange::new(max_ts, min_ts);
+                        tsf.delete_range(seq + 1, &field_ids, &time_range).await
+                    },
+                    None => {
+                        warn!("ts_family {} not found, skip recovering delete.", tf_id);
+                        Ok(())
+                    },
+                }
+            },
+            WalRecord::DropField { tf_id, field_id } => {
+                let all = TimeRange::new(i64::MAX, i64::MIN);
+                match version_set.get_tsfamily_by_id(tf_id) {
+                    Some(tsf) => tsf.delete_range(seq + 1, &[field_id], &all).await,
+                    None => {
+                        warn!("ts_family {} not found, skip recovering drop field.", tf_id);
+                        Ok(())
+                    },
+                }
+            },
+            WalRecord::DictUpdate { series_info } => {
+                forward_index.write()
+                             .await
+                             .add_series_info_if_not_exists(SeriesInfo::decode(&series_info))
+                             .await
+                             .context(error::ForwardIndexErrSnafu)
+            },
+            WalRecord::Request { request_id, points } => {
+                request_window.insert(request_id);
+                Self::recover_points(version_set, None, seq, &points, flush_task_sender).await
+            },
+        }
+    }
+
+    /// Puts the flatbuffers points into the caches, dispatched by tf_id if it is given,
+    /// otherwise by the series id of each point.
+    async fn recover_points(version_set: &mut VersionSet,
+                            tf_id: Option<u32>,
+                            seq: u64,
+                            buf: &[u8],
+                            flush_task_sender: &Sender<FlushReq>)
+                            -> Result<()> {
+        let entry =
+            flatbuffers::root::<fb_models::Points>(buf).context(error::InvalidFlatbufferSnafu)?;
+        let points = match entry.points() {
+            Some(points) => points,
+            None => return Ok(()),
+        };
+        let mut hints = FieldHints::default();
+        for p in points.iter() {
+            let mut point_tags: Vec<models::Tag> = vec![];
+            let sid = if let Some(tags) = p.tags() {
+                for t in tags.iter() {
+                    let tag_key = if let Some(tag_key) = t.key() {
+                        tag_key.to_vec()
+                    } else {
+                        continue;
+                    };
+                    let tag_value =
+                        if let Some(tag_value) = t.value() { tag_value.to_vec() } else { vec![] };
+                    point_tags.push(models::Tag::new(tag_key, tag_value));
+                }
+                models::generate_series_id(&point_tags)
+            } else {
+                // TODO error: no tags
+                0
+            };
+            let tsf = match tf_id {
+                Some(tf_id) => version_set.get_tsfamily_by_id(tf_id),
+                None => version_set.get_tsfamily(sid),
+            };
+            if let Some(tsf) = tsf {
+                if let Some(fields) = p.fields() {
+                    for f in fields.iter() {
+                        let fid = if let Some(field_name) = f.name() {
+                            models::generate_field_id(&field_name.to_vec(), sid)
+                        } else {
+                            // TODO error: no field name
+                            0
+                        };
+                        let val = if let Some(value) = f.value() { value } else { &[0_u8; 0][..] };
+                        let dtype = match f.type_() {
+                            fb_models::FieldType::Float => models::ValueType::Float,
+                            fb_models::FieldType::Integer => models::ValueType::Integer,
+                            fb_models::FieldType::Unsigned => models::ValueType::Unsigned,
+                            fb_models::FieldType::Boolean => models::ValueType::Boolean,
+                            fb_models::FieldType::String => models::ValueType::String,
+                            _ => models::ValueType::Unknown,
+                        };
+                        // todo: change fbs timestamp to i64
+                        let _ = tsf.put_mutcache_hinted(&mut hints,
+                                                        fid,
+                                                        val,
+                                                        dtype,
+                                                        seq,
+                                                        p.timestamp() as i64,
+                                                        flush_task_sender.clone())
+                                   .await;
+                    }
+                }
+            } else {
+                // TODO error: no tseries family
+            }
+        }
+        Ok(())
+    }
 }
 
 pub fn reader(f: File) -> Result<WalReader> {
@@ -443,18 +700,29 @@ impl WalReader {
 #[cfg(test)]
 mod test {
     use core::panic;
-    use std::{borrow::BorrowMut, sync::Arc};
+    use std::{borrow::BorrowMut, collections::HashMap, path::Path, sync::Arc};
 
     use chrono::Utc;
     use flatbuffers::{self, Vector, WIPOffset};
     use lazy_static::lazy_static;
     use protos::{models as fb_models, models_helper};
+    use tokio::sync::{mpsc, RwLock};
 
     use crate::{
+        compaction::build_tsm_file,
+        context::GlobalContext,
         direct_io::{File, FileCursor, FileSync},
         file_manager::{self, list_file_names, FileManager},
-        kv_option,
-        wal::{self, WalEntryBlock, WalEntryType, WalManager, WalReader},
+        file_utils::{make_tsm_file_name, make_tsm_tombstone_file_name},
+        forward_index::ForwardIndex,
+        kv_option::{self, TseriesFamDesc, TseriesFamOpt},
+        memcache::MemCacheTrait,
+        request_window::RequestWindow,
+        summary::CompactMeta,
+        tseries_family::{LevelInfo, TimeRange, Version},
+        tsm::DataBlock,
+        version_set::VersionSet,
+        wal::{self, RecoverTarget, WalEntryBlock, WalEntryType, WalManager, WalReader, WalRecord},
     };
 
     const DIR: &str = "/tmp/test/wal";
@@ -716,4 +984,201 @@ mod test {
             assert_eq!(wrote_crcs, read_crcs);
         }
     }
+
+    #[test]
+    fn test_wal_record_codec() {
+        let record =
+            WalRecord::DeleteRange { tf_id: 1, field_ids: vec![1, 2], min_ts: 3, max_ts: 5 };
+        let buf = record.encode().unwrap();
+        assert_eq!(WalRecord::decode(&buf).unwrap(), record);
+        let timed = record.encode_at(7).unwrap();
+        assert_eq!(WalRecord::decode_with_time(&timed).unwrap(), (Some(7), record.clone()));
+        // a record of version 1 has no time
+        let mut untimed = vec![1];
+        bincode::serialize_into(&mut untimed, &record).unwrap();
+        assert_eq!(WalRecord::decode_with_time(&untimed).unwrap(), (None, record));
+
+        let mut buf = buf;
+        buf[0] = 0;
+        assert!(WalRecord::decode(&buf).is_err());
+        assert!(WalRecord::decode(&[]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_recover_delete_record() {
+        let dir = "/tmp/test/wal_record";
+        let _ = std::fs::remove_dir_all(dir);
+        let tmp = tempfile::tempdir().unwrap();
+        let tf_id = 105;
+        let opt = TseriesFamOpt::for_testing(tmp.path());
+        let tsm_dir = opt.tsm_dir(tf_id);
+        std::fs::create_dir_all(&tsm_dir).unwrap();
+        let _ = std::fs::remove_file(make_tsm_tombstone_file_name(&tsm_dir, 1));
+        let block = DataBlock::I64 { index: 0,
+                                     ts: (1..=10).collect(),
+                                     val: vec![1; 10],
+                                     validity: None };
+        build_tsm_file(make_tsm_file_name(&tsm_dir, 1), HashMap::from([(1, block)])).unwrap();
+        let mut lvl = LevelInfo::init_in(&opt.base_dir, 1);
+        lvl.apply(&CompactMeta { file_id: 1, range: TimeRange::new(10, 1), ..Default::default() });
+        let version = Version::new(tf_id, 1, "db".to_string(), vec![lvl], 0);
+        let desc = [TseriesFamDesc { name: "db".to_string(), opt }];
+        let versions = HashMap::from([(tf_id, Arc::new(RwLock::new(version)))]);
+        let version_set = VersionSet::new(&desc, versions, vec![]).await;
+        let version_set = Arc::new(RwLock::new(version_set));
+
+        // crash after the record is appended, before the tombstone is written
+        let wal_config = kv_option::WalConfig { dir: dir.to_string(), ..Default::default() };
+        let record = WalRecord::DeleteRange { tf_id, field_ids: vec![1], min_ts: 3, max_ts: 5 };
+        let mut mgr = WalManager::new(wal_config.clone());
+        mgr.write(WalEntryType::Record, &record.encode().unwrap()).await.unwrap();
+        drop(mgr);
+
+        let forward_index =
+            Arc::new(RwLock::new(ForwardIndex::new(Path::new("/tmp/test/wal_record_fidx"))));
+        let (flush_task_sender, _flush_task_receiver) = mpsc::channel(16);
+        // replaying the delete again after it was applied is harmless
+        for _ in 0..2 {
+            WalManager::new(wal_config.clone()).recover(version_set.clone(),
+                                                        Arc::new(GlobalContext::new()),
+                                                        flush_task_sender.clone(),
+                                                        forward_index.clone(),
+                                                        &RequestWindow::new(&Default::default()))
+                                               .await
+                                               .unwrap();
+            let mut version_set = version_set.write().await;
+            let tsf = version_set.get_tsfamily_by_id(tf_id).unwrap();
+            let ts = tsf.timestamps(1, &TimeRange::new(i64::MAX, i64::MIN)).await.unwrap();
+            assert_eq!(ts, vec![1, 2, 6, 7, 8, 9, 10]);
+        }
+    }
+
+    // returns a version set of a tseries family with a tsm file of the timestamps 1 to 10
+    async fn tsm_version_set(opt: &TseriesFamOpt, tf_id: u32) -> Arc<RwLock<VersionSet>> {
+        let tsm_dir = opt.tsm_dir(tf_id);
+        if !file_manager::try_exists(make_tsm_file_name(&tsm_dir, 1)) {
+            std::fs::create_dir_all(&tsm_dir).unwrap();
+            let block = DataBlock::I64 { index: 0,
+                                         ts: (1..=10).collect(),
+                                         val: vec![1; 10],
+                                         validity: None };
+            build_tsm_file(make_tsm_file_name(&tsm_dir, 1), HashMap::from([(1, block)])).unwrap();
+        }
+        let mut lvl = LevelInfo::init_in(&opt.base_dir, 1);
+        lvl.apply(&CompactMeta { file_id: 1, range: TimeRange::new(10, 1), ..Default::default() });
+        let version = Version::new(tf_id, 1, "db".to_string(), vec![lvl], 0);
+        let desc = [TseriesFamDesc { name: "db".to_string(), opt: opt.clone() }];
+        let versions = HashMap::from([(tf_id, Arc::new(RwLock::new(version)))]);
+        Arc::new(RwLock::new(VersionSet::new(&desc, versions, vec![]).await))
+    }
+
+    #[tokio::test]
+    async fn test_recover_to() {
+        let tmp = tempfile::tempdir().unwrap();
+        let tf_id = 106;
+        let opt = TseriesFamOpt::for_testing(tmp.path());
+        let dir = tmp.path().join("wal").to_string_lossy().to_string();
+        let wal_config = kv_option::WalConfig { dir, ..Default::default() };
+        let mut mgr = WalManager::new(wal_config.clone());
+        for (logged_at, min_ts, max_ts) in [(100, 3, 5), (200, 7, 8)] {
+            let record = WalRecord::DeleteRange { tf_id, field_ids: vec![1], min_ts, max_ts };
+            let buf = record.encode_at(logged_at).unwrap();
+            mgr.write(WalEntryType::Record, &buf).await.unwrap();
+        }
+        drop(mgr);
+
+        let forward_index = Arc::new(RwLock::new(ForwardIndex::new(&tmp.path().join("fidx"))));
+        let (flush_task_sender, _flush_task_receiver) = mpsc::channel(16);
+        let recover = |version_set: Arc<RwLock<VersionSet>>, end_seq: u64| {
+            let (forward_index, sender) = (forward_index.clone(), flush_task_sender.clone());
+            let wal_config = wal_config.clone();
+            async move {
+                let request_window = RequestWindow::new(&Default::default());
+                WalManager::new(wal_config).recover_to(end_seq,
+                                                       version_set.clone(),
+                                                       Arc::new(GlobalContext::new()),
+                                                       sender,
+                                                       forward_index,
+                                                       &request_window)
+                                           .await
+                                           .unwrap();
+                let mut version_set = version_set.write().await;
+                let tsf = version_set.get_tsfamily_by_id(tf_id).unwrap();
+                tsf.timestamps(1, &TimeRange::new(i64::MAX, i64::MIN)).await.unwrap()
+            }
+        };
+        let all = recover(tsm_version_set(&opt, tf_id).await, u64::MAX).await;
+        assert_eq!(all, vec![1, 2, 6, 9, 10]);
+
+        let mut mgr = WalManager::new(wal_config.clone());
+        assert_eq!(mgr.resolve_target(RecoverTarget::Seq(0)).unwrap(), 1);
+        assert_eq!(mgr.resolve_target(RecoverTarget::Timestamp(50)).unwrap(), 0);
+        assert_eq!(mgr.resolve_target(RecoverTarget::Timestamp(300)).unwrap(), u64::MAX);
+        let end_seq = mgr.resolve_target(RecoverTarget::Timestamp(150)).unwrap();
+        assert_eq!(end_seq, 1);
+
+        // the second delete is ignored in the tombstones and not replayed
+        let version_set = tsm_version_set(&opt, tf_id).await;
+        let files = {
+            let mut vs = version_set.write().await;
+            let version = vs.get_tsfamily_by_id(tf_id).unwrap().version().read().await;
+            version.levels_info.iter().flat_map(|info| info.files.clone()).collect::<Vec<_>>()
+        };
+        files.iter().for_each(|file| file.restore_until(end_seq));
+        let restored = recover(version_set, end_seq).await;
+        assert_eq!(restored, vec![1, 2, 6, 7, 8, 9, 10]);
+
+        // confirmed, the second delete is gone for good and the sequences go on
+        files.iter().for_each(|file| file.retain_tombstones(tf_id).unwrap());
+        mgr.truncate(end_seq).unwrap();
+        assert_eq!(mgr.current_seq_no(), 2);
+        drop(mgr);
+        let all = recover(tsm_version_set(&opt, tf_id).await, u64::MAX).await;
+        assert_eq!(all, vec![1, 2, 6, 7, 8, 9, 10]);
+        assert_eq!(WalManager::new(wal_config).current_seq_no(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_recover_request_record() {
+        let dir = "/tmp/test/wal_request";
+        let _ = std::fs::remove_dir_all(dir);
+        let tmp = tempfile::tempdir().unwrap();
+        // a single tseries family, so that every series is dispatched to it
+        let version = Version::new(0, 1, "db".to_string(), vec![], 0);
+        let opt = TseriesFamOpt::for_testing(tmp.path());
+        let desc = [TseriesFamDesc { name: "db".to_string(), opt }];
+        let versions = HashMap::from([(0, Arc::new(RwLock::new(version)))]);
+        let version_set = VersionSet::new(&desc, versions, vec![]).await;
+        let version_set = Arc::new(RwLock::new(version_set));
+
+        let mut fbb = flatbuffers::FlatBufferBuilder::new();
+        let points = models_helper::create_random_points(&mut fbb, 10);
+        fbb.finish(points, None);
+        let record = WalRecord::Request { request_id: 42, points: fbb.finished_data().to_vec() };
+        let wal_config = kv_option::WalConfig { dir: dir.to_string(), ..Default::default() };
+        let mut mgr = WalManager::new(wal_config.clone());
+        mgr.write(WalEntryType::Record, &record.encode().unwrap()).await.unwrap();
+        drop(mgr);
+
+        let request_window = RequestWindow::new(&Default::default());
+        let forward_index =
+            Arc::new(RwLock::new(ForwardIndex::new(Path::new("/tmp/test/wal_request_fidx"))));
+        let (flush_task_sender, _flush_task_receiver) = mpsc::channel(16);
+        WalManager::new(wal_config).recover(version_set.clone(),
+                                            Arc::new(GlobalContext::new()),
+                                            flush_task_sender,
+                                            forward_index,
+                                            &request_window)
+                                   .await
+                                   .unwrap();
+        // a retry of the request after the restart is ignored
+        assert!(request_window.contains(42));
+        assert!(!request_window.insert(42));
+
+        let mut version_set = version_set.write().await;
+        let tsf = version_set.get_tsfamily_by_id(0).unwrap();
+        let cells = tsf.cache().read().await.summary().cells
+                    + tsf.delta_cache().read().await.summary().cells;
+        assert_eq!(cells, 20);
+    }
 }
@@ -0,0 +1,124 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use logger::warn;
+use models::FieldId;
+use serde::Serialize;
+
+use crate::{compaction::LogEvent, trash};
+
+/// Why a value of a write is not put into the caches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteRejectReason {
+    /// A string value is not valid utf-8 and the utf-8 policy rejects it.
+    InvalidUtf8,
+    /// The value type differs from the type of the values of the field in the cache.
+    TypeConflict,
+    /// No tseries family holds the series of the point.
+    UnknownSeries,
+}
+
+impl WriteRejectReason {
+    pub const ALL: [WriteRejectReason; 3] =
+        [Self::InvalidUtf8, Self::TypeConflict, Self::UnknownSeries];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::InvalidUtf8 => "invalid_utf8",
+            Self::TypeConflict => "type_conflict",
+            Self::UnknownSeries => "unknown_series",
+        }
+    }
+}
+
+/// The numbers of values rejected by reason.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct RejectCounts {
+    pub invalid_utf8: u64,
+    pub type_conflict: u64,
+    pub unknown_series: u64,
+}
+
+impl RejectCounts {
+    pub fn get(&self, reason: WriteRejectReason) -> u64 {
+        match reason {
+            WriteRejectReason::InvalidUtf8 => self.invalid_utf8,
+            WriteRejectReason::TypeConflict => self.type_conflict,
+            WriteRejectReason::UnknownSeries => self.unknown_series,
+        }
+    }
+
+    pub fn add(&mut self, reason: WriteRejectReason, n: u64) {
+        let count = match reason {
+            WriteRejectReason::InvalidUtf8 => &mut self.invalid_utf8,
+            WriteRejectReason::TypeConflict => &mut self.type_conflict,
+            WriteRejectReason::UnknownSeries => &mut self.unknown_series,
+        };
+        *count += n;
+    }
+
+    pub fn total(&self) -> u64 {
+        WriteRejectReason::ALL.iter().map(|r| self.get(*r)).sum()
+    }
+}
+
+/// What a write put into the caches, counted by field value.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct WriteSummary {
+    pub values: u64,
+    pub rejected: RejectCounts,
+}
+
+/// The values rejected since the start by reason. A sample of them is logged, at most one a
+/// second, so that a client sending only rejected values does not flood the log.
+#[derive(Debug, Default)]
+pub struct WriteRejects {
+    counts: [AtomicU64; 3],
+    // seconds since the epoch of the last sample logged
+    last_sample: AtomicU64,
+}
+
+impl WriteRejects {
+    /// Counts a rejected value and logs it if no sample is logged in the current second.
+    pub fn record(&self, field_id: FieldId, ts: i64, reason: WriteRejectReason) {
+        self.add(reason, 1);
+        self.sample(field_id, ts, reason);
+    }
+
+    /// Logs the rejected value, without counting it, if no sample is logged in the current
+    /// second.
+    pub fn sample(&self, field_id: FieldId, ts: i64, reason: WriteRejectReason) {
+        let now = trash::now_secs();
+        let last = self.last_sample.load(Ordering::Relaxed);
+        if now <= last
+           || self.last_sample
+                  .compare_exchange(last, now, Ordering::Relaxed, Ordering::Relaxed)
+                  .is_err()
+        {
+            return;
+        }
+        warn!("{}",
+              LogEvent::new("write_rejected").field("field_id", field_id)
+                                             .field("ts", ts)
+                                             .field("reason", reason.as_str())
+                                             .field("rejected", self.counts().get(reason)));
+    }
+
+    pub fn add(&self, reason: WriteRejectReason, n: u64) {
+        self.counts[reason as usize].fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Adds the counts of a write, whose values are counted and sampled elsewhere.
+    pub fn add_counts(&self, counts: &RejectCounts) {
+        for reason in WriteRejectReason::ALL {
+            self.add(reason, counts.get(reason));
+        }
+    }
+
+    pub fn counts(&self) -> RejectCounts {
+        let mut counts = RejectCounts::default();
+        for reason in WriteRejectReason::ALL {
+            counts.add(reason, self.counts[reason as usize].load(Ordering::Relaxed));
+        }
+        counts
+    }
+}